@@ -0,0 +1,212 @@
+// Admin UI: a dependency-free single page app over the admin JSON API.
+// The admin token is kept in sessionStorage by the /login page.
+"use strict";
+
+const view = document.getElementById("view");
+
+function signIn() {
+  location.href = "/login?next=" + encodeURIComponent("/admin/ui/" + location.hash);
+}
+
+// Aborts the event stream of the live view when navigating away
+let leaving = new AbortController();
+
+async function request(method, path, body) {
+  const token = sessionStorage.getItem("adminToken");
+  if (!token) {
+    signIn();
+    throw new Error("not signed in");
+  }
+  const response = await fetch(path, {
+    method,
+    headers: {
+      Authorization: "Bearer " + token,
+      "Content-Type": "application/json",
+    },
+    body: body === undefined ? undefined : JSON.stringify(body),
+    signal: leaving.signal,
+  });
+  if (response.status === 401 || response.status === 403) {
+    sessionStorage.removeItem("adminToken");
+    signIn();
+    throw new Error("unauthorized");
+  }
+  if (!response.ok) {
+    const error = await response.json().catch(() => ({}));
+    throw new Error(error.error || response.statusText);
+  }
+  return response;
+}
+
+async function api(method, path, body) {
+  const response = await request(method, path, body);
+  return response.status === 204 ? null : response.json();
+}
+
+// Read a Server-Sent Events stream, calling onEvent(name, data) per event.
+// EventSource cannot send the admin token, so the stream is fetched.
+async function events(path, onEvent) {
+  const response = await request("GET", path);
+  const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+  let buffer = "";
+  for (;;) {
+    const { value, done } = await reader.read();
+    if (done) return;
+    buffer += value;
+    let end;
+    while ((end = buffer.indexOf("\n\n")) >= 0) {
+      const block = buffer.slice(0, end);
+      buffer = buffer.slice(end + 2);
+      let name = "message";
+      const data = [];
+      for (const line of block.split("\n")) {
+        if (line.startsWith("event:")) name = line.slice(6).trim();
+        else if (line.startsWith("data:")) data.push(line.slice(5).trimStart());
+      }
+      if (data.length) onEvent(name, JSON.parse(data.join("\n")));
+    }
+  }
+}
+
+function cell(row, text, className) {
+  const td = row.insertCell();
+  td.textContent = text;
+  if (className) td.className = className;
+  return td;
+}
+
+function mount(templateId) {
+  view.replaceChildren(document.getElementById(templateId).content.cloneNode(true));
+}
+
+async function showHealth() {
+  mount("health-view");
+  // Readiness is public, so it works before signing in
+  const report = await fetch("/health/ready").then((r) => r.json());
+  const status = view.querySelector(".status");
+  status.textContent = report.status;
+  status.className = report.status;
+  const rows = view.querySelector("tbody");
+  for (const check of report.checks) {
+    const row = rows.insertRow();
+    cell(row, check.name + (check.error ? " — " + check.error : ""));
+    cell(row, check.criticality);
+    cell(row, check.status, check.status);
+    cell(row, check.latency_ms + " ms");
+  }
+}
+
+async function showLive() {
+  mount("live-view");
+  const summary = view.querySelector(".summary");
+  const line = view.querySelector(".chart polyline");
+  const rates = [];
+
+  function showMetrics(snapshot) {
+    const requests = snapshot.requests;
+    const latency = requests.latency_ms;
+    summary.textContent =
+      requests.per_second + " req/s · " + requests.in_flight + " in flight · " +
+      (latency ? "p50 " + latency.p50 + " ms, p99 " + latency.p99 + " ms · " : "") +
+      Object.entries(requests.statuses).map(([c, n]) => n + " " + c).join(", ") +
+      Object.entries(snapshot.queues).map(([q, n]) => " · " + n + " queued for " + q).join("");
+    rates.push(requests.per_second);
+    if (rates.length > 150) rates.shift();
+    const max = Math.max(1, ...rates);
+    line.setAttribute("points", rates.map((r, i) => i * 2 + "," + (60 - (r / max) * 58)).join(" "));
+
+    const pools = view.querySelector(".pools");
+    pools.replaceChildren();
+    for (const pool of snapshot.pools) {
+      const row = pools.insertRow();
+      cell(row, pool.name);
+      cell(row, pool.size);
+      cell(row, pool.idle);
+      cell(row, pool.max);
+    }
+  }
+
+  function showChecks(run) {
+    const checks = view.querySelector(".checks");
+    checks.replaceChildren();
+    for (const check of run.checks) {
+      const row = checks.insertRow();
+      cell(row, check.name + (check.error ? " — " + check.error : ""));
+      cell(row, check.status, check.status);
+      cell(row, check.latency_ms + " ms");
+    }
+  }
+
+  await events("/admin/stream", (name, data) => {
+    if (name === "metrics") showMetrics(data);
+    else if (name === "health") showChecks(data);
+  });
+}
+
+async function showSettings() {
+  mount("settings-view");
+  const rows = view.querySelector("tbody");
+  const form = view.querySelector("#setting-form");
+  const error = form.querySelector(".error");
+
+  async function load() {
+    rows.replaceChildren();
+    for (const setting of await api("GET", "/admin/settings")) {
+      const row = rows.insertRow();
+      cell(row, setting.key);
+      const value = row.insertCell().appendChild(document.createElement("code"));
+      value.textContent = JSON.stringify(setting.value, null, 2);
+      cell(row, new Date(setting.updated_at).toLocaleString());
+      const actions = row.insertCell();
+      const edit = actions.appendChild(document.createElement("button"));
+      edit.textContent = "Edit";
+      edit.onclick = () => {
+        form.key.value = setting.key;
+        form.value.value = JSON.stringify(setting.value, null, 2);
+      };
+      const remove = actions.appendChild(document.createElement("button"));
+      remove.textContent = "Delete";
+      remove.onclick = async () => {
+        if (!confirm("Delete " + setting.key + "?")) return;
+        await api("DELETE", "/admin/settings/" + encodeURIComponent(setting.key));
+        await load();
+      };
+    }
+  }
+
+  form.onsubmit = async (event) => {
+    event.preventDefault();
+    error.textContent = "";
+    try {
+      const value = JSON.parse(form.value.value);
+      await api("PUT", "/admin/settings/" + encodeURIComponent(form.key.value), value);
+      form.reset();
+      await load();
+    } catch (e) {
+      error.textContent = e.message;
+    }
+  };
+  await load();
+}
+
+const routes = { health: showHealth, live: showLive, settings: showSettings };
+
+function route() {
+  leaving.abort();
+  leaving = new AbortController();
+  const show = routes[location.hash.slice(1)] || showHealth;
+  show().catch((e) => {
+    if (e.name === "AbortError") return;
+    const message = document.createElement("p");
+    message.className = "error";
+    message.textContent = e.message;
+    view.append(message);
+  });
+}
+
+document.getElementById("logout").onclick = () => {
+  sessionStorage.removeItem("adminToken");
+  signIn();
+};
+window.addEventListener("hashchange", route);
+route();
@@ -0,0 +1,46 @@
+//! Prepares the admin UI assets embedded by `src/admin_ui.rs`.
+//!
+//! Every file in `admin-ui/` is copied to `$OUT_DIR/admin-ui/` next to a
+//! brotli-compressed `.br` variant, so compressed responses cost nothing at
+//! runtime.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const SOURCE: &str = "admin-ui";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SOURCE);
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    let target = Path::new(&out_dir).join(SOURCE);
+    if target.exists() {
+        fs::remove_dir_all(&target).expect("failed to clear old admin UI assets");
+    }
+    fs::create_dir_all(&target).expect("failed to create the admin UI asset directory");
+
+    for entry in fs::read_dir(SOURCE).expect("failed to read admin-ui/") {
+        let path = entry.expect("failed to read admin-ui/").path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = path.file_name().expect("files have names");
+        let contents = fs::read(&path).expect("failed to read an admin UI asset");
+        fs::write(target.join(name), &contents).expect("failed to copy an admin UI asset");
+
+        let mut compressed = Vec::new();
+        {
+            let params = brotli::enc::BrotliEncoderParams {
+                quality: 11,
+                ..Default::default()
+            };
+            let mut writer = brotli::CompressorWriter::with_params(&mut compressed, 4096, &params);
+            writer
+                .write_all(&contents)
+                .expect("brotli compression failed");
+        }
+        let mut br = name.to_os_string();
+        br.push(".br");
+        fs::write(target.join(br), compressed).expect("failed to write a compressed asset");
+    }
+}
@@ -0,0 +1,20 @@
+//! Query strings given to `GET /api/v1/collections/:name/docs`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_selfhost_server::collections::parse_filter;
+use rust_selfhost_server::db::validate_identifier;
+use std::collections::HashMap;
+
+fuzz_target!(|data: &[u8]| {
+    let params: HashMap<String, String> = form_urlencoded::parse(data).into_owned().collect();
+    if let Ok(filter) = parse_filter(params) {
+        assert!((1..=500).contains(&filter.limit));
+        assert!(filter.offset >= 0);
+        for (field, _) in &filter.fields {
+            assert!(field != "limit" && field != "offset");
+            assert!(validate_identifier(field).is_ok());
+        }
+    }
+});
@@ -0,0 +1,13 @@
+//! Change feed cursors given to `GET /api/v1/changes?cursor=`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_selfhost_server::changes::Cursor;
+
+fuzz_target!(|token: &str| {
+    if let Some(cursor) = Cursor::decode(token) {
+        assert!(cursor.0 >= 0);
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+    }
+});
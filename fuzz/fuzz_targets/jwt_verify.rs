@@ -0,0 +1,28 @@
+//! Bearer tokens given to `/oidc/userinfo` and forward auth
+//!
+//! Inputs starting with a NUL byte get a header naming the fuzzer's key, so
+//! the signature and claims checks are reached as well as header parsing.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_selfhost_server::jwt::{self, SigningKey};
+use std::sync::OnceLock;
+
+static KEY: OnceLock<(SigningKey, String)> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let (key, header) = KEY.get_or_init(|| {
+        let key = SigningKey::generate().expect("key generation");
+        let token = key
+            .sign(&serde_json::json!({ "exp": i64::MAX }))
+            .expect("signing");
+        let header = token.split('.').next().unwrap().to_string();
+        (key, header)
+    });
+    let token = match data.split_first() {
+        Some((0, rest)) => format!("{}.{}", header, String::from_utf8_lossy(rest)),
+        _ => String::from_utf8_lossy(data).into_owned(),
+    };
+    let _ = jwt::verify(&token, std::slice::from_ref(key));
+});
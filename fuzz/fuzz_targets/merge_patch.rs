@@ -0,0 +1,26 @@
+//! JSON merge patches given to `PATCH /api/v1/collections/:name/docs/:id`
+//!
+//! The input is a document and a patch separated by a NUL byte.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_selfhost_server::collections::merge_patch;
+use serde_json::Value;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(split) = data.iter().position(|b| *b == 0) else {
+        return;
+    };
+    let (Ok(mut target), Ok(patch)) = (
+        serde_json::from_slice::<Value>(&data[..split]),
+        serde_json::from_slice::<Value>(&data[split + 1..]),
+    ) else {
+        return;
+    };
+    merge_patch(&mut target, &patch);
+    // Applying a patch twice is the same as applying it once
+    let once = target.clone();
+    merge_patch(&mut target, &patch);
+    assert_eq!(target, once);
+});
@@ -0,0 +1,86 @@
+# Eingebaute deutsche Meldungen.
+
+internal-error = interner Serverfehler
+admin-disabled = die Admin-API ist deaktiviert
+admin-token-invalid = Admin-Token fehlt oder ist ungültig
+api-key-invalid = API-Schlüssel fehlt oder ist ungültig
+api-key-expired = der API-Schlüssel ist abgelaufen
+api-key-address = der API-Schlüssel wird von dieser Adresse nicht akzeptiert
+api-key-scope = dem API-Schlüssel fehlt der Bereich '{ $scope }'
+username-length = der Benutzername muss 1 bis 64 Zeichen lang sein
+username-characters = der Benutzername darf nur Buchstaben, Ziffern, '_', '-' und '.' enthalten
+password-length = das Passwort muss mindestens { $min } Zeichen lang sein
+user-exists = der Benutzer '{ $username }' existiert bereits
+user-not-found = der Benutzer '{ $username }' wurde nicht gefunden
+timezone-unknown = unbekannte Zeitzone '{ $timezone }'
+document-not-found = Dokument nicht gefunden
+paste-empty = der Inhalt darf nicht leer sein
+paste-too-large = der Inhalt darf höchstens { $max } Bytes lang sein
+paste-syntax = ungültiger Syntax-Hinweis '{ $syntax }'
+paste-expiry = ungültige Ablaufzeit '{ $expires_in }': erwartet wird eine Dauer wie 1h oder 7d, höchstens 365d
+paste-not-found = Paste nicht gefunden
+paste-password = dieses Paste benötigt das richtige Passwort in X-Paste-Password
+paste-too-many-failures = zu viele falsche Passwörter für dieses Paste, später erneut versuchen
+paste-delete-token = X-Delete-Token fehlt oder ist ungültig
+shortlink-target = ungültiges Ziel '{ $target }': erwartet wird eine absolute http- oder https-URL
+shortlink-slug = ungültiger Kurzname '{ $slug }': erlaubt sind 1 bis 64 Buchstaben, Ziffern, '-' oder '_'
+shortlink-expiry = ungültige Ablaufzeit '{ $expires_in }': erwartet wird eine Dauer wie 1h oder 30d
+shortlink-exists = Kurzlink '{ $slug }' existiert bereits
+shortlink-not-found = Kurzlink nicht gefunden
+bookmark-url = ungültige URL '{ $url }': erwartet wird eine absolute http- oder https-URL
+bookmark-title = der Titel darf höchstens { $max } Zeichen lang sein
+bookmark-description = die Beschreibung darf höchstens { $max } Zeichen lang sein
+tag-invalid = ungültiges Schlagwort '{ $tag }'
+tags-too-many = höchstens { $max } Schlagwörter sind erlaubt
+bookmark-exists = '{ $url }' ist bereits als Lesezeichen gespeichert
+bookmark-not-found = Lesezeichen nicht gefunden
+bookmark-import-empty = keine Lesezeichen gefunden; erwartet wird eine Netscape-Lesezeichendatei
+note-title = der Titel muss 1 bis { $max } Zeichen lang sein
+note-too-large = der Notizinhalt darf höchstens { $max } Bytes lang sein
+note-folder = ungültiger Ordner '{ $folder }'
+note-not-found = Notiz nicht gefunden
+note-revision-not-found = Notizversion nicht gefunden
+unfurl-url = ungültige URL '{ $url }': erwartet wird eine absolute http- oder https-URL
+unfurl-failed = '{ $url }' konnte nicht abgerufen werden: { $reason }
+markdown-too-large = Markdown darf höchstens { $max } Bytes lang sein
+markdown-theme-not-found = unbekanntes Hervorhebungsthema '{ $theme }', erwartet wird eines von: { $known }
+crash-not-found = Absturzbericht nicht gefunden
+file-key = ungültiger Dateischlüssel '{ $key }': erlaubt sind durch '/' getrennte Buchstaben, Ziffern, '.', '_' oder '-', nicht mit '.' beginnend
+file-too-large = Dateien dürfen höchstens { $max } Bytes groß sein
+file-digest = der Upload passt nicht zu { $expected }: sein SHA-256 ist { $actual }
+file-interrupted = der Upload wurde unterbrochen: { $reason }
+file-not-found = Datei nicht gefunden
+mail-too-large = Nachrichten dürfen höchstens { $max } Bytes groß sein
+mail-invalid = keine gültige E-Mail-Nachricht: { $reason }
+mail-sender = E-Mails von '{ $sender }' werden nicht angenommen
+mail-unauthorized = fehlendes oder ungültiges Mail-Webhook-Geheimnis
+event-title = Termintitel müssen 1 bis { $max } Zeichen lang sein
+event-too-large = Beschreibung und Ort eines Termins dürfen höchstens { $max } Bytes groß sein
+event-times = Termine können nicht vor ihrem Beginn enden
+event-timezone = ungültige Zeitzone des Termins: { $reason }
+event-rrule = ungültige Wiederholungsregel: { $reason }
+event-not-found = Termin nicht gefunden
+event-window = 'to' muss nach 'from' und höchstens { $max } Tage später liegen
+calendar-feed-token = fehlendes oder ungültiges Token für den Kalender-Feed
+dav-unauthorized = mit Benutzername und Passwort anmelden
+dav-too-many-failures = zu viele fehlgeschlagene Anmeldungen, später erneut versuchen
+dav-invalid-body = ungültiger WebDAV-Anfrageinhalt: { $reason }
+dav-destination = fehlender oder ungültiger Destination-Header
+dav-lock-token = fehlender Lock-Token-Header
+contact-name = Kontaktnamen dürfen nur Buchstaben, Ziffern und -_.@+~ enthalten
+contact-not-found = Kontakt nicht gefunden
+watchdog-shedding = der Server hat kaum noch Ressourcen frei, bitte gleich noch einmal versuchen
+read-only = der Server ist wegen Wartungsarbeiten schreibgeschützt, bitte später noch einmal versuchen
+channel-invalid = ungültiger Kanalname: erlaubt sind 1 bis 128 Buchstaben, Ziffern, '.', '_', '-', ':' oder '/'
+channel-forbidden = auf dem Kanal '{ $channel }' nicht erlaubt
+ws-message-invalid = ungültige Nachricht: { $error }
+session-required = Sie müssen sich anmelden
+login-invalid = ungültiger Benutzername oder ungültiges Passwort
+remembered-device-not-found = gemerktes Gerät nicht gefunden
+session-not-found = Sitzung nicht gefunden
+plan-limit = das Kontingent des Tarifs { $plan } von { $limit } Anfragen im Monat ist aufgebraucht
+billing-no-tenant = dieser API-Schlüssel gehört zu keinem Mandanten
+billing-no-customer = dieser Mandant hat noch kein Stripe-Abonnement
+stripe-failed = Stripe ist nicht erreichbar, bitte später noch einmal versuchen
+stripe-signature-invalid = ungültige oder abgelaufene Stripe-Signatur
+stripe-event-invalid = ungültiges Stripe-Ereignis
@@ -0,0 +1,87 @@
+# Built-in English messages. Copy this file to LOCALES_DIR/<language>/ and
+# translate the values to add a language.
+
+internal-error = internal server error
+admin-disabled = admin API is disabled
+admin-token-invalid = invalid or missing admin token
+api-key-invalid = invalid or missing API key
+api-key-expired = API key has expired
+api-key-address = API key is not accepted from this address
+api-key-scope = API key lacks the '{ $scope }' scope
+username-length = username must be 1 to 64 characters
+username-characters = username may only contain letters, digits, '_', '-' and '.'
+password-length = password must be at least { $min } characters
+user-exists = user '{ $username }' already exists
+user-not-found = user '{ $username }' not found
+timezone-unknown = unknown timezone '{ $timezone }'
+document-not-found = document not found
+paste-empty = paste content must not be empty
+paste-too-large = paste content must be at most { $max } bytes
+paste-syntax = invalid syntax hint '{ $syntax }'
+paste-expiry = invalid expiry '{ $expires_in }': expected a duration such as 1h or 7d, up to 365d
+paste-not-found = paste not found
+paste-password = this paste needs the right password in X-Paste-Password
+paste-too-many-failures = too many wrong passwords for this paste, try again later
+paste-delete-token = invalid or missing X-Delete-Token
+shortlink-target = invalid target '{ $target }': expected an absolute http or https URL
+shortlink-slug = invalid slug '{ $slug }': use 1 to 64 letters, digits, '-' or '_'
+shortlink-expiry = invalid expiry '{ $expires_in }': expected a duration such as 1h or 30d
+shortlink-exists = short link '{ $slug }' already exists
+shortlink-not-found = short link not found
+bookmark-url = invalid URL '{ $url }': expected an absolute http or https URL
+bookmark-title = title must be at most { $max } characters
+bookmark-description = description must be at most { $max } characters
+tag-invalid = invalid tag '{ $tag }'
+tags-too-many = at most { $max } tags are allowed
+bookmark-exists = '{ $url }' is already bookmarked
+bookmark-not-found = bookmark not found
+bookmark-import-empty = no bookmarks found; expected a Netscape bookmark file
+note-title = title must be 1 to { $max } characters
+note-too-large = note body must be at most { $max } bytes
+note-folder = invalid folder '{ $folder }'
+note-not-found = note not found
+note-revision-not-found = note revision not found
+unfurl-url = invalid URL '{ $url }': expected an absolute http or https URL
+unfurl-failed = could not fetch '{ $url }': { $reason }
+markdown-too-large = markdown must be at most { $max } bytes
+markdown-theme-not-found = unknown highlighting theme '{ $theme }', expected one of: { $known }
+crash-not-found = crash report not found
+file-key = invalid file key '{ $key }': use '/'-separated letters, digits, '.', '_' or '-', not starting with '.'
+file-too-large = files must be at most { $max } bytes
+file-digest = upload does not match { $expected }: its SHA-256 is { $actual }
+file-interrupted = upload was interrupted: { $reason }
+file-not-found = file not found
+mail-too-large = messages must be at most { $max } bytes
+mail-invalid = not a valid email message: { $reason }
+mail-sender = mail from '{ $sender }' is not accepted
+mail-unauthorized = missing or invalid mail webhook secret
+event-title = event titles must be 1 to { $max } characters
+event-too-large = event descriptions and locations must be at most { $max } bytes
+event-times = events cannot end before they start
+event-timezone = invalid event timezone: { $reason }
+event-rrule = invalid recurrence rule: { $reason }
+event-not-found = event not found
+event-window = 'to' must be after 'from' and at most { $max } days later
+calendar-feed-token = missing or invalid calendar feed token
+dav-unauthorized = sign in with a username and password
+dav-too-many-failures = too many failed sign-ins, try again later
+dav-invalid-body = invalid WebDAV request body: { $reason }
+dav-destination = missing or invalid Destination header
+dav-lock-token = missing Lock-Token header
+contact-name = contact names may only hold letters, digits and -_.@+~
+contact-not-found = contact not found
+watchdog-shedding = the server is low on resources, try again shortly
+read-only = the server is read-only for maintenance, try again later
+channel-invalid = invalid channel name: use 1 to 128 letters, digits, '.', '_', '-', ':' or '/'
+channel-forbidden = not allowed on channel '{ $channel }'
+ws-message-invalid = invalid message: { $error }
+session-required = you need to log in
+login-invalid = invalid username or password
+remembered-device-not-found = remembered device not found
+session-not-found = session not found
+plan-limit = the { $plan } plan's limit of { $limit } requests a month is used up
+billing-no-tenant = this API key does not belong to a tenant
+billing-no-customer = this tenant has no Stripe subscription yet
+stripe-failed = could not reach Stripe, try again later
+stripe-signature-invalid = invalid or expired Stripe signature
+stripe-event-invalid = invalid Stripe event
@@ -0,0 +1,184 @@
+//! Embedded admin web UI.
+//!
+//! A small dependency-free single page app is compiled into the binary and
+//! served at `/admin/ui/`. The assets themselves are public; the app signs in
+//! through `/login` and calls the admin API with the admin bearer token.
+//! It currently covers health, live metrics and runtime settings.
+//!
+//! Each asset is embedded with a brotli-compressed variant made at build
+//! time, served to clients that accept `br`, so neither a file on disk nor
+//! runtime compression is needed.
+
+use axum::{
+    extract::Path,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use rust_embed::RustEmbed;
+
+use crate::module::{RouteGroup, RouteModule};
+use crate::AppState;
+
+/// Embedded assets, each next to a brotli-compressed `.br` variant
+///
+/// `build.rs` prepares the folder from `admin-ui/`.
+#[derive(RustEmbed)]
+#[folder = "$OUT_DIR/admin-ui"]
+struct Assets;
+
+/// Route module serving the embedded admin UI
+pub struct AdminUiModule;
+
+impl RouteModule for AdminUiModule {
+    fn name(&self) -> &'static str {
+        "admin_ui"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route(
+                "/admin/ui",
+                get(|| async { Redirect::permanent("/admin/ui/") }),
+            )
+            .route(
+                "/admin/ui/",
+                get(|headers: HeaderMap| async move { asset("index.html", &headers) }),
+            )
+            .route(
+                "/admin/ui/:file",
+                get(|Path(file): Path<String>, headers: HeaderMap| async move {
+                    asset(&file, &headers)
+                }),
+            )
+    }
+}
+
+fn content_type(file: &str) -> &'static str {
+    match file.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether the client accepts brotli, going by `Accept-Encoding`
+fn accepts_brotli(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            name.eq_ignore_ascii_case("br") && !refused
+        })
+}
+
+fn asset(file: &str, headers: &HeaderMap) -> Response {
+    // The compressed variants are only served in place of their originals
+    if file.ends_with(".br") {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let Some(original) = Assets::get(file) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let etag = format!(
+        "\"{}\"",
+        original
+            .metadata
+            .sha256_hash()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    );
+    let mut response = if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        match Assets::get(&format!("{}.br", file)).filter(|_| accepts_brotli(headers)) {
+            Some(compressed) => (
+                [(header::CONTENT_ENCODING, "br")],
+                compressed.data.into_owned(),
+            )
+                .into_response(),
+            None => original.data.into_owned().into_response(),
+        }
+    };
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type(file)),
+    );
+    response_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response_headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    response_headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&etag).expect("hex digests are header-safe"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use axum::{body::Body, extract::Request};
+    use http_body_util::BodyExt;
+    use sqlx::PgPool;
+    use tower::Service;
+
+    #[tokio::test]
+    async fn test_serves_precompressed_assets() {
+        let mut app = AdminUiModule
+            .routes()
+            .with_state(AppState::new(Database::from_pool(
+                PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+            )));
+        let mut get = |uri: &str, encoding: &str| {
+            let request = Request::get(uri)
+                .header(header::ACCEPT_ENCODING, encoding)
+                .body(Body::empty())
+                .unwrap();
+            app.call(request)
+        };
+
+        let plain = get("/admin/ui/app.js", "gzip").await.unwrap();
+        assert_eq!(plain.headers().get(header::CONTENT_ENCODING), None);
+        assert_eq!(
+            plain.headers()[header::CONTENT_TYPE],
+            "text/javascript; charset=utf-8"
+        );
+        let plain = plain.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&plain[..], include_bytes!("../admin-ui/app.js"));
+
+        let compressed = get("/admin/ui/app.js", "gzip, br;q=0.9").await.unwrap();
+        assert_eq!(compressed.headers()[header::CONTENT_ENCODING], "br");
+        let compressed = compressed.into_body().collect().await.unwrap().to_bytes();
+        assert!(compressed.len() < plain.len());
+
+        let refused = get("/admin/ui/app.js", "br;q=0").await.unwrap();
+        assert_eq!(refused.headers().get(header::CONTENT_ENCODING), None);
+        let raw = get("/admin/ui/app.js.br", "br").await.unwrap();
+        assert_eq!(raw.status(), StatusCode::NOT_FOUND);
+    }
+}
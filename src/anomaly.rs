@@ -0,0 +1,531 @@
+//! Suspicious activity detection.
+//!
+//! With `ANOMALY_DETECTION=true` every response is observed and counted
+//! per client address and per account (the credential a request presents:
+//! a bearer token's fingerprint or an HMAC client id) over a sliding
+//! `ANOMALY_WINDOW`. A finding is raised when, within one window:
+//!
+//! - a client or account collects `ANOMALY_AUTH_FAILURES` 401/403 responses
+//! - a client collects `ANOMALY_ERROR_BURST` other 4xx responses
+//! - the server returns `ANOMALY_ERROR_BURST` 5xx responses overall
+//! - an account is used from more than `ANOMALY_MAX_NETWORKS` networks
+//!   (/24 for IPv4, /48 for IPv6), the closest this server gets to
+//!   "impossible travel" without a GeoIP database
+//!
+//! Findings are logged, stored in the `security_findings` audit table,
+//! posted to `SECURITY_ALERT_WEBHOOK` if set, and listed at
+//! `GET /admin/security/findings`. Each finding is raised at most once per
+//! window for the same kind and subject. Client addresses are those of the
+//! connecting peer, i.e. the proxy's when running behind Traefik.
+
+use anyhow::Result;
+use axum::{
+    extract::{ConnectInfo, Query, Request, State},
+    http::{header::AUTHORIZATION, Method},
+    middleware::Next,
+    response::{Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::ApiResult;
+use crate::http_client::HttpClient;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::time::Timestamp;
+use crate::{signing, AppState};
+
+/// Tracked keys beyond which stale counters are pruned
+const PRUNE_AFTER: usize = 10_000;
+
+/// Thresholds of the detector
+#[derive(Debug, Clone)]
+pub struct AnomalyConfig {
+    pub enabled: bool,
+    pub window: Duration,
+    pub auth_failures: u32,
+    pub error_burst: u32,
+    pub max_networks: usize,
+    /// URL receiving a JSON `POST` for every finding
+    pub webhook: Option<String>,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            enabled: false,
+            window: Duration::from_secs(300),
+            auth_failures: 20,
+            error_burst: 100,
+            max_networks: 3,
+            webhook: None,
+        }
+    }
+}
+
+/// Kind of suspicious activity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FindingKind {
+    AuthFailures,
+    ClientErrors,
+    ServerErrors,
+    NewNetworks,
+}
+
+impl FindingKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            FindingKind::AuthFailures => "auth_failures",
+            FindingKind::ClientErrors => "client_errors",
+            FindingKind::ServerErrors => "server_errors",
+            FindingKind::NewNetworks => "new_networks",
+        }
+    }
+}
+
+/// A finding about to be recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Detection {
+    pub kind: FindingKind,
+    /// Client address, account, or `server`
+    pub subject: String,
+    pub detail: String,
+    pub count: u32,
+}
+
+/// A recorded finding
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Finding {
+    pub id: i64,
+    pub kind: String,
+    pub subject: String,
+    pub detail: String,
+    pub count: i32,
+    #[serde(with = "crate::time::rfc3339")]
+    pub detected_at: DateTime<Utc>,
+}
+
+/// What one response tells the detector
+#[derive(Debug, Clone)]
+pub struct Observation {
+    pub client: Option<IpAddr>,
+    pub account: Option<String>,
+    pub method: Method,
+    pub path: String,
+    pub status: u16,
+}
+
+#[derive(Debug)]
+struct Counter {
+    started: Instant,
+    count: u32,
+}
+
+/// Sliding-window counters, separate from I/O so they can be tested
+#[derive(Debug, Default)]
+struct Counters {
+    events: HashMap<(FindingKind, String), Counter>,
+    /// Networks each account was seen from, with when
+    networks: HashMap<String, HashMap<String, Instant>>,
+    /// When each kind and subject last raised a finding
+    raised: HashMap<(FindingKind, String), Instant>,
+}
+
+impl Counters {
+    fn observe(
+        &mut self,
+        config: &AnomalyConfig,
+        observation: &Observation,
+        now: Instant,
+    ) -> Vec<Detection> {
+        let window = config.window;
+        let client = observation.client.map(|ip| ip.to_string());
+        let mut detections = Vec::new();
+        let mut count = |kind: FindingKind, subject: &str, threshold: u32| {
+            let counter = self
+                .events
+                .entry((kind, subject.to_string()))
+                .or_insert(Counter {
+                    started: now,
+                    count: 0,
+                });
+            if now.duration_since(counter.started) > window {
+                *counter = Counter {
+                    started: now,
+                    count: 0,
+                };
+            }
+            counter.count += 1;
+            (threshold > 0 && counter.count >= threshold).then(|| (kind, subject.to_string()))
+        };
+
+        let mut hits = Vec::new();
+        match observation.status {
+            401 | 403 => {
+                hits.extend(
+                    client
+                        .iter()
+                        .chain(&observation.account)
+                        .filter_map(|s| count(FindingKind::AuthFailures, s, config.auth_failures)),
+                );
+            }
+            400..=499 => hits.extend(
+                client
+                    .iter()
+                    .filter_map(|s| count(FindingKind::ClientErrors, s, config.error_burst)),
+            ),
+            500..=599 => hits.extend(count(
+                FindingKind::ServerErrors,
+                "server",
+                config.error_burst,
+            )),
+            _ => {}
+        }
+        for (kind, subject) in hits {
+            let count = self.events[&(kind, subject.clone())].count;
+            let detail = match kind {
+                FindingKind::AuthFailures => format!(
+                    "{} failed authentications in {:?}, last {} {}",
+                    count, window, observation.method, observation.path
+                ),
+                FindingKind::ClientErrors => format!(
+                    "{} client errors in {:?}, last {} {}",
+                    count, window, observation.method, observation.path
+                ),
+                _ => format!(
+                    "{} server errors in {:?}, last {} {}",
+                    count, window, observation.method, observation.path
+                ),
+            };
+            detections.push(Detection {
+                kind,
+                subject,
+                detail,
+                count,
+            });
+        }
+
+        if let (Some(account), Some(ip), true) = (
+            &observation.account,
+            observation.client,
+            observation.status < 400,
+        ) {
+            let seen = self.networks.entry(account.clone()).or_default();
+            seen.insert(network(ip), now);
+            seen.retain(|_, at| now.duration_since(*at) <= window);
+            if config.max_networks > 0 && seen.len() > config.max_networks {
+                let mut networks: Vec<_> = seen.keys().cloned().collect();
+                networks.sort();
+                detections.push(Detection {
+                    kind: FindingKind::NewNetworks,
+                    subject: account.clone(),
+                    detail: format!(
+                        "used from {} networks in {:?}: {}",
+                        networks.len(),
+                        window,
+                        networks.join(", ")
+                    ),
+                    count: networks.len() as u32,
+                });
+            }
+        }
+
+        // Raise each kind and subject once per window
+        detections.retain(|d| {
+            let key = (d.kind, d.subject.clone());
+            match self.raised.get(&key) {
+                Some(at) if now.duration_since(*at) <= window => false,
+                _ => {
+                    self.raised.insert(key, now);
+                    true
+                }
+            }
+        });
+
+        if self.events.len() + self.networks.len() + self.raised.len() > PRUNE_AFTER {
+            self.events
+                .retain(|_, c| now.duration_since(c.started) <= window);
+            self.networks.retain(|_, seen| {
+                seen.retain(|_, at| now.duration_since(*at) <= window);
+                !seen.is_empty()
+            });
+            self.raised
+                .retain(|_, at| now.duration_since(*at) <= window);
+        }
+        detections
+    }
+}
+
+/// The network an address belongs to, for spotting accounts used from many places
+fn network(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => network(IpAddr::V4(v4)),
+            None => {
+                let s = v6.segments();
+                format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+            }
+        },
+    }
+}
+
+/// The account a request claims, without revealing its credential
+pub fn account(request: &Request) -> Option<String> {
+    let header = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    if let Some(token) = header.strip_prefix("Bearer ") {
+        let digest = Sha256::digest(token.trim().as_bytes());
+        let fingerprint: String = digest[..6].iter().map(|b| format!("{:02x}", b)).collect();
+        return Some(format!("token:{}", fingerprint));
+    }
+    let params = header.strip_prefix(signing::SCHEME)?;
+    params
+        .split(',')
+        .find_map(|param| param.trim().strip_prefix("client="))
+        .map(|client| format!("hmac:{}", client))
+}
+
+/// Shared detector and the middleware feeding it
+#[derive(Clone)]
+pub struct AnomalyDetector {
+    config: Arc<AnomalyConfig>,
+    counters: Arc<Mutex<Counters>>,
+    pool: PgPool,
+    client: HttpClient,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: AnomalyConfig, pool: PgPool) -> Self {
+        AnomalyDetector {
+            config: Arc::new(config),
+            counters: Arc::default(),
+            pool,
+            client: HttpClient::new(Duration::from_secs(10)),
+        }
+    }
+
+    /// Count a response, recording any findings in the background
+    pub fn observe(&self, observation: &Observation) {
+        let detections = self
+            .counters
+            .lock()
+            .expect("anomaly counters poisoned")
+            .observe(&self.config, observation, Instant::now());
+        for detection in detections {
+            let detector = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = detector.raise(&detection).await {
+                    tracing::warn!("Failed to record security finding: {:#}", e);
+                }
+            });
+        }
+    }
+
+    async fn raise(&self, detection: &Detection) -> Result<()> {
+        tracing::warn!(
+            "🕵️ Suspicious activity ({}) from {}: {}",
+            detection.kind.as_str(),
+            detection.subject,
+            detection.detail
+        );
+        let finding: Finding = sqlx::query_as(
+            "INSERT INTO security_findings (kind, subject, detail, count)
+             VALUES ($1, $2, $3, $4)
+             RETURNING id, kind, subject, detail, count, detected_at",
+        )
+        .bind(detection.kind.as_str())
+        .bind(&detection.subject)
+        .bind(&detection.detail)
+        .bind(detection.count as i32)
+        .fetch_one(&self.pool)
+        .await?;
+        if let Some(webhook) = &self.config.webhook {
+            let body = json!({ "event": "security_finding", "finding": finding });
+            match self
+                .client
+                .request(Method::POST, webhook, Some(&body))
+                .await
+            {
+                Ok((status, _)) if status.is_success() => {}
+                Ok((status, _)) => tracing::warn!("Security alert webhook returned {}", status),
+                Err(e) => tracing::warn!("Security alert webhook failed: {:#}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Middleware observing every response
+    pub async fn middleware(
+        State(detector): State<AnomalyDetector>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let client = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let account = account(&request);
+        let method = request.method().clone();
+        let path = request.uri().path().to_string();
+        let response = next.run(request).await;
+        detector.observe(&Observation {
+            client,
+            account,
+            method,
+            path,
+            status: response.status().as_u16(),
+        });
+        response
+    }
+}
+
+/// Route module listing findings, mounted when `ANOMALY_DETECTION` is on
+pub struct AnomalyModule;
+
+impl RouteModule for AnomalyModule {
+    fn name(&self) -> &'static str {
+        "anomaly"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/admin/security/findings", get(list_findings))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_security_findings",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS security_findings (
+                id BIGSERIAL PRIMARY KEY,
+                kind TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                detail TEXT NOT NULL,
+                count INTEGER NOT NULL,
+                detected_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE INDEX IF NOT EXISTS security_findings_detected_at
+                ON security_findings (detected_at)",
+        }]
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FindingsQuery {
+    #[serde(default = "default_limit")]
+    limit: i64,
+    kind: Option<String>,
+    subject: Option<String>,
+    /// Only findings detected at or after this RFC 3339 timestamp
+    since: Option<Timestamp>,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+/// `GET /admin/security/findings` - most recent findings first
+pub async fn list_findings(
+    State(state): State<AppState>,
+    Query(query): Query<FindingsQuery>,
+) -> ApiResult<Json<Vec<Finding>>> {
+    let findings = sqlx::query_as(
+        "SELECT id, kind, subject, detail, count, detected_at FROM security_findings
+         WHERE ($1::text IS NULL OR kind = $1) AND ($2::text IS NULL OR subject = $2)
+           AND ($4::timestamptz IS NULL OR detected_at >= $4)
+         ORDER BY detected_at DESC, id DESC LIMIT $3",
+    )
+    .bind(query.kind)
+    .bind(query.subject)
+    .bind(query.limit.clamp(1, 1000))
+    .bind(query.since.map(DateTime::from))
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(findings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(client: &str, account: Option<&str>, status: u16) -> Observation {
+        Observation {
+            client: Some(client.parse().unwrap()),
+            account: account.map(String::from),
+            method: Method::GET,
+            path: "/api/v1/info".into(),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_bursts_raise_one_finding_per_window() {
+        let config = AnomalyConfig {
+            auth_failures: 3,
+            error_burst: 2,
+            ..AnomalyConfig::default()
+        };
+        let mut counters = Counters::default();
+        let start = Instant::now();
+        let mut observe = |o: Observation, at: Instant| counters.observe(&config, &o, at);
+
+        assert!(observe(observation("10.0.0.1", None, 401), start).is_empty());
+        assert!(observe(observation("10.0.0.1", None, 401), start).is_empty());
+        let found = observe(observation("10.0.0.1", None, 401), start);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].kind, FindingKind::AuthFailures);
+        assert_eq!(found[0].subject, "10.0.0.1");
+        assert_eq!(found[0].count, 3);
+        assert!(observe(observation("10.0.0.1", None, 401), start).is_empty());
+
+        let later = start + config.window + Duration::from_secs(1);
+        assert!(observe(observation("10.0.0.1", None, 401), later).is_empty());
+
+        assert!(observe(observation("10.0.0.2", None, 404), start).is_empty());
+        let found = observe(observation("10.0.0.2", None, 404), start);
+        assert_eq!(found[0].kind, FindingKind::ClientErrors);
+        observe(observation("10.0.0.3", None, 500), start);
+        let found = observe(observation("10.0.0.4", None, 502), start);
+        assert_eq!(found[0].subject, "server");
+    }
+
+    #[test]
+    fn test_account_used_from_many_networks() {
+        let config = AnomalyConfig {
+            max_networks: 2,
+            ..AnomalyConfig::default()
+        };
+        let mut counters = Counters::default();
+        let now = Instant::now();
+        let mut observe =
+            |ip: &str| counters.observe(&config, &observation(ip, Some("token:ab"), 200), now);
+
+        assert!(observe("10.0.0.1").is_empty());
+        assert!(observe("10.0.0.200").is_empty());
+        assert!(observe("192.168.1.1").is_empty());
+        let found = observe("172.16.0.1");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].kind, FindingKind::NewNetworks);
+        assert_eq!(found[0].subject, "token:ab");
+        assert_eq!(network("::ffff:10.1.2.3".parse().unwrap()), "10.1.2.0/24");
+        assert_eq!(
+            network("2001:db8:1:2::1".parse().unwrap()),
+            "2001:db8:1::/48"
+        );
+    }
+}
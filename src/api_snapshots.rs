@@ -0,0 +1,486 @@
+//! Snapshot tests of the HTTP API contract.
+//!
+//! Every endpoint's status code and JSON body is compared against the
+//! snapshots in `src/snapshots/`, with ids, secrets, keys and timestamps
+//! redacted. After an intentional change, review and accept the new
+//! snapshots with `cargo insta review`.
+
+use insta::assert_json_snapshot;
+use serde_json::{json, Value};
+
+use crate::test_db::TestDatabase;
+use crate::testing::{self, TestRequest, TestServer};
+
+/// Send a request and capture what the contract covers
+async fn call(request: TestRequest<'_>) -> Value {
+    let response = request.send().await.unwrap();
+    let body = if response.body.is_empty() {
+        Value::Null
+    } else {
+        response
+            .json()
+            .unwrap_or_else(|_| Value::String(response.text()))
+    };
+    json!({ "status": response.status.as_u16(), "body": body })
+}
+
+#[tokio::test]
+async fn test_api_snapshots() {
+    let Some(db) = TestDatabase::start().await else {
+        return;
+    };
+    let config = testing::config(&[
+        ("DATABASE_URL", &db.url),
+        ("INSTANCE_NAME", "snapshot"),
+        ("OIDC_ISSUER", "http://localhost:3000"),
+        ("SHORTLINKS", "true"),
+    ])
+    .unwrap();
+    let server = TestServer::spawn(config).await.unwrap();
+
+    let mut settings = insta::Settings::clone_current();
+    settings.set_prepend_module_to_snapshot(false);
+    for field in ["created_at", "updated_at", "started_at", "resolved_at"] {
+        settings.add_redaction(&format!(".**.{}", field), "[timestamp]");
+    }
+    settings.add_redaction(".body.id", "[id]");
+    settings.add_redaction(".body[].id", "[id]");
+    settings.add_redaction(".body[].note_id", "[id]");
+    settings.add_redaction(".body.client_id", "[client_id]");
+    settings.add_redaction(".body[].client_id", "[client_id]");
+    settings.add_redaction(".body.client_secret", "[secret]");
+    settings.add_redaction(".body.version", "[version]");
+    settings.add_redaction(".body.delete_token", "[secret]");
+    settings.add_redaction(".body.raw_url", "[url]");
+    settings.add_redaction(".body.expires_at", "[timestamp]");
+    settings.add_redaction(".**.last_hit_at", "[timestamp]");
+    settings.add_redaction(".body.daily[].day", "[date]");
+    // The bookmark fetcher runs in the background
+    settings.add_redaction(".**.fetch_status", "[fetch_status]");
+    settings.add_redaction(".**.fetch_error", "[fetch_error]");
+    settings.add_redaction(".body.keys[].kid", "[kid]");
+    settings.add_redaction(".body.keys[].n", "[modulus]");
+    settings.add_redaction(".**.latency_ms", "[latency]");
+    settings.add_redaction(".body.checked_at", "[timestamp]");
+    settings.add_redaction(".body.duration_ms", "[latency]");
+    let _guard = settings.bind_to_scope();
+
+    // Public
+    assert_json_snapshot!("root", call(server.get("/")).await);
+    assert_json_snapshot!("health", call(server.get("/health")).await);
+    assert_json_snapshot!("health_db", call(server.get("/health/db")).await);
+    assert_json_snapshot!("health_ready", call(server.get("/health/ready")).await);
+    assert_json_snapshot!("health_deep", call(server.get("/health/deep")).await);
+    assert_json_snapshot!(
+        "health_upstreams",
+        call(server.get("/health/upstreams")).await
+    );
+    assert_json_snapshot!(
+        "status",
+        call(
+            server
+                .get("/status")
+                .header(hyper::header::ACCEPT, "application/json")
+        )
+        .await
+    );
+    assert_json_snapshot!(
+        "forward_auth_unauthorized",
+        call(server.get("/auth/forward")).await
+    );
+
+    // API
+    assert_json_snapshot!("info", call(server.get("/api/v1/info")).await);
+    assert_json_snapshot!("changes", call(server.get("/api/v1/changes")).await);
+    let schema = json!({
+        "schema": {
+            "type": "object",
+            "required": ["title"],
+            "properties": { "title": { "type": "string" } }
+        },
+        "indexes": ["title"]
+    });
+    assert_json_snapshot!(
+        "collection_define",
+        call(server.put("/api/v1/collections/notes").json(&schema)).await
+    );
+    assert_json_snapshot!(
+        "collection_define_invalid_name",
+        call(server.put("/api/v1/collections/no%20pe").json(&json!({}))).await
+    );
+    assert_json_snapshot!(
+        "collections_list",
+        call(server.get("/api/v1/collections")).await
+    );
+    let created = call(
+        server
+            .post("/api/v1/collections/notes/docs")
+            .json(&json!({ "title": "hello" })),
+    )
+    .await;
+    let id = created["body"]["id"].as_str().unwrap().to_string();
+    assert_json_snapshot!("document_create", created);
+    assert_json_snapshot!(
+        "document_create_invalid",
+        call(
+            server
+                .post("/api/v1/collections/notes/docs")
+                .json(&json!({ "title": 1 }))
+        )
+        .await
+    );
+    assert_json_snapshot!(
+        "documents_list",
+        call(server.get("/api/v1/collections/notes/docs?title=hello")).await
+    );
+    let doc = format!("/api/v1/collections/notes/docs/{}", id);
+    assert_json_snapshot!("document_get", call(server.get(&doc)).await);
+    assert_json_snapshot!(
+        "document_update",
+        call(
+            server
+                .request(hyper::Method::PATCH, &doc)
+                .json(&json!({ "tags": ["a"] }))
+        )
+        .await
+    );
+    assert_json_snapshot!("document_delete", call(server.delete(&doc)).await);
+    assert_json_snapshot!("document_not_found", call(server.get(&doc)).await);
+
+    let paste = call(server.post("/api/v1/pastes").json(&json!({
+        "content": "fn main() {}",
+        "syntax": "rust",
+        "expires_in": "1d",
+        "password": "hunter22"
+    })))
+    .await;
+    assert_json_snapshot!("paste_create", paste);
+    let id = paste["body"]["id"].as_str().unwrap().to_string();
+    let token = paste["body"]["delete_token"].as_str().unwrap().to_string();
+    let paste_url = format!("/api/v1/pastes/{}", id);
+    assert_json_snapshot!(
+        "paste_password_required",
+        call(server.get(&paste_url)).await
+    );
+    let password = hyper::header::HeaderName::from_static("x-paste-password");
+    assert_json_snapshot!(
+        "paste_get",
+        call(server.get(&paste_url).header(password.clone(), "hunter22")).await
+    );
+    assert_json_snapshot!(
+        "paste_raw",
+        call(
+            server
+                .get(&format!("/p/{}", id))
+                .header(password, "hunter22")
+        )
+        .await
+    );
+    assert_json_snapshot!(
+        "paste_invalid",
+        call(
+            server
+                .post("/api/v1/pastes")
+                .json(&json!({ "content": "x", "expires_in": "2y" }))
+        )
+        .await
+    );
+    let delete_token = hyper::header::HeaderName::from_static("x-delete-token");
+    assert_json_snapshot!(
+        "paste_delete_forbidden",
+        call(
+            server
+                .delete(&paste_url)
+                .header(delete_token.clone(), "wrong")
+        )
+        .await
+    );
+    assert_json_snapshot!(
+        "paste_delete",
+        call(server.delete(&paste_url).header(delete_token, &token)).await
+    );
+    assert_json_snapshot!("paste_not_found", call(server.get(&paste_url)).await);
+
+    // Unresolvable hosts keep the background fetcher away
+    let bookmark = json!({
+        "url": "https://docs.example.invalid/axum",
+        "title": "Axum guide",
+        "description": "Routing and extractors",
+        "tags": ["Rust", "web"]
+    });
+    let created = call(server.post("/api/v1/bookmarks").json(&bookmark)).await;
+    let bookmark_url = format!("/api/v1/bookmarks/{}", created["body"]["id"]);
+    assert_json_snapshot!("bookmark_create", created);
+    assert_json_snapshot!(
+        "bookmark_create_duplicate",
+        call(server.post("/api/v1/bookmarks").json(&bookmark)).await
+    );
+    assert_json_snapshot!(
+        "bookmark_create_invalid",
+        call(
+            server
+                .post("/api/v1/bookmarks")
+                .json(&json!({ "url": "notes.txt" }))
+        )
+        .await
+    );
+    assert_json_snapshot!(
+        "bookmark_import",
+        call(server.post("/api/v1/bookmarks/import").body(
+            r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<DL><p>
+    <DT><H3>Reading</H3>
+    <DL><p>
+        <DT><A HREF="https://blog.example.invalid/" ADD_DATE="1700000000">Blog</A>
+        <DT><A HREF="https://docs.example.invalid/axum">Axum</A>
+    </DL><p>
+</DL><p>"#
+        ))
+        .await
+    );
+    assert_json_snapshot!(
+        "bookmarks_search",
+        call(server.get("/api/v1/bookmarks?q=extractors&tag=rust")).await
+    );
+    assert_json_snapshot!(
+        "bookmark_tags",
+        call(server.get("/api/v1/bookmarks/tags")).await
+    );
+    assert_json_snapshot!(
+        "bookmark_update",
+        call(
+            server
+                .request(hyper::Method::PATCH, &bookmark_url)
+                .json(&json!({ "tags": ["reading"] }))
+        )
+        .await
+    );
+    let export = server.get("/api/v1/bookmarks/export").send().await.unwrap();
+    assert_eq!(export.status, hyper::StatusCode::OK);
+    assert!(export
+        .text()
+        .contains(r#"HREF="https://blog.example.invalid/" ADD_DATE="1700000000""#));
+    assert_json_snapshot!("bookmark_delete", call(server.delete(&bookmark_url)).await);
+    assert_json_snapshot!("bookmark_not_found", call(server.get(&bookmark_url)).await);
+
+    let created = call(server.post("/api/v1/notes").json(&json!({
+        "title": "Deploy checklist",
+        "body": "# Deploy\n\n- [ ] back up\n\n<script>alert(1)</script>",
+        "folder": "work/ops",
+        "tags": ["Todo"]
+    })))
+    .await;
+    let note_url = format!("/api/v1/notes/{}", created["body"]["id"].as_str().unwrap());
+    assert_json_snapshot!("note_create", created);
+    assert_json_snapshot!(
+        "note_create_invalid",
+        call(server.post("/api/v1/notes").json(&json!({ "title": " " }))).await
+    );
+    assert_json_snapshot!(
+        "note_update",
+        call(
+            server
+                .request(hyper::Method::PATCH, &note_url)
+                .json(&json!({ "title": "Release checklist" }))
+        )
+        .await
+    );
+    assert_json_snapshot!(
+        "notes_search",
+        call(server.get("/api/v1/notes?q=deploy&folder=work")).await
+    );
+    assert_json_snapshot!(
+        "note_folders",
+        call(server.get("/api/v1/notes/folders")).await
+    );
+    assert_json_snapshot!(
+        "note_html",
+        call(server.get(&format!("{}/html", note_url))).await
+    );
+    assert_json_snapshot!(
+        "note_revisions",
+        call(server.get(&format!("{}/revisions", note_url))).await
+    );
+    assert_json_snapshot!(
+        "note_revision_restore",
+        call(server.post(&format!("{}/revisions/1/restore", note_url))).await
+    );
+    assert_json_snapshot!("note_delete", call(server.delete(&note_url)).await);
+    assert_json_snapshot!("note_not_found", call(server.get(&note_url)).await);
+    assert_json_snapshot!(
+        "render_markdown",
+        call(server.post("/api/v1/render/markdown").json(&json!({
+            "markdown": "## Usage\n\n```sh\ncargo run\n```\n\n<iframe src=\"https://example.invalid\"></iframe>"
+        })))
+        .await
+    );
+    assert_json_snapshot!(
+        "render_highlight_css_unknown_theme",
+        call(server.get("/api/v1/render/highlight.css?theme=neon")).await
+    );
+    assert_json_snapshot!(
+        "unfurl_invalid",
+        call(server.get("/api/v1/unfurl?url=ftp%3A%2F%2Fexample.invalid%2F")).await
+    );
+
+    // Admin
+    assert_json_snapshot!(
+        "admin_unauthorized",
+        call(server.get("/admin/settings")).await
+    );
+    assert_json_snapshot!(
+        "setting_put",
+        call(
+            server
+                .put("/admin/settings/site.title")
+                .admin()
+                .json(&json!("Home"))
+        )
+        .await
+    );
+    assert_json_snapshot!(
+        "setting_get",
+        call(server.get("/admin/settings/site.title").admin()).await
+    );
+    assert_json_snapshot!(
+        "settings_list",
+        call(server.get("/admin/settings?prefix=site.").admin()).await
+    );
+    assert_json_snapshot!(
+        "setting_delete",
+        call(server.delete("/admin/settings/site.title").admin()).await
+    );
+    assert_json_snapshot!(
+        "setting_not_found",
+        call(server.get("/admin/settings/site.title").admin()).await
+    );
+    let alice = json!({ "username": "alice", "password": "correct horse", "is_admin": true });
+    assert_json_snapshot!(
+        "user_create",
+        call(server.post("/admin/users").admin().json(&alice)).await
+    );
+    assert_json_snapshot!(
+        "user_create_duplicate",
+        call(server.post("/admin/users").admin().json(&alice)).await
+    );
+    assert_json_snapshot!("users_list", call(server.get("/admin/users").admin()).await);
+    assert_json_snapshot!(
+        "user_delete_not_found",
+        call(server.delete("/admin/users/bob").admin()).await
+    );
+    assert_json_snapshot!(
+        "crashes_list",
+        call(server.get("/admin/crashes").admin()).await
+    );
+    assert_json_snapshot!(
+        "crash_not_found",
+        call(
+            server
+                .get("/admin/crashes/00000000-0000-0000-0000-000000000000")
+                .admin()
+        )
+        .await
+    );
+    let link = json!({ "target": "https://example.com/docs", "slug": "docs" });
+    assert_json_snapshot!(
+        "shortlink_create",
+        call(server.post("/admin/shortlinks").admin().json(&link)).await
+    );
+    assert_json_snapshot!(
+        "shortlink_create_duplicate",
+        call(server.post("/admin/shortlinks").admin().json(&link)).await
+    );
+    assert_json_snapshot!(
+        "shortlink_create_invalid",
+        call(server.post("/admin/shortlinks").admin().json(&json!({
+            "target": "javascript:alert(1)"
+        })))
+        .await
+    );
+    let redirect = server
+        .get("/s/docs")
+        .header(hyper::header::REFERER, "https://news.example/")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(redirect.status, hyper::StatusCode::FOUND);
+    assert_eq!(
+        redirect.headers[hyper::header::LOCATION],
+        "https://example.com/docs"
+    );
+    assert_json_snapshot!(
+        "shortlink_stats",
+        call(server.get("/admin/shortlinks/docs/stats").admin()).await
+    );
+    assert_json_snapshot!(
+        "shortlinks_list",
+        call(server.get("/admin/shortlinks").admin()).await
+    );
+    assert_json_snapshot!(
+        "shortlink_delete",
+        call(server.delete("/admin/shortlinks/docs").admin()).await
+    );
+    assert_json_snapshot!("shortlink_not_found", call(server.get("/s/docs")).await);
+
+    // OIDC
+    assert_json_snapshot!(
+        "oidc_discovery",
+        call(server.get("/.well-known/openid-configuration")).await
+    );
+    assert_json_snapshot!("oidc_jwks", call(server.get("/oidc/jwks")).await);
+    assert_json_snapshot!(
+        "oidc_client_register",
+        call(server.post("/admin/oidc/clients").admin().json(&json!({
+            "name": "Wiki",
+            "redirect_uris": ["https://wiki.example.com/callback"]
+        })))
+        .await
+    );
+    assert_json_snapshot!(
+        "oidc_client_register_invalid",
+        call(server.post("/admin/oidc/clients").admin().json(&json!({
+            "name": "Wiki",
+            "redirect_uris": []
+        })))
+        .await
+    );
+    assert_json_snapshot!(
+        "oidc_clients_list",
+        call(server.get("/admin/oidc/clients").admin()).await
+    );
+    assert_json_snapshot!(
+        "oidc_userinfo_unauthorized",
+        call(server.get("/oidc/userinfo")).await
+    );
+
+    // Well-known
+    assert_json_snapshot!(
+        "well_known_webfinger",
+        call(server.get("/.well-known/webfinger?resource=acct:alice@example.com")).await
+    );
+    assert_json_snapshot!(
+        "well_known_webfinger_invalid",
+        call(server.get("/.well-known/webfinger")).await
+    );
+    assert_json_snapshot!(
+        "well_known_oauth_authorization_server",
+        call(server.get("/.well-known/oauth-authorization-server")).await
+    );
+    call(
+        server
+            .put("/admin/settings/well_known.hello.txt")
+            .admin()
+            .json(&json!("hello")),
+    )
+    .await;
+    assert_json_snapshot!(
+        "well_known_setting",
+        call(server.get("/.well-known/hello.txt")).await
+    );
+    assert_json_snapshot!(
+        "well_known_not_found",
+        call(server.get("/.well-known/security.txt")).await
+    );
+
+    server.shutdown().await.unwrap();
+}
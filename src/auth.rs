@@ -0,0 +1,622 @@
+//! Authentication guards.
+//!
+//! Admin endpoints are protected by a static bearer token taken from
+//! `ADMIN_TOKEN`. When no token is configured the admin API is disabled
+//! entirely rather than left open.
+//!
+//! Route groups using the `auth` middleware require one of the API keys
+//! listed in `API_KEYS`, sent as a bearer token, or a request signed by one
+//! of the `HMAC_CLIENTS` (see [`crate::signing`]).
+//!
+//! API keys may be limited with `;`-separated attributes (see
+//! [`parse_api_keys`]): scopes naming the route groups and read/write access
+//! they grant, an expiry time, and the client addresses they are accepted
+//! from, plus the tenant its usage is accounted to (see [`crate::usage`]).
+//! A key lacking the scope a request needs gets a 403 whose
+//! `WWW-Authenticate` challenge names the scope required.
+
+use anyhow::Result;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderValue, Method,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, NaiveDate, Utc};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::crypto::constant_time_eq;
+use crate::error::ApiError;
+use crate::module::RouteGroup;
+use crate::signing::{self, RequestVerifier};
+use crate::t;
+
+/// Token required to access admin endpoints
+#[derive(Debug, Clone, Default)]
+pub struct AdminToken(Option<Arc<str>>);
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> Self {
+        AdminToken(token.filter(|t| !t.is_empty()).map(Arc::from))
+    }
+
+    /// Whether `candidate` is the configured token
+    pub fn matches(&self, candidate: &str) -> bool {
+        self.0
+            .as_deref()
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), candidate.as_bytes()))
+    }
+}
+
+/// Kind of access a request needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// `GET`, `HEAD` and `OPTIONS`
+    Read,
+    /// Every other method
+    Write,
+}
+
+impl Access {
+    pub fn of(method: &Method) -> Self {
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            Access::Read
+        } else {
+            Access::Write
+        }
+    }
+}
+
+/// Permission granted to an API key
+///
+/// Written `*`, `read`, `write`, a route group (`public`, `api`, `admin`)
+/// or a group and access such as `api:read`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scope {
+    /// Group the scope is limited to, or every group
+    pub group: Option<RouteGroup>,
+    /// Access the scope is limited to, or both
+    pub access: Option<Access>,
+}
+
+impl Scope {
+    /// Whether the scope covers a request in `group` needing `access`
+    ///
+    /// A `None` group is the whole app, which only group-less scopes cover.
+    pub fn allows(&self, group: Option<RouteGroup>, access: Access) -> bool {
+        self.group.is_none_or(|g| Some(g) == group) && self.access.is_none_or(|a| a == access)
+    }
+}
+
+fn group_name(group: RouteGroup) -> &'static str {
+    match group {
+        RouteGroup::Public => "public",
+        RouteGroup::Api => "api",
+        RouteGroup::Admin => "admin",
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = self.access.map(|a| match a {
+            Access::Read => "read",
+            Access::Write => "write",
+        });
+        match (self.group.map(group_name), access) {
+            (None, None) => f.write_str("*"),
+            (Some(group), None) => f.write_str(group),
+            (None, Some(access)) => f.write_str(access),
+            (Some(group), Some(access)) => write!(f, "{}:{}", group, access),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let access = |name: &str| match name {
+            "read" => Ok(Access::Read),
+            "write" => Ok(Access::Write),
+            other => anyhow::bail!("unknown access '{}' (expected read or write)", other),
+        };
+        let group = |name: &str| match name {
+            "public" => Ok(RouteGroup::Public),
+            "api" => Ok(RouteGroup::Api),
+            "admin" => Ok(RouteGroup::Admin),
+            other => anyhow::bail!("unknown scope '{}'", other),
+        };
+        Ok(match s.split_once(':') {
+            _ if s == "*" => Scope {
+                group: None,
+                access: None,
+            },
+            Some((g, a)) => Scope {
+                group: Some(group(g)?),
+                access: Some(access(a)?),
+            },
+            None if s == "read" || s == "write" => Scope {
+                group: None,
+                access: Some(access(s)?),
+            },
+            None => Scope {
+                group: Some(group(s)?),
+                access: None,
+            },
+        })
+    }
+}
+
+/// An address or CIDR block an API key is accepted from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid address '{}'", address))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| anyhow::anyhow!("invalid prefix length in '{}'", s))?,
+        };
+        Ok(IpRange { network, prefix })
+    }
+}
+
+/// An API key and the limits on its use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub key: String,
+    /// Scopes granted; an empty list grants everything
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Client addresses accepted; an empty list accepts any
+    pub allowed_ips: Vec<IpRange>,
+    /// Tenant the key's usage is accounted to
+    pub tenant: Option<String>,
+}
+
+impl From<&str> for ApiKey {
+    fn from(key: &str) -> Self {
+        key.to_string().into()
+    }
+}
+
+impl From<String> for ApiKey {
+    fn from(key: String) -> Self {
+        ApiKey {
+            key,
+            scopes: Vec::new(),
+            expires_at: None,
+            allowed_ips: Vec::new(),
+            tenant: None,
+        }
+    }
+}
+
+impl ApiKey {
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Parse comma-separated API keys with optional `;`-separated attributes
+///
+/// For example `k1;scopes=api:read;expires=2027-01-01;ips=10.0.0.0/8,k2`:
+/// `scopes` and `ips` take space-separated lists, `expires` an RFC 3339
+/// time or a date, meaning midnight UTC, and `tenant` a name.
+pub fn parse_api_keys(input: &str) -> Result<Vec<ApiKey>> {
+    let mut keys = Vec::new();
+    for entry in input.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut parts = entry.split(';').map(str::trim);
+        let mut key = ApiKey::from(parts.next().unwrap_or_default());
+        anyhow::ensure!(!key.key.is_empty(), "empty key in '{}'", entry);
+        for attribute in parts.filter(|p| !p.is_empty()) {
+            let (name, value) = attribute
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected name=value, got '{}'", attribute))?;
+            let list = value.split_whitespace();
+            match name.trim() {
+                "scopes" => key.scopes = list.map(str::parse).collect::<Result<_>>()?,
+                "ips" => key.allowed_ips = list.map(str::parse).collect::<Result<_>>()?,
+                "expires" => key.expires_at = Some(parse_date_time(value.trim())?),
+                "tenant" => {
+                    let tenant = value.trim();
+                    anyhow::ensure!(!tenant.is_empty(), "empty tenant in '{}'", entry);
+                    key.tenant = Some(tenant.to_string());
+                }
+                other => anyhow::bail!(
+                    "unknown key attribute '{}' (expected scopes, expires, ips or tenant)",
+                    other
+                ),
+            }
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// Parse an RFC 3339 time or a date, taken as midnight UTC
+pub(crate) fn parse_date_time(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc())
+        .ok_or_else(|| anyhow::anyhow!("invalid time '{}'", value))
+}
+
+/// Why an API key was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyDenied {
+    /// No configured key matches
+    Unknown,
+    Expired,
+    /// The key is not accepted from the client's address
+    Address,
+    /// The key lacks the scope named
+    Scope(Scope),
+}
+
+/// API keys accepted by the `auth` middleware
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys(Arc<[ApiKey]>);
+
+impl ApiKeys {
+    pub fn new(keys: Vec<ApiKey>) -> Self {
+        ApiKeys(keys.into_iter().filter(|k| !k.key.is_empty()).collect())
+    }
+
+    /// The configured key matching `candidate`, compared in constant time
+    fn find(&self, candidate: &str) -> Option<&ApiKey> {
+        self.0.iter().fold(None, |found, key| {
+            match constant_time_eq(key.key.as_bytes(), candidate.as_bytes()) {
+                true => Some(key),
+                false => found,
+            }
+        })
+    }
+
+    /// The tenant of the key matching `candidate`, if it has one
+    pub fn tenant(&self, candidate: &str) -> Option<&str> {
+        self.find(candidate)?.tenant.as_deref()
+    }
+
+    /// Whether `candidate` matches any unexpired key
+    pub fn contains(&self, candidate: &str) -> bool {
+        self.find(candidate)
+            .is_some_and(|key| !key.is_expired_at(Utc::now()))
+    }
+
+    /// Check a key may make a request to `group` needing `access`
+    pub fn authorize(
+        &self,
+        candidate: &str,
+        client: Option<IpAddr>,
+        group: Option<RouteGroup>,
+        access: Access,
+        now: DateTime<Utc>,
+    ) -> Result<&ApiKey, KeyDenied> {
+        let key = self.find(candidate).ok_or(KeyDenied::Unknown)?;
+        if key.is_expired_at(now) {
+            return Err(KeyDenied::Expired);
+        }
+        if !key.allowed_ips.is_empty()
+            && !client.is_some_and(|ip| key.allowed_ips.iter().any(|range| range.contains(ip)))
+        {
+            return Err(KeyDenied::Address);
+        }
+        if !key.scopes.is_empty() && !key.scopes.iter().any(|s| s.allows(group, access)) {
+            return Err(KeyDenied::Scope(Scope {
+                group,
+                access: Some(access),
+            }));
+        }
+        Ok(key)
+    }
+}
+
+/// Credentials accepted by the `auth` middleware
+#[derive(Debug, Clone, Default)]
+pub struct ApiAuth {
+    pub keys: ApiKeys,
+    pub signatures: RequestVerifier,
+    /// Route group guarded, or `None` for the whole app
+    pub group: Option<RouteGroup>,
+}
+
+/// Extract the token from an `Authorization: Bearer <token>` header
+pub fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Generate a random URL-safe token from `bytes` bytes of entropy
+pub fn random_token(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    rand_core::RngCore::fill_bytes(&mut rand_core::OsRng, &mut buf);
+    URL_SAFE_NO_PAD.encode(buf)
+}
+
+/// Middleware rejecting requests without the admin bearer token
+pub async fn require_admin(
+    State(token): State<AdminToken>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if token.0.is_none() {
+        return ApiError::Forbidden(t!("admin-disabled")).into_response();
+    }
+    match bearer_token(&request) {
+        Some(provided) if token.matches(provided) => next.run(request).await,
+        _ => ApiError::Unauthorized(t!("admin-token-invalid")).into_response(),
+    }
+}
+
+/// Middleware rejecting requests without a valid API key or signature
+pub async fn require_api_key(
+    State(auth): State<ApiAuth>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let signed = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(signing::SCHEME));
+    if signed && auth.signatures.is_enabled() {
+        return match auth.signatures.verify(request).await {
+            Ok(request) => next.run(request).await,
+            Err(reason) => ApiError::Unauthorized(reason.into()).into_response(),
+        };
+    }
+    let Some(provided) = bearer_token(&request) else {
+        return ApiError::Unauthorized(t!("api-key-invalid")).into_response();
+    };
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let access = Access::of(request.method());
+    match auth
+        .keys
+        .authorize(provided, client, auth.group, access, Utc::now())
+    {
+        Ok(_) => next.run(request).await,
+        Err(KeyDenied::Unknown) => ApiError::Unauthorized(t!("api-key-invalid")).into_response(),
+        Err(KeyDenied::Expired) => ApiError::Unauthorized(t!("api-key-expired")).into_response(),
+        Err(KeyDenied::Address) => ApiError::Forbidden(t!("api-key-address")).into_response(),
+        Err(KeyDenied::Scope(required)) => {
+            let mut response =
+                ApiError::Forbidden(t!("api-key-scope", scope = required.to_string()))
+                    .into_response();
+            let challenge = format!(r#"Bearer error="insufficient_scope", scope="{}""#, required);
+            if let Ok(value) = HeaderValue::from_str(&challenge) {
+                response.headers_mut().insert(WWW_AUTHENTICATE, value);
+            }
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_keys_contains() {
+        let keys = ApiKeys::new(vec!["alpha".into(), "".into(), "beta".into()]);
+        assert!(keys.contains("beta"));
+        assert!(!keys.contains(""));
+        assert!(!keys.contains("gamma"));
+    }
+
+    #[test]
+    fn test_parse_api_keys() {
+        let keys =
+            parse_api_keys("plain, ro;scopes=api:read admin;expires=2027-01-01;ips=10.0.0.0/8 ::1")
+                .unwrap();
+        assert_eq!(keys[0], ApiKey::from("plain"));
+        assert_eq!(
+            keys[1]
+                .scopes
+                .iter()
+                .map(Scope::to_string)
+                .collect::<Vec<_>>(),
+            ["api:read", "admin"]
+        );
+        assert_eq!(
+            keys[1].expires_at.unwrap().to_rfc3339(),
+            "2027-01-01T00:00:00+00:00"
+        );
+        assert_eq!(keys[1].allowed_ips.len(), 2);
+        let keys = ApiKeys::new(parse_api_keys("k;tenant=acme,other").unwrap());
+        assert_eq!(keys.tenant("k"), Some("acme"));
+        assert_eq!(keys.tenant("other"), None);
+        assert!(parse_api_keys("k;tenant=").is_err());
+
+        assert!(parse_api_keys("k;scopes=api:delete").is_err());
+        assert!(parse_api_keys("k;scopes=everything").is_err());
+        assert!(parse_api_keys("k;expires=soon").is_err());
+        assert!(parse_api_keys("k;ips=10.0.0.0/33").is_err());
+        assert!(parse_api_keys("k;color=red").is_err());
+    }
+
+    #[test]
+    fn test_authorize_scopes_expiry_and_addresses() {
+        let keys = ApiKeys::new(
+            parse_api_keys(
+                "ro;scopes=api:read;ips=10.1.0.0/16,old;expires=2020-01-01T00:00:00Z,any",
+            )
+            .unwrap(),
+        );
+        let now = Utc::now();
+        let inside = Some("10.1.2.3".parse().unwrap());
+        let api = Some(RouteGroup::Api);
+
+        assert!(keys.authorize("ro", inside, api, Access::Read, now).is_ok());
+        assert_eq!(
+            keys.authorize("ro", inside, api, Access::Write, now),
+            Err(KeyDenied::Scope("api:write".parse().unwrap()))
+        );
+        assert_eq!(
+            keys.authorize("ro", inside, None, Access::Read, now),
+            Err(KeyDenied::Scope("read".parse().unwrap()))
+        );
+        let outside = Some("10.2.0.1".parse().unwrap());
+        assert_eq!(
+            keys.authorize("ro", outside, api, Access::Read, now),
+            Err(KeyDenied::Address)
+        );
+        let mapped = Some("::ffff:10.1.0.9".parse().unwrap());
+        assert!(keys.authorize("ro", mapped, api, Access::Read, now).is_ok());
+
+        assert_eq!(
+            keys.authorize("old", None, api, Access::Read, now),
+            Err(KeyDenied::Expired)
+        );
+        assert!(!keys.contains("old"));
+        assert!(keys
+            .authorize("any", None, None, Access::Write, now)
+            .is_ok());
+        assert_eq!(
+            keys.authorize("nope", None, api, Access::Read, now),
+            Err(KeyDenied::Unknown)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_insufficient_scope_challenge() {
+        use axum::{body::Body, middleware, routing::get, Router};
+        use tower::Service;
+
+        let auth = ApiAuth {
+            keys: ApiKeys::new(parse_api_keys("ro;scopes=api:read").unwrap()),
+            group: Some(RouteGroup::Api),
+            ..ApiAuth::default()
+        };
+        let mut app = Router::new()
+            .route("/items", get(|| async { "ok" }).post(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(auth, require_api_key));
+        let request = |method: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/items")
+                .header(AUTHORIZATION, "Bearer ro")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.call(request("GET")).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let response = app.call(request("POST")).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.headers()[WWW_AUTHENTICATE],
+            r#"Bearer error="insufficient_scope", scope="api:write""#
+        );
+    }
+
+    #[test]
+    fn test_admin_token_ignores_empty() {
+        assert!(AdminToken::new(Some(String::new())).0.is_none());
+        assert!(AdminToken::new(Some("t".into())).0.is_some());
+        assert!(!AdminToken::new(None).matches(""));
+    }
+
+    #[tokio::test]
+    async fn test_signed_requests_pass_the_auth_middleware() {
+        use crate::signing::{authorization, SignedBy, SigningClient};
+        use axum::{body::Body, middleware, routing::post, Extension, Router};
+        use tower::Service;
+
+        let client = SigningClient {
+            id: "ci".into(),
+            secret: "shared-secret".into(),
+        };
+        let auth = ApiAuth {
+            keys: ApiKeys::new(vec!["key".into()]),
+            signatures: RequestVerifier::new(
+                vec![client.clone()],
+                std::time::Duration::from_secs(60),
+            ),
+            group: None,
+        };
+        let mut app = Router::new()
+            .route(
+                "/echo",
+                post(
+                    |Extension(SignedBy(id)): Extension<SignedBy>, body: String| async move {
+                        format!("{}:{}", id, body)
+                    },
+                ),
+            )
+            .layer(middleware::from_fn_with_state(auth, require_api_key));
+        let signed = |nonce: &str| {
+            let now = chrono::Utc::now().timestamp();
+            Request::post("/echo?v=1")
+                .header(
+                    AUTHORIZATION,
+                    authorization(&client, "POST", "/echo?v=1", now, nonce, b"hi"),
+                )
+                .body(Body::from("hi"))
+                .unwrap()
+        };
+
+        let response = app.call(signed("a")).await.unwrap();
+        assert_eq!(response.status(), axum::http::StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), 64)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"ci:hi");
+
+        let replayed = app.call(signed("a")).await.unwrap();
+        assert_eq!(replayed.status(), axum::http::StatusCode::UNAUTHORIZED);
+    }
+}
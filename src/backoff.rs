@@ -0,0 +1,98 @@
+//! Backing off repeated failures, such as wrong passwords.
+//!
+//! Each key, say a client address, gets [`FREE_FAILURES`] failures; every
+//! further one doubles how long it has to wait before trying again, up to
+//! [`MAX_BACKOFF`]. Failures are forgotten on success, or after a quiet
+//! spell of [`MAX_BACKOFF`].
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Failures before a key has to wait between tries
+pub const FREE_FAILURES: u32 = 5;
+
+/// Longest wait, also how long failures are counted
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Keys beyond this count trigger eviction of quiet ones
+const MAX_TRACKED: usize = 10_000;
+
+/// Failures per key, with the time of the latest
+#[derive(Debug)]
+pub struct Backoff<K> {
+    failures: Mutex<HashMap<K, (u32, Instant)>>,
+}
+
+impl<K> Default for Backoff<K> {
+    fn default() -> Self {
+        Backoff {
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Hash + Eq> Backoff<K> {
+    /// Seconds `key` has to wait before its next try, if any
+    pub fn blocked(&self, key: &K, now: Instant) -> Option<u64> {
+        let failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let (count, latest) = failures.get(key)?;
+        let excess = count.checked_sub(FREE_FAILURES)?;
+        let wait = Duration::from_secs(1u64 << excess.min(16)).min(MAX_BACKOFF);
+        let remaining = (*latest + wait).saturating_duration_since(now);
+        (!remaining.is_zero()).then(|| remaining.as_secs().max(1))
+    }
+
+    /// Count a failure of `key`
+    pub fn failed(&self, key: K, now: Instant) {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        if failures.len() >= MAX_TRACKED {
+            failures.retain(|_, (_, latest)| now.duration_since(*latest) < MAX_BACKOFF);
+        }
+        let (count, latest) = failures.entry(key).or_insert((0, now));
+        if now.duration_since(*latest) >= MAX_BACKOFF {
+            *count = 0;
+        }
+        *count += 1;
+        *latest = now;
+    }
+
+    /// Forget the failures of `key`
+    pub fn succeeded(&self, key: &K) {
+        self.failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::default();
+        let start = Instant::now();
+        for _ in 0..FREE_FAILURES {
+            assert_eq!(backoff.blocked(&"a", start), None);
+            backoff.failed("a", start);
+        }
+        assert_eq!(backoff.blocked(&"a", start), Some(1));
+        assert_eq!(backoff.blocked(&"a", start + Duration::from_secs(1)), None);
+        assert_eq!(backoff.blocked(&"b", start), None);
+        backoff.failed("a", start);
+        backoff.failed("a", start);
+        assert_eq!(backoff.blocked(&"a", start), Some(4));
+        backoff.succeeded(&"a");
+        assert_eq!(backoff.blocked(&"a", start), None);
+
+        // Failures are forgotten after a quiet spell
+        for _ in 0..FREE_FAILURES {
+            backoff.failed("a", start);
+        }
+        backoff.failed("a", start + MAX_BACKOFF);
+        assert_eq!(backoff.blocked(&"a", start + MAX_BACKOFF), None);
+    }
+}
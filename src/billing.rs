@@ -0,0 +1,750 @@
+//! Stripe billing, for running the server as a small multi-tenant SaaS.
+//!
+//! With `STRIPE_SECRET_KEY` set, each tenant (the `tenant=` attribute of
+//! API keys) is on one of the `BILLING_PLANS`, each with a monthly request
+//! limit. Tenants start on `BILLING_DEFAULT_PLAN`; Stripe moves them:
+//!
+//! | Webhook event                   | Effect                                  |
+//! |---------------------------------|-----------------------------------------|
+//! | `customer.subscription.created` | plan of the price (`STRIPE_PRICES`)     |
+//! | `customer.subscription.updated` | plan and status follow the subscription |
+//! | `customer.subscription.deleted` | back to the default plan                |
+//! | `invoice.payment_failed`        | past due: default plan limits           |
+//! | `invoice.paid`                  | active again                            |
+//!
+//! Subscriptions name their tenant in their `tenant` metadata, which links
+//! the tenant to the Stripe customer. Webhooks are posted to
+//! `/webhooks/stripe` and checked against `STRIPE_WEBHOOK_SECRET`; events
+//! are applied once, and events older than the last one applied to a tenant
+//! are ignored, as Stripe does not deliver them in order.
+//!
+//! Requests of a tenant over its limit get a 429. Limits are checked
+//! against the usage rollups (see [`crate::usage`]), refreshed every 30
+//! seconds, so a tenant may overshoot by a flush interval's worth of
+//! requests. `POST /api/v1/billing/portal` returns a Stripe customer portal
+//! link for the caller's tenant, to change plans or payment details; it
+//! works over the limit too.
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Bytes,
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::post,
+    Router,
+};
+use chrono::{TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use http_body_util::{BodyExt, Full};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::auth::{self, ApiKeys};
+use crate::crypto::{constant_time_eq, hex};
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::route_overrides::covers;
+use crate::urls::PublicUrl;
+use crate::{t, AppState};
+
+/// Header carrying a webhook's signatures
+pub const SIGNATURE_HEADER: &str = "stripe-signature";
+/// How old a signed webhook may be, in seconds
+const SIGNATURE_TOLERANCE: u64 = 300;
+/// How often tenants' standing is reloaded
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Paths a tenant over its limit can still use
+const ALWAYS_ALLOWED: &str = "/api/v1/billing";
+
+/// A plan and its limits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    pub name: String,
+    /// Requests a month; `None` for unlimited
+    pub monthly_requests: Option<i64>,
+}
+
+/// Billing settings, from configuration
+#[derive(Debug, Clone)]
+pub struct BillingConfig {
+    pub secret_key: String,
+    pub webhook_secret: String,
+    /// Plan of each Stripe price id
+    pub prices: HashMap<String, String>,
+    pub plans: Vec<Plan>,
+    pub default_plan: String,
+    /// Where the customer portal sends customers back to, the server's
+    /// public URL by default
+    pub portal_return_url: Option<String>,
+    /// Base URL of the Stripe API
+    pub api_url: String,
+    pub timeout: Duration,
+}
+
+/// Parse comma-separated `name=limit` plans; a plan without a limit is
+/// unlimited, e.g. `free=10000,pro=1000000,enterprise`
+pub fn parse_plans(input: &str) -> Result<Vec<Plan>> {
+    let mut plans: Vec<Plan> = Vec::new();
+    for entry in input.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, limit) = match entry.split_once('=') {
+            Some((name, limit)) => (name.trim(), Some(limit.trim())),
+            None => (entry, None),
+        };
+        anyhow::ensure!(!name.is_empty(), "empty plan name in '{}'", entry);
+        anyhow::ensure!(
+            !plans.iter().any(|plan| plan.name == name),
+            "plan '{}' is listed twice",
+            name
+        );
+        let monthly_requests = limit
+            .map(|limit| {
+                limit
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|limit| *limit >= 0)
+                    .ok_or_else(|| anyhow::anyhow!("invalid limit in '{}'", entry))
+            })
+            .transpose()?;
+        plans.push(Plan {
+            name: name.to_string(),
+            monthly_requests,
+        });
+    }
+    Ok(plans)
+}
+
+/// Parse comma-separated `price_id=plan` pairs
+pub fn parse_prices(input: &str) -> Result<HashMap<String, String>> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            let (price, plan) = entry
+                .split_once('=')
+                .map(|(price, plan)| (price.trim(), plan.trim()))
+                .filter(|(price, plan)| !price.is_empty() && !plan.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("expected price_id=plan, got '{}'", entry))?;
+            Ok((price.to_string(), plan.to_string()))
+        })
+        .collect()
+}
+
+/// The `v1` signature of a payload signed at `timestamp`
+fn signature(secret: &str, timestamp: i64, payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(payload);
+    hex(&mac.finalize().into_bytes())
+}
+
+/// Check a `Stripe-Signature` header, e.g. `t=1700000000,v1=5257a8...`
+pub fn verify_signature(secret: &str, header: &str, payload: &[u8], now: i64) -> bool {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for (name, value) in header.split(',').filter_map(|part| part.split_once('=')) {
+        match name.trim() {
+            "t" => timestamp = value.trim().parse::<i64>().ok(),
+            "v1" => signatures.push(value.trim()),
+            _ => {}
+        }
+    }
+    let Some(timestamp) = timestamp else {
+        return false;
+    };
+    // `t` comes from the unauthenticated request, so it may be anything
+    if now.abs_diff(timestamp) > SIGNATURE_TOLERANCE {
+        return false;
+    }
+    let expected = signature(secret, timestamp, payload);
+    signatures
+        .iter()
+        .any(|candidate| constant_time_eq(candidate.as_bytes(), expected.as_bytes()))
+}
+
+/// Status of a tenant from a Stripe subscription status
+fn status_of(subscription: &str) -> &'static str {
+    match subscription {
+        "active" | "trialing" => "active",
+        "past_due" | "unpaid" => "past_due",
+        "canceled" | "incomplete_expired" => "canceled",
+        _ => "incomplete",
+    }
+}
+
+/// A tenant's limit and usage this month
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Standing {
+    plan: String,
+    limit: Option<i64>,
+    used: i64,
+}
+
+/// Billing state and the middleware enforcing plan limits
+#[derive(Clone)]
+pub struct Billing {
+    config: Arc<BillingConfig>,
+    keys: ApiKeys,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    standings: Arc<RwLock<HashMap<String, Standing>>>,
+}
+
+impl Billing {
+    pub fn new(config: BillingConfig, keys: ApiKeys) -> Self {
+        let https = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Billing {
+            config: Arc::new(config),
+            keys,
+            client: Client::builder(TokioExecutor::new()).build(https),
+            standings: Arc::default(),
+        }
+    }
+
+    fn default_plan(&self) -> &Plan {
+        self.config
+            .plans
+            .iter()
+            .find(|plan| plan.name == self.config.default_plan)
+            .expect("the default plan is checked when configuring")
+    }
+
+    /// The plan whose limits apply to a tenant
+    fn effective_plan(&self, plan: &str, status: &str) -> &Plan {
+        if status == "past_due" {
+            return self.default_plan();
+        }
+        self.config
+            .plans
+            .iter()
+            .find(|candidate| candidate.name == plan)
+            .unwrap_or_else(|| self.default_plan())
+    }
+
+    /// Reload tenants' plans and this month's usage
+    pub async fn refresh(&self, pool: &PgPool) -> Result<()> {
+        let used: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT tenant, sum(requests)::bigint FROM usage_rollups
+             WHERE tenant <> '' AND bucket >= date_trunc('month', now(), 'UTC')
+             GROUP BY tenant",
+        )
+        .fetch_all(pool)
+        .await?;
+        let tenants: Vec<(String, String, String)> =
+            sqlx::query_as("SELECT name, plan, status FROM tenants")
+                .fetch_all(pool)
+                .await?;
+        let mut standings: HashMap<String, Standing> = tenants
+            .into_iter()
+            .map(|(name, plan, status)| {
+                let plan = self.effective_plan(&plan, &status);
+                let standing = Standing {
+                    plan: plan.name.clone(),
+                    limit: plan.monthly_requests,
+                    used: 0,
+                };
+                (name, standing)
+            })
+            .collect();
+        for (tenant, requests) in used {
+            standings
+                .entry(tenant)
+                .or_insert_with(|| Standing {
+                    plan: self.default_plan().name.clone(),
+                    limit: self.default_plan().monthly_requests,
+                    used: 0,
+                })
+                .used = requests;
+        }
+        *self.standings.write().expect("billing lock poisoned") = standings;
+        Ok(())
+    }
+
+    /// The standing of a tenant over its limit
+    fn over_limit(&self, tenant: &str) -> Option<Standing> {
+        self.standings
+            .read()
+            .expect("billing lock poisoned")
+            .get(tenant)
+            .filter(|standing| standing.limit.is_some_and(|limit| standing.used >= limit))
+            .cloned()
+    }
+
+    /// Apply a webhook event, returning whether it changed a tenant
+    pub async fn handle_event(&self, pool: &PgPool, event: &Value) -> Result<bool> {
+        let id = event["id"].as_str().context("Event without an id")?;
+        let kind = event["type"].as_str().unwrap_or_default();
+        let created = event["created"]
+            .as_i64()
+            .and_then(|created| Utc.timestamp_opt(created, 0).single())
+            .context("Event without a creation time")?;
+        let object = &event["data"]["object"];
+        let customer = object["customer"].as_str();
+        let tenant = object["metadata"]["tenant"].as_str();
+
+        let mut tx = pool.begin().await?;
+        let first =
+            sqlx::query("INSERT INTO stripe_events (id) VALUES ($1) ON CONFLICT DO NOTHING")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                > 0;
+        if !first {
+            return Ok(false);
+        }
+        let default_plan = self.config.default_plan.as_str();
+        let changed = match kind {
+            "customer.subscription.created" | "customer.subscription.updated" => {
+                let price = object["items"]["data"][0]["price"]["id"]
+                    .as_str()
+                    .unwrap_or_default();
+                let status = status_of(object["status"].as_str().unwrap_or_default());
+                let plan = match (status, self.config.prices.get(price)) {
+                    ("canceled", _) => default_plan,
+                    (_, Some(plan)) => plan.as_str(),
+                    (_, None) => {
+                        tracing::warn!("💳 Ignoring {} with unknown price '{}'", kind, price);
+                        tx.commit().await?;
+                        return Ok(false);
+                    }
+                };
+                let tenant = match tenant {
+                    Some(tenant) => Some(tenant.to_string()),
+                    None => {
+                        sqlx::query_scalar("SELECT name FROM tenants WHERE stripe_customer_id = $1")
+                            .bind(customer)
+                            .fetch_optional(&mut *tx)
+                            .await?
+                    }
+                };
+                let Some(tenant) = tenant else {
+                    tracing::warn!("💳 Ignoring {} without a tenant in its metadata", kind);
+                    tx.commit().await?;
+                    return Ok(false);
+                };
+                sqlx::query(
+                    "INSERT INTO tenants
+                        (name, plan, status, stripe_customer_id, stripe_subscription_id,
+                         stripe_event_at, updated_at)
+                     VALUES ($1, $2, $3, $4, $5, $6, now())
+                     ON CONFLICT (name) DO UPDATE SET plan = EXCLUDED.plan,
+                        status = EXCLUDED.status,
+                        stripe_customer_id = EXCLUDED.stripe_customer_id,
+                        stripe_subscription_id = EXCLUDED.stripe_subscription_id,
+                        stripe_event_at = EXCLUDED.stripe_event_at, updated_at = now()
+                     WHERE tenants.stripe_event_at IS NULL
+                        OR tenants.stripe_event_at <= EXCLUDED.stripe_event_at",
+                )
+                .bind(&tenant)
+                .bind(plan)
+                .bind(status)
+                .bind(customer)
+                .bind(object["id"].as_str())
+                .bind(created)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                    > 0
+            }
+            "customer.subscription.deleted" => sqlx::query(
+                "UPDATE tenants SET plan = $2, status = 'canceled', stripe_subscription_id = NULL,
+                    stripe_event_at = $3, updated_at = now()
+                 WHERE stripe_subscription_id = $1
+                    AND (stripe_event_at IS NULL OR stripe_event_at <= $3)",
+            )
+            .bind(object["id"].as_str())
+            .bind(default_plan)
+            .bind(created)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+                > 0,
+            "invoice.payment_failed" | "invoice.paid" => {
+                let (from, to) = match kind {
+                    "invoice.payment_failed" => ("active", "past_due"),
+                    _ => ("past_due", "active"),
+                };
+                sqlx::query(
+                    "UPDATE tenants SET status = $3, stripe_event_at = $4, updated_at = now()
+                     WHERE stripe_customer_id = $1 AND status = $2
+                        AND (stripe_event_at IS NULL OR stripe_event_at <= $4)",
+                )
+                .bind(customer)
+                .bind(from)
+                .bind(to)
+                .bind(created)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                    > 0
+            }
+            _ => false,
+        };
+        tx.commit().await?;
+        if changed {
+            tracing::info!("💳 Applied Stripe event {} ({})", id, kind);
+            self.refresh(pool).await?;
+        }
+        Ok(changed)
+    }
+
+    /// Create a customer portal session, returning its URL
+    ///
+    /// Customers are sent back to `STRIPE_PORTAL_RETURN_URL`, or to `public`.
+    pub async fn portal_url(&self, customer: &str, public: &PublicUrl) -> Result<String> {
+        let return_url = match &self.config.portal_return_url {
+            Some(url) => url.clone(),
+            None => public.join("/"),
+        };
+        let form = form_urlencoded::Serializer::new(String::new())
+            .append_pair("customer", customer)
+            .append_pair("return_url", &return_url)
+            .finish();
+        let url = format!(
+            "{}/v1/billing_portal/sessions",
+            self.config.api_url.trim_end_matches('/')
+        );
+        let request = hyper::Request::post(&url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.config.secret_key))
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Full::new(Bytes::from(form)))?;
+        let (status, body) = tokio::time::timeout(self.config.timeout, async {
+            let response = self.client.request(request).await?;
+            let status = response.status();
+            let body = response.into_body().collect().await?.to_bytes();
+            anyhow::Ok((status, body))
+        })
+        .await
+        .with_context(|| format!("POST {} timed out", url))??;
+        let body: Value = serde_json::from_slice(&body).context("Invalid JSON from Stripe")?;
+        anyhow::ensure!(
+            status.is_success(),
+            "Stripe answered {}: {}",
+            status,
+            body["error"]["message"].as_str().unwrap_or("no message")
+        );
+        body["url"]
+            .as_str()
+            .map(String::from)
+            .context("Stripe returned no portal URL")
+    }
+
+    /// Middleware refusing requests of tenants over their plan's limit
+    pub async fn middleware(
+        State(billing): State<Billing>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let over = auth::bearer_token(&request)
+            .and_then(|token| billing.keys.tenant(token))
+            .filter(|_| !covers(ALWAYS_ALLOWED, request.uri().path()))
+            .and_then(|tenant| billing.over_limit(tenant));
+        match over {
+            Some(standing) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(json!({
+                    "error": t!(
+                        "plan-limit",
+                        plan = standing.plan,
+                        limit = standing.limit.unwrap_or_default()
+                    )
+                })),
+            )
+                .into_response(),
+            None => next.run(request).await,
+        }
+    }
+}
+
+/// Spawn the task reloading tenants' standing, once loaded at startup
+pub fn spawn(billing: Billing, pool: PgPool) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval_at(
+            tokio::time::Instant::now() + REFRESH_INTERVAL,
+            REFRESH_INTERVAL,
+        );
+        loop {
+            ticker.tick().await;
+            if let Err(e) = billing.refresh(&pool).await {
+                tracing::warn!("Failed to refresh billing standings: {:#}", e);
+            }
+        }
+    })
+}
+
+/// Route module with the customer portal, mounted with `STRIPE_SECRET_KEY`
+pub struct BillingModule;
+
+impl RouteModule for BillingModule {
+    fn name(&self) -> &'static str {
+        "billing"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/api/v1/billing/portal", post(portal))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_tenants",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS tenants (
+                name TEXT PRIMARY KEY,
+                plan TEXT NOT NULL,
+                status TEXT NOT NULL,
+                stripe_customer_id TEXT UNIQUE,
+                stripe_subscription_id TEXT,
+                stripe_event_at TIMESTAMPTZ,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE TABLE IF NOT EXISTS stripe_events (
+                id TEXT PRIMARY KEY,
+                received_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        }]
+    }
+}
+
+/// Route module receiving Stripe webhooks, mounted with `STRIPE_SECRET_KEY`
+pub struct BillingWebhookModule;
+
+impl RouteModule for BillingWebhookModule {
+    fn name(&self) -> &'static str {
+        "billing_webhook"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/webhooks/stripe", post(webhook))
+    }
+}
+
+/// `POST /webhooks/stripe` - apply a signed Stripe event
+pub async fn webhook(
+    State(state): State<AppState>,
+    Ext(billing): Ext<Billing>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResult<StatusCode> {
+    let signed = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|header| {
+            verify_signature(
+                &billing.config.webhook_secret,
+                header,
+                &body,
+                Utc::now().timestamp(),
+            )
+        });
+    if !signed {
+        return Err(ApiError::BadRequest(t!("stripe-signature-invalid")));
+    }
+    let event: Value = serde_json::from_slice(&body)
+        .map_err(|_| ApiError::BadRequest(t!("stripe-event-invalid")))?;
+    billing.handle_event(state.db.pool(), &event).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /api/v1/billing/portal` - a customer portal link for the caller's
+/// tenant
+pub async fn portal(
+    State(state): State<AppState>,
+    Ext(billing): Ext<Billing>,
+    public: PublicUrl,
+    request: Request,
+) -> ApiResult<Json<Value>> {
+    let tenant = auth::bearer_token(&request)
+        .and_then(|token| billing.keys.tenant(token))
+        .ok_or_else(|| ApiError::Forbidden(t!("billing-no-tenant")))?;
+    let customer: Option<String> =
+        sqlx::query_scalar("SELECT stripe_customer_id FROM tenants WHERE name = $1")
+            .bind(tenant)
+            .fetch_optional(state.db.pool())
+            .await?
+            .flatten();
+    let customer = customer.ok_or_else(|| ApiError::NotFound(t!("billing-no-customer")))?;
+    let url = billing.portal_url(&customer, &public).await.map_err(|e| {
+        tracing::warn!("Creating a Stripe portal session failed: {:#}", e);
+        ApiError::BadGateway(t!("stripe-failed"))
+    })?;
+    Ok(Json(json!({ "url": url })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::parse_api_keys;
+    use crate::module;
+    use crate::usage::UsageModule;
+
+    fn billing() -> Billing {
+        billing_at("http://127.0.0.1:9")
+    }
+
+    fn billing_at(api_url: &str) -> Billing {
+        Billing::new(
+            BillingConfig {
+                secret_key: "sk_test".into(),
+                webhook_secret: "whsec_test".into(),
+                prices: parse_prices("price_pro=pro").unwrap(),
+                plans: parse_plans("free=2,pro").unwrap(),
+                default_plan: "free".into(),
+                portal_return_url: None,
+                api_url: api_url.into(),
+                timeout: Duration::from_secs(5),
+            },
+            ApiKeys::new(parse_api_keys("k;tenant=acme").unwrap()),
+        )
+    }
+
+    fn event(id: &str, kind: &str, created: i64, object: Value) -> Value {
+        json!({ "id": id, "type": kind, "created": created, "data": { "object": object } })
+    }
+
+    #[test]
+    fn test_parses_plans_and_verifies_signatures() {
+        let plans = parse_plans("free=100, pro").unwrap();
+        assert_eq!(plans[0].monthly_requests, Some(100));
+        assert_eq!(plans[1].monthly_requests, None);
+        assert!(parse_plans("free=lots").is_err());
+        assert!(parse_plans("free,free").is_err());
+        assert!(parse_prices("price_1").is_err());
+
+        let payload = br#"{"id":"evt_1"}"#;
+        let now = 1_700_000_000;
+        let header = format!(
+            "t={},v1=deadbeef,v1={}",
+            now,
+            signature("whsec", now, payload)
+        );
+        assert!(verify_signature("whsec", &header, payload, now + 10));
+        assert!(!verify_signature("other", &header, payload, now));
+        assert!(!verify_signature("whsec", &header, b"{}", now));
+        assert!(!verify_signature("whsec", &header, payload, now + 301));
+        assert!(!verify_signature("whsec", "v1=abc", payload, now));
+        let header = format!(
+            "t=-9223372036854775808,v1={}",
+            signature("whsec", i64::MIN, payload)
+        );
+        assert!(!verify_signature("whsec", &header, payload, now));
+    }
+
+    #[tokio::test]
+    async fn test_creates_portal_sessions() {
+        let stripe = Router::new().route(
+            "/v1/billing_portal/sessions",
+            post(|headers: HeaderMap, form: String| async move {
+                assert_eq!(headers[AUTHORIZATION], "Bearer sk_test");
+                match form.as_str() {
+                    "customer=cus_1&return_url=https%3A%2F%2Fexample.com%2Fapp%2F" => (
+                        StatusCode::OK,
+                        Json(json!({ "url": "https://billing.stripe.com/p/session/1" })),
+                    ),
+                    _ => (
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({ "error": { "message": "No such customer" } })),
+                    ),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, stripe).await });
+
+        let billing = billing_at(&format!("http://{}", addr));
+        let public = PublicUrl::parse("https://example.com/app").unwrap();
+        assert_eq!(
+            billing.portal_url("cus_1", &public).await.unwrap(),
+            "https://billing.stripe.com/p/session/1"
+        );
+        let error = billing.portal_url("cus_2", &public).await.unwrap_err();
+        assert!(error.to_string().contains("No such customer"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_webhooks_flip_plans_and_limits() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        module::run_migrations(pool, &[Arc::new(UsageModule), Arc::new(BillingModule)])
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO usage_rollups VALUES (date_trunc('hour', now()), 'token:abc', 'acme', 5, 0, 0, 0, 0)",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        let billing = billing();
+        billing.refresh(pool).await.unwrap();
+        assert_eq!(billing.over_limit("acme").unwrap().plan, "free");
+
+        let subscription = json!({
+            "id": "sub_1", "customer": "cus_1", "status": "active",
+            "metadata": { "tenant": "acme" },
+            "items": { "data": [{ "price": { "id": "price_pro" } }] },
+        });
+        let created = event(
+            "evt_1",
+            "customer.subscription.created",
+            100,
+            subscription.clone(),
+        );
+        assert!(billing.handle_event(pool, &created).await.unwrap());
+        assert!(billing.over_limit("acme").is_none());
+        // Redelivered events are applied once
+        assert!(!billing.handle_event(pool, &created).await.unwrap());
+
+        let failed = event(
+            "evt_2",
+            "invoice.payment_failed",
+            200,
+            json!({ "customer": "cus_1" }),
+        );
+        assert!(billing.handle_event(pool, &failed).await.unwrap());
+        assert_eq!(billing.over_limit("acme").unwrap().plan, "free");
+        let paid = event("evt_3", "invoice.paid", 300, json!({ "customer": "cus_1" }));
+        assert!(billing.handle_event(pool, &paid).await.unwrap());
+        assert!(billing.over_limit("acme").is_none());
+
+        let deleted = event(
+            "evt_4",
+            "customer.subscription.deleted",
+            500,
+            json!({ "id": "sub_1", "customer": "cus_1" }),
+        );
+        // Arriving late, an older event is ignored
+        let stale = event("evt_5", "customer.subscription.updated", 400, subscription);
+        assert!(billing.handle_event(pool, &deleted).await.unwrap());
+        assert!(!billing.handle_event(pool, &stale).await.unwrap());
+        let (plan, status): (String, String) =
+            sqlx::query_as("SELECT plan, status FROM tenants WHERE name = 'acme'")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        assert_eq!((plan.as_str(), status.as_str()), ("free", "canceled"));
+        assert!(billing.over_limit("acme").is_some());
+    }
+}
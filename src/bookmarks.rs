@@ -0,0 +1,868 @@
+//! Bookmark manager.
+//!
+//! Bookmarks live under `/api/v1/bookmarks`:
+//!
+//! - `GET /api/v1/bookmarks?q=rust+axum&tag=reading` lists bookmarks, newest
+//!   first, or by relevance when `q` searches titles and descriptions
+//!   (`websearch_to_tsquery` syntax: quoted phrases, `or`, `-excluded`)
+//! - `POST /api/v1/bookmarks` `{"url": "...", "title": "...", "tags": ["rust"]}`
+//! - `GET`, `PATCH` and `DELETE /api/v1/bookmarks/:id`
+//! - `GET /api/v1/bookmarks/tags` counts bookmarks per tag
+//! - `GET /api/v1/bookmarks/export` and `POST /api/v1/bookmarks/import`
+//!   speak the Netscape bookmark file format browsers import and export;
+//!   imported folders become tags
+//!
+//! New bookmarks are queued for fetching: a background worker claims
+//! pending rows with `FOR UPDATE SKIP LOCKED`, so replicas share the work,
+//! fetches each page through the SSRF-guarded [`Fetcher`] and fills in the
+//! page title (unless one was given) and favicon. Failed fetches are
+//! retried with a growing delay before the bookmark is marked `failed`;
+//! `POST /api/v1/bookmarks/:id/fetch` queues one again.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::time::{Duration, Instant};
+
+use crate::error::{ApiError, ApiResult};
+use crate::fetch::{is_web_url, FetchedPage, Fetcher};
+use crate::html;
+use crate::jobs::{JobStats, QueueReading};
+use crate::module::{Migration, MigrationKind, RouteModule};
+use crate::tags;
+use crate::usage::{Subject, UsageRecorder};
+use crate::{t, AppState};
+
+/// Longest title kept
+const MAX_TITLE_LEN: usize = 512;
+
+/// Longest description kept
+const MAX_DESCRIPTION_LEN: usize = 4096;
+
+/// How often the worker looks for bookmarks to fetch
+const FETCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bookmarks fetched per round
+const FETCH_BATCH: i64 = 10;
+
+/// Job type of fetching bookmarks, as reported at `/admin/jobs/stats`
+pub const FETCH_JOB: &str = "bookmark_fetch";
+
+/// Attempts before a bookmark is marked `failed`
+const MAX_FETCH_ATTEMPTS: i32 = 3;
+
+/// Delay before a retry, multiplied by the attempts so far
+const FETCH_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Most of a page read when looking for its title and icon
+const MAX_PAGE_BYTES: usize = 256 * 1024;
+
+const COLUMNS: &str = "id, url, title, description, tags, favicon_url, fetch_status, \
+                       fetch_error, created_at, updated_at";
+
+/// A stored bookmark
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Bookmark {
+    pub id: i64,
+    pub url: String,
+    pub title: Option<String>,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub favicon_url: Option<String>,
+    /// `pending`, `done` or `failed`
+    pub fetch_status: String,
+    pub fetch_error: Option<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Route module for bookmarks
+pub struct BookmarksModule;
+
+impl RouteModule for BookmarksModule {
+    fn name(&self) -> &'static str {
+        "bookmarks"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route(
+                "/api/v1/bookmarks",
+                get(list_bookmarks).post(create_bookmark),
+            )
+            .route("/api/v1/bookmarks/tags", get(list_tags))
+            .route("/api/v1/bookmarks/export", get(export_bookmarks))
+            .route("/api/v1/bookmarks/import", post(import_bookmarks))
+            .route(
+                "/api/v1/bookmarks/:id",
+                get(get_bookmark)
+                    .patch(update_bookmark)
+                    .delete(delete_bookmark),
+            )
+            .route("/api/v1/bookmarks/:id/fetch", post(refetch_bookmark))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_bookmarks",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS bookmarks (
+                id BIGSERIAL PRIMARY KEY,
+                url TEXT NOT NULL UNIQUE,
+                title TEXT,
+                description TEXT NOT NULL DEFAULT '',
+                tags TEXT[] NOT NULL DEFAULT '{}',
+                favicon_url TEXT,
+                fetch_status TEXT NOT NULL DEFAULT 'pending',
+                fetch_error TEXT,
+                fetch_attempts INTEGER NOT NULL DEFAULT 0,
+                next_fetch_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                fetched_at TIMESTAMPTZ,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                search TSVECTOR GENERATED ALWAYS AS (
+                    to_tsvector('simple', coalesce(title, '') || ' ' || description)
+                ) STORED
+            );
+            CREATE INDEX IF NOT EXISTS bookmarks_search ON bookmarks USING GIN (search);
+            CREATE INDEX IF NOT EXISTS bookmarks_tags ON bookmarks USING GIN (tags);
+            CREATE INDEX IF NOT EXISTS bookmarks_pending ON bookmarks (next_fetch_at)
+                WHERE fetch_status = 'pending'",
+        }]
+    }
+}
+
+fn check_text(title: Option<&str>, description: Option<&str>) -> ApiResult<()> {
+    if title.is_some_and(|title| title.chars().count() > MAX_TITLE_LEN) {
+        return Err(ApiError::Validation(t!(
+            "bookmark-title",
+            max = MAX_TITLE_LEN
+        )));
+    }
+    if description.is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_LEN) {
+        return Err(ApiError::Validation(t!(
+            "bookmark-description",
+            max = MAX_DESCRIPTION_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    text.chars().take(max).collect()
+}
+
+/// A bookmark about to be created
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct NewBookmark {
+    pub url: String,
+    /// Fetched from the page when left out
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Check and store a bookmark, returning `None` if its URL is already saved
+pub async fn create(pool: &PgPool, new: &NewBookmark) -> ApiResult<Option<Bookmark>> {
+    if !is_web_url(&new.url) {
+        return Err(ApiError::Validation(t!(
+            "bookmark-url",
+            url = new.url.as_str()
+        )));
+    }
+    let title = new
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|t| !t.is_empty());
+    check_text(title, Some(&new.description))?;
+    let tags = tags::normalize_all(&new.tags)?;
+    let bookmark = sqlx::query_as::<_, Bookmark>(&format!(
+        "INSERT INTO bookmarks (url, title, description, tags) VALUES ($1, $2, $3, $4)
+         ON CONFLICT (url) DO NOTHING
+         RETURNING {COLUMNS}"
+    ))
+    .bind(&new.url)
+    .bind(title)
+    .bind(&new.description)
+    .bind(&tags)
+    .fetch_optional(pool)
+    .await?;
+    Ok(bookmark)
+}
+
+/// Look up a bookmark
+pub async fn find(pool: &PgPool, id: i64) -> ApiResult<Bookmark> {
+    sqlx::query_as::<_, Bookmark>(&format!("SELECT {COLUMNS} FROM bookmarks WHERE id = $1"))
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(t!("bookmark-not-found")))
+}
+
+/// Filters of a bookmark listing
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BookmarkQuery {
+    /// Full-text search over titles and descriptions
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+impl Default for BookmarkQuery {
+    fn default() -> Self {
+        BookmarkQuery {
+            q: None,
+            tag: None,
+            limit: default_limit(),
+            offset: 0,
+        }
+    }
+}
+
+/// Bookmarks matching `query`, best matches or newest first
+pub async fn search(pool: &PgPool, query: &BookmarkQuery) -> ApiResult<Vec<Bookmark>> {
+    let text = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let mut sql: QueryBuilder<Postgres> =
+        QueryBuilder::new(format!("SELECT {COLUMNS} FROM bookmarks WHERE true"));
+    if let Some(text) = text {
+        sql.push(" AND search @@ websearch_to_tsquery('simple', ")
+            .push_bind(text)
+            .push(")");
+    }
+    if let Some(tag) = &query.tag {
+        let tag = tags::normalize(tag).unwrap_or_default();
+        sql.push(" AND tags @> ARRAY[")
+            .push_bind(tag)
+            .push("]::text[]");
+    }
+    match text {
+        Some(text) => sql
+            .push(" ORDER BY ts_rank(search, websearch_to_tsquery('simple', ")
+            .push_bind(text)
+            .push(")) DESC, created_at DESC, id DESC"),
+        None => sql.push(" ORDER BY created_at DESC, id DESC"),
+    };
+    sql.push(" LIMIT ")
+        .push_bind(query.limit.clamp(1, 1000))
+        .push(" OFFSET ")
+        .push_bind(query.offset.max(0));
+    Ok(sql.build_query_as().fetch_all(pool).await?)
+}
+
+/// `GET /api/v1/bookmarks` - list or search bookmarks
+pub async fn list_bookmarks(
+    State(state): State<AppState>,
+    Query(query): Query<BookmarkQuery>,
+) -> ApiResult<Json<Vec<Bookmark>>> {
+    Ok(Json(search(state.db.pool(), &query).await?))
+}
+
+/// `POST /api/v1/bookmarks` - save a bookmark
+pub async fn create_bookmark(
+    State(state): State<AppState>,
+    Json(body): Json<NewBookmark>,
+) -> ApiResult<(StatusCode, Json<Bookmark>)> {
+    create(state.db.pool(), &body)
+        .await?
+        .map(|bookmark| (StatusCode::CREATED, Json(bookmark)))
+        .ok_or_else(|| ApiError::Validation(t!("bookmark-exists", url = body.url.as_str())))
+}
+
+/// `GET /api/v1/bookmarks/:id` - one bookmark
+pub async fn get_bookmark(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> ApiResult<Json<Bookmark>> {
+    Ok(Json(find(state.db.pool(), id).await?))
+}
+
+/// Changes to a bookmark; fields left out are kept
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BookmarkUpdate {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+/// `PATCH /api/v1/bookmarks/:id` - change a bookmark's title, description or tags
+pub async fn update_bookmark(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(body): Json<BookmarkUpdate>,
+) -> ApiResult<Json<Bookmark>> {
+    check_text(body.title.as_deref(), body.description.as_deref())?;
+    let tags = body.tags.as_deref().map(tags::normalize_all).transpose()?;
+    sqlx::query_as::<_, Bookmark>(&format!(
+        "UPDATE bookmarks SET title = COALESCE($2, title),
+            description = COALESCE($3, description),
+            tags = COALESCE($4, tags),
+            updated_at = now()
+         WHERE id = $1
+         RETURNING {COLUMNS}"
+    ))
+    .bind(id)
+    .bind(body.title.as_deref().map(str::trim))
+    .bind(&body.description)
+    .bind(tags)
+    .fetch_optional(state.db.pool())
+    .await?
+    .map(Json)
+    .ok_or_else(|| ApiError::NotFound(t!("bookmark-not-found")))
+}
+
+/// `DELETE /api/v1/bookmarks/:id` - remove a bookmark
+pub async fn delete_bookmark(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> ApiResult<StatusCode> {
+    let result = sqlx::query("DELETE FROM bookmarks WHERE id = $1")
+        .bind(id)
+        .execute(state.db.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(t!("bookmark-not-found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /api/v1/bookmarks/:id/fetch` - fetch a bookmark's title and icon again
+pub async fn refetch_bookmark(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> ApiResult<(StatusCode, Json<Bookmark>)> {
+    sqlx::query_as::<_, Bookmark>(&format!(
+        "UPDATE bookmarks SET fetch_status = 'pending', fetch_error = NULL,
+            fetch_attempts = 0, next_fetch_at = now()
+         WHERE id = $1
+         RETURNING {COLUMNS}"
+    ))
+    .bind(id)
+    .fetch_optional(state.db.pool())
+    .await?
+    .map(|bookmark| (StatusCode::ACCEPTED, Json(bookmark)))
+    .ok_or_else(|| ApiError::NotFound(t!("bookmark-not-found")))
+}
+
+/// Bookmarks carrying a tag
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: i64,
+}
+
+/// `GET /api/v1/bookmarks/tags` - tags by number of bookmarks
+pub async fn list_tags(State(state): State<AppState>) -> ApiResult<Json<Vec<TagCount>>> {
+    let tags = sqlx::query_as::<_, TagCount>(
+        "SELECT tag, count(*) AS count FROM bookmarks, unnest(tags) AS tag
+         GROUP BY tag ORDER BY count DESC, tag",
+    )
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(tags))
+}
+
+/// A bookmark read from a Netscape bookmark file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedBookmark {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub added: Option<DateTime<Utc>>,
+}
+
+/// Read the bookmarks of a Netscape bookmark file
+///
+/// Tags come from the `TAGS` attribute and the folders a bookmark is in,
+/// except the toolbar and "other bookmarks" folders browsers always add.
+pub fn parse_netscape(document: &str) -> Vec<ImportedBookmark> {
+    let token = Regex::new(r"(?is)<h3\b([^>]*)>(.*?)</h3>|</dl>|<a\b([^>]*)>(.*?)</a>|<dd>([^<]*)")
+        .expect("valid pattern");
+    let mut folders: Vec<Option<String>> = Vec::new();
+    let mut bookmarks: Vec<ImportedBookmark> = Vec::new();
+    let mut after_link = false;
+    for captures in token.captures_iter(document) {
+        if let Some(name) = captures.get(2) {
+            let attributes = html::attributes(&captures[1]);
+            let builtin = ["personal_toolbar_folder", "unfiled_bookmarks_folder"]
+                .iter()
+                .any(|key| attributes.contains_key(*key));
+            folders.push(
+                (!builtin)
+                    .then(|| tags::normalize(&html::unescape(name.as_str())))
+                    .flatten(),
+            );
+            after_link = false;
+        } else if let Some(text) = captures.get(4) {
+            let mut attributes = html::attributes(&captures[3]);
+            let url = attributes.remove("href").unwrap_or_default();
+            let title = html::unescape(text.as_str()).trim().to_string();
+            let mut tags: Vec<String> = folders.iter().flatten().cloned().collect();
+            for tag in attributes
+                .get("tags")
+                .map_or("", String::as_str)
+                .split(',')
+                .filter_map(tags::normalize)
+            {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+            tags.truncate(tags::MAX_TAGS);
+            let added = attributes
+                .get("add_date")
+                .and_then(|date| date.parse::<i64>().ok())
+                .and_then(|seconds| Utc.timestamp_opt(seconds, 0).single());
+            bookmarks.push(ImportedBookmark {
+                url,
+                title: (!title.is_empty()).then(|| truncate(&title, MAX_TITLE_LEN)),
+                description: String::new(),
+                tags,
+                added,
+            });
+            after_link = true;
+        } else if let Some(description) = captures.get(5) {
+            if let Some(bookmark) = bookmarks.last_mut().filter(|_| after_link) {
+                let description = html::unescape(description.as_str()).trim().to_string();
+                bookmark.description = truncate(&description, MAX_DESCRIPTION_LEN);
+            }
+            after_link = false;
+        } else {
+            folders.pop();
+            after_link = false;
+        }
+    }
+    bookmarks
+}
+
+/// Write bookmarks as a Netscape bookmark file
+pub fn to_netscape(bookmarks: &[Bookmark]) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n\
+         <META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n\
+         <TITLE>Bookmarks</TITLE>\n\
+         <H1>Bookmarks</H1>\n\
+         <DL><p>\n",
+    );
+    for bookmark in bookmarks {
+        out.push_str("    <DT><A HREF=\"");
+        html::escape_into(&bookmark.url, &mut out);
+        out.push_str(&format!(
+            "\" ADD_DATE=\"{}\" LAST_MODIFIED=\"{}\"",
+            bookmark.created_at.timestamp(),
+            bookmark.updated_at.timestamp()
+        ));
+        if !bookmark.tags.is_empty() {
+            out.push_str(" TAGS=\"");
+            html::escape_into(&bookmark.tags.join(","), &mut out);
+            out.push('"');
+        }
+        out.push('>');
+        html::escape_into(bookmark.title.as_deref().unwrap_or(&bookmark.url), &mut out);
+        out.push_str("</A>\n");
+        if !bookmark.description.is_empty() {
+            out.push_str("    <DD>");
+            html::escape_into(&bookmark.description, &mut out);
+            out.push('\n');
+        }
+    }
+    out.push_str("</DL><p>\n");
+    out
+}
+
+/// `GET /api/v1/bookmarks/export` - every bookmark as a Netscape bookmark file
+pub async fn export_bookmarks(State(state): State<AppState>) -> ApiResult<Response> {
+    let bookmarks = sqlx::query_as::<_, Bookmark>(&format!(
+        "SELECT {COLUMNS} FROM bookmarks ORDER BY created_at, id"
+    ))
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok((
+        [
+            (CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"bookmarks.html\"",
+            ),
+        ],
+        to_netscape(&bookmarks),
+    )
+        .into_response())
+}
+
+/// Outcome of an import
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub imported: u64,
+    /// Bookmarks already saved or without an http(s) URL
+    pub skipped: u64,
+}
+
+/// Store imported bookmarks, skipping URLs already saved
+pub async fn import(pool: &PgPool, bookmarks: &[ImportedBookmark]) -> ApiResult<ImportReport> {
+    let mut report = ImportReport {
+        imported: 0,
+        skipped: 0,
+    };
+    let mut tx = pool.begin().await?;
+    for bookmark in bookmarks {
+        if !is_web_url(&bookmark.url) {
+            report.skipped += 1;
+            continue;
+        }
+        let result = sqlx::query(
+            "INSERT INTO bookmarks (url, title, description, tags, created_at, updated_at)
+             VALUES ($1, $2, $3, $4, COALESCE($5, now()), COALESCE($5, now()))
+             ON CONFLICT (url) DO NOTHING",
+        )
+        .bind(&bookmark.url)
+        .bind(&bookmark.title)
+        .bind(&bookmark.description)
+        .bind(&bookmark.tags)
+        .bind(bookmark.added)
+        .execute(&mut *tx)
+        .await?;
+        match result.rows_affected() {
+            0 => report.skipped += 1,
+            _ => report.imported += 1,
+        }
+    }
+    tx.commit().await?;
+    Ok(report)
+}
+
+/// `POST /api/v1/bookmarks/import` - add the bookmarks of a Netscape bookmark file
+pub async fn import_bookmarks(
+    State(state): State<AppState>,
+    body: String,
+) -> ApiResult<Json<ImportReport>> {
+    let bookmarks = parse_netscape(&body);
+    if bookmarks.is_empty() {
+        return Err(ApiError::Validation(t!("bookmark-import-empty")));
+    }
+    Ok(Json(import(state.db.pool(), &bookmarks).await?))
+}
+
+/// The title and icon of a fetched page
+fn page_details(page: &FetchedPage) -> (Option<String>, String) {
+    let title = page
+        .is_html()
+        .then(|| html::title(&page.text()))
+        .flatten()
+        .map(|title| truncate(&title, MAX_TITLE_LEN));
+    (title, page.icon_url())
+}
+
+/// Fetch a batch of pending bookmarks, returning how many were attempted
+///
+/// Every attempt is counted in `jobs` as a [`FETCH_JOB`].
+pub async fn fetch_pending(
+    pool: &PgPool,
+    fetcher: &Fetcher,
+    jobs: &JobStats,
+) -> anyhow::Result<usize> {
+    // Claiming pushes next_fetch_at out, so a worker dying mid-fetch only
+    // delays the bookmark until its retry
+    let claimed: Vec<(i64, String, i32)> = sqlx::query_as(
+        "UPDATE bookmarks SET fetch_attempts = fetch_attempts + 1,
+            next_fetch_at = now() + make_interval(secs => $2 * (fetch_attempts + 1))
+         WHERE id IN (
+            SELECT id FROM bookmarks
+            WHERE fetch_status = 'pending' AND next_fetch_at <= now()
+            ORDER BY next_fetch_at LIMIT $1
+            FOR UPDATE SKIP LOCKED
+         )
+         RETURNING id, url, fetch_attempts",
+    )
+    .bind(FETCH_BATCH)
+    .bind(FETCH_BACKOFF.as_secs_f64())
+    .fetch_all(pool)
+    .await?;
+
+    let mut fetches = tokio::task::JoinSet::new();
+    for (id, url, attempts) in claimed {
+        let fetcher = fetcher.clone();
+        fetches.spawn(async move {
+            let result = fetcher.get(&url, MAX_PAGE_BYTES).await.and_then(|page| {
+                anyhow::ensure!(page.status.is_success(), "{} answered {}", url, page.status);
+                Ok(page_details(&page))
+            });
+            (id, attempts, result)
+        });
+    }
+    let mut attempted = 0;
+    while let Some(joined) = fetches.join_next().await {
+        let (id, attempts, result) = joined?;
+        attempted += 1;
+        jobs.record(FETCH_JOB, result.is_ok());
+        match result {
+            Ok((title, icon)) => {
+                sqlx::query(
+                    "UPDATE bookmarks SET title = COALESCE(title, $2), favicon_url = $3,
+                        fetch_status = 'done', fetch_error = NULL, fetched_at = now()
+                     WHERE id = $1",
+                )
+                .bind(id)
+                .bind(title)
+                .bind(icon)
+                .execute(pool)
+                .await?;
+            }
+            Err(e) => {
+                tracing::debug!("Fetching bookmark {} failed: {:#}", id, e);
+                let status = if attempts >= MAX_FETCH_ATTEMPTS {
+                    "failed"
+                } else {
+                    "pending"
+                };
+                sqlx::query(
+                    "UPDATE bookmarks SET fetch_status = $2, fetch_error = $3, fetched_at = now()
+                     WHERE id = $1",
+                )
+                .bind(id)
+                .bind(status)
+                .bind(format!("{:#}", e))
+                .execute(pool)
+                .await?;
+            }
+        }
+    }
+    Ok(attempted)
+}
+
+/// Number of bookmarks waiting to be fetched
+pub async fn queue_depth(pool: &PgPool) -> sqlx::Result<i64> {
+    sqlx::query_scalar("SELECT count(*) FROM bookmarks WHERE fetch_status = 'pending'")
+        .fetch_one(pool)
+        .await
+}
+
+/// Bookmarks waiting to be fetched, and how long the oldest has waited
+pub async fn queue_reading(pool: &PgPool) -> sqlx::Result<QueueReading> {
+    let (depth, oldest_age_secs): (i64, Option<f64>) = sqlx::query_as(
+        "SELECT count(*), EXTRACT(EPOCH FROM now() - min(created_at))::float8
+         FROM bookmarks WHERE fetch_status = 'pending'",
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(QueueReading {
+        depth,
+        oldest_age_secs,
+    })
+}
+
+/// Spawn the background task fetching titles and icons of new bookmarks
+///
+/// With a usage recorder, time spent on batches is accounted to
+/// `system:bookmark-fetch`.
+pub fn spawn(
+    pool: PgPool,
+    fetcher: Fetcher,
+    usage: Option<UsageRecorder>,
+    jobs: JobStats,
+) -> tokio::task::JoinHandle<()> {
+    jobs.register(FETCH_JOB);
+    tokio::spawn(async move {
+        let subject = Subject::system("bookmark-fetch");
+        let mut ticker = tokio::time::interval(FETCH_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let started = Instant::now();
+            match fetch_pending(&pool, &fetcher, &jobs).await {
+                Ok(0) => {}
+                Ok(_) => {
+                    if let Some(usage) = &usage {
+                        usage.record_job(&subject, started.elapsed());
+                    }
+                }
+                Err(e) => tracing::warn!("Fetching bookmarks failed: {:#}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETSCAPE: &str = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<TITLE>Bookmarks</TITLE>
+<DL><p>
+    <DT><H3 PERSONAL_TOOLBAR_FOLDER="true">Bookmarks bar</H3>
+    <DL><p>
+        <DT><A HREF="https://www.rust-lang.org/" ADD_DATE="1700000000" TAGS="lang,Systems Programming">Rust &amp; friends</A>
+        <DD>The Rust language
+        <DT><H3>Read Later</H3>
+        <DD>Folder notes
+        <DL><p>
+            <DT><A HREF="https://docs.rs/axum">axum</A>
+        </DL><p>
+        <DT><A HREF="javascript:void(0)">Bookmarklet</A>
+    </DL><p>
+</DL><p>"#;
+
+    #[test]
+    fn test_parse_netscape() {
+        let bookmarks = parse_netscape(NETSCAPE);
+        assert_eq!(bookmarks.len(), 3);
+        assert_eq!(bookmarks[0].url, "https://www.rust-lang.org/");
+        assert_eq!(bookmarks[0].title.as_deref(), Some("Rust & friends"));
+        assert_eq!(bookmarks[0].description, "The Rust language");
+        assert_eq!(bookmarks[0].tags, ["lang", "systems-programming"]);
+        assert_eq!(bookmarks[0].added.unwrap().timestamp(), 1_700_000_000);
+        assert_eq!(bookmarks[1].tags, ["read-later"]);
+        assert_eq!(bookmarks[1].description, "");
+        assert!(bookmarks[2].tags.is_empty());
+    }
+
+    #[test]
+    fn test_netscape_round_trip() {
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let bookmark = Bookmark {
+            id: 1,
+            url: "https://example.com/?a=1&b=2".into(),
+            title: Some("<Example>".into()),
+            description: "Tom & Jerry".into(),
+            tags: vec!["a".into(), "b".into()],
+            favicon_url: None,
+            fetch_status: "done".into(),
+            fetch_error: None,
+            created_at: now,
+            updated_at: now,
+        };
+        let exported = to_netscape(&[bookmark]);
+        assert!(exported.contains("HREF=\"https://example.com/?a=1&amp;b=2\""));
+        let imported = parse_netscape(&exported);
+        assert_eq!(
+            imported,
+            [ImportedBookmark {
+                url: "https://example.com/?a=1&b=2".into(),
+                title: Some("<Example>".into()),
+                description: "Tom & Jerry".into(),
+                tags: vec!["a".into(), "b".into()],
+                added: Some(now),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_and_fetch() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+
+        let page = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    axum::response::Html(
+                        "<html><head><title>Local Page</title>\
+                         <link rel=\"shortcut icon\" href=\"/static/icon.png\"></head></html>",
+                    )
+                }),
+            )
+            .route("/missing", get(|| async { StatusCode::NOT_FOUND }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, page).await });
+
+        let new = |url: String, title: Option<&str>, tags: &[&str]| NewBookmark {
+            url,
+            title: title.map(String::from),
+            description: "notes about web servers".into(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+        };
+        let fetched = create(pool, &new(format!("{}/", base), None, &["Web"]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetched.tags, ["web"]);
+        let named = new(format!("{}/missing", base), Some("Axum Guide"), &[]);
+        let missing = create(pool, &named).await.unwrap().unwrap();
+        assert!(create(pool, &named).await.unwrap().is_none());
+        assert!(matches!(
+            create(pool, &new("ftp://example.com".into(), None, &[])).await,
+            Err(ApiError::Validation(_))
+        ));
+
+        let query = |q: &str, tag: Option<&str>| BookmarkQuery {
+            q: Some(q.into()),
+            tag: tag.map(String::from),
+            limit: 10,
+            ..BookmarkQuery::default()
+        };
+        let found = search(pool, &query("axum", None)).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, missing.id);
+        assert_eq!(
+            search(pool, &query("servers", None)).await.unwrap().len(),
+            2
+        );
+        assert_eq!(
+            search(pool, &query("", Some("WEB"))).await.unwrap().len(),
+            1
+        );
+
+        // Only public hosts are fetched unless private networks are allowed
+        let guarded = Fetcher::new(Duration::from_secs(2), false);
+        let jobs = JobStats::default();
+        assert_eq!(fetch_pending(pool, &guarded, &jobs).await.unwrap(), 2);
+        assert_eq!(queue_reading(pool).await.unwrap().depth, 2);
+        assert_eq!(
+            find(pool, fetched.id).await.unwrap().fetch_status,
+            "pending"
+        );
+        sqlx::query("UPDATE bookmarks SET next_fetch_at = now()")
+            .execute(pool)
+            .await
+            .unwrap();
+
+        let fetcher = Fetcher::new(Duration::from_secs(2), true);
+        assert_eq!(fetch_pending(pool, &fetcher, &jobs).await.unwrap(), 2);
+        let fetched = find(pool, fetched.id).await.unwrap();
+        assert_eq!(fetched.fetch_status, "done");
+        assert_eq!(fetched.title.as_deref(), Some("Local Page"));
+        assert_eq!(
+            fetched.favicon_url,
+            Some(format!("{}/static/icon.png", base))
+        );
+        let missing = find(pool, missing.id).await.unwrap();
+        assert_eq!(missing.fetch_status, "pending");
+        assert_eq!(missing.title.as_deref(), Some("Axum Guide"));
+        assert!(missing.fetch_error.unwrap().contains("404"));
+        let counted = jobs.stats(pool).await.unwrap();
+        assert_eq!(counted[FETCH_JOB].processed_total, 4);
+        assert_eq!(counted[FETCH_JOB].failed_total, 3);
+        assert_eq!(counted[FETCH_JOB].queue.depth, 1);
+    }
+}
@@ -0,0 +1,347 @@
+//! Canary routing.
+//!
+//! With `CANARY=true`, `CANARY_PERCENT` of requests under `CANARY_PATHS`
+//! are served by the canary variant; a request can also pick its variant
+//! with the `CANARY_HEADER` header or `CANARY_COOKIE` cookie, set to `1`
+//! (canary) or `0` (stable). With `CANARY_UPSTREAM` set, canary requests are
+//! forwarded to that instance; otherwise they are served here and handlers
+//! choose their implementation with the [`Variant`] extractor.
+//!
+//! Responses carry `X-Canary-Variant`, and requests, server errors and
+//! latency are counted per variant at `GET /admin/canary`, so the two can be
+//! compared before a rollout.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::extensions::Ext;
+use crate::module::{RouteGroup, RouteModule};
+use crate::proxy::{Proxy, ProxyRoute};
+use crate::route_overrides::covers;
+use crate::{faults, sessions, AppState};
+
+/// Response header naming the variant that served a request
+pub const VARIANT_HEADER: HeaderName = HeaderName::from_static("x-canary-variant");
+
+/// Canary settings, from configuration
+#[derive(Debug, Clone)]
+pub struct CanaryConfig {
+    /// Percentage of requests sent to the canary
+    pub percent: f64,
+    /// Path prefixes eligible for the canary; empty for all
+    pub paths: Vec<String>,
+    /// Header choosing the variant
+    pub header: HeaderName,
+    /// Cookie choosing the variant
+    pub cookie: String,
+    /// Base URL of a canary instance, e.g. `http://10.0.0.6:3000`
+    pub upstream: Option<String>,
+    /// How long forwarded requests may take
+    pub timeout: Duration,
+}
+
+/// Which implementation serves a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    Stable,
+    Canary,
+}
+
+impl Variant {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Variant::Stable => "stable",
+            Variant::Canary => "canary",
+        }
+    }
+
+    /// Parse a variant chosen by a header or cookie
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "1" | "true" | "canary" => Some(Variant::Canary),
+            "0" | "false" | "stable" => Some(Variant::Stable),
+            _ => None,
+        }
+    }
+}
+
+/// The request's variant; [`Variant::Stable`] without canary routing
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Variant {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Variant>()
+            .copied()
+            .unwrap_or(Variant::Stable))
+    }
+}
+
+/// Counters for one variant
+#[derive(Debug, Default, Serialize)]
+pub struct VariantStats {
+    pub requests: AtomicU64,
+    /// Responses with a 5xx status
+    pub server_errors: AtomicU64,
+    /// Sum of response times, for the mean
+    #[serde(skip)]
+    latency_micros: AtomicU64,
+}
+
+impl VariantStats {
+    fn record(&self, response: &Response, elapsed: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if response.status().is_server_error() {
+            self.server_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> serde_json::Value {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let server_errors = self.server_errors.load(Ordering::Relaxed);
+        let latency = self.latency_micros.load(Ordering::Relaxed);
+        let ratio = |value: u64| (requests > 0).then(|| value as f64 / requests as f64);
+        serde_json::json!({
+            "requests": requests,
+            "server_errors": server_errors,
+            "error_rate": ratio(server_errors),
+            "mean_latency_ms": ratio(latency).map(|micros| micros / 1000.0),
+        })
+    }
+}
+
+/// Shared canary state and the middleware routing requests
+#[derive(Clone)]
+pub struct Canary {
+    config: Arc<CanaryConfig>,
+    /// Forwards to the canary instance, if there is one
+    upstream: Option<(Proxy, Arc<ProxyRoute>)>,
+    stable: Arc<VariantStats>,
+    canary: Arc<VariantStats>,
+}
+
+impl Canary {
+    pub fn new(config: CanaryConfig) -> Self {
+        let upstream = config.upstream.as_ref().map(|upstream| {
+            let route = ProxyRoute {
+                prefix: String::new(),
+                upstream: upstream.clone(),
+                timeout: None,
+            };
+            (Proxy::new(Vec::new(), config.timeout), Arc::new(route))
+        });
+        Canary {
+            config: Arc::new(config),
+            upstream,
+            stable: Arc::default(),
+            canary: Arc::default(),
+        }
+    }
+
+    /// Counters of a variant since startup
+    pub fn stats(&self, variant: Variant) -> &VariantStats {
+        match variant {
+            Variant::Stable => &self.stable,
+            Variant::Canary => &self.canary,
+        }
+    }
+
+    /// The variant serving a request, or `None` if it is not eligible
+    fn choose(&self, request: &Request) -> Option<Variant> {
+        let path = request.uri().path();
+        if !self.config.paths.is_empty()
+            && !self.config.paths.iter().any(|prefix| covers(prefix, path))
+        {
+            return None;
+        }
+        let headers = request.headers();
+        let chosen = headers
+            .get(&self.config.header)
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| sessions::cookie(headers, &self.config.cookie))
+            .and_then(Variant::parse);
+        Some(chosen.unwrap_or_else(|| {
+            if faults::random_unit() * 100.0 < self.config.percent {
+                Variant::Canary
+            } else {
+                Variant::Stable
+            }
+        }))
+    }
+
+    /// Middleware routing a share of requests to the canary
+    pub async fn middleware(
+        State(canary): State<Canary>,
+        mut request: Request,
+        next: Next,
+    ) -> Response {
+        let Some(variant) = canary.choose(&request) else {
+            return next.run(request).await;
+        };
+        let started = Instant::now();
+        let mut response = match (&canary.upstream, variant) {
+            (Some((proxy, route)), Variant::Canary) => proxy
+                .forward(route, request)
+                .await
+                .unwrap_or_else(IntoResponse::into_response),
+            _ => {
+                request.extensions_mut().insert(variant);
+                next.run(request).await
+            }
+        };
+        canary.stats(variant).record(&response, started.elapsed());
+        response
+            .headers_mut()
+            .insert(VARIANT_HEADER, HeaderValue::from_static(variant.as_str()));
+        response
+    }
+}
+
+/// Route module reporting canary counters, mounted with `CANARY=true`
+pub struct CanaryModule;
+
+impl RouteModule for CanaryModule {
+    fn name(&self) -> &'static str {
+        "canary"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/admin/canary", get(status))
+    }
+}
+
+/// `GET /admin/canary` - how traffic is split and how each variant fares
+pub async fn status(Ext(canary): Ext<Canary>) -> Json<serde_json::Value> {
+    let config = &canary.config;
+    Json(serde_json::json!({
+        "percent": config.percent,
+        "paths": config.paths,
+        "header": config.header.as_str(),
+        "cookie": config.cookie,
+        "upstream": config.upstream,
+        "variants": {
+            "stable": canary.stats(Variant::Stable).report(),
+            "canary": canary.stats(Variant::Canary).report(),
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware};
+    use http_body_util::BodyExt;
+    use tokio::net::TcpListener;
+    use tower::Service;
+
+    fn config(percent: f64, upstream: Option<String>) -> CanaryConfig {
+        CanaryConfig {
+            percent,
+            paths: vec!["/api".into()],
+            header: HeaderName::from_static("x-canary"),
+            cookie: "canary".into(),
+            upstream,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    async fn body(response: Response) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_header_cookie_and_percentage() {
+        let canary = Canary::new(config(0.0, None));
+        let handler = |variant: Variant| async move { variant.as_str() };
+        let mut app = Router::new()
+            .route("/api/items", get(handler))
+            .route("/health", get(handler))
+            .layer(middleware::from_fn_with_state(
+                canary.clone(),
+                Canary::middleware,
+            ));
+        let mut send = |uri: &str, header: Option<(&'static str, &'static str)>| {
+            let mut request = Request::get(uri);
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            app.call(request.body(Body::empty()).unwrap())
+        };
+
+        let response = send("/api/items", None).await.unwrap();
+        assert_eq!(response.headers()[&VARIANT_HEADER], "stable");
+        assert_eq!(body(response).await, "stable");
+        let response = send("/api/items", Some(("x-canary", "1"))).await.unwrap();
+        assert_eq!(response.headers()[&VARIANT_HEADER], "canary");
+        assert_eq!(body(response).await, "canary");
+        let response = send("/api/items", Some(("cookie", "canary=canary")))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, "canary");
+        let response = send("/health", Some(("x-canary", "1"))).await.unwrap();
+        assert!(response.headers().get(&VARIANT_HEADER).is_none());
+        assert_eq!(body(response).await, "stable");
+
+        assert_eq!(
+            canary
+                .stats(Variant::Stable)
+                .requests
+                .load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(
+            canary
+                .stats(Variant::Canary)
+                .requests
+                .load(Ordering::Relaxed),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forwards_canary_requests_upstream() {
+        let upstream = Router::new().route(
+            "/api/items",
+            get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "from the canary") }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let canary = Canary::new(config(100.0, Some(format!("http://{}", addr))));
+        let mut app = Router::new()
+            .route("/api/items", get(|| async { "local" }))
+            .layer(middleware::from_fn_with_state(
+                canary.clone(),
+                Canary::middleware,
+            ));
+        let request = Request::get("/api/items").body(Body::empty()).unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.headers()[&VARIANT_HEADER], "canary");
+        assert_eq!(body(response).await, "from the canary");
+        let stats = canary.stats(Variant::Canary);
+        assert_eq!(stats.server_errors.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,518 @@
+//! Request capture and replay, for debugging regressions.
+//!
+//! With `CAPTURE_DIR` set, a `CAPTURE_SAMPLE_RATE` fraction of requests is
+//! recorded with their responses to `CAPTURE_DIR/requests-<date>.jsonl`,
+//! one [`Exchange`] per line. Credentials are masked: headers carrying
+//! them (`Authorization`, `Cookie`, paste passwords, ...) entirely, members
+//! of JSON bodies named like credentials (`password`, `access_token`, ...)
+//! through [`redact::redact_json`], and everything else through [`redact`]. Bodies are kept up to
+//! `CAPTURE_MAX_BODY` bytes, as text when they are UTF-8 and base64
+//! otherwise; larger or streamed bodies are passed through unrecorded.
+//!
+//! `rust-selfhost-server replay <file>` re-sends the recorded requests in
+//! order to another instance and reports responses whose status differs.
+//! Masked headers are not sent; supply working credentials with `--header`.
+//!
+//! Captures hold real user data even after redaction; this is meant for
+//! development and staging, not for routine use in production.
+
+use anyhow::{Context, Result};
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::Engine;
+use chrono::{DateTime, SubsecRound, Utc};
+use http_body_util::Full;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::http_client::HttpClient;
+use crate::{crashes, faults, pastes, redact};
+
+/// Exchanges buffered for the writer; more are dropped
+const QUEUE_LEN: usize = 1024;
+
+/// Headers recorded as [`redact::MASK`] whatever their value
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    pastes::PASSWORD_HEADER,
+    pastes::DELETE_TOKEN_HEADER,
+];
+
+/// Headers describing one connection or message, never replayed
+const HOP_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "content-length",
+    "transfer-encoding",
+    "keep-alive",
+    "upgrade",
+];
+
+/// Capture settings, from configuration
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    pub dir: PathBuf,
+    /// Fraction of requests recorded, from 0 to 1
+    pub sample_rate: f64,
+    /// Largest body recorded, in bytes
+    pub max_body: u64,
+}
+
+/// A request or response, as recorded
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+    /// The body was too large or streamed, and is missing
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub body_omitted: bool,
+}
+
+impl Message {
+    fn new(headers: &HeaderMap, body: Option<&[u8]>) -> Self {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                    redact::MASK.to_string()
+                } else {
+                    redact::redact(&String::from_utf8_lossy(value.as_bytes())).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+        let mut message = Message {
+            headers,
+            body_omitted: body.is_none(),
+            ..Message::default()
+        };
+        match body.map(std::str::from_utf8) {
+            Some(Ok("")) | None => {}
+            Some(Ok(text)) => {
+                let text = match serde_json::from_str::<serde_json::Value>(text) {
+                    Ok(mut value) if value.is_object() || value.is_array() => {
+                        redact::redact_json(&mut value);
+                        value.to_string()
+                    }
+                    _ => text.to_string(),
+                };
+                message.body = Some(redact::redact(&text).into_owned())
+            }
+            Some(Err(_)) => {
+                message.body_base64 =
+                    body.map(|body| base64::engine::general_purpose::STANDARD.encode(body))
+            }
+        }
+        message
+    }
+
+    /// The recorded body bytes
+    pub fn body_bytes(&self) -> Result<Bytes> {
+        Ok(match (&self.body, &self.body_base64) {
+            (Some(text), _) => Bytes::from(text.clone()),
+            (None, Some(encoded)) => Bytes::from(
+                base64::engine::general_purpose::STANDARD
+                    .decode(encoded)
+                    .context("Invalid body_base64")?,
+            ),
+            (None, None) => Bytes::new(),
+        })
+    }
+}
+
+/// One recorded request with its response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub captured_at: DateTime<Utc>,
+    pub method: String,
+    /// Path and query
+    pub uri: String,
+    pub request: Message,
+    pub status: u16,
+    pub response: Message,
+    pub duration_ms: u64,
+}
+
+/// Shared recorder and the middleware feeding it
+#[derive(Clone)]
+pub struct Recorder {
+    config: Arc<CaptureConfig>,
+    sender: mpsc::Sender<Exchange>,
+}
+
+impl Recorder {
+    /// Start the task appending exchanges to the capture files
+    pub fn spawn(config: CaptureConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(write_exchanges(config.dir.clone(), receiver));
+        Recorder {
+            config: Arc::new(config),
+            sender,
+        }
+    }
+
+    /// Middleware recording a sample of requests and their responses
+    pub async fn middleware(
+        State(recorder): State<Recorder>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        if faults::random_unit() >= recorder.config.sample_rate {
+            return next.run(request).await;
+        }
+        let started = Instant::now();
+        let captured_at = Utc::now().trunc_subsecs(3);
+        let request_id = crashes::current_request_id();
+        let (parts, body) = request.into_parts();
+        let (body, request_body) = match buffer(body, recorder.config.max_body).await {
+            Ok(buffered) => buffered,
+            Err(e) => {
+                tracing::debug!("Failed to read request body for capture: {}", e);
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        };
+        let method = parts.method.to_string();
+        let uri = redact::redact(&parts.uri.to_string()).into_owned();
+        let request_message = Message::new(&parts.headers, request_body.as_deref());
+
+        let response = next.run(Request::from_parts(parts, body)).await;
+        let (parts, body) = response.into_parts();
+        let (body, response_body) = match buffer(body, recorder.config.max_body).await {
+            Ok(buffered) => buffered,
+            Err(e) => {
+                tracing::debug!("Failed to read response body for capture: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let exchange = Exchange {
+            request_id,
+            captured_at,
+            method,
+            uri,
+            request: request_message,
+            status: parts.status.as_u16(),
+            response: Message::new(&parts.headers, response_body.as_deref()),
+            duration_ms: started.elapsed().as_millis() as u64,
+        };
+        if recorder.sender.try_send(exchange).is_err() {
+            tracing::debug!("Capture queue full, dropping an exchange");
+        }
+        Response::from_parts(parts, body)
+    }
+}
+
+/// Read a body of known size up to `max` bytes, leaving others untouched
+pub(crate) async fn buffer(body: Body, max: u64) -> Result<(Body, Option<Bytes>), axum::Error> {
+    match body.size_hint().exact() {
+        Some(len) if len <= max => {
+            let bytes = axum::body::to_bytes(body, max as usize).await?;
+            Ok((Body::from(bytes.clone()), Some(bytes)))
+        }
+        _ => Ok((body, None)),
+    }
+}
+
+/// The capture file for a day
+fn file_for(dir: &Path, at: DateTime<Utc>) -> PathBuf {
+    dir.join(format!("requests-{}.jsonl", at.format("%Y-%m-%d")))
+}
+
+async fn write_exchanges(dir: PathBuf, mut receiver: mpsc::Receiver<Exchange>) {
+    if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+        tracing::warn!(
+            "Failed to create capture directory {}: {}",
+            dir.display(),
+            e
+        );
+    }
+    while let Some(exchange) = receiver.recv().await {
+        let path = file_for(&dir, exchange.captured_at);
+        let result = async {
+            let mut line = serde_json::to_vec(&exchange)?;
+            line.push(b'\n');
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            file.write_all(&line).await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            tracing::warn!("Failed to write capture to {}: {:#}", path.display(), e);
+        }
+    }
+}
+
+/// Read the exchanges in a capture file
+pub fn read(path: &Path) -> Result<Vec<Exchange>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid exchange on line {}", index + 1))
+        })
+        .collect()
+}
+
+/// How a replayed request compared with its recording
+#[derive(Debug, Clone, Serialize)]
+pub struct Replayed {
+    pub method: String,
+    pub uri: String,
+    pub recorded_status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Whether the body matched, when both bodies are known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_matches: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl Replayed {
+    /// Whether the status code was reproduced
+    pub fn matches(&self) -> bool {
+        self.status == Some(self.recorded_status)
+    }
+}
+
+/// Re-sends recorded requests to another instance
+pub struct Replayer {
+    base_url: String,
+    client: HttpClient,
+    headers: HeaderMap,
+}
+
+impl Replayer {
+    /// `headers` are sent with every request, replacing recorded ones
+    pub fn new(base_url: &str, client: HttpClient, headers: HeaderMap) -> Self {
+        Replayer {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client,
+            headers,
+        }
+    }
+
+    /// Send one recorded request and compare the response
+    pub async fn replay(&self, exchange: &Exchange) -> Replayed {
+        let started = Instant::now();
+        let mut replayed = Replayed {
+            method: exchange.method.clone(),
+            uri: exchange.uri.clone(),
+            recorded_status: exchange.status,
+            status: None,
+            body_matches: None,
+            error: None,
+            duration_ms: 0,
+        };
+        match self.send(exchange).await {
+            Ok((status, body)) => {
+                replayed.status = Some(status.as_u16());
+                let recorded = &exchange.response;
+                if !recorded.body_omitted {
+                    let actual = Message::new(&HeaderMap::new(), Some(&body));
+                    replayed.body_matches = Some(
+                        actual.body == recorded.body && actual.body_base64 == recorded.body_base64,
+                    );
+                }
+            }
+            Err(e) => replayed.error = Some(format!("{:#}", e)),
+        }
+        replayed.duration_ms = started.elapsed().as_millis() as u64;
+        replayed
+    }
+
+    async fn send(&self, exchange: &Exchange) -> Result<(StatusCode, Bytes)> {
+        anyhow::ensure!(
+            !exchange.request.body_omitted,
+            "request body was not recorded"
+        );
+        let method = Method::from_bytes(exchange.method.as_bytes())?;
+        let mut request = hyper::Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.base_url, exchange.uri));
+        let headers = request.headers_mut().context("Invalid request")?;
+        for (name, value) in &exchange.request.headers {
+            if value == redact::MASK || HOP_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        for (name, value) in &self.headers {
+            headers.insert(name, value.clone());
+        }
+        let request = request.body(Full::new(exchange.request.body_bytes()?))?;
+        let (status, _, body) = self.client.send(request).await?;
+        Ok((status, body))
+    }
+}
+
+/// Parse a `--header` argument such as `Authorization: Bearer abc`
+pub fn parse_header(input: &str) -> Result<(HeaderName, HeaderValue)> {
+    let (name, value) = input
+        .split_once(':')
+        .with_context(|| format!("Expected 'Name: value', got '{}'", input))?;
+    Ok((
+        HeaderName::from_bytes(name.trim().as_bytes()).context("Invalid header name")?,
+        HeaderValue::from_str(value.trim()).context("Invalid header value")?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::post, Router};
+    use tower::Service;
+
+    #[tokio::test]
+    async fn test_records_redacted_exchanges() {
+        let dir = std::env::temp_dir().join(format!("capture-{}", std::process::id()));
+        let recorder = Recorder::spawn(CaptureConfig {
+            dir: dir.clone(),
+            sample_rate: 1.0,
+            max_body: 1024,
+        });
+        let mut app = Router::new()
+            .route("/echo", post(|body: Bytes| async move { body }))
+            .layer(middleware::from_fn_with_state(
+                recorder.clone(),
+                Recorder::middleware,
+            ));
+        let response = app
+            .call(
+                Request::post("/echo?token=abcdef")
+                    .header("authorization", "Bearer secret-token")
+                    .header("content-type", "text/plain")
+                    .body(Body::from("hello"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        assert_eq!(body, "hello");
+        app.call(
+            Request::post("/echo")
+                .body(Body::from(vec![0xff; 2048]))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        drop(recorder);
+        let path = file_for(&dir, Utc::now());
+        let mut exchanges = Vec::new();
+        for _ in 0..50 {
+            exchanges = read(&path).unwrap_or_default();
+            if exchanges.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(exchanges.len(), 2);
+        let first = &exchanges[0];
+        assert_eq!(first.uri, "/echo?token=***");
+        assert_eq!(first.status, 200);
+        assert!(first
+            .request
+            .headers
+            .contains(&("authorization".to_string(), "***".to_string())));
+        assert_eq!(first.request.body.as_deref(), Some("hello"));
+        assert_eq!(first.response.body.as_deref(), Some("hello"));
+        assert!(exchanges[1].request.body_omitted);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_masks_login_credentials() {
+        let dir = std::env::temp_dir().join(format!("capture-login-{}", std::process::id()));
+        let recorder = Recorder::spawn(CaptureConfig {
+            dir: dir.clone(),
+            sample_rate: 1.0,
+            max_body: 1024,
+        });
+        let mut app = Router::new()
+            .route(
+                "/api/v1/session",
+                post(|| async {
+                    axum::Json(serde_json::json!({
+                        "user": {"username": "alice"},
+                        "access_token": "issued-session-token"
+                    }))
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                recorder.clone(),
+                Recorder::middleware,
+            ));
+        app.call(
+            Request::post("/api/v1/session")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    r#"{"username":"alice","password":"correct-horse-battery"}"#,
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        drop(recorder);
+        let path = file_for(&dir, Utc::now());
+        let mut text = String::new();
+        for _ in 0..50 {
+            text = std::fs::read_to_string(&path).unwrap_or_default();
+            if !text.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(text.contains("alice"));
+        assert!(!text.contains("correct-horse-battery"));
+        assert!(!text.contains("issued-session-token"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_binary_bodies_round_trip() {
+        let message = Message::new(&HeaderMap::new(), Some(&[0xff, 0x00]));
+        assert_eq!(message.body, None);
+        assert_eq!(
+            message.body_bytes().unwrap(),
+            Bytes::from_static(&[0xff, 0x00])
+        );
+        assert!(parse_header("X-Test: 1").is_ok());
+        assert!(parse_header("no colon").is_err());
+    }
+}
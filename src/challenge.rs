@@ -0,0 +1,374 @@
+//! Proof-of-work challenges for abuse-prone endpoints.
+//!
+//! Routes listed in `CHALLENGE_ROUTES` (e.g. login, registration,
+//! password reset or search) only accept requests carrying a solved
+//! challenge. A request without one is answered with
+//! `428 Precondition Required` and a fresh challenge, in the body and the
+//! `X-Challenge` header:
+//!
+//! ```text
+//! {"error": "...", "challenge": "<token>", "difficulty": 20, "expires_at": 1700000300}
+//! ```
+//!
+//! The client finds a `solution` such that the SHA-256 of
+//! `<token>:<solution>` starts with `difficulty` zero bits (see [`solve`]),
+//! and repeats the request with `X-Challenge: <token>` and
+//! `X-Challenge-Solution: <solution>`. Each extra bit doubles the expected
+//! work; 20 bits take well under a second in a browser.
+//!
+//! Tokens are HMAC-signed rather than stored, so any replica sharing
+//! `CHALLENGE_SECRET` can check them, and are valid for `CHALLENGE_TTL`.
+//! Solved tokens are remembered per process until they expire, so each
+//! is accepted once per replica.
+
+use anyhow::Result;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::crypto::hex;
+
+/// Header carrying the challenge token, in both directions
+pub const CHALLENGE_HEADER: &str = "x-challenge";
+
+/// Header carrying the client's solution
+pub const SOLUTION_HEADER: &str = "x-challenge-solution";
+
+/// Highest difficulty a route may ask for
+pub const MAX_DIFFICULTY: u8 = 32;
+
+/// Longest solution accepted
+const MAX_SOLUTION_LEN: usize = 64;
+
+/// A route requiring a solved challenge
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChallengeRoute {
+    /// Method the rule applies to, or any
+    pub method: Option<Method>,
+    /// Path prefix the rule applies to
+    pub prefix: String,
+    /// Leading zero bits required
+    pub difficulty: u8,
+}
+
+impl ChallengeRoute {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method)
+            && path
+                .strip_prefix(self.prefix.trim_end_matches('/'))
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// Parse comma-separated `[METHOD ]/prefix[=difficulty]` rules
+pub fn parse_routes(input: &str, default_difficulty: u8) -> Result<Vec<ChallengeRoute>> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (route, difficulty) = match entry.rsplit_once('=') {
+                Some((route, bits)) => (
+                    route.trim(),
+                    bits.trim()
+                        .parse::<u8>()
+                        .map_err(|e| anyhow::anyhow!("invalid difficulty in '{}': {}", entry, e))?,
+                ),
+                None => (entry, default_difficulty),
+            };
+            if difficulty == 0 || difficulty > MAX_DIFFICULTY {
+                anyhow::bail!(
+                    "difficulty in '{}' must be between 1 and {}",
+                    entry,
+                    MAX_DIFFICULTY
+                );
+            }
+            let (method, prefix) = match route.split_once(' ') {
+                Some((method, prefix)) => (
+                    Some(
+                        method
+                            .to_ascii_uppercase()
+                            .parse::<Method>()
+                            .map_err(|_| anyhow::anyhow!("invalid method in '{}'", entry))?,
+                    ),
+                    prefix.trim(),
+                ),
+                None => (None, route),
+            };
+            if !prefix.starts_with('/') {
+                anyhow::bail!("path in '{}' must start with '/'", entry);
+            }
+            Ok(ChallengeRoute {
+                method,
+                prefix: prefix.to_string(),
+                difficulty,
+            })
+        })
+        .collect()
+}
+
+/// Leading zero bits of a hash
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn meets(token: &str, solution: &str, difficulty: u8) -> bool {
+    let hash = Sha256::digest(format!("{}:{}", token, solution).as_bytes());
+    leading_zero_bits(&hash) >= u32::from(difficulty)
+}
+
+/// Find a solution to a challenge, as a client would
+pub fn solve(token: &str, difficulty: u8) -> String {
+    (0u64..)
+        .map(|n| n.to_string())
+        .find(|solution| meets(token, solution, difficulty))
+        .expect("a solution exists")
+}
+
+/// Issues and checks challenges for the configured routes
+#[derive(Debug, Clone)]
+pub struct ChallengeGuard {
+    routes: Arc<[ChallengeRoute]>,
+    secret: Arc<[u8]>,
+    ttl: Duration,
+    /// Solved tokens, with their expiry
+    solved: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl ChallengeGuard {
+    /// Create a guard, signing tokens with `secret` or a random per-process key
+    pub fn new(routes: Vec<ChallengeRoute>, secret: Option<&str>, ttl: Duration) -> Self {
+        let secret = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut key = vec![0u8; 32];
+                rand_core::RngCore::fill_bytes(&mut rand_core::OsRng, &mut key);
+                key
+            }
+        };
+        ChallengeGuard {
+            routes: routes.into(),
+            secret: secret.into(),
+            ttl,
+            solved: Arc::default(),
+        }
+    }
+
+    /// Difficulty required for a request, if any rule matches
+    pub fn difficulty(&self, method: &Method, path: &str) -> Option<u8> {
+        self.routes
+            .iter()
+            .filter(|route| route.matches(method, path))
+            .map(|route| route.difficulty)
+            .max()
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// A signed `<expires>.<difficulty>.<salt>.<mac>` token issued at `now`
+    pub fn issue_at(&self, difficulty: u8, now: i64) -> (String, i64) {
+        let mut salt = [0u8; 12];
+        rand_core::RngCore::fill_bytes(&mut rand_core::OsRng, &mut salt);
+        let expires = now + self.ttl.as_secs() as i64;
+        let payload = format!("{}.{}.{}", expires, difficulty, hex(&salt));
+        let mac = hex(&self.mac(&payload).finalize().into_bytes());
+        (format!("{}.{}", payload, mac), expires)
+    }
+
+    /// Check a solved token against the difficulty a route requires
+    pub fn verify_at(
+        &self,
+        token: &str,
+        solution: &str,
+        difficulty: u8,
+        now: i64,
+    ) -> Result<(), &'static str> {
+        let (payload, mac) = token.rsplit_once('.').ok_or("malformed challenge")?;
+        let mac = (0..mac.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(mac.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or("malformed challenge")?;
+        self.mac(payload)
+            .verify_slice(&mac)
+            .map_err(|_| "invalid challenge")?;
+        let mut fields = payload.split('.');
+        let expires: i64 = fields
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or("malformed challenge")?;
+        let issued_difficulty: u8 = fields
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or("malformed challenge")?;
+        if now > expires {
+            return Err("challenge expired");
+        }
+        if issued_difficulty < difficulty {
+            return Err("challenge too easy for this route");
+        }
+        if solution.is_empty()
+            || solution.len() > MAX_SOLUTION_LEN
+            || !meets(token, solution, issued_difficulty)
+        {
+            return Err("wrong solution");
+        }
+
+        let mut solved = self.solved.lock().expect("challenge lock poisoned");
+        solved.retain(|_, expires| *expires >= now);
+        if solved.contains_key(token) {
+            return Err("challenge already used");
+        }
+        solved.insert(token.to_string(), expires);
+        Ok(())
+    }
+
+    /// Check the challenge headers of a request
+    fn check(&self, headers: &HeaderMap, difficulty: u8, now: i64) -> Result<(), &'static str> {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        match (header(CHALLENGE_HEADER), header(SOLUTION_HEADER)) {
+            (Some(token), Some(solution)) => self.verify_at(token, solution, difficulty, now),
+            _ => Err("solve the challenge and retry"),
+        }
+    }
+
+    /// Middleware requiring a solved challenge on the configured routes
+    pub async fn middleware(
+        State(guard): State<ChallengeGuard>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let Some(difficulty) = guard.difficulty(request.method(), request.uri().path()) else {
+            return next.run(request).await;
+        };
+        let now = chrono::Utc::now().timestamp();
+        let error = match guard.check(request.headers(), difficulty, now) {
+            Ok(()) => return next.run(request).await,
+            Err(e) => e,
+        };
+
+        let (token, expires_at) = guard.issue_at(difficulty, now);
+        let mut response = (
+            StatusCode::PRECONDITION_REQUIRED,
+            Json(json!({
+                "error": error,
+                "challenge": token,
+                "difficulty": difficulty,
+                "expires_at": expires_at,
+            })),
+        )
+            .into_response();
+        if let Ok(value) = HeaderValue::from_str(&token) {
+            response.headers_mut().insert(CHALLENGE_HEADER, value);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware::from_fn_with_state, routing::post, Router};
+    use tower::Service;
+
+    #[test]
+    fn test_parse_routes() {
+        let routes = parse_routes("POST /oidc/authorize=12, /search", 18).unwrap();
+        assert_eq!(routes[0].method, Some(Method::POST));
+        assert_eq!(routes[0].difficulty, 12);
+        assert_eq!(routes[1].method, None);
+        assert_eq!(routes[1].difficulty, 18);
+        assert!(parse_routes("/x=40", 18).is_err());
+        assert!(parse_routes("search", 18).is_err());
+
+        let guard = ChallengeGuard::new(routes, None, Duration::from_secs(60));
+        assert_eq!(guard.difficulty(&Method::POST, "/oidc/authorize"), Some(12));
+        assert_eq!(guard.difficulty(&Method::GET, "/oidc/authorize"), None);
+        assert_eq!(guard.difficulty(&Method::GET, "/search/all"), Some(18));
+        assert_eq!(guard.difficulty(&Method::GET, "/searching"), None);
+    }
+
+    #[test]
+    fn test_verify_challenge() {
+        let guard = ChallengeGuard::new(Vec::new(), Some("secret"), Duration::from_secs(60));
+        let now = 1_700_000_000;
+        let (token, expires) = guard.issue_at(8, now);
+        assert_eq!(expires, now + 60);
+        let solution = solve(&token, 8);
+
+        assert_eq!(
+            guard.verify_at(&token, &solution, 10, now),
+            Err("challenge too easy for this route")
+        );
+        assert_eq!(
+            guard.verify_at(&token, &solution, 8, now + 61),
+            Err("challenge expired")
+        );
+        assert_eq!(guard.verify_at(&token, &solution, 8, now), Ok(()));
+        assert_eq!(
+            guard.verify_at(&token, &solution, 8, now),
+            Err("challenge already used")
+        );
+
+        let other = ChallengeGuard::new(Vec::new(), Some("other"), Duration::from_secs(60));
+        assert_eq!(
+            other.verify_at(&token, &solution, 8, now),
+            Err("invalid challenge")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_middleware_requires_solution() {
+        let guard = ChallengeGuard::new(
+            parse_routes("POST /register=4", 4).unwrap(),
+            None,
+            Duration::from_secs(60),
+        );
+        let mut app = Router::new()
+            .route("/register", post(|| async { "ok" }))
+            .layer(from_fn_with_state(guard, ChallengeGuard::middleware));
+        let request = |headers: &[(&str, &str)]| {
+            let mut builder = Request::post("/register");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = app.call(request(&[])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+        let token = response.headers()[CHALLENGE_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let solution = solve(&token, 4);
+        let response = app
+            .call(request(&[
+                (CHALLENGE_HEADER, &token),
+                (SOLUTION_HEADER, &solution),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
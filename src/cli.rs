@@ -0,0 +1,1129 @@
+//! Command-line interface.
+//!
+//! The binary runs one of several subcommands sharing the same
+//! configuration loading:
+//!
+//! - `serve` (the default) runs the server
+//! - `migrate` applies pending module migrations
+//! - `user create|list|delete` manages user accounts
+//! - `config check` (or `check-config`) validates the environment and
+//!   prints a redacted summary
+//! - `healthcheck` probes a running instance, for container health checks
+//! - `backup` dumps the database with `pg_dump`
+//! - `smoke` checks a running instance after a deploy
+//! - `doctor` runs the startup self-test on demand
+//! - `self-update` installs the latest signed release
+//! - `completions` and `man` print shell completions and man pages
+//!
+//! With `--json` every command prints one JSON document on stdout, errors
+//! included, for use from scripts. Exit codes follow `sysexits.h` so callers
+//! can tell a bad configuration from an unreachable database. `--port`,
+//! `--config` and `--profile` override `PORT`, `CONFIG_FILE` and
+//! `CONFIG_PROFILE` for every command.
+
+use anyhow::Context;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::Method;
+use serde::Serialize;
+use serde_json::json;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use crate::capture::{self, Replayer};
+use crate::config::{Config, Vars};
+use crate::crypto::password;
+use crate::db::Database;
+use crate::doctor::SelfTest;
+use crate::encryption::{self, KeyRing};
+use crate::fetch::Fetcher;
+use crate::http_client::HttpClient;
+use crate::i18n::{self, Catalog};
+use crate::logging;
+use crate::redact::{self, MakeRedacting};
+use crate::smoke::{self, Outcome, SmokeTest};
+use crate::{crashes, module, server, update, users, ServerBuilder};
+
+/// Process exit codes
+pub mod exit {
+    /// Success
+    pub const OK: u8 = 0;
+    /// Unclassified failure
+    pub const FAILURE: u8 = 1;
+    /// Invalid command-line usage
+    pub const USAGE: u8 = 64;
+    /// Invalid input data, e.g. a rejected username
+    pub const DATA: u8 = 65;
+    /// A required service or tool is unavailable
+    pub const UNAVAILABLE: u8 = 69;
+    /// Invalid configuration
+    pub const CONFIG: u8 = 78;
+}
+
+/// Self-hosted web server
+#[derive(Debug, Parser)]
+#[command(name = "rust-selfhost-server", version, about)]
+pub struct Cli {
+    /// Print machine-readable JSON instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Port to listen on or to reach the local instance at, overriding PORT
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+
+    /// Config file to read, overriding CONFIG_FILE
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Config file profile to apply, overriding CONFIG_PROFILE
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the server (the default)
+    Serve,
+    /// Apply pending database migrations and exit
+    Migrate {
+        /// Apply only backward-compatible (expand) migrations, for use
+        /// while older versions still share the database
+        #[arg(long)]
+        gate: bool,
+        /// Print the SQL of the migrations that would run, without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Manage user accounts
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Validate the configuration and print a redacted summary, like
+    /// `config check`
+    CheckConfig,
+    /// Check that a running instance is healthy, e.g. as a Docker HEALTHCHECK
+    ///
+    /// Exits non-zero unless the endpoint answers with a 2xx status.
+    Healthcheck {
+        /// Base URL of the instance; defaults to http://127.0.0.1:$PORT
+        #[arg(long)]
+        url: Option<String>,
+        /// Endpoint to request, e.g. /health/ready
+        #[arg(long, default_value = "/health")]
+        path: String,
+        /// Timeout for the request
+        #[arg(long, default_value = "5s", value_parser = crate::config::parse_duration)]
+        timeout: Duration,
+    },
+    /// Manage field encryption keys
+    #[command(subcommand)]
+    Encryption(EncryptionCommand),
+    /// Dump the database with pg_dump
+    Backup {
+        /// File to write; defaults to backup-<timestamp>.dump
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Run post-deploy checks against a running instance
+    ///
+    /// Exits non-zero if any check fails. The migration check runs when
+    /// DATABASE_URL is set.
+    Smoke {
+        /// Base URL of the instance; defaults to http://127.0.0.1:$PORT
+        #[arg(long)]
+        url: Option<String>,
+        /// Admin token for the auth and CRUD checks; defaults to $ADMIN_TOKEN
+        #[arg(long)]
+        admin_token: Option<String>,
+        /// Timeout for each request
+        #[arg(long, default_value = "10s", value_parser = crate::config::parse_duration)]
+        timeout: Duration,
+    },
+    /// Run the startup self-test and print its report
+    ///
+    /// Checks the clock, TLS files, writable directories, disk space, the
+    /// database and HEALTH_DEPENDENCIES. Exits non-zero if any check fails.
+    Doctor,
+    /// Re-send requests recorded with CAPTURE_DIR to an instance
+    ///
+    /// Exits non-zero if any response status differs from the recorded one.
+    Replay {
+        /// Capture file (requests-<date>.jsonl) to replay
+        file: PathBuf,
+        /// Base URL of the instance; defaults to http://127.0.0.1:$PORT
+        #[arg(long)]
+        target: Option<String>,
+        /// Header sent with every request, e.g. "Authorization: Bearer ...";
+        /// recorded credentials are masked and not sent
+        #[arg(short = 'H', long = "header", value_parser = parse_header)]
+        headers: Vec<(HeaderName, HeaderValue)>,
+        /// Timeout for each request
+        #[arg(long, default_value = "10s", value_parser = crate::config::parse_duration)]
+        timeout: Duration,
+    },
+    /// Replace this binary with the latest signed release
+    ///
+    /// Releases are looked up at $UPDATE_URL (GitHub releases by default)
+    /// and must be signed with the key in $UPDATE_PUBLIC_KEY.
+    SelfUpdate {
+        /// Only report whether an update is available
+        #[arg(long)]
+        check: bool,
+        /// Install the latest release even if it is not newer
+        #[arg(long)]
+        force: bool,
+        /// Run $UPDATE_RESTART_COMMAND after installing
+        #[arg(long)]
+        restart: bool,
+        /// Timeout for each download
+        #[arg(long, default_value = "5m", value_parser = crate::config::parse_duration)]
+        timeout: Duration,
+    },
+    /// Print a shell completion script
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
+    },
+    /// Print the man page, or write one per subcommand to a directory
+    Man {
+        /// Directory to write rust-selfhost-server*.1 pages to
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Create a user, generating a password unless one is piped in
+    Create {
+        username: String,
+        /// Grant admin rights
+        #[arg(long)]
+        admin: bool,
+        /// Read the password from the first line of stdin
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// List users
+    List,
+    /// Delete a user
+    Delete { username: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the configuration and print a redacted summary
+    Check,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum EncryptionCommand {
+    /// Print a new key entry for ENCRYPTION_KEYS
+    GenerateKey {
+        /// Key id; defaults to k<date>
+        #[arg(long)]
+        id: Option<String>,
+    },
+    /// Re-encrypt every encrypted column with the first ENCRYPTION_KEYS key
+    Rotate {
+        /// Only count the values that would be re-encrypted
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// A failed command with the exit code it maps to
+#[derive(Debug)]
+pub struct CliError {
+    pub code: u8,
+    pub error: anyhow::Error,
+    /// The command already printed its own failure report
+    reported: bool,
+}
+
+impl CliError {
+    fn new(code: u8, error: impl Into<anyhow::Error>) -> Self {
+        CliError {
+            code,
+            error: error.into(),
+            reported: false,
+        }
+    }
+}
+
+impl From<anyhow::Error> for CliError {
+    fn from(error: anyhow::Error) -> Self {
+        CliError::new(exit::FAILURE, error)
+    }
+}
+
+type CliResult<T> = std::result::Result<T, CliError>;
+
+/// Prints results as text or JSON
+#[derive(Debug, Clone, Copy)]
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn print(&self, value: &impl Serialize, text: impl FnOnce() -> String) {
+        if self.json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(value).unwrap_or_default()
+            );
+        } else {
+            println!("{}", text());
+        }
+    }
+
+    fn error(&self, error: &CliError) {
+        if error.reported {
+            return;
+        }
+        if self.json {
+            println!(
+                "{}",
+                json!({ "error": redact::redact(&format!("{:#}", error.error)), "code": error.code })
+            );
+        } else {
+            eprintln!("❌ {}", redact::redact(&format!("{:#}", error.error)));
+        }
+    }
+}
+
+/// Run the parsed command line
+pub async fn run(cli: Cli) -> ExitCode {
+    let output = Output { json: cli.json };
+    apply_overrides(&cli);
+    let command = cli.command.unwrap_or(Command::Serve);
+    redact::install_panic_hook();
+    if matches!(command, Command::Serve) {
+        logging::init_serve();
+    } else {
+        tracing_subscriber::fmt()
+            .with_writer(MakeRedacting(std::io::stderr))
+            .with_env_filter(
+                tracing_subscriber::EnvFilter::try_from_default_env()
+                    .unwrap_or_else(|_| "warn".into()),
+            )
+            .init();
+    }
+
+    let result = match command {
+        Command::Serve => serve().await,
+        Command::Migrate { gate, dry_run } => migrate(gate, dry_run, output).await,
+        Command::User(command) => user(command, output).await,
+        Command::Config(ConfigCommand::Check) | Command::CheckConfig => config_check(output),
+        Command::Healthcheck { url, path, timeout } => {
+            healthcheck(url, path, timeout, output).await
+        }
+        Command::Encryption(command) => encryption(command, output).await,
+        Command::Backup { output: path } => backup(path, output).await,
+        Command::Smoke {
+            url,
+            admin_token,
+            timeout,
+        } => smoke(url, admin_token, timeout, output).await,
+        Command::Doctor => doctor(output).await,
+        Command::Replay {
+            file,
+            target,
+            headers,
+            timeout,
+        } => replay(file, target, headers, timeout, output).await,
+        Command::SelfUpdate {
+            check,
+            force,
+            restart,
+            timeout,
+        } => self_update(check, force, restart, timeout, output).await,
+        Command::Completions { shell } => completions(shell),
+        Command::Man { dir } => man(dir, output),
+    };
+    match result {
+        Ok(()) => ExitCode::from(exit::OK),
+        Err(error) => {
+            output.error(&error);
+            ExitCode::from(error.code)
+        }
+    }
+}
+
+/// Apply the global flags by setting the variables they override, so the
+/// configuration and everything reading the environment directly see them
+fn apply_overrides(cli: &Cli) {
+    let overrides = [
+        ("PORT", cli.port.map(|port| port.to_string())),
+        (
+            "CONFIG_FILE",
+            cli.config.as_ref().map(|path| path.display().to_string()),
+        ),
+        ("CONFIG_PROFILE", cli.profile.clone()),
+    ];
+    for (key, value) in overrides {
+        if let Some(value) = value {
+            std::env::set_var(key, value);
+        }
+    }
+}
+
+/// Base URL of the instance on this host, at PORT from the environment or
+/// the config file
+fn local_url() -> String {
+    let port = Vars::load()
+        .ok()
+        .and_then(|vars| vars.get("PORT"))
+        .unwrap_or_else(|| "3000".to_string());
+    format!("http://127.0.0.1:{}", port)
+}
+
+fn load_vars() -> CliResult<Vars> {
+    Vars::load().map_err(|e| CliError::new(exit::CONFIG, e))
+}
+
+fn load_config() -> CliResult<Config> {
+    let config = Config::from_env().map_err(|e| CliError::new(exit::CONFIG, e))?;
+    redact::configure(&config);
+    encryption::install(KeyRing::new(config.encryption_keys().to_vec()));
+    password::install(config.password_hash());
+    let catalog = Catalog::load(config.locales_dir(), config.default_locale().clone())
+        .map_err(|e| CliError::new(exit::CONFIG, e))?;
+    i18n::install(catalog);
+    Ok(config)
+}
+
+async fn connect(config: &Config) -> CliResult<Database> {
+    Database::new(config)
+        .await
+        .map_err(|e| CliError::new(exit::UNAVAILABLE, e))
+}
+
+async fn serve() -> CliResult<()> {
+    tracing::info!("🔧 Loading configuration...");
+    let config = load_config()?;
+    let _log_files = logging::install(&config)?;
+    crashes::install_hook(
+        config.crash_dir().to_path_buf(),
+        config.instance().name.clone(),
+    );
+    match (config.config_file(), config.config_profile()) {
+        (Some(file), Some(profile)) => tracing::info!(
+            "✅ Configuration loaded successfully (file {}, profile {})",
+            file.display(),
+            profile
+        ),
+        (Some(file), None) => tracing::info!(
+            "✅ Configuration loaded successfully (file {})",
+            file.display()
+        ),
+        _ => tracing::info!("✅ Configuration loaded successfully"),
+    }
+    ServerBuilder::new(config).serve().await?;
+    tracing::info!("🛑 Server shutdown complete");
+    Ok(())
+}
+
+async fn migrate(gate: bool, dry_run: bool, output: Output) -> CliResult<()> {
+    let config = load_config()?;
+    let modules = server::enabled_modules(module::builtin_modules(), config.disabled_modules())
+        .map_err(|e| CliError::new(exit::CONFIG, e))?;
+    let db = connect(&config).await?;
+    if dry_run {
+        let planned = module::planned_migrations(db.pool(), &modules, gate).await?;
+        output.print(&json!({ "pending": planned }), || {
+            if planned.is_empty() {
+                return "✅ Database is up to date".to_string();
+            }
+            let mut text = format!("-- {} migrations would run", planned.len());
+            for migration in &planned {
+                text.push_str(&format!(
+                    "\n\n-- {} ({})\n{};",
+                    migration.name,
+                    migration.kind.as_str(),
+                    migration.sql.trim()
+                ));
+            }
+            text
+        });
+        return Ok(());
+    }
+    module::check_schema(db.pool(), &modules).await?;
+    module::check_checksums(db.pool(), &modules, config.migration_drift()).await?;
+    let applied = if gate {
+        module::run_gated_migrations(db.pool(), &modules).await?
+    } else {
+        module::run_migrations(db.pool(), &modules).await?
+    };
+    let held: Vec<String> = module::pending_migrations(db.pool(), &modules)
+        .await?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    output.print(&json!({ "applied": applied, "held": held }), || {
+        let mut text = if applied.is_empty() && held.is_empty() {
+            "✅ Database is up to date".to_string()
+        } else if applied.is_empty() {
+            "✅ No backward-compatible migrations pending".to_string()
+        } else {
+            let mut text = format!("✅ Applied {} migrations", applied.len());
+            for name in &applied {
+                text.push_str(&format!("\n  {}", name));
+            }
+            text
+        };
+        if !held.is_empty() {
+            text.push_str(&format!(
+                "\n⏸️ Held back {} migrations until older versions are stopped",
+                held.len()
+            ));
+            for name in &held {
+                text.push_str(&format!("\n  {}", name));
+            }
+        }
+        text
+    });
+    Ok(())
+}
+
+async fn user(command: UserCommand, output: Output) -> CliResult<()> {
+    let config = load_config()?;
+    let db = connect(&config).await?;
+    let pool = db.pool();
+    module::run_migrations(pool, &[std::sync::Arc::new(users::UsersModule)]).await?;
+
+    match command {
+        UserCommand::Create {
+            username,
+            admin,
+            password_stdin,
+        } => {
+            let (password, generated) = if password_stdin {
+                let mut input = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut input)
+                    .context("Failed to read password from stdin")?;
+                (input.lines().next().unwrap_or_default().to_string(), false)
+            } else {
+                (crate::auth::random_token(15), true)
+            };
+            users::validate(&username, &password)
+                .map_err(|e| CliError::new(exit::DATA, anyhow::anyhow!(e)))?;
+            let user = users::create(pool, &username, &password, admin)
+                .await?
+                .ok_or_else(|| {
+                    CliError::new(
+                        exit::DATA,
+                        anyhow::anyhow!("User '{}' already exists", username),
+                    )
+                })?;
+            let password = generated.then_some(password);
+            output.print(&json!({ "user": user, "password": password }), || {
+                let mut text = format!(
+                    "✅ Created {} '{}'",
+                    if user.is_admin { "admin" } else { "user" },
+                    user.username
+                );
+                if let Some(password) = &password {
+                    text.push_str(&format!("\n🔑 Password: {}", password));
+                }
+                text
+            });
+        }
+        UserCommand::List => {
+            let users = users::list(pool).await?;
+            output.print(&users, || {
+                users
+                    .iter()
+                    .map(|u| {
+                        format!(
+                            "{}{}\t{}",
+                            u.username,
+                            if u.is_admin { " (admin)" } else { "" },
+                            u.created_at.format("%Y-%m-%d %H:%M UTC")
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        }
+        UserCommand::Delete { username } => {
+            if !users::delete(pool, &username).await? {
+                return Err(CliError::new(
+                    exit::DATA,
+                    anyhow::anyhow!("User '{}' not found", username),
+                ));
+            }
+            output.print(&json!({ "deleted": username }), || {
+                format!("✅ Deleted user '{}'", username)
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Redacted view of the effective configuration
+#[derive(Debug, Serialize)]
+struct ConfigSummary {
+    config_file: Option<String>,
+    config_profile: Option<String>,
+    port: u16,
+    database_url: String,
+    db_max_connections: u32,
+    modules: Vec<&'static str>,
+    admin_token: bool,
+    api_keys: usize,
+    leader_election: crate::leader::ElectionBackend,
+    instance: String,
+    oidc_issuer: Option<String>,
+    proxy_routes: Vec<String>,
+    dependencies: Vec<String>,
+    mdns: bool,
+    fault_injection: bool,
+}
+
+fn config_check(output: Output) -> CliResult<()> {
+    let config = load_config()?;
+    let modules = server::enabled_modules(module::builtin_modules(), config.disabled_modules())
+        .map_err(|e| CliError::new(exit::CONFIG, e))?;
+    let summary = ConfigSummary {
+        config_file: config.config_file().map(|file| file.display().to_string()),
+        config_profile: config.config_profile().map(String::from),
+        port: config.port(),
+        database_url: redact::redact_url(config.database_url()),
+        db_max_connections: config.max_connections(),
+        modules: modules.iter().map(|m| m.name()).collect(),
+        admin_token: config.admin_token().is_some(),
+        api_keys: config.api_keys().len(),
+        leader_election: config.leader_election(),
+        instance: config.instance().name.clone(),
+        oidc_issuer: config.oidc_issuer().map(String::from),
+        proxy_routes: config
+            .proxy_routes()
+            .iter()
+            .map(|r| format!("{} -> {}", r.prefix, r.upstream))
+            .collect(),
+        dependencies: config
+            .dependencies()
+            .iter()
+            .map(|d| d.name.clone())
+            .collect(),
+        mdns: config.mdns().is_some(),
+        fault_injection: config.fault_injection(),
+    };
+    output.print(&json!({ "valid": true, "config": summary }), || {
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                "none".to_string()
+            } else {
+                items.join(", ")
+            }
+        };
+        format!(
+            "✅ Configuration is valid\n\
+             \x20 config file:      {}\n\
+             \x20 port:             {}\n\
+             \x20 database:         {}\n\
+             \x20 modules:          {}\n\
+             \x20 admin token:      {}\n\
+             \x20 api keys:         {}\n\
+             \x20 leader election:  {}\n\
+             \x20 oidc issuer:      {}\n\
+             \x20 proxy routes:     {}\n\
+             \x20 dependencies:     {}\n\
+             \x20 fault injection:  {}",
+            match (&summary.config_file, &summary.config_profile) {
+                (Some(file), Some(profile)) => format!("{} (profile {})", file, profile),
+                (Some(file), None) => file.clone(),
+                _ => "none".to_string(),
+            },
+            summary.port,
+            summary.database_url,
+            summary.modules.join(", "),
+            if summary.admin_token {
+                "set"
+            } else {
+                "not set"
+            },
+            summary.api_keys,
+            format!("{:?}", summary.leader_election).to_lowercase(),
+            summary.oidc_issuer.as_deref().unwrap_or("disabled"),
+            list(&summary.proxy_routes),
+            list(&summary.dependencies),
+            if summary.fault_injection {
+                "enabled"
+            } else {
+                "disabled"
+            },
+        )
+    });
+    Ok(())
+}
+
+async fn encryption(command: EncryptionCommand, output: Output) -> CliResult<()> {
+    match command {
+        EncryptionCommand::GenerateKey { id } => {
+            let id = id.unwrap_or_else(|| format!("k{}", chrono::Utc::now().format("%Y%m%d")));
+            let key = encryption::generate_key();
+            let entry = format!("{}:{}", id, key);
+            encryption::parse_keys(&entry).map_err(|e| CliError::new(exit::USAGE, e))?;
+            output.print(&json!({ "id": id, "key": key }), || entry.clone());
+        }
+        EncryptionCommand::Rotate { dry_run } => {
+            let config = load_config()?;
+            let modules =
+                server::enabled_modules(module::builtin_modules(), config.disabled_modules())
+                    .map_err(|e| CliError::new(exit::CONFIG, e))?;
+            if config.encryption_keys().is_empty() {
+                return Err(CliError::new(
+                    exit::CONFIG,
+                    anyhow::anyhow!("ENCRYPTION_KEYS is not set; there is no key to encrypt with"),
+                ));
+            }
+            let db = connect(&config).await?;
+            module::run_migrations(db.pool(), &modules).await?;
+            let reports = encryption::rotate(db.pool(), &modules, dry_run).await?;
+            output.print(&reports, || {
+                let verb = if dry_run {
+                    "Would re-encrypt"
+                } else {
+                    "Re-encrypted"
+                };
+                reports
+                    .iter()
+                    .map(|r| format!("✅ {} {} values in {}.{}", verb, r.rows, r.table, r.column))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        }
+    }
+    Ok(())
+}
+
+async fn backup(path: Option<PathBuf>, output: Output) -> CliResult<()> {
+    let config = load_config()?;
+    let path = path.unwrap_or_else(|| {
+        PathBuf::from(format!(
+            "backup-{}.dump",
+            chrono::Utc::now().format("%Y%m%d-%H%M%S")
+        ))
+    });
+    let result = tokio::process::Command::new("pg_dump")
+        .arg("--format=custom")
+        .arg("--file")
+        .arg(&path)
+        .arg("--dbname")
+        .arg(config.database_url())
+        .output()
+        .await;
+    let dump = match result {
+        Ok(dump) => dump,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(CliError::new(
+                exit::UNAVAILABLE,
+                anyhow::anyhow!("pg_dump not found; install the PostgreSQL client tools"),
+            ))
+        }
+        Err(e) => {
+            return Err(anyhow::Error::new(e)
+                .context("Failed to run pg_dump")
+                .into())
+        }
+    };
+    if !dump.status.success() {
+        return Err(CliError::new(
+            exit::UNAVAILABLE,
+            anyhow::anyhow!(
+                "pg_dump failed: {}",
+                String::from_utf8_lossy(&dump.stderr).trim()
+            ),
+        ));
+    }
+    let bytes = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    output.print(&json!({ "path": path, "bytes": bytes }), || {
+        format!("✅ Wrote {} ({} bytes)", path.display(), bytes)
+    });
+    Ok(())
+}
+
+async fn smoke(
+    url: Option<String>,
+    admin_token: Option<String>,
+    timeout: Duration,
+    output: Output,
+) -> CliResult<()> {
+    let url = url.unwrap_or_else(local_url);
+    let mut test = SmokeTest::new(&url, timeout);
+    let vars = load_vars()?;
+    if let Some(token) = admin_token.or_else(|| vars.get("ADMIN_TOKEN")) {
+        test = test.admin_token(token);
+    }
+    if vars.get("DATABASE_URL").is_some() {
+        let config = load_config()?;
+        let modules = server::enabled_modules(module::builtin_modules(), config.disabled_modules())
+            .map_err(|e| CliError::new(exit::CONFIG, e))?;
+        test = test.database(connect(&config).await?.pool().clone(), modules);
+    }
+
+    let checks = test.run().await;
+    let failed = checks.iter().filter(|c| c.outcome == Outcome::Fail).count();
+    let mut report = smoke::report(&checks);
+    if failed > 0 {
+        report["error"] = json!(format!("{} smoke checks failed", failed));
+        report["code"] = json!(exit::FAILURE);
+    }
+    output.print(&report, || {
+        format_checks(&format!("Smoke checks against {}", url), &checks)
+    });
+    if failed > 0 {
+        let mut error = CliError::new(
+            exit::FAILURE,
+            anyhow::anyhow!("{} smoke checks failed", failed),
+        );
+        error.reported = true;
+        return Err(error);
+    }
+    Ok(())
+}
+
+async fn healthcheck(
+    url: Option<String>,
+    path: String,
+    timeout: Duration,
+    output: Output,
+) -> CliResult<()> {
+    let url = format!(
+        "{}/{}",
+        url.unwrap_or_else(local_url).trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    let (status, _) = HttpClient::new(timeout)
+        .request(Method::GET, &url, None)
+        .await
+        .map_err(|e| CliError::new(exit::UNAVAILABLE, e))?;
+    let healthy = status.is_success();
+    let mut report = json!({ "url": url, "status": status.as_u16(), "healthy": healthy });
+    if !healthy {
+        report["error"] = json!(format!("{} answered {}", url, status));
+        report["code"] = json!(exit::UNAVAILABLE);
+    }
+    output.print(&report, || {
+        let icon = if healthy { "✅" } else { "❌" };
+        format!("{} {} answered {}", icon, url, status)
+    });
+    if !healthy {
+        let mut error = CliError::new(
+            exit::UNAVAILABLE,
+            anyhow::anyhow!("{} answered {}", url, status),
+        );
+        error.reported = true;
+        return Err(error);
+    }
+    Ok(())
+}
+
+async fn doctor(output: Output) -> CliResult<()> {
+    let config = load_config()?;
+    let test = SelfTest::new(&config);
+    let mut checks = test.local().await;
+    // An unreachable database is a finding, not a reason to stop
+    let db = Database::new(&config).await;
+    if let Err(e) = &db {
+        tracing::warn!("Failed to connect to the database: {:#}", e);
+    }
+    checks.extend(test.connected(db.as_ref().ok()).await);
+
+    let failed = checks.iter().filter(|c| c.outcome == Outcome::Fail).count();
+    let mut report = smoke::report(&checks);
+    if failed > 0 {
+        report["error"] = json!(format!("{} self-test checks failed", failed));
+        report["code"] = json!(exit::FAILURE);
+    }
+    output.print(&report, || format_checks("Self-test", &checks));
+    if failed > 0 {
+        let mut error = CliError::new(
+            exit::FAILURE,
+            anyhow::anyhow!("{} self-test checks failed", failed),
+        );
+        error.reported = true;
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// One line per check under `title`, then the summary
+fn format_checks(title: &str, checks: &[smoke::Check]) -> String {
+    let mut text = title.to_string();
+    for check in checks {
+        let icon = match check.outcome {
+            Outcome::Pass => "✅",
+            Outcome::Fail => "❌",
+            Outcome::Skip => "⏭️ ",
+        };
+        text.push_str(&format!(
+            "\n{} {:<11} {} ({} ms)",
+            icon, check.name, check.detail, check.duration_ms
+        ));
+    }
+    text.push_str(&format!("\n{}", smoke::summarize(checks)));
+    text
+}
+
+async fn replay(
+    file: PathBuf,
+    target: Option<String>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    timeout: Duration,
+    output: Output,
+) -> CliResult<()> {
+    let exchanges = capture::read(&file).map_err(|e| CliError::new(exit::DATA, e))?;
+    let target = target.unwrap_or_else(local_url);
+    let replayer = Replayer::new(
+        &target,
+        HttpClient::new(timeout),
+        headers.into_iter().collect(),
+    );
+    let mut results = Vec::with_capacity(exchanges.len());
+    for exchange in &exchanges {
+        results.push(replayer.replay(exchange).await);
+    }
+
+    let failed = results.iter().filter(|r| !r.matches()).count();
+    let mut report = json!({
+        "target": target,
+        "replayed": results.len(),
+        "mismatched": failed,
+        "results": results,
+    });
+    if failed > 0 {
+        report["error"] = json!(format!("{} of {} responses differ", failed, results.len()));
+        report["code"] = json!(exit::FAILURE);
+    }
+    output.print(&report, || {
+        let mut text = format!("Replaying {} requests against {}", results.len(), target);
+        for result in &results {
+            let icon = if result.matches() { "✅" } else { "❌" };
+            let outcome = match (&result.error, result.status) {
+                (Some(error), _) => error.clone(),
+                (None, Some(status)) if status == result.recorded_status => status.to_string(),
+                (None, status) => format!(
+                    "{} (recorded {})",
+                    status.unwrap_or_default(),
+                    result.recorded_status
+                ),
+            };
+            let body = match result.body_matches {
+                Some(false) => ", body differs",
+                _ => "",
+            };
+            text.push_str(&format!(
+                "\n{} {} {} → {}{} ({} ms)",
+                icon, result.method, result.uri, outcome, body, result.duration_ms
+            ));
+        }
+        text.push_str(&format!(
+            "\n{} matched, {} differ",
+            results.len() - failed,
+            failed
+        ));
+        text
+    });
+    if failed > 0 {
+        let mut error = CliError::new(
+            exit::FAILURE,
+            anyhow::anyhow!("{} of {} responses differ", failed, results.len()),
+        );
+        error.reported = true;
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn parse_header(input: &str) -> Result<(HeaderName, HeaderValue), String> {
+    capture::parse_header(input).map_err(|e| format!("{:#}", e))
+}
+
+async fn self_update(
+    check: bool,
+    force: bool,
+    restart: bool,
+    timeout: Duration,
+    output: Output,
+) -> CliResult<()> {
+    let vars = load_vars()?;
+    let url = vars
+        .get("UPDATE_URL")
+        .unwrap_or_else(|| update::DEFAULT_RELEASES_URL.to_string());
+    // The release URL is the operator's choice, so private hosts are fine
+    let fetcher = Fetcher::new(timeout, true);
+    let current = env!("CARGO_PKG_VERSION");
+    let release = update::latest_release(&fetcher, &url)
+        .await
+        .map_err(|e| CliError::new(exit::UNAVAILABLE, e))?;
+    let latest = release.version().to_string();
+    let available = update::is_newer(&latest, current);
+    if check || !(available || force) {
+        output.print(
+            &json!({ "current": current, "latest": latest, "available": available, "updated": false }),
+            || match available {
+                true => format!("⬆️ {} is available (running {})", latest, current),
+                false => format!("✅ {} is up to date", current),
+            },
+        );
+        return Ok(());
+    }
+
+    let public_key = vars.get("UPDATE_PUBLIC_KEY").ok_or_else(|| {
+        CliError::new(
+            exit::CONFIG,
+            anyhow::anyhow!("UPDATE_PUBLIC_KEY must be set to verify releases"),
+        )
+    })?;
+    let restart_command = match restart {
+        true => Some(vars.get("UPDATE_RESTART_COMMAND").ok_or_else(|| {
+            CliError::new(
+                exit::CONFIG,
+                anyhow::anyhow!("--restart needs UPDATE_RESTART_COMMAND"),
+            )
+        })?),
+        false => None,
+    };
+    let binary = update::download_verified(&fetcher, &release, &public_key)
+        .await
+        .map_err(|e| CliError::new(exit::DATA, e))?;
+    let path = std::env::current_exe().context("Failed to locate the running binary")?;
+    update::replace_binary(&path, &binary)?;
+
+    if let Some(command) = &restart_command {
+        let status = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .status()
+            .await
+            .context("Failed to run UPDATE_RESTART_COMMAND")?;
+        if !status.success() {
+            return Err(anyhow::anyhow!(
+                "Installed {} but UPDATE_RESTART_COMMAND failed with {}",
+                latest,
+                status
+            )
+            .into());
+        }
+    }
+    output.print(
+        &json!({
+            "current": current,
+            "latest": latest,
+            "available": available,
+            "updated": true,
+            "path": path,
+            "restarted": restart_command.is_some(),
+        }),
+        || {
+            let mut text = format!(
+                "✅ Updated {} from {} to {}",
+                path.display(),
+                current,
+                latest
+            );
+            if restart_command.is_none() {
+                text.push_str("; restart the server to run it");
+            }
+            text
+        },
+    );
+    Ok(())
+}
+
+fn completions(shell: Shell) -> CliResult<()> {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, name, &mut script);
+    std::io::Write::write_all(&mut std::io::stdout(), &script)
+        .context("Failed to write completions")?;
+    Ok(())
+}
+
+fn man(dir: Option<PathBuf>, output: Output) -> CliResult<()> {
+    let command = Cli::command();
+    let Some(dir) = dir else {
+        clap_mangen::Man::new(command)
+            .render(&mut std::io::stdout())
+            .context("Failed to render man page")?;
+        return Ok(());
+    };
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    clap_mangen::generate_to(command, &dir)
+        .with_context(|| format!("Failed to write man pages to {}", dir.display()))?;
+    output.print(&json!({ "dir": dir }), || {
+        format!("✅ Wrote man pages to {}", dir.display())
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_parses_subcommands() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["app"]).unwrap();
+        assert!(cli.command.is_none());
+
+        let cli =
+            Cli::try_parse_from(["app", "user", "create", "alice", "--admin", "--json"]).unwrap();
+        assert!(cli.json);
+        assert!(matches!(
+            cli.command,
+            Some(Command::User(UserCommand::Create { ref username, admin: true, password_stdin: false }))
+                if username == "alice"
+        ));
+
+        assert!(Cli::try_parse_from(["app", "user"]).is_err());
+        assert!(matches!(
+            Cli::try_parse_from(["app", "smoke", "--timeout", "2s"]).unwrap().command,
+            Some(Command::Smoke { timeout, .. }) if timeout == Duration::from_secs(2)
+        ));
+        assert!(Cli::try_parse_from(["app", "smoke", "--timeout", "soon"]).is_err());
+        assert!(matches!(
+            Cli::try_parse_from(["app", "doctor", "--json"])
+                .unwrap()
+                .command,
+            Some(Command::Doctor)
+        ));
+        assert!(Cli::try_parse_from(["app", "completions", "fish"]).is_ok());
+        assert!(Cli::try_parse_from(["app", "completions", "cmd.exe"]).is_err());
+
+        assert!(matches!(
+            Cli::try_parse_from(["app", "check-config"])
+                .unwrap()
+                .command,
+            Some(Command::CheckConfig)
+        ));
+        let cli = Cli::try_parse_from(["app", "healthcheck", "--port", "8080"]).unwrap();
+        assert_eq!(cli.port, Some(8080));
+        assert!(matches!(
+            cli.command,
+            Some(Command::Healthcheck { url: None, ref path, timeout })
+                if path == "/health" && timeout == Duration::from_secs(5)
+        ));
+        let cli =
+            Cli::try_parse_from(["app", "--config", "prod.toml", "serve", "--profile", "prod"])
+                .unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("prod.toml")));
+        assert_eq!(cli.profile.as_deref(), Some("prod"));
+        assert!(Cli::try_parse_from(["app", "--port", "http"]).is_err());
+    }
+}
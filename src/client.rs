@@ -0,0 +1,593 @@
+//! Typed client for the JSON API.
+//!
+//! Built with the `client` feature, for Rust programs talking to a running
+//! server. Requests and responses use the server's own model types
+//! ([`Note`], [`NewNote`], [`Bookmark`] ...), so the two cannot drift
+//! apart:
+//!
+//! ```no_run
+//! # async fn run() -> rust_selfhost_server::client::ClientResult<()> {
+//! use rust_selfhost_server::client::Client;
+//! use rust_selfhost_server::notes::NewNote;
+//!
+//! let client = Client::new("https://notes.example.com")?.api_key("secret")?;
+//! let note = client
+//!     .create_note(&NewNote {
+//!         title: "Groceries".into(),
+//!         ..NewNote::default()
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests authenticate with an API key or an [HMAC
+//! signature](crate::signing), signed afresh for every attempt. Failed
+//! attempts are retried with exponential backoff when that is safe: after
+//! connection failures and 429 responses for any request, which never
+//! reached a handler, and after timeouts and 502/503/504 responses for
+//! idempotent methods only. A `Retry-After` header overrides the backoff.
+//! Error responses become [`ClientError::Api`] with the server's message.
+
+use anyhow::Context;
+use axum::body::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    header::{HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER},
+    Method, Request, StatusCode,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{connect::HttpConnector, Client as HyperClient};
+use hyper_util::rt::TokioExecutor;
+use rand_core::RngCore;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::bookmarks::{Bookmark, BookmarkQuery, BookmarkUpdate, NewBookmark, TagCount};
+use crate::notes::{FolderCount, NewNote, Note, NoteQuery, NoteUpdate, Revision, RevisionSummary};
+use crate::signing::{self, SigningClient};
+
+/// Why a request failed
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// The server answered with an error status
+    #[error("{status}: {message}")]
+    Api { status: StatusCode, message: String },
+    /// The request could not be sent or its response read
+    #[error("{0:#}")]
+    Transport(#[from] anyhow::Error),
+}
+
+impl ClientError {
+    /// Status of an error response
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::Api { status, .. } => Some(*status),
+            ClientError::Transport(_) => None,
+        }
+    }
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+/// How failed attempts are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Retries after the first attempt; 0 disables retrying
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each further one
+    pub backoff: Duration,
+    /// Longest delay between attempts, including `Retry-After`
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            retries: 3,
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Retry {
+    /// Delay before retry number `attempt` (from 1), with jitter so that
+    /// clients failing together do not retry together
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_backoff);
+        let jitter = rand_core::OsRng.next_u32() as f64 / u32::MAX as f64;
+        delay.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+#[derive(Clone)]
+enum Auth {
+    None,
+    Bearer(HeaderValue),
+    Signed(SigningClient),
+}
+
+/// Async client for the `/api/v1` endpoints
+#[derive(Clone)]
+pub struct Client {
+    http: HyperClient<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    base_url: String,
+    auth: Auth,
+    retry: Retry,
+    timeout: Duration,
+}
+
+impl Client {
+    /// A client for the server at `base_url`, e.g. `https://notes.example.com`
+    pub fn new(base_url: &str) -> ClientResult<Self> {
+        let base_url = base_url.trim_end_matches('/');
+        if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+            return Err(
+                anyhow::anyhow!("Invalid base URL '{}': expected http(s)://", base_url).into(),
+            );
+        }
+        let https = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Client {
+            http: HyperClient::builder(TokioExecutor::new()).build(https),
+            base_url: base_url.to_string(),
+            auth: Auth::None,
+            retry: Retry::default(),
+            timeout: Duration::from_secs(30),
+        })
+    }
+
+    /// Authenticate with an API key from `API_KEYS`
+    pub fn api_key(mut self, key: &str) -> ClientResult<Self> {
+        let value = HeaderValue::from_str(&format!("Bearer {}", key)).context("Invalid API key")?;
+        self.auth = Auth::Bearer(value);
+        Ok(self)
+    }
+
+    /// Sign requests as a client from `HMAC_CLIENTS`
+    pub fn signing(mut self, client: SigningClient) -> Self {
+        self.auth = Auth::Signed(client);
+        self
+    }
+
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Time allowed for each attempt
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// `GET /api/v1/notes`
+    pub async fn notes(&self, query: &NoteQuery) -> ClientResult<Vec<Note>> {
+        let path = format!("/api/v1/notes?{}", query_string(query)?);
+        self.json(Method::GET, &path, None::<&()>).await
+    }
+
+    /// `POST /api/v1/notes`
+    pub async fn create_note(&self, note: &NewNote) -> ClientResult<Note> {
+        self.json(Method::POST, "/api/v1/notes", Some(note)).await
+    }
+
+    /// `GET /api/v1/notes/:id`
+    pub async fn note(&self, id: Uuid) -> ClientResult<Note> {
+        let path = format!("/api/v1/notes/{}", id);
+        self.json(Method::GET, &path, None::<&()>).await
+    }
+
+    /// `PATCH /api/v1/notes/:id`
+    pub async fn update_note(&self, id: Uuid, changes: &NoteUpdate) -> ClientResult<Note> {
+        let path = format!("/api/v1/notes/{}", id);
+        self.json(Method::PATCH, &path, Some(changes)).await
+    }
+
+    /// `DELETE /api/v1/notes/:id`
+    pub async fn delete_note(&self, id: Uuid) -> ClientResult<()> {
+        let path = format!("/api/v1/notes/{}", id);
+        self.send(Method::DELETE, &path, None::<&()>).await?;
+        Ok(())
+    }
+
+    /// `GET /api/v1/notes/folders`
+    pub async fn note_folders(&self) -> ClientResult<Vec<FolderCount>> {
+        self.json(Method::GET, "/api/v1/notes/folders", None::<&()>)
+            .await
+    }
+
+    /// `GET /api/v1/notes/:id/revisions`
+    pub async fn note_revisions(&self, id: Uuid) -> ClientResult<Vec<RevisionSummary>> {
+        let path = format!("/api/v1/notes/{}/revisions", id);
+        self.json(Method::GET, &path, None::<&()>).await
+    }
+
+    /// `GET /api/v1/notes/:id/revisions/:revision`
+    pub async fn note_revision(&self, id: Uuid, revision: i32) -> ClientResult<Revision> {
+        let path = format!("/api/v1/notes/{}/revisions/{}", id, revision);
+        self.json(Method::GET, &path, None::<&()>).await
+    }
+
+    /// `POST /api/v1/notes/:id/revisions/:revision/restore`
+    pub async fn restore_note_revision(&self, id: Uuid, revision: i32) -> ClientResult<Note> {
+        let path = format!("/api/v1/notes/{}/revisions/{}/restore", id, revision);
+        self.json(Method::POST, &path, None::<&()>).await
+    }
+
+    /// `GET /api/v1/bookmarks`
+    pub async fn bookmarks(&self, query: &BookmarkQuery) -> ClientResult<Vec<Bookmark>> {
+        let path = format!("/api/v1/bookmarks?{}", query_string(query)?);
+        self.json(Method::GET, &path, None::<&()>).await
+    }
+
+    /// `POST /api/v1/bookmarks`
+    pub async fn create_bookmark(&self, bookmark: &NewBookmark) -> ClientResult<Bookmark> {
+        self.json(Method::POST, "/api/v1/bookmarks", Some(bookmark))
+            .await
+    }
+
+    /// `GET /api/v1/bookmarks/:id`
+    pub async fn bookmark(&self, id: i64) -> ClientResult<Bookmark> {
+        let path = format!("/api/v1/bookmarks/{}", id);
+        self.json(Method::GET, &path, None::<&()>).await
+    }
+
+    /// `PATCH /api/v1/bookmarks/:id`
+    pub async fn update_bookmark(
+        &self,
+        id: i64,
+        changes: &BookmarkUpdate,
+    ) -> ClientResult<Bookmark> {
+        let path = format!("/api/v1/bookmarks/{}", id);
+        self.json(Method::PATCH, &path, Some(changes)).await
+    }
+
+    /// `DELETE /api/v1/bookmarks/:id`
+    pub async fn delete_bookmark(&self, id: i64) -> ClientResult<()> {
+        let path = format!("/api/v1/bookmarks/{}", id);
+        self.send(Method::DELETE, &path, None::<&()>).await?;
+        Ok(())
+    }
+
+    /// `POST /api/v1/bookmarks/:id/fetch` - fetch the page's title again
+    pub async fn refetch_bookmark(&self, id: i64) -> ClientResult<Bookmark> {
+        let path = format!("/api/v1/bookmarks/{}/fetch", id);
+        self.json(Method::POST, &path, None::<&()>).await
+    }
+
+    /// `GET /api/v1/bookmarks/tags`
+    pub async fn bookmark_tags(&self) -> ClientResult<Vec<TagCount>> {
+        self.json(Method::GET, "/api/v1/bookmarks/tags", None::<&()>)
+            .await
+    }
+
+    /// Send a request and parse the JSON response, for endpoints without
+    /// a method of their own
+    pub async fn json<B: Serialize + ?Sized, T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> ClientResult<T> {
+        let bytes = self.send(method.clone(), path, body).await?;
+        let value = serde_json::from_slice(&bytes)
+            .with_context(|| format!("Invalid response to {} {}", method, path))?;
+        Ok(value)
+    }
+
+    /// Send a request, retrying as configured, and return the response body
+    pub async fn send<B: Serialize + ?Sized>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> ClientResult<Bytes> {
+        let body = match body {
+            Some(body) => Some(Bytes::from(
+                serde_json::to_vec(body).context("Failed to encode the request body")?,
+            )),
+            None => None,
+        };
+        let idempotent = matches!(
+            method,
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+        );
+        let mut attempt = 0;
+        loop {
+            let (error, retry_after) = match self.attempt(&method, path, body.as_ref()).await {
+                Ok(Attempt::Done(bytes)) => return Ok(bytes),
+                Ok(Attempt::Failed { error, retry_after }) => {
+                    let status = error.status().unwrap_or_default();
+                    let retryable = status == StatusCode::TOO_MANY_REQUESTS
+                        || (idempotent
+                            && matches!(
+                                status,
+                                StatusCode::BAD_GATEWAY
+                                    | StatusCode::SERVICE_UNAVAILABLE
+                                    | StatusCode::GATEWAY_TIMEOUT
+                            ));
+                    if !retryable {
+                        return Err(error);
+                    }
+                    (error, retry_after)
+                }
+                Err(Unsent::Connect(e)) => (ClientError::Transport(e), None),
+                Err(Unsent::Other(e)) if idempotent => (ClientError::Transport(e), None),
+                Err(Unsent::Other(e)) => return Err(e.into()),
+            };
+            attempt += 1;
+            if attempt > self.retry.retries {
+                return Err(error);
+            }
+            let delay = retry_after
+                .map(|delay| delay.min(self.retry.max_backoff))
+                .unwrap_or_else(|| self.retry.delay(attempt));
+            tracing::debug!(
+                "{} {} failed ({}); retrying in {:?}",
+                method,
+                path,
+                error,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn attempt(
+        &self,
+        method: &Method,
+        path: &str,
+        body: Option<&Bytes>,
+    ) -> Result<Attempt, Unsent> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut request = Request::builder()
+            .method(method)
+            .uri(&url)
+            .header(ACCEPT, "application/json");
+        if body.is_some() {
+            request = request.header(CONTENT_TYPE, "application/json");
+        }
+        let body = body.cloned().unwrap_or_default();
+        match &self.auth {
+            Auth::None => {}
+            Auth::Bearer(value) => request = request.header(AUTHORIZATION, value),
+            Auth::Signed(client) => {
+                let mut nonce = [0u8; 16];
+                rand_core::OsRng.fill_bytes(&mut nonce);
+                let nonce: String = nonce.iter().map(|b| format!("{:02x}", b)).collect();
+                let timestamp = chrono::Utc::now().timestamp();
+                let signature =
+                    signing::authorization(client, method.as_str(), path, timestamp, &nonce, &body);
+                request = request.header(AUTHORIZATION, signature);
+            }
+        }
+        let request = request
+            .body(Full::new(body))
+            .map_err(|e| Unsent::Other(anyhow::anyhow!("Invalid request to {}: {}", url, e)))?;
+
+        let response = match tokio::time::timeout(self.timeout, async {
+            let response = self.http.request(request).await;
+            match response {
+                Ok(response) => {
+                    let (parts, body) = response.into_parts();
+                    let body = body.collect().await.map(|body| body.to_bytes());
+                    Ok((parts, body))
+                }
+                Err(e) if e.is_connect() => Err(Unsent::Connect(
+                    anyhow::Error::new(e).context(format!("Failed to connect to {}", url)),
+                )),
+                Err(e) => Err(Unsent::Other(
+                    anyhow::Error::new(e).context(format!("{} {} failed", method, url)),
+                )),
+            }
+        })
+        .await
+        {
+            Ok(response) => response?,
+            Err(_) => {
+                return Err(Unsent::Other(anyhow::anyhow!(
+                    "{} {} timed out after {:?}",
+                    method,
+                    url,
+                    self.timeout
+                )))
+            }
+        };
+        let (parts, body) = response;
+        let body = body.map_err(|e| {
+            Unsent::Other(anyhow::Error::new(e).context(format!("{} {} failed", method, url)))
+        })?;
+        if parts.status.is_success() {
+            return Ok(Attempt::Done(body));
+        }
+        let message = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|value| value.get("error")?.as_str().map(String::from))
+            .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+        let retry_after = parts
+            .headers
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map(Duration::from_secs);
+        Ok(Attempt::Failed {
+            error: ClientError::Api {
+                status: parts.status,
+                message,
+            },
+            retry_after,
+        })
+    }
+}
+
+/// Outcome of an attempt that got a response
+enum Attempt {
+    Done(Bytes),
+    Failed {
+        error: ClientError,
+        retry_after: Option<Duration>,
+    },
+}
+
+/// An attempt that got no response
+enum Unsent {
+    /// No connection, so the server never saw the request
+    Connect(anyhow::Error),
+    /// The request may or may not have been handled
+    Other(anyhow::Error),
+}
+
+/// Encode a query struct's set fields as a query string
+fn query_string<Q: Serialize>(query: &Q) -> ClientResult<String> {
+    let value = serde_json::to_value(query).context("Failed to encode the query")?;
+    let mut encoded = form_urlencoded::Serializer::new(String::new());
+    for (key, value) in value.as_object().into_iter().flatten() {
+        match value {
+            Value::Null => {}
+            Value::String(value) => {
+                encoded.append_pair(key, value);
+            }
+            other => {
+                encoded.append_pair(key, &other.to_string());
+            }
+        }
+    }
+    Ok(encoded.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{self, ApiAuth, ApiKeys};
+    use crate::signing::RequestVerifier;
+    use axum::{
+        extract::{Path, Query},
+        middleware,
+        routing::get,
+        Json, Router,
+    };
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn note(id: Uuid, title: &str) -> Value {
+        serde_json::json!({
+            "id": id,
+            "title": title,
+            "body": "",
+            "folder": "",
+            "tags": [],
+            "revision": 1,
+            "created_at": "2026-01-01T00:00:00.000Z",
+            "updated_at": "2026-01-01T00:00:00.000Z",
+        })
+    }
+
+    #[tokio::test]
+    async fn test_authenticates_retries_and_reports_errors() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let signer = SigningClient {
+            id: "worker".into(),
+            secret: "s3cret".into(),
+        };
+        let router = Router::new()
+            .route(
+                "/api/v1/notes",
+                get(|Query(query): Query<HashMap<String, String>>| async move {
+                    Json(vec![note(Uuid::nil(), &query["q"])])
+                })
+                .post(|| async { axum::http::StatusCode::SERVICE_UNAVAILABLE }),
+            )
+            .route(
+                "/api/v1/notes/:id",
+                get(move |Path(id): Path<Uuid>| {
+                    let counter = counter.clone();
+                    async move {
+                        // Busy twice, then found
+                        match counter.fetch_add(1, Ordering::SeqCst) {
+                            0 | 1 => Err((
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                [(RETRY_AFTER, "0")],
+                                Json(serde_json::json!({ "error": "busy" })),
+                            )),
+                            _ => Ok(Json(note(id, "Found"))),
+                        }
+                    }
+                })
+                .delete(|| async {
+                    (
+                        StatusCode::NOT_FOUND,
+                        Json(serde_json::json!({ "error": "Note not found" })),
+                    )
+                }),
+            )
+            .route("/whoami", get(|| async { Json(true) }))
+            .layer(middleware::from_fn_with_state(
+                ApiAuth {
+                    keys: ApiKeys::new(vec!["key".into()]),
+                    signatures: RequestVerifier::new(vec![signer.clone()], Duration::from_secs(60)),
+                    group: None,
+                },
+                auth::require_api_key,
+            ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let retry = Retry {
+            retries: 2,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        };
+        let client = Client::new(&base_url)
+            .unwrap()
+            .api_key("key")
+            .unwrap()
+            .retry(retry);
+        let id = Uuid::new_v4();
+        let found = client.note(id).await.unwrap();
+        assert_eq!((found.id, found.title.as_str()), (id, "Found"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Not idempotent, so not retried
+        let error = client.create_note(&NewNote::default()).await.unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        let error = client.delete_note(id).await.unwrap_err();
+        assert_eq!(error.to_string(), "404 Not Found: Note not found");
+
+        let signed = Client::new(&base_url).unwrap().signing(signer);
+        let query = NoteQuery {
+            q: Some("deploy & release".into()),
+            ..NoteQuery::default()
+        };
+        let notes = signed.notes(&query).await.unwrap();
+        assert_eq!(notes[0].title, "deploy & release");
+
+        let anonymous = Client::new(&base_url).unwrap();
+        let error = anonymous
+            .json::<(), bool>(Method::GET, "/whoami", None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::UNAUTHORIZED));
+        let error = Client::new("http://127.0.0.1:1")
+            .unwrap()
+            .retry(Retry {
+                retries: 0,
+                ..retry
+            })
+            .bookmark_tags()
+            .await
+            .unwrap_err();
+        assert!(error.status().is_none());
+    }
+}
@@ -0,0 +1,474 @@
+//! Generic JSONB document collections.
+//!
+//! A schemaless document store for small self-hosted apps. Documents live in
+//! the `collection_docs` table as JSONB, grouped by collection name. A
+//! collection can optionally be declared with a JSON Schema that every
+//! document must satisfy and a list of fields to index. Indexes are built
+//! and dropped concurrently, so declaring a collection does not block
+//! writes to its documents.
+//!
+//! Listing supports equality filters on top-level fields: every query
+//! parameter other than `limit` and `offset` is matched against the
+//! document's field of the same name.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+    routing::{get, put},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::crypto::sha256_hex;
+use crate::db::validate_identifier;
+use crate::error::{ApiError, ApiResult};
+use crate::module::{Migration, MigrationKind, RouteModule};
+use crate::{t, AppState};
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 500;
+
+/// A declared collection with its optional schema and indexed fields
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Collection {
+    pub name: String,
+    pub schema: Option<Value>,
+    pub indexes: Vec<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// A document stored in a collection
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Document {
+    pub id: Uuid,
+    pub collection: String,
+    pub data: Value,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Route module serving the document store
+pub struct CollectionsModule;
+
+impl RouteModule for CollectionsModule {
+    fn name(&self) -> &'static str {
+        "collections"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/api/v1/collections", get(list_collections))
+            .route("/api/v1/collections/:name", put(define_collection))
+            .route(
+                "/api/v1/collections/:name/docs",
+                get(list_documents).post(create_document),
+            )
+            .route(
+                "/api/v1/collections/:name/docs/:id",
+                get(get_document)
+                    .patch(update_document)
+                    .delete(delete_document),
+            )
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[
+            Migration {
+                name: "0001_create_collections",
+                kind: MigrationKind::Expand,
+                sql: "CREATE TABLE IF NOT EXISTS collections (
+                name TEXT PRIMARY KEY,
+                schema JSONB,
+                indexes TEXT[] NOT NULL DEFAULT '{}',
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+
+            CREATE TABLE IF NOT EXISTS collection_docs (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                collection TEXT NOT NULL,
+                data JSONB NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+
+            CREATE INDEX IF NOT EXISTS collection_docs_collection_idx
+                ON collection_docs (collection, created_at);",
+            },
+            Migration {
+                // Field indexes were named `collection_docs_{name}_{field}_idx`
+                name: "0002_hash_index_names",
+                kind: MigrationKind::Expand,
+                sql: "DO $$
+                DECLARE
+                    legacy RECORD;
+                    declared RECORD;
+                BEGIN
+                    FOR legacy IN
+                        SELECT indexname FROM pg_indexes
+                        WHERE schemaname = current_schema() AND tablename = 'collection_docs'
+                            AND indexdef LIKE '%WHERE (collection = %'
+                    LOOP
+                        EXECUTE format('DROP INDEX %I', legacy.indexname);
+                    END LOOP;
+                    FOR declared IN SELECT name, unnest(indexes) AS field FROM collections LOOP
+                        EXECUTE format(
+                            'CREATE INDEX IF NOT EXISTS %I
+                             ON collection_docs ((data->>%L)) WHERE collection = %L',
+                            'collection_docs_' || left(encode(sha256(convert_to(
+                                declared.name || '/' || declared.field, 'UTF8')), 'hex'), 16)
+                                || '_idx',
+                            declared.field,
+                            declared.name
+                        );
+                    END LOOP;
+                END $$;",
+            },
+        ]
+    }
+}
+
+/// Apply an RFC 7386 JSON merge patch to a document
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let target = target.as_object_mut().expect("target is an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Name of the index on `field` of the collection `name`
+///
+/// Hashed, as joining the two is ambiguous and may pass the 63 bytes
+/// Postgres truncates names to.
+fn index_name(name: &str, field: &str) -> String {
+    let hash = sha256_hex(&format!("{}/{}", name, field));
+    format!("collection_docs_{}_idx", &hash[..16])
+}
+
+fn validate_name(name: &str) -> ApiResult<()> {
+    validate_identifier(name).map_err(|e| ApiError::BadRequest(e.to_string()))
+}
+
+/// Validate a document against the collection's schema, if it has one
+async fn validate_document(pool: &PgPool, collection: &str, data: &Value) -> ApiResult<()> {
+    if !data.is_object() {
+        return Err(ApiError::BadRequest(
+            "document must be a JSON object".into(),
+        ));
+    }
+    let schema: Option<Option<Value>> =
+        sqlx::query_scalar("SELECT schema FROM collections WHERE name = $1")
+            .bind(collection)
+            .fetch_optional(pool)
+            .await?;
+    if let Some(Some(schema)) = schema {
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| anyhow::anyhow!("Stored schema for {} is invalid: {}", collection, e))?;
+        let errors: Vec<String> = validator.iter_errors(data).map(|e| e.to_string()).collect();
+        if !errors.is_empty() {
+            return Err(ApiError::Validation(errors.join("; ")));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DefineCollection {
+    schema: Option<Value>,
+    #[serde(default)]
+    indexes: Vec<String>,
+}
+
+/// `GET /api/v1/collections` - list declared collections
+pub async fn list_collections(State(state): State<AppState>) -> ApiResult<Json<Vec<Collection>>> {
+    let collections = sqlx::query_as::<_, Collection>(
+        "SELECT name, schema, indexes, created_at FROM collections ORDER BY name",
+    )
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(collections))
+}
+
+/// `PUT /api/v1/collections/:name` - declare a collection's schema and indexes
+pub async fn define_collection(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(body): Json<DefineCollection>,
+) -> ApiResult<Json<Collection>> {
+    validate_name(&name)?;
+    if let Some(schema) = &body.schema {
+        jsonschema::validator_for(schema)
+            .map_err(|e| ApiError::BadRequest(format!("invalid JSON Schema: {}", e)))?;
+    }
+    for field in &body.indexes {
+        validate_name(field)?;
+    }
+
+    let pool = state.db.pool();
+    let previous: Vec<String> =
+        sqlx::query_scalar("SELECT indexes FROM collections WHERE name = $1")
+            .bind(&name)
+            .fetch_optional(pool)
+            .await?
+            .unwrap_or_default();
+    let collection = sqlx::query_as::<_, Collection>(
+        "INSERT INTO collections (name, schema, indexes) VALUES ($1, $2, $3)
+         ON CONFLICT (name) DO UPDATE SET schema = EXCLUDED.schema, indexes = EXCLUDED.indexes
+         RETURNING name, schema, indexes, created_at",
+    )
+    .bind(&name)
+    .bind(&body.schema)
+    .bind(&body.indexes)
+    .fetch_one(pool)
+    .await?;
+
+    // Concurrent builds cannot run in a transaction, so each statement runs
+    // on its own. Both identifiers are validated above, so interpolation is
+    // safe
+    for field in &body.indexes {
+        let index = index_name(&name, field);
+        let created = sqlx::raw_sql(&format!(
+            "CREATE INDEX CONCURRENTLY IF NOT EXISTS \"{index}\"
+             ON collection_docs ((data->>'{field}')) WHERE collection = '{name}'"
+        ))
+        .execute(pool)
+        .await;
+        if let Err(e) = created {
+            // A failed build leaves an invalid index behind, which
+            // `IF NOT EXISTS` would keep from being built again
+            sqlx::raw_sql(&format!("DROP INDEX CONCURRENTLY IF EXISTS \"{index}\""))
+                .execute(pool)
+                .await?;
+            return Err(e.into());
+        }
+    }
+    for field in previous.iter().filter(|f| !body.indexes.contains(f)) {
+        sqlx::raw_sql(&format!(
+            "DROP INDEX CONCURRENTLY IF EXISTS \"{}\"",
+            index_name(&name, field)
+        ))
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(Json(collection))
+}
+
+/// `POST /api/v1/collections/:name/docs` - insert a document
+pub async fn create_document(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(data): Json<Value>,
+) -> ApiResult<(StatusCode, Json<Document>)> {
+    validate_name(&name)?;
+    validate_document(state.db.pool(), &name, &data).await?;
+
+    let document = sqlx::query_as::<_, Document>(
+        "INSERT INTO collection_docs (collection, data) VALUES ($1, $2)
+         RETURNING id, collection, data, created_at, updated_at",
+    )
+    .bind(&name)
+    .bind(&data)
+    .fetch_one(state.db.pool())
+    .await?;
+    Ok((StatusCode::CREATED, Json(document)))
+}
+
+/// Paging and field filters from a document list query string
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentFilter {
+    pub limit: i64,
+    pub offset: i64,
+    /// `(field, value)` pairs with validated field names, sorted by field
+    pub fields: Vec<(String, String)>,
+}
+
+/// Parse `limit`, `offset` and field equality filters from query parameters
+pub fn parse_filter(mut params: HashMap<String, String>) -> ApiResult<DocumentFilter> {
+    let parse = |value: Option<String>, default: i64| -> ApiResult<i64> {
+        value.map_or(Ok(default), |v| {
+            v.parse()
+                .map_err(|_| ApiError::BadRequest(format!("invalid number '{}'", v)))
+        })
+    };
+    let limit = parse(params.remove("limit"), DEFAULT_LIMIT)?.clamp(1, MAX_LIMIT);
+    let offset = parse(params.remove("offset"), 0)?.max(0);
+    let mut fields: Vec<(String, String)> = params.into_iter().collect();
+    fields.sort();
+    for (field, _) in &fields {
+        validate_name(field)?;
+    }
+    Ok(DocumentFilter {
+        limit,
+        offset,
+        fields,
+    })
+}
+
+/// `GET /api/v1/collections/:name/docs` - list documents with field filters
+pub async fn list_documents(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> ApiResult<Json<Vec<Document>>> {
+    validate_name(&name)?;
+    let filter = parse_filter(params)?;
+
+    let mut query: QueryBuilder<Postgres> = QueryBuilder::new(
+        "SELECT id, collection, data, created_at, updated_at FROM collection_docs WHERE collection = ",
+    );
+    query.push_bind(&name);
+    for (field, value) in &filter.fields {
+        query
+            .push(" AND data->>")
+            .push_bind(field)
+            .push(" = ")
+            .push_bind(value);
+    }
+    query
+        .push(" ORDER BY created_at, id LIMIT ")
+        .push_bind(filter.limit)
+        .push(" OFFSET ")
+        .push_bind(filter.offset);
+
+    let documents = query
+        .build_query_as::<Document>()
+        .fetch_all(state.db.pool())
+        .await?;
+    Ok(Json(documents))
+}
+
+/// `GET /api/v1/collections/:name/docs/:id` - fetch a single document
+pub async fn get_document(
+    State(state): State<AppState>,
+    Path((name, id)): Path<(String, Uuid)>,
+) -> ApiResult<Json<Document>> {
+    let document = sqlx::query_as::<_, Document>(
+        "SELECT id, collection, data, created_at, updated_at FROM collection_docs
+         WHERE collection = $1 AND id = $2",
+    )
+    .bind(&name)
+    .bind(id)
+    .fetch_optional(state.db.pool())
+    .await?
+    .ok_or_else(|| ApiError::NotFound(t!("document-not-found")))?;
+    Ok(Json(document))
+}
+
+/// `PATCH /api/v1/collections/:name/docs/:id` - merge-patch a document
+pub async fn update_document(
+    State(state): State<AppState>,
+    Path((name, id)): Path<(String, Uuid)>,
+    Json(patch): Json<Value>,
+) -> ApiResult<Json<Document>> {
+    let mut tx = state.db.pool().begin().await?;
+    let mut data: Value = sqlx::query_scalar(
+        "SELECT data FROM collection_docs WHERE collection = $1 AND id = $2 FOR UPDATE",
+    )
+    .bind(&name)
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(t!("document-not-found")))?;
+
+    merge_patch(&mut data, &patch);
+    validate_document(state.db.pool(), &name, &data).await?;
+
+    let document = sqlx::query_as::<_, Document>(
+        "UPDATE collection_docs SET data = $3, updated_at = now()
+         WHERE collection = $1 AND id = $2
+         RETURNING id, collection, data, created_at, updated_at",
+    )
+    .bind(&name)
+    .bind(id)
+    .bind(&data)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Json(document))
+}
+
+/// `DELETE /api/v1/collections/:name/docs/:id` - delete a document
+pub async fn delete_document(
+    State(state): State<AppState>,
+    Path((name, id)): Path<(String, Uuid)>,
+) -> ApiResult<StatusCode> {
+    let result = sqlx::query("DELETE FROM collection_docs WHERE collection = $1 AND id = $2")
+        .bind(&name)
+        .bind(id)
+        .execute(state.db.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(t!("document-not-found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge_patch() {
+        let mut doc = json!({"title": "a", "tags": ["x"], "meta": {"views": 1, "draft": true}});
+        merge_patch(
+            &mut doc,
+            &json!({"title": "b", "meta": {"draft": null, "views": 2}}),
+        );
+        assert_eq!(
+            doc,
+            json!({"title": "b", "tags": ["x"], "meta": {"views": 2}})
+        );
+    }
+
+    #[test]
+    fn test_merge_patch_replaces_non_objects() {
+        let mut doc = json!({"tags": ["x"]});
+        merge_patch(&mut doc, &json!({"tags": ["y", "z"]}));
+        assert_eq!(doc, json!({"tags": ["y", "z"]}));
+    }
+
+    #[test]
+    fn test_index_name() {
+        assert_ne!(index_name("a_b", "c"), index_name("a", "b_c"));
+        let long = "x".repeat(63);
+        assert_ne!(index_name(&long, "a"), index_name(&long, "b"));
+        assert!(index_name(&long, &long).len() <= 63);
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        let params = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+        let filter = parse_filter(params(&[("limit", "9999"), ("title", "a")])).unwrap();
+        assert_eq!(filter.limit, MAX_LIMIT);
+        assert_eq!(filter.offset, 0);
+        assert_eq!(filter.fields, vec![("title".into(), "a".into())]);
+        assert!(parse_filter(params(&[("offset", "x")])).is_err());
+        assert!(parse_filter(params(&[("bad field", "a")])).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Per-identity concurrency limits.
+//!
+//! Rate limiting bounds how often a client may call; it does not stop one
+//! client from holding many slow requests open at once and starving
+//! everyone else of workers and database connections. The
+//! `concurrency_limit` middleware caps the requests in flight per API key,
+//! signing client or logged-in user at `CONCURRENCY_LIMIT` and answers
+//! others with 429 until one finishes. Anonymous requests are not limited;
+//! list the layer after `auth` so only valid credentials get a slot.
+
+use axum::{
+    extract::{Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::anomaly;
+use crate::sessions::SessionHandle;
+
+/// In-flight request counts keyed by identity
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter {
+    limit: usize,
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+/// A held slot, released when dropped
+#[derive(Debug)]
+pub struct Slot {
+    identity: String,
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().expect("concurrency lock poisoned");
+        if let Some(count) = in_flight.get_mut(&self.identity) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.identity);
+            }
+        }
+    }
+}
+
+impl ConcurrencyLimiter {
+    /// Create a limiter allowing `limit` requests in flight per identity
+    pub fn new(limit: u32) -> Self {
+        ConcurrencyLimiter {
+            limit: limit.max(1) as usize,
+            in_flight: Arc::default(),
+        }
+    }
+
+    /// Take a slot for `identity`, or `None` when all of its slots are held
+    pub fn acquire(&self, identity: &str) -> Option<Slot> {
+        let mut in_flight = self.in_flight.lock().expect("concurrency lock poisoned");
+        let count = in_flight.entry(identity.to_string()).or_default();
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(Slot {
+            identity: identity.to_string(),
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    /// Requests in flight for `identity`
+    pub fn in_flight(&self, identity: &str) -> usize {
+        self.in_flight
+            .lock()
+            .expect("concurrency lock poisoned")
+            .get(identity)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Middleware rejecting requests over their identity's limit with 429
+    pub async fn middleware(
+        State(limiter): State<ConcurrencyLimiter>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let Some(identity) = identity(&request) else {
+            return next.run(request).await;
+        };
+        match limiter.acquire(&identity) {
+            Some(_slot) => next.run(request).await,
+            None => {
+                let mut response = (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    axum::Json(json!({ "error": "too many concurrent requests" })),
+                )
+                    .into_response();
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(1));
+                response
+            }
+        }
+    }
+}
+
+/// Who a request's slot is counted against, if anyone
+fn identity(request: &Request) -> Option<String> {
+    anomaly::account(request).or_else(|| {
+        request
+            .extensions()
+            .get::<SessionHandle>()
+            .map(|session| format!("user:{}", session.user_id()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::Service;
+
+    #[test]
+    fn test_slots_are_released_on_drop() {
+        let limiter = ConcurrencyLimiter::new(2);
+        let first = limiter.acquire("token:a").unwrap();
+        let _second = limiter.acquire("token:a").unwrap();
+        assert!(limiter.acquire("token:a").is_none());
+        assert!(limiter.acquire("token:b").is_some());
+
+        drop(first);
+        assert_eq!(limiter.in_flight("token:a"), 1);
+        assert!(limiter.acquire("token:a").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_requests_over_the_limit() {
+        let limiter = ConcurrencyLimiter::new(1);
+        let app =
+            Router::new()
+                .route("/", get(|| async { "ok" }))
+                .layer(middleware::from_fn_with_state(
+                    limiter.clone(),
+                    ConcurrencyLimiter::middleware,
+                ));
+        let send = |token: Option<&str>| {
+            let mut request = Request::builder().uri("/");
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            let mut app = app.clone();
+            let request = request.body(Body::empty()).unwrap();
+            async move { app.call(request).await.unwrap() }
+        };
+
+        let busy = anomaly::account(
+            &Request::builder()
+                .header("authorization", "Bearer busy")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .unwrap();
+        let _slot = limiter.acquire(&busy).unwrap();
+        let response = send(Some("busy")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+        assert_eq!(send(Some("idle")).await.status(), StatusCode::OK);
+        assert_eq!(send(None).await.status(), StatusCode::OK);
+    }
+}
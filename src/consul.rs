@@ -0,0 +1,171 @@
+//! Consul service registration.
+//!
+//! When `CONSUL_ADDR` is set the instance registers itself with the local
+//! Consul agent once it is ready, with a TTL check that is kept up to date
+//! from the [`HealthRegistry`]: healthy reports pass, degraded ones warn and
+//! unavailable ones (including while draining) are critical. The service is
+//! deregistered on graceful shutdown.
+
+use anyhow::Result;
+use hyper::{Method, StatusCode};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+
+use crate::health::{HealthRegistry, Status, CHECK_TIMEOUT};
+use crate::http_client::HttpClient;
+
+/// How to register with the Consul agent
+#[derive(Debug, Clone)]
+pub struct ConsulConfig {
+    pub addr: String,
+    pub service_name: String,
+    pub service_id: String,
+    pub address: Option<String>,
+    pub tags: Vec<String>,
+    pub ttl: Duration,
+}
+
+/// A registration with the Consul agent
+#[derive(Clone)]
+pub struct Consul {
+    client: HttpClient,
+    config: ConsulConfig,
+    heartbeat: Arc<Mutex<Option<AbortHandle>>>,
+}
+
+impl Consul {
+    pub fn new(config: ConsulConfig) -> Self {
+        Consul {
+            client: HttpClient::new(Duration::from_secs(5)),
+            config,
+            heartbeat: Arc::default(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{}/v1/agent/{}",
+            self.config.addr.trim_end_matches('/'),
+            path
+        )
+    }
+
+    fn check_id(&self) -> String {
+        format!("{}:ttl", self.config.service_id)
+    }
+
+    /// Register the service and its TTL check
+    pub async fn register(&self, port: u16) -> Result<()> {
+        let body = registration(&self.config, &self.check_id(), port);
+        let (status, _) = self
+            .client
+            .request(Method::PUT, &self.url("service/register"), Some(&body))
+            .await?;
+        anyhow::ensure!(
+            status == StatusCode::OK,
+            "Consul registration returned {}",
+            status
+        );
+        tracing::info!(
+            "📇 Registered '{}' with Consul as '{}'",
+            self.config.service_name,
+            self.config.service_id
+        );
+        Ok(())
+    }
+
+    /// Keep the TTL check updated from the health registry
+    pub fn spawn_heartbeat(&self, health: HealthRegistry) {
+        let consul = self.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(consul.config.ttl / 3);
+            loop {
+                interval.tick().await;
+                let report = health.run(CHECK_TIMEOUT).await;
+                let body = json!({
+                    "Status": check_status(report.status),
+                    "Output": serde_json::to_string(&report).unwrap_or_default(),
+                });
+                let url = consul.url(&format!("check/update/{}", consul.check_id()));
+                match consul.client.request(Method::PUT, &url, Some(&body)).await {
+                    Ok((StatusCode::OK, _)) => {}
+                    Ok((status, _)) => tracing::warn!("Consul check update returned {}", status),
+                    Err(e) => tracing::warn!("Consul check update failed: {:#}", e),
+                }
+            }
+        });
+        *self.heartbeat.lock().expect("consul lock poisoned") = Some(task.abort_handle());
+    }
+
+    /// Remove the service from the agent
+    pub async fn deregister(&self) -> Result<()> {
+        if let Some(heartbeat) = self.heartbeat.lock().expect("consul lock poisoned").take() {
+            heartbeat.abort();
+        }
+        let url = self.url(&format!("service/deregister/{}", self.config.service_id));
+        let (status, _) = self.client.request(Method::PUT, &url, None).await?;
+        anyhow::ensure!(
+            status == StatusCode::OK,
+            "Consul deregistration returned {}",
+            status
+        );
+        tracing::info!("Deregistered '{}' from Consul", self.config.service_id);
+        Ok(())
+    }
+}
+
+/// Body for `PUT /v1/agent/service/register`
+fn registration(config: &ConsulConfig, check_id: &str, port: u16) -> Value {
+    let mut body = json!({
+        "ID": config.service_id,
+        "Name": config.service_name,
+        "Tags": config.tags,
+        "Port": port,
+        "Check": {
+            "CheckID": check_id,
+            "Name": "Service readiness",
+            "TTL": format!("{}s", config.ttl.as_secs()),
+            // Clean up after instances that die without deregistering
+            "DeregisterCriticalServiceAfter": "10m",
+        },
+    });
+    if let Some(address) = &config.address {
+        body["Address"] = json!(address);
+    }
+    body
+}
+
+/// Consul check status for a health report status
+fn check_status(status: Status) -> &'static str {
+    match status {
+        Status::Ok => "passing",
+        Status::Degraded => "warning",
+        Status::Unavailable => "critical",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registration_body() {
+        let config = ConsulConfig {
+            addr: "http://127.0.0.1:8500".to_string(),
+            service_name: "selfhost".to_string(),
+            service_id: "selfhost-web-1".to_string(),
+            address: None,
+            tags: vec!["api".to_string()],
+            ttl: Duration::from_secs(15),
+        };
+
+        let body = registration(&config, "selfhost-web-1:ttl", 3000);
+        assert_eq!(body["ID"], "selfhost-web-1");
+        assert_eq!(body["Port"], 3000);
+        assert_eq!(body["Check"]["TTL"], "15s");
+        assert!(body.get("Address").is_none());
+        assert_eq!(check_status(Status::Degraded), "warning");
+    }
+}
@@ -0,0 +1,431 @@
+//! Crash reports.
+//!
+//! A panic hook, chained after the redacting one from
+//! [`redact::install_panic_hook`], writes a JSON report of every panic to
+//! `CRASH_DIR`: the message, location, thread, a backtrace, build
+//! information and, when the panic happened while handling a request, the
+//! request's id, method and path. Writing a file needs nothing from the
+//! async runtime, so reports survive release builds aborting on panic.
+//!
+//! A background task imports the files into `crash_reports` at startup and
+//! every few seconds after, deleting them; with `CRASH_ALERTS` each new
+//! report is also posted to `HEALTH_ALERT_WEBHOOK`. Reports are listed at
+//! `GET /admin/crashes`.
+//!
+//! [`middleware`] gives every request an id, taken from `X-Request-Id` or
+//! generated, and echoes it in the response so a report can be matched to
+//! the request that caused it.
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::{Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use std::path::{Path as FsPath, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiResult};
+use crate::http_client::HttpClient;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::redact;
+use crate::time::Timestamp;
+use crate::{t, AppState};
+
+/// Header carrying the request id
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// How often reports are imported from disk
+const IMPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Timeout for posting a report to the alert webhook
+const ALERT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The request being handled when a panic happened
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestInfo {
+    pub id: String,
+    pub method: String,
+    pub path: String,
+}
+
+tokio::task_local! {
+    static REQUEST: RequestInfo;
+}
+
+/// The id of the request being handled by the current task, if any
+pub fn current_request_id() -> Option<String> {
+    REQUEST.try_with(|request| request.id.clone()).ok()
+}
+
+/// The binary that crashed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub name: String,
+    pub version: String,
+    pub target: String,
+    pub profile: String,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        BuildInfo {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            profile: if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            }
+            .to_string(),
+        }
+    }
+}
+
+/// One panic, with secrets masked
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct CrashReport {
+    pub id: Uuid,
+    pub message: String,
+    pub location: Option<String>,
+    pub thread: String,
+    pub backtrace: String,
+    #[sqlx(json(nullable))]
+    pub request: Option<RequestInfo>,
+    #[sqlx(json)]
+    pub build: BuildInfo,
+    pub instance: String,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl CrashReport {
+    /// Describe a panic on the current thread
+    pub fn capture(message: &str, location: Option<String>, instance: &str) -> Self {
+        let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+        CrashReport {
+            id: Uuid::new_v4(),
+            message: redact::redact(message).into_owned(),
+            location,
+            thread: std::thread::current()
+                .name()
+                .unwrap_or("<unnamed>")
+                .to_string(),
+            backtrace: redact::redact(&backtrace).into_owned(),
+            request: REQUEST.try_with(Clone::clone).ok(),
+            build: BuildInfo::current(),
+            instance: instance.to_string(),
+            created_at: Utc::now().trunc_subsecs(3),
+        }
+    }
+
+    /// Write the report to `dir`, renaming it into place once complete
+    pub fn write(&self, dir: &FsPath) -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let staged = dir.join(format!(".{}.json.tmp", self.id));
+        let path = dir.join(format!(
+            "{}-{}.json",
+            self.created_at.format("%Y%m%dT%H%M%S%.3fZ"),
+            self.id
+        ));
+        std::fs::write(&staged, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&staged, &path)?;
+        Ok(path)
+    }
+}
+
+/// Also write a crash report for every panic
+pub fn install_hook(dir: PathBuf, instance: String) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()));
+        let report = CrashReport::capture(redact::panic_message(info), location, &instance);
+        match report.write(&dir) {
+            Ok(path) => eprintln!("Crash report written to {}", path.display()),
+            Err(e) => eprintln!("Failed to write crash report to {}: {}", dir.display(), e),
+        }
+    }));
+}
+
+/// Whether a client-supplied request id is safe to log and echo
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Middleware assigning a request id and recording the request for reports
+pub async fn middleware(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| valid_request_id(id))
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let info = RequestInfo {
+        id: id.clone(),
+        method: request.method().to_string(),
+        path: redact::redact(request.uri().path()).into_owned(),
+    };
+    let mut response = REQUEST.scope(info, next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+/// Move reports from `dir` into the database, returning those new to it
+pub async fn import(pool: &PgPool, dir: &FsPath) -> anyhow::Result<Vec<CrashReport>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut imported = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let report: CrashReport = match serde_json::from_slice(&tokio::fs::read(&path).await?) {
+            Ok(report) => report,
+            Err(e) => {
+                tracing::warn!("Skipping unreadable crash report {}: {}", path.display(), e);
+                tokio::fs::rename(&path, path.with_extension("invalid")).await?;
+                continue;
+            }
+        };
+        let inserted = sqlx::query(
+            "INSERT INTO crash_reports
+                (id, message, location, thread, backtrace, request, build, instance, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+             ON CONFLICT (id) DO NOTHING",
+        )
+        .bind(report.id)
+        .bind(&report.message)
+        .bind(&report.location)
+        .bind(&report.thread)
+        .bind(&report.backtrace)
+        .bind(report.request.as_ref().map(sqlx::types::Json))
+        .bind(sqlx::types::Json(&report.build))
+        .bind(&report.instance)
+        .bind(report.created_at)
+        .execute(pool)
+        .await?
+        .rows_affected();
+        tokio::fs::remove_file(&path).await?;
+        if inserted == 1 {
+            imported.push(report);
+        }
+    }
+    Ok(imported)
+}
+
+async fn alert(client: &HttpClient, webhook: &str, report: &CrashReport) {
+    let body = json!({
+        "crash": report.id,
+        "message": report.message,
+        "location": report.location,
+        "request_id": report.request.as_ref().map(|r| &r.id),
+        "instance": report.instance,
+        "version": report.build.version,
+    });
+    match client.request(Method::POST, webhook, Some(&body)).await {
+        Ok((status, _)) if status.is_success() => {}
+        Ok((status, _)) => tracing::warn!("Crash alert webhook returned {}", status),
+        Err(e) => tracing::warn!("Crash alert webhook failed: {:#}", e),
+    }
+}
+
+/// Import reports from `dir` now and every few seconds
+pub fn spawn(pool: PgPool, dir: PathBuf, webhook: Option<String>) {
+    let client = HttpClient::new(ALERT_TIMEOUT);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(IMPORT_INTERVAL);
+        loop {
+            interval.tick().await;
+            match import(&pool, &dir).await {
+                Ok(reports) => {
+                    for report in reports {
+                        tracing::error!(
+                            "💥 Crash {} at {}: {}",
+                            report.id,
+                            report.location.as_deref().unwrap_or("unknown location"),
+                            report.message
+                        );
+                        if let Some(webhook) = &webhook {
+                            alert(&client, webhook, &report).await;
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to import crash reports: {:#}", e),
+            }
+        }
+    });
+}
+
+/// Route module serving `/admin/crashes`
+pub struct CrashesModule;
+
+impl RouteModule for CrashesModule {
+    fn name(&self) -> &'static str {
+        "crashes"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/admin/crashes", get(list_crashes))
+            .route("/admin/crashes/:id", get(get_crash))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_crash_reports",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS crash_reports (
+                id UUID PRIMARY KEY,
+                message TEXT NOT NULL,
+                location TEXT,
+                thread TEXT NOT NULL,
+                backtrace TEXT NOT NULL,
+                request JSONB,
+                build JSONB NOT NULL,
+                instance TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS crash_reports_created_at ON crash_reports (created_at)",
+        }]
+    }
+}
+
+const COLUMNS: &str =
+    "id, message, location, thread, backtrace, request, build, instance, created_at";
+
+#[derive(Debug, Deserialize)]
+pub struct CrashesQuery {
+    #[serde(default = "default_limit")]
+    limit: i64,
+    /// Only crashes at or after this RFC 3339 timestamp
+    since: Option<Timestamp>,
+}
+
+fn default_limit() -> i64 {
+    50
+}
+
+/// `GET /admin/crashes` - most recent crashes first
+pub async fn list_crashes(
+    State(state): State<AppState>,
+    Query(query): Query<CrashesQuery>,
+) -> ApiResult<Json<Vec<CrashReport>>> {
+    let reports = sqlx::query_as(&format!(
+        "SELECT {} FROM crash_reports
+         WHERE ($2::timestamptz IS NULL OR created_at >= $2)
+         ORDER BY created_at DESC, id LIMIT $1",
+        COLUMNS
+    ))
+    .bind(query.limit.clamp(1, 500))
+    .bind(query.since.map(|since| since.0))
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(reports))
+}
+
+/// `GET /admin/crashes/:id` - one crash report
+pub async fn get_crash(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<CrashReport>> {
+    sqlx::query_as(&format!(
+        "SELECT {} FROM crash_reports WHERE id = $1",
+        COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(state.db.pool())
+    .await?
+    .map(Json)
+    .ok_or_else(|| ApiError::NotFound(t!("crash-not-found")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reports_are_written_and_imported() {
+        let request = RequestInfo {
+            id: "req-1".to_string(),
+            method: "POST".to_string(),
+            path: "/api/v1/notes".to_string(),
+        };
+        let report = REQUEST
+            .scope(request.clone(), async {
+                assert_eq!(current_request_id().as_deref(), Some("req-1"));
+                CrashReport::capture(
+                    "index out of bounds, token=abcdef",
+                    Some("src/notes.rs:1:1".to_string()),
+                    "node-1",
+                )
+            })
+            .await;
+        assert_eq!(report.request, Some(request));
+        assert_eq!(report.message, "index out of bounds, token=***");
+        assert!(!report.backtrace.is_empty());
+        assert_eq!(current_request_id(), None);
+
+        let dir = std::env::temp_dir().join(format!("crashes-{}", std::process::id()));
+        let path = report.write(&dir).unwrap();
+        let written: CrashReport = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written, report);
+
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        };
+        let pool = &db.pool;
+        crate::module::run_migrations(pool, &[std::sync::Arc::new(CrashesModule)])
+            .await
+            .unwrap();
+        assert_eq!(
+            import(pool, &dir).await.unwrap(),
+            std::slice::from_ref(&report)
+        );
+        assert!(!path.exists());
+        let stored: CrashReport = sqlx::query_as(&format!(
+            "SELECT {} FROM crash_reports WHERE id = $1",
+            COLUMNS
+        ))
+        .bind(report.id)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert_eq!(stored, report);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_valid_request_id() {
+        assert!(valid_request_id("0f8e2b1c-aa:01.x_y"));
+        assert!(!valid_request_id(""));
+        assert!(!valid_request_id("a b"));
+        assert!(!valid_request_id(&"a".repeat(129)));
+    }
+}
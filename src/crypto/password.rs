@@ -0,0 +1,181 @@
+//! Password hashing with Argon2id.
+//!
+//! New hashes use Argon2id with the cost installed from `PASSWORD_HASH_*`
+//! (see [`install`]), defaulting to the OWASP-recommended 19 MiB, two
+//! passes and one lane. Hashes made with another Argon2 variant, an older
+//! version or a lower cost still verify, and [`needs_rehash`] tells the
+//! caller to replace them once the password is known, i.e. on login.
+//!
+//! Hashing takes tens of milliseconds of CPU by design. Request handlers
+//! use the `_async` variants, which run it on the blocking thread pool so
+//! concurrent logins do not stall every other request.
+
+use anyhow::Result;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, PasswordVerifier, Version};
+use std::sync::RwLock;
+
+/// Cost of new password hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashParams {
+    /// Memory in KiB
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for HashParams {
+    fn default() -> Self {
+        HashParams {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl HashParams {
+    /// Check the parameters are accepted by Argon2
+    pub fn validate(&self) -> Result<()> {
+        self.argon2().map(|_| ())
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| anyhow::anyhow!("invalid Argon2 parameters: {}", e))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+static PARAMS: RwLock<Option<HashParams>> = RwLock::new(None);
+
+/// Use `params` for every new hash from now on
+pub fn install(params: HashParams) {
+    *PARAMS.write().expect("hash params poisoned") = Some(params);
+}
+
+/// The installed hash parameters
+pub fn params() -> HashParams {
+    PARAMS
+        .read()
+        .expect("hash params poisoned")
+        .unwrap_or_default()
+}
+
+/// Hash a password with Argon2id and a random salt
+pub fn hash(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = params()
+        .argon2()?
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// Check a password against a stored hash of any Argon2 variant
+pub fn verify(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        // The variant, version and cost are read from the hash itself
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Spend the time of a verification, for accounts that do not exist
+///
+/// Answering unknown usernames faster than wrong passwords would reveal
+/// which accounts exist.
+pub fn verify_dummy(password: &str) {
+    let _ = hash(password);
+}
+
+/// [`hash`] on the blocking thread pool
+pub async fn hash_async(password: &str) -> Result<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || hash(&password)).await?
+}
+
+/// [`verify`] on the blocking thread pool
+pub async fn verify_async(password: &str, hash: &str) -> Result<bool> {
+    let (password, hash) = (password.to_string(), hash.to_string());
+    Ok(tokio::task::spawn_blocking(move || verify(&password, &hash)).await?)
+}
+
+/// [`verify_dummy`] on the blocking thread pool
+pub async fn verify_dummy_async(password: &str) -> Result<()> {
+    let password = password.to_string();
+    Ok(tokio::task::spawn_blocking(move || verify_dummy(&password)).await?)
+}
+
+/// Whether a hash is weaker than, or made differently from, new hashes
+pub fn needs_rehash(hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    if hash.algorithm != Algorithm::Argon2id.ident() || hash.version != Some(Version::V0x13.into())
+    {
+        return true;
+    }
+    let current = params();
+    Params::try_from(&hash).map_or(true, |p| {
+        p.m_cost() < current.memory_kib
+            || p.t_cost() < current.iterations
+            || p.p_cost() < current.parallelism
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_verify_and_rehash() {
+        let hash = hash("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$"));
+        assert!(verify("correct horse", &hash));
+        assert!(!verify("wrong horse", &hash));
+        assert!(!verify("correct horse", "not a hash"));
+        assert!(!needs_rehash(&hash));
+
+        let weak = HashParams {
+            memory_kib: 8,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let salt = SaltString::generate(&mut OsRng);
+        let legacy = Argon2::new(
+            Algorithm::Argon2i,
+            Version::V0x13,
+            Params::new(weak.memory_kib, weak.iterations, weak.parallelism, None).unwrap(),
+        )
+        .hash_password(b"correct horse", &salt)
+        .unwrap()
+        .to_string();
+        assert!(verify("correct horse", &legacy));
+        assert!(needs_rehash(&legacy));
+
+        let cheap = weak.argon2().unwrap();
+        let cheap = cheap
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify("correct horse", &cheap));
+        assert!(needs_rehash(&cheap));
+        assert!(needs_rehash("not a hash"));
+        assert!(HashParams {
+            memory_kib: 1,
+            ..weak
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_async_variants() {
+        let hash = hash_async("correct horse").await.unwrap();
+        assert!(verify_async("correct horse", &hash).await.unwrap());
+        assert!(!verify_async("wrong horse", &hash).await.unwrap());
+        verify_dummy_async("correct horse").await.unwrap();
+    }
+}
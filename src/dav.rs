@@ -0,0 +1,510 @@
+//! CalDAV and CardDAV access for calendar and contacts clients.
+//!
+//! With `DAV=true`, phones and desktop clients can sync against the server
+//! directly, signing in with a user account over HTTP Basic auth:
+//!
+//! - `/dav/calendar/` is a read-only calendar holding every event, one
+//!   `<id>.ics` resource each (see [`caldav`]). Events are still created
+//!   and changed through `/api/v1/events`.
+//! - `/dav/contacts/` is an address book of vCards that clients create,
+//!   change and delete (see [`carddav`]).
+//!
+//! Both answer `PROPFIND`, the multiget and query `REPORT`s and RFC 6578
+//! `sync-collection`. Each resource has an ETag and each collection a
+//! `getctag` and sync token that change with any member, so clients only
+//! fetch what changed. Deletions are not tracked: a stale sync token is
+//! refused with `valid-sync-token`, and the client lists the collection
+//! again. `/.well-known/caldav` and `/.well-known/carddav` point clients at
+//! `/dav/`, which is both the principal and the home of the collections.
+//!
+//! With `STORAGE_DIR` set as well, `/dav/files/` serves the stored files as
+//! a WebDAV share that can be mounted as a network drive (see [`files`]).
+//!
+//! Events, contacts and files are shared by every user, as in the JSON API.
+
+pub mod caldav;
+pub mod carddav;
+pub mod files;
+pub mod xml;
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{
+        header::{ALLOW, AUTHORIZATION, LOCATION, RETRY_AFTER, WWW_AUTHENTICATE},
+        request::Parts,
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::backoff::Backoff;
+use crate::error::{ApiError, ApiResult};
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::users::{self, User};
+use crate::{t, AppState};
+use xml::{Element, Multistatus, PropFind, Resource};
+
+pub const DAV: &str = "DAV:";
+pub const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+pub const CARDDAV: &str = "urn:ietf:params:xml:ns:carddav";
+/// Namespace of `getctag`, which clients poll before syncing
+pub const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+
+/// The principal, which is also the home of the collections
+pub const ROOT: &str = "/dav/";
+
+/// Compliance classes announced in the `DAV` header
+const COMPLIANCE: &str = "1, 3, calendar-access, addressbook";
+
+/// Route module for `/dav/`, mounted with `DAV=true`
+pub struct DavModule;
+
+impl RouteModule for DavModule {
+    fn name(&self) -> &'static str {
+        "dav"
+    }
+
+    fn group(&self) -> RouteGroup {
+        // Authenticated with user accounts by `DavUser`
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/.well-known/caldav", any(well_known))
+            .route("/.well-known/carddav", any(well_known))
+            .route("/dav", any(root))
+            .route("/dav/", any(root))
+            .route("/dav/calendar", any(caldav::collection))
+            .route("/dav/calendar/", any(caldav::collection))
+            .route("/dav/calendar/:resource", any(caldav::resource))
+            .route("/dav/contacts", any(carddav::collection))
+            .route("/dav/contacts/", any(carddav::collection))
+            .route("/dav/contacts/:resource", any(carddav::resource))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_contacts",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS contacts (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                resource TEXT NOT NULL UNIQUE,
+                uid TEXT NOT NULL,
+                full_name TEXT NOT NULL DEFAULT '',
+                vcard TEXT NOT NULL,
+                revision INTEGER NOT NULL DEFAULT 0,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        }]
+    }
+}
+
+/// How long credentials that signed in are trusted without a password check
+const CREDENTIALS_TTL: Duration = Duration::from_secs(60);
+
+/// Entries beyond this count trigger eviction of expired ones
+const MAX_TRACKED: usize = 10_000;
+
+/// Sign-in state shared by DAV requests
+///
+/// Clients sync in bursts of requests that each carry the password, so
+/// credentials that signed in are remembered for [`CREDENTIALS_TTL`], keyed
+/// by a hash of the `Authorization` header, instead of hashing the password
+/// every time; a changed password takes as long to apply. Addresses that
+/// keep failing back off through [`Backoff`], and are answered `429`
+/// meanwhile.
+#[derive(Debug, Default)]
+pub struct DavAuth {
+    signed_in: Mutex<HashMap<String, (User, Instant)>>,
+    /// Failures per address
+    failures: Backoff<IpAddr>,
+}
+
+impl DavAuth {
+    /// The user `credentials` signed in as recently
+    pub fn cached(&self, credentials: &str, now: Instant) -> Option<User> {
+        let signed_in = self.signed_in.lock().unwrap_or_else(|e| e.into_inner());
+        signed_in
+            .get(credentials)
+            .filter(|(_, expires)| *expires > now)
+            .map(|(user, _)| user.clone())
+    }
+
+    /// Remember that `credentials` signed in as `user`
+    pub fn remember(&self, credentials: String, user: User, now: Instant) {
+        let mut signed_in = self.signed_in.lock().unwrap_or_else(|e| e.into_inner());
+        if signed_in.len() >= MAX_TRACKED {
+            signed_in.retain(|_, (_, expires)| *expires > now);
+        }
+        signed_in.insert(credentials, (user, now + CREDENTIALS_TTL));
+    }
+
+    /// Seconds `client` has to wait before its next try, if any
+    pub fn blocked(&self, client: IpAddr, now: Instant) -> Option<u64> {
+        self.failures.blocked(&client, now)
+    }
+
+    /// Count a failed sign-in from `client`
+    pub fn failed(&self, client: IpAddr, now: Instant) {
+        self.failures.failed(client, now);
+    }
+
+    /// Forget the failures of `client` once it signs in
+    pub fn succeeded(&self, client: IpAddr) {
+        self.failures.succeeded(&client);
+    }
+}
+
+/// A user signed in with HTTP Basic auth
+///
+/// Rejects with a `Basic` challenge, which DAV clients answer by asking for
+/// the password. Goes through [`DavAuth`] when it is registered.
+pub struct DavUser(pub User);
+
+#[async_trait]
+impl FromRequestParts<AppState> for DavUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        let auth = state.extension::<DavAuth>();
+        let client = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let now = Instant::now();
+        if let Some(wait) = auth.as_ref().and_then(|auth| auth.blocked(client, now)) {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, HeaderValue::from(wait))],
+                axum::Json(serde_json::json!({ "error": t!("dav-too-many-failures") })),
+            )
+                .into_response());
+        }
+        let header = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "));
+        let key = header.map(crate::crypto::sha256_hex);
+        if let Some(user) = key
+            .as_deref()
+            .zip(auth.as_ref())
+            .and_then(|(key, auth)| auth.cached(key, now))
+        {
+            return Ok(DavUser(user));
+        }
+        let credentials = header
+            .and_then(|v| STANDARD.decode(v.trim()).ok())
+            .and_then(|v| String::from_utf8(v).ok());
+        if let Some((username, password)) = credentials.as_deref().and_then(|v| v.split_once(':')) {
+            match users::authenticate(state.db.pool(), username, password).await {
+                Ok(Some(user)) => {
+                    if let (Some(auth), Some(key)) = (&auth, key) {
+                        auth.succeeded(client);
+                        auth.remember(key, user.clone(), now);
+                    }
+                    return Ok(DavUser(user));
+                }
+                Ok(None) => {
+                    if let Some(auth) = &auth {
+                        auth.failed(client, Instant::now());
+                    }
+                }
+                Err(e) => return Err(ApiError::Internal(e).into_response()),
+            }
+        }
+        let mut response = ApiError::Unauthorized(t!("dav-unauthorized")).into_response();
+        response.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"dav\", charset=\"UTF-8\""),
+        );
+        Err(response)
+    }
+}
+
+/// Whether a `PROPFIND` reaches the members of a collection
+///
+/// `Depth: infinity`, the default, is answered like `1`; collections here
+/// hold no collections of their own.
+pub fn members_requested(headers: &HeaderMap) -> bool {
+    headers
+        .get("depth")
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|depth| depth.trim() != "0")
+}
+
+/// The ETag of a stored resource
+pub fn etag(revision: i32, updated_at: DateTime<Utc>) -> String {
+    format!("\"{}-{}\"", revision, updated_at.timestamp_micros())
+}
+
+/// Tag of a collection from the ETags of its members, changing whenever
+/// one is added, changed or removed
+pub fn collection_tag<'a>(members: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let mut members: Vec<_> = members.into_iter().collect();
+    members.sort_unstable();
+    let listing: String = members
+        .iter()
+        .map(|(href, etag)| format!("{} {}\n", href, etag))
+        .collect();
+    format!("data:,{}", &crate::crypto::sha256_hex(&listing)[..32])
+}
+
+/// Whether `If-Match` and `If-None-Match` allow a write to a resource
+/// currently tagged `current`
+pub fn preconditions_met(headers: &HeaderMap, current: Option<&str>) -> bool {
+    let matches = |header: &str| {
+        headers.get(header).and_then(|v| v.to_str().ok()).map(|v| {
+            v.split(',').map(str::trim).any(|tag| {
+                tag == "*" && current.is_some() || Some(tag.trim_start_matches("W/")) == current
+            })
+        })
+    };
+    matches("if-match") != Some(false) && matches("if-none-match") != Some(true)
+}
+
+/// The request body as XML, `None` if empty
+pub fn body(bytes: &Bytes) -> ApiResult<Option<Element>> {
+    xml::parse(bytes)
+        .map_err(|reason| ApiError::BadRequest(t!("dav-invalid-body", reason = reason)))
+}
+
+/// Answer `OPTIONS`, announcing DAV support and the methods `allow`ed
+pub fn options(allow: &'static str) -> Response {
+    (
+        StatusCode::OK,
+        [("dav", COMPLIANCE), (ALLOW.as_str(), allow)],
+    )
+        .into_response()
+}
+
+/// Refuse a method a resource does not support
+pub fn not_allowed(allow: &'static str) -> Response {
+    (StatusCode::METHOD_NOT_ALLOWED, [(ALLOW, allow)]).into_response()
+}
+
+/// Answer a `sync-collection` report
+///
+/// Without a token every member is listed. A token from before the latest
+/// change cannot be answered without knowing what was deleted, so it is
+/// refused and the client starts over.
+pub fn sync_collection(report: &Element, token: &str, members: &[Resource]) -> Response {
+    let request = PropFind::from_element(Some(report));
+    let given = report
+        .child(DAV, "sync-token")
+        .map(|element| element.text.trim())
+        .unwrap_or_default();
+    let mut multistatus = Multistatus::new();
+    if given.is_empty() {
+        for member in members {
+            multistatus.resource(member, &request);
+        }
+    } else if given != token {
+        return xml::precondition(StatusCode::FORBIDDEN, DAV, "valid-sync-token");
+    }
+    multistatus.sync_token(token);
+    multistatus.into_response()
+}
+
+/// Member names of `hrefs` under `collection`, e.g. from a multiget
+pub fn member_names<'a>(
+    collection: &'a str,
+    hrefs: impl Iterator<Item = &'a Element> + 'a,
+) -> impl Iterator<Item = (&'a str, Option<String>)> + 'a {
+    hrefs.map(move |href| {
+        let text = href.text.trim();
+        let name = href_path(text).and_then(|path| {
+            path.strip_prefix(collection)
+                .filter(|name| !name.is_empty() && !name.contains('/'))
+                .map(String::from)
+        });
+        (text, name)
+    })
+}
+
+/// The decoded path of an `href` or `Destination` header
+pub fn href_path(text: &str) -> Option<String> {
+    // Clients may send full URLs
+    let path = text
+        .find("://")
+        .and_then(|scheme| {
+            text[scheme + 3..]
+                .find('/')
+                .map(|i| &text[scheme + 3 + i..])
+        })
+        .unwrap_or(text);
+    String::from_utf8(crate::mail::mime::percent_decode(path)).ok()
+}
+
+/// Properties every resource has
+fn common(resource: Resource) -> Resource {
+    resource
+        .href(DAV, "current-user-principal", ROOT)
+        .href(DAV, "owner", ROOT)
+}
+
+/// The principal and home of the collections
+fn principal() -> Resource {
+    common(Resource::new(ROOT))
+        .xml(DAV, "resourcetype", "<d:collection/><d:principal/>")
+        .text(DAV, "displayname", "DAV")
+        .href(DAV, "principal-URL", ROOT)
+        .href(CALDAV, "calendar-home-set", ROOT)
+        .href(CARDDAV, "addressbook-home-set", ROOT)
+        .xml(
+            DAV,
+            "current-user-privilege-set",
+            "<d:privilege><d:read/></d:privilege>",
+        )
+}
+
+/// `/.well-known/caldav` and `/.well-known/carddav` - point at [`ROOT`]
+pub async fn well_known() -> Response {
+    (StatusCode::MOVED_PERMANENTLY, [(LOCATION, ROOT)]).into_response()
+}
+
+/// `/dav/` - the principal, listing the calendar and the address book
+pub async fn root(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    _user: DavUser,
+    method: Method,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> ApiResult<Response> {
+    const ALLOW_ROOT: &str = "OPTIONS, PROPFIND";
+    match method.as_str() {
+        "OPTIONS" => Ok(options(ALLOW_ROOT)),
+        "PROPFIND" => {
+            let request = PropFind::from_element(body(&bytes)?.as_ref());
+            let mut multistatus = Multistatus::new();
+            multistatus.resource(&principal(), &request);
+            if members_requested(&headers) {
+                let pool = state.db.pool();
+                multistatus.resource(&caldav::calendar(pool).await?, &request);
+                multistatus.resource(&carddav::address_book(pool).await?, &request);
+                if let (Some(storage), Some(locks)) = (
+                    state.extension::<crate::storage::Storage>(),
+                    state.extension::<files::Locks>(),
+                ) {
+                    multistatus.resource(&files::home(&storage, &locks).await?, &request);
+                }
+            }
+            Ok(multistatus.into_response())
+        }
+        _ => Ok(not_allowed(ALLOW_ROOT)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backoff::FREE_FAILURES;
+
+    #[test]
+    fn test_preconditions() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+        assert!(preconditions_met(&headers(&[]), None));
+        assert!(preconditions_met(&headers(&[]), Some("\"1-2\"")));
+        assert!(preconditions_met(&headers(&[("if-none-match", "*")]), None));
+        assert!(!preconditions_met(
+            &headers(&[("if-none-match", "*")]),
+            Some("\"1-2\"")
+        ));
+        assert!(preconditions_met(
+            &headers(&[("if-match", "\"0-1\", \"1-2\"")]),
+            Some("\"1-2\"")
+        ));
+        assert!(!preconditions_met(
+            &headers(&[("if-match", "\"0-1\"")]),
+            Some("\"1-2\"")
+        ));
+        assert!(!preconditions_met(&headers(&[("if-match", "*")]), None));
+    }
+
+    #[test]
+    fn test_auth_cache_and_backoff() {
+        let auth = DavAuth::default();
+        let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let start = Instant::now();
+        let user = User {
+            id: 1,
+            username: "alice".into(),
+            is_admin: false,
+            timezone: None,
+            created_at: Utc::now(),
+        };
+        auth.remember("key".into(), user, start);
+        assert!(auth.cached("key", start).is_some());
+        assert!(auth.cached("other", start).is_none());
+        assert!(auth.cached("key", start + CREDENTIALS_TTL).is_none());
+
+        for _ in 0..FREE_FAILURES {
+            assert_eq!(auth.blocked(client, start), None);
+            auth.failed(client, start);
+        }
+        assert_eq!(auth.blocked(client, start), Some(1));
+        assert_eq!(auth.blocked(client, start + Duration::from_secs(1)), None);
+        auth.failed(client, start);
+        auth.failed(client, start);
+        assert_eq!(auth.blocked(client, start), Some(4));
+        auth.succeeded(client);
+        assert_eq!(auth.blocked(client, start), None);
+    }
+
+    #[test]
+    fn test_collection_tags_and_hrefs() {
+        let tag = collection_tag([("/a.ics", "\"1\""), ("/b.ics", "\"1\"")]);
+        assert_eq!(
+            tag,
+            collection_tag([("/b.ics", "\"1\""), ("/a.ics", "\"1\"")])
+        );
+        assert_ne!(tag, collection_tag([("/a.ics", "\"1\"")]));
+        assert_ne!(
+            tag,
+            collection_tag([("/a.ics", "\"2\""), ("/b.ics", "\"1\"")])
+        );
+        assert!(tag.starts_with("data:,"));
+
+        let report = xml::parse(
+            b"<multiget xmlns=\"DAV:\">\
+              <href>/dav/contacts/a%20b.vcf</href>\
+              <href>https://example.com/dav/contacts/c.vcf</href>\
+              <href>/dav/calendar/d.ics</href>\
+              <href>/dav/contacts/</href>\
+              </multiget>",
+        )
+        .unwrap()
+        .unwrap();
+        let names: Vec<_> = member_names("/dav/contacts/", report.children_named(DAV, "href"))
+            .map(|(_, name)| name)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                Some("a b.vcf".to_string()),
+                Some("c.vcf".to_string()),
+                None,
+                None
+            ]
+        );
+    }
+}
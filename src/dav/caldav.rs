@@ -0,0 +1,349 @@
+//! CalDAV (RFC 4791) view of events.
+//!
+//! A single read-only calendar at `/dav/calendar/` holds every event as
+//! `<id>.ics`. Writes are refused with `need-privileges`, which clients
+//! show as a read-only calendar.
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{
+        header::{CONTENT_TYPE, ETAG},
+        HeaderMap, Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use quick_xml::escape::escape;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::xml::{self, Element, Multistatus, PropFind, Resource};
+use super::{DavUser, CALDAV, CALENDARSERVER, DAV};
+use crate::error::{ApiError, ApiResult};
+use crate::events::{self, ics, Event};
+use crate::{t, AppState};
+
+/// Path of the calendar
+pub const HREF: &str = "/dav/calendar/";
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PROPFIND, REPORT";
+
+const ICS: &str = "text/calendar; charset=utf-8";
+
+/// How far an open-ended `time-range` reaches into the future
+const OPEN_RANGE_DAYS: i64 = 100 * 366;
+
+fn href(event: &Event) -> String {
+    format!("{}{}.ics", HREF, event.id)
+}
+
+fn etag(event: &Event) -> String {
+    super::etag(event.sequence, event.updated_at)
+}
+
+fn tag(events: &[Event]) -> String {
+    let members: Vec<(String, String)> = events
+        .iter()
+        .map(|event| (href(event), etag(event)))
+        .collect();
+    super::collection_tag(
+        members
+            .iter()
+            .map(|(href, etag)| (href.as_str(), etag.as_str())),
+    )
+}
+
+/// The calendar collection itself
+fn collection_resource(events: &[Event]) -> Resource {
+    let tag = tag(events);
+    super::common(Resource::new(HREF))
+        .xml(DAV, "resourcetype", "<d:collection/><c:calendar/>")
+        .text(DAV, "displayname", "Calendar")
+        .xml(
+            CALDAV,
+            "supported-calendar-component-set",
+            "<c:comp name=\"VEVENT\"/>",
+        )
+        .xml(
+            DAV,
+            "supported-report-set",
+            "<d:supported-report><d:report><c:calendar-multiget/></d:report></d:supported-report>\
+             <d:supported-report><d:report><c:calendar-query/></d:report></d:supported-report>\
+             <d:supported-report><d:report><d:sync-collection/></d:report></d:supported-report>",
+        )
+        .xml(
+            DAV,
+            "current-user-privilege-set",
+            "<d:privilege><d:read/></d:privilege>",
+        )
+        .text(CALENDARSERVER, "getctag", &tag)
+        .text(DAV, "sync-token", &tag)
+}
+
+/// The calendar as listed in its home
+pub async fn calendar(pool: &PgPool) -> ApiResult<Resource> {
+    Ok(collection_resource(&events::all(pool).await?))
+}
+
+/// An event as a member of the calendar
+fn member(event: &Event, request: &PropFind) -> Resource {
+    let mut resource = super::common(Resource::new(href(event)))
+        .xml(DAV, "resourcetype", "")
+        .text(DAV, "getetag", &etag(event))
+        .text(
+            DAV,
+            "getcontenttype",
+            "text/calendar; charset=utf-8; component=vevent",
+        )
+        .xml(
+            DAV,
+            "current-user-privilege-set",
+            "<d:privilege><d:read/></d:privilege>",
+        );
+    // Only sent when asked for, as with `allprop` in RFC 4791
+    if request.wants(CALDAV, "calendar-data") {
+        resource = resource.xml(
+            CALDAV,
+            "calendar-data",
+            escape(ics::calendar(None, std::slice::from_ref(event))),
+        );
+    }
+    resource
+}
+
+/// `/dav/calendar/` - the calendar of every event
+pub async fn collection(
+    State(state): State<AppState>,
+    _user: DavUser,
+    method: Method,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> ApiResult<Response> {
+    let pool = state.db.pool();
+    match method.as_str() {
+        "OPTIONS" => Ok(super::options(ALLOW)),
+        "GET" | "HEAD" => {
+            let events = events::all(pool).await?;
+            Ok((
+                [(CONTENT_TYPE, ICS.to_string()), (ETAG, tag(&events))],
+                ics::calendar(Some("Calendar"), &events),
+            )
+                .into_response())
+        }
+        "PROPFIND" => {
+            let request = PropFind::from_element(super::body(&bytes)?.as_ref());
+            let events = events::all(pool).await?;
+            let mut multistatus = Multistatus::new();
+            multistatus.resource(&collection_resource(&events), &request);
+            if super::members_requested(&headers) {
+                for event in &events {
+                    multistatus.resource(&member(event, &request), &request);
+                }
+            }
+            Ok(multistatus.into_response())
+        }
+        "REPORT" => {
+            let report = super::body(&bytes)?.ok_or_else(|| {
+                ApiError::BadRequest(t!("dav-invalid-body", reason = "missing report"))
+            })?;
+            report_response(&report, events::all(pool).await?)
+        }
+        "PUT" | "DELETE" | "MKCOL" | "PROPPATCH" => Ok(read_only()),
+        _ => Ok(super::not_allowed(ALLOW)),
+    }
+}
+
+/// `/dav/calendar/:id.ics` - one event
+pub async fn resource(
+    State(state): State<AppState>,
+    _user: DavUser,
+    Path(name): Path<String>,
+    method: Method,
+    bytes: Bytes,
+) -> ApiResult<Response> {
+    match method.as_str() {
+        "OPTIONS" => return Ok(super::options(ALLOW)),
+        "PUT" | "DELETE" | "PROPPATCH" => return Ok(read_only()),
+        "GET" | "HEAD" | "PROPFIND" => {}
+        _ => return Ok(super::not_allowed(ALLOW)),
+    }
+    let id = name
+        .strip_suffix(".ics")
+        .and_then(|id| id.parse::<Uuid>().ok())
+        .ok_or_else(|| ApiError::NotFound(t!("event-not-found")))?;
+    let event = events::find(state.db.pool(), id).await?;
+    if method.as_str() == "PROPFIND" {
+        let request = PropFind::from_element(super::body(&bytes)?.as_ref());
+        let mut multistatus = Multistatus::new();
+        multistatus.resource(&member(&event, &request), &request);
+        return Ok(multistatus.into_response());
+    }
+    Ok((
+        [(CONTENT_TYPE, ICS.to_string()), (ETAG, etag(&event))],
+        ics::calendar(None, std::slice::from_ref(&event)),
+    )
+        .into_response())
+}
+
+fn read_only() -> Response {
+    xml::precondition(StatusCode::FORBIDDEN, DAV, "need-privileges")
+}
+
+/// Answer a `REPORT` on the calendar
+fn report_response(report: &Element, events: Vec<Event>) -> ApiResult<Response> {
+    let request = PropFind::from_element(Some(report));
+    let mut multistatus = Multistatus::new();
+    if report.is(CALDAV, "calendar-multiget") {
+        for (href, name) in super::member_names(HREF, report.children_named(DAV, "href")) {
+            let id = name
+                .as_deref()
+                .and_then(|name| name.strip_suffix(".ics"))
+                .and_then(|id| id.parse::<Uuid>().ok());
+            match events.iter().find(|event| Some(event.id) == id) {
+                Some(event) => multistatus.resource(&member(event, &request), &request),
+                None => multistatus.status(href, StatusCode::NOT_FOUND),
+            }
+        }
+    } else if report.is(CALDAV, "calendar-query") {
+        let range = report
+            .child(CALDAV, "filter")
+            .and_then(|filter| filter.find(CALDAV, "time-range"))
+            .map(time_range)
+            .transpose()?;
+        for event in &events {
+            let in_range =
+                range.is_none_or(|(from, to)| !event.occurrences(from, to, 1).is_empty());
+            if in_range {
+                multistatus.resource(&member(event, &request), &request);
+            }
+        }
+    } else if report.is(DAV, "sync-collection") {
+        let members: Vec<Resource> = events.iter().map(|event| member(event, &request)).collect();
+        return Ok(super::sync_collection(report, &tag(&events), &members));
+    } else {
+        return Ok(xml::precondition(
+            StatusCode::FORBIDDEN,
+            DAV,
+            "supported-report",
+        ));
+    }
+    Ok(multistatus.into_response())
+}
+
+/// The bounds of a `time-range` filter, open ends made finite
+fn time_range(element: &Element) -> ApiResult<(DateTime<Utc>, DateTime<Utc>)> {
+    let parse = |name: &str| {
+        element
+            .attribute(name)
+            .map(|value| {
+                NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ")
+                    .map(|time| time.and_utc())
+                    .map_err(|e| {
+                        ApiError::BadRequest(t!(
+                            "dav-invalid-body",
+                            reason = format!("invalid time-range {}: {}", name, e)
+                        ))
+                    })
+            })
+            .transpose()
+    };
+    let from = parse("start")?.unwrap_or(DateTime::<Utc>::MIN_UTC);
+    let to = match parse("end")? {
+        Some(to) => to,
+        None => from.max(Utc::now()) + Duration::days(OPEN_RANGE_DAYS),
+    };
+    Ok((from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+
+    fn event(id: u128, starts_at: &str, ends_at: &str) -> Event {
+        let at = |input| crate::time::parse(input).unwrap();
+        Event {
+            id: Uuid::from_u128(id),
+            title: format!("Event {}", id),
+            description: String::new(),
+            location: String::new(),
+            starts_at: at(starts_at),
+            ends_at: at(ends_at),
+            all_day: false,
+            timezone: "UTC".into(),
+            rrule: None,
+            sequence: 0,
+            created_at: at("2026-10-01T00:00:00Z"),
+            updated_at: at("2026-10-01T00:00:00Z"),
+        }
+    }
+
+    async fn report(body: &str, events: Vec<Event>) -> (StatusCode, String) {
+        let element = xml::parse(body.as_bytes()).unwrap().unwrap();
+        let response = report_response(&element, events).unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_reports() {
+        let events = vec![
+            event(1, "2026-10-19T07:00:00Z", "2026-10-19T08:00:00Z"),
+            event(2, "2026-11-19T07:00:00Z", "2026-11-19T08:00:00Z"),
+        ];
+        let (status, body) = report(
+            r#"<c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+                 <d:prop><d:getetag/><c:calendar-data/></d:prop>
+                 <c:filter><c:comp-filter name="VCALENDAR"><c:comp-filter name="VEVENT">
+                   <c:time-range start="20261101T000000Z" end="20261201T000000Z"/>
+                 </c:comp-filter></c:comp-filter></c:filter>
+               </c:calendar-query>"#,
+            events.clone(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(!body.contains("00000000-0000-0000-0000-000000000001.ics"));
+        assert!(body.contains("<d:href>/dav/calendar/00000000-0000-0000-0000-000000000002.ics"));
+        assert!(body.contains("<c:calendar-data>BEGIN:VCALENDAR"));
+        assert!(body.contains("SUMMARY:Event 2"));
+
+        let (_, body) = report(
+            r#"<c:calendar-multiget xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+                 <d:prop><d:getetag/></d:prop>
+                 <d:href>/dav/calendar/00000000-0000-0000-0000-000000000001.ics</d:href>
+                 <d:href>/dav/calendar/missing.ics</d:href>
+               </c:calendar-multiget>"#,
+            events.clone(),
+        )
+        .await;
+        assert!(body.contains("<d:getetag>&quot;0-"));
+        assert!(body.contains(
+            "<d:href>/dav/calendar/missing.ics</d:href><d:status>HTTP/1.1 404 Not Found"
+        ));
+        assert!(!body.contains("calendar-data"));
+
+        let sync = |token: &str| {
+            format!(
+                r#"<d:sync-collection xmlns:d="DAV:"><d:sync-token>{}</d:sync-token>
+                   <d:sync-level>1</d:sync-level><d:prop><d:getetag/></d:prop>
+                   </d:sync-collection>"#,
+                token
+            )
+        };
+        let (_, body) = report(&sync(""), events.clone()).await;
+        assert_eq!(body.matches("<d:response>").count(), 2);
+        let token = tag(&events);
+        assert!(body.contains(&format!("<d:sync-token>{}</d:sync-token>", token)));
+        let (status, body) = report(&sync(&token), events.clone()).await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert_eq!(body.matches("<d:response>").count(), 0);
+        let (status, body) = report(&sync(&token), events[..1].to_vec()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(body.contains("<d:valid-sync-token/>"));
+
+        let (status, _) = report(r#"<d:expand-property xmlns:d="DAV:"/>"#, events).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,460 @@
+//! CardDAV (RFC 6352) address book.
+//!
+//! Contacts exist only as the vCards clients store at
+//! `/dav/contacts/<name>.vcf`; the server keeps each one as sent and reads
+//! just its `UID` and `FN` for listing. Names are what the client chose,
+//! usually a UUID, limited to URL-safe characters.
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{
+        header::{CONTENT_TYPE, ETAG},
+        HeaderMap, Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use quick_xml::escape::escape;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::xml::{self, Element, Multistatus, PropFind, Resource};
+use super::{DavUser, CALENDARSERVER, CARDDAV, DAV};
+use crate::error::{ApiError, ApiResult};
+use crate::{t, AppState};
+
+/// Path of the address book
+pub const HREF: &str = "/dav/contacts/";
+
+const ALLOW_COLLECTION: &str = "OPTIONS, GET, HEAD, PROPFIND, REPORT";
+
+const ALLOW_RESOURCE: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND";
+
+const VCARD: &str = "text/vcard; charset=utf-8";
+
+/// Largest vCard accepted, photos included
+pub const MAX_VCARD_BYTES: usize = 1024 * 1024;
+
+/// Longest resource name
+const MAX_NAME_LEN: usize = 255;
+
+/// What any signed-in user may do with contacts
+const PRIVILEGES: &str = "<d:privilege><d:read/></d:privilege>\
+     <d:privilege><d:write/></d:privilege>\
+     <d:privilege><d:write-content/></d:privilege>\
+     <d:privilege><d:bind/></d:privilege>\
+     <d:privilege><d:unbind/></d:privilege>";
+
+const COLUMNS: &str = "id, resource, uid, full_name, vcard, revision, created_at, updated_at";
+
+/// A stored vCard
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Contact {
+    pub id: Uuid,
+    /// Name under [`HREF`]
+    pub resource: String,
+    pub uid: String,
+    pub full_name: String,
+    pub vcard: String,
+    /// Bumped on every change
+    pub revision: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Contact {
+    fn href(&self) -> String {
+        format!("{}{}", HREF, self.resource)
+    }
+
+    fn etag(&self) -> String {
+        super::etag(self.revision, self.updated_at)
+    }
+}
+
+/// The `UID` and `FN` of a vCard
+#[derive(Debug, PartialEq, Eq)]
+pub struct Card {
+    pub uid: Option<String>,
+    pub full_name: String,
+}
+
+/// Check that `text` holds a single vCard and read its `UID` and `FN`
+pub fn parse_vcard(text: &str) -> Option<Card> {
+    let unfolded = text
+        .replace("\r\n", "\n")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+    let lines: Vec<&str> = unfolded.lines().filter(|line| !line.is_empty()).collect();
+    let is = |line: &&str, value: &str| line.trim_end().eq_ignore_ascii_case(value);
+    if !lines.first().is_some_and(|line| is(line, "BEGIN:VCARD"))
+        || !lines.last().is_some_and(|line| is(line, "END:VCARD"))
+        || lines.iter().filter(|line| is(line, "BEGIN:VCARD")).count() != 1
+    {
+        return None;
+    }
+    let mut card = Card {
+        uid: None,
+        full_name: String::new(),
+    };
+    for line in &lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        // Drop parameters and any group, as in `item1.TEL;TYPE=cell`
+        let name = name.split(';').next().unwrap_or_default();
+        let name = name.rsplit('.').next().unwrap_or_default();
+        if name.eq_ignore_ascii_case("UID") {
+            card.uid = Some(value.trim().to_string());
+        } else if name.eq_ignore_ascii_case("FN") {
+            card.full_name = unescape(value.trim());
+        }
+    }
+    Some(card)
+}
+
+/// Unescape a vCard text value
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Whether `name` may name a contact
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@' | '+' | '~'))
+}
+
+/// Every contact, by name
+pub async fn all(pool: &PgPool) -> ApiResult<Vec<Contact>> {
+    Ok(
+        sqlx::query_as::<_, Contact>(&format!("SELECT {COLUMNS} FROM contacts ORDER BY resource"))
+            .fetch_all(pool)
+            .await?,
+    )
+}
+
+/// Look up a contact by name
+pub async fn find(pool: &PgPool, resource: &str) -> ApiResult<Option<Contact>> {
+    Ok(sqlx::query_as::<_, Contact>(&format!(
+        "SELECT {COLUMNS} FROM contacts WHERE resource = $1"
+    ))
+    .bind(resource)
+    .fetch_optional(pool)
+    .await?)
+}
+
+/// Store a vCard under `resource`, replacing any there
+pub async fn store(pool: &PgPool, resource: &str, card: &Card, vcard: &str) -> ApiResult<Contact> {
+    Ok(sqlx::query_as::<_, Contact>(&format!(
+        "INSERT INTO contacts (resource, uid, full_name, vcard)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (resource) DO UPDATE SET uid = EXCLUDED.uid,
+            full_name = EXCLUDED.full_name, vcard = EXCLUDED.vcard,
+            revision = contacts.revision + 1, updated_at = now()
+         RETURNING {COLUMNS}"
+    ))
+    .bind(resource)
+    .bind(card.uid.as_deref().unwrap_or(resource))
+    .bind(&card.full_name)
+    .bind(vcard)
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Remove a contact, returning whether it existed
+pub async fn delete(pool: &PgPool, resource: &str) -> ApiResult<bool> {
+    let result = sqlx::query("DELETE FROM contacts WHERE resource = $1")
+        .bind(resource)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+fn tag(contacts: &[Contact]) -> String {
+    let members: Vec<(String, String)> = contacts
+        .iter()
+        .map(|contact| (contact.href(), contact.etag()))
+        .collect();
+    super::collection_tag(
+        members
+            .iter()
+            .map(|(href, etag)| (href.as_str(), etag.as_str())),
+    )
+}
+
+/// The address book collection itself
+fn collection_resource(contacts: &[Contact]) -> Resource {
+    let tag = tag(contacts);
+    super::common(Resource::new(HREF))
+        .xml(DAV, "resourcetype", "<d:collection/><card:addressbook/>")
+        .text(DAV, "displayname", "Contacts")
+        .xml(
+            CARDDAV,
+            "supported-address-data",
+            "<card:address-data-type content-type=\"text/vcard\" version=\"3.0\"/>\
+             <card:address-data-type content-type=\"text/vcard\" version=\"4.0\"/>",
+        )
+        .text(CARDDAV, "max-resource-size", &MAX_VCARD_BYTES.to_string())
+        .xml(
+            DAV,
+            "supported-report-set",
+            "<d:supported-report><d:report><card:addressbook-multiget/></d:report></d:supported-report>\
+             <d:supported-report><d:report><card:addressbook-query/></d:report></d:supported-report>\
+             <d:supported-report><d:report><d:sync-collection/></d:report></d:supported-report>",
+        )
+        .xml(DAV, "current-user-privilege-set", PRIVILEGES)
+        .text(CALENDARSERVER, "getctag", &tag)
+        .text(DAV, "sync-token", &tag)
+}
+
+/// The address book as listed in its home
+pub async fn address_book(pool: &PgPool) -> ApiResult<Resource> {
+    Ok(collection_resource(&all(pool).await?))
+}
+
+/// A contact as a member of the address book
+fn member(contact: &Contact, request: &PropFind) -> Resource {
+    let mut resource = super::common(Resource::new(contact.href()))
+        .xml(DAV, "resourcetype", "")
+        .text(DAV, "getetag", &contact.etag())
+        .text(DAV, "getcontenttype", VCARD)
+        .text(DAV, "displayname", &contact.full_name)
+        .xml(DAV, "current-user-privilege-set", PRIVILEGES);
+    if request.wants(CARDDAV, "address-data") {
+        resource = resource.xml(CARDDAV, "address-data", escape(&contact.vcard));
+    }
+    resource
+}
+
+/// `/dav/contacts/` - the address book
+pub async fn collection(
+    State(state): State<AppState>,
+    _user: DavUser,
+    method: Method,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> ApiResult<Response> {
+    let pool = state.db.pool();
+    match method.as_str() {
+        "OPTIONS" => Ok(super::options(ALLOW_COLLECTION)),
+        "GET" | "HEAD" => {
+            let contacts = all(pool).await?;
+            let body: String = contacts
+                .iter()
+                .map(|contact| contact.vcard.trim_end().to_string() + "\r\n")
+                .collect();
+            Ok((
+                [(CONTENT_TYPE, VCARD.to_string()), (ETAG, tag(&contacts))],
+                body,
+            )
+                .into_response())
+        }
+        "PROPFIND" => {
+            let request = PropFind::from_element(super::body(&bytes)?.as_ref());
+            let contacts = all(pool).await?;
+            let mut multistatus = Multistatus::new();
+            multistatus.resource(&collection_resource(&contacts), &request);
+            if super::members_requested(&headers) {
+                for contact in &contacts {
+                    multistatus.resource(&member(contact, &request), &request);
+                }
+            }
+            Ok(multistatus.into_response())
+        }
+        "REPORT" => {
+            let report = super::body(&bytes)?.ok_or_else(|| {
+                ApiError::BadRequest(t!("dav-invalid-body", reason = "missing report"))
+            })?;
+            Ok(report_response(&report, all(pool).await?))
+        }
+        _ => Ok(super::not_allowed(ALLOW_COLLECTION)),
+    }
+}
+
+/// `/dav/contacts/:name` - one vCard
+pub async fn resource(
+    State(state): State<AppState>,
+    _user: DavUser,
+    Path(name): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+    bytes: Bytes,
+) -> ApiResult<Response> {
+    if !valid_name(&name) {
+        return Err(ApiError::BadRequest(t!("contact-name")));
+    }
+    let pool = state.db.pool();
+    let not_found = || ApiError::NotFound(t!("contact-not-found"));
+    match method.as_str() {
+        "OPTIONS" => Ok(super::options(ALLOW_RESOURCE)),
+        "GET" | "HEAD" => {
+            let contact = find(pool, &name).await?.ok_or_else(not_found)?;
+            Ok((
+                [(CONTENT_TYPE, VCARD.to_string()), (ETAG, contact.etag())],
+                contact.vcard,
+            )
+                .into_response())
+        }
+        "PROPFIND" => {
+            let request = PropFind::from_element(super::body(&bytes)?.as_ref());
+            let contact = find(pool, &name).await?.ok_or_else(not_found)?;
+            let mut multistatus = Multistatus::new();
+            multistatus.resource(&member(&contact, &request), &request);
+            Ok(multistatus.into_response())
+        }
+        "PUT" => {
+            if bytes.len() > MAX_VCARD_BYTES {
+                return Ok(xml::precondition(
+                    StatusCode::FORBIDDEN,
+                    CARDDAV,
+                    "max-resource-size",
+                ));
+            }
+            let Some((vcard, card)) = std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|vcard| Some((vcard, parse_vcard(vcard)?)))
+            else {
+                return Ok(xml::precondition(
+                    StatusCode::FORBIDDEN,
+                    CARDDAV,
+                    "valid-address-data",
+                ));
+            };
+            let existing = find(pool, &name).await?;
+            if !super::preconditions_met(&headers, existing.as_ref().map(Contact::etag).as_deref())
+            {
+                return Ok(StatusCode::PRECONDITION_FAILED.into_response());
+            }
+            let contact = store(pool, &name, &card, vcard).await?;
+            let status = if existing.is_some() {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::CREATED
+            };
+            Ok((status, [(ETAG, contact.etag())]).into_response())
+        }
+        "DELETE" => {
+            let existing = find(pool, &name).await?.ok_or_else(not_found)?;
+            if !super::preconditions_met(&headers, Some(&existing.etag())) {
+                return Ok(StatusCode::PRECONDITION_FAILED.into_response());
+            }
+            delete(pool, &name).await?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        _ => Ok(super::not_allowed(ALLOW_RESOURCE)),
+    }
+}
+
+/// Answer a `REPORT` on the address book
+///
+/// `addressbook-query` filters are not applied; every contact matches.
+fn report_response(report: &Element, contacts: Vec<Contact>) -> Response {
+    let request = PropFind::from_element(Some(report));
+    let mut multistatus = Multistatus::new();
+    if report.is(CARDDAV, "addressbook-multiget") {
+        for (href, name) in super::member_names(HREF, report.children_named(DAV, "href")) {
+            match contacts
+                .iter()
+                .find(|contact| Some(&contact.resource) == name.as_ref())
+            {
+                Some(contact) => multistatus.resource(&member(contact, &request), &request),
+                None => multistatus.status(href, StatusCode::NOT_FOUND),
+            }
+        }
+    } else if report.is(CARDDAV, "addressbook-query") {
+        for contact in &contacts {
+            multistatus.resource(&member(contact, &request), &request);
+        }
+    } else if report.is(DAV, "sync-collection") {
+        let members: Vec<Resource> = contacts
+            .iter()
+            .map(|contact| member(contact, &request))
+            .collect();
+        return super::sync_collection(report, &tag(&contacts), &members);
+    } else {
+        return xml::precondition(StatusCode::FORBIDDEN, DAV, "supported-report");
+    }
+    multistatus.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dav::DavModule;
+    use crate::module::{run_migrations, RouteModule};
+    use std::sync::Arc;
+
+    const ALICE: &str = "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:4f1c\r\n\
+                         FN:Alice\\, \r\n Liddell\r\nitem1.TEL;TYPE=cell:+1 555\r\nEND:VCARD\r\n";
+
+    #[test]
+    fn test_parses_vcards() {
+        assert_eq!(
+            parse_vcard(ALICE),
+            Some(Card {
+                uid: Some("4f1c".into()),
+                full_name: "Alice, Liddell".into(),
+            })
+        );
+        assert_eq!(
+            parse_vcard("begin:vcard\nfn:Bob\nend:vcard"),
+            Some(Card {
+                uid: None,
+                full_name: "Bob".into(),
+            })
+        );
+        assert_eq!(parse_vcard("BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n"), None);
+        assert_eq!(parse_vcard(&ALICE.repeat(2)), None);
+        assert_eq!(parse_vcard(""), None);
+
+        assert!(valid_name("4F1C-99.vcf"));
+        assert!(!valid_name(".."));
+        assert!(!valid_name("a b.vcf"));
+        assert!(!valid_name(&"a".repeat(MAX_NAME_LEN + 1)));
+    }
+
+    #[tokio::test]
+    async fn test_stores_contacts() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        let module: Arc<dyn RouteModule> = Arc::new(DavModule);
+        run_migrations(pool, &[module]).await.unwrap();
+
+        let card = parse_vcard(ALICE).unwrap();
+        let created = store(pool, "alice.vcf", &card, ALICE).await.unwrap();
+        assert_eq!(created.uid, "4f1c");
+        assert_eq!(created.revision, 0);
+        let updated = store(pool, "alice.vcf", &card, ALICE).await.unwrap();
+        assert_eq!(updated.id, created.id);
+        assert_eq!(updated.revision, 1);
+        assert_ne!(updated.etag(), created.etag());
+
+        let contacts = all(pool).await.unwrap();
+        assert_eq!(contacts.len(), 1);
+        let before = tag(&contacts);
+        assert!(delete(pool, "alice.vcf").await.unwrap());
+        assert!(!delete(pool, "alice.vcf").await.unwrap());
+        assert!(find(pool, "alice.vcf").await.unwrap().is_none());
+        assert_ne!(tag(&all(pool).await.unwrap()), before);
+    }
+}
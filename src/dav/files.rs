@@ -0,0 +1,790 @@
+//! WebDAV (RFC 4918) share of the stored files.
+//!
+//! `/dav/files/` mirrors the storage directory: directories are
+//! collections and files are resources, at the same keys as under
+//! `/api/v1/files/`. Clients list them with `PROPFIND`, read with `GET`,
+//! write with `PUT`, and arrange them with `MKCOL`, `MOVE` and `DELETE`,
+//! which is what Finder, Windows Explorer and GNOME Files need to mount
+//! the share as a network drive. Bytes written and freed count towards
+//! storage usage as they do through the files API.
+//!
+//! Clients take write locks (class 2) before saving. Locks live in memory
+//! for at most an hour unless refreshed, so they are lost on restart and
+//! not shared between instances. A locked resource, or one in a locked
+//! collection, can only be changed by a request submitting the lock's token
+//! in its `If` header; others get `423 Locked`. Locking a name that does
+//! not exist yet creates an empty file, as clients expect.
+//!
+//! Names follow the storage key rules, so files some clients create on
+//! their own, like macOS `._` files, are refused.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Request},
+    http::{
+        header::{
+            ALLOW, CONTENT_LENGTH, CONTENT_TYPE, ETAG, LAST_MODIFIED, X_CONTENT_TYPE_OPTIONS,
+        },
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use quick_xml::escape::escape;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use super::xml::{self, Element, Multistatus, PropFind, Resource};
+use super::{DavUser, DAV};
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::module::{RouteGroup, RouteModule};
+use crate::storage::{valid_key, Entry, Storage};
+use crate::usage::Meter;
+use crate::{t, AppState};
+
+/// Path of the share
+pub const HREF: &str = "/dav/files/";
+
+const ALLOW_FILES: &str = "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, MOVE, PROPFIND, LOCK, UNLOCK";
+
+/// Compliance classes announced in the `DAV` header, with locking
+const COMPLIANCE: &str = "1, 2";
+
+/// Largest `PROPFIND` or `LOCK` body read
+const MAX_XML_BYTES: usize = 64 * 1024;
+
+/// Longest a lock is held without being refreshed
+const MAX_LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+
+const SUPPORTED_LOCK: &str = "<d:lockentry><d:lockscope><d:exclusive/></d:lockscope>\
+     <d:locktype><d:write/></d:locktype></d:lockentry>\
+     <d:lockentry><d:lockscope><d:shared/></d:lockscope>\
+     <d:locktype><d:write/></d:locktype></d:lockentry>";
+
+/// Route module for `/dav/files/`, mounted with `DAV=true` and `STORAGE_DIR`
+pub struct DavFilesModule;
+
+impl RouteModule for DavFilesModule {
+    fn name(&self) -> &'static str {
+        "dav_files"
+    }
+
+    fn group(&self) -> RouteGroup {
+        // Authenticated with user accounts by `DavUser`
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/dav/files", any(root))
+            .route("/dav/files/", any(root))
+            .route("/dav/files/*path", any(resource))
+    }
+}
+
+/// A write lock on a file or directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lock {
+    /// `opaquelocktoken:` URI submitted to use the lock
+    pub token: String,
+    /// Storage key, empty for the whole share
+    pub key: String,
+    /// Path the lock was taken on
+    pub root: String,
+    pub exclusive: bool,
+    /// Whether it covers everything in a directory (`Depth: infinity`)
+    pub deep: bool,
+    /// The `owner` the client gave, as XML
+    pub owner: String,
+    pub expires: Instant,
+}
+
+impl Lock {
+    /// Whether the lock applies to `key`
+    fn covers(&self, key: &str) -> bool {
+        self.key == key || self.deep && within(key, &self.key)
+    }
+}
+
+/// Whether `key` lies below the directory `ancestor`
+fn within(key: &str, ancestor: &str) -> bool {
+    match ancestor {
+        "" => !key.is_empty(),
+        _ => key
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.starts_with('/')),
+    }
+}
+
+/// The directory holding `key`
+fn parent(key: &str) -> &str {
+    key.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// Locks held on the share, in memory
+#[derive(Debug, Default)]
+pub struct Locks {
+    held: Mutex<Vec<Lock>>,
+}
+
+impl Locks {
+    /// The unexpired locks
+    fn held(&self) -> MutexGuard<'_, Vec<Lock>> {
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        held.retain(|lock| lock.expires > now);
+        held
+    }
+
+    /// Whether `tokens` hold every lock applying to `key`, and with
+    /// `subtree` every lock below it too
+    pub fn permits(&self, key: &str, tokens: &[String], subtree: bool) -> bool {
+        self.held()
+            .iter()
+            .filter(|lock| lock.covers(key) || subtree && within(&lock.key, key))
+            .all(|lock| tokens.contains(&lock.token))
+    }
+
+    /// Take a lock on `key`, `None` if it conflicts with one already held
+    ///
+    /// Shared locks only conflict with exclusive ones.
+    pub fn lock(
+        &self,
+        key: &str,
+        root: &str,
+        exclusive: bool,
+        deep: bool,
+        owner: String,
+        timeout: Duration,
+    ) -> Option<Lock> {
+        let mut held = self.held();
+        let conflict = held
+            .iter()
+            .filter(|lock| lock.covers(key) || deep && within(&lock.key, key))
+            .any(|lock| exclusive || lock.exclusive);
+        if conflict {
+            return None;
+        }
+        let lock = Lock {
+            token: format!("opaquelocktoken:{}", Uuid::new_v4()),
+            key: key.to_string(),
+            root: root.to_string(),
+            exclusive,
+            deep,
+            owner,
+            expires: Instant::now() + timeout,
+        };
+        held.push(lock.clone());
+        Some(lock)
+    }
+
+    /// Extend a lock applying to `key` whose token is among `tokens`
+    pub fn refresh(&self, key: &str, tokens: &[String], timeout: Duration) -> Option<Lock> {
+        let mut held = self.held();
+        let lock = held
+            .iter_mut()
+            .find(|lock| lock.covers(key) && tokens.contains(&lock.token))?;
+        lock.expires = Instant::now() + timeout;
+        Some(lock.clone())
+    }
+
+    /// Release the lock `token` if it applies to `key`
+    pub fn unlock(&self, key: &str, token: &str) -> bool {
+        let mut held = self.held();
+        let before = held.len();
+        held.retain(|lock| !(lock.token == token && lock.covers(key)));
+        held.len() < before
+    }
+
+    /// Drop the locks on `key` and below, once it is gone
+    pub fn release(&self, key: &str) {
+        self.held()
+            .retain(|lock| lock.key != key && !within(&lock.key, key));
+    }
+
+    /// The locks applying to `key`
+    pub fn discovery(&self, key: &str) -> Vec<Lock> {
+        self.held()
+            .iter()
+            .filter(|lock| lock.covers(key))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Lock tokens submitted in the `If` header
+///
+/// Conditions are not evaluated otherwise; holding the token is what
+/// counts.
+pub fn submitted_tokens(headers: &HeaderMap) -> Vec<String> {
+    let Some(header) = headers.get("if").and_then(|v| v.to_str().ok()) else {
+        return Vec::new();
+    };
+    header
+        .split('<')
+        .filter_map(|part| part.split_once('>'))
+        .map(|(token, _)| token.trim())
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .map(String::from)
+        .collect()
+}
+
+/// How long a lock is asked for in the `Timeout` header, at most an hour
+pub fn lock_timeout(headers: &HeaderMap) -> Duration {
+    headers
+        .get("timeout")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.split(',').map(str::trim).find_map(|timeout| {
+                if timeout.eq_ignore_ascii_case("infinite") {
+                    Some(MAX_LOCK_TIMEOUT)
+                } else {
+                    let seconds = timeout.strip_prefix("Second-")?.parse().ok()?;
+                    Some(Duration::from_secs(seconds))
+                }
+            })
+        })
+        .unwrap_or(MAX_LOCK_TIMEOUT)
+        .min(MAX_LOCK_TIMEOUT)
+}
+
+/// The storage key a `Destination` header points at
+fn destination(headers: &HeaderMap) -> Option<String> {
+    let path = super::href_path(headers.get("destination")?.to_str().ok()?.trim())?;
+    if path == HREF.trim_end_matches('/') {
+        return Some(String::new());
+    }
+    path.strip_prefix(HREF)
+        .map(|key| key.trim_end_matches('/').to_string())
+}
+
+fn href(entry: &Entry) -> String {
+    match (entry.dir, entry.key.as_str()) {
+        (_, "") => HREF.to_string(),
+        (true, key) => format!("{}{}/", HREF, key),
+        (false, key) => format!("{}{}", HREF, key),
+    }
+}
+
+fn etag(entry: &Entry) -> String {
+    format!("\"{}-{}\"", entry.size, entry.modified.timestamp_micros())
+}
+
+/// The `activelock` XML of a lock
+fn active_lock(lock: &Lock) -> String {
+    let remaining = lock.expires.saturating_duration_since(Instant::now());
+    format!(
+        "<d:activelock><d:locktype><d:write/></d:locktype>\
+         <d:lockscope><d:{}/></d:lockscope><d:depth>{}</d:depth>\
+         <d:owner>{}</d:owner><d:timeout>Second-{}</d:timeout>\
+         <d:locktoken><d:href>{}</d:href></d:locktoken>\
+         <d:lockroot><d:href>{}</d:href></d:lockroot></d:activelock>",
+        if lock.exclusive {
+            "exclusive"
+        } else {
+            "shared"
+        },
+        if lock.deep { "infinity" } else { "0" },
+        lock.owner,
+        remaining.as_secs(),
+        escape(&lock.token),
+        escape(&lock.root),
+    )
+}
+
+/// Properties of a file or directory
+fn properties(entry: &Entry, locks: &Locks) -> Resource {
+    let name = match entry.key.rsplit('/').next() {
+        Some("") | None => "Files",
+        Some(name) => name,
+    };
+    let discovery: String = locks
+        .discovery(&entry.key)
+        .iter()
+        .map(active_lock)
+        .collect();
+    let resource = super::common(Resource::new(href(entry)))
+        .text(DAV, "displayname", name)
+        .text(
+            DAV,
+            "getlastmodified",
+            &entry
+                .modified
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        )
+        .text(DAV, "getetag", &etag(entry))
+        .xml(DAV, "supportedlock", SUPPORTED_LOCK)
+        .xml(DAV, "lockdiscovery", discovery);
+    if entry.dir {
+        resource.xml(DAV, "resourcetype", "<d:collection/>")
+    } else {
+        resource
+            .xml(DAV, "resourcetype", "")
+            .text(DAV, "getcontentlength", &entry.size.to_string())
+            .text(DAV, "getcontenttype", "application/octet-stream")
+    }
+}
+
+/// The share as listed in the DAV root
+pub async fn home(storage: &Storage, locks: &Locks) -> ApiResult<Resource> {
+    let entry = storage
+        .entry("")
+        .await?
+        .ok_or_else(|| ApiError::NotFound(t!("file-not-found")))?;
+    Ok(properties(&entry, locks))
+}
+
+/// Answer `OPTIONS`, announcing locking
+fn options() -> Response {
+    (
+        StatusCode::OK,
+        [
+            ("dav", COMPLIANCE),
+            (ALLOW.as_str(), ALLOW_FILES),
+            // Lets Microsoft Office save back over WebDAV
+            ("ms-author-via", "DAV"),
+        ],
+    )
+        .into_response()
+}
+
+/// Refuse a change to a locked resource
+fn locked() -> Response {
+    xml::precondition(StatusCode::LOCKED, DAV, "lock-token-submitted")
+}
+
+/// A `PROPFIND` or `LOCK` body as XML, `None` if empty
+async fn read_body(body: Body) -> ApiResult<Option<Element>> {
+    let bytes: Bytes = axum::body::to_bytes(body, MAX_XML_BYTES)
+        .await
+        .map_err(|e| ApiError::BadRequest(t!("dav-invalid-body", reason = e.to_string())))?;
+    super::body(&bytes)
+}
+
+/// Whether the directory at `key` exists, to hold a new member
+async fn is_dir(storage: &Storage, key: &str) -> ApiResult<bool> {
+    Ok(storage.entry(key).await?.is_some_and(|entry| entry.dir))
+}
+
+/// `/dav/files/` - the storage directory
+pub async fn root(
+    Ext(storage): Ext<Storage>,
+    Ext(locks): Ext<Locks>,
+    _user: DavUser,
+    meter: Meter,
+    request: Request,
+) -> ApiResult<Response> {
+    handle(&storage, &locks, &meter, "", request).await
+}
+
+/// `/dav/files/*path` - a file or directory
+pub async fn resource(
+    Ext(storage): Ext<Storage>,
+    Ext(locks): Ext<Locks>,
+    _user: DavUser,
+    meter: Meter,
+    Path(path): Path<String>,
+    request: Request,
+) -> ApiResult<Response> {
+    let key = path.trim_end_matches('/');
+    if key.is_empty() {
+        return handle(&storage, &locks, &meter, "", request).await;
+    }
+    let probe = matches!(
+        request.method().as_str(),
+        "GET" | "HEAD" | "PROPFIND" | "DELETE"
+    );
+    if !valid_key(key) && probe {
+        // Clients probe for names they keep on their own, like `.DS_Store`
+        return Err(ApiError::NotFound(t!("file-not-found")));
+    }
+    handle(&storage, &locks, &meter, key, request).await
+}
+
+async fn handle(
+    storage: &Storage,
+    locks: &Locks,
+    meter: &Meter,
+    key: &str,
+    request: Request,
+) -> ApiResult<Response> {
+    let (parts, body) = request.into_parts();
+    let headers = &parts.headers;
+    let not_found = || ApiError::NotFound(t!("file-not-found"));
+    let tokens = submitted_tokens(headers);
+    match parts.method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "GET" | "HEAD" => {
+            let entry = storage.entry(key).await?.ok_or_else(not_found)?;
+            if entry.dir {
+                return Ok(super::not_allowed(ALLOW_FILES));
+            }
+            let (file, size) = storage.open(key).await?;
+            Ok((
+                [
+                    (CONTENT_TYPE, "application/octet-stream".to_string()),
+                    (CONTENT_LENGTH, size.to_string()),
+                    (ETAG, etag(&entry)),
+                    (
+                        LAST_MODIFIED,
+                        entry
+                            .modified
+                            .format("%a, %d %b %Y %H:%M:%S GMT")
+                            .to_string(),
+                    ),
+                    (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+                ],
+                storage.stream(file),
+            )
+                .into_response())
+        }
+        "PROPFIND" => {
+            let request = PropFind::from_element(read_body(body).await?.as_ref());
+            let entry = storage.entry(key).await?.ok_or_else(not_found)?;
+            let mut multistatus = Multistatus::new();
+            multistatus.resource(&properties(&entry, locks), &request);
+            if entry.dir && super::members_requested(headers) {
+                for member in storage.list(key).await? {
+                    multistatus.resource(&properties(&member, locks), &request);
+                }
+            }
+            Ok(multistatus.into_response())
+        }
+        "PUT" => {
+            let existing = storage.entry(key).await?;
+            if key.is_empty() || existing.as_ref().is_some_and(|entry| entry.dir) {
+                return Ok(super::not_allowed(ALLOW_FILES));
+            }
+            if !is_dir(storage, parent(key)).await? {
+                return Ok(StatusCode::CONFLICT.into_response());
+            }
+            if !locks.permits(key, &tokens, false)
+                || existing.is_none() && !locks.permits(parent(key), &tokens, false)
+            {
+                return Ok(locked());
+            }
+            if !super::preconditions_met(headers, existing.as_ref().map(etag).as_deref()) {
+                return Ok(StatusCode::PRECONDITION_FAILED.into_response());
+            }
+            let declared = headers
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            if declared.is_some_and(|len| len > storage.max_file_size()) {
+                return Err(ApiError::Validation(t!(
+                    "file-too-large",
+                    max = storage.max_file_size()
+                )));
+            }
+            let stored = storage.put(key, body.into_data_stream(), None).await?;
+            meter.storage(
+                stored.size as i64 - existing.as_ref().map_or(0, |entry| entry.size) as i64,
+            );
+            let entry = storage.entry(key).await?.ok_or_else(not_found)?;
+            let status = match existing {
+                Some(_) => StatusCode::NO_CONTENT,
+                None => StatusCode::CREATED,
+            };
+            Ok((status, [(ETAG, etag(&entry))]).into_response())
+        }
+        "DELETE" => {
+            let entry = storage.entry(key).await?.ok_or_else(not_found)?;
+            if key.is_empty() {
+                return Ok(super::not_allowed(ALLOW_FILES));
+            }
+            if !locks.permits(key, &tokens, true) || !locks.permits(parent(key), &tokens, false) {
+                return Ok(locked());
+            }
+            if !super::preconditions_met(headers, Some(&etag(&entry))) {
+                return Ok(StatusCode::PRECONDITION_FAILED.into_response());
+            }
+            let freed = storage.remove(key).await?;
+            meter.storage(-(freed as i64));
+            locks.release(key);
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        "MKCOL" => {
+            let bytes = axum::body::to_bytes(body, MAX_XML_BYTES).await;
+            if !bytes.is_ok_and(|bytes| bytes.is_empty()) {
+                // Bodies describing what to create are not supported
+                return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+            }
+            if key.is_empty() || storage.entry(key).await?.is_some() {
+                return Ok(super::not_allowed(ALLOW_FILES));
+            }
+            if !is_dir(storage, parent(key)).await? {
+                return Ok(StatusCode::CONFLICT.into_response());
+            }
+            if !locks.permits(key, &tokens, false) || !locks.permits(parent(key), &tokens, false) {
+                return Ok(locked());
+            }
+            storage.create_dir(key).await?;
+            Ok(StatusCode::CREATED.into_response())
+        }
+        "MOVE" => {
+            storage.entry(key).await?.ok_or_else(not_found)?;
+            let target =
+                destination(headers).ok_or_else(|| ApiError::BadRequest(t!("dav-destination")))?;
+            if key.is_empty() || target.is_empty() || target == key || within(&target, key) {
+                return Ok(StatusCode::FORBIDDEN.into_response());
+            }
+            let existing = storage.entry(&target).await?;
+            if !is_dir(storage, parent(&target)).await? {
+                return Ok(StatusCode::CONFLICT.into_response());
+            }
+            let overwrite = headers
+                .get("overwrite")
+                .and_then(|v| v.to_str().ok())
+                .is_none_or(|v| !v.trim().eq_ignore_ascii_case("F"));
+            if existing.is_some() && !overwrite {
+                return Ok(StatusCode::PRECONDITION_FAILED.into_response());
+            }
+            if !locks.permits(key, &tokens, true)
+                || !locks.permits(parent(key), &tokens, false)
+                || !locks.permits(&target, &tokens, true)
+                || !locks.permits(parent(&target), &tokens, false)
+            {
+                return Ok(locked());
+            }
+            if existing.is_some() {
+                let freed = storage.remove(&target).await?;
+                meter.storage(-(freed as i64));
+                locks.release(&target);
+            }
+            storage.rename(key, &target).await?;
+            locks.release(key);
+            let status = match existing {
+                Some(_) => StatusCode::NO_CONTENT,
+                None => StatusCode::CREATED,
+            };
+            Ok(status.into_response())
+        }
+        "LOCK" => {
+            let timeout = lock_timeout(headers);
+            let Some(info) = read_body(body).await? else {
+                // An empty body refreshes a lock the client holds
+                return Ok(match locks.refresh(key, &tokens, timeout) {
+                    Some(lock) => lock_response(StatusCode::OK, &lock, false),
+                    None => xml::precondition(
+                        StatusCode::PRECONDITION_FAILED,
+                        DAV,
+                        "lock-token-matches-request-uri",
+                    ),
+                });
+            };
+            let existing = storage.entry(key).await?;
+            if existing.is_none() {
+                if !is_dir(storage, parent(key)).await? {
+                    return Ok(StatusCode::CONFLICT.into_response());
+                }
+                if !locks.permits(parent(key), &tokens, false) {
+                    return Ok(locked());
+                }
+            }
+            let exclusive = info
+                .child(DAV, "lockscope")
+                .is_none_or(|scope| scope.child(DAV, "shared").is_none());
+            let owner = info
+                .child(DAV, "owner")
+                .map(|owner| match owner.child(DAV, "href") {
+                    Some(href) => format!("<d:href>{}</d:href>", escape(href.text.trim())),
+                    None => escape(owner.text.trim()).into_owned(),
+                })
+                .unwrap_or_default();
+            let deep = existing.as_ref().is_some_and(|entry| entry.dir)
+                && super::members_requested(headers);
+            let root = existing
+                .as_ref()
+                .map_or_else(|| format!("{}{}", HREF, key), href);
+            let Some(lock) = locks.lock(key, &root, exclusive, deep, owner, timeout) else {
+                return Ok(xml::precondition(
+                    StatusCode::LOCKED,
+                    DAV,
+                    "no-conflicting-lock",
+                ));
+            };
+            if existing.is_some() {
+                return Ok(lock_response(StatusCode::OK, &lock, true));
+            }
+            let empty = futures_util::stream::empty::<Result<Bytes, std::convert::Infallible>>();
+            if let Err(e) = storage.put(key, empty, None).await {
+                locks.unlock(key, &lock.token);
+                return Err(e);
+            }
+            Ok(lock_response(StatusCode::CREATED, &lock, true))
+        }
+        "UNLOCK" => {
+            let token = headers
+                .get("lock-token")
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().trim_start_matches('<').trim_end_matches('>'))
+                .ok_or_else(|| ApiError::BadRequest(t!("dav-lock-token")))?;
+            if locks.unlock(key, token) {
+                Ok(StatusCode::NO_CONTENT.into_response())
+            } else {
+                Ok(xml::precondition(
+                    StatusCode::CONFLICT,
+                    DAV,
+                    "lock-token-matches-request-uri",
+                ))
+            }
+        }
+        _ => Ok(super::not_allowed(ALLOW_FILES)),
+    }
+}
+
+/// Answer a `LOCK` with the lock, and its token if it is `new`
+fn lock_response(status: StatusCode, lock: &Lock, new: bool) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <d:prop xmlns:d=\"DAV:\"><d:lockdiscovery>{}</d:lockdiscovery></d:prop>",
+        active_lock(lock)
+    );
+    let mut response = (status, [(CONTENT_TYPE, xml::XML)], body).into_response();
+    if new {
+        if let Ok(token) = format!("<{}>", lock.token).parse() {
+            response.headers_mut().insert("lock-token", token);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_locks() {
+        let locks = Locks::default();
+        let hour = Duration::from_secs(3600);
+        let docs = locks
+            .lock("docs", "/dav/files/docs/", true, true, String::new(), hour)
+            .unwrap();
+        let tokens = [docs.token.clone()];
+
+        assert!(!locks.permits("docs/a.txt", &[], false));
+        assert!(locks.permits("docs/a.txt", &tokens, false));
+        assert!(locks.permits("docsx", &[], false));
+        assert!(!locks.permits("", &[], true), "the whole share holds docs");
+        assert!(locks.permits("", &[], false));
+        assert!(locks
+            .lock(
+                "docs/a.txt",
+                "/dav/files/docs/a.txt",
+                false,
+                false,
+                String::new(),
+                hour
+            )
+            .is_none());
+
+        // Shared locks coexist
+        let shared = locks
+            .lock(
+                "notes.txt",
+                "/dav/files/notes.txt",
+                false,
+                false,
+                String::new(),
+                hour,
+            )
+            .unwrap();
+        assert!(locks
+            .lock(
+                "notes.txt",
+                "/dav/files/notes.txt",
+                false,
+                false,
+                String::new(),
+                hour
+            )
+            .is_some());
+        assert!(locks
+            .lock(
+                "notes.txt",
+                "/dav/files/notes.txt",
+                true,
+                false,
+                String::new(),
+                hour
+            )
+            .is_none());
+        assert_eq!(locks.discovery("notes.txt").len(), 2);
+
+        assert!(locks.refresh("docs/a.txt", &tokens, hour).is_some());
+        assert!(locks.refresh("notes.txt", &tokens, hour).is_none());
+        assert!(!locks.unlock("notes.txt", &docs.token));
+        assert!(locks.unlock("notes.txt", &shared.token));
+        assert_eq!(locks.discovery("notes.txt").len(), 1);
+
+        locks.release("docs");
+        assert!(locks.permits("docs/a.txt", &[], false));
+
+        locks
+            .lock(
+                "old.txt",
+                "/dav/files/old.txt",
+                true,
+                false,
+                String::new(),
+                Duration::ZERO,
+            )
+            .unwrap();
+        assert!(locks.permits("old.txt", &[], false), "expired locks lapse");
+    }
+
+    #[test]
+    fn test_lock_headers() {
+        assert_eq!(
+            submitted_tokens(&headers(&[(
+                "if",
+                "</dav/files/a.txt> (<opaquelocktoken:1234> [\"1-2\"]) (Not <DAV:no-lock>)"
+            )])),
+            ["opaquelocktoken:1234"]
+        );
+        assert!(submitted_tokens(&headers(&[])).is_empty());
+
+        assert_eq!(
+            lock_timeout(&headers(&[("timeout", "Second-600")])),
+            Duration::from_secs(600)
+        );
+        assert_eq!(
+            lock_timeout(&headers(&[("timeout", "Infinite, Second-4100000000")])),
+            MAX_LOCK_TIMEOUT
+        );
+        assert_eq!(lock_timeout(&headers(&[])), MAX_LOCK_TIMEOUT);
+
+        assert_eq!(
+            destination(&headers(&[(
+                "destination",
+                "https://example.com/dav/files/archive/a%2D1.txt"
+            )])),
+            Some("archive/a-1.txt".to_string())
+        );
+        assert_eq!(
+            destination(&headers(&[("destination", "/dav/files/docs/")])),
+            Some("docs".to_string())
+        );
+        assert_eq!(
+            destination(&headers(&[("destination", "/dav/files")])),
+            Some(String::new())
+        );
+        assert_eq!(
+            destination(&headers(&[("destination", "/dav/contacts/a.vcf")])),
+            None
+        );
+    }
+}
@@ -0,0 +1,472 @@
+//! XML of WebDAV requests and `207 Multi-Status` responses.
+
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+use quick_xml::name::ResolveResult;
+use quick_xml::NsReader;
+
+use super::{CALDAV, CALENDARSERVER, CARDDAV, DAV};
+
+/// Deepest element nesting accepted in a request body
+const MAX_DEPTH: usize = 32;
+
+/// Prefixes declared on every response
+const PREFIXES: [(&str, &str); 4] = [
+    ("d", DAV),
+    ("c", CALDAV),
+    ("card", CARDDAV),
+    ("cs", CALENDARSERVER),
+];
+
+/// Content type of XML responses
+pub const XML: &str = "application/xml; charset=utf-8";
+
+/// An element of a request body, with its namespace resolved
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Element {
+    pub namespace: String,
+    pub name: String,
+    /// Unqualified attributes, by local name
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Element>,
+    pub text: String,
+}
+
+impl Element {
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    /// The first child named `namespace:name`
+    pub fn child(&self, namespace: &str, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.is(namespace, name))
+    }
+
+    /// Every child named `namespace:name`
+    pub fn children_named<'a>(
+        &'a self,
+        namespace: &'a str,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Element> {
+        self.children
+            .iter()
+            .filter(move |child| child.is(namespace, name))
+    }
+
+    /// The first element named `namespace:name` below this one, depth first
+    pub fn find(&self, namespace: &str, name: &str) -> Option<&Element> {
+        self.children.iter().find_map(|child| {
+            if child.is(namespace, name) {
+                Some(child)
+            } else {
+                child.find(namespace, name)
+            }
+        })
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parse a request body, `None` if it is empty
+pub fn parse(body: &[u8]) -> Result<Option<Element>, String> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    let mut reader = NsReader::from_reader(body);
+    reader.config_mut().trim_text(true);
+    let mut buf = Vec::new();
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root = None;
+    loop {
+        let (namespace, event) = reader
+            .read_resolved_event_into(&mut buf)
+            .map_err(|e| e.to_string())?;
+        let empty = matches!(event, Event::Empty(_));
+        match event {
+            Event::Start(start) | Event::Empty(start) => {
+                if stack.len() >= MAX_DEPTH {
+                    return Err("elements are nested too deeply".into());
+                }
+                let namespace = match namespace {
+                    ResolveResult::Bound(namespace) => {
+                        String::from_utf8_lossy(namespace.as_ref()).into_owned()
+                    }
+                    ResolveResult::Unbound => String::new(),
+                    ResolveResult::Unknown(prefix) => {
+                        return Err(format!(
+                            "undeclared prefix '{}'",
+                            String::from_utf8_lossy(&prefix)
+                        ))
+                    }
+                };
+                let mut element = Element {
+                    namespace,
+                    name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+                    ..Element::default()
+                };
+                for attribute in start.attributes() {
+                    let attribute = attribute.map_err(|e| e.to_string())?;
+                    if attribute.key.prefix().is_some() {
+                        continue;
+                    }
+                    let value = attribute
+                        .decode_and_unescape_value(reader.decoder())
+                        .map_err(|e| e.to_string())?;
+                    element.attributes.push((
+                        String::from_utf8_lossy(attribute.key.local_name().as_ref()).into_owned(),
+                        value.into_owned(),
+                    ));
+                }
+                if empty {
+                    close(&mut stack, &mut root, element);
+                } else {
+                    stack.push(element);
+                }
+            }
+            Event::End(_) => {
+                let element = stack.pop().ok_or("unbalanced end tag")?;
+                close(&mut stack, &mut root, element);
+            }
+            Event::Text(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element
+                        .text
+                        .push_str(&text.unescape().map_err(|e| e.to_string())?);
+                }
+            }
+            Event::CData(data) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&String::from_utf8_lossy(&data));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    if !stack.is_empty() {
+        return Err("unclosed elements".into());
+    }
+    root.map(Some).ok_or_else(|| "no root element".into())
+}
+
+fn close(stack: &mut [Element], root: &mut Option<Element>, element: Element) {
+    match stack.last_mut() {
+        Some(parent) => parent.children.push(element),
+        None => *root = Some(element),
+    }
+}
+
+/// A property name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Property {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl Property {
+    pub fn new(namespace: &str, name: &str) -> Self {
+        Property {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    /// The property as an element holding `value`, itself XML
+    fn write(&self, out: &mut String, value: &str) {
+        let (tag, declaration) = match PREFIXES.iter().find(|(_, ns)| *ns == self.namespace) {
+            Some((prefix, _)) => (format!("{}:{}", prefix, self.name), String::new()),
+            None => (
+                format!("x:{}", self.name),
+                format!(" xmlns:x=\"{}\"", escape(&self.namespace)),
+            ),
+        };
+        if value.is_empty() {
+            out.push_str(&format!("<{}{}/>", tag, declaration));
+        } else {
+            out.push_str(&format!("<{}{}>{}</{}>", tag, declaration, value, tag));
+        }
+    }
+}
+
+/// What a `PROPFIND` or `REPORT` asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PropFind {
+    /// Every property (`allprop`, or an empty body)
+    All,
+    /// Only the names of the properties (`propname`)
+    Names,
+    Props(Vec<Property>),
+}
+
+impl PropFind {
+    /// Read a `propfind` body or the children of a `REPORT`
+    pub fn from_element(element: Option<&Element>) -> Self {
+        let Some(element) = element else {
+            return PropFind::All;
+        };
+        if element.child(DAV, "propname").is_some() {
+            return PropFind::Names;
+        }
+        match element.child(DAV, "prop") {
+            Some(prop) => PropFind::Props(
+                prop.children
+                    .iter()
+                    .map(|child| Property::new(&child.namespace, &child.name))
+                    .collect(),
+            ),
+            None => PropFind::All,
+        }
+    }
+
+    /// Whether `namespace:name` was asked for by name
+    pub fn wants(&self, namespace: &str, name: &str) -> bool {
+        match self {
+            PropFind::Props(props) => props.iter().any(|prop| prop.is(namespace, name)),
+            _ => false,
+        }
+    }
+}
+
+/// A resource and the XML values of its properties
+#[derive(Debug, Clone)]
+pub struct Resource {
+    pub href: String,
+    pub properties: Vec<(Property, String)>,
+}
+
+impl Resource {
+    pub fn new(href: impl Into<String>) -> Self {
+        Resource {
+            href: href.into(),
+            properties: Vec::new(),
+        }
+    }
+
+    /// Add a property whose value is already XML
+    pub fn xml(mut self, namespace: &str, name: &str, value: impl Into<String>) -> Self {
+        self.properties
+            .push((Property::new(namespace, name), value.into()));
+        self
+    }
+
+    /// Add a property holding text
+    pub fn text(self, namespace: &str, name: &str, value: &str) -> Self {
+        self.xml(namespace, name, escape(value))
+    }
+
+    /// Add a property holding an `href`
+    pub fn href(self, namespace: &str, name: &str, href: &str) -> Self {
+        self.xml(
+            namespace,
+            name,
+            format!("<d:href>{}</d:href>", escape(href)),
+        )
+    }
+}
+
+/// A `207 Multi-Status` response being built
+pub struct Multistatus {
+    body: String,
+}
+
+impl Default for Multistatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Multistatus {
+    pub fn new() -> Self {
+        let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<d:multistatus");
+        for (prefix, namespace) in PREFIXES {
+            body.push_str(&format!(" xmlns:{}=\"{}\"", prefix, namespace));
+        }
+        body.push('>');
+        Multistatus { body }
+    }
+
+    /// Add the properties of `resource` that `request` asks for
+    pub fn resource(&mut self, resource: &Resource, request: &PropFind) {
+        let (found, missing): (Vec<(&Property, &str)>, Vec<&Property>) = match request {
+            PropFind::All => (
+                resource
+                    .properties
+                    .iter()
+                    .map(|(prop, value)| (prop, value.as_str()))
+                    .collect(),
+                Vec::new(),
+            ),
+            PropFind::Names => (
+                resource
+                    .properties
+                    .iter()
+                    .map(|(prop, _)| (prop, ""))
+                    .collect(),
+                Vec::new(),
+            ),
+            PropFind::Props(props) => {
+                let mut found = Vec::new();
+                let mut missing = Vec::new();
+                for prop in props {
+                    match resource.properties.iter().find(|(have, _)| have == prop) {
+                        Some((have, value)) => found.push((have, value.as_str())),
+                        None => missing.push(prop),
+                    }
+                }
+                (found, missing)
+            }
+        };
+        self.body.push_str(&format!(
+            "<d:response><d:href>{}</d:href>",
+            escape(&resource.href)
+        ));
+        if !found.is_empty() || missing.is_empty() {
+            self.body.push_str("<d:propstat><d:prop>");
+            for (prop, value) in found {
+                prop.write(&mut self.body, value);
+            }
+            self.body
+                .push_str("</d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>");
+        }
+        if !missing.is_empty() {
+            self.body.push_str("<d:propstat><d:prop>");
+            for prop in missing {
+                prop.write(&mut self.body, "");
+            }
+            self.body
+                .push_str("</d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>");
+        }
+        self.body.push_str("</d:response>");
+    }
+
+    /// Add a response with only a status, e.g. for a missing or removed member
+    pub fn status(&mut self, href: &str, status: StatusCode) {
+        self.body.push_str(&format!(
+            "<d:response><d:href>{}</d:href><d:status>HTTP/1.1 {}</d:status></d:response>",
+            escape(href),
+            status
+        ));
+    }
+
+    /// Add the token of a `sync-collection` report
+    pub fn sync_token(&mut self, token: &str) {
+        self.body
+            .push_str(&format!("<d:sync-token>{}</d:sync-token>", escape(token)));
+    }
+
+    pub fn into_body(mut self) -> String {
+        self.body.push_str("</d:multistatus>");
+        self.body
+    }
+}
+
+impl IntoResponse for Multistatus {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::MULTI_STATUS,
+            [(CONTENT_TYPE, XML)],
+            self.into_body(),
+        )
+            .into_response()
+    }
+}
+
+/// A `DAV:error` response naming a failed precondition
+pub fn precondition(status: StatusCode, namespace: &str, name: &str) -> Response {
+    let property = Property::new(namespace, name);
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<d:error");
+    for (prefix, namespace) in PREFIXES {
+        body.push_str(&format!(" xmlns:{}=\"{}\"", prefix, namespace));
+    }
+    body.push('>');
+    property.write(&mut body, "");
+    body.push_str("</d:error>");
+    (status, [(CONTENT_TYPE, XML)], body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_propfind() {
+        let body = br#"<?xml version="1.0"?>
+            <D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+              <D:prop>
+                <D:getetag/>
+                <C:calendar-data/>
+                <X:color xmlns:X="http://apple.com/ns/ical/"/>
+              </D:prop>
+            </D:propfind>"#;
+        let element = parse(body).unwrap().unwrap();
+        assert!(element.is(DAV, "propfind"));
+        let request = PropFind::from_element(Some(&element));
+        assert_eq!(
+            request,
+            PropFind::Props(vec![
+                Property::new(DAV, "getetag"),
+                Property::new(CALDAV, "calendar-data"),
+                Property::new("http://apple.com/ns/ical/", "color"),
+            ])
+        );
+        assert!(request.wants(CALDAV, "calendar-data"));
+
+        assert_eq!(parse(b"  \n").unwrap(), None);
+        assert_eq!(PropFind::from_element(None), PropFind::All);
+        let names = parse(br#"<propfind xmlns="DAV:"><propname/></propfind>"#).unwrap();
+        assert_eq!(PropFind::from_element(names.as_ref()), PropFind::Names);
+        assert!(parse(b"<a:propfind/>").is_err());
+        assert!(parse(b"<propfind xmlns=\"DAV:\"><prop>").is_err());
+        assert!(parse(&b"<a>".repeat(MAX_DEPTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_writes_multistatus() {
+        let resource = Resource::new("/dav/calendar/a b.ics")
+            .text(DAV, "getetag", "\"1-2\"")
+            .xml(DAV, "resourcetype", "");
+        let mut multistatus = Multistatus::new();
+        multistatus.resource(
+            &resource,
+            &PropFind::Props(vec![
+                Property::new(DAV, "getetag"),
+                Property::new("http://apple.com/ns/ical/", "color"),
+            ]),
+        );
+        multistatus.status("/dav/calendar/gone.ics", StatusCode::NOT_FOUND);
+        multistatus.sync_token("data:,1");
+        let body = multistatus.into_body();
+        assert!(body.contains(
+            "<d:response><d:href>/dav/calendar/a b.ics</d:href><d:propstat><d:prop>\
+             <d:getetag>&quot;1-2&quot;</d:getetag></d:prop>\
+             <d:status>HTTP/1.1 200 OK</d:status></d:propstat>"
+        ));
+        assert!(body.contains(
+            "<x:color xmlns:x=\"http://apple.com/ns/ical/\"/></d:prop>\
+             <d:status>HTTP/1.1 404 Not Found</d:status>"
+        ));
+        assert!(body.contains("<d:status>HTTP/1.1 404 Not Found</d:status></d:response>"));
+        assert!(body.ends_with("<d:sync-token>data:,1</d:sync-token></d:multistatus>"));
+
+        // A parsed response reads back
+        let element = parse(body.as_bytes()).unwrap().unwrap();
+        assert_eq!(element.children_named(DAV, "response").count(), 2);
+        assert_eq!(
+            element.find(DAV, "getetag").map(|e| e.text.as_str()),
+            Some("\"1-2\"")
+        );
+    }
+}
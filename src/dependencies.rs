@@ -0,0 +1,421 @@
+//! External dependency monitoring.
+//!
+//! `HEALTH_DEPENDENCIES` lists downstream services the server relies on:
+//! HTTP endpoints, raw TCP ports and other Postgres databases. Each one is
+//! probed every `HEALTH_DEPENDENCY_INTERVAL` and the latest result is served
+//! at `/health/upstreams`. Dependencies are registered as non-critical
+//! checks, so an outage degrades `/health/ready` without failing it.
+//!
+//! After `HEALTH_ALERT_AFTER` consecutive failures the outage is logged as an
+//! error and, if `HEALTH_ALERT_WEBHOOK` is set, posted to that URL. Recovery
+//! is only reported after `HEALTH_RECOVER_AFTER` consecutive successes, so a
+//! flapping dependency raises one alert rather than one per flap.
+
+use anyhow::{Context, Result};
+use axum::{extract::State, http::Method, response::Json, routing::get, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Connection, PgConnection};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+use crate::health::{Criticality, HealthRegistry, Status, CHECK_TIMEOUT};
+use crate::http_client::HttpClient;
+use crate::module::{RouteGroup, RouteModule};
+use crate::redact;
+use crate::AppState;
+
+/// How a dependency is probed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// `GET` must answer with a non-error status
+    Http(String),
+    /// A TCP connection must be accepted, `host:port`
+    Tcp(String),
+    /// A connection must be established and answer a ping
+    Postgres(String),
+}
+
+impl Target {
+    fn kind(&self) -> &'static str {
+        match self {
+            Target::Http(_) => "http",
+            Target::Tcp(_) => "tcp",
+            Target::Postgres(_) => "postgres",
+        }
+    }
+
+    /// The target without credentials, safe to show
+    fn display(&self) -> String {
+        match self {
+            Target::Http(url) | Target::Postgres(url) => redact::redact_url(url),
+            Target::Tcp(addr) => format!("tcp://{}", addr),
+        }
+    }
+}
+
+/// A named downstream service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub name: String,
+    pub target: Target,
+}
+
+/// Parse a list of dependencies
+///
+/// Dependencies are comma-separated `name=url` entries, where the URL scheme
+/// selects the probe: `http://`, `tcp://host:port` or `postgres://`, e.g.
+/// `auth=http://auth:8080/health,cache=tcp://redis:6379`.
+pub fn parse_dependencies(input: &str) -> Result<Vec<Dependency>> {
+    let mut dependencies: Vec<Dependency> = Vec::new();
+    for entry in input.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, url) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Invalid dependency '{}': expected name=url", entry))?;
+        let (name, url) = (name.trim(), url.trim());
+        if name.is_empty() {
+            anyhow::bail!("Invalid dependency '{}': missing name", entry);
+        }
+        if dependencies.iter().any(|d| d.name == name) {
+            anyhow::bail!("Duplicate dependency '{}'", name);
+        }
+        let target = if url.starts_with("http://") {
+            Target::Http(url.to_string())
+        } else if let Some(addr) = url.strip_prefix("tcp://") {
+            if addr
+                .rsplit_once(':')
+                .is_none_or(|(_, port)| port.parse::<u16>().is_err())
+            {
+                anyhow::bail!("Invalid dependency '{}': expected tcp://host:port", name);
+            }
+            Target::Tcp(addr.to_string())
+        } else if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            Target::Postgres(url.to_string())
+        } else {
+            anyhow::bail!(
+                "Invalid dependency '{}': expected an http://, tcp:// or postgres:// URL",
+                name
+            );
+        };
+        dependencies.push(Dependency {
+            name: name.to_string(),
+            target,
+        });
+    }
+    Ok(dependencies)
+}
+
+/// Latest probe result for one dependency
+#[derive(Debug, Clone, Serialize)]
+pub struct DependencyStatus {
+    pub name: String,
+    pub kind: &'static str,
+    pub target: String,
+    pub status: Status,
+    pub latency_ms: Option<u64>,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    /// Whether an outage was alerted and its recovery not yet
+    pub alerting: bool,
+    #[serde(with = "crate::time::rfc3339_option")]
+    pub last_checked: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+/// Alerting settings for sustained failures
+#[derive(Debug, Clone)]
+pub struct AlertConfig {
+    /// Consecutive failures before alerting
+    pub after: u32,
+    /// Consecutive successes before an alerted outage counts as recovered
+    pub recover_after: u32,
+    /// URL receiving a JSON `POST` on outage and recovery
+    pub webhook: Option<String>,
+}
+
+/// Background prober holding the latest status of every dependency
+#[derive(Clone)]
+pub struct DependencyMonitor {
+    statuses: Arc<RwLock<Vec<DependencyStatus>>>,
+}
+
+impl DependencyMonitor {
+    /// Snapshot of every dependency's latest status
+    pub fn statuses(&self) -> Vec<DependencyStatus> {
+        self.statuses
+            .read()
+            .expect("dependency lock poisoned")
+            .clone()
+    }
+
+    fn failure(&self, name: &str) -> Option<String> {
+        let statuses = self.statuses.read().expect("dependency lock poisoned");
+        let status = statuses.iter().find(|s| s.name == name)?;
+        (status.status != Status::Ok).then(|| {
+            status
+                .error
+                .clone()
+                .unwrap_or_else(|| "not checked yet".to_string())
+        })
+    }
+}
+
+/// Start probing `dependencies` and register a health check for each
+pub fn spawn(
+    dependencies: Vec<Dependency>,
+    interval: Duration,
+    alerts: AlertConfig,
+    health: &HealthRegistry,
+) -> DependencyMonitor {
+    let monitor = DependencyMonitor {
+        statuses: Arc::new(RwLock::new(
+            dependencies
+                .iter()
+                .map(|dependency| DependencyStatus {
+                    name: dependency.name.clone(),
+                    kind: dependency.target.kind(),
+                    target: dependency.target.display(),
+                    status: Status::Unavailable,
+                    latency_ms: None,
+                    consecutive_failures: 0,
+                    consecutive_successes: 0,
+                    alerting: false,
+                    last_checked: None,
+                    error: None,
+                })
+                .collect(),
+        )),
+    };
+
+    let client = HttpClient::new(CHECK_TIMEOUT);
+    for dependency in dependencies {
+        let checked = monitor.clone();
+        let name = dependency.name.clone();
+        health.register(
+            format!("dependency:{}", name),
+            Criticality::NonCritical,
+            move || {
+                let failure = checked.failure(&name);
+                async move {
+                    match failure {
+                        Some(error) => anyhow::bail!(error),
+                        None => Ok(()),
+                    }
+                }
+            },
+        );
+
+        let (monitor, client, alerts) = (monitor.clone(), client.clone(), alerts.clone());
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let started = Instant::now();
+                let result =
+                    tokio::time::timeout(CHECK_TIMEOUT, probe(&client, &dependency.target))
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(anyhow::anyhow!("timed out after {:?}", CHECK_TIMEOUT))
+                        });
+                let latency = started.elapsed();
+                let alert = monitor.record(&dependency.name, result, latency, &alerts);
+                if let Some(alert) = alert {
+                    send_alert(&client, &alerts, &dependency.name, alert).await;
+                }
+            }
+        });
+    }
+    monitor
+}
+
+/// A change worth alerting about
+#[derive(Debug, PartialEq, Eq)]
+enum Alert {
+    Down(String),
+    Recovered,
+}
+
+impl DependencyMonitor {
+    /// Store a probe result, returning an alert when the outage state changes
+    fn record(
+        &self,
+        name: &str,
+        result: Result<()>,
+        latency: Duration,
+        alerts: &AlertConfig,
+    ) -> Option<Alert> {
+        let mut statuses = self.statuses.write().expect("dependency lock poisoned");
+        let status = statuses.iter_mut().find(|s| s.name == name)?;
+        status.last_checked = Some(Utc::now());
+        status.latency_ms = Some(latency.as_millis() as u64);
+        match result {
+            Ok(()) => {
+                status.status = Status::Ok;
+                status.consecutive_failures = 0;
+                status.consecutive_successes += 1;
+                status.error = None;
+                let recovered =
+                    status.alerting && status.consecutive_successes >= alerts.recover_after;
+                if recovered {
+                    status.alerting = false;
+                }
+                recovered.then_some(Alert::Recovered)
+            }
+            Err(e) => {
+                let error = format!("{:#}", e);
+                status.status = Status::Unavailable;
+                status.consecutive_failures += 1;
+                status.consecutive_successes = 0;
+                status.error = Some(error.clone());
+                let down = !status.alerting && status.consecutive_failures >= alerts.after;
+                if down {
+                    status.alerting = true;
+                }
+                down.then_some(Alert::Down(error))
+            }
+        }
+    }
+}
+
+pub(crate) async fn probe(client: &HttpClient, target: &Target) -> Result<()> {
+    match target {
+        Target::Http(url) => {
+            let (status, _) = client.request(Method::GET, url, None).await?;
+            anyhow::ensure!(
+                !status.is_client_error() && !status.is_server_error(),
+                "returned {}",
+                status
+            );
+        }
+        Target::Tcp(addr) => {
+            TcpStream::connect(addr)
+                .await
+                .with_context(|| format!("connecting to {}", addr))?;
+        }
+        Target::Postgres(url) => {
+            let mut conn = PgConnection::connect(url).await?;
+            conn.ping().await?;
+            let _ = conn.close().await;
+        }
+    }
+    Ok(())
+}
+
+async fn send_alert(client: &HttpClient, alerts: &AlertConfig, name: &str, alert: Alert) {
+    let body = match &alert {
+        Alert::Down(error) => {
+            tracing::error!(
+                "🚨 Dependency '{}' has failed {} checks in a row: {}",
+                name,
+                alerts.after,
+                error
+            );
+            json!({ "dependency": name, "status": "down", "error": error })
+        }
+        Alert::Recovered => {
+            tracing::info!("✅ Dependency '{}' recovered", name);
+            json!({ "dependency": name, "status": "up" })
+        }
+    };
+    if let Some(webhook) = &alerts.webhook {
+        match client.request(Method::POST, webhook, Some(&body)).await {
+            Ok((status, _)) if status.is_success() => {}
+            Ok((status, _)) => tracing::warn!("Alert webhook returned {}", status),
+            Err(e) => tracing::warn!("Alert webhook failed: {:#}", e),
+        }
+    }
+}
+
+/// Route module serving `/health/upstreams`
+pub struct DependenciesModule;
+
+impl RouteModule for DependenciesModule {
+    fn name(&self) -> &'static str {
+        "dependencies"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/health/upstreams", get(upstreams))
+    }
+}
+
+/// `GET /health/upstreams` - latest status of every external dependency
+pub async fn upstreams(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let dependencies = state
+        .extension::<DependencyMonitor>()
+        .map(|monitor| monitor.statuses())
+        .unwrap_or_default();
+    let status = if dependencies.iter().all(|d| d.status == Status::Ok) {
+        Status::Ok
+    } else {
+        Status::Degraded
+    };
+    Json(json!({ "status": status, "dependencies": dependencies }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dependencies() {
+        let deps = parse_dependencies(
+            "auth=http://auth:8080/health, cache=tcp://redis:6379,db=postgres://u:p@db/app",
+        )
+        .unwrap();
+        assert_eq!(
+            deps[0].target,
+            Target::Http("http://auth:8080/health".into())
+        );
+        assert_eq!(deps[1].target, Target::Tcp("redis:6379".into()));
+        assert_eq!(deps[2].target.display(), "postgres://***@db/app");
+
+        assert!(parse_dependencies("cache=tcp://redis").is_err());
+        assert!(parse_dependencies("x=ftp://host").is_err());
+        assert!(parse_dependencies("a=tcp://h:1,a=tcp://h:2").is_err());
+    }
+
+    #[test]
+    fn test_alerts_on_sustained_failure_and_recovery() {
+        let monitor = DependencyMonitor {
+            statuses: Arc::new(RwLock::new(vec![DependencyStatus {
+                name: "cache".into(),
+                kind: "tcp",
+                target: "tcp://redis:6379".into(),
+                status: Status::Unavailable,
+                latency_ms: None,
+                consecutive_failures: 0,
+                consecutive_successes: 0,
+                alerting: false,
+                last_checked: None,
+                error: None,
+            }])),
+        };
+        let alerts = AlertConfig {
+            after: 2,
+            recover_after: 2,
+            webhook: None,
+        };
+
+        let fail = || Err(anyhow::anyhow!("refused"));
+        let record = |result| monitor.record("cache", result, Duration::ZERO, &alerts);
+        assert_eq!(record(fail()), None);
+        assert_eq!(record(fail()), Some(Alert::Down("refused".into())));
+        assert_eq!(record(fail()), None);
+        assert_eq!(monitor.failure("cache").as_deref(), Some("refused"));
+        // A single success while flapping neither recovers nor re-alerts
+        assert_eq!(record(Ok(())), None);
+        assert_eq!(monitor.failure("cache"), None);
+        assert_eq!(record(fail()), None);
+        assert_eq!(record(fail()), None);
+        assert_eq!(record(Ok(())), None);
+        assert_eq!(record(Ok(())), Some(Alert::Recovered));
+        assert_eq!(record(Ok(())), None);
+    }
+}
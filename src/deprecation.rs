@@ -0,0 +1,427 @@
+//! Deprecated routes.
+//!
+//! Routes on their way out are announced to clients with the headers of
+//! RFC 9745 and RFC 8594: `Deprecation` (when the route was deprecated),
+//! `Sunset` (when it will stop working) and a `Link` to migration notes.
+//! Modules declare their own in [`RouteModule::deprecations`]; deployments
+//! add or override them with `DEPRECATED_ROUTES`:
+//!
+//! ```text
+//! DEPRECATED_ROUTES=/api/v1/notes/search;since=2026-09-01;sunset=2027-03-01;link=https://example.com/v2
+//! ```
+//!
+//! A `link` starting with `/` is a page on this server and is announced as
+//! an absolute URL under the request's public URL (see [`crate::urls`]).
+//! Prefixes match like `ROUTE_OVERRIDES`, the longest one winning. Every
+//! request to a deprecated route is counted, together with the clients
+//! still making them, and reported at `GET /admin/deprecations` so the
+//! route can be removed once nobody calls it any more.
+//!
+//! [`RouteModule::deprecations`]: crate::module::RouteModule::deprecations
+
+use anyhow::Result;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header::LINK, HeaderValue},
+    middleware::Next,
+    response::{Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::auth::parse_date_time;
+use crate::extensions::Ext;
+use crate::module::{RouteGroup, RouteModule};
+use crate::route_overrides::covers;
+use crate::urls::PublicUrl;
+use crate::AppState;
+
+/// Distinct clients remembered per route; later ones are counted as `other`
+const MAX_CLIENTS: usize = 1000;
+/// Clients listed per route in the report
+const TOP_CLIENTS: usize = 10;
+
+/// A deprecated path prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Deprecation {
+    pub prefix: String,
+    /// When the route was or will be deprecated; unknown if None
+    pub since: Option<DateTime<Utc>>,
+    /// When the route stops working
+    pub sunset: Option<DateTime<Utc>>,
+    /// Where clients learn what to use instead
+    pub link: Option<String>,
+}
+
+impl Deprecation {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Deprecation {
+            prefix: prefix.into(),
+            ..Deprecation::default()
+        }
+    }
+}
+
+/// Parse `DEPRECATED_ROUTES`
+pub fn parse(input: &str) -> Result<Vec<Deprecation>> {
+    let deprecations = input
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_entry)
+        .collect::<Result<Vec<_>>>()?;
+    for (index, entry) in deprecations.iter().enumerate() {
+        if deprecations[..index]
+            .iter()
+            .any(|d| d.prefix == entry.prefix)
+        {
+            anyhow::bail!("Duplicate deprecated route '{}'", entry.prefix);
+        }
+    }
+    Ok(deprecations)
+}
+
+fn parse_entry(entry: &str) -> Result<Deprecation> {
+    let mut parts = entry.split(';').map(str::trim);
+    let prefix = parts.next().unwrap_or_default().trim_end_matches('/');
+    if !prefix.starts_with('/') || prefix.contains(['*', '?', '#']) {
+        anyhow::bail!(
+            "Invalid deprecated route '{}': expected a path such as /api/v1/notes/search",
+            prefix
+        );
+    }
+    let mut deprecation = Deprecation::new(prefix);
+    for setting in parts.filter(|p| !p.is_empty()) {
+        let (key, value) = setting
+            .split_once('=')
+            .map(|(k, v)| (k.trim(), v.trim()))
+            .ok_or_else(|| anyhow::anyhow!("Invalid setting '{}': expected key=value", setting))?;
+        match key {
+            "since" => deprecation.since = Some(parse_date_time(value)?),
+            "sunset" => deprecation.sunset = Some(parse_date_time(value)?),
+            "link" => {
+                HeaderValue::from_str(value)?;
+                deprecation.link = Some(value.to_string());
+            }
+            other => anyhow::bail!(
+                "Unknown setting '{}' (expected since, sunset or link)",
+                other
+            ),
+        }
+    }
+    if let (Some(since), Some(sunset)) = (deprecation.since, deprecation.sunset) {
+        anyhow::ensure!(
+            since <= sunset,
+            "Deprecated route '{}' sunsets before it is deprecated",
+            deprecation.prefix
+        );
+    }
+    Ok(deprecation)
+}
+
+/// Requests made to one deprecated route
+#[derive(Debug, Default)]
+struct Usage {
+    requests: u64,
+    last_used: Option<DateTime<Utc>>,
+    clients: HashMap<String, u64>,
+    /// Requests from clients beyond [`MAX_CLIENTS`]
+    other: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    deprecation: Deprecation,
+    headers: Vec<(&'static str, HeaderValue)>,
+    usage: Mutex<Usage>,
+}
+
+/// Usage of a deprecated route, as reported
+#[derive(Debug, Serialize)]
+pub struct DeprecationReport {
+    #[serde(flatten)]
+    pub deprecation: Deprecation,
+    pub requests: u64,
+    pub last_used: Option<DateTime<Utc>>,
+    /// Busiest clients first
+    pub clients: Vec<ClientUsage>,
+    pub other_clients: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ClientUsage {
+    pub client: String,
+    pub requests: u64,
+}
+
+/// The deprecated routes and their usage
+#[derive(Debug, Clone)]
+pub struct Deprecations {
+    entries: Arc<[Entry]>,
+}
+
+impl Deprecations {
+    /// Earlier entries win over later ones for the same prefix, so
+    /// configured deprecations go before those of modules
+    pub fn new(deprecations: Vec<Deprecation>) -> Self {
+        let mut entries: Vec<Entry> = Vec::new();
+        for deprecation in deprecations {
+            if entries
+                .iter()
+                .any(|e| e.deprecation.prefix == deprecation.prefix)
+            {
+                continue;
+            }
+            entries.push(Entry {
+                headers: headers(&deprecation),
+                deprecation,
+                usage: Mutex::default(),
+            });
+        }
+        // Longest prefix first, so the first match is the most specific
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.deprecation.prefix.len()));
+        Deprecations {
+            entries: entries.into(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn find(&self, path: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|entry| covers(&entry.deprecation.prefix, path))
+    }
+
+    /// Usage of every deprecated route, most used first
+    pub fn report(&self) -> Vec<DeprecationReport> {
+        let mut report: Vec<_> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let usage = entry.usage.lock().expect("deprecation lock poisoned");
+                let mut clients: Vec<_> = usage
+                    .clients
+                    .iter()
+                    .map(|(client, requests)| ClientUsage {
+                        client: client.clone(),
+                        requests: *requests,
+                    })
+                    .collect();
+                clients.sort_by(|a, b| {
+                    b.requests
+                        .cmp(&a.requests)
+                        .then_with(|| a.client.cmp(&b.client))
+                });
+                let other_clients = usage.other
+                    + clients
+                        .iter()
+                        .skip(TOP_CLIENTS)
+                        .map(|c| c.requests)
+                        .sum::<u64>();
+                clients.truncate(TOP_CLIENTS);
+                DeprecationReport {
+                    deprecation: entry.deprecation.clone(),
+                    requests: usage.requests,
+                    last_used: usage.last_used,
+                    clients,
+                    other_clients,
+                }
+            })
+            .collect();
+        report.sort_by_key(|r| std::cmp::Reverse(r.requests));
+        report
+    }
+
+    /// Middleware announcing deprecations and counting their use
+    pub async fn middleware(
+        State(deprecations): State<Deprecations>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let Some(entry) = deprecations.find(request.uri().path()) else {
+            return next.run(request).await;
+        };
+        let client = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map_or_else(
+                || "unknown".to_string(),
+                |ConnectInfo(addr)| addr.ip().to_string(),
+            );
+        {
+            let mut usage = entry.usage.lock().expect("deprecation lock poisoned");
+            usage.requests += 1;
+            usage.last_used = Some(Utc::now());
+            let known = usage.clients.len() < MAX_CLIENTS || usage.clients.contains_key(&client);
+            if known {
+                *usage.clients.entry(client).or_default() += 1;
+            } else {
+                usage.other += 1;
+            }
+        }
+
+        let local_link = entry
+            .deprecation
+            .link
+            .as_deref()
+            .filter(|link| link.starts_with('/'))
+            .map(|link| PublicUrl::of(&request).join(link));
+
+        let mut response = next.run(request).await;
+        for (name, value) in &entry.headers {
+            response.headers_mut().append(*name, value.clone());
+        }
+        if let Some(value) = local_link.as_deref().and_then(link_header) {
+            response.headers_mut().append(LINK, value);
+        }
+        response
+    }
+}
+
+/// Response headers announcing a deprecation
+fn headers(deprecation: &Deprecation) -> Vec<(&'static str, HeaderValue)> {
+    let mut headers = Vec::new();
+    // A structured-field date, or just the fact when the date is unknown
+    let since = match deprecation.since {
+        Some(since) => HeaderValue::from_str(&format!("@{}", since.timestamp())),
+        None => Ok(HeaderValue::from_static("true")),
+    };
+    headers.extend(since.ok().map(|value| ("deprecation", value)));
+    if let Some(sunset) = deprecation.sunset {
+        let date = sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        headers.extend(HeaderValue::from_str(&date).ok().map(|v| ("sunset", v)));
+    }
+    // Links to this server depend on the request, so are added per response
+    if let Some(link) = deprecation.link.as_deref().filter(|l| !l.starts_with('/')) {
+        headers.extend(link_header(link).map(|v| (LINK.as_str(), v)));
+    }
+    headers
+}
+
+fn link_header(link: &str) -> Option<HeaderValue> {
+    let link = format!(r#"<{}>; rel="deprecation"; type="text/html""#, link);
+    HeaderValue::from_str(&link).ok()
+}
+
+/// Route module reporting deprecated route usage, mounted when any route
+/// is deprecated
+pub struct DeprecationsModule;
+
+impl RouteModule for DeprecationsModule {
+    fn name(&self) -> &'static str {
+        "deprecations"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/admin/deprecations", get(list_deprecations))
+    }
+}
+
+/// `GET /admin/deprecations` - deprecated routes and who still calls them
+async fn list_deprecations(Ext(deprecations): Ext<Deprecations>) -> Json<Vec<DeprecationReport>> {
+    Json(deprecations.report())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware};
+    use tower::Service;
+
+    #[test]
+    fn test_parse_deprecated_routes() {
+        let deprecations =
+            parse("/api/v1/search/;since=2026-09-01;sunset=2027-03-01T12:00:00Z;link=https://example.com/v2, /old")
+                .unwrap();
+        assert_eq!(deprecations[0].prefix, "/api/v1/search");
+        assert_eq!(
+            deprecations[0].sunset.unwrap().to_rfc3339(),
+            "2027-03-01T12:00:00+00:00"
+        );
+        assert_eq!(deprecations[1], Deprecation::new("/old"));
+
+        assert!(parse("old").is_err());
+        assert!(parse("/old;since=soon").is_err());
+        assert!(parse("/old;since=2027-01-01;sunset=2026-01-01").is_err());
+        assert!(parse("/old;color=red").is_err());
+        assert!(parse("/old,/old/").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_announces_and_counts_deprecated_routes() {
+        let deprecations = Deprecations::new(vec![
+            parse("/v1/search;since=2026-09-01;sunset=2027-03-01;link=https://example.com/v2")
+                .unwrap()
+                .remove(0),
+            parse("/v1;link=/docs/v2").unwrap().remove(0),
+            // Shadowed by the configured entry above
+            Deprecation::new("/v1/search"),
+        ]);
+        let mut app = Router::new()
+            .route("/v1/search", get(|| async {}))
+            .route("/v1/notes", get(|| async {}))
+            .route("/v2/search", get(|| async {}))
+            .layer(middleware::from_fn_with_state(
+                deprecations.clone(),
+                Deprecations::middleware,
+            ));
+        let mut get = |uri: &str, client: &str| {
+            let mut request = Request::get(uri).body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::new(client.parse().unwrap(), 1234)));
+            app.call(request)
+        };
+
+        let response = get("/v1/search", "10.0.0.1").await.unwrap();
+        let header = |name| response.headers().get(name).unwrap().to_str().unwrap();
+        assert_eq!(header("deprecation"), "@1788220800");
+        assert_eq!(header("sunset"), "Mon, 01 Mar 2027 00:00:00 GMT");
+        assert_eq!(
+            header("link"),
+            r#"<https://example.com/v2>; rel="deprecation"; type="text/html""#
+        );
+        get("/v1/search", "10.0.0.1").await.unwrap();
+        get("/v1/search", "10.0.0.2").await.unwrap();
+        let response = get("/v1/notes", "10.0.0.1").await.unwrap();
+        assert_eq!(response.headers()["deprecation"], "true");
+        assert!(response.headers().get("sunset").is_none());
+        assert_eq!(
+            response.headers()["link"],
+            r#"<http://localhost/docs/v2>; rel="deprecation"; type="text/html""#
+        );
+        let response = get("/v2/search", "10.0.0.1").await.unwrap();
+        assert!(response.headers().get("deprecation").is_none());
+
+        let report = deprecations.report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].deprecation.prefix, "/v1/search");
+        assert_eq!(report[0].requests, 3);
+        assert_eq!(
+            report[0].clients,
+            [
+                ClientUsage {
+                    client: "10.0.0.1".into(),
+                    requests: 2
+                },
+                ClientUsage {
+                    client: "10.0.0.2".into(),
+                    requests: 1
+                },
+            ]
+        );
+        assert_eq!(report[1].requests, 1);
+    }
+}
@@ -0,0 +1,367 @@
+//! Disk space checks.
+//!
+//! The filesystems holding the working directory, where crash reports,
+//! captures and uploads are written, the log and crash directories and any
+//! `HEALTH_DISK_PATHS` are checked as part of health. Below
+//! `HEALTH_DISK_WARN_FREE` free the `disk` check carries a warning in
+//! `/health/ready`; below `HEALTH_DISK_MIN_FREE` it fails, which degrades
+//! readiness without failing it. With `HEALTH_DB_MAX_SIZE` set, the
+//! `database_size` check does the same for the Postgres database against
+//! `HEALTH_DB_WARN_SIZE` and `HEALTH_DB_MAX_SIZE`.
+//!
+//! A background task samples the same readings every `HEALTH_DISK_INTERVAL`
+//! and posts to `HEALTH_ALERT_WEBHOOK` whenever one changes level, so a
+//! filling disk is reported before it is full, not only when probed. Free
+//! space is read with `statvfs`, so the checks only work on Unix.
+
+use anyhow::Result;
+use axum::http::Method;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::health::{Criticality, HealthRegistry};
+use crate::http_client::HttpClient;
+use crate::leader::Leadership;
+
+/// Timeout for posting to the alert webhook
+const ALERT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Space on the filesystem holding a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DiskUsage {
+    /// Bytes available to the server's user
+    pub free_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// Read the space on the filesystem holding `path`
+#[cfg(unix)]
+pub fn usage(path: &Path) -> Result<DiskUsage> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is NUL-terminated and `stat` is only read on success
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(anyhow::Error::new(std::io::Error::last_os_error())
+                .context(format!("reading disk space of {}", path.display())));
+        }
+        stat.assume_init()
+    };
+    let block = stat.f_frsize as u64;
+    Ok(DiskUsage {
+        free_bytes: stat.f_bavail as u64 * block,
+        total_bytes: stat.f_blocks as u64 * block,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn usage(path: &Path) -> Result<DiskUsage> {
+    anyhow::bail!("reading disk space of {} is not supported", path.display())
+}
+
+/// Disk and database size thresholds, from configuration
+#[derive(Debug, Clone)]
+pub struct DiskConfig {
+    /// Directories whose filesystems are checked
+    pub paths: Vec<PathBuf>,
+    /// Free bytes below which the `disk` check warns (zero disables it)
+    pub warn_free: u64,
+    /// Free bytes below which the `disk` check fails (zero disables it)
+    pub min_free: u64,
+    /// Database size above which the `database_size` check warns
+    pub db_warn_size: u64,
+    /// Database size above which the `database_size` check fails (zero
+    /// disables the check)
+    pub db_max_size: u64,
+    /// How often readings are sampled for alerts
+    pub interval: Duration,
+}
+
+impl DiskConfig {
+    fn checks_disk(&self) -> bool {
+        self.min_free > 0 || self.warn_free > 0
+    }
+
+    fn checks_database(&self) -> bool {
+        self.db_max_size > 0
+    }
+}
+
+/// How close a resource is to running out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Ok,
+    Warning,
+    Critical,
+}
+
+/// A resource's level, with the numbers behind it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reading {
+    /// A directory, or `database`
+    pub resource: String,
+    pub level: Level,
+    pub detail: String,
+}
+
+/// The nearest ancestor of `path` that exists, as directories such as the
+/// crash directory are only created when first written to
+fn existing(path: &Path) -> &Path {
+    path.ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."))
+}
+
+/// Read the free space on the filesystem holding `path`
+pub fn read_path(path: &Path, warn_free: u64, min_free: u64) -> Result<Reading> {
+    let usage = usage(existing(path))?;
+    let level = if usage.free_bytes < min_free {
+        Level::Critical
+    } else if usage.free_bytes < warn_free {
+        Level::Warning
+    } else {
+        Level::Ok
+    };
+    let threshold = match level {
+        Level::Critical => format!(", below {} MiB", min_free >> 20),
+        Level::Warning => format!(", below {} MiB", warn_free >> 20),
+        Level::Ok => String::new(),
+    };
+    Ok(Reading {
+        resource: path.display().to_string(),
+        level,
+        detail: format!(
+            "{} MiB free of {} MiB{}",
+            usage.free_bytes >> 20,
+            usage.total_bytes >> 20,
+            threshold
+        ),
+    })
+}
+
+/// Read the size of the connected database
+pub async fn read_database(pool: &PgPool, warn_size: u64, max_size: u64) -> Result<Reading> {
+    let size: i64 = sqlx::query_scalar("SELECT pg_database_size(current_database())")
+        .fetch_one(pool)
+        .await?;
+    let size = size as u64;
+    let level = if size >= max_size {
+        Level::Critical
+    } else if warn_size > 0 && size >= warn_size {
+        Level::Warning
+    } else {
+        Level::Ok
+    };
+    Ok(Reading {
+        resource: "database".into(),
+        level,
+        detail: format!("{} MiB of at most {} MiB", size >> 20, max_size >> 20),
+    })
+}
+
+/// Read every configured path, off the async runtime
+async fn read_paths(config: Arc<DiskConfig>) -> Result<Vec<Reading>> {
+    tokio::task::spawn_blocking(move || {
+        config
+            .paths
+            .iter()
+            .map(|path| read_path(path, config.warn_free, config.min_free))
+            .collect()
+    })
+    .await?
+}
+
+/// Fail a check for critical readings and warn about the others
+fn evaluate(health: &HealthRegistry, check: &str, readings: &[Reading]) -> Result<()> {
+    let describe = |level| {
+        let described: Vec<String> = readings
+            .iter()
+            .filter(|reading| reading.level == level)
+            .map(|reading| format!("{}: {}", reading.resource, reading.detail))
+            .collect();
+        (!described.is_empty()).then(|| described.join("; "))
+    };
+    health.set_warning(check, describe(Level::Warning));
+    match describe(Level::Critical) {
+        Some(critical) => anyhow::bail!(critical),
+        None => Ok(()),
+    }
+}
+
+/// Register the non-critical `disk` and `database_size` checks configured
+pub fn register_health_checks(health: &HealthRegistry, config: &DiskConfig, pool: PgPool) {
+    let config = Arc::new(config.clone());
+    if config.checks_disk() {
+        let (registry, config) = (health.clone(), config.clone());
+        health.register("disk", Criticality::NonCritical, move || {
+            let (registry, config) = (registry.clone(), config.clone());
+            async move {
+                let readings = read_paths(config).await?;
+                evaluate(&registry, "disk", &readings)
+            }
+        });
+    }
+    if config.checks_database() {
+        let registry = health.clone();
+        health.register("database_size", Criticality::NonCritical, move || {
+            let (registry, config, pool) = (registry.clone(), config.clone(), pool.clone());
+            async move {
+                let reading = read_database(&pool, config.db_warn_size, config.db_max_size).await?;
+                evaluate(&registry, "database_size", &[reading])
+            }
+        });
+    }
+}
+
+/// Levels last alerted, by resource
+#[derive(Default)]
+struct Alerted(HashMap<String, Level>);
+
+impl Alerted {
+    /// Readings whose level changed since the last sample; at first, only
+    /// those not `Ok`
+    fn changed(&mut self, readings: Vec<Reading>) -> Vec<Reading> {
+        readings
+            .into_iter()
+            .filter(|reading| {
+                let previous = self
+                    .0
+                    .insert(reading.resource.clone(), reading.level)
+                    .unwrap_or(Level::Ok);
+                previous != reading.level
+            })
+            .collect()
+    }
+}
+
+async fn alert(client: &HttpClient, webhook: Option<&str>, reading: &Reading) {
+    match reading.level {
+        Level::Ok => tracing::info!("✅ {} recovered: {}", reading.resource, reading.detail),
+        Level::Warning => {
+            tracing::warn!("💾 {} is filling up: {}", reading.resource, reading.detail)
+        }
+        Level::Critical => {
+            tracing::error!("🚨 {} is nearly full: {}", reading.resource, reading.detail)
+        }
+    }
+    let Some(webhook) = webhook else {
+        return;
+    };
+    let body = json!({
+        "resource": reading.resource,
+        "status": reading.level,
+        "detail": reading.detail,
+    });
+    match client.request(Method::POST, webhook, Some(&body)).await {
+        Ok((status, _)) if status.is_success() => {}
+        Ok((status, _)) => tracing::warn!("Disk alert webhook returned {}", status),
+        Err(e) => tracing::warn!("Disk alert webhook failed: {:#}", e),
+    }
+}
+
+/// Sample readings every `HEALTH_DISK_INTERVAL`, alerting on level changes
+///
+/// Every replica watches its own disks; only the leader watches the shared
+/// database.
+pub fn spawn_alerts(
+    config: DiskConfig,
+    pool: PgPool,
+    webhook: Option<String>,
+    leadership: Leadership,
+) -> tokio::task::JoinHandle<()> {
+    let config = Arc::new(config);
+    let client = HttpClient::new(ALERT_TIMEOUT);
+    tokio::spawn(async move {
+        if !config.checks_disk() && !config.checks_database() {
+            return;
+        }
+        let mut alerted = Alerted::default();
+        let mut ticker = tokio::time::interval(config.interval);
+        loop {
+            ticker.tick().await;
+            let mut readings = Vec::new();
+            if config.checks_disk() {
+                match read_paths(config.clone()).await {
+                    Ok(paths) => readings.extend(paths),
+                    Err(e) => tracing::warn!("Failed to read disk space: {:#}", e),
+                }
+            }
+            if config.checks_database() && leadership.is_leader() {
+                match read_database(&pool, config.db_warn_size, config.db_max_size).await {
+                    Ok(reading) => readings.push(reading),
+                    Err(e) => tracing::warn!("Failed to read the database size: {:#}", e),
+                }
+            }
+            for reading in alerted.changed(readings) {
+                alert(&client, webhook.as_deref(), &reading).await;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::Status;
+
+    #[test]
+    fn test_reads_usage_of_the_working_directory() {
+        let usage = usage(Path::new(".")).unwrap();
+        assert!(usage.total_bytes > 0);
+        assert!(usage.free_bytes <= usage.total_bytes);
+        assert!(super::usage(Path::new("/does/not/exist")).is_err());
+
+        // Missing directories are read from their nearest existing ancestor
+        let reading = read_path(Path::new("not-created-yet/crashes"), 0, 0).unwrap();
+        assert_eq!(reading.level, Level::Ok);
+        let reading = read_path(Path::new("."), u64::MAX, 0).unwrap();
+        assert_eq!(reading.level, Level::Warning);
+        let reading = read_path(Path::new("."), u64::MAX, u64::MAX).unwrap();
+        assert_eq!(reading.level, Level::Critical);
+    }
+
+    #[tokio::test]
+    async fn test_warns_in_readiness_and_alerts_on_changes() {
+        let health = HealthRegistry::default();
+        health.set_serving_state(crate::health::ServingState::Serving);
+        let config = DiskConfig {
+            paths: vec![PathBuf::from(".")],
+            warn_free: u64::MAX,
+            min_free: 1,
+            db_warn_size: 0,
+            db_max_size: 0,
+            interval: Duration::from_secs(60),
+        };
+        let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        register_health_checks(&health, &config, pool);
+        let report = health.run(Duration::from_secs(5)).await;
+        assert_eq!(report.status, Status::Ok);
+        assert_eq!(report.checks.len(), 1);
+        assert!(report.checks[0]
+            .warning
+            .as_deref()
+            .unwrap()
+            .starts_with(".: "));
+
+        let reading = |level| Reading {
+            resource: "/data".into(),
+            level,
+            detail: String::new(),
+        };
+        let mut alerted = Alerted::default();
+        assert!(alerted.changed(vec![reading(Level::Ok)]).is_empty());
+        assert_eq!(alerted.changed(vec![reading(Level::Warning)]).len(), 1);
+        assert!(alerted.changed(vec![reading(Level::Warning)]).is_empty());
+        assert_eq!(alerted.changed(vec![reading(Level::Critical)]).len(), 1);
+        assert_eq!(alerted.changed(vec![reading(Level::Ok)]).len(), 1);
+    }
+}
@@ -0,0 +1,348 @@
+//! Startup self-test.
+//!
+//! Parsing the configuration catches malformed values; [`SelfTest`]
+//! catches the ones that are well-formed but won't work on this host. It
+//! runs in two phases:
+//!
+//! 1. [`SelfTest::local`], before the database is connected: the clock is
+//!    plausible, `TLS_CERT_FILE` and `TLS_KEY_FILE` are readable, the crash,
+//!    log, capture and storage directories are writable and their disks not
+//!    full.
+//! 2. [`SelfTest::connected`], once it is: the database answers, its clock
+//!    agrees with ours and every `HEALTH_DEPENDENCIES` entry (SMTP, caches,
+//!    object storage) is reachable.
+//!
+//! Each phase's report is logged. `STARTUP_SELF_TEST=fail` refuses to start
+//! when a check fails, `warn` (the default) only logs it and `ignore` skips
+//! the checks. `doctor` runs both phases on demand and prints the report.
+
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::db::Database;
+use crate::dependencies;
+use crate::disk::{self, Level};
+use crate::http_client::HttpClient;
+use crate::smoke::{self, timed, Check, CheckResult, Outcome, Unmet};
+
+/// Earliest plausible time; a clock before it was never set
+const CLOCK_FLOOR: &str = "2025-01-01T00:00:00Z";
+
+/// Largest difference tolerated between our clock and the database's
+const MAX_CLOCK_SKEW: TimeDelta = TimeDelta::seconds(5);
+
+/// How long each dependency may take to answer
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a failed self-test check does at startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestPolicy {
+    /// Refuse to start
+    Fail,
+    /// Log the report and carry on
+    Warn,
+    /// Skip the checks
+    Ignore,
+}
+
+impl FromStr for SelfTestPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(SelfTestPolicy::Fail),
+            "warn" => Ok(SelfTestPolicy::Warn),
+            "ignore" => Ok(SelfTestPolicy::Ignore),
+            other => anyhow::bail!(
+                "Unknown self-test policy '{}' (expected fail, warn or ignore)",
+                other
+            ),
+        }
+    }
+}
+
+/// The checks of one configuration
+pub struct SelfTest<'a> {
+    config: &'a Config,
+    /// `TLS_CERT_FILE` and `TLS_KEY_FILE`, if set
+    tls: Option<(PathBuf, PathBuf)>,
+}
+
+impl<'a> SelfTest<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        let var = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty());
+        let tls = match (var("TLS_CERT_FILE"), var("TLS_KEY_FILE")) {
+            (None, None) => None,
+            (cert, key) => Some((
+                cert.map(PathBuf::from).unwrap_or_default(),
+                key.map(PathBuf::from).unwrap_or_default(),
+            )),
+        };
+        SelfTest { config, tls }
+    }
+
+    /// Checks needing nothing but the host
+    pub async fn local(&self) -> Vec<Check> {
+        vec![
+            timed("clock", async { clock(Utc::now()) }).await,
+            timed("tls", async { self.tls() }).await,
+            timed("directories", async { self.directories() }).await,
+            timed("disk", async { self.disk() }).await,
+        ]
+    }
+
+    /// Checks against the database and dependencies
+    pub async fn connected(&self, db: Option<&Database>) -> Vec<Check> {
+        vec![
+            timed("database", database(db)).await,
+            timed("clock_skew", clock_skew(db)).await,
+            timed("dependencies", self.dependencies()).await,
+        ]
+    }
+
+    fn tls(&self) -> CheckResult {
+        let (cert, key) = self
+            .tls
+            .as_ref()
+            .ok_or_else(|| Unmet::Skipped("TLS_CERT_FILE is not set".to_string()))?;
+        for (name, path) in [("TLS_CERT_FILE", cert), ("TLS_KEY_FILE", key)] {
+            if path.as_os_str().is_empty() {
+                return Err(Unmet::Failed(format!("{} is not set", name)));
+            }
+            let pem = std::fs::read(path)
+                .map_err(|e| Unmet::Failed(format!("{} {}: {}", name, path.display(), e)))?;
+            if !pem.starts_with(b"-----BEGIN") {
+                return Err(Unmet::Failed(format!(
+                    "{} {} is not PEM",
+                    name,
+                    path.display()
+                )));
+            }
+        }
+        Ok(format!("{} and {} readable", cert.display(), key.display()))
+    }
+
+    /// Directories the server writes to, by what it writes there
+    fn written_directories(&self) -> Vec<(&'static str, &Path)> {
+        let mut directories = vec![("crashes", self.config.crash_dir())];
+        if let Some(files) = self.config.log_files() {
+            directories.push(("logs", &files.dir));
+        }
+        if let Some(capture) = self.config.capture() {
+            directories.push(("captures", &capture.dir));
+        }
+        if let Some(storage) = self.config.storage() {
+            directories.push(("storage", &storage.dir));
+        }
+        directories
+    }
+
+    fn directories(&self) -> CheckResult {
+        let directories = self.written_directories();
+        for (name, dir) in &directories {
+            writable(dir)
+                .map_err(|e| Unmet::Failed(format!("{} ({}): {:#}", name, dir.display(), e)))?;
+        }
+        let names: Vec<_> = directories.iter().map(|(name, _)| *name).collect();
+        Ok(format!("{} writable", names.join(", ")))
+    }
+
+    fn disk(&self) -> CheckResult {
+        let config = self.config.disk();
+        if config.min_free == 0 && config.warn_free == 0 {
+            return Err(Unmet::Skipped("HEALTH_DISK_MIN_FREE is 0".to_string()));
+        }
+        let mut low = Vec::new();
+        for path in &config.paths {
+            let reading = disk::read_path(path, config.warn_free, config.min_free)?;
+            if reading.level == Level::Critical {
+                return Err(Unmet::Failed(format!(
+                    "{}: {}",
+                    reading.resource, reading.detail
+                )));
+            }
+            if reading.level == Level::Warning {
+                low.push(format!("{}: {}", reading.resource, reading.detail));
+            }
+        }
+        if low.is_empty() {
+            Ok(format!("{} paths have room", config.paths.len()))
+        } else {
+            Ok(format!("running low on {}", low.join("; ")))
+        }
+    }
+
+    async fn dependencies(&self) -> CheckResult {
+        let dependencies = self.config.dependencies();
+        if dependencies.is_empty() {
+            return Err(Unmet::Skipped("no HEALTH_DEPENDENCIES".to_string()));
+        }
+        let client = HttpClient::new(PROBE_TIMEOUT);
+        let probes = dependencies.iter().map(|dependency| {
+            let client = &client;
+            async move {
+                let probe = dependencies::probe(client, &dependency.target);
+                match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(format!("{}: {:#}", dependency.name, e)),
+                    Err(_) => Some(format!("{}: timed out", dependency.name)),
+                }
+            }
+        });
+        let failed: Vec<String> = futures_util::future::join_all(probes)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        if failed.is_empty() {
+            Ok(format!("{} reachable", dependencies.len()))
+        } else {
+            Err(Unmet::Failed(failed.join("; ")))
+        }
+    }
+}
+
+fn clock(now: DateTime<Utc>) -> CheckResult {
+    let floor: DateTime<Utc> = CLOCK_FLOOR.parse().expect("CLOCK_FLOOR is valid");
+    if now < floor {
+        return Err(Unmet::Failed(format!(
+            "system time {} is before {}; is the clock set?",
+            now.to_rfc3339(),
+            CLOCK_FLOOR
+        )));
+    }
+    Ok(now.to_rfc3339())
+}
+
+/// Create `dir` if needed and write and remove a file in it
+fn writable(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".self-test-{}", std::process::id()));
+    std::fs::write(&probe, b"ok")?;
+    std::fs::remove_file(&probe)?;
+    Ok(())
+}
+
+async fn database(db: Option<&Database>) -> CheckResult {
+    let db = db.ok_or_else(|| Unmet::Failed("not connected".to_string()))?;
+    let version: String = sqlx::query_scalar("SHOW server_version")
+        .fetch_one(db.pool())
+        .await
+        .map_err(anyhow::Error::from)?;
+    Ok(format!("PostgreSQL {}", version))
+}
+
+async fn clock_skew(db: Option<&Database>) -> CheckResult {
+    let db = db.ok_or_else(|| Unmet::Skipped("not connected".to_string()))?;
+    let before = Utc::now();
+    let theirs: DateTime<Utc> = sqlx::query_scalar("SELECT clock_timestamp()")
+        .fetch_one(db.pool())
+        .await
+        .map_err(anyhow::Error::from)?;
+    // Compare against the middle of the round trip
+    let ours = before + (Utc::now() - before) / 2;
+    let skew = (theirs - ours).abs();
+    let detail = format!("{} ms from the database", skew.num_milliseconds());
+    if skew > MAX_CLOCK_SKEW {
+        Err(Unmet::Failed(detail))
+    } else {
+        Ok(detail)
+    }
+}
+
+/// Log a phase's report, failing under [`SelfTestPolicy::Fail`] if any
+/// check failed
+pub fn enforce(policy: SelfTestPolicy, phase: &str, checks: &[Check]) -> Result<()> {
+    for check in checks {
+        match check.outcome {
+            Outcome::Pass => tracing::info!("🩺 {} {}: {}", phase, check.name, check.detail),
+            Outcome::Skip => {
+                tracing::debug!("🩺 {} {} skipped: {}", phase, check.name, check.detail)
+            }
+            Outcome::Fail => tracing::warn!("🩺 {} {} failed: {}", phase, check.name, check.detail),
+        }
+    }
+    let failed: Vec<_> = checks
+        .iter()
+        .filter(|check| check.outcome == Outcome::Fail)
+        .map(|check| check.name)
+        .collect();
+    tracing::info!("🩺 {} self-test: {}", phase, smoke::summarize(checks));
+    if policy == SelfTestPolicy::Fail && !failed.is_empty() {
+        anyhow::bail!(
+            "Startup self-test failed: {} (set STARTUP_SELF_TEST=warn to start anyway)",
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::TestDatabase;
+
+    fn outcome(checks: &[Check], name: &str) -> Outcome {
+        checks.iter().find(|c| c.name == name).unwrap().outcome
+    }
+
+    #[tokio::test]
+    async fn test_checks_local_files_and_directories() {
+        let dir = std::env::temp_dir().join(format!("self-test-{}", std::process::id()));
+        let cert = dir.join("cert.pem");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&cert, "-----BEGIN CERTIFICATE-----\n").unwrap();
+        let config = crate::config::Config::from_vars(|key| match key {
+            "DATABASE_URL" => Some("postgres://localhost/selfhost".into()),
+            "CRASH_DIR" => Some(dir.join("crashes").display().to_string()),
+            "HEALTH_DISK_MIN_FREE" => Some("0".into()),
+            "HEALTH_DISK_WARN_FREE" => Some("0".into()),
+            _ => None,
+        })
+        .unwrap();
+        let mut test = SelfTest::new(&config);
+
+        test.tls = Some((cert.clone(), dir.join("missing.pem")));
+        let checks = test.local().await;
+        assert_eq!(outcome(&checks, "clock"), Outcome::Pass);
+        assert_eq!(outcome(&checks, "tls"), Outcome::Fail);
+        assert_eq!(outcome(&checks, "directories"), Outcome::Pass);
+        assert!(dir.join("crashes").is_dir());
+        assert_eq!(outcome(&checks, "disk"), Outcome::Skip);
+
+        test.tls = Some((cert.clone(), cert));
+        assert_eq!(outcome(&test.local().await, "tls"), Outcome::Pass);
+        assert!(clock("2001-01-01T00:00:00Z".parse().unwrap()).is_err());
+
+        let error = enforce(SelfTestPolicy::Fail, "local", &checks).unwrap_err();
+        assert!(error.to_string().contains(": tls ("), "{}", error);
+        enforce(SelfTestPolicy::Warn, "local", &checks).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checks_the_database_and_dependencies() {
+        let Some(db) = TestDatabase::start().await else {
+            return;
+        };
+        let config = crate::config::Config::from_vars(|key| match key {
+            "DATABASE_URL" => Some(db.url.clone()),
+            "HEALTH_DEPENDENCIES" => Some("mail=tcp://127.0.0.1:1".into()),
+            _ => None,
+        })
+        .unwrap();
+        let database = Database::from_pool(db.pool.clone());
+        let checks = SelfTest::new(&config).connected(Some(&database)).await;
+        assert_eq!(outcome(&checks, "database"), Outcome::Pass);
+        assert_eq!(outcome(&checks, "clock_skew"), Outcome::Pass);
+        assert_eq!(outcome(&checks, "dependencies"), Outcome::Fail);
+
+        let checks = SelfTest::new(&config).connected(None).await;
+        assert_eq!(outcome(&checks, "database"), Outcome::Fail);
+        assert_eq!(outcome(&checks, "clock_skew"), Outcome::Skip);
+    }
+}
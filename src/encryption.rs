@@ -0,0 +1,383 @@
+//! Field-level encryption of sensitive columns.
+//!
+//! Model fields typed [`Encrypted`] are encrypted with AES-256-GCM when
+//! bound to a query and decrypted when decoded from a row, so they are
+//! only ever plaintext inside the process:
+//!
+//! ```ignore
+//! #[derive(sqlx::FromRow)]
+//! struct Integration {
+//!     name: String,
+//!     api_token: Encrypted,
+//! }
+//! ```
+//!
+//! Keys come from `ENCRYPTION_KEYS` (or the file named by
+//! `ENCRYPTION_KEYS_FILE`, e.g. mounted by a KMS or secrets operator) as
+//! `id:base64` pairs. The first key encrypts; the others only decrypt, so a
+//! new key can be put first and old values re-encrypted with
+//! `rust-selfhost-server encryption rotate`, which rewrites every column
+//! modules declare in [`RouteModule::encrypted_columns`].
+//!
+//! Encrypted values are stored as text, `enc:v1:<key id>:<base64 nonce and
+//! ciphertext>`. Values without that prefix are read as plaintext, and
+//! without keys values are written as plaintext, so encryption can be
+//! enabled on an existing database and the rotate command run once.
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Serialize, Serializer};
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef};
+use sqlx::{Decode, Encode, PgPool, Postgres, Type};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use crate::db::validate_identifier;
+use crate::module::RouteModule;
+
+/// Prefix of encrypted values
+pub const PREFIX: &str = "enc:v1:";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// A named AES-256-GCM key
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptionKey {
+    /// Key from 32 bytes of key material
+    pub fn new(id: &str, key: &[u8]) -> Result<Self> {
+        anyhow::ensure!(
+            !id.is_empty()
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "key id '{}' must be letters, digits, '-' or '_'",
+            id
+        );
+        anyhow::ensure!(
+            key.len() == KEY_LEN,
+            "key '{}' must be {} bytes, got {}",
+            id,
+            KEY_LEN,
+            key.len()
+        );
+        Ok(EncryptionKey {
+            id: id.to_string(),
+            cipher: Aes256Gcm::new_from_slice(key)?,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Fresh base64 key material for `ENCRYPTION_KEYS`
+pub fn generate_key() -> String {
+    STANDARD.encode(Aes256Gcm::generate_key(&mut rand_core::OsRng))
+}
+
+/// Parse `id:base64` pairs separated by commas or newlines
+pub fn parse_keys(input: &str) -> Result<Vec<EncryptionKey>> {
+    let mut keys: Vec<EncryptionKey> = Vec::new();
+    for entry in input
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+    {
+        let (id, material) = entry
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expected id:base64key, got an entry without ':'"))?;
+        let material = STANDARD
+            .decode(material.trim())
+            .with_context(|| format!("key '{}' is not valid base64", id.trim()))?;
+        let key = EncryptionKey::new(id.trim(), &material)?;
+        if keys.iter().any(|k| k.id == key.id) {
+            anyhow::bail!("key '{}' is listed more than once", key.id);
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// Keys used to encrypt and decrypt fields; the first one encrypts
+#[derive(Debug, Clone, Default)]
+pub struct KeyRing {
+    keys: Arc<[EncryptionKey]>,
+}
+
+impl KeyRing {
+    pub fn new(keys: Vec<EncryptionKey>) -> Self {
+        KeyRing { keys: keys.into() }
+    }
+
+    /// The key new values are encrypted with
+    pub fn primary(&self) -> Option<&EncryptionKey> {
+        self.keys.first()
+    }
+
+    /// Encrypt a value with the primary key, or keep it as is without keys
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let Some(key) = self.primary() else {
+            return Ok(plaintext.to_string());
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut rand_core::OsRng);
+        let ciphertext = key
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+        let mut payload = nonce.to_vec();
+        payload.extend(ciphertext);
+        Ok(format!("{}{}:{}", PREFIX, key.id, STANDARD.encode(payload)))
+    }
+
+    /// Decrypt a stored value; values without the prefix are plaintext
+    pub fn decrypt(&self, stored: &str) -> Result<String> {
+        let Some(rest) = stored.strip_prefix(PREFIX) else {
+            return Ok(stored.to_string());
+        };
+        let (id, payload) = rest.split_once(':').context("malformed encrypted value")?;
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.id == id)
+            .with_context(|| format!("unknown encryption key '{}'", id))?;
+        let payload = STANDARD
+            .decode(payload)
+            .context("malformed encrypted value")?;
+        anyhow::ensure!(payload.len() > NONCE_LEN, "malformed encrypted value");
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let plaintext = key
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("failed to decrypt value with key '{}'", id))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    /// Whether a stored value is already encrypted with the primary key
+    pub fn is_current(&self, stored: &str) -> bool {
+        match self.primary() {
+            Some(key) => stored
+                .strip_prefix(PREFIX)
+                .and_then(|rest| rest.split_once(':'))
+                .is_some_and(|(id, _)| id == key.id),
+            None => !stored.starts_with(PREFIX),
+        }
+    }
+}
+
+static KEY_RING: RwLock<Option<KeyRing>> = RwLock::new(None);
+
+/// Use `keys` for every [`Encrypted`] field from now on
+pub fn install(keys: KeyRing) {
+    *KEY_RING.write().expect("key ring poisoned") = Some(keys);
+}
+
+/// The installed key ring
+pub fn key_ring() -> KeyRing {
+    KEY_RING
+        .read()
+        .expect("key ring poisoned")
+        .clone()
+        .unwrap_or_default()
+}
+
+/// A text column encrypted at rest with the installed [`KeyRing`]
+///
+/// Serializes as its plaintext; never put one in a response unless the
+/// client may see it.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Encrypted(pub String);
+
+impl Encrypted {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl fmt::Debug for Encrypted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Encrypted(***)")
+    }
+}
+
+impl From<String> for Encrypted {
+    fn from(value: String) -> Self {
+        Encrypted(value)
+    }
+}
+
+impl Serialize for Encrypted {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl Type<Postgres> for Encrypted {
+    fn type_info() -> PgTypeInfo {
+        <String as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <String as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, Postgres> for Encrypted {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        let stored = key_ring().encrypt(&self.0)?;
+        <String as Encode<Postgres>>::encode(stored, buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for Encrypted {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let stored = <&str as Decode<Postgres>>::decode(value)?;
+        Ok(Encrypted(key_ring().decrypt(stored)?))
+    }
+}
+
+/// A column holding [`Encrypted`] values, declared by its module
+#[derive(Debug, Clone, Copy)]
+pub struct EncryptedColumn {
+    pub table: &'static str,
+    pub column: &'static str,
+    /// Column identifying rows, e.g. the primary key
+    pub key: &'static str,
+}
+
+/// Outcome of re-encrypting one column
+#[derive(Debug, Clone, Serialize)]
+pub struct RotationReport {
+    pub table: &'static str,
+    pub column: &'static str,
+    pub rows: u64,
+    pub dry_run: bool,
+}
+
+/// Re-encrypt every declared column's values not under the primary key
+pub async fn rotate(
+    pool: &PgPool,
+    modules: &[Arc<dyn RouteModule>],
+    dry_run: bool,
+) -> Result<Vec<RotationReport>> {
+    let keys = key_ring();
+    let primary = keys
+        .primary()
+        .context("ENCRYPTION_KEYS is not set; there is no key to encrypt with")?;
+    let current = format!("{}{}:%", PREFIX, primary.id());
+
+    let mut reports = Vec::new();
+    for column in modules.iter().flat_map(|m| m.encrypted_columns()) {
+        for identifier in [column.table, column.column, column.key] {
+            validate_identifier(identifier)?;
+        }
+        let mut tx = pool.begin().await?;
+        let rows: Vec<(String, String)> = sqlx::query_as(&format!(
+            r#"SELECT "{key}"::text, "{column}" FROM "{table}"
+               WHERE "{column}" IS NOT NULL AND "{column}" NOT LIKE $1
+               FOR UPDATE"#,
+            key = column.key,
+            column = column.column,
+            table = column.table,
+        ))
+        .bind(&current)
+        .fetch_all(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to read {}.{}", column.table, column.column))?;
+
+        if !dry_run {
+            for (id, stored) in &rows {
+                let plaintext = keys
+                    .decrypt(stored)
+                    .with_context(|| format!("{}.{} row {}", column.table, column.column, id))?;
+                sqlx::query(&format!(
+                    r#"UPDATE "{table}" SET "{column}" = $1 WHERE "{key}"::text = $2"#,
+                    key = column.key,
+                    column = column.column,
+                    table = column.table,
+                ))
+                .bind(keys.encrypt(&plaintext)?)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        reports.push(RotationReport {
+            table: column.table,
+            column: column.column,
+            rows: rows.len() as u64,
+            dry_run,
+        });
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str) -> EncryptionKey {
+        let material = STANDARD.decode(generate_key()).unwrap();
+        EncryptionKey::new(id, &material).unwrap()
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let input = format!("new:{}, old:{}", generate_key(), generate_key());
+        let keys = parse_keys(&input).unwrap();
+        assert_eq!(
+            keys.iter().map(EncryptionKey::id).collect::<Vec<_>>(),
+            ["new", "old"]
+        );
+        assert!(parse_keys("nocolon").is_err());
+        assert!(parse_keys("short:AAAA").is_err());
+        assert!(parse_keys(&format!("a:{0},a:{0}", generate_key())).is_err());
+        assert!(parse_keys("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_and_rotate_keys() {
+        let old = KeyRing::new(vec![key("old")]);
+        let stored = old.encrypt("s3cret").unwrap();
+        assert!(stored.starts_with("enc:v1:old:"));
+        assert_ne!(stored, old.encrypt("s3cret").unwrap());
+        assert_eq!(old.decrypt(&stored).unwrap(), "s3cret");
+        assert_eq!(old.decrypt("legacy plaintext").unwrap(), "legacy plaintext");
+
+        let rotated = KeyRing::new(vec![key("new"), old.primary().unwrap().clone()]);
+        assert_eq!(rotated.decrypt(&stored).unwrap(), "s3cret");
+        assert!(!rotated.is_current(&stored));
+        assert!(rotated.is_current(&rotated.encrypt("s3cret").unwrap()));
+
+        let (head, payload) = stored.rsplit_once(':').unwrap();
+        let mut payload = STANDARD.decode(payload).unwrap();
+        payload[NONCE_LEN] ^= 1;
+        let tampered = format!("{}:{}", head, STANDARD.encode(payload));
+        assert!(old.decrypt(&tampered).is_err());
+        assert!(KeyRing::default().decrypt(&stored).is_err());
+        assert_eq!(KeyRing::default().encrypt("plain").unwrap(), "plain");
+    }
+}
@@ -0,0 +1,87 @@
+//! API error type.
+//!
+//! Handlers returning JSON use `ApiError` so failures are rendered as a
+//! consistent `{"error": "..."}` body with a matching status code. Internal
+//! errors are logged and replaced with a generic message; other messages
+//! have secrets masked by [`redact`](crate::redact).
+
+use axum::{
+    http::{header::WWW_AUTHENTICATE, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::json;
+
+/// Error returned from API handlers
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Validation(String),
+    #[error("{0}")]
+    BadGateway(String),
+    #[error("{0}")]
+    GatewayTimeout(String),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl ApiError {
+    /// HTTP status code for this error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            ApiError::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let connection_failure = matches!(
+            &self,
+            ApiError::Internal(e) if crate::failover::caused_by_connection_failure(e)
+        );
+        let message = match &self {
+            ApiError::Internal(e) => {
+                tracing::error!("Internal error: {:#}", e);
+                crate::t!("internal-error")
+            }
+            other => crate::redact::redact(&other.to_string()).into_owned(),
+        };
+        let mut response = (status, Json(json!({ "error": message }))).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        if connection_failure {
+            response
+                .extensions_mut()
+                .insert(crate::failover::ConnectionFailure);
+        }
+        response
+    }
+}
+
+/// Result type for API handlers
+pub type ApiResult<T> = Result<T, ApiError>;
@@ -0,0 +1,635 @@
+//! Calendar events with recurrence and an iCalendar feed.
+//!
+//! Events live under `/api/v1/events`. An event has a start and end, an
+//! IANA `timezone` (default `UTC`) and optionally an `rrule`, a recurrence
+//! rule such as `FREQ=WEEKLY;BYDAY=MO,WE;COUNT=10` (see [`recurrence`] for
+//! the supported subset). `all_day` events span whole days in their
+//! timezone. `GET /api/v1/events/occurrences?from=..&to=..` expands the
+//! series into the occurrences overlapping a window of up to a year.
+//!
+//! With `CALENDAR_FEED_TOKEN` set, every event is published as an
+//! iCalendar feed at `/feeds/calendar.ics?token=..` for calendar clients to
+//! subscribe to; clients that can send headers may use a bearer token
+//! instead. Each change bumps an event's `sequence`, so clients pick it up.
+
+pub mod ics;
+pub mod recurrence;
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::auth;
+use crate::crypto::constant_time_eq;
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::time::{self, Timestamp};
+use crate::{t, AppState};
+use recurrence::Rule;
+
+/// Longest title, in characters
+const MAX_TITLE_LEN: usize = 256;
+
+/// Longest description or location, in bytes
+const MAX_TEXT_BYTES: usize = 64 * 1024;
+
+/// Longest window occurrences are listed for
+const MAX_WINDOW_DAYS: i64 = 366;
+
+/// Most occurrences listed at once
+const MAX_OCCURRENCES: usize = 1000;
+
+const COLUMNS: &str = "id, title, description, location, starts_at, ends_at, all_day, timezone, \
+     rrule, sequence, created_at, updated_at";
+
+/// A stored event
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Event {
+    pub id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub location: String,
+    /// Start of the first occurrence
+    #[serde(with = "crate::time::rfc3339")]
+    pub starts_at: DateTime<Utc>,
+    /// End of the first occurrence
+    #[serde(with = "crate::time::rfc3339")]
+    pub ends_at: DateTime<Utc>,
+    pub all_day: bool,
+    /// IANA timezone occurrences repeat in
+    pub timezone: String,
+    /// Recurrence rule, if the event repeats
+    pub rrule: Option<String>,
+    /// Revision number, bumped on every change
+    pub sequence: i32,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Event {
+    /// The event's timezone, UTC if it is no longer known
+    pub fn tz(&self) -> Tz {
+        time::parse_timezone(&self.timezone).unwrap_or(Tz::UTC)
+    }
+
+    /// Start times of occurrences overlapping `from..to`, at most `limit`
+    pub fn occurrences(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: usize,
+    ) -> Vec<DateTime<Utc>> {
+        let duration = self.ends_at - self.starts_at;
+        match self
+            .rrule
+            .as_deref()
+            .and_then(|rule| rule.parse::<Rule>().ok())
+        {
+            Some(rule) => rule.occurrences(self.starts_at, duration, self.tz(), from, to, limit),
+            None => {
+                let overlaps = self.starts_at < to
+                    && (self.starts_at + duration > from || self.starts_at >= from);
+                overlaps
+                    .then_some(self.starts_at)
+                    .into_iter()
+                    .take(limit)
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Route module for events
+pub struct EventsModule;
+
+impl RouteModule for EventsModule {
+    fn name(&self) -> &'static str {
+        "events"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/api/v1/events", get(list_events).post(create_event))
+            .route("/api/v1/events/occurrences", get(list_occurrences))
+            .route(
+                "/api/v1/events/:id",
+                get(get_event).patch(update_event).delete(delete_event),
+            )
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_events",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS events (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                title TEXT NOT NULL,
+                description TEXT NOT NULL DEFAULT '',
+                location TEXT NOT NULL DEFAULT '',
+                starts_at TIMESTAMPTZ NOT NULL,
+                ends_at TIMESTAMPTZ NOT NULL,
+                all_day BOOLEAN NOT NULL DEFAULT false,
+                timezone TEXT NOT NULL DEFAULT 'UTC',
+                rrule TEXT,
+                sequence INTEGER NOT NULL DEFAULT 0,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE INDEX IF NOT EXISTS events_starts_at ON events (starts_at)",
+        }]
+    }
+}
+
+/// An event about to be created
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewEvent {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub location: String,
+    pub starts_at: Timestamp,
+    /// Defaults to an hour after the start, or a day for all-day events
+    #[serde(default)]
+    pub ends_at: Option<Timestamp>,
+    #[serde(default)]
+    pub all_day: bool,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub rrule: Option<String>,
+}
+
+/// Changes to an event; fields left out are kept, and an empty `rrule`
+/// stops the event repeating
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EventUpdate {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub starts_at: Option<Timestamp>,
+    #[serde(default)]
+    pub ends_at: Option<Timestamp>,
+    #[serde(default)]
+    pub all_day: Option<bool>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub rrule: Option<String>,
+}
+
+/// Check an event's fields, normalizing its title and rule
+fn check(event: &mut Event) -> ApiResult<()> {
+    event.title = event.title.trim().to_string();
+    if event.title.is_empty() || event.title.chars().count() > MAX_TITLE_LEN {
+        return Err(ApiError::Validation(t!("event-title", max = MAX_TITLE_LEN)));
+    }
+    if event.description.len() > MAX_TEXT_BYTES || event.location.len() > MAX_TEXT_BYTES {
+        return Err(ApiError::Validation(t!(
+            "event-too-large",
+            max = MAX_TEXT_BYTES
+        )));
+    }
+    if event.ends_at < event.starts_at {
+        return Err(ApiError::Validation(t!("event-times")));
+    }
+    time::parse_timezone(&event.timezone)
+        .map_err(|reason| ApiError::Validation(t!("event-timezone", reason = reason)))?;
+    event.rrule = match event.rrule.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(rule) => Some(
+            rule.parse::<Rule>()
+                .map_err(|reason| ApiError::Validation(t!("event-rrule", reason = reason)))?
+                .to_string(),
+        ),
+    };
+    Ok(())
+}
+
+/// Check and store an event
+pub async fn create(pool: &PgPool, new: &NewEvent) -> ApiResult<Event> {
+    let starts_at = new.starts_at.0;
+    let default_length = if new.all_day {
+        Duration::days(1)
+    } else {
+        Duration::hours(1)
+    };
+    let mut event = Event {
+        id: Uuid::nil(),
+        title: new.title.clone(),
+        description: new.description.clone(),
+        location: new.location.clone(),
+        starts_at,
+        ends_at: new.ends_at.map_or(starts_at + default_length, |end| end.0),
+        all_day: new.all_day,
+        timezone: new.timezone.clone().unwrap_or_else(|| "UTC".to_string()),
+        rrule: new.rrule.clone(),
+        sequence: 0,
+        created_at: starts_at,
+        updated_at: starts_at,
+    };
+    check(&mut event)?;
+    let event = sqlx::query_as::<_, Event>(&format!(
+        "INSERT INTO events (title, description, location, starts_at, ends_at, all_day, timezone, rrule)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+         RETURNING {COLUMNS}"
+    ))
+    .bind(&event.title)
+    .bind(&event.description)
+    .bind(&event.location)
+    .bind(event.starts_at)
+    .bind(event.ends_at)
+    .bind(event.all_day)
+    .bind(&event.timezone)
+    .bind(&event.rrule)
+    .fetch_one(pool)
+    .await?;
+    Ok(event)
+}
+
+/// Look up an event
+pub async fn find(pool: &PgPool, id: Uuid) -> ApiResult<Event> {
+    sqlx::query_as::<_, Event>(&format!("SELECT {COLUMNS} FROM events WHERE id = $1"))
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(t!("event-not-found")))
+}
+
+/// Apply changes to an event, bumping its sequence
+pub async fn update(pool: &PgPool, id: Uuid, changes: &EventUpdate) -> ApiResult<Event> {
+    let mut event = find(pool, id).await?;
+    if let Some(title) = &changes.title {
+        event.title = title.clone();
+    }
+    if let Some(description) = &changes.description {
+        event.description = description.clone();
+    }
+    if let Some(location) = &changes.location {
+        event.location = location.clone();
+    }
+    if let Some(starts_at) = changes.starts_at {
+        // Moving the start keeps the length unless the end moves too
+        event.ends_at = starts_at.0 + (event.ends_at - event.starts_at);
+        event.starts_at = starts_at.0;
+    }
+    if let Some(ends_at) = changes.ends_at {
+        event.ends_at = ends_at.0;
+    }
+    if let Some(all_day) = changes.all_day {
+        event.all_day = all_day;
+    }
+    if let Some(timezone) = &changes.timezone {
+        event.timezone = timezone.clone();
+    }
+    if let Some(rrule) = &changes.rrule {
+        event.rrule = Some(rrule.clone());
+    }
+    check(&mut event)?;
+    sqlx::query_as::<_, Event>(&format!(
+        "UPDATE events SET title = $2, description = $3, location = $4, starts_at = $5,
+            ends_at = $6, all_day = $7, timezone = $8, rrule = $9,
+            sequence = sequence + 1, updated_at = now()
+         WHERE id = $1
+         RETURNING {COLUMNS}"
+    ))
+    .bind(id)
+    .bind(&event.title)
+    .bind(&event.description)
+    .bind(&event.location)
+    .bind(event.starts_at)
+    .bind(event.ends_at)
+    .bind(event.all_day)
+    .bind(&event.timezone)
+    .bind(&event.rrule)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(t!("event-not-found")))
+}
+
+/// Every event, by start
+pub async fn all(pool: &PgPool) -> ApiResult<Vec<Event>> {
+    Ok(sqlx::query_as::<_, Event>(&format!(
+        "SELECT {COLUMNS} FROM events ORDER BY starts_at, id"
+    ))
+    .fetch_all(pool)
+    .await?)
+}
+
+/// Paging of an event listing
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EventQuery {
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+fn default_limit() -> i64 {
+    50
+}
+
+/// `GET /api/v1/events` - events by start
+pub async fn list_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventQuery>,
+) -> ApiResult<Json<Vec<Event>>> {
+    let events = sqlx::query_as::<_, Event>(&format!(
+        "SELECT {COLUMNS} FROM events ORDER BY starts_at, id LIMIT $1 OFFSET $2"
+    ))
+    .bind(query.limit.clamp(1, 500))
+    .bind(query.offset.max(0))
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(events))
+}
+
+/// `POST /api/v1/events` - create an event
+pub async fn create_event(
+    State(state): State<AppState>,
+    Json(body): Json<NewEvent>,
+) -> ApiResult<(StatusCode, Json<Event>)> {
+    let event = create(state.db.pool(), &body).await?;
+    Ok((StatusCode::CREATED, Json(event)))
+}
+
+/// `GET /api/v1/events/:id` - one event
+pub async fn get_event(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<Event>> {
+    Ok(Json(find(state.db.pool(), id).await?))
+}
+
+/// `PATCH /api/v1/events/:id` - change an event
+pub async fn update_event(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(body): Json<EventUpdate>,
+) -> ApiResult<Json<Event>> {
+    Ok(Json(update(state.db.pool(), id, &body).await?))
+}
+
+/// `DELETE /api/v1/events/:id` - remove an event and its series
+pub async fn delete_event(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<StatusCode> {
+    let result = sqlx::query("DELETE FROM events WHERE id = $1")
+        .bind(id)
+        .execute(state.db.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(t!("event-not-found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Window of an occurrence listing
+#[derive(Debug, Deserialize)]
+pub struct OccurrenceQuery {
+    pub from: Timestamp,
+    pub to: Timestamp,
+}
+
+/// One occurrence of an event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Occurrence {
+    pub event_id: Uuid,
+    pub title: String,
+    #[serde(with = "crate::time::rfc3339")]
+    pub starts_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub ends_at: DateTime<Utc>,
+    pub all_day: bool,
+}
+
+/// Occurrences of every event overlapping `from..to`, by start
+pub async fn occurrences(
+    pool: &PgPool,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> ApiResult<Vec<Occurrence>> {
+    if to <= from || to - from > Duration::days(MAX_WINDOW_DAYS) {
+        return Err(ApiError::Validation(t!(
+            "event-window",
+            max = MAX_WINDOW_DAYS
+        )));
+    }
+    let events = sqlx::query_as::<_, Event>(&format!(
+        "SELECT {COLUMNS} FROM events
+         WHERE starts_at < $2 AND (rrule IS NOT NULL OR ends_at >= $1)"
+    ))
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+    let mut out: Vec<Occurrence> = events
+        .iter()
+        .flat_map(|event| {
+            let duration = event.ends_at - event.starts_at;
+            event
+                .occurrences(from, to, MAX_OCCURRENCES)
+                .into_iter()
+                .map(move |starts_at| Occurrence {
+                    event_id: event.id,
+                    title: event.title.clone(),
+                    starts_at,
+                    ends_at: starts_at + duration,
+                    all_day: event.all_day,
+                })
+        })
+        .collect();
+    out.sort_by_key(|occurrence| (occurrence.starts_at, occurrence.event_id));
+    out.truncate(MAX_OCCURRENCES);
+    Ok(out)
+}
+
+/// `GET /api/v1/events/occurrences?from=..&to=..` - the expanded schedule
+pub async fn list_occurrences(
+    State(state): State<AppState>,
+    Query(query): Query<OccurrenceQuery>,
+) -> ApiResult<Json<Vec<Occurrence>>> {
+    Ok(Json(
+        occurrences(state.db.pool(), query.from.0, query.to.0).await?,
+    ))
+}
+
+/// The published calendar feed
+#[derive(Clone)]
+pub struct CalendarFeed {
+    /// Secret guarding it, from `CALENDAR_FEED_TOKEN`
+    pub token: String,
+    /// Calendar name shown by clients
+    pub name: String,
+}
+
+/// Route module serving the iCalendar feed, mounted with `CALENDAR_FEED_TOKEN`
+pub struct CalendarFeedModule;
+
+impl RouteModule for CalendarFeedModule {
+    fn name(&self) -> &'static str {
+        "calendar_feed"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/feeds/calendar.ics", get(calendar_feed))
+    }
+}
+
+/// Feed credentials passed in the query string
+#[derive(Debug, Deserialize)]
+pub struct FeedQuery {
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// `GET /feeds/calendar.ics` - every event as an iCalendar feed
+pub async fn calendar_feed(
+    State(state): State<AppState>,
+    Ext(feed): Ext<CalendarFeed>,
+    Query(query): Query<FeedQuery>,
+    request: Request,
+) -> ApiResult<Response> {
+    let given = query
+        .token
+        .as_deref()
+        .or_else(|| auth::bearer_token(&request));
+    if !given.is_some_and(|given| constant_time_eq(given.as_bytes(), feed.token.as_bytes())) {
+        return Err(ApiError::Unauthorized(t!("calendar-feed-token")));
+    }
+    let events = all(state.db.pool()).await?;
+    Ok((
+        [(CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        ics::calendar(Some(&feed.name), &events),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(input: &str) -> Timestamp {
+        input.parse().unwrap()
+    }
+
+    fn new_event(title: &str, starts_at: &str) -> NewEvent {
+        NewEvent {
+            title: title.into(),
+            description: String::new(),
+            location: String::new(),
+            starts_at: at(starts_at),
+            ends_at: None,
+            all_day: false,
+            timezone: None,
+            rrule: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events_and_occurrences() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+
+        let standup = create(
+            pool,
+            &NewEvent {
+                ends_at: Some(at("2026-10-19T07:15:00Z")),
+                timezone: Some("Europe/Berlin".into()),
+                rrule: Some("freq=daily;byday=mo,tu,we,th,fr".into()),
+                ..new_event(" Stand-up ", "2026-10-19T07:00:00Z")
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(standup.title, "Stand-up");
+        assert_eq!(
+            standup.rrule.as_deref(),
+            Some("FREQ=DAILY;BYDAY=MO,TU,WE,TH,FR")
+        );
+        let launch = create(
+            pool,
+            &NewEvent {
+                all_day: true,
+                ..new_event("Launch", "2026-10-21T00:00:00Z")
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(launch.ends_at, at("2026-10-22T00:00:00Z").0);
+
+        let schedule = occurrences(
+            pool,
+            at("2026-10-19T00:00:00Z").0,
+            at("2026-10-26T00:00:00Z").0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(schedule.len(), 6);
+        assert_eq!(schedule[2].event_id, launch.id, "sorted by start");
+        assert!(
+            occurrences(
+                pool,
+                at("2026-01-01T00:00:00Z").0,
+                at("2028-01-01T00:00:00Z").0
+            )
+            .await
+            .is_err(),
+            "window over a year"
+        );
+
+        let changes = EventUpdate {
+            starts_at: Some(at("2026-10-19T08:00:00Z")),
+            rrule: Some(String::new()),
+            ..EventUpdate::default()
+        };
+        let moved = update(pool, standup.id, &changes).await.unwrap();
+        assert_eq!(moved.ends_at, at("2026-10-19T08:15:00Z").0);
+        assert_eq!(moved.rrule, None);
+        assert_eq!(moved.sequence, 1);
+
+        let invalid = [
+            EventUpdate {
+                ends_at: Some(at("2026-10-18T00:00:00Z")),
+                ..EventUpdate::default()
+            },
+            EventUpdate {
+                timezone: Some("Mars/Olympus".into()),
+                ..EventUpdate::default()
+            },
+            EventUpdate {
+                rrule: Some("FREQ=MONTHLY;BYDAY=1MO".into()),
+                ..EventUpdate::default()
+            },
+        ];
+        for changes in invalid {
+            assert!(matches!(
+                update(pool, standup.id, &changes).await,
+                Err(ApiError::Validation(_))
+            ));
+        }
+    }
+}
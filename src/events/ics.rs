@@ -0,0 +1,167 @@
+//! iCalendar (RFC 5545) rendering of events.
+
+use chrono::{DateTime, Duration, Utc};
+
+use super::Event;
+
+/// Product identifier of generated calendars
+const PRODID: &str = "-//rust-selfhost-server//events//EN";
+
+/// Longest content line, in octets, before it is folded
+const LINE_OCTETS: usize = 75;
+
+/// A `VCALENDAR` holding `events`
+pub fn calendar(name: Option<&str>, events: &[Event]) -> String {
+    let mut out = String::new();
+    line(&mut out, "BEGIN:VCALENDAR");
+    line(&mut out, "VERSION:2.0");
+    line(&mut out, &format!("PRODID:{}", PRODID));
+    line(&mut out, "CALSCALE:GREGORIAN");
+    if let Some(name) = name {
+        line(&mut out, &format!("X-WR-CALNAME:{}", escape(name)));
+    }
+    for event in events {
+        vevent(&mut out, event);
+    }
+    line(&mut out, "END:VCALENDAR");
+    out
+}
+
+/// Append an event as a `VEVENT`
+pub fn vevent(out: &mut String, event: &Event) {
+    line(out, "BEGIN:VEVENT");
+    line(out, &format!("UID:{}", event.id));
+    line(out, &format!("DTSTAMP:{}", utc(&event.updated_at)));
+    line(out, &format!("CREATED:{}", utc(&event.created_at)));
+    line(out, &format!("LAST-MODIFIED:{}", utc(&event.updated_at)));
+    line(out, &format!("SEQUENCE:{}", event.sequence));
+    line(out, &format!("SUMMARY:{}", escape(&event.title)));
+    if !event.description.is_empty() {
+        line(out, &format!("DESCRIPTION:{}", escape(&event.description)));
+    }
+    if !event.location.is_empty() {
+        line(out, &format!("LOCATION:{}", escape(&event.location)));
+    }
+    let timezone = event.tz();
+    if event.all_day {
+        let start = event.starts_at.with_timezone(&timezone).date_naive();
+        let end = event
+            .ends_at
+            .with_timezone(&timezone)
+            .date_naive()
+            .max(start + Duration::days(1));
+        line(
+            out,
+            &format!("DTSTART;VALUE=DATE:{}", start.format("%Y%m%d")),
+        );
+        line(out, &format!("DTEND;VALUE=DATE:{}", end.format("%Y%m%d")));
+    } else if event.timezone == "UTC" {
+        line(out, &format!("DTSTART:{}", utc(&event.starts_at)));
+        line(out, &format!("DTEND:{}", utc(&event.ends_at)));
+    } else {
+        for (name, time) in [("DTSTART", &event.starts_at), ("DTEND", &event.ends_at)] {
+            let local = time.with_timezone(&timezone).format("%Y%m%dT%H%M%S");
+            line(out, &format!("{};TZID={}:{}", name, event.timezone, local));
+        }
+    }
+    if let Some(rrule) = &event.rrule {
+        line(out, &format!("RRULE:{}", rrule));
+    }
+    line(out, "END:VEVENT");
+}
+
+fn utc(time: &DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escape a `TEXT` value
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ';' => out.push_str("\\;"),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Append a content line, folded to [`LINE_OCTETS`] without splitting
+/// characters
+fn line(out: &mut String, content: &str) {
+    let mut width = 0;
+    for c in content.chars() {
+        if width + c.len_utf8() > LINE_OCTETS {
+            out.push_str("\r\n ");
+            // The leading space counts towards the next line
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn event() -> Event {
+        let at = |input| crate::time::parse(input).unwrap();
+        Event {
+            id: Uuid::nil(),
+            title: "Stand-up; daily, short".into(),
+            description: "Agenda:\nblockers".into(),
+            location: String::new(),
+            starts_at: at("2026-10-19T07:00:00Z"),
+            ends_at: at("2026-10-19T07:15:00Z"),
+            all_day: false,
+            timezone: "Europe/Berlin".into(),
+            rrule: Some("FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR".into()),
+            sequence: 2,
+            created_at: at("2026-10-01T00:00:00Z"),
+            updated_at: at("2026-10-02T00:00:00Z"),
+        }
+    }
+
+    #[test]
+    fn test_renders_events() {
+        let ics = calendar(Some("Team"), &[event()]);
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(ics.contains("X-WR-CALNAME:Team\r\n"));
+        assert!(ics.contains("UID:00000000-0000-0000-0000-000000000000\r\n"));
+        assert!(ics.contains("SUMMARY:Stand-up\\; daily\\, short\r\n"));
+        assert!(ics.contains("DESCRIPTION:Agenda:\\nblockers\r\n"));
+        assert!(ics.contains("DTSTART;TZID=Europe/Berlin:20261019T090000\r\n"));
+        assert!(ics.contains("DTEND;TZID=Europe/Berlin:20261019T091500\r\n"));
+        assert!(ics.contains("RRULE:FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR\r\n"));
+        assert!(ics.contains("SEQUENCE:2\r\n"));
+        assert!(ics.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+
+        let mut all_day = event();
+        all_day.all_day = true;
+        all_day.rrule = None;
+        let ics = calendar(None, &[all_day]);
+        assert!(ics.contains("DTSTART;VALUE=DATE:20261019\r\nDTEND;VALUE=DATE:20261020\r\n"));
+        assert!(!ics.contains("RRULE"));
+    }
+
+    #[test]
+    fn test_folds_long_lines() {
+        let mut out = String::new();
+        line(&mut out, &format!("SUMMARY:{}", "é".repeat(60)));
+        let lines: Vec<&str> = out.trim_end().split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.len() <= LINE_OCTETS));
+        assert!(lines[1].starts_with(' '));
+        assert_eq!(
+            out.replace("\r\n ", ""),
+            format!("SUMMARY:{}\r\n", "é".repeat(60))
+        );
+    }
+}
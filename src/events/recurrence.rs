@@ -0,0 +1,373 @@
+//! Recurrence rules, a subset of RFC 5545 `RRULE`.
+//!
+//! Supported are `FREQ` (`DAILY`, `WEEKLY`, `MONTHLY`, `YEARLY`),
+//! `INTERVAL`, `COUNT`, `UNTIL` and `BYDAY` as plain weekdays with daily or
+//! weekly rules, e.g. `FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE;COUNT=10`. Other
+//! parts, and ordinal weekdays such as `BYDAY=2TU`, are refused rather than
+//! ignored, so a series never silently differs from what clients show.
+//!
+//! Occurrences repeat at the same wall-clock time in the event's timezone,
+//! so a weekly 09:00 meeting stays at 09:00 across daylight saving changes.
+//! Monthly and yearly dates that do not exist in a period (the 31st, 29
+//! February) are skipped, as RFC 5545 requires.
+
+use chrono::{
+    DateTime, Datelike, Duration, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+    Weekday,
+};
+use chrono_tz::Tz;
+use std::fmt;
+use std::str::FromStr;
+
+/// Most periods stepped through, bounding expansion of far-off windows
+const MAX_PERIODS: u32 = 100_000;
+
+/// How often a rule repeats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl Frequency {
+    fn as_str(self) -> &'static str {
+        match self {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+            Frequency::Yearly => "YEARLY",
+        }
+    }
+}
+
+/// A parsed recurrence rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub frequency: Frequency,
+    /// Periods between occurrences, at least 1
+    pub interval: u32,
+    /// Occurrences in the series, the first included
+    pub count: Option<u32>,
+    /// Last moment an occurrence may start
+    pub until: Option<DateTime<Utc>>,
+    /// Weekdays occurrences fall on, in week order
+    pub by_day: Vec<Weekday>,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        let input = input.strip_prefix("RRULE:").unwrap_or(input);
+        let mut frequency = None;
+        let mut rule = Rule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+        };
+        for part in input.split(';').filter(|part| !part.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected NAME=value, got '{}'", part))?;
+            match name.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    frequency = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        other => return Err(format!("unsupported FREQ '{}'", other)),
+                    })
+                }
+                "INTERVAL" => {
+                    rule.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|interval| *interval > 0)
+                        .ok_or_else(|| format!("invalid INTERVAL '{}'", value))?
+                }
+                "COUNT" => {
+                    rule.count = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|count| *count > 0)
+                            .ok_or_else(|| format!("invalid COUNT '{}'", value))?,
+                    )
+                }
+                "UNTIL" => rule.until = Some(parse_until(value)?),
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        let day = weekday(day)
+                            .ok_or_else(|| format!("unsupported BYDAY value '{}'", day))?;
+                        if !rule.by_day.contains(&day) {
+                            rule.by_day.push(day);
+                        }
+                    }
+                    rule.by_day.sort_by_key(|day| day.num_days_from_monday());
+                }
+                "WKST" if value.eq_ignore_ascii_case("MO") => {}
+                other => return Err(format!("unsupported rule part '{}'", other)),
+            }
+        }
+        rule.frequency = frequency.ok_or("missing FREQ")?;
+        if rule.count.is_some() && rule.until.is_some() {
+            return Err("COUNT and UNTIL cannot both be given".into());
+        }
+        if !rule.by_day.is_empty()
+            && matches!(rule.frequency, Frequency::Monthly | Frequency::Yearly)
+        {
+            return Err("BYDAY is only supported with DAILY and WEEKLY rules".into());
+        }
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FREQ={}", self.frequency.as_str())?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        if let Some(until) = self.until {
+            write!(f, ";UNTIL={}", until.format("%Y%m%dT%H%M%SZ"))?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<&str> = self.by_day.iter().map(|day| weekday_code(*day)).collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse `UNTIL` as a UTC time or a date, which includes its whole day
+fn parse_until(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ") {
+        return Ok(time.and_utc());
+    }
+    NaiveDate::parse_from_str(value, "%Y%m%d")
+        .map(|date| {
+            date.and_time(NaiveTime::MIN).and_utc() + Duration::days(1) - Duration::seconds(1)
+        })
+        .map_err(|_| {
+            format!(
+                "invalid UNTIL '{}' (expected 20241231 or 20241231T235959Z)",
+                value
+            )
+        })
+}
+
+fn weekday(code: &str) -> Option<Weekday> {
+    Some(match code.trim().to_ascii_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+fn weekday_code(day: Weekday) -> &'static str {
+    match day {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+impl Rule {
+    /// Start times of occurrences overlapping `from..to`, at most `limit`
+    ///
+    /// `start` is the first occurrence and `duration` the length of each;
+    /// times are stepped through in `timezone`.
+    pub fn occurrences(
+        &self,
+        start: DateTime<Utc>,
+        duration: Duration,
+        timezone: Tz,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        limit: usize,
+    ) -> Vec<DateTime<Utc>> {
+        let local = start.with_timezone(&timezone).naive_local();
+        let (first_date, time) = (local.date(), local.time());
+        let last_date = to.with_timezone(&timezone).date_naive() + Duration::days(1);
+        let mut out = Vec::new();
+        let mut seen = 0;
+        for period in 0..MAX_PERIODS {
+            let Some((anchor, dates)) = self.period(first_date, period) else {
+                break;
+            };
+            if anchor > last_date {
+                break;
+            }
+            for date in dates.into_iter().filter(|date| *date >= first_date) {
+                let Some(at) = timezone
+                    .from_local_datetime(&date.and_time(time))
+                    .earliest()
+                else {
+                    // Skipped by a daylight saving change
+                    continue;
+                };
+                let at = at.with_timezone(&Utc);
+                seen += 1;
+                if self.count.is_some_and(|count| seen > count)
+                    || self.until.is_some_and(|until| at > until)
+                    || at >= to
+                {
+                    return out;
+                }
+                if at + duration > from || at >= from {
+                    out.push(at);
+                    if out.len() >= limit {
+                        return out;
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// The first day of the `n`th period after the one holding `start`, and
+    /// the dates of that period occurrences may fall on
+    fn period(&self, start: NaiveDate, n: u32) -> Option<(NaiveDate, Vec<NaiveDate>)> {
+        let step = n.checked_mul(self.interval)?;
+        Some(match self.frequency {
+            Frequency::Daily => {
+                let date = start.checked_add_signed(Duration::days(step.into()))?;
+                let dates = if self.by_day.is_empty() || self.by_day.contains(&date.weekday()) {
+                    vec![date]
+                } else {
+                    Vec::new()
+                };
+                (date, dates)
+            }
+            Frequency::Weekly => {
+                let monday = start - Duration::days(start.weekday().num_days_from_monday().into());
+                let monday = monday.checked_add_signed(Duration::weeks(step.into()))?;
+                let days = match self.by_day.as_slice() {
+                    [] => vec![start.weekday()],
+                    days => days.to_vec(),
+                };
+                let dates = days
+                    .iter()
+                    .map(|day| monday + Duration::days(day.num_days_from_monday().into()))
+                    .collect();
+                (monday, dates)
+            }
+            Frequency::Monthly => {
+                let month = start.with_day(1)?.checked_add_months(Months::new(step))?;
+                (month, month.with_day(start.day()).into_iter().collect())
+            }
+            Frequency::Yearly => {
+                let year = start.year().checked_add(i32::try_from(step).ok()?)?;
+                (
+                    NaiveDate::from_ymd_opt(year, 1, 1)?,
+                    NaiveDate::from_ymd_opt(year, start.month(), start.day())
+                        .into_iter()
+                        .collect(),
+                )
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(input: &str) -> DateTime<Utc> {
+        crate::time::parse(input).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        let rule: Rule = "RRULE:FREQ=weekly;INTERVAL=2;BYDAY=WE,MO,WE;UNTIL=20261231"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.frequency, Frequency::Weekly);
+        assert_eq!(rule.by_day, [Weekday::Mon, Weekday::Wed]);
+        assert_eq!(rule.until, Some(at("2026-12-31T23:59:59Z")));
+        assert_eq!(
+            rule.to_string(),
+            "FREQ=WEEKLY;INTERVAL=2;UNTIL=20261231T235959Z;BYDAY=MO,WE"
+        );
+        assert!("INTERVAL=2".parse::<Rule>().is_err());
+        assert!("FREQ=HOURLY".parse::<Rule>().is_err());
+        assert!("FREQ=MONTHLY;BYDAY=2TU".parse::<Rule>().is_err());
+        assert!("FREQ=DAILY;BYSETPOS=1".parse::<Rule>().is_err());
+        assert!("FREQ=DAILY;COUNT=0".parse::<Rule>().is_err());
+        assert!("FREQ=DAILY;COUNT=2;UNTIL=20260101".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_weekly_keeps_local_time_across_dst() {
+        let rule: Rule = "FREQ=WEEKLY;BYDAY=MO,FR;COUNT=4".parse().unwrap();
+        let berlin: Tz = "Europe/Berlin".parse().unwrap();
+        // Friday 23 October 2026, 09:00 in Berlin; DST ends on the 25th
+        let occurrences = rule.occurrences(
+            at("2026-10-23T07:00:00Z"),
+            Duration::hours(1),
+            berlin,
+            at("2026-01-01T00:00:00Z"),
+            at("2027-01-01T00:00:00Z"),
+            100,
+        );
+        assert_eq!(
+            occurrences,
+            [
+                at("2026-10-23T07:00:00Z"),
+                at("2026-10-26T08:00:00Z"),
+                at("2026-10-30T08:00:00Z"),
+                at("2026-11-02T08:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_monthly_skips_missing_days_and_windows() {
+        let rule: Rule = "FREQ=MONTHLY".parse().unwrap();
+        let occurrences = rule.occurrences(
+            at("2026-01-31T12:00:00Z"),
+            Duration::hours(2),
+            Tz::UTC,
+            at("2026-03-31T13:00:00Z"),
+            at("2026-08-01T00:00:00Z"),
+            2,
+        );
+        // Still running at the window's start, then May; the limit stops it
+        assert_eq!(
+            occurrences,
+            [at("2026-03-31T12:00:00Z"), at("2026-05-31T12:00:00Z")]
+        );
+
+        let rule: Rule = "FREQ=YEARLY;INTERVAL=1".parse().unwrap();
+        let occurrences = rule.occurrences(
+            at("2024-02-29T00:00:00Z"),
+            Duration::days(1),
+            Tz::UTC,
+            at("2024-01-01T00:00:00Z"),
+            at("2030-01-01T00:00:00Z"),
+            100,
+        );
+        assert_eq!(
+            occurrences,
+            [at("2024-02-29T00:00:00Z"), at("2028-02-29T00:00:00Z")]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! A/B experiment bucketing.
+//!
+//! With `EXPERIMENTS=true`, experiments are defined in the settings table
+//! under `experiments.<name>` (managed through `/admin/settings`), e.g.
+//!
+//! ```json
+//! {"salt": "2026-10", "variants": [{"name": "control", "weight": 50}, {"name": "blue", "weight": 50}]}
+//! ```
+//!
+//! Every request is assigned a variant of each enabled experiment by hashing
+//! the experiment's salt (its name by default) with the logged-in user's id,
+//! so a user sees the same variants on every replica and every visit.
+//! Anonymous visitors get a random id in the `EXPERIMENTS_COOKIE` cookie
+//! instead. Handlers read assignments with the [`Assignments`] extractor and
+//! frontends from the `X-Experiments` response header, e.g.
+//! `X-Experiments: checkout=blue, search=control`.
+//!
+//! Definitions are reloaded every `EXPERIMENTS_REFRESH`; changing a salt or
+//! the weights reshuffles assignments.
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header::SET_COOKIE, request::Parts, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::sessions::{self, SessionHandle};
+use crate::settings;
+
+/// Settings key prefix of experiment definitions
+pub const KEY_PREFIX: &str = "experiments.";
+/// Response header listing the request's assignments
+pub const EXPERIMENTS_HEADER: HeaderName = HeaderName::from_static("x-experiments");
+/// How long the visitor cookie lasts
+const VISITOR_COOKIE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// Experiment settings, from configuration
+#[derive(Debug, Clone)]
+pub struct ExperimentsConfig {
+    /// Cookie holding anonymous visitors' ids
+    pub cookie: String,
+    /// How often definitions are reloaded
+    pub refresh: Duration,
+}
+
+/// A variant and its share of traffic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WeightedVariant {
+    pub name: String,
+    pub weight: u32,
+}
+
+/// An experiment as stored in the settings table
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Experiment {
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Mixed into the hash; defaults to the experiment name
+    #[serde(default)]
+    pub salt: Option<String>,
+    pub variants: Vec<WeightedVariant>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Whether a name fits in the `X-Experiments` header unescaped
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+impl Experiment {
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(valid_name(&self.name), "invalid experiment name");
+        anyhow::ensure!(!self.variants.is_empty(), "no variants");
+        for variant in &self.variants {
+            anyhow::ensure!(
+                valid_name(&variant.name),
+                "invalid variant name '{}'",
+                variant.name
+            );
+        }
+        anyhow::ensure!(
+            self.variants.iter().any(|variant| variant.weight > 0),
+            "all weights are 0"
+        );
+        Ok(())
+    }
+
+    /// The variant `subject` is assigned to
+    pub fn assign(&self, subject: &str) -> &str {
+        let salt = self.salt.as_deref().unwrap_or(&self.name);
+        let digest = Sha256::new()
+            .chain_update(salt)
+            .chain_update(":")
+            .chain_update(subject)
+            .finalize();
+        let hash = u64::from_be_bytes(digest[..8].try_into().expect("digests are 32 bytes"));
+        let total: u64 = self.variants.iter().map(|v| u64::from(v.weight)).sum();
+        let mut point = hash % total;
+        for variant in &self.variants {
+            let weight = u64::from(variant.weight);
+            if point < weight {
+                return &variant.name;
+            }
+            point -= weight;
+        }
+        unreachable!("the point is below the total weight")
+    }
+}
+
+/// The request's experiment variants, by experiment name
+///
+/// Empty without `EXPERIMENTS=true`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Assignments(Vec<(String, String)>);
+
+impl Assignments {
+    /// The variant of an experiment, if it is running
+    pub fn variant(&self, experiment: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == experiment)
+            .map(|(_, variant)| variant.as_str())
+    }
+
+    /// Whether the request is in `variant` of `experiment`
+    pub fn is(&self, experiment: &str, variant: &str) -> bool {
+        self.variant(experiment) == Some(variant)
+    }
+
+    fn header(&self) -> Option<HeaderValue> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let listed = self
+            .0
+            .iter()
+            .map(|(name, variant)| format!("{}={}", name, variant))
+            .collect::<Vec<_>>()
+            .join(", ");
+        HeaderValue::from_str(&listed).ok()
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Assignments {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Assignments>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Loaded experiment definitions and the middleware assigning variants
+#[derive(Clone)]
+pub struct Experiments {
+    config: Arc<ExperimentsConfig>,
+    experiments: Arc<RwLock<Arc<Vec<Experiment>>>>,
+}
+
+impl Experiments {
+    pub fn new(config: ExperimentsConfig) -> Self {
+        Experiments {
+            config: Arc::new(config),
+            experiments: Arc::default(),
+        }
+    }
+
+    /// Replace the running experiments
+    pub fn set(&self, mut experiments: Vec<Experiment>) {
+        experiments.retain(|experiment| experiment.enabled);
+        experiments.sort_by(|a, b| a.name.cmp(&b.name));
+        *self.experiments.write().expect("experiments lock poisoned") = Arc::new(experiments);
+    }
+
+    fn current(&self) -> Arc<Vec<Experiment>> {
+        self.experiments
+            .read()
+            .expect("experiments lock poisoned")
+            .clone()
+    }
+
+    /// Reload definitions from the settings table
+    ///
+    /// Malformed definitions are logged and skipped.
+    pub async fn reload(&self, pool: &PgPool) -> Result<()> {
+        let mut experiments = Vec::new();
+        for setting in settings::list(pool, KEY_PREFIX).await? {
+            let name = setting.key[KEY_PREFIX.len()..].to_string();
+            let experiment = serde_json::from_value::<Experiment>(setting.value)
+                .map_err(anyhow::Error::from)
+                .map(|experiment| Experiment { name, ..experiment })
+                .and_then(|experiment| experiment.validate().map(|()| experiment));
+            match experiment {
+                Ok(experiment) => experiments.push(experiment),
+                Err(e) => tracing::warn!("Ignoring malformed experiment {}: {:#}", setting.key, e),
+            }
+        }
+        self.set(experiments);
+        Ok(())
+    }
+
+    /// Middleware assigning the request's variants
+    pub async fn middleware(
+        State(experiments): State<Experiments>,
+        mut request: Request,
+        next: Next,
+    ) -> Response {
+        let running = experiments.current();
+        if running.is_empty() {
+            return next.run(request).await;
+        }
+
+        let user = request
+            .extensions()
+            .get::<SessionHandle>()
+            .map(SessionHandle::user_id);
+        let visitor = sessions::cookie(request.headers(), &experiments.config.cookie)
+            .filter(|id| valid_name(id))
+            .map(String::from);
+        let (subject, new_visitor) = match (user, visitor) {
+            (Some(user), _) => (format!("user:{}", user), None),
+            (None, Some(visitor)) => (format!("visitor:{}", visitor), None),
+            (None, None) => {
+                let mut id = [0u8; 16];
+                rand_core::OsRng.fill_bytes(&mut id);
+                let id = URL_SAFE_NO_PAD.encode(id);
+                (format!("visitor:{}", id), Some(id))
+            }
+        };
+        let assignments = Assignments(
+            running
+                .iter()
+                .map(|experiment| {
+                    let variant = experiment.assign(&subject).to_string();
+                    (experiment.name.clone(), variant)
+                })
+                .collect(),
+        );
+        let header = assignments.header();
+        request.extensions_mut().insert(assignments);
+
+        let mut response = next.run(request).await;
+        let headers = response.headers_mut();
+        if let Some(header) = header {
+            headers.insert(EXPERIMENTS_HEADER, header);
+        }
+        if let Some(id) = new_visitor {
+            let cookie = format!(
+                "{}={}; Path=/; Max-Age={}; SameSite=Lax",
+                experiments.config.cookie, id, VISITOR_COOKIE_MAX_AGE
+            );
+            if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+                headers.append(SET_COOKIE, cookie);
+            }
+        }
+        response
+    }
+}
+
+/// Spawn the task reloading experiment definitions, once loaded at startup
+pub fn spawn(experiments: Experiments, pool: PgPool) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let refresh = experiments.config.refresh;
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + refresh, refresh);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = experiments.reload(&pool).await {
+                tracing::warn!("Failed to reload experiments: {:#}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::header::COOKIE, middleware, routing::get, Router};
+    use tower::Service;
+
+    fn experiment(name: &str, weights: &[(&str, u32)]) -> Experiment {
+        Experiment {
+            name: name.into(),
+            enabled: true,
+            salt: None,
+            variants: weights
+                .iter()
+                .map(|(name, weight)| WeightedVariant {
+                    name: name.to_string(),
+                    weight: *weight,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_assigns_deterministically_by_weight() {
+        let checkout = experiment("checkout", &[("control", 90), ("blue", 10)]);
+        let assigned: Vec<&str> = (0..1000)
+            .map(|user| checkout.assign(&format!("user:{}", user)))
+            .collect();
+        let blue = assigned
+            .iter()
+            .filter(|variant| **variant == "blue")
+            .count();
+        assert!((50..150).contains(&blue), "{}", blue);
+        assert_eq!(checkout.assign("user:7"), assigned[7]);
+
+        let resalted = Experiment {
+            salt: Some("second run".into()),
+            ..checkout.clone()
+        };
+        assert!((0..1000).any(|user| {
+            let subject = format!("user:{}", user);
+            checkout.assign(&subject) != resalted.assign(&subject)
+        }));
+        assert!(experiment("x", &[("a", 0)]).validate().is_err());
+        assert!(experiment("x", &[("a b", 1)]).validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_assigns_visitors_and_sets_headers() {
+        let experiments = Experiments::new(ExperimentsConfig {
+            cookie: "visitor".into(),
+            refresh: Duration::from_secs(30),
+        });
+        experiments.set(vec![
+            experiment("search", &[("control", 1), ("fuzzy", 1)]),
+            experiment("checkout", &[("blue", 1)]),
+        ]);
+        let mut app = Router::new()
+            .route(
+                "/",
+                get(|assignments: Assignments| async move {
+                    assignments
+                        .variant("checkout")
+                        .unwrap_or("none")
+                        .to_string()
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                experiments,
+                Experiments::middleware,
+            ));
+
+        let response = app
+            .call(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+        let visitor = cookie.split(';').next().unwrap().to_string();
+        let first = response.headers()[&EXPERIMENTS_HEADER].clone();
+        assert!(first
+            .to_str()
+            .unwrap()
+            .starts_with("checkout=blue, search="));
+
+        for _ in 0..5 {
+            let request = Request::get("/")
+                .header(COOKIE, &visitor)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            assert!(response.headers().get(SET_COOKIE).is_none());
+            assert_eq!(response.headers()[&EXPERIMENTS_HEADER], first);
+        }
+    }
+}
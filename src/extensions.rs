@@ -0,0 +1,98 @@
+//! Typed extension registry.
+//!
+//! Subsystems such as caches, mailers or event buses store a shared value in
+//! [`AppState`] keyed by its type, instead of adding a field to the state and
+//! threading it through every handler. Values are inserted on the
+//! [`ServerBuilder`](crate::ServerBuilder) or from a startup hook and read
+//! back with [`AppState::extension`] or the [`Ext`] extractor.
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::error::ApiError;
+use crate::AppState;
+
+type AnyValue = Arc<dyn Any + Send + Sync>;
+
+/// Type map of shared values, one per type
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: Arc<RwLock<HashMap<TypeId, AnyValue>>>,
+}
+
+impl Extensions {
+    /// Store a value, replacing any previous value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) {
+        self.values
+            .write()
+            .expect("extensions lock poisoned")
+            .insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    /// Move every value from `other` into this registry
+    pub fn extend(&self, other: Extensions) {
+        let other = std::mem::take(&mut *other.values.write().expect("extensions lock poisoned"));
+        self.values
+            .write()
+            .expect("extensions lock poisoned")
+            .extend(other);
+    }
+
+    /// Get the value of type `T`, if one was inserted
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let value = self
+            .values
+            .read()
+            .expect("extensions lock poisoned")
+            .get(&TypeId::of::<T>())
+            .cloned()?;
+        value.downcast().ok()
+    }
+}
+
+/// Extractor for a value stored in the extension registry
+///
+/// Rejects the request with a 500 if no value of type `T` was registered.
+pub struct Ext<T>(pub Arc<T>);
+
+#[async_trait]
+impl<T: Send + Sync + 'static> FromRequestParts<AppState> for Ext<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        _parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        state.extension::<T>().map(Ext).ok_or_else(|| {
+            ApiError::Internal(anyhow::anyhow!(
+                "extension {} is not registered",
+                std::any::type_name::<T>()
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Mailer(&'static str);
+
+    #[test]
+    fn test_insert_and_get_by_type() {
+        let extensions = Extensions::default();
+        assert!(extensions.get::<Mailer>().is_none());
+
+        let shared = extensions.clone();
+        shared.insert(Mailer("smtp"));
+        shared.insert(42u32);
+        assert_eq!(*extensions.get::<Mailer>().unwrap(), Mailer("smtp"));
+        assert_eq!(*extensions.get::<u32>().unwrap(), 42);
+
+        extensions.insert(Mailer("ses"));
+        assert_eq!(*shared.get::<Mailer>().unwrap(), Mailer("ses"));
+    }
+}
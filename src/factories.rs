@@ -0,0 +1,359 @@
+//! Factories inserting test data.
+//!
+//! Available to the crate's tests and, with the `testing` feature, to
+//! downstream integration tests. Each factory fills in unique defaults from
+//! a shared [`sequence`] so tests only spell out the fields they care
+//! about, and creates related rows it needs (a document's collection) when
+//! none is given:
+//!
+//! ```no_run
+//! # use rust_selfhost_server::factories::{DocumentFactory, UserFactory};
+//! # async fn run(pool: &sqlx::PgPool) -> anyhow::Result<()> {
+//! let admin = UserFactory::new().admin().insert(pool).await?;
+//! let doc = DocumentFactory::new()
+//!     .data(serde_json::json!({ "owner": admin.username }))
+//!     .insert(pool)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::collections::{Collection, Document};
+use crate::oidc::Client;
+use crate::settings::{self, Setting};
+use crate::status::Incident;
+use crate::users::{self, User};
+
+/// Password given to users unless overridden
+pub const DEFAULT_PASSWORD: &str = "correct horse battery staple";
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
+/// Next value of a process-wide counter, for unique names
+pub fn sequence() -> u64 {
+    SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Builds rows in `users`
+#[derive(Debug, Clone)]
+pub struct UserFactory {
+    username: Option<String>,
+    password: String,
+    is_admin: bool,
+}
+
+impl Default for UserFactory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UserFactory {
+    pub fn new() -> Self {
+        UserFactory {
+            username: None,
+            password: DEFAULT_PASSWORD.to_string(),
+            is_admin: false,
+        }
+    }
+
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = password.into();
+        self
+    }
+
+    pub fn admin(mut self) -> Self {
+        self.is_admin = true;
+        self
+    }
+
+    pub async fn insert(self, pool: &PgPool) -> Result<User> {
+        let username = self
+            .username
+            .unwrap_or_else(|| format!("user{}", sequence()));
+        users::create(pool, &username, &self.password, self.is_admin)
+            .await?
+            .with_context(|| format!("User '{}' already exists", username))
+    }
+}
+
+/// Builds rows in `settings`
+#[derive(Debug, Clone, Default)]
+pub struct SettingFactory {
+    key: Option<String>,
+    value: Option<Value>,
+}
+
+impl SettingFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    pub fn value(mut self, value: Value) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    pub async fn insert(self, pool: &PgPool) -> Result<Setting> {
+        let n = sequence();
+        let key = self.key.unwrap_or_else(|| format!("test.setting{}", n));
+        let value = self.value.unwrap_or_else(|| json!(n));
+        settings::set(pool, &key, &value).await
+    }
+}
+
+/// Builds rows in `collections`
+#[derive(Debug, Clone, Default)]
+pub struct CollectionFactory {
+    name: Option<String>,
+    schema: Option<Value>,
+    indexes: Vec<String>,
+}
+
+impl CollectionFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// JSON Schema documents must satisfy
+    pub fn schema(mut self, schema: Value) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Record a field as indexed
+    ///
+    /// Only the collection row is written; the expression index itself is
+    /// created by the API.
+    pub fn index(mut self, field: impl Into<String>) -> Self {
+        self.indexes.push(field.into());
+        self
+    }
+
+    pub async fn insert(self, pool: &PgPool) -> Result<Collection> {
+        let name = self
+            .name
+            .unwrap_or_else(|| format!("collection{}", sequence()));
+        let collection = sqlx::query_as::<_, Collection>(
+            "INSERT INTO collections (name, schema, indexes) VALUES ($1, $2, $3)
+             RETURNING name, schema, indexes, created_at",
+        )
+        .bind(name)
+        .bind(self.schema)
+        .bind(self.indexes)
+        .fetch_one(pool)
+        .await?;
+        Ok(collection)
+    }
+}
+
+/// Builds rows in `collection_docs`, creating a collection if none is set
+#[derive(Debug, Clone, Default)]
+pub struct DocumentFactory {
+    collection: Option<String>,
+    data: Option<Value>,
+}
+
+impl DocumentFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn collection(mut self, collection: &Collection) -> Self {
+        self.collection = Some(collection.name.clone());
+        self
+    }
+
+    pub fn data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub async fn insert(self, pool: &PgPool) -> Result<Document> {
+        let collection = match self.collection {
+            Some(name) => name,
+            None => CollectionFactory::new().insert(pool).await?.name,
+        };
+        let data = self
+            .data
+            .unwrap_or_else(|| json!({ "title": format!("Document {}", sequence()) }));
+        let document = sqlx::query_as::<_, Document>(
+            "INSERT INTO collection_docs (collection, data) VALUES ($1, $2)
+             RETURNING id, collection, data, created_at, updated_at",
+        )
+        .bind(collection)
+        .bind(data)
+        .fetch_one(pool)
+        .await?;
+        Ok(document)
+    }
+}
+
+/// Builds rows in `oidc_clients`
+#[derive(Debug, Clone, Default)]
+pub struct OidcClientFactory {
+    name: Option<String>,
+    secret: Option<String>,
+    redirect_uris: Vec<String>,
+}
+
+impl OidcClientFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn secret(mut self, secret: impl Into<String>) -> Self {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    pub fn redirect_uri(mut self, uri: impl Into<String>) -> Self {
+        self.redirect_uris.push(uri.into());
+        self
+    }
+
+    /// Insert the client, returning it with its plaintext secret
+    pub async fn insert(self, pool: &PgPool) -> Result<(Client, String)> {
+        let n = sequence();
+        let secret = self.secret.unwrap_or_else(|| format!("secret{}", n));
+        let redirect_uris = if self.redirect_uris.is_empty() {
+            vec![format!("https://app{}.example.com/callback", n)]
+        } else {
+            self.redirect_uris
+        };
+        let client = sqlx::query_as::<_, Client>(
+            "INSERT INTO oidc_clients (client_id, name, secret_hash, redirect_uris)
+             VALUES ($1, $2, $3, $4)
+             RETURNING client_id, name, secret_hash, redirect_uris, created_at",
+        )
+        .bind(format!("client{}", n))
+        .bind(self.name.unwrap_or_else(|| format!("App {}", n)))
+        .bind(crate::crypto::sha256_hex(&secret))
+        .bind(redirect_uris)
+        .fetch_one(pool)
+        .await?;
+        Ok((client, secret))
+    }
+}
+
+/// Builds rows in `status_incidents`
+#[derive(Debug, Clone, Default)]
+pub struct IncidentFactory {
+    component: Option<String>,
+    error: Option<String>,
+    resolved: bool,
+}
+
+impl IncidentFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn component(mut self, component: impl Into<String>) -> Self {
+        self.component = Some(component.into());
+        self
+    }
+
+    pub fn error(mut self, error: impl Into<String>) -> Self {
+        self.error = Some(error.into());
+        self
+    }
+
+    pub fn resolved(mut self) -> Self {
+        self.resolved = true;
+        self
+    }
+
+    pub async fn insert(self, pool: &PgPool) -> Result<Incident> {
+        let incident = sqlx::query_as::<_, Incident>(
+            "INSERT INTO status_incidents (component, error, resolved_at)
+             VALUES ($1, $2, CASE WHEN $3 THEN now() END)
+             RETURNING id, component, error, started_at, resolved_at",
+        )
+        .bind(
+            self.component
+                .unwrap_or_else(|| format!("component{}", sequence())),
+        )
+        .bind(self.error)
+        .bind(self.resolved)
+        .fetch_one(pool)
+        .await?;
+        Ok(incident)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::TestDatabase;
+
+    #[tokio::test]
+    async fn test_factories_insert_related_rows() {
+        let Some(db) = TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+
+        let alice = UserFactory::new().admin().insert(pool).await.unwrap();
+        let bob = UserFactory::new().insert(pool).await.unwrap();
+        assert_ne!(alice.username, bob.username);
+        assert!(alice.is_admin && !bob.is_admin);
+        let found = users::authenticate(pool, &bob.username, DEFAULT_PASSWORD)
+            .await
+            .unwrap();
+        assert_eq!(found.map(|u| u.id), Some(bob.id));
+
+        let doc = DocumentFactory::new().insert(pool).await.unwrap();
+        let notes = CollectionFactory::new()
+            .name("notes")
+            .insert(pool)
+            .await
+            .unwrap();
+        let note = DocumentFactory::new()
+            .collection(&notes)
+            .data(json!({ "owner": alice.username }))
+            .insert(pool)
+            .await
+            .unwrap();
+        assert_ne!(doc.collection, "notes");
+        assert_eq!(note.collection, "notes");
+
+        let setting = SettingFactory::new().insert(pool).await.unwrap();
+        assert!(setting.key.starts_with("test.setting"));
+
+        let (client, secret) = OidcClientFactory::new().insert(pool).await.unwrap();
+        assert_eq!(client.secret_hash, crate::crypto::sha256_hex(&secret));
+
+        let incident = IncidentFactory::new()
+            .resolved()
+            .insert(pool)
+            .await
+            .unwrap();
+        assert!(incident.resolved_at.is_some());
+    }
+}
@@ -0,0 +1,139 @@
+//! Recovery from database restarts and failovers.
+//!
+//! When Postgres restarts or fails over, connections in the pool break or
+//! stay attached to the old primary, now a read-only standby, and every
+//! request drawing one would fail until it expired. Errors that mean a
+//! connection is no longer usable ([`is_connection_failure`]) are marked on
+//! the error response; this layer then recycles the pools (see
+//! [`Database::recycle`]) and runs `GET`, `HEAD` and `OPTIONS` requests once
+//! more on a fresh connection. Other requests are not retried, as their
+//! writes may have been partly applied, but later ones get fresh
+//! connections too.
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::db::Database;
+
+/// SQLSTATE codes of a server going away or refusing writes
+///
+/// `57P01`-`57P03` are the server shutting down, crashed or starting up,
+/// `25006` is a write on a standby.
+const FAILOVER_CODES: &[&str] = &["57P01", "57P02", "57P03", "25006"];
+
+/// Marks an error response caused by a broken database connection
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionFailure;
+
+/// Whether an error means its connection no longer reaches a writable server
+pub fn is_connection_failure(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::Protocol(_) => true,
+        sqlx::Error::WorkerCrashed => true,
+        sqlx::Error::Database(e) => e.code().is_some_and(|code| {
+            // Class 08 is connection exceptions
+            code.starts_with("08") || FAILOVER_CODES.contains(&code.as_ref())
+        }),
+        _ => false,
+    }
+}
+
+/// Whether any error in a chain is a connection failure
+pub fn caused_by_connection_failure(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<sqlx::Error>())
+        .any(is_connection_failure)
+}
+
+/// Middleware recycling connections after a connection failure and
+/// retrying safe requests once
+pub async fn middleware(State(db): State<Database>, request: Request, next: Next) -> Response {
+    let retry = match request.method() {
+        &Method::GET | &Method::HEAD | &Method::OPTIONS if request.body().is_end_stream() => {
+            let mut retry = Request::new(Body::empty());
+            *retry.method_mut() = request.method().clone();
+            *retry.uri_mut() = request.uri().clone();
+            *retry.version_mut() = request.version();
+            *retry.headers_mut() = request.headers().clone();
+            *retry.extensions_mut() = request.extensions().clone();
+            Some(retry)
+        }
+        _ => None,
+    };
+    let response = next.clone().run(request).await;
+    if response.extensions().get::<ConnectionFailure>().is_none() {
+        return response;
+    }
+    db.recycle();
+    match retry {
+        Some(retry) => {
+            tracing::info!(
+                "Retrying {} after a database connection failure",
+                retry.uri()
+            );
+            next.run(retry).await
+        }
+        None => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{ApiError, ApiResult};
+    use axum::{http::StatusCode, routing::get, Router};
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tower::Service;
+
+    #[test]
+    fn test_classifies_connection_failures() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(is_connection_failure(&sqlx::Error::Io(reset)));
+        assert!(!is_connection_failure(&sqlx::Error::RowNotFound));
+        assert!(!is_connection_failure(&sqlx::Error::PoolTimedOut));
+
+        let error = anyhow::Error::new(sqlx::Error::WorkerCrashed).context("Failed to commit");
+        assert!(caused_by_connection_failure(&error));
+        assert!(!caused_by_connection_failure(&anyhow::anyhow!("boom")));
+    }
+
+    #[tokio::test]
+    async fn test_retries_reads_once_after_a_connection_failure() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let calls = calls.clone();
+            move || {
+                let calls = calls.clone();
+                async move {
+                    match calls.fetch_add(1, Ordering::SeqCst) {
+                        0 => Err(ApiError::from(sqlx::Error::WorkerCrashed)),
+                        _ => ApiResult::Ok("fresh"),
+                    }
+                }
+            }
+        };
+        let db =
+            Database::from_pool(PgPoolOptions::new().connect_lazy_with(PgConnectOptions::new()));
+        let mut app = Router::new()
+            .route("/items", get(handler.clone()).post(handler))
+            .layer(axum::middleware::from_fn_with_state(db, middleware));
+
+        let request = Request::get("/items").body(Body::empty()).unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        calls.store(0, Ordering::SeqCst);
+        let request = Request::post("/items").body(Body::empty()).unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
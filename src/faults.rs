@@ -0,0 +1,362 @@
+//! Fault injection for resilience testing.
+//!
+//! Enabled with `FAULT_INJECTION=true`, for development and staging only.
+//! Faults add latency, replace the response with an error status, or drop
+//! the connection before a complete response is sent. They are declared as
+//! rules matched by path prefix through `PUT /admin/faults`, or per request
+//! with headers, which take precedence over rules:
+//!
+//! - `X-Fault-Latency-Ms`: delay before the request is handled
+//! - `X-Fault-Status`: respond with this 4xx/5xx status instead
+//! - `X-Fault-Drop`: `true` to drop the connection
+//! - `X-Fault-Probability`: chance between 0 and 1 of applying the fault
+//!
+//! Injected error responses carry an `X-Fault-Injected` header so they
+//! can be told apart from real failures. `/admin/faults` itself is never
+//! faulted, so rules can always be cleared.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use hyper::body::Frame;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::module::{RouteGroup, RouteModule};
+use crate::AppState;
+
+const ADMIN_PATH: &str = "/admin/faults";
+
+/// A fault applied to requests under a path prefix
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultRule {
+    /// Path prefix the rule applies to
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Delay before the request is handled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// Error status returned instead of the real response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Drop the connection instead of responding
+    #[serde(default)]
+    pub drop: bool,
+    /// Chance of applying the fault to a matching request
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+impl FaultRule {
+    /// Check the rule injects something sensible
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.path.starts_with('/') {
+            return Err(format!("path '{}' must start with '/'", self.path));
+        }
+        if !(0.0..=1.0).contains(&self.probability) {
+            return Err("probability must be between 0 and 1".to_string());
+        }
+        if let Some(status) = self.status {
+            if !(400..=599).contains(&status) {
+                return Err(format!("status {} is not a 4xx or 5xx code", status));
+            }
+        }
+        if self.latency_ms.is_none() && self.status.is_none() && !self.drop {
+            return Err("rule needs latency_ms, status or drop".to_string());
+        }
+        Ok(())
+    }
+
+    /// Read a fault from `X-Fault-*` request headers, if any are set
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, String> {
+        fn header<T: std::str::FromStr>(
+            headers: &HeaderMap,
+            name: &str,
+        ) -> Result<Option<T>, String> {
+            headers
+                .get(name)
+                .map(|value| {
+                    value
+                        .to_str()
+                        .ok()
+                        .and_then(|v| v.trim().parse().ok())
+                        .ok_or_else(|| format!("invalid {} header", name))
+                })
+                .transpose()
+        }
+        let rule = FaultRule {
+            path: default_path(),
+            latency_ms: header(headers, "x-fault-latency-ms")?,
+            status: header(headers, "x-fault-status")?,
+            drop: header(headers, "x-fault-drop")?.unwrap_or(false),
+            probability: header(headers, "x-fault-probability")?
+                .unwrap_or_else(default_probability),
+        };
+        if rule.latency_ms.is_none() && rule.status.is_none() && !rule.drop {
+            return Ok(None);
+        }
+        rule.validate()?;
+        Ok(Some(rule))
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(self.path.trim_end_matches('/'))
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// Shared set of fault rules and the middleware applying them
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    rules: Arc<RwLock<Vec<FaultRule>>>,
+}
+
+impl FaultInjector {
+    /// Current rules, in match order
+    pub fn rules(&self) -> Vec<FaultRule> {
+        self.rules.read().expect("fault rules poisoned").clone()
+    }
+
+    /// Replace every rule after validating them
+    pub fn set_rules(&self, rules: Vec<FaultRule>) -> Result<(), String> {
+        for rule in &rules {
+            rule.validate()?;
+        }
+        *self.rules.write().expect("fault rules poisoned") = rules;
+        Ok(())
+    }
+
+    /// First rule whose prefix matches `path`
+    pub fn rule_for(&self, path: &str) -> Option<FaultRule> {
+        self.rules
+            .read()
+            .expect("fault rules poisoned")
+            .iter()
+            .find(|rule| rule.matches(path))
+            .cloned()
+    }
+
+    /// Middleware applying header or rule faults to each request
+    pub async fn middleware(
+        State(injector): State<FaultInjector>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let path = request.uri().path();
+        if path == ADMIN_PATH {
+            return next.run(request).await;
+        }
+        let rule = match FaultRule::from_headers(request.headers()) {
+            Ok(Some(rule)) => rule,
+            Ok(None) => match injector.rule_for(path) {
+                Some(rule) => rule,
+                None => return next.run(request).await,
+            },
+            Err(e) => return ApiError::BadRequest(e).into_response(),
+        };
+        if random_unit() >= rule.probability {
+            return next.run(request).await;
+        }
+
+        if let Some(latency) = rule.latency_ms {
+            tokio::time::sleep(Duration::from_millis(latency)).await;
+        }
+        if rule.drop {
+            tracing::debug!("💥 Dropping connection for {}", request.uri());
+            return dropped();
+        }
+        if let Some(status) = rule.status {
+            let status = StatusCode::from_u16(status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+            let mut response = (status, Json(json!({ "error": "injected fault" }))).into_response();
+            response
+                .headers_mut()
+                .insert("x-fault-injected", HeaderValue::from_static("true"));
+            return response;
+        }
+        next.run(request).await
+    }
+}
+
+/// Uniformly distributed value in `[0, 1)`
+pub(crate) fn random_unit() -> f64 {
+    (rand_core::RngCore::next_u64(&mut rand_core::OsRng) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A response whose body fails, making the server abort the connection
+fn dropped() -> Response {
+    Response::new(Body::new(FailingBody))
+}
+
+struct FailingBody;
+
+impl hyper::body::Body for FailingBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        Poll::Ready(Some(Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "connection dropped by fault injection",
+        ))))
+    }
+}
+
+/// `GET /admin/faults` - list fault rules
+async fn list_rules(Ext(injector): Ext<FaultInjector>) -> Json<Vec<FaultRule>> {
+    Json(injector.rules())
+}
+
+/// `PUT /admin/faults` - replace the fault rules
+async fn set_rules(
+    Ext(injector): Ext<FaultInjector>,
+    Json(rules): Json<Vec<FaultRule>>,
+) -> ApiResult<Json<Vec<FaultRule>>> {
+    injector.set_rules(rules).map_err(ApiError::BadRequest)?;
+    for rule in injector.rules() {
+        tracing::warn!("💥 Injecting faults on {}: {:?}", rule.path, rule);
+    }
+    Ok(Json(injector.rules()))
+}
+
+/// `DELETE /admin/faults` - remove every fault rule
+async fn clear_rules(Ext(injector): Ext<FaultInjector>) -> StatusCode {
+    injector
+        .rules
+        .write()
+        .expect("fault rules poisoned")
+        .clear();
+    StatusCode::NO_CONTENT
+}
+
+/// Route module managing fault rules, mounted when `FAULT_INJECTION` is on
+pub struct FaultsModule;
+
+impl RouteModule for FaultsModule {
+    fn name(&self) -> &'static str {
+        "faults"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route(
+            ADMIN_PATH,
+            get(list_rules).put(set_rules).delete(clear_rules),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get};
+    use tower::Service;
+
+    fn app(injector: &FaultInjector) -> Router {
+        Router::new()
+            .route("/api/items", get(|| async { "ok" }))
+            .route("/other", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                injector.clone(),
+                FaultInjector::middleware,
+            ))
+    }
+
+    async fn status(mut app: Router, request: Request) -> StatusCode {
+        app.call(request).await.unwrap().status()
+    }
+
+    #[test]
+    fn test_rule_validation_and_matching() {
+        let rule = |json: serde_json::Value| serde_json::from_value::<FaultRule>(json).unwrap();
+        assert!(rule(json!({ "status": 503 })).validate().is_ok());
+        assert!(rule(json!({ "status": 200 })).validate().is_err());
+        assert!(rule(json!({ "drop": true, "probability": 2.0 }))
+            .validate()
+            .is_err());
+        assert!(rule(json!({ "path": "/api" })).validate().is_err());
+
+        let api = rule(json!({ "path": "/api/", "status": 503 }));
+        assert!(api.matches("/api") && api.matches("/api/items"));
+        assert!(!api.matches("/apix"));
+        assert!(rule(json!({ "status": 503 })).matches("/anything"));
+    }
+
+    #[tokio::test]
+    async fn test_rules_and_headers_inject_faults() {
+        let injector = FaultInjector::default();
+        injector
+            .set_rules(vec![FaultRule {
+                path: "/api".into(),
+                latency_ms: None,
+                status: Some(503),
+                drop: false,
+                probability: 1.0,
+            }])
+            .unwrap();
+
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        assert_eq!(
+            status(app(&injector), get("/api/items")).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status(app(&injector), get("/other")).await, StatusCode::OK);
+
+        let request = Request::get("/other")
+            .header("x-fault-status", "429")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            status(app(&injector), request).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        let request = Request::get("/other")
+            .header("x-fault-status", "503")
+            .header("x-fault-probability", "0")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(app(&injector), request).await, StatusCode::OK);
+        let request = Request::get("/other")
+            .header("x-fault-status", "nope")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            status(app(&injector), request).await,
+            StatusCode::BAD_REQUEST
+        );
+
+        let request = Request::get("/other")
+            .header("x-fault-drop", "true")
+            .body(Body::empty())
+            .unwrap();
+        let response = app(&injector).call(request).await.unwrap();
+        assert!(axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,373 @@
+//! Fetching pages from arbitrary internet hosts.
+//!
+//! Unlike [`HttpClient`](crate::http_client::HttpClient), which talks to
+//! trusted sidecars, [`Fetcher`] is meant for URLs supplied by users, so it
+//! guards against server-side request forgery: hosts are resolved by a
+//! resolver that drops loopback, private, link-local and other non-public
+//! addresses (and connections only go to the addresses it returned, so DNS
+//! rebinding cannot sneak past), IP literals are checked the same way, and
+//! every redirect is re-validated. Responses are cut off after a byte limit
+//! and the whole exchange after `FETCH_TIMEOUT`.
+//!
+//! `FETCH_ALLOW_PRIVATE_NETWORKS=true` lifts the address checks, for
+//! self-hosters who want to reach services on their own network.
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{
+    header::{ACCEPT, CONTENT_TYPE, LOCATION, USER_AGENT},
+    Request, StatusCode, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{
+    connect::{dns::Name, HttpConnector},
+    Client,
+};
+use hyper_util::rt::TokioExecutor;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+/// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// Whether `url` is an absolute http(s) URL
+pub fn is_web_url(url: &str) -> bool {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return false;
+    };
+    matches!(scheme, "http" | "https")
+        && !rest.is_empty()
+        && !rest.starts_with('/')
+        && !url.chars().any(char::is_whitespace)
+}
+
+/// Whether an address is reachable on the public internet
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (carrier-grade NAT)
+        || (a == 100 && (64..128).contains(&b))
+        // Benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved for future use
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [first, second, ..] = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local
+        || (first & 0xfe00) == 0xfc00
+        // Link-local
+        || (first & 0xffc0) == 0xfe80
+        // Documentation
+        || (first == 0x2001 && second == 0x0db8))
+}
+
+/// DNS resolver keeping only addresses allowed as fetch targets
+#[derive(Clone)]
+struct GuardedResolver {
+    allow_private: bool,
+}
+
+impl tower::Service<Name> for GuardedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allow_private = self.allow_private;
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| allow_private || is_public(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("{} has no public address", name),
+                ));
+            }
+            Ok(addresses.into_iter())
+        })
+    }
+}
+
+/// Resolve `reference` against the URL `base`, as a browser would
+pub fn resolve_url(base: &Uri, reference: &str) -> Option<String> {
+    let reference = reference.trim();
+    if let Some((scheme, _)) = reference.split_once(':') {
+        if scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        {
+            return Some(reference.to_string());
+        }
+    }
+    let scheme = base.scheme_str()?;
+    let authority = base.authority()?;
+    if let Some(rest) = reference.strip_prefix("//") {
+        return Some(format!("{}://{}", scheme, rest));
+    }
+    let path = if reference.starts_with('/') {
+        reference.to_string()
+    } else if reference.starts_with(['?', '#']) {
+        format!("{}{}", base.path(), reference)
+    } else {
+        let directory = &base.path()[..=base.path().rfind('/').unwrap_or(0)];
+        format!("{}{}", directory, reference)
+    };
+    Some(format!("{}://{}{}", scheme, authority, path))
+}
+
+/// A fetched response
+#[derive(Debug)]
+pub struct FetchedPage {
+    /// The URL after following redirects
+    pub url: Uri,
+    pub status: StatusCode,
+    pub content_type: Option<String>,
+    /// The body, cut off after the requested limit
+    pub body: Bytes,
+}
+
+impl FetchedPage {
+    /// Whether the body is HTML
+    pub fn is_html(&self) -> bool {
+        self.content_type
+            .as_deref()
+            .is_some_and(|c| c.starts_with("text/html") || c.starts_with("application/xhtml"))
+    }
+
+    /// The body as text, replacing invalid UTF-8
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// The page's icon: its `<link rel="icon">` or, failing that, the
+    /// site's `/favicon.ico`
+    pub fn icon_url(&self) -> String {
+        let declared = self.is_html().then(|| {
+            let links = crate::html::tags(&self.text(), "link");
+            let rel_is = |link: &crate::html::Attributes, wanted: &str| {
+                link.get("rel").is_some_and(|rel| {
+                    rel.split_whitespace()
+                        .any(|r| r.eq_ignore_ascii_case(wanted))
+                })
+            };
+            ["icon", "apple-touch-icon"].iter().find_map(|wanted| {
+                links
+                    .iter()
+                    .filter(|link| rel_is(link, wanted))
+                    .find_map(|link| link.get("href"))
+                    .and_then(|href| resolve_url(&self.url, href))
+                    .filter(|icon| is_web_url(icon) || icon.starts_with("data:image/"))
+            })
+        });
+        declared.flatten().unwrap_or_else(|| {
+            format!(
+                "{}://{}/favicon.ico",
+                self.url.scheme_str().unwrap_or("https"),
+                self.url.authority().map_or("", |a| a.as_str())
+            )
+        })
+    }
+}
+
+/// HTTP(S) client for user-supplied URLs
+#[derive(Clone)]
+pub struct Fetcher {
+    client: Client<HttpsConnector<HttpConnector<GuardedResolver>>, Empty<Bytes>>,
+    timeout: Duration,
+    allow_private: bool,
+}
+
+impl Fetcher {
+    pub fn new(timeout: Duration, allow_private: bool) -> Self {
+        let mut http = HttpConnector::new_with_resolver(GuardedResolver { allow_private });
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(timeout));
+        let https = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(http);
+        Fetcher {
+            client: Client::builder(TokioExecutor::new()).build(https),
+            timeout,
+            allow_private,
+        }
+    }
+
+    /// Check a URL may be fetched, before resolving its host
+    fn check(&self, url: &str) -> Result<Uri> {
+        let uri: Uri = url
+            .parse()
+            .with_context(|| format!("Invalid URL '{}'", url))?;
+        anyhow::ensure!(
+            matches!(uri.scheme_str(), Some("http" | "https")),
+            "Only http:// and https:// URLs can be fetched, got '{}'",
+            url
+        );
+        let host = uri.host().context("URL has no host")?;
+        // IP literals never reach the resolver
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+            anyhow::ensure!(
+                self.allow_private || is_public(ip),
+                "{} is not a public address",
+                ip
+            );
+        }
+        Ok(uri)
+    }
+
+    /// `GET` a URL, following redirects and reading at most `max_bytes`
+    pub async fn get(&self, url: &str, max_bytes: usize) -> Result<FetchedPage> {
+        tokio::time::timeout(self.timeout, self.get_inner(url, max_bytes))
+            .await
+            .with_context(|| format!("GET {} timed out after {:?}", url, self.timeout))?
+    }
+
+    async fn get_inner(&self, url: &str, max_bytes: usize) -> Result<FetchedPage> {
+        let mut uri = self.check(url)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let request = Request::get(uri.clone())
+                .header(
+                    USER_AGENT,
+                    concat!("rust-selfhost-server/", env!("CARGO_PKG_VERSION")),
+                )
+                .header(ACCEPT, "text/html,application/xhtml+xml;q=0.9,*/*;q=0.5")
+                .body(Empty::new())?;
+            let response = self
+                .client
+                .request(request)
+                .await
+                .with_context(|| format!("GET {} failed", uri))?;
+            let status = response.status();
+            if status.is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .with_context(|| format!("{} redirected without a Location", uri))?;
+                let next = resolve_url(&uri, location)
+                    .with_context(|| format!("Invalid redirect to '{}'", location))?;
+                uri = self.check(&next)?;
+                continue;
+            }
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_ascii_lowercase());
+            let mut body = response.into_body();
+            let mut bytes = Vec::new();
+            while bytes.len() < max_bytes {
+                let Some(frame) = body.frame().await else {
+                    break;
+                };
+                if let Ok(data) = frame?.into_data() {
+                    bytes.extend_from_slice(&data);
+                }
+            }
+            bytes.truncate(max_bytes);
+            return Ok(FetchedPage {
+                url: uri,
+                status,
+                content_type,
+                body: bytes.into(),
+            });
+        }
+        anyhow::bail!("GET {} redirected more than {} times", url, MAX_REDIRECTS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_web_url() {
+        assert!(is_web_url("https://example.com/a?b=c"));
+        assert!(!is_web_url("javascript:alert(1)"));
+        assert!(!is_web_url("https:///path") && !is_web_url("/relative"));
+        assert!(!is_web_url("ftp://example.com"));
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_resolve_url() {
+        let base: Uri = "https://example.com/docs/page.html?x=1".parse().unwrap();
+        let resolve = |reference| resolve_url(&base, reference).unwrap();
+        assert_eq!(resolve("/favicon.ico"), "https://example.com/favicon.ico");
+        assert_eq!(resolve("icon.png"), "https://example.com/docs/icon.png");
+        assert_eq!(
+            resolve("//cdn.example.net/a.png"),
+            "https://cdn.example.net/a.png"
+        );
+        assert_eq!(resolve("http://other.example/"), "http://other.example/");
+        assert_eq!(resolve("?y=2"), "https://example.com/docs/page.html?y=2");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_private_targets() {
+        let fetcher = Fetcher::new(Duration::from_secs(2), false);
+        for url in [
+            "http://127.0.0.1/",
+            "http://[::1]:8080/",
+            "http://localhost/",
+            "file:///etc/passwd",
+        ] {
+            assert!(fetcher.get(url, 1024).await.is_err(), "{}", url);
+        }
+    }
+}
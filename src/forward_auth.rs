@@ -0,0 +1,118 @@
+//! Forward-auth endpoint for reverse proxies.
+//!
+//! Traefik (`forwardAuth`), nginx (`auth_request`) and Caddy
+//! (`forward_auth`) can ask `GET /auth/forward` whether a request to another
+//! application is allowed. The original `Authorization` header is checked
+//! against `ADMIN_TOKEN` and `API_KEYS`; a valid token is answered with 200
+//! and identity headers for the proxy to copy upstream, anything else with
+//! 401.
+//!
+//! | Header        | Admin token | API key                   |
+//! |---------------|-------------|---------------------------|
+//! | `X-Auth-User` | `admin`     | `api-key:<fingerprint>`   |
+//! | `X-Auth-Role` | `admin`     | `api`                     |
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use sha2::{Digest, Sha256};
+
+use crate::auth::{bearer_token, AdminToken, ApiKeys};
+use crate::error::ApiError;
+use crate::extensions::Ext;
+use crate::module::{RouteGroup, RouteModule};
+use crate::AppState;
+
+const AUTH_USER: HeaderName = HeaderName::from_static("x-auth-user");
+const AUTH_ROLE: HeaderName = HeaderName::from_static("x-auth-role");
+
+/// Route module serving `/auth/forward`
+pub struct ForwardAuthModule;
+
+impl RouteModule for ForwardAuthModule {
+    fn name(&self) -> &'static str {
+        "forward_auth"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/auth/forward", get(forward_auth))
+    }
+}
+
+/// Who a valid token belongs to
+#[derive(Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub user: String,
+    pub role: &'static str,
+}
+
+/// Resolve a bearer token to an identity
+pub fn identify(token: &str, admin: &AdminToken, keys: &ApiKeys) -> Option<Identity> {
+    if admin.matches(token) {
+        return Some(Identity {
+            user: "admin".to_string(),
+            role: "admin",
+        });
+    }
+    keys.contains(token).then(|| Identity {
+        user: format!("api-key:{}", fingerprint(token)),
+        role: "api",
+    })
+}
+
+/// Short, stable identifier for a key that does not reveal it
+fn fingerprint(key: &str) -> String {
+    Sha256::digest(key.as_bytes())[..6]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// `GET /auth/forward` - 200 with identity headers or 401
+pub async fn forward_auth(
+    Ext(admin): Ext<AdminToken>,
+    Ext(keys): Ext<ApiKeys>,
+    request: Request,
+) -> Response {
+    let identity = bearer_token(&request).and_then(|token| identify(token, &admin, &keys));
+    let Some(identity) = identity else {
+        return ApiError::Unauthorized("invalid or missing token".into()).into_response();
+    };
+    match HeaderValue::from_str(&identity.user) {
+        Ok(user) => (
+            StatusCode::OK,
+            [
+                (AUTH_USER, user),
+                (AUTH_ROLE, HeaderValue::from_static(identity.role)),
+            ],
+        )
+            .into_response(),
+        Err(e) => ApiError::Internal(e.into()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identify() {
+        let admin = AdminToken::new(Some("root".into()));
+        let keys = ApiKeys::new(vec!["alpha".into()]);
+
+        assert_eq!(identify("root", &admin, &keys).unwrap().role, "admin");
+        let identity = identify("alpha", &admin, &keys).unwrap();
+        assert_eq!(identity.role, "api");
+        assert_eq!(identity.user, format!("api-key:{}", fingerprint("alpha")));
+        assert_eq!(identity.user.len(), "api-key:".len() + 12);
+        assert_eq!(identify("beta", &admin, &keys), None);
+    }
+}
@@ -0,0 +1,359 @@
+//! gRPC health checking.
+//!
+//! With `GRPC_PORT` set, the server also listens there for cleartext
+//! HTTP/2 (h2c) gRPC and serves `grpc.health.v1.Health`, the standard
+//! health protocol that Kubernetes `grpc` probes, Envoy and other service
+//! meshes speak natively:
+//!
+//! - `Check` answers `SERVING` or `NOT_SERVING` once; `Watch` sends the
+//!   status right away and again whenever it changes, checking every five
+//!   seconds
+//! - the empty service name is the whole server, with the semantics of
+//!   `/health/ready`: `NOT_SERVING` while starting, draining or when a
+//!   critical check fails
+//! - any other name is a single registered check, e.g. `db`, `SERVING` only
+//!   while it passes; unknown names fail `Check` with `NOT_FOUND` and make
+//!   `Watch` report `SERVICE_UNKNOWN`
+//!
+//! That is the only service: the two messages are small enough to encode
+//! by hand, without a protobuf toolchain. Compressed messages and TLS are
+//! not supported; probes and sidecars reach the port directly.
+
+use anyhow::{Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::stream::{self, Stream, StreamExt};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Empty, Limited, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use hyper::{Method, Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::graceful::GracefulShutdown;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+use crate::health::{HealthRegistry, Status, CHECK_TIMEOUT};
+
+/// How often `Watch` re-runs the checks
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// Largest request message accepted; a request only holds a service name
+const MAX_REQUEST: usize = 4096;
+/// How long open `Watch` streams get to finish on shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+const CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+const WATCH_PATH: &str = "/grpc.health.v1.Health/Watch";
+
+/// `grpc.health.v1.HealthCheckResponse.ServingStatus`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServingStatus {
+    Serving = 1,
+    NotServing = 2,
+    ServiceUnknown = 3,
+}
+
+/// gRPC status codes used here
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Code {
+    Ok = 0,
+    InvalidArgument = 3,
+    NotFound = 5,
+    Unimplemented = 12,
+}
+
+type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+/// Status of `service`, or `None` if no check has that name
+pub async fn status(health: &HealthRegistry, service: &str) -> Option<ServingStatus> {
+    let report = health.run(CHECK_TIMEOUT).await;
+    let serving = if service.is_empty() {
+        report.status != Status::Unavailable
+    } else {
+        report
+            .checks
+            .iter()
+            .find(|check| check.name == service)?
+            .status
+            == Status::Ok
+    };
+    Some(if serving {
+        ServingStatus::Serving
+    } else {
+        ServingStatus::NotServing
+    })
+}
+
+/// Decode the `service` field of a `HealthCheckRequest`
+pub fn decode_request(mut message: &[u8]) -> Result<String> {
+    let mut service = String::new();
+    while message.has_remaining() {
+        let key = read_varint(&mut message)?;
+        match (key >> 3, key & 7) {
+            (1, 2) => {
+                let length = read_varint(&mut message)? as usize;
+                anyhow::ensure!(message.remaining() >= length, "truncated service name");
+                service = String::from_utf8(message[..length].to_vec())
+                    .context("service name is not UTF-8")?;
+                message.advance(length);
+            }
+            // Skip unknown fields by wire type
+            (_, 0) => {
+                read_varint(&mut message)?;
+            }
+            (_, 1) if message.remaining() >= 8 => message.advance(8),
+            (_, 2) => {
+                let length = read_varint(&mut message)? as usize;
+                anyhow::ensure!(message.remaining() >= length, "truncated field");
+                message.advance(length);
+            }
+            (_, 5) if message.remaining() >= 4 => message.advance(4),
+            (field, wire_type) => {
+                anyhow::bail!("invalid field {} of wire type {}", field, wire_type)
+            }
+        }
+    }
+    Ok(service)
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        anyhow::ensure!(buf.has_remaining(), "truncated varint");
+        let byte = buf.get_u8();
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    anyhow::bail!("varint too long")
+}
+
+/// Encode a `HealthCheckResponse` as a length-prefixed gRPC message
+pub fn encode_response(status: ServingStatus) -> Bytes {
+    let mut frame = BytesMut::with_capacity(7);
+    frame.put_u8(0);
+    frame.put_u32(2);
+    frame.put_u8(0x08);
+    frame.put_u8(status as u8);
+    frame.freeze()
+}
+
+/// Split the single length-prefixed message off a request body
+fn unframe(body: &[u8]) -> Result<&[u8], (Code, &'static str)> {
+    let invalid = (
+        Code::InvalidArgument,
+        "expected one length-prefixed message",
+    );
+    let (prefix, message) = body.split_first_chunk::<5>().ok_or(invalid)?;
+    if prefix[0] != 0 {
+        return Err((Code::Unimplemented, "compressed messages are not supported"));
+    }
+    let length = u32::from_be_bytes([prefix[1], prefix[2], prefix[3], prefix[4]]) as usize;
+    if message.len() != length {
+        return Err(invalid);
+    }
+    Ok(message)
+}
+
+fn trailers(code: Code, message: Option<&str>) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(code as u16));
+    if let Some(value) = message.and_then(|m| HeaderValue::from_str(m).ok()) {
+        trailers.insert("grpc-message", value);
+    }
+    trailers
+}
+
+/// A response with no messages, carrying the status in its headers
+fn trailers_only(code: Code, message: &str) -> Response<Body> {
+    let mut response = Response::new(Empty::new().boxed_unsync());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    headers.extend(trailers(code, Some(message)));
+    response
+}
+
+/// A response sending `frames`, which end with trailers for unary calls
+fn streaming(frames: impl Stream<Item = Frame<Bytes>> + Send + 'static) -> Response<Body> {
+    let body = StreamBody::new(frames.map(Ok::<_, Infallible>));
+    let mut response = Response::new(body.boxed_unsync());
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    response
+}
+
+/// Handle one gRPC call
+pub async fn handle<B>(health: HealthRegistry, request: Request<B>) -> Response<Body>
+where
+    B: hyper::body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let path = request.uri().path().to_string();
+    if request.method() != Method::POST || (path != CHECK_PATH && path != WATCH_PATH) {
+        return trailers_only(Code::Unimplemented, "unknown method");
+    }
+    let body = match Limited::new(request.into_body(), MAX_REQUEST)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            tracing::debug!("Failed to read a gRPC request: {}", e);
+            return trailers_only(Code::InvalidArgument, "unreadable request");
+        }
+    };
+    let service = match unframe(&body) {
+        Ok(message) => match decode_request(message) {
+            Ok(service) => service,
+            Err(_) => return trailers_only(Code::InvalidArgument, "invalid HealthCheckRequest"),
+        },
+        Err((code, message)) => return trailers_only(code, message),
+    };
+
+    if path == CHECK_PATH {
+        return match status(&health, &service).await {
+            Some(status) => streaming(stream::iter([
+                Frame::data(encode_response(status)),
+                Frame::trailers(trailers(Code::Ok, None)),
+            ])),
+            None => trailers_only(Code::NotFound, "unknown service"),
+        };
+    }
+
+    // Watch: send the first status at once, then only changes
+    let updates = stream::unfold(
+        (health, service, None),
+        |(health, service, last)| async move {
+            loop {
+                if last.is_some() {
+                    tokio::time::sleep(WATCH_INTERVAL).await;
+                }
+                let current = status(&health, &service)
+                    .await
+                    .unwrap_or(ServingStatus::ServiceUnknown);
+                if last != Some(current) {
+                    let frame = Frame::data(encode_response(current));
+                    return Some((frame, (health, service, Some(current))));
+                }
+            }
+        },
+    );
+    streaming(updates)
+}
+
+/// Serve gRPC health on `listener` until `shutdown` turns true
+pub async fn serve(
+    listener: TcpListener,
+    health: HealthRegistry,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let graceful = GracefulShutdown::new();
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Failed to accept a gRPC connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+        let health = health.clone();
+        let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+            let health = health.clone();
+            async move { Ok::<_, Infallible>(handle(health, request).await) }
+        });
+        let connection = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+            .serve_connection(TokioIo::new(stream), service);
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("gRPC connection failed: {}", e);
+            }
+        });
+    }
+    if tokio::time::timeout(SHUTDOWN_GRACE, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::debug!("Closed gRPC streams still open after {:?}", SHUTDOWN_GRACE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::Criticality;
+    use http_body_util::Full;
+
+    fn request(path: &str, service: &str) -> Request<Full<Bytes>> {
+        let mut message = vec![0x0a, service.len() as u8];
+        message.extend_from_slice(service.as_bytes());
+        let mut body = vec![0];
+        body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        body.extend_from_slice(&message);
+        Request::post(path)
+            .header(CONTENT_TYPE, "application/grpc")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    }
+
+    async fn frames(response: Response<Body>, count: usize) -> Vec<Frame<Bytes>> {
+        let mut body = response.into_body();
+        let mut frames = Vec::new();
+        while frames.len() < count {
+            match body.frame().await {
+                Some(frame) => frames.push(frame.unwrap()),
+                None => break,
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn test_decodes_requests_and_encodes_responses() {
+        assert_eq!(decode_request(b"").unwrap(), "");
+        assert_eq!(decode_request(b"\x0a\x02db").unwrap(), "db");
+        // An unknown varint field before the service name is skipped
+        assert_eq!(decode_request(b"\x10\x96\x01\x0a\x02db").unwrap(), "db");
+        assert!(decode_request(b"\x0a\x05db").is_err());
+        assert_eq!(
+            &encode_response(ServingStatus::NotServing)[..],
+            b"\x00\x00\x00\x00\x02\x08\x02"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checks_the_server_and_single_checks() {
+        let health = HealthRegistry::default();
+        health.set_serving_state(crate::health::ServingState::Serving);
+        health.register("db", Criticality::Critical, || async { Ok(()) });
+        health.register("cache", Criticality::NonCritical, || async {
+            anyhow::bail!("down")
+        });
+
+        for (service, expected) in [("", 1), ("db", 1), ("cache", 2)] {
+            let response = handle(health.clone(), request(CHECK_PATH, service)).await;
+            let frames = frames(response, 2).await;
+            let data = frames[0].data_ref().unwrap();
+            assert_eq!(data[..], [0, 0, 0, 0, 2, 8, expected], "{}", service);
+            let trailers = frames[1].trailers_ref().unwrap();
+            assert_eq!(trailers["grpc-status"], "0");
+        }
+
+        let response = handle(health.clone(), request(CHECK_PATH, "nope")).await;
+        assert_eq!(response.headers()["grpc-status"], "5");
+        let response = handle(health.clone(), request("/grpc.health.v1.Health/List", "")).await;
+        assert_eq!(response.headers()["grpc-status"], "12");
+
+        let response = handle(health.clone(), request(WATCH_PATH, "nope")).await;
+        let frames = frames(response, 1).await;
+        assert_eq!(frames[0].data_ref().unwrap()[..], [0, 0, 0, 0, 2, 8, 3]);
+
+        health.set_serving_state(crate::health::ServingState::Draining);
+        assert_eq!(status(&health, "").await, Some(ServingStatus::NotServing));
+    }
+}
@@ -0,0 +1,651 @@
+//! Health check endpoints.
+//!
+//! `/health` is a plain liveness probe. `/health/startup` succeeds once the
+//! server has finished starting. `/health/ready` runs every check in the
+//! [`HealthRegistry`], where subsystems register named checks with a
+//! [`Criticality`]: a failing critical check makes the service unready (503),
+//! a failing non-critical one only reports it as degraded. Readiness also
+//! fails while starting and while draining before shutdown, so load
+//! balancers stop routing to the instance before its listeners close.
+//!
+//! `/health/deep` runs the same checks with a longer timeout and reports
+//! them with the instance and leadership, for the status page and external
+//! alerting rather than load balancers.
+//!
+//! Every check outcome is kept in memory for a day, and `/health/history`
+//! reports each check's availability over the last hour and day, along
+//! with whether it is flapping between healthy and failing. With
+//! `HEALTH_HISTORY_DB=true` outcomes are also stored in `health_probes`.
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+
+use crate::config::Instance;
+use crate::leader::Leadership;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::AppState;
+
+/// How long probe outcomes are kept in memory
+const HISTORY_WINDOW: chrono::Duration = chrono::Duration::hours(24);
+/// Most outcomes kept per check, bounding memory under frequent probing
+const HISTORY_LIMIT: usize = 20_000;
+/// Changes between healthy and failing within an hour that count as flapping
+const FLAP_TRANSITIONS: usize = 4;
+
+/// Route module serving the root and health endpoints
+pub struct HealthModule;
+
+impl RouteModule for HealthModule {
+    fn name(&self) -> &'static str {
+        "health"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/", get(root_handler))
+            .route("/health", get(health_check))
+            .route("/health/db", get(db_health_check))
+            .route("/health/startup", get(startup_check))
+            .route("/health/ready", get(readiness_check))
+            .route("/health/deep", get(deep_check))
+            .route("/health/history", get(history))
+    }
+}
+
+/// `GET /` - service banner
+pub async fn root_handler(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({
+        "message": "Rust Self-Host Server",
+        "status": "running",
+        "instance": state.extension::<Instance>().as_deref(),
+    }))
+}
+
+/// `GET /health` - liveness probe
+pub async fn health_check() -> StatusCode {
+    StatusCode::OK
+}
+
+/// `GET /health/db` - database connectivity probe
+pub async fn db_health_check(State(state): State<AppState>) -> StatusCode {
+    match state.db.health_check().await {
+        Ok(_) => StatusCode::OK,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// `GET /health/startup` - startup probe, failing until startup has finished
+pub async fn startup_check(State(state): State<AppState>) -> StatusCode {
+    match state.health.serving_state() {
+        ServingState::Starting => StatusCode::SERVICE_UNAVAILABLE,
+        ServingState::Serving | ServingState::Draining => StatusCode::OK,
+    }
+}
+
+/// `GET /health/ready` - readiness probe aggregating every registered check
+pub async fn readiness_check(State(state): State<AppState>) -> impl IntoResponse {
+    let report = state.health.run(CHECK_TIMEOUT).await;
+    let status = if report.status == Status::Unavailable {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(report))
+}
+
+/// `GET /health/deep` - every registered check, with the instance and leadership
+pub async fn deep_check(State(state): State<AppState>) -> impl IntoResponse {
+    let started = Instant::now();
+    let report = state.health.run(DEEP_CHECK_TIMEOUT).await;
+    let count = |status| report.checks.iter().filter(|c| c.status == status).count();
+    let body = json!({
+        "status": report.status,
+        "state": report.state,
+        "checked_at": crate::time::format(&Utc::now()),
+        "duration_ms": started.elapsed().as_millis() as u64,
+        "instance": state.extension::<Instance>().as_deref(),
+        "leader": state.extension::<Leadership>().map(|leadership| json!({
+            "backend": leadership.backend(),
+            "is_leader": leadership.is_leader(),
+        })),
+        "summary": {
+            "ok": count(Status::Ok),
+            "unavailable": count(Status::Unavailable),
+        },
+        "checks": report.checks,
+    });
+    let status = if report.status == Status::Unavailable {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(body))
+}
+
+/// `GET /health/history` - availability of every check over the last hour and day
+pub async fn history(State(state): State<AppState>) -> Json<Value> {
+    Json(json!({ "checks": state.health.history() }))
+}
+
+/// How long a single check may take before it counts as failed
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Timeout of each check in `/health/deep` and status sampling, where
+/// thoroughness matters more than a fast answer
+pub const DEEP_CHECK_TIMEOUT: Duration = Duration::from_secs(15);
+
+type CheckFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type CheckFn = Arc<dyn Fn() -> CheckFuture + Send + Sync>;
+
+/// How a failing check affects overall readiness
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Criticality {
+    /// Failure makes the service unready
+    Critical,
+    /// Failure only degrades the service
+    NonCritical,
+}
+
+/// Where the server is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServingState {
+    /// Startup has not finished yet
+    Starting,
+    /// Accepting and routing traffic
+    Serving,
+    /// Shutting down; still answering while load balancers catch up
+    Draining,
+}
+
+/// Aggregate or per-check health status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Degraded,
+    Unavailable,
+}
+
+impl Status {
+    /// The serialized name of the status
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Degraded => "degraded",
+            Status::Unavailable => "unavailable",
+        }
+    }
+}
+
+/// Result of a single check
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub criticality: Criticality,
+    pub status: Status,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Trouble ahead that does not fail the check yet, e.g. a filling disk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+/// Results of every registered check
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub status: Status,
+    pub state: ServingState,
+    pub checks: Vec<CheckResult>,
+}
+
+/// Outcome of one run of a check
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Probe {
+    ok: bool,
+    at: DateTime<Utc>,
+}
+
+/// Every check run together, as sent to [`HealthRegistry::subscribe`]
+#[derive(Debug, Clone)]
+pub struct ProbeRun {
+    pub at: DateTime<Utc>,
+    pub checks: Vec<CheckResult>,
+}
+
+/// How a check has fared recently
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CheckHistory {
+    pub name: String,
+    /// Percentage of successful probes in the last hour, if any ran
+    pub availability_1h: Option<f64>,
+    /// Percentage of successful probes in the last day, if any ran
+    pub availability_24h: Option<f64>,
+    pub probes_1h: usize,
+    pub probes_24h: usize,
+    /// Changes between healthy and failing in the last hour
+    pub transitions_1h: usize,
+    pub flapping: bool,
+    #[serde(with = "crate::time::rfc3339_option")]
+    pub last_failure: Option<DateTime<Utc>>,
+}
+
+impl CheckHistory {
+    fn summarize(name: &str, probes: &VecDeque<Probe>, now: DateTime<Utc>) -> Self {
+        let day: Vec<&Probe> = probes
+            .iter()
+            .filter(|probe| probe.at > now - HISTORY_WINDOW)
+            .collect();
+        let hour: Vec<&Probe> = day
+            .iter()
+            .copied()
+            .filter(|probe| probe.at > now - chrono::Duration::hours(1))
+            .collect();
+        let availability = |probes: &[&Probe]| {
+            (!probes.is_empty()).then(|| {
+                let ok = probes.iter().filter(|probe| probe.ok).count();
+                (10_000.0 * ok as f64 / probes.len() as f64).round() / 100.0
+            })
+        };
+        let transitions_1h = hour.windows(2).filter(|w| w[0].ok != w[1].ok).count();
+        CheckHistory {
+            name: name.to_string(),
+            availability_1h: availability(&hour),
+            availability_24h: availability(&day),
+            probes_1h: hour.len(),
+            probes_24h: day.len(),
+            transitions_1h,
+            flapping: transitions_1h >= FLAP_TRANSITIONS,
+            last_failure: day.iter().rev().find(|probe| !probe.ok).map(|p| p.at),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Check {
+    name: String,
+    criticality: Criticality,
+    run: CheckFn,
+}
+
+/// Named health checks registered by subsystems
+#[derive(Clone)]
+pub struct HealthRegistry {
+    checks: Arc<RwLock<Vec<Check>>>,
+    state: Arc<AtomicU8>,
+    history: Arc<Mutex<HashMap<String, VecDeque<Probe>>>>,
+    warnings: Arc<RwLock<HashMap<String, String>>>,
+    runs: broadcast::Sender<Arc<ProbeRun>>,
+}
+
+impl Default for HealthRegistry {
+    fn default() -> Self {
+        HealthRegistry {
+            checks: Arc::default(),
+            state: Arc::default(),
+            history: Arc::default(),
+            warnings: Arc::default(),
+            runs: broadcast::channel(64).0,
+        }
+    }
+}
+
+impl HealthRegistry {
+    /// Current lifecycle state
+    pub fn serving_state(&self) -> ServingState {
+        match self.state.load(Ordering::Acquire) {
+            0 => ServingState::Starting,
+            1 => ServingState::Serving,
+            _ => ServingState::Draining,
+        }
+    }
+
+    /// Record a lifecycle transition
+    pub fn set_serving_state(&self, state: ServingState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Register a check, replacing any existing check with the same name
+    pub fn register<F, Fut>(&self, name: impl Into<String>, criticality: Criticality, check: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let check = Check {
+            name: name.into(),
+            criticality,
+            run: Arc::new(move || Box::pin(check())),
+        };
+        let mut checks = self.checks.write().expect("health registry lock poisoned");
+        checks.retain(|c| c.name != check.name);
+        checks.push(check);
+    }
+
+    /// Set or clear the warning reported with a check's next results
+    pub fn set_warning(&self, check: &str, warning: Option<String>) {
+        let mut warnings = self
+            .warnings
+            .write()
+            .expect("health registry lock poisoned");
+        match warning {
+            Some(warning) => warnings.insert(check.to_string(), warning),
+            None => warnings.remove(check),
+        };
+    }
+
+    /// Run every check concurrently, each bounded by `timeout`
+    pub async fn run(&self, timeout: Duration) -> HealthReport {
+        let checks = self
+            .checks
+            .read()
+            .expect("health registry lock poisoned")
+            .clone();
+
+        let mut tasks = JoinSet::new();
+        let mut spawned = HashMap::new();
+        for (index, check) in checks.into_iter().enumerate() {
+            let task = (index, check.name.clone(), check.criticality);
+            let handle = tasks.spawn(async move {
+                let started = Instant::now();
+                let outcome = match tokio::time::timeout(timeout, (check.run)()).await {
+                    Ok(outcome) => outcome,
+                    Err(_) => Err(anyhow::anyhow!("timed out after {:?}", timeout)),
+                };
+                let result = CheckResult {
+                    name: check.name,
+                    criticality: check.criticality,
+                    status: if outcome.is_ok() {
+                        Status::Ok
+                    } else {
+                        Status::Unavailable
+                    },
+                    latency_ms: started.elapsed().as_millis() as u64,
+                    error: outcome.err().map(|e| format!("{:#}", e)),
+                    warning: None,
+                };
+                (index, result)
+            });
+            spawned.insert(handle.id(), task);
+        }
+
+        let mut results = Vec::new();
+        while let Some(joined) = tasks.join_next_with_id().await {
+            match joined {
+                Ok((_, result)) => results.push(result),
+                Err(e) => {
+                    // A panicking check counts as failed rather than vanishing
+                    let Some((index, name, criticality)) = spawned.remove(&e.id()) else {
+                        continue;
+                    };
+                    results.push((
+                        index,
+                        CheckResult {
+                            name,
+                            criticality,
+                            status: Status::Unavailable,
+                            latency_ms: 0,
+                            error: Some("check panicked".to_string()),
+                            warning: None,
+                        },
+                    ));
+                }
+            }
+        }
+        results.sort_by_key(|(index, _)| *index);
+        let warnings = self.warnings.read().expect("health registry lock poisoned");
+        let checks: Vec<CheckResult> = results
+            .into_iter()
+            .map(|(_, result)| CheckResult {
+                warning: warnings.get(&result.name).cloned(),
+                ..result
+            })
+            .collect();
+        drop(warnings);
+        self.record(&checks, Utc::now());
+
+        let failed = |criticality| {
+            checks
+                .iter()
+                .any(|c| c.criticality == criticality && c.status != Status::Ok)
+        };
+        let state = self.serving_state();
+        let status = if state != ServingState::Serving || failed(Criticality::Critical) {
+            Status::Unavailable
+        } else if failed(Criticality::NonCritical) {
+            Status::Degraded
+        } else {
+            Status::Ok
+        };
+        HealthReport {
+            status,
+            state,
+            checks,
+        }
+    }
+
+    /// Receive the outcome of every run from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ProbeRun>> {
+        self.runs.subscribe()
+    }
+
+    /// Recent history of every check, by name
+    pub fn history(&self) -> Vec<CheckHistory> {
+        let now = Utc::now();
+        let history = self.history.lock().expect("health history lock poisoned");
+        let mut checks: Vec<CheckHistory> = history
+            .iter()
+            .map(|(name, probes)| CheckHistory::summarize(name, probes, now))
+            .collect();
+        checks.sort_by(|a, b| a.name.cmp(&b.name));
+        checks
+    }
+
+    fn record(&self, checks: &[CheckResult], at: DateTime<Utc>) {
+        {
+            let mut history = self.history.lock().expect("health history lock poisoned");
+            for check in checks {
+                let probes = history.entry(check.name.clone()).or_default();
+                probes.push_back(Probe {
+                    ok: check.status == Status::Ok,
+                    at,
+                });
+                while probes.len() > HISTORY_LIMIT
+                    || probes.front().is_some_and(|p| p.at < at - HISTORY_WINDOW)
+                {
+                    probes.pop_front();
+                }
+            }
+        }
+        if self.runs.receiver_count() > 0 {
+            let _ = self.runs.send(Arc::new(ProbeRun {
+                at,
+                checks: checks.to_vec(),
+            }));
+        }
+    }
+}
+
+/// Route module storing probe outcomes in Postgres, mounted with
+/// `HEALTH_HISTORY_DB`
+pub struct HealthHistoryModule;
+
+impl RouteModule for HealthHistoryModule {
+    fn name(&self) -> &'static str {
+        "health_history"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_health_probes",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS health_probes (
+                check_name TEXT NOT NULL,
+                status TEXT NOT NULL,
+                latency_ms BIGINT NOT NULL,
+                error TEXT,
+                instance TEXT NOT NULL,
+                probed_at TIMESTAMPTZ NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS health_probes_probed_at
+                ON health_probes (check_name, probed_at)",
+        }]
+    }
+}
+
+/// Store every probe outcome of `health` in `health_probes`
+pub fn spawn_recorder(
+    pool: PgPool,
+    health: &HealthRegistry,
+    instance: String,
+) -> tokio::task::JoinHandle<()> {
+    let mut runs = health.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let run = match runs.recv().await {
+                Ok(run) => run,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Dropped {} health probe runs from the history", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let stored = sqlx::query(
+                "INSERT INTO health_probes (check_name, status, latency_ms, error, instance, probed_at)
+                 SELECT name, status, latency_ms, error, $5, $6
+                 FROM UNNEST($1::text[], $2::text[], $3::bigint[], $4::text[])
+                     AS t(name, status, latency_ms, error)",
+            )
+            .bind(run.checks.iter().map(|c| c.name.clone()).collect::<Vec<_>>())
+            .bind(
+                run.checks
+                    .iter()
+                    .map(|c| c.status.as_str())
+                    .collect::<Vec<_>>(),
+            )
+            .bind(
+                run.checks
+                    .iter()
+                    .map(|c| c.latency_ms as i64)
+                    .collect::<Vec<_>>(),
+            )
+            .bind(run.checks.iter().map(|c| c.error.clone()).collect::<Vec<_>>())
+            .bind(&instance)
+            .bind(run.at)
+            .execute(&pool)
+            .await;
+            if let Err(e) = stored {
+                tracing::warn!("Failed to store health probes: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_report_status_follows_criticality() {
+        let registry = HealthRegistry::default();
+        registry.set_serving_state(ServingState::Serving);
+        registry.register("db", Criticality::Critical, || async { Ok(()) });
+        registry.register("smtp", Criticality::NonCritical, || async {
+            anyhow::bail!("connection refused")
+        });
+
+        let report = registry.run(Duration::from_secs(1)).await;
+        assert_eq!(report.status, Status::Degraded);
+        assert_eq!(report.checks[0].name, "db");
+        assert_eq!(
+            report.checks[1].error.as_deref(),
+            Some("connection refused")
+        );
+
+        registry.register("db", Criticality::Critical, || async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        });
+        let report = registry.run(Duration::from_millis(10)).await;
+        assert_eq!(report.status, Status::Unavailable);
+        assert_eq!(report.checks.len(), 2);
+
+        registry.register("db", Criticality::Critical, || async { panic!("bug") });
+        let report = registry.run(Duration::from_secs(1)).await;
+        assert_eq!(report.status, Status::Unavailable);
+        assert_eq!(report.checks[1].name, "db");
+        assert_eq!(report.checks[1].error.as_deref(), Some("check panicked"));
+    }
+
+    #[test]
+    fn test_history_reports_availability_and_flapping() {
+        let registry = HealthRegistry::default();
+        let now = Utc::now();
+        let result = |ok: bool| CheckResult {
+            name: "cache".into(),
+            criticality: Criticality::NonCritical,
+            status: if ok { Status::Ok } else { Status::Unavailable },
+            latency_ms: 1,
+            error: None,
+            warning: None,
+        };
+        registry.record(&[result(false)], now - chrono::Duration::hours(30));
+        registry.record(&[result(true)], now - chrono::Duration::hours(3));
+        for (minutes, ok) in [(50, true), (40, false), (30, true), (20, false), (10, true)] {
+            registry.record(&[result(ok)], now - chrono::Duration::minutes(minutes));
+        }
+
+        let history = &registry.history()[0];
+        assert_eq!(history.probes_1h, 5);
+        assert_eq!(history.probes_24h, 6);
+        assert_eq!(history.availability_1h, Some(60.0));
+        assert_eq!(history.availability_24h, Some(66.67));
+        assert_eq!(history.transitions_1h, 4);
+        assert!(history.flapping);
+        assert_eq!(
+            history.last_failure,
+            Some(now - chrono::Duration::minutes(20))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_while_draining() {
+        let registry = HealthRegistry::default();
+        assert_eq!(registry.serving_state(), ServingState::Starting);
+        registry.set_serving_state(ServingState::Draining);
+
+        let report = registry.run(Duration::from_secs(1)).await;
+        assert_eq!(report.state, ServingState::Draining);
+        assert_eq!(report.status, Status::Unavailable);
+    }
+}
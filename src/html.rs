@@ -0,0 +1,164 @@
+//! HTML helpers for rendered pages and fetched documents.
+//!
+//! This is not a parser. Fetched pages and imported bookmark files are
+//! scanned with patterns that reliably pick tags and attributes out of
+//! well-formed markup, which is all that metadata extraction needs; the
+//! results are plain text and must be escaped again before rendering.
+
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Append `text` to `out` with HTML special characters escaped
+pub fn escape_into(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// `text` with HTML special characters escaped
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    escape_into(text, &mut out);
+    out
+}
+
+/// Decode character references such as `&amp;`, `&#39;` and `&#x2014;`
+///
+/// Only the named references that commonly appear in titles and attribute
+/// values are known; others are left as they are.
+pub fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| {
+                let name = &rest[1..=end];
+                let c = match name {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "nbsp" => Some('\u{a0}'),
+                    _ => match name.strip_prefix('#') {
+                        Some(hex) if hex.starts_with(['x', 'X']) => {
+                            u32::from_str_radix(&hex[1..], 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                        }
+                        Some(decimal) => decimal.parse().ok().and_then(char::from_u32),
+                        None => None,
+                    },
+                };
+                c.map(|c| (c, end + 2))
+            });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Collapse runs of whitespace into single spaces
+fn squash(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Attributes of a tag, with lowercase names and decoded values
+pub type Attributes = HashMap<String, String>;
+
+/// Parse the attributes inside a start tag, e.g. `href="/a" rel=icon`
+pub fn attributes(tag: &str) -> Attributes {
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    let pattern = ATTRIBUTE.get_or_init(|| {
+        Regex::new(r#"([A-Za-z_:][-A-Za-z0-9_:.]*)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'=<>`]+))"#)
+            .expect("valid pattern")
+    });
+    pattern
+        .captures_iter(tag)
+        .map(|captures| {
+            let value = captures
+                .get(2)
+                .or_else(|| captures.get(3))
+                .or_else(|| captures.get(4))
+                .map_or("", |m| m.as_str());
+            (captures[1].to_ascii_lowercase(), unescape(value))
+        })
+        .collect()
+}
+
+/// Attributes of every `<name ...>` start tag in a document
+pub fn tags(html: &str, name: &str) -> Vec<Attributes> {
+    let pattern =
+        Regex::new(&format!(r"(?i)<{}\b([^>]*)>", regex::escape(name))).expect("valid pattern");
+    pattern
+        .captures_iter(html)
+        .map(|captures| attributes(&captures[1]))
+        .collect()
+}
+
+/// The text of a document's `<title>`
+pub fn title(html: &str) -> Option<String> {
+    static TITLE: OnceLock<Regex> = OnceLock::new();
+    let pattern = TITLE
+        .get_or_init(|| Regex::new(r"(?is)<title\b[^>]*>(.*?)</title>").expect("valid pattern"));
+    let title = squash(&unescape(pattern.captures(html)?.get(1)?.as_str()));
+    (!title.is_empty()).then_some(title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_and_unescape() {
+        let text = r#"<a href="x">Tom & Jerry's</a>"#;
+        assert_eq!(
+            escape(text),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(unescape(&escape(text)), text);
+        assert_eq!(
+            unescape("a &#x2014; b &#8212; c"),
+            "a \u{2014} b \u{2014} c"
+        );
+        assert_eq!(unescape("AT&T &unknown; &"), "AT&T &unknown; &");
+    }
+
+    #[test]
+    fn test_title_and_tags() {
+        let html = r#"<html><head>
+            <TITLE>
+              Rust &amp; Axum
+            </TITLE>
+            <link rel="icon" href='/favicon.png'>
+            <LINK REL=stylesheet HREF=/site.css>
+            </head></html>"#;
+        assert_eq!(title(html).as_deref(), Some("Rust & Axum"));
+        let links = tags(html, "link");
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0]["href"], "/favicon.png");
+        assert_eq!(links[1]["rel"], "stylesheet");
+        assert_eq!(title("<title> </title>"), None);
+    }
+}
@@ -0,0 +1,104 @@
+//! Minimal outbound HTTP client.
+//!
+//! Speaks plain HTTP/1.1 only. It is meant for talking to local agents and
+//! sidecars (a Consul agent, `kubectl proxy`) over loopback or a private
+//! network, not to arbitrary internet hosts.
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    Method, Request, StatusCode,
+};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use serde_json::Value;
+use std::time::Duration;
+
+/// JSON-over-HTTP client with a per-request timeout
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client<HttpConnector, Full<Bytes>>,
+    timeout: Duration,
+    authorization: Option<HeaderValue>,
+}
+
+impl HttpClient {
+    pub fn new(timeout: Duration) -> Self {
+        HttpClient {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            timeout,
+            authorization: None,
+        }
+    }
+
+    /// A client sending `Authorization: Bearer <token>` with every request
+    pub fn bearer(&self, token: &str) -> Result<Self> {
+        let mut client = self.clone();
+        client.authorization = Some(
+            HeaderValue::from_str(&format!("Bearer {}", token)).context("Invalid bearer token")?,
+        );
+        Ok(client)
+    }
+
+    /// Send a request with an optional JSON body and return the raw response
+    pub async fn request(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&Value>,
+    ) -> Result<(StatusCode, Bytes)> {
+        anyhow::ensure!(
+            url.starts_with("http://"),
+            "Only plain http:// URLs are supported, got '{}'",
+            url
+        );
+        let mut request = Request::builder().method(method).uri(url);
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let body = match body {
+            Some(body) => {
+                request = request.header(CONTENT_TYPE, "application/json");
+                Bytes::from(serde_json::to_vec(body)?)
+            }
+            None => Bytes::new(),
+        };
+        let (status, _, body) = self.send(request.body(Full::new(body))?).await?;
+        Ok((status, body))
+    }
+
+    /// Send a prepared request and return the raw response with its headers
+    pub async fn send(
+        &self,
+        request: Request<Full<Bytes>>,
+    ) -> Result<(StatusCode, HeaderMap, Bytes)> {
+        let (method, url) = (request.method().clone(), request.uri().to_string());
+        tokio::time::timeout(self.timeout, async {
+            let response = self.client.request(request).await?;
+            let (parts, body) = response.into_parts();
+            let body = body.collect().await?.to_bytes();
+            anyhow::Ok((parts.status, parts.headers, body))
+        })
+        .await
+        .with_context(|| format!("{} {} timed out after {:?}", method, url, self.timeout))?
+        .with_context(|| format!("{} {} failed", method, url))
+    }
+
+    /// Send a request and parse the response body as JSON (`null` if empty)
+    pub async fn json(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&Value>,
+    ) -> Result<(StatusCode, Value)> {
+        let (status, bytes) = self.request(method, url, body).await?;
+        let value = if bytes.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&bytes).with_context(|| format!("Invalid JSON from {}", url))?
+        };
+        Ok((status, value))
+    }
+}
@@ -0,0 +1,307 @@
+//! Localized API messages.
+//!
+//! Messages are [Fluent](https://projectfluent.org) catalogs, one directory
+//! per language holding `.ftl` files. English and German are built in;
+//! files in `LOCALES_DIR/<language>/` add languages or override built-in
+//! messages, so a self-hoster can translate `locales/en/messages.ftl` into
+//! `LOCALES_DIR/fr/messages.ftl` without rebuilding.
+//!
+//! The [`middleware`] picks each request's language from `Accept-Language`
+//! among the available ones, falling back to `DEFAULT_LOCALE`, and answers
+//! with a matching `Content-Language`. Code handling the request formats
+//! messages in that language with [`t!`](crate::t); outside a request,
+//! e.g. in the CLI, the default language is used.
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::Request,
+    http::{
+        header::{ACCEPT_LANGUAGE, CONTENT_LANGUAGE},
+        HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+use fluent_bundle::{concurrent::FluentBundle, FluentResource};
+use fluent_langneg::{negotiate_languages, NegotiationStrategy};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+pub use fluent_bundle::FluentArgs;
+pub use unic_langid::LanguageIdentifier;
+
+/// Catalogs shipped with the binary
+const BUILTIN: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en/messages.ftl")),
+    ("de", include_str!("../locales/de/messages.ftl")),
+];
+
+tokio::task_local! {
+    static LOCALE: LanguageIdentifier;
+}
+
+/// Translations of every available language
+pub struct Catalog {
+    default: LanguageIdentifier,
+    bundles: HashMap<LanguageIdentifier, FluentBundle<FluentResource>>,
+}
+
+impl std::fmt::Debug for Catalog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Catalog")
+            .field("default", &self.default)
+            .field("languages", &self.bundles.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn parse_resource(source: String, origin: &str) -> Result<FluentResource> {
+    FluentResource::try_new(source).map_err(|(_, errors)| {
+        anyhow::anyhow!(
+            "{}: {}",
+            origin,
+            errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")
+        )
+    })
+}
+
+impl Catalog {
+    /// The built-in catalogs plus those in `dir`, if any
+    pub fn load(dir: Option<&Path>, default: LanguageIdentifier) -> Result<Self> {
+        let mut catalog = Catalog {
+            default,
+            bundles: HashMap::new(),
+        };
+        for (language, source) in BUILTIN {
+            let language = language.parse().expect("valid built-in language");
+            catalog.add(language, parse_resource(source.to_string(), "built-in")?);
+        }
+        if let Some(dir) = dir {
+            let entries = std::fs::read_dir(dir)
+                .with_context(|| format!("Failed to read {}", dir.display()))?;
+            for entry in entries {
+                let path = entry?.path();
+                if !path.is_dir() {
+                    continue;
+                }
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let language: LanguageIdentifier = name
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("'{}' is not a language tag: {}", name, e))?;
+                let mut files: Vec<_> = std::fs::read_dir(&path)?
+                    .map(|entry| entry.map(|e| e.path()))
+                    .collect::<std::io::Result<_>>()?;
+                files.retain(|file| file.extension().is_some_and(|ext| ext == "ftl"));
+                files.sort();
+                for file in files {
+                    let source = std::fs::read_to_string(&file)
+                        .with_context(|| format!("Failed to read {}", file.display()))?;
+                    let origin = file.display().to_string();
+                    catalog.add(language.clone(), parse_resource(source, &origin)?);
+                }
+            }
+        }
+        if !catalog.bundles.contains_key(&catalog.default) {
+            anyhow::bail!("no messages for the default locale '{}'", catalog.default);
+        }
+        Ok(catalog)
+    }
+
+    fn add(&mut self, language: LanguageIdentifier, resource: FluentResource) {
+        let bundle = self.bundles.entry(language.clone()).or_insert_with(|| {
+            let mut bundle = FluentBundle::new_concurrent(vec![language]);
+            // Messages end up in JSON, where bidi isolation marks are noise
+            bundle.set_use_isolating(false);
+            bundle
+        });
+        bundle.add_resource_overriding(resource);
+    }
+
+    /// Languages with messages, the default first
+    pub fn languages(&self) -> Vec<&LanguageIdentifier> {
+        let mut languages: Vec<_> = self.bundles.keys().collect();
+        languages.sort_by_key(|l| (**l != self.default, l.to_string()));
+        languages
+    }
+
+    /// The best available language for an `Accept-Language` header
+    pub fn negotiate(&self, accept_language: Option<&str>) -> LanguageIdentifier {
+        let requested = fluent_langneg::accepted_languages::parse(accept_language.unwrap_or(""));
+        let available = self.languages();
+        let available: Vec<_> = available.into_iter().cloned().collect();
+        negotiate_languages(
+            &requested,
+            &available,
+            Some(&self.default),
+            NegotiationStrategy::Lookup,
+        )
+        .first()
+        .map_or_else(|| self.default.clone(), |l| (*l).clone())
+    }
+
+    /// Format message `id` in `language`, falling back to the default language
+    pub fn format(
+        &self,
+        language: &LanguageIdentifier,
+        id: &str,
+        args: Option<&FluentArgs>,
+    ) -> String {
+        [language, &self.default]
+            .into_iter()
+            .filter_map(|language| self.bundles.get(language))
+            .find_map(|bundle| {
+                let pattern = bundle.get_message(id)?.value()?;
+                let mut errors = Vec::new();
+                let text = bundle.format_pattern(pattern, args, &mut errors);
+                if !errors.is_empty() {
+                    tracing::warn!("Errors formatting message '{}': {:?}", id, errors);
+                }
+                Some(text.into_owned())
+            })
+            .unwrap_or_else(|| {
+                tracing::warn!("Missing message '{}'", id);
+                id.to_string()
+            })
+    }
+}
+
+static CATALOG: RwLock<Option<Arc<Catalog>>> = RwLock::new(None);
+
+/// Use `catalog` for every message from now on
+pub fn install(catalog: Catalog) {
+    *CATALOG.write().expect("catalog lock poisoned") = Some(Arc::new(catalog));
+}
+
+/// The installed catalog, or the built-in one
+pub fn catalog() -> Arc<Catalog> {
+    if let Some(catalog) = CATALOG.read().expect("catalog lock poisoned").as_ref() {
+        return catalog.clone();
+    }
+    let mut installed = CATALOG.write().expect("catalog lock poisoned");
+    installed
+        .get_or_insert_with(|| {
+            let english = "en".parse().expect("valid language");
+            Arc::new(Catalog::load(None, english).expect("valid built-in catalogs"))
+        })
+        .clone()
+}
+
+/// The language of the request being handled, or the default one
+pub fn locale() -> LanguageIdentifier {
+    LOCALE
+        .try_with(Clone::clone)
+        .unwrap_or_else(|_| catalog().default.clone())
+}
+
+/// Format message `id` in the current request's language
+pub fn message(id: &str, args: Option<&FluentArgs>) -> String {
+    catalog().format(&locale(), id, args)
+}
+
+/// Format a message in the current request's language
+///
+/// ```
+/// use rust_selfhost_server::t;
+///
+/// assert_eq!(t!("document-not-found"), "document not found");
+/// assert_eq!(t!("user-exists", username = "alice"), "user 'alice' already exists");
+/// ```
+#[macro_export]
+macro_rules! t {
+    ($id:expr) => {
+        $crate::i18n::message($id, None)
+    };
+    ($id:expr, $($name:ident = $value:expr),+ $(,)?) => {{
+        let mut args = $crate::i18n::FluentArgs::new();
+        $(args.set(stringify!($name), $value);)+
+        $crate::i18n::message($id, Some(&args))
+    }};
+}
+
+/// Middleware choosing each request's language from `Accept-Language`
+pub async fn middleware(request: Request, next: Next) -> Response {
+    let locale = catalog().negotiate(
+        request
+            .headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let language = HeaderValue::from_str(&locale.to_string()).ok();
+    let mut response = LOCALE.scope(locale, next.run(request)).await;
+    if let Some(language) = language {
+        response
+            .headers_mut()
+            .entry(CONTENT_LANGUAGE)
+            .or_insert(language);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_and_format() {
+        let catalog = Catalog::load(None, "en".parse().unwrap()).unwrap();
+        let de: LanguageIdentifier = "de".parse().unwrap();
+        assert_eq!(catalog.negotiate(Some("de-AT, en;q=0.5")), de);
+        assert_eq!(catalog.negotiate(Some("fr;q=0.9, de;q=0.8")), de);
+        assert_eq!(catalog.negotiate(Some("ja")).to_string(), "en");
+        assert_eq!(catalog.negotiate(None).to_string(), "en");
+
+        let mut args = FluentArgs::new();
+        args.set("min", 8);
+        assert_eq!(
+            catalog.format(&de, "password-length", Some(&args)),
+            "das Passwort muss mindestens 8 Zeichen lang sein"
+        );
+        assert_eq!(
+            catalog.format(&de, "no-such-message", None),
+            "no-such-message"
+        );
+    }
+
+    #[test]
+    fn test_catalog_directory() {
+        let dir = std::env::temp_dir().join(format!("locales-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("fr")).unwrap();
+        std::fs::create_dir_all(dir.join("en")).unwrap();
+        std::fs::write(
+            dir.join("fr/messages.ftl"),
+            "document-not-found = document introuvable\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("en/custom.ftl"),
+            "document-not-found = no such doc\n",
+        )
+        .unwrap();
+
+        let catalog = Catalog::load(Some(&dir), "en".parse().unwrap()).unwrap();
+        let fr = catalog.negotiate(Some("fr-CA"));
+        assert_eq!(fr.to_string(), "fr");
+        assert_eq!(
+            catalog.format(&fr, "document-not-found", None),
+            "document introuvable"
+        );
+        // Untranslated messages fall back to the default language
+        assert_eq!(
+            catalog.format(&fr, "api-key-expired", None),
+            "API key has expired"
+        );
+        assert_eq!(
+            catalog.format(&"en".parse().unwrap(), "document-not-found", None),
+            "no such doc"
+        );
+
+        std::fs::write(dir.join("fr/broken.ftl"), "= nope").unwrap();
+        assert!(Catalog::load(Some(&dir), "en".parse().unwrap()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+//! Instance information endpoint.
+
+use axum::{extract::State, response::Json, routing::get, Router};
+use serde_json::{json, Value};
+
+use crate::config::Instance;
+use crate::leader::Leadership;
+use crate::module::RouteModule;
+use crate::AppState;
+
+/// Route module serving `/api/v1/info`
+pub struct InfoModule;
+
+impl RouteModule for InfoModule {
+    fn name(&self) -> &'static str {
+        "info"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/api/v1/info", get(info))
+    }
+}
+
+/// `GET /api/v1/info` - version, instance labels and leadership status
+pub async fn info(State(state): State<AppState>) -> Json<Value> {
+    let leadership = state.extension::<Leadership>();
+    Json(json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "instance": state.extension::<Instance>().as_deref(),
+        "leader": leadership.as_deref().map(|l| json!({
+            "backend": l.backend(),
+            "is_leader": l.is_leader(),
+        })),
+    }))
+}
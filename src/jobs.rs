@@ -0,0 +1,314 @@
+//! Job queue statistics.
+//!
+//! Background work waits in database tables until a worker claims it; today
+//! that is the bookmark fetch queue (`bookmark_fetch`). For every job type
+//! the depth of its queue and the age of its oldest waiting job are read
+//! from the database, and the jobs workers finish and fail are counted in
+//! process, so operators can alert on a backlog that keeps growing:
+//!
+//! - `GET /admin/jobs/stats` reports them as JSON, with the jobs processed
+//!   per minute and the share of them that failed over the last
+//!   [`WINDOW`] minutes
+//! - `GET /admin/jobs/metrics` reports them in the OpenMetrics text format
+//!   for Prometheus-compatible scrapers, with running totals instead of
+//!   rates
+//!
+//! Counts are per instance and start over on restart; queue depth and age
+//! are shared by every replica.
+
+use axum::{
+    extract::State,
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::bookmarks;
+use crate::error::ApiResult;
+use crate::extensions::Ext;
+use crate::module::{RouteGroup, RouteModule};
+use crate::AppState;
+
+/// Minutes of finished jobs the rates cover
+pub const WINDOW: u64 = 5;
+
+/// Content type of the OpenMetrics text format
+const OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Jobs waiting in a queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct QueueReading {
+    pub depth: i64,
+    /// Seconds the oldest waiting job has been queued, if any is waiting
+    pub oldest_age_secs: Option<f64>,
+}
+
+/// Jobs finished within one minute
+#[derive(Debug, Clone, Copy, Default)]
+struct Minute {
+    /// Minutes since the stats were created
+    at: u64,
+    processed: u64,
+    failed: u64,
+}
+
+/// Finished jobs of one type
+#[derive(Debug, Clone, Default)]
+struct Counts {
+    minutes: VecDeque<Minute>,
+    processed_total: u64,
+    failed_total: u64,
+}
+
+/// Statistics of one job type
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JobTypeStats {
+    #[serde(flatten)]
+    pub queue: QueueReading,
+    /// Jobs finished per minute over the window, failed ones included
+    pub processed_per_minute: f64,
+    /// Share of jobs finished over the window that failed, from 0 to 1
+    pub failure_rate: f64,
+    pub processed_total: u64,
+    pub failed_total: u64,
+}
+
+/// Finished-job counters shared with the workers
+#[derive(Clone)]
+pub struct JobStats {
+    started: Instant,
+    counts: Arc<Mutex<BTreeMap<&'static str, Counts>>>,
+}
+
+impl Default for JobStats {
+    fn default() -> Self {
+        JobStats {
+            started: Instant::now(),
+            counts: Arc::default(),
+        }
+    }
+}
+
+impl JobStats {
+    /// Report a job type even before any of its jobs finished
+    pub fn register(&self, job_type: &'static str) {
+        self.counts
+            .lock()
+            .expect("job stats lock poisoned")
+            .entry(job_type)
+            .or_default();
+    }
+
+    /// Count a finished job
+    pub fn record(&self, job_type: &'static str, succeeded: bool) {
+        self.record_at(job_type, succeeded, self.minute());
+    }
+
+    fn record_at(&self, job_type: &'static str, succeeded: bool, minute: u64) {
+        let mut counts = self.counts.lock().expect("job stats lock poisoned");
+        let counts = counts.entry(job_type).or_default();
+        counts.processed_total += 1;
+        counts.failed_total += u64::from(!succeeded);
+        if counts.minutes.back().map(|m| m.at) != Some(minute) {
+            counts.minutes.push_back(Minute {
+                at: minute,
+                ..Minute::default()
+            });
+            while counts.minutes.len() as u64 > WINDOW {
+                counts.minutes.pop_front();
+            }
+        }
+        let current = counts.minutes.back_mut().expect("a minute was just pushed");
+        current.processed += 1;
+        current.failed += u64::from(!succeeded);
+    }
+
+    fn minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / 60
+    }
+
+    /// Statistics of every registered job type, with its queue read from `pool`
+    pub async fn stats(&self, pool: &PgPool) -> ApiResult<BTreeMap<&'static str, JobTypeStats>> {
+        let mut stats = self.counted_at(self.minute());
+        for (job_type, stats) in stats.iter_mut() {
+            stats.queue = read_queue(pool, job_type).await?;
+        }
+        Ok(stats)
+    }
+
+    /// Rates over the window ending at `minute`, and totals, per job type
+    fn counted_at(&self, minute: u64) -> BTreeMap<&'static str, JobTypeStats> {
+        let counts = self.counts.lock().expect("job stats lock poisoned");
+        counts
+            .iter()
+            .map(|(job_type, counts)| {
+                let (processed, failed) = counts
+                    .minutes
+                    .iter()
+                    .filter(|m| m.at + WINDOW > minute)
+                    .fold((0, 0), |(p, f), m| (p + m.processed, f + m.failed));
+                let failure_rate = match processed {
+                    0 => 0.0,
+                    processed => failed as f64 / processed as f64,
+                };
+                let stats = JobTypeStats {
+                    queue: QueueReading::default(),
+                    processed_per_minute: processed as f64 / WINDOW as f64,
+                    failure_rate,
+                    processed_total: counts.processed_total,
+                    failed_total: counts.failed_total,
+                };
+                (*job_type, stats)
+            })
+            .collect()
+    }
+}
+
+/// Read the queue of a job type
+async fn read_queue(pool: &PgPool, job_type: &str) -> sqlx::Result<QueueReading> {
+    match job_type {
+        bookmarks::FETCH_JOB => bookmarks::queue_reading(pool).await,
+        _ => Ok(QueueReading::default()),
+    }
+}
+
+/// Render statistics in the OpenMetrics text format
+pub fn openmetrics(stats: &BTreeMap<&'static str, JobTypeStats>) -> String {
+    let mut out = String::new();
+    let mut family =
+        |name: &str, kind: &str, help: &str, value: &dyn Fn(&JobTypeStats) -> Option<String>| {
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let sample = if kind == "counter" {
+                format!("{}_total", name)
+            } else {
+                name.to_string()
+            };
+            for (job_type, stats) in stats {
+                if let Some(value) = value(stats) {
+                    let _ = writeln!(out, "{}{{type=\"{}\"}} {}", sample, job_type, value);
+                }
+            }
+        };
+    family("jobs_queue_depth", "gauge", "Jobs waiting to run.", &|s| {
+        Some(s.queue.depth.to_string())
+    });
+    family(
+        "jobs_oldest_age_seconds",
+        "gauge",
+        "Seconds the oldest waiting job has been queued.",
+        &|s| s.queue.oldest_age_secs.map(|age| age.to_string()),
+    );
+    family(
+        "jobs_processed",
+        "counter",
+        "Jobs finished, failed ones included.",
+        &|s| Some(s.processed_total.to_string()),
+    );
+    family("jobs_failed", "counter", "Jobs that failed.", &|s| {
+        Some(s.failed_total.to_string())
+    });
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Route module serving `/admin/jobs/*`
+pub struct JobsModule;
+
+impl RouteModule for JobsModule {
+    fn name(&self) -> &'static str {
+        "jobs"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/admin/jobs/stats", get(job_stats))
+            .route("/admin/jobs/metrics", get(job_metrics))
+    }
+}
+
+/// `GET /admin/jobs/stats` - queue depth, age and rates per job type
+pub async fn job_stats(
+    State(state): State<AppState>,
+    Ext(jobs): Ext<JobStats>,
+) -> ApiResult<Json<BTreeMap<&'static str, JobTypeStats>>> {
+    Ok(Json(jobs.stats(state.db.pool()).await?))
+}
+
+/// `GET /admin/jobs/metrics` - the same in the OpenMetrics text format
+pub async fn job_metrics(
+    State(state): State<AppState>,
+    Ext(jobs): Ext<JobStats>,
+) -> ApiResult<Response> {
+    let stats = jobs.stats(state.db.pool()).await?;
+    Ok(([(CONTENT_TYPE, OPENMETRICS)], openmetrics(&stats)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates_cover_the_window() {
+        let jobs = JobStats::default();
+        jobs.register("idle");
+        jobs.record_at("fetch", true, 0);
+        jobs.record_at("fetch", false, 0);
+        for _ in 0..8 {
+            jobs.record_at("fetch", true, 3);
+        }
+
+        let counted = jobs.counted_at(3);
+        assert_eq!(counted["fetch"].processed_per_minute, 2.0);
+        assert_eq!(counted["fetch"].failure_rate, 0.1);
+        assert_eq!(counted["idle"].processed_total, 0);
+        let counted = jobs.counted_at(5);
+        assert_eq!(
+            counted["fetch"].processed_per_minute, 1.6,
+            "minute 0 left the window"
+        );
+        assert_eq!(counted["fetch"].failure_rate, 0.0);
+        assert_eq!(
+            (
+                counted["fetch"].processed_total,
+                counted["fetch"].failed_total
+            ),
+            (10, 1)
+        );
+    }
+
+    #[test]
+    fn test_openmetrics() {
+        let stats = BTreeMap::from([(
+            "bookmark_fetch",
+            JobTypeStats {
+                queue: QueueReading {
+                    depth: 3,
+                    oldest_age_secs: Some(42.5),
+                },
+                processed_per_minute: 1.0,
+                failure_rate: 0.0,
+                processed_total: 7,
+                failed_total: 2,
+            },
+        )]);
+        let text = openmetrics(&stats);
+        assert!(text.contains("# TYPE jobs_queue_depth gauge\n"));
+        assert!(text.contains("jobs_queue_depth{type=\"bookmark_fetch\"} 3\n"));
+        assert!(text.contains("jobs_oldest_age_seconds{type=\"bookmark_fetch\"} 42.5\n"));
+        assert!(text.contains("# TYPE jobs_failed counter\n"));
+        assert!(text.contains("jobs_failed_total{type=\"bookmark_fetch\"} 2\n"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,281 @@
+//! RS256 JSON Web Tokens.
+//!
+//! Signing keys are 2048-bit RSA keys stored PKCS#8-encoded in the
+//! `signing_keys` table, encrypted when `ENCRYPTION_KEYS` is set, so every
+//! replica signs with the same key and tokens survive restarts. Public keys are published as a JWK set.
+//!
+//! Keys are rotated by [`rotate_keys`]: the newest key signs, and the keys
+//! it replaced keep verifying until their `expires_at`, after which they
+//! are deleted.
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rsa::pkcs1v15::{Signature, SigningKey as RsaSigner, VerifyingKey};
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use std::time::Duration;
+
+use crate::encryption::Encrypted;
+
+/// An RSA key used to sign tokens
+#[derive(Clone)]
+pub struct SigningKey {
+    kid: String,
+    key: RsaPrivateKey,
+}
+
+impl SigningKey {
+    /// Generate a new key; slow, so call it from a blocking task
+    pub fn generate() -> Result<Self> {
+        let key = RsaPrivateKey::new(&mut rand_core::OsRng, 2048)?;
+        Ok(Self::from_key(key))
+    }
+
+    fn from_key(key: RsaPrivateKey) -> Self {
+        // The key ID is a digest of the public modulus, stable across restarts
+        let digest = Sha256::digest(key.n().to_bytes_be());
+        SigningKey {
+            kid: URL_SAFE_NO_PAD.encode(&digest[..12]),
+            key,
+        }
+    }
+
+    /// Decode a PKCS#8 PEM key
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        let key = RsaPrivateKey::from_pkcs8_pem(pem).context("Invalid signing key")?;
+        Ok(Self::from_key(key))
+    }
+
+    /// Encode the key as PKCS#8 PEM
+    pub fn to_pem(&self) -> Result<String> {
+        Ok(self.key.to_pkcs8_pem(LineEnding::LF)?.to_string())
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    /// The public key as a JWK
+    pub fn jwk(&self) -> Value {
+        json!({
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": self.kid,
+            "n": URL_SAFE_NO_PAD.encode(self.key.n().to_bytes_be()),
+            "e": URL_SAFE_NO_PAD.encode(self.key.e().to_bytes_be()),
+        })
+    }
+
+    /// Sign `claims` as a compact JWS
+    pub fn sign(&self, claims: &Value) -> Result<String> {
+        let header = json!({ "alg": "RS256", "typ": "JWT", "kid": self.kid });
+        let input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?)
+        );
+        let signature = RsaSigner::<Sha256>::new(self.key.clone()).sign(input.as_bytes());
+        Ok(format!(
+            "{}.{}",
+            input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ))
+    }
+}
+
+/// Verify a token signed by one of `keys` and return its claims
+///
+/// The `exp` claim is required and checked; other claims are left to the
+/// caller.
+pub fn verify(token: &str, keys: &[SigningKey]) -> Result<Value> {
+    let (input, signature) = token.rsplit_once('.').context("malformed token")?;
+    let (header, claims) = input.split_once('.').context("malformed token")?;
+
+    let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+    anyhow::ensure!(header["alg"] == "RS256", "unsupported algorithm");
+    let key = keys
+        .iter()
+        .find(|key| header["kid"] == key.kid)
+        .context("unknown signing key")?;
+    let signature = Signature::try_from(URL_SAFE_NO_PAD.decode(signature)?.as_slice())?;
+    VerifyingKey::<Sha256>::new(key.key.to_public_key())
+        .verify(input.as_bytes(), &signature)
+        .context("invalid signature")?;
+
+    let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims)?)?;
+    let exp = claims["exp"].as_i64().context("missing exp claim")?;
+    anyhow::ensure!(exp > chrono::Utc::now().timestamp(), "token expired");
+    Ok(claims)
+}
+
+/// Load the signing keys, newest first, creating one if there are none
+pub async fn load_or_create_keys(pool: &PgPool) -> Result<Vec<SigningKey>> {
+    let keys = load_keys(pool).await?;
+    if !keys.is_empty() {
+        return Ok(keys);
+    }
+
+    tracing::info!("🔑 Generating a new token signing key");
+    let key = tokio::task::spawn_blocking(SigningKey::generate).await??;
+    let mut tx = pool.begin().await?;
+    lock_keys(&mut tx).await?;
+    // Another replica may have created a key at the same time; use whichever won
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM signing_keys WHERE expires_at IS NULL OR expires_at > now())",
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if !exists {
+        insert_key(&mut tx, &key).await?;
+    }
+    tx.commit().await?;
+    load_keys(pool).await
+}
+
+/// Serialize key creation and rotation across replicas until the transaction ends
+async fn lock_keys(conn: &mut PgConnection) -> Result<()> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('signing_keys'))")
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Load the unexpired signing keys, newest first
+pub async fn load_keys(pool: &PgPool) -> Result<Vec<SigningKey>> {
+    let pems: Vec<Encrypted> = sqlx::query_scalar(
+        "SELECT private_key FROM signing_keys
+         WHERE expires_at IS NULL OR expires_at > now()
+         ORDER BY created_at DESC",
+    )
+    .fetch_all(pool)
+    .await?;
+    pems.iter()
+        .map(|pem| SigningKey::from_pem(pem.as_str()))
+        .collect()
+}
+
+async fn insert_key(conn: &mut PgConnection, key: &SigningKey) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO signing_keys (kid, private_key) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+    )
+    .bind(key.kid())
+    .bind(Encrypted(key.to_pem()?))
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Whether the newest active key is older than `max_age`
+///
+/// Never due before the first key is created by [`load_or_create_keys`].
+async fn rotation_due(conn: &mut PgConnection, max_age: Duration) -> Result<bool> {
+    let due = sqlx::query_scalar(
+        "SELECT COALESCE(max(created_at) <= now() - make_interval(secs => $1), false)
+         FROM signing_keys WHERE expires_at IS NULL",
+    )
+    .bind(max_age.as_secs_f64())
+    .fetch_one(conn)
+    .await?;
+    Ok(due)
+}
+
+/// Replace the signing key if the newest is older than `max_age`
+///
+/// The replaced keys expire after `grace`, so tokens they signed keep
+/// verifying, and already expired keys are deleted. A zero `max_age`
+/// rotates unconditionally. Returns the new key's ID if one was generated.
+pub async fn rotate_keys(
+    pool: &PgPool,
+    max_age: Duration,
+    grace: Duration,
+) -> Result<Option<String>> {
+    let mut rotated = None;
+    if rotation_due(&mut *pool.acquire().await?, max_age).await? {
+        let key = tokio::task::spawn_blocking(SigningKey::generate).await??;
+        let mut tx = pool.begin().await?;
+        lock_keys(&mut tx).await?;
+        // Another replica may have rotated while the key was being generated
+        if rotation_due(&mut tx, max_age).await? {
+            sqlx::query(
+                "UPDATE signing_keys SET expires_at = now() + make_interval(secs => $1)
+                 WHERE expires_at IS NULL",
+            )
+            .bind(grace.as_secs_f64())
+            .execute(&mut *tx)
+            .await?;
+            insert_key(&mut tx, &key).await?;
+            rotated = Some(key.kid().to_string());
+        }
+        tx.commit().await?;
+    }
+
+    let deleted = sqlx::query("DELETE FROM signing_keys WHERE expires_at <= now()")
+        .execute(pool)
+        .await?;
+    if deleted.rows_affected() > 0 {
+        tracing::info!(
+            "🔑 Deleted {} expired signing keys",
+            deleted.rows_affected()
+        );
+    }
+    Ok(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SigningKey::generate().unwrap();
+        let key = SigningKey::from_pem(&key.to_pem().unwrap()).unwrap();
+        let exp = chrono::Utc::now().timestamp() + 60;
+
+        let token = key.sign(&json!({ "sub": "admin", "exp": exp })).unwrap();
+        let claims = verify(&token, std::slice::from_ref(&key)).unwrap();
+        assert_eq!(claims["sub"], "admin");
+        assert_eq!(key.jwk()["kid"], key.kid());
+
+        let tampered = token.replacen('.', ".e30", 1);
+        assert!(verify(&tampered, std::slice::from_ref(&key)).is_err());
+        let expired = key.sign(&json!({ "sub": "admin", "exp": 0 })).unwrap();
+        assert!(verify(&expired, &[key]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rotate_keys() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        let grace = Duration::from_secs(3600);
+
+        let first = load_or_create_keys(pool).await.unwrap();
+        assert_eq!(first.len(), 1);
+        let day = Duration::from_secs(86400);
+        assert_eq!(rotate_keys(pool, day, grace).await.unwrap(), None);
+
+        let kid = rotate_keys(pool, Duration::ZERO, grace).await.unwrap();
+        let keys = load_keys(pool).await.unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(Some(keys[0].kid().to_string()), kid);
+        assert_eq!(keys[1].kid(), first[0].kid());
+
+        // Once past its grace period the replaced key is gone
+        sqlx::query("UPDATE signing_keys SET expires_at = now() WHERE kid = $1")
+            .bind(first[0].kid())
+            .execute(pool)
+            .await
+            .unwrap();
+        assert_eq!(rotate_keys(pool, day, grace).await.unwrap(), None);
+        let keys = load_keys(pool).await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(Some(keys[0].kid().to_string()), kid);
+    }
+}
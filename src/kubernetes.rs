@@ -0,0 +1,205 @@
+//! Kubernetes Lease based leader election.
+//!
+//! Replicas compete for a `coordination.k8s.io/v1` Lease named by
+//! `KUBE_LEASE_NAME` in the pod's namespace. The holder renews it every third
+//! of the lease duration; other replicas take it over once it has expired.
+//! The API is reached over plain HTTP at `KUBE_API_URL`, normally a
+//! `kubectl proxy` sidecar that handles authentication and TLS.
+
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::{Method, StatusCode};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::http_client::HttpClient;
+use crate::leader::{ElectionBackend, Leadership};
+
+/// Where and how to hold the lease
+#[derive(Debug, Clone)]
+pub struct LeaseConfig {
+    pub api_url: String,
+    pub namespace: String,
+    pub name: String,
+    pub identity: String,
+    pub duration: Duration,
+}
+
+impl LeaseConfig {
+    fn url(&self) -> String {
+        format!(
+            "{}/apis/coordination.k8s.io/v1/namespaces/{}/leases",
+            self.api_url.trim_end_matches('/'),
+            self.namespace
+        )
+    }
+}
+
+/// Start competing for the lease in the background
+pub fn spawn(config: LeaseConfig) -> (Leadership, LeaseElector) {
+    let (tx, leadership) = Leadership::channel(ElectionBackend::Kubernetes);
+    let elector = LeaseElector {
+        client: HttpClient::new(Duration::from_secs(5)),
+        config,
+    };
+    let task = elector.clone();
+    tokio::spawn(async move { task.run(tx).await });
+    (leadership, elector)
+}
+
+/// Acquires, renews and releases the lease
+#[derive(Clone)]
+pub struct LeaseElector {
+    client: HttpClient,
+    config: LeaseConfig,
+}
+
+impl LeaseElector {
+    async fn run(self, tx: watch::Sender<bool>) {
+        let mut interval = tokio::time::interval(self.config.duration / 3);
+        loop {
+            interval.tick().await;
+            let leader = match self.try_acquire_or_renew().await {
+                Ok(leader) => leader,
+                Err(e) => {
+                    tracing::warn!("Lease election for '{}' failed: {:#}", self.config.name, e);
+                    false
+                }
+            };
+            if leader != *tx.borrow() {
+                if leader {
+                    tracing::info!("👑 Acquired lease '{}'", self.config.name);
+                } else {
+                    tracing::info!("Lost lease '{}'", self.config.name);
+                }
+            }
+            if tx.send(leader).is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn try_acquire_or_renew(&self) -> Result<bool> {
+        let url = format!("{}/{}", self.config.url(), self.config.name);
+        let now = Utc::now();
+        let (status, mut lease) = self.client.json(Method::GET, &url, None).await?;
+
+        if status == StatusCode::NOT_FOUND {
+            let lease = json!({
+                "apiVersion": "coordination.k8s.io/v1",
+                "kind": "Lease",
+                "metadata": { "name": self.config.name, "namespace": self.config.namespace },
+                "spec": {
+                    "holderIdentity": self.config.identity,
+                    "leaseDurationSeconds": self.config.duration.as_secs(),
+                    "acquireTime": micro_time(now),
+                    "renewTime": micro_time(now),
+                    "leaseTransitions": 0
+                }
+            });
+            let (status, _) = self
+                .client
+                .json(Method::POST, &self.config.url(), Some(&lease))
+                .await?;
+            return match status {
+                StatusCode::CREATED | StatusCode::OK => Ok(true),
+                StatusCode::CONFLICT => Ok(false),
+                other => anyhow::bail!("creating lease returned {}", other),
+            };
+        }
+        anyhow::ensure!(
+            status == StatusCode::OK,
+            "reading lease returned {}",
+            status
+        );
+
+        let Some(takeover) = claim(&lease["spec"], &self.config.identity, now) else {
+            return Ok(false);
+        };
+        let spec = &mut lease["spec"];
+        if takeover {
+            let transitions = spec["leaseTransitions"].as_u64().unwrap_or(0);
+            spec["holderIdentity"] = json!(self.config.identity);
+            spec["acquireTime"] = json!(micro_time(now));
+            spec["leaseTransitions"] = json!(transitions + 1);
+        }
+        spec["leaseDurationSeconds"] = json!(self.config.duration.as_secs());
+        spec["renewTime"] = json!(micro_time(now));
+
+        // The update carries metadata.resourceVersion, so a concurrent
+        // writer makes it fail with 409 instead of both becoming leader.
+        let (status, _) = self.client.json(Method::PUT, &url, Some(&lease)).await?;
+        match status {
+            StatusCode::OK => Ok(true),
+            StatusCode::CONFLICT => Ok(false),
+            other => anyhow::bail!("updating lease returned {}", other),
+        }
+    }
+
+    /// Give up the lease so another replica can take over immediately
+    pub async fn release(&self) -> Result<()> {
+        let url = format!("{}/{}", self.config.url(), self.config.name);
+        let (status, mut lease) = self.client.json(Method::GET, &url, None).await?;
+        if status != StatusCode::OK
+            || lease["spec"]["holderIdentity"].as_str() != Some(&self.config.identity)
+        {
+            return Ok(());
+        }
+        lease["spec"]["holderIdentity"] = Value::Null;
+        let (status, _) = self.client.json(Method::PUT, &url, Some(&lease)).await?;
+        anyhow::ensure!(
+            status == StatusCode::OK,
+            "releasing lease returned {}",
+            status
+        );
+        tracing::info!("Released lease '{}'", self.config.name);
+        Ok(())
+    }
+}
+
+/// Decide whether `identity` may hold the lease described by `spec`
+///
+/// Returns `Some(false)` to renew our own lease, `Some(true)` to take over a
+/// free or expired one and `None` while another holder is still valid.
+fn claim(spec: &Value, identity: &str, now: DateTime<Utc>) -> Option<bool> {
+    let holder = spec["holderIdentity"].as_str().unwrap_or_default();
+    if holder == identity {
+        return Some(false);
+    }
+    if holder.is_empty() {
+        return Some(true);
+    }
+    let renewed = spec["renewTime"]
+        .as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())?;
+    let duration = chrono::Duration::seconds(spec["leaseDurationSeconds"].as_i64().unwrap_or(0));
+    (renewed + duration < now).then_some(true)
+}
+
+/// Format a timestamp as a Kubernetes `MicroTime`
+fn micro_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_respects_valid_holders() {
+        let now = Utc::now();
+        let spec = |holder: &str, age: i64| {
+            json!({
+                "holderIdentity": holder,
+                "leaseDurationSeconds": 15,
+                "renewTime": micro_time(now - chrono::Duration::seconds(age)),
+            })
+        };
+
+        assert_eq!(claim(&spec("pod-a", 5), "pod-a", now), Some(false));
+        assert_eq!(claim(&spec("pod-b", 5), "pod-a", now), None);
+        assert_eq!(claim(&spec("pod-b", 30), "pod-a", now), Some(true));
+        assert_eq!(claim(&spec("", 5), "pod-a", now), Some(true));
+    }
+}
@@ -0,0 +1,251 @@
+//! Leader election for singleton tasks.
+//!
+//! When several replicas run, exactly one of them should run the scheduler
+//! and maintenance jobs. `LEADER_ELECTION` selects how the leader is chosen:
+//!
+//! - `none`: every instance considers itself the leader (single replica)
+//! - `postgres`: a session-level advisory lock keyed by `LEADER_ELECTION_KEY`
+//!   is held on a dedicated connection; if the leader dies its connection
+//!   closes and another replica takes the lock
+//! - `kubernetes`: a Kubernetes Lease, see [`crate::kubernetes`]
+//!
+//! Tasks check or wait on the shared [`Leadership`] handle, which is
+//! available from the extension registry, or register leader-only hooks with
+//! [`ServerBuilder::on_leader`](crate::ServerBuilder::on_leader).
+
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::{Connection, PgConnection};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+use crate::health::{Criticality, HealthRegistry};
+use crate::AppState;
+
+/// How often a follower retries and a leader verifies its lock
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Mechanism used to elect the leader
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ElectionBackend {
+    None,
+    Postgres,
+    Kubernetes,
+}
+
+impl FromStr for ElectionBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(ElectionBackend::None),
+            "postgres" => Ok(ElectionBackend::Postgres),
+            "kubernetes" => Ok(ElectionBackend::Kubernetes),
+            other => anyhow::bail!(
+                "Unknown leader election backend '{}' (expected none, postgres or kubernetes)",
+                other
+            ),
+        }
+    }
+}
+
+/// Shared view of whether this instance currently holds leadership
+#[derive(Debug, Clone)]
+pub struct Leadership {
+    rx: watch::Receiver<bool>,
+    backend: ElectionBackend,
+}
+
+impl Leadership {
+    /// Create a handle together with the sender an elector updates
+    pub fn channel(backend: ElectionBackend) -> (watch::Sender<bool>, Self) {
+        let (tx, rx) = watch::channel(false);
+        (tx, Leadership { rx, backend })
+    }
+
+    /// A handle that is always the leader, for single-instance deployments
+    pub fn always() -> Self {
+        let (_, rx) = watch::channel(true);
+        Leadership {
+            rx,
+            backend: ElectionBackend::None,
+        }
+    }
+
+    /// The mechanism electing the leader
+    pub fn backend(&self) -> ElectionBackend {
+        self.backend
+    }
+
+    /// Whether this instance is the leader right now
+    pub fn is_leader(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Whether the elector has stopped, so leadership can no longer change
+    ///
+    /// Always false without election, where leadership is fixed.
+    pub fn elector_stopped(&self) -> bool {
+        self.backend != ElectionBackend::None && self.rx.has_changed().is_err()
+    }
+
+    /// Wait until this instance becomes the leader
+    ///
+    /// Never returns once the elector has stopped without leadership.
+    pub async fn acquired(&mut self) {
+        if self.rx.wait_for(|leader| *leader).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Wait until this instance stops being the leader
+    ///
+    /// Never returns if leadership can no longer change.
+    pub async fn lost(&mut self) {
+        if self.rx.wait_for(|leader| !*leader).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Register a non-critical `scheduler` check failing once the elector has
+/// stopped, when this instance would never run leader-only work again
+pub fn register_health_check(leadership: &Leadership, health: &HealthRegistry) {
+    let leadership = leadership.clone();
+    health.register("scheduler", Criticality::NonCritical, move || {
+        let stopped = leadership.elector_stopped();
+        async move {
+            anyhow::ensure!(!stopped, "leader election has stopped");
+            Ok(())
+        }
+    });
+}
+
+/// Run `hook` every time this instance becomes the leader
+///
+/// The hook is abandoned if leadership is lost while it runs.
+pub fn spawn_leader_hook<F, Fut>(name: String, mut leadership: Leadership, state: AppState, hook: F)
+where
+    F: Fn(AppState) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            leadership.acquired().await;
+            tracing::info!("👑 Running leader hook '{}'", name);
+            let mut watcher = leadership.clone();
+            tokio::select! {
+                result = hook(state.clone()) => {
+                    if let Err(e) = result {
+                        tracing::error!("Leader hook '{}' failed: {:#}", name, e);
+                    }
+                }
+                _ = watcher.lost() => {
+                    tracing::warn!("Leadership lost while running leader hook '{}'", name);
+                }
+            }
+            leadership.lost().await;
+        }
+    });
+}
+
+/// Holds the advisory lock of the Postgres backend
+#[derive(Clone)]
+pub struct PgElector {
+    stop: Arc<Notify>,
+}
+
+impl PgElector {
+    /// Give up leadership by closing the lock connection
+    pub fn release(&self) {
+        self.stop.notify_one();
+    }
+}
+
+/// Start competing for the Postgres advisory lock in the background
+pub fn spawn_postgres(database_url: String, key: String) -> (Leadership, PgElector) {
+    let (tx, leadership) = Leadership::channel(ElectionBackend::Postgres);
+    let stop = Arc::new(Notify::new());
+    let elector = PgElector { stop: stop.clone() };
+
+    tokio::spawn(async move {
+        let mut conn: Option<PgConnection> = None;
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop.notified() => break,
+            }
+            let was_leader = *tx.borrow();
+            let leader = match poll_lock(&mut conn, &database_url, &key, was_leader).await {
+                Ok(leader) => leader,
+                Err(e) => {
+                    tracing::warn!("Leader election failed: {:#}", e);
+                    // Drop the connection so a stale lock is never trusted
+                    conn = None;
+                    false
+                }
+            };
+            if leader != was_leader {
+                if leader {
+                    tracing::info!("👑 Acquired leadership '{}'", key);
+                } else {
+                    tracing::info!("Lost leadership '{}'", key);
+                }
+            }
+            let _ = tx.send(leader);
+        }
+
+        let _ = tx.send(false);
+        if let Some(conn) = conn {
+            let _ = conn.close().await;
+            tracing::info!("Released leadership '{}'", key);
+        }
+    });
+    (leadership, elector)
+}
+
+/// Take the lock as a follower, or check the connection holding it is alive
+async fn poll_lock(
+    conn: &mut Option<PgConnection>,
+    database_url: &str,
+    key: &str,
+    leader: bool,
+) -> Result<bool> {
+    if conn.is_none() {
+        *conn = Some(PgConnection::connect(database_url).await?);
+    }
+    let conn = conn.as_mut().expect("connection was just opened");
+    if leader {
+        conn.ping().await?;
+        return Ok(true);
+    }
+    let acquired = sqlx::query_scalar("SELECT pg_try_advisory_lock(hashtext($1))")
+        .bind(key)
+        .fetch_one(conn)
+        .await?;
+    Ok(acquired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_leadership_follows_elector() {
+        let (tx, leadership) = Leadership::channel(ElectionBackend::Postgres);
+        assert!(!leadership.is_leader());
+
+        let mut waiter = leadership.clone();
+        tx.send(true).unwrap();
+        waiter.acquired().await;
+        assert!(leadership.is_leader());
+
+        assert!(Leadership::always().is_leader());
+        assert!("etcd".parse::<ElectionBackend>().is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Rust Self-Host Server as a library.
+//!
+//! The binary is a thin wrapper around [`cli`] and [`ServerBuilder`]; other projects can
+//! embed the server the same way and register their own routers, layers and
+//! shutdown hooks against the shared [`AppState`].
+//!
+//! ```no_run
+//! use axum::{routing::get, Router};
+//! use rust_selfhost_server::{config::Config, AppState, ServerBuilder};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let extra = Router::<AppState>::new().route("/hello", get(|| async { "hello" }));
+//! ServerBuilder::new(Config::from_env()?)
+//!     .route(extra)
+//!     .serve()
+//!     .await
+//! # }
+//! ```
+
+pub mod admin_ui;
+pub mod anomaly;
+#[cfg(all(test, feature = "testing"))]
+mod api_snapshots;
+pub mod auth;
+pub mod backoff;
+pub mod billing;
+pub mod bookmarks;
+pub mod canary;
+pub mod capture;
+pub mod challenge;
+pub mod changes;
+pub mod cli;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod collections;
+pub mod concurrency;
+pub mod config;
+pub mod consul;
+pub mod crashes;
+pub mod crypto;
+pub mod dav;
+pub mod db;
+pub mod dependencies;
+pub mod deprecation;
+pub mod disk;
+pub mod doctor;
+pub mod encryption;
+pub mod error;
+pub mod events;
+pub mod experiments;
+pub mod extensions;
+#[cfg(any(test, feature = "testing"))]
+pub mod factories;
+pub mod failover;
+pub mod faults;
+pub mod fetch;
+pub mod forward_auth;
+pub mod grpc;
+pub mod health;
+pub mod html;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod http_client;
+pub mod i18n;
+pub mod info;
+pub mod jobs;
+pub mod jwt;
+pub mod kubernetes;
+pub mod leader;
+pub mod lifecycle;
+pub mod listener;
+pub mod live;
+pub mod logging;
+pub mod mail;
+pub mod markdown;
+pub mod mdns;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock_db;
+pub mod module;
+pub mod notes;
+pub mod oidc;
+pub mod pages;
+pub mod pastes;
+pub mod pipeline;
+#[cfg(feature = "plugins")]
+pub mod plugins;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod pubsub;
+pub mod rate_limit;
+pub mod read_only;
+pub mod redact;
+pub mod retention;
+pub mod route_overrides;
+pub mod schema;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod server;
+pub mod sessions;
+pub mod settings;
+pub mod shadow;
+pub mod shortlinks;
+pub mod signing;
+pub mod single_flight;
+pub mod smoke;
+pub mod sql_console;
+pub mod sql_log;
+pub mod startup;
+pub mod status;
+pub mod storage;
+pub mod tags;
+pub mod templates;
+#[cfg(test)]
+mod test_db;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod time;
+pub mod transaction;
+pub mod unfurl;
+pub mod update;
+pub mod urls;
+pub mod usage;
+pub mod users;
+pub mod watchdog;
+pub mod well_known;
+
+pub use module::RouteModule;
+pub use server::ServerBuilder;
+
+use std::sync::Arc;
+
+use db::Database;
+use extensions::Extensions;
+use health::HealthRegistry;
+use urls::UrlBuilder;
+
+/// Shared state available to every handler
+///
+/// Core services are fields; other subsystems live in the typed
+/// [`Extensions`] registry so adding one does not change this struct.
+#[derive(Clone)]
+pub struct AppState {
+    pub db: Database,
+    pub health: HealthRegistry,
+    /// Builds absolute links to this server
+    pub urls: UrlBuilder,
+    pub extensions: Extensions,
+}
+
+impl AppState {
+    /// Create state around a database with empty registries
+    pub fn new(db: Database) -> Self {
+        AppState {
+            db,
+            health: HealthRegistry::default(),
+            urls: UrlBuilder::default(),
+            extensions: Extensions::default(),
+        }
+    }
+
+    /// Get a secondary database by its name in `DATABASES`
+    ///
+    /// The server's own database is the [`db`](Self::db) field.
+    pub fn db(&self, name: &str) -> Option<&Database> {
+        self.db.named(name)
+    }
+
+    /// Get a shared subsystem by type, if one was registered
+    pub fn extension<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.extensions.get::<T>()
+    }
+}
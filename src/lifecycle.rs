@@ -0,0 +1,149 @@
+//! Application lifecycle hooks.
+//!
+//! Subsystems such as schedulers, listeners and cache warmers register async
+//! hooks for three phases:
+//!
+//! - **startup** runs after migrations, before the router is built. A failing
+//!   or timed-out startup hook aborts startup.
+//! - **ready** runs once the listeners accept connections.
+//! - **shutdown** runs after the listeners have stopped, before the database
+//!   pool is closed.
+//!
+//! Hooks in a phase run one at a time in registration order, each bounded by
+//! `HOOK_TIMEOUT`. Ready and shutdown failures are logged and the remaining
+//! hooks still run.
+
+use anyhow::Result;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::AppState;
+
+type HookFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type HookFn = Box<dyn FnOnce(AppState) -> HookFuture + Send>;
+
+/// Point in the server lifecycle a hook runs at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Startup,
+    Ready,
+    Shutdown,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Startup => "startup",
+            Phase::Ready => "ready",
+            Phase::Shutdown => "shutdown",
+        })
+    }
+}
+
+struct Hook {
+    name: String,
+    run: HookFn,
+}
+
+/// Registered hooks for every lifecycle phase
+#[derive(Default)]
+pub struct Hooks {
+    startup: Vec<Hook>,
+    ready: Vec<Hook>,
+    shutdown: Vec<Hook>,
+}
+
+impl Hooks {
+    /// Register a named hook for a phase
+    pub fn add<F, Fut>(&mut self, phase: Phase, name: impl Into<String>, hook: F)
+    where
+        F: FnOnce(AppState) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let hook = Hook {
+            name: name.into(),
+            run: Box::new(move |state| Box::pin(hook(state))),
+        };
+        self.phase_mut(phase).push(hook);
+    }
+
+    /// Run and consume the hooks of a phase in registration order
+    ///
+    /// Only startup failures are returned; other phases log them and carry on.
+    pub async fn run(&mut self, phase: Phase, state: &AppState, timeout: Duration) -> Result<()> {
+        for hook in std::mem::take(self.phase_mut(phase)) {
+            let error = match tokio::time::timeout(timeout, (hook.run)(state.clone())).await {
+                Ok(Ok(())) => {
+                    tracing::debug!("{} hook '{}' completed", phase, hook.name);
+                    continue;
+                }
+                Ok(Err(e)) => e,
+                Err(_) => anyhow::anyhow!("timed out after {:?}", timeout),
+            };
+            if phase == Phase::Startup {
+                anyhow::bail!("Startup hook '{}' failed: {:#}", hook.name, error);
+            }
+            tracing::error!("{} hook '{}' failed: {:#}", phase, hook.name, error);
+        }
+        Ok(())
+    }
+
+    fn phase_mut(&mut self, phase: Phase) -> &mut Vec<Hook> {
+        match phase {
+            Phase::Startup => &mut self.startup,
+            Phase::Ready => &mut self.ready,
+            Phase::Shutdown => &mut self.shutdown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use sqlx::PgPool;
+    use std::sync::{Arc, Mutex};
+
+    fn state() -> AppState {
+        AppState::new(Database::from_pool(
+            PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_hooks_run_in_order_despite_failures() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut hooks = Hooks::default();
+        for name in ["first", "failing", "last"] {
+            let log = log.clone();
+            hooks.add(Phase::Shutdown, name, move |_| async move {
+                log.lock().unwrap().push(name);
+                anyhow::ensure!(name != "failing", "boom");
+                Ok(())
+            });
+        }
+
+        hooks
+            .run(Phase::Shutdown, &state(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(*log.lock().unwrap(), ["first", "failing", "last"]);
+    }
+
+    #[tokio::test]
+    async fn test_startup_hook_timeout_aborts() {
+        let mut hooks = Hooks::default();
+        hooks.add(Phase::Startup, "slow", |_| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        });
+
+        let err = hooks
+            .run(Phase::Startup, &state(), Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("'slow'"));
+    }
+}
@@ -0,0 +1,351 @@
+//! HTTP listeners and connection tuning.
+//!
+//! Every TCP listener serving the router goes through [`serve`], which
+//! drives hyper directly so the `HTTP_*`, `TCP_NODELAY` and
+//! `LISTEN_BACKLOG` settings apply: keep-alive, how long a client may take
+//! to send request headers, how large they may be, HTTP/2 pings and an idle
+//! timeout for connections that stop sending or receiving anything. The
+//! defaults are hyper's own, so a server without any of them set behaves as
+//! before.
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tower::Service;
+
+use crate::proxy_protocol::{self, ProxyProtocolConfig};
+
+/// How long a peer gets to send a PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long open connections get to finish on shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// TCP and HTTP connection settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionTuning {
+    /// Reuse HTTP/1.1 connections for further requests
+    pub keep_alive: bool,
+    /// How long a client may take to send a request's headers
+    pub header_read_timeout: Duration,
+    /// Largest request head accepted, in bytes
+    pub max_header_size: usize,
+    /// Most request headers accepted over HTTP/1.1
+    pub max_headers: usize,
+    /// How often to ping HTTP/2 clients; None never pings
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// How long an HTTP/2 ping may go unanswered before closing
+    pub http2_keep_alive_timeout: Duration,
+    /// Disable Nagle's algorithm on accepted sockets
+    pub tcp_nodelay: bool,
+    /// Pending connections the kernel queues before refusing more
+    pub backlog: u32,
+    /// Close connections with no traffic for this long; None keeps them
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionTuning {
+    fn default() -> Self {
+        ConnectionTuning {
+            keep_alive: true,
+            header_read_timeout: Duration::from_secs(30),
+            max_header_size: 8192 + 4096 * 100,
+            max_headers: 100,
+            http2_keep_alive_interval: None,
+            http2_keep_alive_timeout: Duration::from_secs(20),
+            tcp_nodelay: false,
+            backlog: 1024,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl ConnectionTuning {
+    fn builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .keep_alive(self.keep_alive)
+            .header_read_timeout(self.header_read_timeout)
+            .max_buf_size(self.max_header_size)
+            .max_headers(self.max_headers);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .keep_alive_interval(self.http2_keep_alive_interval)
+            .keep_alive_timeout(self.http2_keep_alive_timeout)
+            .max_header_list_size(self.max_header_size.try_into().unwrap_or(u32::MAX));
+        builder
+    }
+}
+
+/// Bind a listener on `addr` with the configured backlog
+pub fn bind(addr: SocketAddr, tuning: &ConnectionTuning) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(tuning.backlog)
+}
+
+/// Accept connections until shutdown, serving each with `router`
+///
+/// With `proxy_protocol`, each connection must start with a PROXY protocol
+/// header and the client it names is the request's `ConnectInfo`.
+pub async fn serve(
+    listener: TcpListener,
+    router: Router,
+    tuning: ConnectionTuning,
+    proxy_protocol: Option<ProxyProtocolConfig>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let builder = Arc::new(tuning.builder());
+    // Each connection holds a sender; recv() returns None once all are gone
+    let (open, mut closed) = mpsc::channel::<()>(1);
+    loop {
+        let (mut stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+        if let Some(config) = &proxy_protocol {
+            if !config.is_trusted(peer.ip()) {
+                tracing::debug!("Refused PROXY protocol from untrusted {}", peer);
+                continue;
+            }
+        }
+        if tuning.tcp_nodelay {
+            let _ = stream.set_nodelay(true);
+        }
+        let (router, builder, open) = (router.clone(), builder.clone(), open.clone());
+        let (idle_timeout, mut shutdown) = (tuning.idle_timeout, shutdown.clone());
+        let proxied = proxy_protocol.is_some();
+        tokio::spawn(async move {
+            let _open = open;
+            let client = if proxied {
+                let header = proxy_protocol::read_header(&mut stream);
+                match tokio::time::timeout(PROXY_HEADER_TIMEOUT, header).await {
+                    Ok(Ok(client)) => client.unwrap_or(peer),
+                    Ok(Err(e)) => {
+                        tracing::debug!("Invalid PROXY protocol header from {}: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!("No PROXY protocol header from {} in time", peer);
+                        return;
+                    }
+                }
+            } else {
+                peer
+            };
+            let service = hyper::service::service_fn(move |request: hyper::Request<Incoming>| {
+                let mut router = router.clone();
+                let mut request = request.map(Body::new);
+                request.extensions_mut().insert(ConnectInfo(client));
+                async move { router.call(request).await }
+            });
+            let stream = Tracked::new(stream);
+            let activity = stream.activity.clone();
+            let mut connection =
+                pin!(builder.serve_connection_with_upgrades(TokioIo::new(stream), service));
+            let mut closing = false;
+            let result = loop {
+                let idle = async {
+                    match idle_timeout {
+                        Some(timeout) if !closing => activity.idle_for(timeout).await,
+                        _ => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    result = connection.as_mut() => break result,
+                    _ = shutdown.wait_for(|stop| *stop), if !closing => {}
+                    _ = idle => tracing::debug!("Closing idle connection from {}", client),
+                }
+                connection.as_mut().graceful_shutdown();
+                closing = true;
+            };
+            if let Err(e) = result {
+                tracing::debug!("Connection from {} failed: {}", client, e);
+            }
+        });
+    }
+    drop(open);
+    if tokio::time::timeout(SHUTDOWN_GRACE, closed.recv())
+        .await
+        .is_err()
+    {
+        tracing::debug!("Closed connections still open after {:?}", SHUTDOWN_GRACE);
+    }
+}
+
+/// When a connection last moved bytes
+struct Activity {
+    started: Instant,
+    /// Milliseconds since `started`
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Resolve once nothing has moved for `timeout`
+    async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let last = self.started + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            if last.elapsed() >= timeout {
+                return;
+            }
+            tokio::time::sleep_until(last + timeout).await;
+        }
+    }
+}
+
+/// A stream recording its activity for the idle timeout
+struct Tracked {
+    inner: TcpStream,
+    activity: Arc<Activity>,
+}
+
+impl Tracked {
+    fn new(inner: TcpStream) -> Self {
+        Tracked {
+            inner,
+            activity: Arc::new(Activity {
+                started: Instant::now(),
+                last: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+impl AsyncRead for Tracked {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for Tracked {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn start(tuning: ConnectionTuning) -> (SocketAddr, watch::Sender<bool>) {
+        let listener = bind("127.0.0.1:0".parse().unwrap(), &tuning).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        let (stop, shutdown) = watch::channel(false);
+        tokio::spawn(serve(listener, router, tuning, None, shutdown));
+        (addr, stop)
+    }
+
+    #[tokio::test]
+    async fn test_applies_header_limits_and_idle_timeout() {
+        let (addr, _stop) = start(ConnectionTuning {
+            max_header_size: 8192,
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..ConnectionTuning::default()
+        })
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let large = format!(
+            "GET / HTTP/1.1\r\nHost: x\r\nX-Big: {}\r\n\r\n",
+            "a".repeat(10_000)
+        );
+        stream.write_all(large.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+
+        // A keep-alive connection is closed once idle
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+            .await
+            .expect("idle connection closed")
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_can_be_disabled() {
+        let (addr, stop) = start(ConnectionTuning {
+            keep_alive: false,
+            tcp_nodelay: true,
+            ..ConnectionTuning::default()
+        })
+        .await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+            .await
+            .expect("connection closed after the response")
+            .unwrap();
+        assert!(response.contains("connection: close"), "{}", response);
+        stop.send(true).unwrap();
+    }
+}
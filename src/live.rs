@@ -0,0 +1,405 @@
+//! Live metrics stream.
+//!
+//! `GET /admin/stream` is a Server-Sent Events stream for dashboards and
+//! the admin UI: every `ADMIN_STREAM_INTERVAL` it sends a `metrics` event
+//! with a JSON snapshot of the server, and whenever health checks run a
+//! `health` event with their results.
+//!
+//! Snapshots cover the requests of the last interval (count, rate, status
+//! classes and latency percentiles), requests in flight, connection pool
+//! usage of every database, the bookmark fetch queue and the process's
+//! memory and file descriptors. Requests are counted per second for the
+//! last minute only, so there is nothing to scrape or store; latency
+//! percentiles are the upper bound of the histogram bucket they fall in.
+//!
+//! Browsers' `EventSource` cannot send the admin token, so clients read
+//! the stream with `fetch` instead, as the admin UI does.
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Response,
+    },
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::bookmarks;
+use crate::db::Database;
+use crate::extensions::Ext;
+use crate::health::ProbeRun;
+use crate::module::{RouteGroup, RouteModule};
+use crate::watchdog::Usage;
+use crate::AppState;
+
+/// Upper bounds of the latency histogram buckets, in milliseconds
+const LATENCY_BOUNDS: [u64; 13] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+/// Seconds of per-second counts kept
+const WINDOW: u64 = 60;
+
+/// Requests completed within one second
+#[derive(Debug, Clone, Default)]
+struct Second {
+    /// Seconds since the stats were created
+    at: u64,
+    requests: u64,
+    /// Responses by status class, 1xx to 5xx
+    statuses: [u64; 5],
+    /// Responses per latency bucket, the last one for slower than every bound
+    latencies: [u64; LATENCY_BOUNDS.len() + 1],
+    max_ms: u64,
+}
+
+/// Request counters feeding the stream
+#[derive(Clone)]
+pub struct LiveStats {
+    started: Instant,
+    seconds: Arc<Mutex<VecDeque<Second>>>,
+    in_flight: Arc<AtomicU64>,
+    interval: Duration,
+    /// Whether the bookmarks module, and so its fetch queue, is enabled
+    bookmark_queue: bool,
+}
+
+/// Requests of a recent window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RequestSnapshot {
+    pub total: u64,
+    pub per_second: f64,
+    pub in_flight: u64,
+    /// Responses by status class, e.g. `2xx`
+    pub statuses: BTreeMap<&'static str, u64>,
+    pub latency_ms: Option<Latency>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Latency {
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl LiveStats {
+    pub fn new(interval: Duration, bookmark_queue: bool) -> Self {
+        LiveStats {
+            started: Instant::now(),
+            seconds: Arc::default(),
+            in_flight: Arc::default(),
+            interval,
+            bookmark_queue,
+        }
+    }
+
+    /// Count a completed request
+    pub fn record(&self, status: u16, elapsed: Duration) {
+        let at = self.started.elapsed().as_secs();
+        let ms = elapsed.as_millis() as u64;
+        let mut seconds = self.seconds.lock().expect("live stats lock poisoned");
+        if seconds.back().is_none_or(|second| second.at != at) {
+            seconds.push_back(Second {
+                at,
+                ..Second::default()
+            });
+            while seconds
+                .front()
+                .is_some_and(|second| second.at + WINDOW <= at)
+            {
+                seconds.pop_front();
+            }
+        }
+        let second = seconds.back_mut().expect("a second was just pushed");
+        second.requests += 1;
+        if let Some(class) = (status / 100).checked_sub(1).filter(|class| *class < 5) {
+            second.statuses[class as usize] += 1;
+        }
+        let bucket = LATENCY_BOUNDS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BOUNDS.len());
+        second.latencies[bucket] += 1;
+        second.max_ms = second.max_ms.max(ms);
+    }
+
+    /// Requests completed within the last `window`, at most a minute
+    pub fn requests(&self, window: Duration) -> RequestSnapshot {
+        let window = window.as_secs().clamp(1, WINDOW);
+        let now = self.started.elapsed().as_secs();
+        let mut total = Second::default();
+        {
+            let seconds = self.seconds.lock().expect("live stats lock poisoned");
+            for second in seconds.iter().filter(|second| second.at + window > now) {
+                total.requests += second.requests;
+                for (sum, count) in total.statuses.iter_mut().zip(second.statuses) {
+                    *sum += count;
+                }
+                for (sum, count) in total.latencies.iter_mut().zip(second.latencies) {
+                    *sum += count;
+                }
+                total.max_ms = total.max_ms.max(second.max_ms);
+            }
+        }
+        let percentile = |p: u64| {
+            let rank = (total.requests * p).div_ceil(100).max(1);
+            let mut seen = 0;
+            for (bucket, count) in total.latencies.iter().enumerate() {
+                seen += count;
+                if seen >= rank {
+                    return LATENCY_BOUNDS
+                        .get(bucket)
+                        .map_or(total.max_ms, |bound| (*bound).min(total.max_ms));
+                }
+            }
+            total.max_ms
+        };
+        RequestSnapshot {
+            total: total.requests,
+            per_second: (total.requests as f64 / window as f64 * 100.0).round() / 100.0,
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            statuses: ["1xx", "2xx", "3xx", "4xx", "5xx"]
+                .into_iter()
+                .zip(total.statuses)
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+            latency_ms: (total.requests > 0).then(|| Latency {
+                p50: percentile(50),
+                p95: percentile(95),
+                p99: percentile(99),
+                max: total.max_ms,
+            }),
+        }
+    }
+
+    /// Middleware counting every response and its latency
+    pub async fn middleware(
+        State(stats): State<LiveStats>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let started = Instant::now();
+        stats.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _guard = InFlight(&stats.in_flight);
+        let response = next.run(request).await;
+        stats.record(response.status().as_u16(), started.elapsed());
+        response
+    }
+}
+
+/// Decrements the in-flight count, also when the request is cancelled
+struct InFlight<'a>(&'a AtomicU64);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Connection usage of a pool
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PoolSnapshot {
+    pub name: String,
+    pub size: u32,
+    pub idle: u32,
+    pub max: u32,
+}
+
+impl PoolSnapshot {
+    fn of(name: &str, db: &Database) -> Self {
+        let info = db.pool_info();
+        PoolSnapshot {
+            name: name.to_string(),
+            size: info.size,
+            idle: info.num_idle as u32,
+            max: db.pool().options().get_max_connections(),
+        }
+    }
+}
+
+/// Everything sent in one `metrics` event
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    #[serde(with = "crate::time::rfc3339")]
+    pub at: DateTime<Utc>,
+    pub uptime_secs: u64,
+    pub requests: RequestSnapshot,
+    pub pools: Vec<PoolSnapshot>,
+    /// Pending jobs per queue
+    pub queues: BTreeMap<&'static str, i64>,
+    pub process: Usage,
+}
+
+/// Take a snapshot of the server
+pub async fn snapshot(state: &AppState, stats: &LiveStats) -> Snapshot {
+    let mut pools = vec![PoolSnapshot::of("default", &state.db)];
+    pools.extend(
+        state
+            .db
+            .named_databases()
+            .map(|(name, db)| PoolSnapshot::of(name, db)),
+    );
+    pools[1..].sort_by(|a, b| a.name.cmp(&b.name));
+    let mut queues = BTreeMap::new();
+    if stats.bookmark_queue {
+        match bookmarks::queue_depth(state.db.pool()).await {
+            Ok(depth) => {
+                queues.insert("bookmark_fetch", depth);
+            }
+            Err(e) => tracing::debug!("Reading the bookmark queue depth failed: {}", e),
+        }
+    }
+    Snapshot {
+        at: Utc::now(),
+        uptime_secs: stats.started.elapsed().as_secs(),
+        requests: stats.requests(stats.interval),
+        pools,
+        queues,
+        process: tokio::task::spawn_blocking(Usage::sample)
+            .await
+            .unwrap_or_default(),
+    }
+}
+
+/// What the stream waits for next
+enum Wake {
+    Tick,
+    Health(Arc<ProbeRun>),
+    Closed,
+}
+
+struct StreamState {
+    state: AppState,
+    stats: LiveStats,
+    ticker: tokio::time::Interval,
+    health: broadcast::Receiver<Arc<ProbeRun>>,
+}
+
+/// `GET /admin/stream` - `metrics` and `health` events
+pub async fn stream(
+    State(state): State<AppState>,
+    Ext(stats): Ext<LiveStats>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stats = LiveStats::clone(&stats);
+    let initial = StreamState {
+        health: state.health.subscribe(),
+        ticker: tokio::time::interval(stats.interval),
+        state,
+        stats,
+    };
+    let events = stream::unfold(initial, |mut s| async move {
+        loop {
+            let next = tokio::select! {
+                _ = s.ticker.tick() => Wake::Tick,
+                run = s.health.recv() => match run {
+                    Ok(run) => Wake::Health(run),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => Wake::Closed,
+                },
+            };
+            let event = match next {
+                Wake::Tick => Event::default()
+                    .event("metrics")
+                    .json_data(snapshot(&s.state, &s.stats).await),
+                Wake::Health(run) => Event::default().event("health").json_data(json!({
+                    "at": crate::time::format(&run.at),
+                    "checks": run.checks,
+                })),
+                Wake::Closed => return None,
+            };
+            match event {
+                Ok(event) => return Some((Ok(event), s)),
+                Err(e) => tracing::warn!("Failed to encode a live metrics event: {}", e),
+            }
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Route module serving `/admin/stream`
+pub struct LiveModule;
+
+impl RouteModule for LiveModule {
+    fn name(&self) -> &'static str {
+        "live"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/admin/stream", get(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use http_body_util::BodyExt;
+    use sqlx::PgPool;
+    use tower::Service;
+
+    #[test]
+    fn test_counts_requests_by_status_and_latency() {
+        let stats = LiveStats::new(Duration::from_secs(2), false);
+        assert_eq!(stats.requests(Duration::from_secs(2)).latency_ms, None);
+
+        for ms in 1..=98 {
+            stats.record(200, Duration::from_millis(ms));
+        }
+        stats.record(503, Duration::from_millis(3000));
+        stats.record(503, Duration::from_millis(2000));
+        let snapshot = stats.requests(Duration::from_secs(2));
+        assert_eq!(snapshot.total, 100);
+        assert_eq!(snapshot.per_second, 50.0);
+        assert_eq!(snapshot.statuses, BTreeMap::from([("2xx", 98), ("5xx", 2)]));
+        assert_eq!(
+            snapshot.latency_ms,
+            Some(Latency {
+                p50: 50,
+                p95: 100,
+                p99: 2500,
+                max: 3000,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streams_metrics_events() {
+        let state = AppState::new(Database::from_pool(
+            PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+        ));
+        let stats = LiveStats::new(Duration::from_secs(1), false);
+        stats.record(404, Duration::from_millis(7));
+        state.extensions.insert(stats);
+        let mut app = LiveModule.routes().with_state(state);
+
+        let request = axum::http::Request::get("/admin/stream")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.call(request).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body();
+        let frame = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        let frame = String::from_utf8(frame.to_vec()).unwrap();
+        let data = frame
+            .strip_prefix("event: metrics\ndata: ")
+            .and_then(|rest| rest.strip_suffix("\n\n"))
+            .unwrap_or_else(|| panic!("unexpected event: {}", frame));
+        let snapshot: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(snapshot["requests"]["statuses"], json!({ "4xx": 1 }));
+        assert_eq!(snapshot["pools"][0]["name"], "default");
+    }
+}
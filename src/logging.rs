@@ -0,0 +1,484 @@
+//! Log output.
+//!
+//! `LOG_OUTPUT` picks where `serve` logs: `stdout` (the default), `syslog`
+//! (RFC 5424, see [`syslog`]) and `journald` (see [`journald`]), or several
+//! of them. The structured outputs keep each event's fields and those of
+//! its spans as key/value pairs; all outputs mask secrets.
+//!
+//! With `LOG_DIR` set, logs are also written to `server.log` in that
+//! directory, without colors, through a background writer thread. The file
+//! is rotated when it would grow past `LOG_ROTATE_SIZE` and, with
+//! `LOG_ROTATE_DAILY`, at the first write of a new UTC day: it is renamed
+//! to `server.<UTC timestamp>.log`, then gzipped and old files pruned on a
+//! separate thread. Timestamped names sort chronologically, so pruning
+//! keeps the newest `LOG_ROTATE_KEEP` files and drops any older than
+//! `LOG_ROTATE_MAX_AGE`.
+//!
+//! Configuration is loaded after logging starts, so `serve` starts with
+//! stdout and [`install`] swaps in the configured outputs.
+
+pub mod journald;
+pub mod syslog;
+
+use anyhow::Context as _;
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Layer, Registry};
+
+use crate::config::Config;
+use crate::redact::{self, MakeRedacting};
+use syslog::SyslogConfig;
+
+/// Name of the file being written
+const FILE_NAME: &str = "server.log";
+
+/// Where and how to write log files, from configuration
+#[derive(Debug, Clone)]
+pub struct LogFiles {
+    pub dir: PathBuf,
+    /// Rotate before the file grows past this many bytes
+    pub rotate_size: Option<u64>,
+    /// Rotate when the UTC date changes
+    pub rotate_daily: bool,
+    /// Gzip rotated files
+    pub compress: bool,
+    /// Rotated files kept, `0` for no limit
+    pub keep: usize,
+    /// Rotated files older than this are deleted
+    pub max_age: Option<Duration>,
+}
+
+/// Where logs go besides `LOG_DIR`, from `LOG_OUTPUT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogOutputs {
+    pub stdout: bool,
+    pub syslog: Option<SyslogConfig>,
+    pub journald: bool,
+}
+
+impl Default for LogOutputs {
+    fn default() -> Self {
+        LogOutputs {
+            stdout: true,
+            syslog: None,
+            journald: false,
+        }
+    }
+}
+
+impl LogOutputs {
+    /// Parse a comma-separated list of `stdout`, `syslog` and `journald`
+    ///
+    /// `syslog` is configured by `syslog`, only called if it is listed.
+    pub fn parse(
+        list: &str,
+        syslog: impl FnOnce() -> anyhow::Result<SyslogConfig>,
+    ) -> anyhow::Result<Self> {
+        let names: Vec<&str> = list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        if names.is_empty() {
+            return Ok(LogOutputs::default());
+        }
+        if let Some(name) = names
+            .iter()
+            .find(|name| !matches!(**name, "stdout" | "syslog" | "journald"))
+        {
+            anyhow::bail!(
+                "unknown output '{}', expected stdout, syslog or journald",
+                name
+            );
+        }
+        Ok(LogOutputs {
+            stdout: names.contains(&"stdout"),
+            syslog: names.contains(&"syslog").then(syslog).transpose()?,
+            journald: names.contains(&"journald"),
+        })
+    }
+}
+
+type OutputLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+static OUTPUTS: OnceLock<reload::Handle<Vec<OutputLayer>, Registry>> = OnceLock::new();
+
+fn stdout_layer() -> OutputLayer {
+    fmt::layer()
+        .with_writer(MakeRedacting(std::io::stdout))
+        .boxed()
+}
+
+/// Log to stdout until [`install`] is called
+pub fn init_serve() {
+    let (outputs, handle) = reload::Layer::new(vec![stdout_layer()]);
+    let _ = OUTPUTS.set(handle);
+    tracing_subscriber::registry()
+        .with(outputs)
+        .with(LevelFilter::INFO)
+        .init();
+}
+
+/// Switch to the configured outputs
+///
+/// With `LOG_DIR` set, buffered lines are written to the file when the
+/// returned guard is dropped, so it must live until the server exits.
+pub fn install(config: &Config) -> anyhow::Result<Option<WorkerGuard>> {
+    let handle = OUTPUTS.get().context("logging is not initialized")?;
+    let outputs = config.log_outputs();
+    let mut layers = Vec::new();
+    if outputs.stdout {
+        layers.push(stdout_layer());
+    }
+    let guard = match config.log_files() {
+        Some(files) => {
+            let file = RollingFile::open(files.clone())
+                .with_context(|| format!("Failed to open log file in {}", files.dir.display()))?;
+            let (writer, guard) = tracing_appender::non_blocking(file);
+            layers.push(
+                fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(MakeRedacting(writer))
+                    .boxed(),
+            );
+            Some(guard)
+        }
+        None => None,
+    };
+    if outputs.syslog.is_some() || outputs.journald {
+        layers.push(SpanFieldsLayer.boxed());
+    }
+    if let Some(syslog) = &outputs.syslog {
+        layers.push(syslog::SyslogLayer::new(syslog, &config.instance().name)?.boxed());
+    }
+    if outputs.journald {
+        layers.push(journald::JournaldLayer::new()?.boxed());
+    }
+    if config.sql_log().is_some() {
+        layers.push(crate::sql_log::QueryCountLayer.boxed());
+    }
+    handle.reload(layers)?;
+    Ok(guard)
+}
+
+/// Collects fields as text, with secrets masked
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.push(field, format!("{:?}", value));
+    }
+}
+
+impl FieldVisitor {
+    fn push(&mut self, field: &Field, value: String) {
+        let value = redact::redact(&value).into_owned();
+        if field.name() == "message" {
+            self.message = value;
+        } else {
+            self.fields.push((field.name(), value));
+        }
+    }
+}
+
+/// Fields recorded on a span, for the structured outputs
+struct SpanFields(Vec<(&'static str, String)>);
+
+/// Keeps span fields for the structured outputs
+///
+/// Added once ahead of them, so spans are not recorded twice.
+pub struct SpanFieldsLayer;
+
+impl<S> Layer<S> for SpanFieldsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                fields.0.extend(visitor.fields);
+            }
+        }
+    }
+}
+
+/// An event flattened for the structured outputs, with secrets masked
+#[derive(Debug)]
+pub struct LogRecord {
+    pub level: Level,
+    pub target: &'static str,
+    pub file: Option<&'static str>,
+    pub line: Option<u32>,
+    pub message: String,
+    /// Fields of the enclosing spans, outermost first, then of the event
+    pub fields: Vec<(&'static str, String)>,
+}
+
+impl LogRecord {
+    pub fn new<S>(event: &Event<'_>, ctx: &Context<'_, S>) -> Self
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let mut fields = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<SpanFields>() {
+                    fields.extend(span_fields.0.iter().cloned());
+                }
+            }
+        }
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        fields.extend(visitor.fields);
+        let metadata = event.metadata();
+        LogRecord {
+            level: *metadata.level(),
+            target: metadata.target(),
+            file: metadata.file(),
+            line: metadata.line(),
+            message: visitor.message,
+            fields,
+        }
+    }
+}
+
+/// A log file rotated by size and date
+pub struct RollingFile {
+    settings: LogFiles,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+}
+
+impl RollingFile {
+    /// Open `server.log` for appending, creating the directory if needed
+    pub fn open(settings: LogFiles) -> io::Result<Self> {
+        fs::create_dir_all(&settings.dir)?;
+        let file = Self::open_file(&settings.dir)?;
+        let metadata = file.metadata()?;
+        // A file left from an earlier day is rotated on the first write
+        let opened_on = metadata
+            .modified()
+            .map(|modified| DateTime::<Utc>::from(modified).date_naive())
+            .unwrap_or_else(|_| Utc::now().date_naive());
+        Ok(RollingFile {
+            settings,
+            file,
+            size: metadata.len(),
+            opened_on,
+        })
+    }
+
+    fn open_file(dir: &Path) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(FILE_NAME))
+    }
+
+    fn should_rotate(&self, incoming: usize, today: NaiveDate) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_large = self
+            .settings
+            .rotate_size
+            .is_some_and(|max| self.size + incoming as u64 > max);
+        too_large || (self.settings.rotate_daily && today != self.opened_on)
+    }
+
+    fn rotate(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        self.file.flush()?;
+        let dir = &self.settings.dir;
+        let rotated = dir.join(format!("server.{}.log", now.format("%Y%m%dT%H%M%S%.3fZ")));
+        fs::rename(dir.join(FILE_NAME), &rotated)?;
+        self.file = Self::open_file(dir)?;
+        self.size = 0;
+        self.opened_on = now.date_naive();
+
+        let settings = self.settings.clone();
+        std::thread::spawn(move || {
+            // One rotation at a time, so pruning never sees a half-compressed file
+            static MAINTENANCE: Mutex<()> = Mutex::new(());
+            let _lock = MAINTENANCE.lock().unwrap_or_else(|e| e.into_inner());
+            if settings.compress {
+                if let Err(e) = compress(&rotated) {
+                    eprintln!("Failed to compress {}: {}", rotated.display(), e);
+                }
+            }
+            if let Err(e) = prune(&settings) {
+                eprintln!("Failed to prune logs in {}: {}", settings.dir.display(), e);
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Utc::now();
+        if self.should_rotate(buf.len(), now.date_naive()) {
+            // Keep logging to the current file if the rename fails
+            if let Err(e) = self.rotate(now) {
+                eprintln!("Failed to rotate {}: {}", FILE_NAME, e);
+            }
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Replace a rotated file with a gzipped copy
+fn compress(path: &Path) -> io::Result<()> {
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(".gz");
+    let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+/// Delete rotated files beyond the retention limits
+fn prune(settings: &LogFiles) -> io::Result<()> {
+    let mut rotated = Vec::new();
+    for entry in fs::read_dir(&settings.dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name != FILE_NAME
+            && name.starts_with("server.")
+            && (name.ends_with(".log") || name.ends_with(".log.gz"))
+        {
+            rotated.push((name, entry.metadata()?.modified()?));
+        }
+    }
+    // Newest first
+    rotated.sort_by(|a, b| b.0.cmp(&a.0));
+    let now = SystemTime::now();
+    for (index, (name, modified)) in rotated.into_iter().enumerate() {
+        let too_many = settings.keep > 0 && index >= settings.keep;
+        let too_old = settings
+            .max_age
+            .is_some_and(|max_age| now.duration_since(modified).is_ok_and(|age| age > max_age));
+        if too_many || too_old {
+            fs::remove_file(settings.dir.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn settings(dir: &Path) -> LogFiles {
+        LogFiles {
+            dir: dir.to_path_buf(),
+            rotate_size: Some(100),
+            rotate_daily: true,
+            compress: true,
+            keep: 2,
+            max_age: None,
+        }
+    }
+
+    fn rotated(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != FILE_NAME)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_parse_outputs() {
+        let syslog = || SyslogConfig::parse("udp://127.0.0.1:514", "daemon");
+        assert_eq!(
+            LogOutputs::parse("", syslog).unwrap(),
+            LogOutputs::default()
+        );
+        let outputs = LogOutputs::parse("journald, syslog", syslog).unwrap();
+        assert!(!outputs.stdout && outputs.journald);
+        assert_eq!(outputs.syslog.unwrap().facility, 3);
+        let outputs = LogOutputs::parse("stdout", || anyhow::bail!("not needed")).unwrap();
+        assert!(outputs.stdout && outputs.syslog.is_none());
+        assert!(LogOutputs::parse("stdout,file", syslog).is_err());
+    }
+
+    #[test]
+    fn test_rotation_and_retention() {
+        let dir = std::env::temp_dir().join(format!("logs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut file = RollingFile::open(settings(&dir)).unwrap();
+
+        let line = [b'x'; 40];
+        file.write_all(&line).unwrap();
+        assert!(!file.should_rotate(60, file.opened_on));
+        assert!(file.should_rotate(61, file.opened_on));
+        assert!(file.should_rotate(1, file.opened_on.succ_opt().unwrap()));
+
+        // Every third line rotates, leaving 4 rotated files of which 2 are kept
+        for _ in 0..9 {
+            file.write_all(&line).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let wait = std::time::Instant::now();
+        while rotated(&dir).len() != 2 || rotated(&dir).iter().any(|n| n.ends_with(".log")) {
+            assert!(
+                wait.elapsed() < Duration::from_secs(5),
+                "{:?}",
+                rotated(&dir)
+            );
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(fs::metadata(dir.join(FILE_NAME)).unwrap().len(), 80);
+        let newest = dir.join(rotated(&dir).pop().unwrap());
+        let mut text = Vec::new();
+        GzDecoder::new(File::open(newest).unwrap())
+            .read_to_end(&mut text)
+            .unwrap();
+        assert_eq!(text, [b'x'; 80]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
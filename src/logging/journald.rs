@@ -0,0 +1,147 @@
+//! systemd-journald output.
+//!
+//! Events are sent to journald's native socket with their fields (and
+//! those of the enclosing spans) as journal fields, so
+//! `journalctl STATUS=503` or `-o json` see them. Field names are
+//! uppercased with other characters turned into `_`. An entry too large
+//! for a single datagram is dropped.
+
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use super::LogRecord;
+
+/// journald's native protocol socket
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Syslog priority of a tracing level
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// A valid journal field name for a tracing field
+///
+/// Names are `[A-Z0-9_]` and may not start with `_` (reserved for trusted
+/// fields) or a digit.
+fn field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .take(63)
+        .collect();
+    if name.starts_with(|c: char| c == '_' || c.is_ascii_digit()) || name.is_empty() {
+        format!("F{}", name)
+    } else {
+        name
+    }
+}
+
+fn push_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // Binary-safe form: name, newline, little-endian length, value
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Encode an event in journald's native protocol
+pub fn encode(record: &LogRecord) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(256);
+    push_field(&mut entry, "MESSAGE", &record.message);
+    push_field(&mut entry, "PRIORITY", priority(record.level));
+    push_field(&mut entry, "SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+    push_field(&mut entry, "TARGET", record.target);
+    if let Some(file) = record.file {
+        push_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line {
+        push_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    for (name, value) in &record.fields {
+        push_field(&mut entry, &field_name(name), value);
+    }
+    entry
+}
+
+/// Layer sending every event to journald
+pub struct JournaldLayer {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+impl JournaldLayer {
+    /// Connect to the local journald
+    #[cfg(unix)]
+    pub fn new() -> anyhow::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket
+            .connect(SOCKET)
+            .map_err(|e| anyhow::anyhow!("Failed to connect to journald at {}: {}", SOCKET, e))?;
+        Ok(JournaldLayer { socket })
+    }
+
+    /// Connect to the local journald
+    #[cfg(not(unix))]
+    pub fn new() -> anyhow::Result<Self> {
+        anyhow::bail!("journald is not supported on this platform")
+    }
+}
+
+impl<S> Layer<S> for JournaldLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let entry = encode(&LogRecord::new(event, &ctx));
+        #[cfg(unix)]
+        let _ = self.socket.send(&entry);
+        #[cfg(not(unix))]
+        let _ = entry;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let record = LogRecord {
+            level: Level::WARN,
+            target: "app::db",
+            file: Some("src/db.rs"),
+            line: Some(12),
+            message: "slow query".to_string(),
+            fields: vec![
+                ("elapsed_ms", "950".to_string()),
+                ("sql", "SELECT 1\nFROM t".to_string()),
+                ("_uid", "0".to_string()),
+            ],
+        };
+        let mut expected = b"MESSAGE=slow query\nPRIORITY=4\n\
+            SYSLOG_IDENTIFIER=rust-selfhost-server\nTARGET=app::db\n\
+            CODE_FILE=src/db.rs\nCODE_LINE=12\nELAPSED_MS=950\nSQL\n"
+            .to_vec();
+        expected.extend_from_slice(&15u64.to_le_bytes());
+        expected.extend_from_slice(b"SELECT 1\nFROM t\nF_UID=0\n");
+        assert_eq!(encode(&record), expected);
+    }
+}
@@ -0,0 +1,318 @@
+//! RFC 5424 syslog output.
+//!
+//! Each event becomes one message with its fields (and those of the
+//! enclosing spans) in a `fields@32473` structured data element, so
+//! collectors that parse structured data keep them as key/value pairs.
+//! Messages go over UDP (one datagram each), TCP (octet-counted framing,
+//! RFC 6587) or a local unix datagram socket such as `/dev/log`. Sending
+//! happens on a dedicated thread; when it falls behind, events are dropped
+//! rather than slowing down requests.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use super::LogRecord;
+
+/// Structured data ID; 32473 is the enterprise number reserved for examples
+const SD_ID: &str = "fields@32473";
+
+/// Events buffered for the sender thread
+const QUEUE_LEN: usize = 8192;
+
+/// Where syslog messages are sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogAddress {
+    Udp(String),
+    Tcp(String),
+    Unix(String),
+}
+
+/// Syslog output settings, from configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogConfig {
+    pub address: SyslogAddress,
+    /// Facility code, e.g. 3 for `daemon` or 16 for `local0`
+    pub facility: u8,
+}
+
+impl SyslogConfig {
+    /// Parse `SYSLOG_ADDRESS` (`udp://host:port`, `tcp://host:port` or
+    /// `unix:///path`) and `SYSLOG_FACILITY` (a name such as `daemon`)
+    pub fn parse(address: &str, facility: &str) -> anyhow::Result<Self> {
+        let address = match address.trim().split_once("://") {
+            Some(("udp", host)) if !host.is_empty() => SyslogAddress::Udp(host.to_string()),
+            Some(("tcp", host)) if !host.is_empty() => SyslogAddress::Tcp(host.to_string()),
+            Some(("unix", path)) if path.starts_with('/') => SyslogAddress::Unix(path.to_string()),
+            _ => anyhow::bail!(
+                "expected udp://host:port, tcp://host:port or unix:///path, got '{}'",
+                address
+            ),
+        };
+        let facility = match facility.trim().to_ascii_lowercase().as_str() {
+            "kern" => 0,
+            "user" => 1,
+            "mail" => 2,
+            "daemon" => 3,
+            "auth" => 4,
+            "syslog" => 5,
+            "lpr" => 6,
+            "news" => 7,
+            "uucp" => 8,
+            "cron" => 9,
+            "authpriv" => 10,
+            "ftp" => 11,
+            name => match name
+                .strip_prefix("local")
+                .and_then(|n| n.parse::<u8>().ok())
+            {
+                Some(n @ 0..=7) => 16 + n,
+                _ => anyhow::bail!("unknown facility '{}'", facility),
+            },
+        };
+        Ok(SyslogConfig { address, facility })
+    }
+}
+
+/// Syslog severity of a tracing level
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// A header field: printable ASCII without spaces, or `-` if empty
+fn header(value: &str, max: usize) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { '-' })
+        .take(max)
+        .collect();
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+/// Format an event as an RFC 5424 message
+pub fn format(
+    record: &LogRecord,
+    facility: u8,
+    hostname: &str,
+    timestamp: DateTime<Utc>,
+) -> String {
+    let mut message = format!(
+        "<{}>1 {} {} {} {} - [{} target=\"{}\"",
+        facility * 8 + severity(record.level),
+        timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+        header(hostname, 255),
+        header(env!("CARGO_PKG_NAME"), 48),
+        std::process::id(),
+        SD_ID,
+        escape(record.target)
+    );
+    for (name, value) in &record.fields {
+        // Parameter names exclude `=`, space, `]` and `"`, up to 32 characters
+        let name: String = name
+            .chars()
+            .filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"'))
+            .take(32)
+            .collect();
+        if !name.is_empty() {
+            message.push_str(&format!(" {}=\"{}\"", name, escape(value)));
+        }
+    }
+    message.push_str("] ");
+    message.push_str(&record.message);
+    message
+}
+
+/// Escape a structured data parameter value
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp(String, Option<TcpStream>),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+impl Transport {
+    fn connect(address: &SyslogAddress) -> std::io::Result<Self> {
+        Ok(match address {
+            SyslogAddress::Udp(host) => {
+                let target = host.to_socket_addrs()?.next().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::NotFound, "no address")
+                })?;
+                let local = if target.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(target)?;
+                Transport::Udp(socket)
+            }
+            SyslogAddress::Tcp(host) => {
+                Transport::Tcp(host.clone(), Some(TcpStream::connect(host.as_str())?))
+            }
+            #[cfg(unix)]
+            SyslogAddress::Unix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Transport::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            SyslogAddress::Unix(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "unix sockets are not supported on this platform",
+                ))
+            }
+        })
+    }
+
+    fn send(&mut self, message: &str) {
+        // Failures are dropped: there is nowhere left to log them
+        match self {
+            Transport::Udp(socket) => {
+                let _ = socket.send(message.as_bytes());
+            }
+            Transport::Tcp(host, stream) => {
+                if stream.is_none() {
+                    *stream = TcpStream::connect(host.as_str()).ok();
+                }
+                let framed = format!("{} {}", message.len(), message);
+                if let Some(connected) = stream {
+                    if connected.write_all(framed.as_bytes()).is_err() {
+                        // Reconnect for the next message
+                        *stream = None;
+                    }
+                }
+            }
+            #[cfg(unix)]
+            Transport::Unix(socket) => {
+                let _ = socket.send(message.as_bytes());
+            }
+        }
+    }
+}
+
+fn run(mut transport: Transport, messages: Receiver<String>) {
+    for message in messages {
+        transport.send(&message);
+    }
+}
+
+/// Layer sending every event to syslog
+pub struct SyslogLayer {
+    facility: u8,
+    hostname: String,
+    sender: SyncSender<String>,
+}
+
+impl SyslogLayer {
+    /// Connect to the syslog daemon and start the sender thread
+    pub fn new(config: &SyslogConfig, hostname: &str) -> anyhow::Result<Self> {
+        let transport = Transport::connect(&config.address).map_err(|e| {
+            anyhow::anyhow!("Failed to connect to syslog at {:?}: {}", config.address, e)
+        })?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("syslog".to_string())
+            .spawn(move || run(transport, receiver))?;
+        Ok(SyslogLayer {
+            facility: config.facility,
+            hostname: hostname.to_string(),
+            sender,
+        })
+    }
+}
+
+impl<S> Layer<S> for SyslogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let record = LogRecord::new(event, &ctx);
+        let message = format(&record, self.facility, &self.hostname, Utc::now());
+        let _ = self.sender.try_send(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::SpanFieldsLayer;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            SyslogConfig::parse("udp://logs.lan:514", "local3").unwrap(),
+            SyslogConfig {
+                address: SyslogAddress::Udp("logs.lan:514".to_string()),
+                facility: 19,
+            }
+        );
+        let unix = SyslogConfig::parse("unix:///dev/log", "Daemon").unwrap();
+        assert_eq!(unix.address, SyslogAddress::Unix("/dev/log".to_string()));
+        assert_eq!(unix.facility, 3);
+        assert!(SyslogConfig::parse("logs.lan:514", "daemon").is_err());
+        assert!(SyslogConfig::parse("tcp://logs.lan:601", "local8").is_err());
+    }
+
+    #[test]
+    fn test_sends_structured_messages() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let config = SyslogConfig::parse(
+            &format!("udp://{}", receiver.local_addr().unwrap()),
+            "local0",
+        )
+        .unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(SpanFieldsLayer)
+            .with(SyslogLayer::new(&config, "home server").unwrap());
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", method = "GET");
+            let _entered = span.enter();
+            tracing::warn!(status = 503, path = "/a\"]", "upstream down");
+        });
+
+        let mut buf = [0; 2048];
+        let len = receiver.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+        let (head, rest) = message.split_once(" - [").unwrap();
+        assert!(head.starts_with("<132>1 20"), "{}", head);
+        assert!(head.ends_with(&format!(
+            " home-server rust-selfhost-server {}",
+            std::process::id()
+        )));
+        assert_eq!(
+            rest,
+            "fields@32473 target=\"rust_selfhost_server::logging::syslog::tests\" \
+             method=\"GET\" status=\"503\" path=\"/a\\\"\\]\"] upstream down"
+        );
+    }
+}
@@ -0,0 +1,587 @@
+//! Inbound mail, turned into notes.
+//!
+//! Messages arrive two ways:
+//!
+//! - with `MAIL_IMAP_URL` set, the leader polls the mailbox every
+//!   `MAIL_POLL_INTERVAL` and marks what it took in as seen (see
+//!   [`imap`] for what the client supports)
+//! - with `MAIL_WEBHOOK_SECRET` set, a relay such as Postfix or an email
+//!   service's inbound hook posts the raw message to `POST /webhooks/mail`
+//!   with the secret as a bearer token
+//!
+//! Each message becomes a note in `MAIL_NOTE_FOLDER` tagged `email`: the
+//! subject is its title and the text part its body, under the sender and
+//! date. With file storage (`STORAGE_DIR`), attachments are stored under
+//! `mail/` and linked from the note; without it they are listed but
+//! dropped, and stored attachments count towards storage usage. Messages
+//! are taken in once by `Message-ID`, so a relay may retry, and only from
+//! `MAIL_ALLOWED_SENDERS` when it is set. That check reads the `From:`
+//! header, which the sender chooses freely; it filters mistakes and noise,
+//! and keeping strangers out is left to the relay or mailbox. When pub/sub
+//! is enabled, a `mail.received` event is published on the `mail` channel
+//! for every note created.
+
+pub mod imap;
+pub mod mime;
+
+use anyhow::Result;
+use axum::{
+    body::{to_bytes, Bytes},
+    extract::{Request, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    routing::post,
+    Router,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth;
+use crate::crypto::{constant_time_eq, hex};
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::jobs::JobStats;
+use crate::leader::Leadership;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::notes::{self, NewNote, Note};
+use crate::pubsub::PubSub;
+use crate::storage::Storage;
+use crate::usage::{Meter, UsageRecorder};
+use crate::{t, AppState};
+use imap::ImapConfig;
+
+/// Job type the poller's messages are counted under
+pub const JOB: &str = "mail_inbound";
+
+/// Channel events are published on
+pub const CHANNEL: &str = "mail";
+
+/// Tag of notes created from mail
+const TAG: &str = "email";
+
+/// How inbound mail is received and filed
+#[derive(Debug, Clone)]
+pub struct MailConfig {
+    pub imap: Option<ImapConfig>,
+    pub poll_interval: Duration,
+    pub webhook_secret: Option<String>,
+    /// Folder notes are created in
+    pub folder: String,
+    /// Addresses or `@domain`s accepted, or empty to accept anyone
+    pub allowed_senders: Vec<String>,
+    /// Largest message accepted, in bytes
+    pub max_message_size: u64,
+}
+
+impl MailConfig {
+    /// Whether mail from `sender` is taken in
+    pub fn allows(&self, sender: Option<&str>) -> bool {
+        if self.allowed_senders.is_empty() {
+            return true;
+        }
+        let Some(sender) = sender else {
+            return false;
+        };
+        self.allowed_senders
+            .iter()
+            .any(|allowed| match allowed.strip_prefix('@') {
+                Some(domain) => sender.rsplit_once('@').is_some_and(|(_, d)| d == domain),
+                None => allowed == sender,
+            })
+    }
+}
+
+/// Parse `MAIL_ALLOWED_SENDERS`, e.g. `me@example.com,@example.org`
+pub fn parse_senders(value: &str) -> Result<Vec<String>> {
+    value
+        .split(',')
+        .map(|sender| sender.trim().to_ascii_lowercase())
+        .filter(|sender| !sender.is_empty())
+        .map(|sender| {
+            let (local, domain) = sender.split_once('@').unwrap_or(("", ""));
+            anyhow::ensure!(
+                !domain.is_empty() && !domain.contains('@') && !local.contains(char::is_whitespace),
+                "'{}' is not an address or @domain",
+                sender
+            );
+            Ok(sender)
+        })
+        .collect()
+}
+
+/// What became of a delivered message
+#[derive(Debug)]
+pub enum Delivery {
+    Created(Note),
+    /// Taken in before, as the note given if it was created
+    Duplicate(Option<Uuid>),
+}
+
+/// Everything delivering a message needs, shared by the poller and webhook
+#[derive(Clone)]
+pub struct Inbox {
+    config: Arc<MailConfig>,
+    storage: Option<Arc<Storage>>,
+    pubsub: Option<Arc<PubSub>>,
+}
+
+impl Inbox {
+    pub fn new(
+        config: MailConfig,
+        storage: Option<Arc<Storage>>,
+        pubsub: Option<Arc<PubSub>>,
+    ) -> Self {
+        Inbox {
+            config: Arc::new(config),
+            storage,
+            pubsub,
+        }
+    }
+
+    /// Turn a raw message into a note, unless it was taken in before
+    ///
+    /// The `Message-ID` is claimed in the transaction creating the note, so
+    /// a retry arriving meanwhile waits for it and is then a duplicate.
+    pub async fn deliver(&self, pool: &PgPool, meter: &Meter, raw: &[u8]) -> ApiResult<Delivery> {
+        if raw.len() as u64 > self.config.max_message_size {
+            return Err(ApiError::Validation(t!(
+                "mail-too-large",
+                max = self.config.max_message_size
+            )));
+        }
+        let message = mime::parse(raw)
+            .map_err(|e| ApiError::BadRequest(t!("mail-invalid", reason = e.to_string())))?;
+        if !self.config.allows(message.from.as_deref()) {
+            return Err(ApiError::Forbidden(t!(
+                "mail-sender",
+                sender = message.from.clone().unwrap_or_default()
+            )));
+        }
+        let id = message
+            .message_id
+            .clone()
+            .unwrap_or_else(|| format!("sha256:{}", hex(&Sha256::digest(raw))));
+        let mut tx = pool.begin().await?;
+        let claimed: Option<String> = sqlx::query_scalar(
+            "INSERT INTO inbound_mail (message_id, sender, subject) VALUES ($1, $2, $3)
+             ON CONFLICT (message_id) DO NOTHING
+             RETURNING message_id",
+        )
+        .bind(&id)
+        .bind(&message.from)
+        .bind(&message.subject)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if claimed.is_none() {
+            tx.rollback().await?;
+            let note_id: Option<Uuid> =
+                sqlx::query_scalar("SELECT note_id FROM inbound_mail WHERE message_id = $1")
+                    .bind(&id)
+                    .fetch_optional(pool)
+                    .await?
+                    .flatten();
+            return Ok(Delivery::Duplicate(note_id));
+        }
+
+        let stored = self.store_attachments(&id, &message, meter).await?;
+        let note = notes::create(
+            &mut tx,
+            &NewNote {
+                title: title(&message.subject),
+                body: body(&message, &stored),
+                folder: self.config.folder.clone(),
+                tags: vec![TAG.to_string()],
+            },
+        )
+        .await?;
+        sqlx::query("UPDATE inbound_mail SET note_id = $2, attachments = $3 WHERE message_id = $1")
+            .bind(&id)
+            .bind(note.id)
+            .bind(stored.iter().filter(|a| a.key.is_some()).count() as i32)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        if let Some(pubsub) = &self.pubsub {
+            pubsub.publish(
+                CHANNEL,
+                json!({
+                    "type": "mail.received",
+                    "message_id": id,
+                    "from": message.from,
+                    "subject": message.subject,
+                    "note_id": note.id,
+                }),
+            );
+        }
+        Ok(Delivery::Created(note))
+    }
+
+    /// Store a message's attachments, when file storage is enabled
+    async fn store_attachments(
+        &self,
+        id: &str,
+        message: &mime::Message,
+        meter: &Meter,
+    ) -> ApiResult<Vec<StoredAttachment>> {
+        let prefix = format!("mail/{}", &hex(&Sha256::digest(id.as_bytes()))[..16]);
+        let mut stored = Vec::with_capacity(message.attachments.len());
+        for (index, attachment) in message.attachments.iter().enumerate() {
+            let name = match attachment.filename.trim() {
+                "" => format!("attachment-{}", index + 1),
+                name => name.to_string(),
+            };
+            let key = match &self.storage {
+                Some(storage) => {
+                    let key = format!("{}/{}-{}", prefix, index + 1, file_key_name(&name));
+                    let data = Bytes::copy_from_slice(&attachment.data);
+                    let body = futures_util::stream::iter([Ok::<_, Infallible>(data)]);
+                    let previous = storage.size(&key).await?;
+                    let file = storage.put(&key, body, None).await?;
+                    meter.storage(file.size as i64 - previous.unwrap_or(0) as i64);
+                    Some(file.key)
+                }
+                None => None,
+            };
+            stored.push(StoredAttachment {
+                name,
+                size: attachment.data.len(),
+                key,
+            });
+        }
+        Ok(stored)
+    }
+
+    /// Take in the unseen messages of the mailbox, returning how many
+    ///
+    /// Messages that can never be taken in (too large, malformed, from a
+    /// sender not allowed) are marked seen and skipped; on any other error
+    /// the message is left unseen for the next poll.
+    pub async fn poll(
+        &self,
+        pool: &PgPool,
+        imap: &ImapConfig,
+        meter: &Meter,
+        jobs: &JobStats,
+    ) -> Result<usize> {
+        let max = self.config.max_message_size;
+        let mut connection = imap::Connection::connect(imap, max as usize).await?;
+        let mut delivered = 0;
+        for uid in connection.unseen().await? {
+            let size = connection.size(uid).await?;
+            let result = if size > max {
+                Err(ApiError::Validation(t!("mail-too-large", max = max)))
+            } else {
+                let raw = connection.fetch(uid).await?;
+                self.deliver(pool, meter, &raw).await
+            };
+            jobs.record(JOB, result.is_ok());
+            match result {
+                Ok(_) => delivered += 1,
+                Err(ApiError::Internal(e)) => return Err(e),
+                Err(e) => tracing::warn!("Skipping inbound message {}: {}", uid, e),
+            }
+            connection.mark_seen(uid).await?;
+        }
+        connection.logout().await?;
+        Ok(delivered)
+    }
+}
+
+/// An attachment as linked from its note
+struct StoredAttachment {
+    name: String,
+    size: usize,
+    /// Storage key, if it was stored
+    key: Option<String>,
+}
+
+/// A note title from a subject
+fn title(subject: &str) -> String {
+    let subject = subject.trim();
+    if subject.is_empty() {
+        return "(no subject)".to_string();
+    }
+    subject.chars().take(notes::MAX_TITLE_LEN).collect()
+}
+
+/// A note body from a message and its attachments
+fn body(message: &mime::Message, attachments: &[StoredAttachment]) -> String {
+    let mut out = String::new();
+    if let Some(from) = &message.from {
+        let _ = writeln!(out, "From: {}  ", from);
+    }
+    if let Some(date) = &message.date {
+        let _ = writeln!(out, "Date: {}  ", date);
+    }
+    out.push('\n');
+    let mut footer = String::new();
+    if !attachments.is_empty() {
+        footer.push_str("\n\nAttachments:\n");
+        for attachment in attachments {
+            let _ = match &attachment.key {
+                Some(key) => writeln!(
+                    footer,
+                    "- [{}](/api/v1/files/{}) ({} bytes)",
+                    attachment.name, key, attachment.size
+                ),
+                None => writeln!(
+                    footer,
+                    "- {} ({} bytes, not stored)",
+                    attachment.name, attachment.size
+                ),
+            };
+        }
+    }
+    // Cut the text, not the header or attachments, to fit a note
+    let room = notes::MAX_BODY_BYTES.saturating_sub(out.len() + footer.len());
+    let mut end = message.text.len().min(room);
+    while !message.text.is_char_boundary(end) {
+        end -= 1;
+    }
+    out.push_str(&message.text[..end]);
+    out.push_str(&footer);
+    out
+}
+
+/// A file name safe to use as the last segment of a storage key
+fn file_key_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "attachment".to_string(),
+        name => name.chars().take(128).collect(),
+    }
+}
+
+/// Spawn the task polling the mailbox every `MAIL_POLL_INTERVAL`
+///
+/// Only the leader polls, so replicas do not take in a message twice.
+pub fn spawn(
+    inbox: Inbox,
+    imap: ImapConfig,
+    pool: PgPool,
+    usage: Option<UsageRecorder>,
+    jobs: JobStats,
+    leadership: Leadership,
+) -> tokio::task::JoinHandle<()> {
+    jobs.register(JOB);
+    tokio::spawn(async move {
+        let meter = Meter::system(usage, JOB);
+        let mut ticker = tokio::time::interval(inbox.config.poll_interval);
+        loop {
+            ticker.tick().await;
+            if !leadership.is_leader() {
+                continue;
+            }
+            match inbox.poll(&pool, &imap, &meter, &jobs).await {
+                Ok(0) => {}
+                Ok(delivered) => tracing::info!("Took in {} messages from IMAP", delivered),
+                Err(e) => tracing::warn!("Polling IMAP at {} failed: {:#}", imap.address, e),
+            }
+        }
+    })
+}
+
+/// Route module owning the inbound mail table, mounted with mail enabled
+pub struct MailModule;
+
+impl RouteModule for MailModule {
+    fn name(&self) -> &'static str {
+        "mail"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_inbound_mail",
+            kind: MigrationKind::Expand,
+            sql: "
+            CREATE TABLE IF NOT EXISTS inbound_mail (
+                message_id TEXT PRIMARY KEY,
+                sender TEXT,
+                subject TEXT NOT NULL,
+                note_id UUID,
+                attachments INT NOT NULL DEFAULT 0,
+                received_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        }]
+    }
+}
+
+/// Route module receiving relayed mail, mounted with `MAIL_WEBHOOK_SECRET`
+pub struct MailWebhookModule;
+
+impl RouteModule for MailWebhookModule {
+    fn name(&self) -> &'static str {
+        "mail_webhook"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/webhooks/mail", post(webhook))
+    }
+}
+
+/// `POST /webhooks/mail` - take in a raw RFC 5322 message
+pub async fn webhook(
+    State(state): State<AppState>,
+    Ext(inbox): Ext<Inbox>,
+    meter: Meter,
+    request: Request,
+) -> ApiResult<Response> {
+    let secret = inbox.config.webhook_secret.as_deref().unwrap_or_default();
+    let authorized = auth::bearer_token(&request).is_some_and(|token| {
+        !secret.is_empty() && constant_time_eq(token.as_bytes(), secret.as_bytes())
+    });
+    if !authorized {
+        return Err(ApiError::Unauthorized(t!("mail-unauthorized")));
+    }
+    let max = inbox.config.max_message_size;
+    let raw = to_bytes(request.into_body(), max as usize)
+        .await
+        .map_err(|_| ApiError::Validation(t!("mail-too-large", max = max)))?;
+    Ok(match inbox.deliver(state.db.pool(), &meter, &raw).await? {
+        Delivery::Created(note) => {
+            (StatusCode::CREATED, Json(json!({ "note": note }))).into_response()
+        }
+        Delivery::Duplicate(note_id) => {
+            Json(json!({ "duplicate": true, "note_id": note_id })).into_response()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allowed: &str) -> MailConfig {
+        MailConfig {
+            imap: None,
+            poll_interval: Duration::from_secs(60),
+            webhook_secret: None,
+            folder: "inbox".into(),
+            allowed_senders: parse_senders(allowed).unwrap(),
+            max_message_size: 1024,
+        }
+    }
+
+    #[test]
+    fn test_allowed_senders() {
+        assert!(config("").allows(None));
+        let config = config("Me@Example.com, @example.org");
+        assert!(config.allows(Some("me@example.com")));
+        assert!(config.allows(Some("anyone@example.org")));
+        assert!(!config.allows(Some("anyone@sub.example.org")));
+        assert!(!config.allows(Some("you@example.com")));
+        assert!(!config.allows(None));
+        assert!(parse_senders("example.com").is_err());
+        assert!(parse_senders("a@b@c").is_err());
+    }
+
+    #[test]
+    fn test_note_from_message() {
+        let message = mime::Message {
+            from: Some("alice@example.com".into()),
+            date: Some("Fri, 16 Oct 2026 09:00:00 +0000".into()),
+            text: "See attached.".into(),
+            ..mime::Message::default()
+        };
+        let attachments = [
+            StoredAttachment {
+                name: "Q3 report.pdf".into(),
+                size: 8,
+                key: Some(format!("mail/abc/1-{}", file_key_name("Q3 report.pdf"))),
+            },
+            StoredAttachment {
+                name: "photo.jpg".into(),
+                size: 3,
+                key: None,
+            },
+        ];
+        assert_eq!(
+            body(&message, &attachments),
+            "From: alice@example.com  \nDate: Fri, 16 Oct 2026 09:00:00 +0000  \n\n\
+             See attached.\n\nAttachments:\n\
+             - [Q3 report.pdf](/api/v1/files/mail/abc/1-Q3_report.pdf) (8 bytes)\n\
+             - photo.jpg (3 bytes, not stored)\n"
+        );
+        assert_eq!(title("  "), "(no subject)");
+        assert_eq!(title(&"x".repeat(300)).len(), notes::MAX_TITLE_LEN);
+        assert_eq!(file_key_name("../.env"), "_.env");
+        assert_eq!(file_key_name("..."), "attachment");
+    }
+
+    #[tokio::test]
+    async fn test_delivers_messages_once() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        crate::module::run_migrations(pool, &[Arc::new(MailModule)])
+            .await
+            .unwrap();
+        let inbox = Inbox::new(config("@example.com"), None, None);
+        let raw = b"From: Alice <alice@example.com>\r\n\
+            Subject: Groceries\r\n\
+            Message-ID: <list-1@example.com>\r\n\
+            \r\n\
+            Milk, eggs\r\n";
+
+        let Delivery::Created(note) = inbox.deliver(pool, &Meter::default(), raw).await.unwrap()
+        else {
+            panic!("expected a new note");
+        };
+        assert_eq!(note.title, "Groceries");
+        assert_eq!(note.folder, "inbox");
+        assert_eq!(note.tags, ["email"]);
+        assert!(note.body.ends_with("\nMilk, eggs"));
+        match inbox.deliver(pool, &Meter::default(), raw).await.unwrap() {
+            Delivery::Duplicate(note_id) => assert_eq!(note_id, Some(note.id)),
+            other => panic!("expected a duplicate, got {:?}", other),
+        }
+
+        // A relay retrying before the first delivery finished
+        let retried = b"From: alice@example.com\r\n\
+            Subject: Retried\r\n\
+            Message-ID: <retry-1@example.com>\r\n\
+            \r\n\
+            Twice\r\n";
+        let meter = Meter::default();
+        let (first, second) = tokio::join!(
+            inbox.deliver(pool, &meter, retried),
+            inbox.deliver(pool, &meter, retried)
+        );
+        let created = [first.unwrap(), second.unwrap()]
+            .iter()
+            .filter(|delivery| matches!(delivery, Delivery::Created(_)))
+            .count();
+        assert_eq!(created, 1);
+
+        let stranger = b"From: eve@example.net\r\nSubject: Hi\r\n\r\nHello\r\n";
+        assert!(matches!(
+            inbox.deliver(pool, &Meter::default(), stranger).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            inbox.deliver(pool, &Meter::default(), &[b'x'; 2048]).await,
+            Err(ApiError::Validation(_))
+        ));
+    }
+}
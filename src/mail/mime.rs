@@ -0,0 +1,444 @@
+//! Minimal RFC 5322 / MIME message parser.
+//!
+//! Only what turning a message into a note needs is implemented: the
+//! sender, subject, date and message id, RFC 2047 encoded words, nested
+//! multipart bodies, base64 and quoted-printable transfer encodings and the
+//! UTF-8, US-ASCII and ISO-8859-1 charsets (others are read as UTF-8,
+//! replacing invalid bytes). The first `text/plain` part is the text, or
+//! the first `text/html` part with its tags stripped; parts with a file
+//! name or an `attachment` disposition are attachments.
+
+use anyhow::Result;
+use base64::Engine;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::html;
+
+/// Deepest multipart nesting followed
+const MAX_DEPTH: usize = 8;
+
+/// A parsed message
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message {
+    pub message_id: Option<String>,
+    /// Address of the sender, lowercased
+    pub from: Option<String>,
+    pub subject: String,
+    pub date: Option<String>,
+    pub text: String,
+    pub attachments: Vec<Attachment>,
+}
+
+/// A file attached to a message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Headers with lowercased names, in order
+struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Parse a raw message
+pub fn parse(raw: &[u8]) -> Result<Message> {
+    let (headers, body) = split(raw);
+    anyhow::ensure!(!headers.0.is_empty(), "no headers");
+    let mut message = Message {
+        message_id: headers
+            .get("message-id")
+            .map(|id| {
+                id.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+            .filter(|id| !id.is_empty()),
+        from: headers.get("from").and_then(address),
+        subject: headers.get("subject").map(decode_words).unwrap_or_default(),
+        date: headers.get("date").map(|date| date.trim().to_string()),
+        ..Message::default()
+    };
+    let mut html = None;
+    walk(&headers, body, 0, &mut message, &mut html);
+    if message.text.is_empty() {
+        message.text = html.map(|html| html_to_text(&html)).unwrap_or_default();
+    }
+    message.text = message.text.replace("\r\n", "\n").trim().to_string();
+    Ok(message)
+}
+
+/// Collect the text and attachments of a part and its children
+fn walk(
+    headers: &Headers,
+    body: &[u8],
+    depth: usize,
+    message: &mut Message,
+    html: &mut Option<String>,
+) {
+    let (mime, params) = headers
+        .get("content-type")
+        .map(content_type)
+        .unwrap_or_else(|| ("text/plain".to_string(), HashMap::new()));
+    if let Some(boundary) = params
+        .get("boundary")
+        .filter(|_| mime.starts_with("multipart/"))
+    {
+        if depth < MAX_DEPTH {
+            for part in multipart(body, boundary) {
+                let (headers, body) = split(part);
+                walk(&headers, body, depth + 1, message, html);
+            }
+        }
+        return;
+    }
+    let data = decode_transfer(headers.get("content-transfer-encoding"), body);
+    let disposition = headers.get("content-disposition").map(content_type);
+    let filename = disposition
+        .as_ref()
+        .and_then(|(_, params)| params.get("filename"))
+        .or_else(|| params.get("name"))
+        .cloned();
+    let attached = disposition
+        .as_ref()
+        .is_some_and(|(kind, _)| kind == "attachment");
+    if filename.is_none() && !attached {
+        let charset = params.get("charset").map(String::as_str);
+        if mime == "text/plain" && message.text.is_empty() {
+            message.text = decode_charset(&data, charset);
+            return;
+        }
+        if mime == "text/html" && html.is_none() {
+            *html = Some(decode_charset(&data, charset));
+            return;
+        }
+        if mime.starts_with("text/") || mime.starts_with("multipart/") {
+            return;
+        }
+    }
+    message.attachments.push(Attachment {
+        filename: filename.unwrap_or_default(),
+        content_type: mime,
+        data,
+    });
+}
+
+/// Split a message or part into its headers and body
+fn split(raw: &[u8]) -> (Headers, &[u8]) {
+    let (head, body) = match find(raw, b"\r\n\r\n") {
+        Some(at) => (&raw[..at], &raw[at + 4..]),
+        None => match find(raw, b"\n\n") {
+            Some(at) => (&raw[..at], &raw[at + 2..]),
+            None => (raw, &raw[raw.len()..]),
+        },
+    };
+    let head = String::from_utf8_lossy(head);
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in head.lines() {
+        if line.starts_with([' ', '\t']) {
+            // A folded continuation of the previous header
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    (Headers(headers), body)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// The parts of a multipart body, without their delimiters
+fn multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut at = 0;
+    while at < body.len() {
+        let end = body[at..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(body.len(), |i| at + i + 1);
+        let line = body[at..end].trim_ascii_end();
+        if let Some(rest) = line.strip_prefix(delimiter.as_bytes()) {
+            if let Some(start) = start {
+                // The line break before a delimiter belongs to it
+                let mut part_end = at;
+                if body[..part_end].ends_with(b"\r\n") {
+                    part_end -= 2;
+                } else if body[..part_end].ends_with(b"\n") {
+                    part_end -= 1;
+                }
+                parts.push(&body[start..part_end.max(start)]);
+            }
+            if rest.starts_with(b"--") {
+                break;
+            }
+            start = Some(end);
+        }
+        at = end;
+    }
+    parts
+}
+
+/// A `Content-Type` or `Content-Disposition` value and its parameters,
+/// names lowercased
+fn content_type(value: &str) -> (String, HashMap<String, String>) {
+    let mut pieces = split_params(value).into_iter();
+    let kind = pieces
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let mut params = HashMap::new();
+    for piece in pieces {
+        let Some((name, value)) = piece.split_once('=') else {
+            continue;
+        };
+        let name = name.trim().to_ascii_lowercase();
+        let value = value.trim();
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+            None => value.to_string(),
+        };
+        match name.strip_suffix('*') {
+            // RFC 2231: charset'language'percent-encoded
+            Some(name) => {
+                let encoded = value.splitn(3, '\'').nth(2).unwrap_or(&value);
+                let bytes = percent_decode(encoded);
+                params.insert(
+                    name.to_string(),
+                    String::from_utf8_lossy(&bytes).into_owned(),
+                );
+            }
+            None => {
+                params.insert(name, decode_words(&value));
+            }
+        }
+    }
+    (kind, params)
+}
+
+/// Split on `;` outside quoted strings
+fn split_params(value: &str) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            ';' if !quoted => pieces.push(String::new()),
+            _ => {
+                if c == '"' && !escaped {
+                    quoted = !quoted;
+                }
+                escaped = c == '\\' && !escaped;
+                pieces.last_mut().expect("starts with a piece").push(c);
+            }
+        }
+    }
+    pieces
+}
+
+/// Decode `%XX` escapes, leaving malformed ones as they are
+pub fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// The address in a `From` header, e.g. `"Alice" <alice@example.com>`
+fn address(value: &str) -> Option<String> {
+    let address = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value,
+    };
+    let address = address.trim().to_ascii_lowercase();
+    address.contains('@').then_some(address)
+}
+
+static ENCODED_WORD: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"=\?([^?\s]+)\?([bBqQ])\?([^?\s]*)\?=").expect("encoded word pattern is valid")
+});
+
+/// Decode RFC 2047 encoded words, e.g. `=?UTF-8?B?SGVsbG8=?=`
+pub fn decode_words(value: &str) -> String {
+    // Whitespace between adjacent encoded words is dropped
+    static BETWEEN: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\?=\s+=\?").expect("pattern is valid"));
+    let value = BETWEEN.replace_all(value, "?==?");
+    ENCODED_WORD
+        .replace_all(&value, |caps: &regex::Captures| {
+            let bytes = match &caps[2] {
+                "b" | "B" => base64::engine::general_purpose::STANDARD
+                    .decode(&caps[3])
+                    .unwrap_or_default(),
+                _ => quoted_printable(caps[3].replace('_', " ").as_bytes()),
+            };
+            decode_charset(&bytes, Some(&caps[1]))
+        })
+        .trim()
+        .to_string()
+}
+
+fn decode_transfer(encoding: Option<&str>, body: &[u8]) -> Vec<u8> {
+    match encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+        Some("base64") => {
+            let compact: Vec<u8> = body
+                .iter()
+                .copied()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect();
+            base64::engine::general_purpose::STANDARD
+                .decode(&compact)
+                .unwrap_or_else(|_| body.to_vec())
+        }
+        Some("quoted-printable") => quoted_printable(body),
+        _ => body.to_vec(),
+    }
+}
+
+fn quoted_printable(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != b'=' {
+            out.push(input[i]);
+            i += 1;
+            continue;
+        }
+        match &input[i + 1..] {
+            // Soft line break
+            [b'\r', b'\n', ..] => i += 3,
+            [b'\n', ..] => i += 2,
+            [a, b, ..] if a.is_ascii_hexdigit() && b.is_ascii_hexdigit() => {
+                let hex = [*a, *b];
+                let hex = std::str::from_utf8(&hex).expect("hex digits are ASCII");
+                out.push(u8::from_str_radix(hex, 16).expect("checked hex digits"));
+                i += 3;
+            }
+            _ => {
+                out.push(b'=');
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn decode_charset(bytes: &[u8], charset: Option<&str>) -> String {
+    match charset.map(str::to_ascii_lowercase).as_deref() {
+        Some("iso-8859-1" | "latin1" | "windows-1252") => {
+            bytes.iter().map(|&b| b as char).collect()
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Readable text of an HTML body
+fn html_to_text(input: &str) -> String {
+    static HIDDEN: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?is)<(script|style|head)\b.*?</(script|style|head)\s*>")
+            .expect("pattern is valid")
+    });
+    static BREAKS: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?i)<br\s*/?>|</(p|div|li|tr|h[1-6])\s*>").expect("pattern is valid")
+    });
+    static TAGS: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"<[^>]*>").expect("pattern is valid"));
+    let text = HIDDEN.replace_all(input, "");
+    let text = BREAKS.replace_all(&text, "\n");
+    let text = TAGS.replace_all(&text, "");
+    html::unescape(&text)
+        .lines()
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_a_plain_message() {
+        let raw = b"From: \"Alice\" <Alice@Example.com>\r\n\
+            Subject: =?UTF-8?B?R3LDvMOfZQ==?=\r\n  from =?iso-8859-1?Q?M=FCnchen?=\r\n\
+            Message-ID: <abc@example.com>\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\
+            \r\n\
+            Caf=C3=A9 opens at 9=\r\n:00.\r\n";
+        let message = parse(raw).unwrap();
+        assert_eq!(message.from.as_deref(), Some("alice@example.com"));
+        assert_eq!(message.subject, "Grüße from München");
+        assert_eq!(message.message_id.as_deref(), Some("abc@example.com"));
+        assert_eq!(message.text, "Café opens at 9:00.");
+        assert!(message.attachments.is_empty());
+        assert!(parse(b"").is_err());
+    }
+
+    #[test]
+    fn test_parses_multipart_with_attachments() {
+        let raw = "From: bob@example.com\n\
+            Subject: Report\n\
+            Content-Type: multipart/mixed; boundary=\"outer\"\n\
+            \n\
+            preamble\n\
+            --outer\n\
+            Content-Type: multipart/alternative; boundary=inner\n\
+            \n\
+            --inner\n\
+            Content-Type: text/html\n\
+            \n\
+            <p>Hello&amp;bye</p><style>p{}</style>\n\
+            --inner--\n\
+            --outer\n\
+            Content-Type: application/pdf; name=\"ignored.pdf\"\n\
+            Content-Disposition: attachment; filename*=utf-8''r%C3%A9sum%C3%A9.pdf\n\
+            Content-Transfer-Encoding: base64\n\
+            \n\
+            JVBERi0x\n\
+            LjQ=\n\
+            --outer--\n\
+            epilogue\n";
+        let message = parse(raw.as_bytes()).unwrap();
+        assert_eq!(message.text, "Hello&bye");
+        assert_eq!(message.attachments.len(), 1);
+        let attachment = &message.attachments[0];
+        assert_eq!(attachment.filename, "résumé.pdf");
+        assert_eq!(attachment.content_type, "application/pdf");
+        assert_eq!(attachment.data, b"%PDF-1.4");
+    }
+}
@@ -0,0 +1,295 @@
+//! Markdown rendering.
+//!
+//! Markdown is CommonMark with the common extensions (tables,
+//! strikethrough, task lists, footnotes). Raw HTML in the source is allowed
+//! by CommonMark, so the rendered HTML is always passed through an
+//! allow-list sanitizer before it is served: scripts, event handlers,
+//! `javascript:` links and the like are dropped, and links get
+//! `rel="noopener noreferrer"`. `MARKDOWN_ALLOWED_TAGS` narrows or widens
+//! the tags kept.
+//!
+//! Fenced code blocks with a known language are highlighted server-side
+//! into `<span class="hl-...">` elements; the matching stylesheet is served
+//! at `GET /api/v1/render/highlight.css`.
+
+use axum::{
+    extract::Query,
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+use pulldown_cmark::{html, CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::module::RouteModule;
+use crate::{t, AppState};
+
+/// Largest markdown document rendered, in bytes
+const MAX_SOURCE_BYTES: usize = 1024 * 1024;
+
+/// Highlighted tokens get classes such as `hl-keyword hl-control`
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// Stylesheet served when no theme is asked for
+const DEFAULT_THEME: &str = "InspiredGitHub";
+
+/// Tags the sanitizer can never allow, because their content is dropped
+pub const FORBIDDEN_TAGS: [&str; 2] = ["script", "style"];
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn themes() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Highlighted HTML for `code`, or `None` if the language is unknown
+fn highlight(language: &str, code: &str) -> Option<String> {
+    let syntaxes = syntaxes();
+    let syntax = syntaxes.find_syntax_by_token(language)?;
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, syntaxes, CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        generator
+            .parse_html_for_line_which_includes_newline(line)
+            .ok()?;
+    }
+    Some(generator.finalize())
+}
+
+fn code_block(language: &str, code: &str) -> String {
+    let code = highlight(language, code).unwrap_or_else(|| crate::html::escape(code));
+    format!(
+        "<pre><code class=\"language-{}\">{}</code></pre>\n",
+        crate::html::escape(language),
+        code
+    )
+}
+
+/// Renders markdown with a fixed set of allowed tags
+#[derive(Debug, Clone)]
+pub struct Renderer {
+    allowed_tags: HashSet<String>,
+}
+
+impl Default for Renderer {
+    /// The sanitizer's default tags plus task list checkboxes
+    fn default() -> Self {
+        let mut allowed_tags: HashSet<String> = ammonia::Builder::default()
+            .clone_tags()
+            .into_iter()
+            .map(String::from)
+            .collect();
+        allowed_tags.insert("input".to_string());
+        Renderer { allowed_tags }
+    }
+}
+
+impl Renderer {
+    /// A renderer keeping only `allowed_tags`, or the defaults if empty
+    ///
+    /// `script` and `style` are never kept, see [`FORBIDDEN_TAGS`].
+    pub fn new(allowed_tags: &[String]) -> Self {
+        if allowed_tags.is_empty() {
+            return Renderer::default();
+        }
+        let allowed_tags = allowed_tags
+            .iter()
+            .map(|tag| tag.to_ascii_lowercase())
+            .filter(|tag| !FORBIDDEN_TAGS.contains(&tag.as_str()))
+            .collect();
+        Renderer { allowed_tags }
+    }
+
+    /// Render markdown to sanitized HTML
+    ///
+    /// `only_tags` further restricts the allowed tags for this call; tags
+    /// the renderer does not allow stay disallowed.
+    pub fn render(&self, source: &str, only_tags: Option<&[String]>, highlight: bool) -> String {
+        let options = Options::ENABLE_TABLES
+            | Options::ENABLE_STRIKETHROUGH
+            | Options::ENABLE_TASKLISTS
+            | Options::ENABLE_FOOTNOTES;
+        let mut events = Vec::new();
+        // The language and text of a fenced code block being highlighted
+        let mut block: Option<(String, String)> = None;
+        for event in Parser::new_ext(source, options) {
+            if let Some((language, code)) = &mut block {
+                match event {
+                    Event::Text(text) => code.push_str(&text),
+                    Event::End(TagEnd::CodeBlock) => {
+                        events.push(Event::Html(code_block(language, code).into()));
+                        block = None;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match event {
+                Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info))) if highlight => {
+                    match info.split_whitespace().next() {
+                        Some(language) => block = Some((language.to_string(), String::new())),
+                        None => events.push(event),
+                    }
+                }
+                event => events.push(event),
+            }
+        }
+        let mut unsafe_html = String::with_capacity(source.len() * 3 / 2);
+        html::push_html(&mut unsafe_html, events.into_iter());
+
+        let tags: HashSet<&str> = self
+            .allowed_tags
+            .iter()
+            .map(String::as_str)
+            .filter(|tag| {
+                only_tags.is_none_or(|only| only.iter().any(|o| o.eq_ignore_ascii_case(tag)))
+            })
+            .collect();
+        ammonia::Builder::default()
+            .tags(tags)
+            // Task list checkboxes
+            .add_tag_attributes("input", ["type", "checked", "disabled"])
+            // Code languages and highlighted tokens
+            .add_tag_attributes("code", ["class"])
+            .add_tag_attributes("span", ["class"])
+            .clean(&unsafe_html)
+            .to_string()
+    }
+}
+
+/// Route module serving `/api/v1/render`
+pub struct RenderModule;
+
+impl RouteModule for RenderModule {
+    fn name(&self) -> &'static str {
+        "render"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/api/v1/render/markdown", post(render_markdown))
+            .route("/api/v1/render/highlight.css", get(highlight_css))
+    }
+}
+
+fn default_highlight() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenderRequest {
+    pub markdown: String,
+    /// Keep only these of the server's allowed tags
+    #[serde(default)]
+    pub allowed_tags: Option<Vec<String>>,
+    #[serde(default = "default_highlight")]
+    pub highlight: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Rendered {
+    pub html: String,
+}
+
+/// `POST /api/v1/render/markdown` - markdown rendered to sanitized HTML
+pub async fn render_markdown(
+    Ext(renderer): Ext<Renderer>,
+    Json(request): Json<RenderRequest>,
+) -> ApiResult<Json<Rendered>> {
+    if request.markdown.len() > MAX_SOURCE_BYTES {
+        return Err(ApiError::Validation(t!(
+            "markdown-too-large",
+            max = MAX_SOURCE_BYTES
+        )));
+    }
+    // Highlighting a large document takes a while
+    let html = tokio::task::spawn_blocking(move || {
+        renderer.render(
+            &request.markdown,
+            request.allowed_tags.as_deref(),
+            request.highlight,
+        )
+    })
+    .await
+    .map_err(anyhow::Error::from)?;
+    Ok(Json(Rendered { html }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThemeQuery {
+    pub theme: Option<String>,
+}
+
+/// `GET /api/v1/render/highlight.css?theme=` - classes for highlighted code
+pub async fn highlight_css(Query(query): Query<ThemeQuery>) -> ApiResult<Response> {
+    let name = query.theme.as_deref().unwrap_or(DEFAULT_THEME);
+    let theme = themes().themes.get(name).ok_or_else(|| {
+        let known = themes()
+            .themes
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ");
+        ApiError::NotFound(t!("markdown-theme-not-found", theme = name, known = known))
+    })?;
+    let css = css_for_theme_with_class_style(theme, CLASS_STYLE).map_err(anyhow::Error::from)?;
+    Ok(([(CONTENT_TYPE, "text/css; charset=utf-8")], css).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_sanitizes() {
+        let html = Renderer::default().render(
+            "# Title\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\n- [x] done\n\n\
+             [link](https://example.com) [bad](javascript:alert(1))\n\n\
+             <script>alert(1)</script><img src=x onerror=alert(1)>",
+            None,
+            true,
+        );
+        assert!(html.contains("<h1>Title</h1>"));
+        assert!(html.contains("<table>"));
+        assert!(html.contains(r#"<input disabled="" type="checkbox" checked="">"#));
+        assert!(html.contains(r#"<a href="https://example.com" rel="noopener noreferrer">"#));
+        assert!(!html.contains("javascript:"));
+        assert!(!html.contains("<script>") && !html.contains("onerror"));
+    }
+
+    #[test]
+    fn test_render_highlights_and_restricts_tags() {
+        let source = "Some *text*\n\n```rust\nfn main() {}\n```\n\n```nope\n<b>\n```\n";
+        let html = Renderer::default().render(source, None, true);
+        assert!(
+            html.contains(r#"<pre><code class="language-rust"><span class="hl-source hl-rust">"#)
+        );
+        assert!(html.contains("<span class=\"hl-storage hl-type hl-function hl-rust\">fn</span>"));
+        assert!(html.contains("<code class=\"language-nope\">&lt;b&gt;\n</code>"));
+
+        let plain = Renderer::default().render(source, None, false);
+        assert!(plain.contains("<code class=\"language-rust\">fn main() {}\n</code>"));
+
+        let renderer = Renderer::new(&["p".to_string(), "EM".to_string(), "script".to_string()]);
+        let html = renderer.render("Some *text* `code`", None, true);
+        assert_eq!(html, "<p>Some <em>text</em> code</p>\n");
+        let html = renderer.render(
+            "Some *text*",
+            Some(&["p".to_string(), "pre".to_string()]),
+            true,
+        );
+        assert_eq!(html, "<p>Some text</p>\n");
+    }
+}
@@ -0,0 +1,372 @@
+//! mDNS/DNS-SD service advertisement.
+//!
+//! When `MDNS_ENABLED` is set the server announces itself on the local
+//! network as an `_http._tcp` service so LAN clients can discover it without
+//! configuration. A small responder answers queries for the service type, the
+//! instance and its host name, announces on startup and sends goodbye
+//! packets on shutdown. Only IPv4 is advertised.
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE_TYPE: &str = "_http._tcp.local";
+const SERVICES_META: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Tells caches to replace, not add to, records of a unique name
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// What to advertise, from configuration
+#[derive(Debug, Clone)]
+pub struct MdnsConfig {
+    pub instance: String,
+    /// Address to advertise; detected from the default route when unset
+    pub address: Option<Ipv4Addr>,
+}
+
+/// The service being advertised
+#[derive(Debug, Clone)]
+pub struct Advertisement {
+    /// Human-readable instance name, e.g. `Home Server`
+    pub instance: String,
+    /// Host label, resolved as `<host>.local`
+    pub host: String,
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+impl Advertisement {
+    fn instance_name(&self) -> String {
+        format!("{}.{}", self.instance, SERVICE_TYPE)
+    }
+
+    fn host_name(&self) -> String {
+        format!("{}.local", self.host)
+    }
+
+    /// Encode a response carrying every record, with `ttl` 0 for goodbyes
+    fn response(&self, id: u16, goodbye: bool) -> Vec<u8> {
+        let ttl = |normal: u32| if goodbye { 0 } else { normal };
+        let mut packet = Vec::with_capacity(512);
+        for value in [id, 0x8400, 0, 5, 0, 0] {
+            packet.extend_from_slice(&value.to_be_bytes());
+        }
+
+        let mut rdata = Vec::new();
+        encode_name(&self.instance_name(), &mut rdata);
+        let mut service = Vec::new();
+        encode_name(SERVICE_TYPE, &mut service);
+        record(
+            &mut packet,
+            SERVICES_META,
+            TYPE_PTR,
+            CLASS_IN,
+            ttl(4500),
+            &service,
+        );
+        record(
+            &mut packet,
+            SERVICE_TYPE,
+            TYPE_PTR,
+            CLASS_IN,
+            ttl(4500),
+            &rdata,
+        );
+
+        let mut srv = Vec::new();
+        srv.extend_from_slice(&[0, 0, 0, 0]);
+        srv.extend_from_slice(&self.port.to_be_bytes());
+        encode_name(&self.host_name(), &mut srv);
+        let unique = CLASS_IN | CACHE_FLUSH;
+        record(
+            &mut packet,
+            &self.instance_name(),
+            TYPE_SRV,
+            unique,
+            ttl(120),
+            &srv,
+        );
+
+        let txt = b"path=/";
+        let mut txt_data = vec![txt.len() as u8];
+        txt_data.extend_from_slice(txt);
+        record(
+            &mut packet,
+            &self.instance_name(),
+            TYPE_TXT,
+            unique,
+            ttl(4500),
+            &txt_data,
+        );
+        record(
+            &mut packet,
+            &self.host_name(),
+            TYPE_A,
+            unique,
+            ttl(120),
+            &self.address.octets(),
+        );
+        packet
+    }
+
+    /// Whether a query asks about this service, returning its id
+    fn answers(&self, packet: &[u8]) -> Option<u16> {
+        let id = u16::from_be_bytes([*packet.first()?, *packet.get(1)?]);
+        let flags = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]);
+        if flags & 0x8000 != 0 {
+            return None; // a response, not a query
+        }
+        let questions = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]);
+        let wanted = [
+            (SERVICE_TYPE.to_string(), TYPE_PTR),
+            (SERVICES_META.to_string(), TYPE_PTR),
+            (self.instance_name(), TYPE_SRV),
+            (self.instance_name(), TYPE_TXT),
+            (self.host_name(), TYPE_A),
+        ];
+        let mut offset = 12;
+        for _ in 0..questions {
+            let name = decode_name(packet, &mut offset)?;
+            let qtype = u16::from_be_bytes([*packet.get(offset)?, *packet.get(offset + 1)?]);
+            offset += 4;
+            if wanted.iter().any(|(wanted_name, wanted_type)| {
+                wanted_name.eq_ignore_ascii_case(&name)
+                    && (qtype == *wanted_type || qtype == TYPE_ANY)
+            }) {
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+fn record(out: &mut Vec<u8>, name: &str, rtype: u16, class: u16, ttl: u32, data: &[u8]) {
+    encode_name(name, out);
+    out.extend_from_slice(&rtype.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&ttl.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Decode a possibly compressed name, advancing `offset` past it
+fn decode_name(packet: &[u8], offset: &mut usize) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut pos = *offset;
+    let mut jumped = false;
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        if len & 0xC0 == 0xC0 {
+            let pointer = ((len & 0x3F) << 8) | *packet.get(pos + 1)? as usize;
+            if !jumped {
+                *offset = pos + 2;
+            }
+            jumped = true;
+            pos = pointer;
+        } else if len == 0 {
+            if !jumped {
+                *offset = pos + 1;
+            }
+            return Some(labels.join("."));
+        } else {
+            let label = packet.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+    None
+}
+
+/// Turn an instance name into a valid host label
+pub fn host_label(name: &str) -> String {
+    let label: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        "selfhost".to_string()
+    } else {
+        label.chars().take(63).collect()
+    }
+}
+
+/// Best guess at the LAN address other hosts can reach us on
+pub fn local_ipv4() -> Result<Ipv4Addr> {
+    // Connecting a UDP socket sends nothing but selects the outgoing interface
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((MDNS_ADDR, MDNS_PORT))?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Ok(ip),
+        _ => anyhow::bail!("Could not determine a local IPv4 address"),
+    }
+}
+
+fn bind_multicast(interface: Ipv4Addr) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Share the port with any system responder such as avahi
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &interface)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Running responder; dropping it does not stop it, call [`Responder::stop`]
+#[derive(Clone)]
+pub struct Responder {
+    stop: Arc<Notify>,
+    stopped: Arc<Notify>,
+}
+
+impl Responder {
+    /// Send goodbye packets and stop answering
+    pub async fn stop(&self) {
+        self.stop.notify_one();
+        let _ = tokio::time::timeout(Duration::from_secs(1), self.stopped.notified()).await;
+    }
+}
+
+/// Start advertising the service on `port` in the background
+pub fn spawn(config: &MdnsConfig, port: u16) -> Result<Responder> {
+    let advertisement = Advertisement {
+        // Dots would split the instance label
+        instance: config.instance.replace('.', "-"),
+        host: host_label(&config.instance),
+        address: match config.address {
+            Some(address) => address,
+            None => local_ipv4()?,
+        },
+        port,
+    };
+    let socket = bind_multicast(advertisement.address).context("Failed to bind mDNS socket")?;
+    let responder = Responder {
+        stop: Arc::new(Notify::new()),
+        stopped: Arc::new(Notify::new()),
+    };
+    let (stop, stopped) = (responder.stop.clone(), responder.stopped.clone());
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_ADDR, MDNS_PORT));
+
+    tokio::spawn(async move {
+        // Announce twice, one second apart, as RFC 6762 recommends
+        for _ in 0..2 {
+            let _ = socket
+                .send_to(&advertisement.response(0, false), group)
+                .await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        tracing::info!(
+            "📡 Advertising '{}' via mDNS at {}:{}",
+            advertisement.instance_name(),
+            advertisement.host_name(),
+            advertisement.port
+        );
+
+        let mut buf = [0u8; 9000];
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let Ok((len, from)) = received else { continue };
+                    let Some(id) = advertisement.answers(&buf[..len]) else { continue };
+                    // Legacy resolvers query from an ephemeral port and expect a unicast reply
+                    let (target, id) = if from.port() == MDNS_PORT { (group, 0) } else { (from, id) };
+                    let _ = socket.send_to(&advertisement.response(id, false), target).await;
+                }
+                _ = stop.notified() => break,
+            }
+        }
+        let _ = socket
+            .send_to(&advertisement.response(0, true), group)
+            .await;
+        tracing::info!("Stopped mDNS advertisement");
+        stopped.notify_one();
+    });
+    Ok(responder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advertisement() -> Advertisement {
+        Advertisement {
+            instance: "Home Server".to_string(),
+            host: host_label("Home Server"),
+            address: Ipv4Addr::new(192, 168, 1, 20),
+            port: 3000,
+        }
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        encode_name(name, &mut packet);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_answers_matching_queries_only() {
+        let ad = advertisement();
+        assert_eq!(ad.host, "home-server");
+        assert_eq!(
+            ad.answers(&query("_http._tcp.local", TYPE_PTR)),
+            Some(0x1234)
+        );
+        assert_eq!(
+            ad.answers(&query("HOME-SERVER.local", TYPE_A)),
+            Some(0x1234)
+        );
+        assert_eq!(ad.answers(&query("_ipp._tcp.local", TYPE_PTR)), None);
+        assert_eq!(ad.answers(&[0x12]), None);
+    }
+
+    #[test]
+    fn test_response_records_decode() {
+        let packet = advertisement().response(0, false);
+        assert_eq!(u16::from_be_bytes([packet[6], packet[7]]), 5);
+
+        let mut offset = 12;
+        assert_eq!(decode_name(&packet, &mut offset).unwrap(), SERVICES_META);
+        // Skip type, class, TTL and the PTR data of the first record
+        offset += 8;
+        let len = u16::from_be_bytes([packet[offset], packet[offset + 1]]) as usize;
+        offset += 2 + len;
+        assert_eq!(decode_name(&packet, &mut offset).unwrap(), SERVICE_TYPE);
+        offset += 10;
+        assert_eq!(
+            decode_name(&packet, &mut offset).unwrap(),
+            "Home Server._http._tcp.local"
+        );
+    }
+}
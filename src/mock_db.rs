@@ -0,0 +1,128 @@
+//! In-memory [`Db`] for handler unit tests.
+//!
+//! [`MockDb`] records every statement it receives and answers from replies
+//! queued with [`returns`](MockDb::returns), [`affects`](MockDb::affects)
+//! and [`fails`](MockDb::fails). A reply is used once, by the first
+//! statement containing its SQL fragment; statements without a reply get no
+//! rows and affect nothing. SQL is compared with whitespace collapsed so
+//! fragments need not match the source's line breaks.
+//!
+//! ```no_run
+//! # use rust_selfhost_server::{db::Database, mock_db::MockDb, AppState};
+//! # use std::sync::Arc;
+//! let mock = MockDb::new();
+//! mock.returns("SELECT value FROM settings", vec![serde_json::json!({ "value": 1 })]);
+//! let state = AppState::new(Database::from_db(Arc::new(mock.clone())));
+//! ```
+
+use anyhow::Result;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+
+use crate::db::{BoxFuture, Db, Param};
+
+/// A statement issued against a [`MockDb`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub sql: String,
+    pub params: Vec<Param>,
+}
+
+#[derive(Debug)]
+enum Reply {
+    Rows(Vec<Value>),
+    Affected(u64),
+    Error(String),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    statements: Vec<Statement>,
+    replies: Vec<(String, Reply)>,
+}
+
+/// Recording, scriptable stand-in for the database
+#[derive(Debug, Clone, Default)]
+pub struct MockDb {
+    state: Arc<Mutex<State>>,
+}
+
+fn normalize(sql: &str) -> String {
+    sql.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl MockDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn reply(&self, fragment: &str, reply: Reply) -> &Self {
+        self.state
+            .lock()
+            .expect("mock poisoned")
+            .replies
+            .push((normalize(fragment), reply));
+        self
+    }
+
+    /// Answer the next query containing `fragment` with `rows`
+    pub fn returns(&self, fragment: &str, rows: Vec<Value>) -> &Self {
+        self.reply(fragment, Reply::Rows(rows))
+    }
+
+    /// Report `rows` affected for the next statement containing `fragment`
+    pub fn affects(&self, fragment: &str, rows: u64) -> &Self {
+        self.reply(fragment, Reply::Affected(rows))
+    }
+
+    /// Fail the next statement containing `fragment`
+    pub fn fails(&self, fragment: &str, message: &str) -> &Self {
+        self.reply(fragment, Reply::Error(message.to_string()))
+    }
+
+    /// Every statement issued so far, in order
+    pub fn statements(&self) -> Vec<Statement> {
+        self.state.lock().expect("mock poisoned").statements.clone()
+    }
+
+    fn record(&self, sql: &str, params: Vec<Param>) -> Option<Reply> {
+        let sql = normalize(sql);
+        let mut state = self.state.lock().expect("mock poisoned");
+        let reply = state
+            .replies
+            .iter()
+            .position(|(fragment, _)| sql.contains(fragment.as_str()))
+            .map(|i| state.replies.remove(i).1);
+        state.statements.push(Statement { sql, params });
+        reply
+    }
+}
+
+impl Db for MockDb {
+    fn fetch_all<'a>(
+        &'a self,
+        sql: &'a str,
+        params: Vec<Param>,
+    ) -> BoxFuture<'a, Result<Vec<Value>>> {
+        let reply = self.record(sql, params);
+        Box::pin(async move {
+            match reply {
+                Some(Reply::Rows(rows)) => Ok(rows),
+                Some(Reply::Error(message)) => Err(anyhow::anyhow!(message)),
+                Some(Reply::Affected(_)) | None => Ok(Vec::new()),
+            }
+        })
+    }
+
+    fn execute<'a>(&'a self, sql: &'a str, params: Vec<Param>) -> BoxFuture<'a, Result<u64>> {
+        let reply = self.record(sql, params);
+        Box::pin(async move {
+            match reply {
+                Some(Reply::Affected(rows)) => Ok(rows),
+                Some(Reply::Rows(rows)) => Ok(rows.len() as u64),
+                Some(Reply::Error(message)) => Err(anyhow::anyhow!(message)),
+                None => Ok(0),
+            }
+        })
+    }
+}
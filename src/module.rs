@@ -0,0 +1,541 @@
+//! Route modules.
+//!
+//! Features are packaged as [`RouteModule`]s that contribute a router and
+//! the migrations their tables need. Built-in endpoints are modules too, so
+//! any of them can be switched off per deployment with `DISABLED_MODULES`,
+//! and third parties can ship drop-in modules registered through
+//! [`ServerBuilder::module`](crate::ServerBuilder::module).
+//!
+//! Module migrations are applied once each, in registration order, and
+//! recorded in the `module_migrations` table.
+//!
+//! Migrations are either [`Expand`](MigrationKind::Expand) steps, which the
+//! previous release keeps working against, or
+//! [`Contract`](MigrationKind::Contract) steps that remove what it still
+//! needs. The server only applies expand steps at startup, so old and new
+//! instances can share a database during a rolling deploy; contract steps
+//! wait for `migrate` once no older instance is left. An instance refuses
+//! to start when the database has contract steps it does not know, i.e. a
+//! newer release has already dropped something it may use.
+//! With `MIGRATE_ON_STARTUP=false` startup applies nothing and instead
+//! refuses to run while expand steps are pending.
+//!
+//! The checksum of every applied migration's SQL is recorded too. When an
+//! applied migration no longer matches the SQL embedded in the binary, it
+//! was edited after the fact and the schema may differ from what the code
+//! expects; `MIGRATION_DRIFT` decides whether that stops startup.
+
+use anyhow::{Context, Result};
+use axum::Router;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::deprecation::Deprecation;
+use crate::encryption::EncryptedColumn;
+use crate::AppState;
+
+/// Route group a module's routes belong to, selecting its middleware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteGroup {
+    /// Unauthenticated endpoints such as health checks
+    Public,
+    /// The `/api/v1` JSON API
+    Api,
+    /// Admin endpoints guarded by `ADMIN_TOKEN`
+    Admin,
+}
+
+/// Whether a migration is safe to apply while older releases still run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationKind {
+    /// Backward-compatible: adds tables, nullable columns or indexes
+    Expand,
+    /// Drops or renames something older releases still use
+    Contract,
+}
+
+impl MigrationKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MigrationKind::Expand => "expand",
+            MigrationKind::Contract => "contract",
+        }
+    }
+}
+
+/// A named schema change owned by a module
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    /// Unique name within the module, e.g. `0001_create_settings`
+    pub name: &'static str,
+    pub kind: MigrationKind,
+    /// SQL to run; may contain several statements
+    pub sql: &'static str,
+}
+
+impl Migration {
+    /// SHA-256 of the migration's SQL, as recorded when it is applied
+    pub fn checksum(&self) -> String {
+        crate::crypto::sha256_hex(self.sql)
+    }
+}
+
+/// What to do when applied migrations no longer match their SQL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriftPolicy {
+    /// Refuse to start
+    Fail,
+    /// Log the drift and carry on
+    Warn,
+    Ignore,
+}
+
+impl FromStr for DriftPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(DriftPolicy::Fail),
+            "warn" => Ok(DriftPolicy::Warn),
+            "ignore" => Ok(DriftPolicy::Ignore),
+            other => anyhow::bail!(
+                "Unknown drift policy '{}' (expected fail, warn or ignore)",
+                other
+            ),
+        }
+    }
+}
+
+/// A self-contained feature contributing routes and migrations
+pub trait RouteModule: Send + Sync {
+    /// Unique module name used in configuration
+    fn name(&self) -> &'static str;
+
+    /// Route group the module's routes are mounted in
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Api
+    }
+
+    /// Routes served by the module
+    fn routes(&self) -> Router<AppState>;
+
+    /// Migrations the module's routes depend on
+    fn migrations(&self) -> &'static [Migration] {
+        &[]
+    }
+
+    /// Columns holding [`Encrypted`](crate::encryption::Encrypted) values,
+    /// re-encrypted by `encryption rotate`
+    fn encrypted_columns(&self) -> &'static [EncryptedColumn] {
+        &[]
+    }
+
+    /// Routes announced as deprecated, unless `DEPRECATED_ROUTES`
+    /// configures the same prefix
+    fn deprecations(&self) -> Vec<Deprecation> {
+        Vec::new()
+    }
+}
+
+/// Modules shipped with the server
+pub fn builtin_modules() -> Vec<Arc<dyn RouteModule>> {
+    vec![
+        Arc::new(crate::health::HealthModule),
+        Arc::new(crate::dependencies::DependenciesModule),
+        Arc::new(crate::status::StatusModule),
+        Arc::new(crate::pages::PagesModule),
+        Arc::new(crate::admin_ui::AdminUiModule),
+        Arc::new(crate::info::InfoModule),
+        Arc::new(crate::forward_auth::ForwardAuthModule),
+        Arc::new(crate::oidc::OidcModule),
+        Arc::new(crate::oidc::OidcClientsModule),
+        Arc::new(crate::settings::SettingsModule),
+        Arc::new(crate::changes::ChangesModule),
+        Arc::new(crate::crashes::CrashesModule),
+        Arc::new(crate::jobs::JobsModule),
+        Arc::new(crate::read_only::ReadOnlyModule),
+        Arc::new(crate::schema::SchemaModule),
+        Arc::new(crate::live::LiveModule),
+        Arc::new(crate::collections::CollectionsModule),
+        Arc::new(crate::users::UsersModule),
+        Arc::new(crate::pastes::PastesModule),
+        Arc::new(crate::pastes::PasteLinksModule),
+        Arc::new(crate::bookmarks::BookmarksModule),
+        Arc::new(crate::notes::NotesModule),
+        Arc::new(crate::events::EventsModule),
+        Arc::new(crate::markdown::RenderModule),
+        Arc::new(crate::unfurl::UnfurlModule),
+        Arc::new(crate::well_known::WellKnownModule),
+    ]
+}
+
+/// Apply pending migrations for the given modules
+///
+/// Returns the `module/name` of every migration applied.
+pub async fn run_migrations(
+    pool: &PgPool,
+    modules: &[Arc<dyn RouteModule>],
+) -> Result<Vec<String>> {
+    apply(pool, modules, false).await
+}
+
+/// Apply only the pending expand migrations of the given modules
+///
+/// A module's migrations stop at its first pending contract migration,
+/// since later ones may build on it. Returns the `module/name` of every
+/// migration applied.
+pub async fn run_gated_migrations(
+    pool: &PgPool,
+    modules: &[Arc<dyn RouteModule>],
+) -> Result<Vec<String>> {
+    apply(pool, modules, true).await
+}
+
+async fn apply(
+    pool: &PgPool,
+    modules: &[Arc<dyn RouteModule>],
+    gated: bool,
+) -> Result<Vec<String>> {
+    create_table(pool).await?;
+
+    let mut applied_names = Vec::new();
+    for module in modules {
+        for migration in module.migrations() {
+            let mut tx = pool.begin().await?;
+            let applied: bool = sqlx::query_scalar(
+                "SELECT EXISTS (SELECT 1 FROM module_migrations WHERE module = $1 AND name = $2)",
+            )
+            .bind(module.name())
+            .bind(migration.name)
+            .fetch_one(&mut *tx)
+            .await?;
+            if applied {
+                continue;
+            }
+            if gated && migration.kind == MigrationKind::Contract {
+                break;
+            }
+
+            sqlx::raw_sql(migration.sql)
+                .execute(&mut *tx)
+                .await
+                .with_context(|| {
+                    format!("Migration {}/{} failed", module.name(), migration.name)
+                })?;
+            sqlx::query(
+                "INSERT INTO module_migrations (module, name, kind, checksum) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(module.name())
+            .bind(migration.name)
+            .bind(migration.kind.as_str())
+            .bind(migration.checksum())
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            let name = format!("{}/{}", module.name(), migration.name);
+            tracing::info!("Applied migration {}", name);
+            applied_names.push(name);
+        }
+    }
+    Ok(applied_names)
+}
+
+async fn create_table(pool: &PgPool) -> Result<()> {
+    sqlx::raw_sql(
+        "CREATE TABLE IF NOT EXISTS module_migrations (
+            module TEXT NOT NULL,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            PRIMARY KEY (module, name)
+        );
+        ALTER TABLE module_migrations ADD COLUMN IF NOT EXISTS kind TEXT NOT NULL DEFAULT 'expand';
+        ALTER TABLE module_migrations ADD COLUMN IF NOT EXISTS checksum TEXT;",
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create module_migrations table")?;
+    Ok(())
+}
+
+/// Fail if the database was migrated past what this version supports
+///
+/// That is the case when a contract migration of one of the given modules
+/// is recorded but unknown to this binary: a newer release has removed
+/// something this one may still use.
+pub async fn check_schema(pool: &PgPool, modules: &[Arc<dyn RouteModule>]) -> Result<()> {
+    create_table(pool).await?;
+    let applied: Vec<(String, String)> = sqlx::query_as(
+        "SELECT module, name FROM module_migrations WHERE kind = 'contract' ORDER BY applied_at",
+    )
+    .fetch_all(pool)
+    .await?;
+    let unknown: Vec<String> = applied
+        .into_iter()
+        .filter(|(module, name)| {
+            modules
+                .iter()
+                .any(|m| m.name() == module && !m.migrations().iter().any(|mig| mig.name == name))
+        })
+        .map(|(module, name)| format!("{}/{}", module, name))
+        .collect();
+    if !unknown.is_empty() {
+        anyhow::bail!(
+            "The database schema is newer than this version supports: {} applied by a later release",
+            unknown.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Applied migrations whose SQL no longer matches their recorded checksum
+///
+/// Returns their `module/name`. Migrations applied before checksums were
+/// recorded adopt the checksum of their current SQL.
+pub async fn drifted_migrations(
+    pool: &PgPool,
+    modules: &[Arc<dyn RouteModule>],
+) -> Result<Vec<String>> {
+    create_table(pool).await?;
+    let recorded: Vec<(String, String, Option<String>)> =
+        sqlx::query_as("SELECT module, name, checksum FROM module_migrations")
+            .fetch_all(pool)
+            .await?;
+    let mut drifted = Vec::new();
+    for (module, name, checksum) in recorded {
+        let Some(migration) = modules
+            .iter()
+            .filter(|m| m.name() == module)
+            .flat_map(|m| m.migrations())
+            .find(|migration| migration.name == name)
+        else {
+            continue;
+        };
+        match checksum {
+            Some(checksum) if checksum == migration.checksum() => {}
+            Some(_) => drifted.push(format!("{}/{}", module, name)),
+            None => {
+                sqlx::query(
+                    "UPDATE module_migrations SET checksum = $3 WHERE module = $1 AND name = $2",
+                )
+                .bind(&module)
+                .bind(&name)
+                .bind(migration.checksum())
+                .execute(pool)
+                .await?;
+            }
+        }
+    }
+    Ok(drifted)
+}
+
+/// Check applied migrations against their SQL, acting on drift per `policy`
+pub async fn check_checksums(
+    pool: &PgPool,
+    modules: &[Arc<dyn RouteModule>],
+    policy: DriftPolicy,
+) -> Result<()> {
+    if policy == DriftPolicy::Ignore {
+        return Ok(());
+    }
+    let drifted = drifted_migrations(pool, modules).await?;
+    if drifted.is_empty() {
+        return Ok(());
+    }
+    let message = format!(
+        "Applied migrations were changed since they ran: {}; the schema may not match what this version expects",
+        drifted.join(", ")
+    );
+    if policy == DriftPolicy::Fail {
+        anyhow::bail!("{} (set MIGRATION_DRIFT=warn to start anyway)", message);
+    }
+    tracing::warn!("🗄️ {}", message);
+    Ok(())
+}
+
+/// A migration that would run, as listed by `migrate --dry-run`
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedMigration {
+    /// `module/name`
+    pub name: String,
+    pub kind: MigrationKind,
+    pub sql: &'static str,
+}
+
+/// Migrations of the given modules that have not been applied yet
+///
+/// Returns them in the order they would run; with `gated`, only those
+/// [`run_gated_migrations`] would apply.
+pub async fn planned_migrations(
+    pool: &PgPool,
+    modules: &[Arc<dyn RouteModule>],
+    gated: bool,
+) -> Result<Vec<PlannedMigration>> {
+    let tracked: bool = sqlx::query_scalar("SELECT to_regclass('module_migrations') IS NOT NULL")
+        .fetch_one(pool)
+        .await?;
+    let applied: Vec<(String, String)> = if tracked {
+        sqlx::query_as("SELECT module, name FROM module_migrations")
+            .fetch_all(pool)
+            .await?
+    } else {
+        Vec::new()
+    };
+    let mut planned = Vec::new();
+    for module in modules {
+        for migration in module.migrations() {
+            if applied
+                .iter()
+                .any(|(m, n)| m == module.name() && n == migration.name)
+            {
+                continue;
+            }
+            if gated && migration.kind == MigrationKind::Contract {
+                break;
+            }
+            planned.push(PlannedMigration {
+                name: format!("{}/{}", module.name(), migration.name),
+                kind: migration.kind,
+                sql: migration.sql,
+            });
+        }
+    }
+    Ok(planned)
+}
+
+/// Migrations of the given modules that have not been applied yet
+///
+/// Returns their `module/name` and kind, in the order they would run.
+pub async fn pending_migrations(
+    pool: &PgPool,
+    modules: &[Arc<dyn RouteModule>],
+) -> Result<Vec<(String, MigrationKind)>> {
+    Ok(planned_migrations(pool, modules, false)
+        .await?
+        .into_iter()
+        .map(|migration| (migration.name, migration.kind))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_module_names_are_unique() {
+        let modules = builtin_modules();
+        let mut names: Vec<_> = modules.iter().map(|m| m.name()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), modules.len());
+    }
+
+    /// A module whose migrations are given per test
+    struct Versioned(&'static [Migration]);
+
+    impl RouteModule for Versioned {
+        fn name(&self) -> &'static str {
+            "versioned"
+        }
+
+        fn routes(&self) -> Router<AppState> {
+            Router::new()
+        }
+
+        fn migrations(&self) -> &'static [Migration] {
+            self.0
+        }
+    }
+
+    const V1: &[Migration] = &[Migration {
+        name: "0001_create",
+        kind: MigrationKind::Expand,
+        sql: "CREATE TABLE versioned (id INT, legacy TEXT)",
+    }];
+    const V2: &[Migration] = &[
+        V1[0],
+        Migration {
+            name: "0002_drop_legacy",
+            kind: MigrationKind::Contract,
+            sql: "ALTER TABLE versioned DROP COLUMN legacy",
+        },
+        Migration {
+            name: "0003_add_name",
+            kind: MigrationKind::Expand,
+            sql: "ALTER TABLE versioned ADD COLUMN name TEXT",
+        },
+    ];
+
+    #[tokio::test]
+    async fn test_gated_migrations_hold_back_contract_steps() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        let v1: Vec<Arc<dyn RouteModule>> = vec![Arc::new(Versioned(V1))];
+        let v2: Vec<Arc<dyn RouteModule>> = vec![Arc::new(Versioned(V2))];
+
+        assert_eq!(
+            run_gated_migrations(pool, &v2).await.unwrap(),
+            ["versioned/0001_create"]
+        );
+        assert_eq!(
+            pending_migrations(pool, &v2).await.unwrap(),
+            [
+                (
+                    "versioned/0002_drop_legacy".to_string(),
+                    MigrationKind::Contract
+                ),
+                ("versioned/0003_add_name".to_string(), MigrationKind::Expand),
+            ]
+        );
+        check_schema(pool, &v1).await.unwrap();
+
+        assert_eq!(run_migrations(pool, &v2).await.unwrap().len(), 2);
+        check_schema(pool, &v2).await.unwrap();
+        let error = check_schema(pool, &v1).await.unwrap_err();
+        assert!(error.to_string().contains("versioned/0002_drop_legacy"));
+    }
+
+    #[tokio::test]
+    async fn test_detects_edited_migrations() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        let v2: Vec<Arc<dyn RouteModule>> = vec![Arc::new(Versioned(V2))];
+        run_gated_migrations(pool, &v2).await.unwrap();
+        let planned = planned_migrations(pool, &v2, true).await.unwrap();
+        assert!(planned.is_empty());
+        let planned = planned_migrations(pool, &v2, false).await.unwrap();
+        assert_eq!(planned[0].sql, V2[1].sql);
+
+        const EDITED: &[Migration] = &[Migration {
+            name: "0001_create",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE versioned (id BIGINT, legacy TEXT)",
+        }];
+        let edited: Vec<Arc<dyn RouteModule>> = vec![Arc::new(Versioned(EDITED))];
+        assert!(drifted_migrations(pool, &v2).await.unwrap().is_empty());
+        assert_eq!(
+            drifted_migrations(pool, &edited).await.unwrap(),
+            ["versioned/0001_create"]
+        );
+        assert!(check_checksums(pool, &edited, DriftPolicy::Fail)
+            .await
+            .is_err());
+        check_checksums(pool, &edited, DriftPolicy::Warn)
+            .await
+            .unwrap();
+
+        // Rows recorded before checksums existed adopt the current SQL
+        sqlx::query("UPDATE module_migrations SET checksum = NULL")
+            .execute(pool)
+            .await
+            .unwrap();
+        assert!(drifted_migrations(pool, &edited).await.unwrap().is_empty());
+        assert_eq!(drifted_migrations(pool, &v2).await.unwrap().len(), 1);
+    }
+}
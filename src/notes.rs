@@ -0,0 +1,588 @@
+//! Markdown notes with folders, tags and revision history.
+//!
+//! Notes live under `/api/v1/notes` and are listed, filtered and paged like
+//! bookmarks: `GET /api/v1/notes?q=..&folder=work&tag=todo&limit=50&offset=0`
+//! searches titles and bodies, with `folder` also matching its subfolders.
+//! Folders are `/`-separated paths such as `work/projects`; the empty path
+//! is the top level.
+//!
+//! Every save is kept as a numbered revision in `note_revisions`, listed at
+//! `GET /api/v1/notes/:id/revisions`; restoring one saves its content as a
+//! new revision, so history is never rewritten. `GET /api/v1/notes/:id/html`
+//! serves the body rendered to sanitized HTML.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{CONTENT_SECURITY_POLICY, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool, Postgres, QueryBuilder};
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::markdown::Renderer;
+use crate::module::{Migration, MigrationKind, RouteModule};
+use crate::tags;
+use crate::{t, AppState};
+
+/// Longest title, in characters
+pub const MAX_TITLE_LEN: usize = 256;
+
+/// Largest note body, in bytes
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Longest folder path, in characters
+const MAX_FOLDER_LEN: usize = 256;
+
+const COLUMNS: &str = "id, title, body, folder, tags, revision, created_at, updated_at";
+
+/// A stored note
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Note {
+    pub id: Uuid,
+    pub title: String,
+    /// Markdown source
+    pub body: String,
+    pub folder: String,
+    pub tags: Vec<String>,
+    /// Number of the latest revision
+    pub revision: i32,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A saved version of a note
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Revision {
+    pub note_id: Uuid,
+    pub revision: i32,
+    pub title: String,
+    pub body: String,
+    pub folder: String,
+    pub tags: Vec<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Route module for notes
+pub struct NotesModule;
+
+impl RouteModule for NotesModule {
+    fn name(&self) -> &'static str {
+        "notes"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/api/v1/notes", get(list_notes).post(create_note))
+            .route("/api/v1/notes/folders", get(list_folders))
+            .route(
+                "/api/v1/notes/:id",
+                get(get_note).patch(update_note).delete(delete_note),
+            )
+            .route("/api/v1/notes/:id/html", get(note_html))
+            .route("/api/v1/notes/:id/revisions", get(list_revisions))
+            .route("/api/v1/notes/:id/revisions/:revision", get(get_revision))
+            .route(
+                "/api/v1/notes/:id/revisions/:revision/restore",
+                post(restore_revision),
+            )
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_notes",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS notes (
+                id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+                title TEXT NOT NULL,
+                body TEXT NOT NULL DEFAULT '',
+                folder TEXT NOT NULL DEFAULT '',
+                tags TEXT[] NOT NULL DEFAULT '{}',
+                revision INTEGER NOT NULL DEFAULT 1,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                search TSVECTOR GENERATED ALWAYS AS (
+                    setweight(to_tsvector('simple', title), 'A')
+                        || setweight(to_tsvector('simple', body), 'B')
+                ) STORED
+            );
+            CREATE INDEX IF NOT EXISTS notes_search ON notes USING GIN (search);
+            CREATE INDEX IF NOT EXISTS notes_tags ON notes USING GIN (tags);
+            CREATE INDEX IF NOT EXISTS notes_folder ON notes (folder);
+            CREATE TABLE IF NOT EXISTS note_revisions (
+                note_id UUID NOT NULL REFERENCES notes (id) ON DELETE CASCADE,
+                revision INTEGER NOT NULL,
+                title TEXT NOT NULL,
+                body TEXT NOT NULL,
+                folder TEXT NOT NULL,
+                tags TEXT[] NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                PRIMARY KEY (note_id, revision)
+            )",
+        }]
+    }
+}
+
+/// Normalize a folder path such as ` work / projects/`, or `None` if invalid
+pub fn normalize_folder(folder: &str) -> Option<String> {
+    let segments: Vec<&str> = folder
+        .split('/')
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let folder = segments.join("/");
+    (folder.chars().count() <= MAX_FOLDER_LEN && !folder.chars().any(char::is_control))
+        .then_some(folder)
+}
+
+fn check_title(title: &str) -> ApiResult<String> {
+    let title = title.trim();
+    if title.is_empty() || title.chars().count() > MAX_TITLE_LEN {
+        return Err(ApiError::Validation(t!("note-title", max = MAX_TITLE_LEN)));
+    }
+    Ok(title.to_string())
+}
+
+fn check_body(body: &str) -> ApiResult<()> {
+    if body.len() > MAX_BODY_BYTES {
+        return Err(ApiError::Validation(t!(
+            "note-too-large",
+            max = MAX_BODY_BYTES
+        )));
+    }
+    Ok(())
+}
+
+fn check_folder(folder: &str) -> ApiResult<String> {
+    normalize_folder(folder).ok_or_else(|| ApiError::Validation(t!("note-folder", folder = folder)))
+}
+
+/// A note about to be created
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct NewNote {
+    pub title: String,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub folder: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Check and store a note as its first revision
+pub async fn create(conn: &mut PgConnection, new: &NewNote) -> ApiResult<Note> {
+    let title = check_title(&new.title)?;
+    check_body(&new.body)?;
+    let folder = check_folder(&new.folder)?;
+    let tags = tags::normalize_all(&new.tags)?;
+    let note = sqlx::query_as::<_, Note>(&format!(
+        "WITH note AS (
+            INSERT INTO notes (title, body, folder, tags) VALUES ($1, $2, $3, $4)
+            RETURNING *
+         ), saved AS (
+            INSERT INTO note_revisions (note_id, revision, title, body, folder, tags, created_at)
+            SELECT id, revision, title, body, folder, tags, updated_at FROM note
+         )
+         SELECT {COLUMNS} FROM note"
+    ))
+    .bind(title)
+    .bind(&new.body)
+    .bind(folder)
+    .bind(tags)
+    .fetch_one(conn)
+    .await?;
+    Ok(note)
+}
+
+/// Look up a note
+pub async fn find(pool: &PgPool, id: Uuid) -> ApiResult<Note> {
+    sqlx::query_as::<_, Note>(&format!("SELECT {COLUMNS} FROM notes WHERE id = $1"))
+        .bind(id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(t!("note-not-found")))
+}
+
+/// Changes to a note; fields left out are kept
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct NoteUpdate {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+/// Apply changes to a note, saving the result as a new revision
+pub async fn update(pool: &PgPool, id: Uuid, changes: &NoteUpdate) -> ApiResult<Note> {
+    let title = changes.title.as_deref().map(check_title).transpose()?;
+    if let Some(body) = &changes.body {
+        check_body(body)?;
+    }
+    let folder = changes.folder.as_deref().map(check_folder).transpose()?;
+    let tags = changes
+        .tags
+        .as_deref()
+        .map(tags::normalize_all)
+        .transpose()?;
+    sqlx::query_as::<_, Note>(&format!(
+        "WITH note AS (
+            UPDATE notes SET title = COALESCE($2, title),
+                body = COALESCE($3, body),
+                folder = COALESCE($4, folder),
+                tags = COALESCE($5, tags),
+                revision = revision + 1,
+                updated_at = now()
+            WHERE id = $1
+            RETURNING *
+         ), saved AS (
+            INSERT INTO note_revisions (note_id, revision, title, body, folder, tags, created_at)
+            SELECT id, revision, title, body, folder, tags, updated_at FROM note
+         )
+         SELECT {COLUMNS} FROM note"
+    ))
+    .bind(id)
+    .bind(title)
+    .bind(&changes.body)
+    .bind(folder)
+    .bind(tags)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(t!("note-not-found")))
+}
+
+/// Look up one revision of a note
+pub async fn find_revision(pool: &PgPool, id: Uuid, revision: i32) -> ApiResult<Revision> {
+    sqlx::query_as::<_, Revision>(
+        "SELECT * FROM note_revisions WHERE note_id = $1 AND revision = $2",
+    )
+    .bind(id)
+    .bind(revision)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(t!("note-revision-not-found")))
+}
+
+/// Filters of a note listing
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NoteQuery {
+    /// Full-text search over titles and bodies
+    #[serde(default)]
+    pub q: Option<String>,
+    /// A folder, including its subfolders
+    #[serde(default)]
+    pub folder: Option<String>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+fn default_limit() -> i64 {
+    50
+}
+
+impl Default for NoteQuery {
+    fn default() -> Self {
+        NoteQuery {
+            q: None,
+            folder: None,
+            tag: None,
+            limit: default_limit(),
+            offset: 0,
+        }
+    }
+}
+
+/// Notes matching `query`, best matches or most recently updated first
+pub async fn search(pool: &PgPool, query: &NoteQuery) -> ApiResult<Vec<Note>> {
+    let text = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let mut sql: QueryBuilder<Postgres> =
+        QueryBuilder::new(format!("SELECT {COLUMNS} FROM notes WHERE true"));
+    if let Some(text) = text {
+        sql.push(" AND search @@ websearch_to_tsquery('simple', ")
+            .push_bind(text)
+            .push(")");
+    }
+    if let Some(folder) = query.folder.as_deref().and_then(normalize_folder) {
+        if !folder.is_empty() {
+            sql.push(" AND (folder = ")
+                .push_bind(folder.clone())
+                .push(" OR starts_with(folder, ")
+                .push_bind(format!("{}/", folder))
+                .push("))");
+        }
+    }
+    if let Some(tag) = &query.tag {
+        let tag = tags::normalize(tag).unwrap_or_default();
+        sql.push(" AND tags @> ARRAY[")
+            .push_bind(tag)
+            .push("]::text[]");
+    }
+    match text {
+        Some(text) => sql
+            .push(" ORDER BY ts_rank(search, websearch_to_tsquery('simple', ")
+            .push_bind(text)
+            .push(")) DESC, updated_at DESC, id"),
+        None => sql.push(" ORDER BY updated_at DESC, id"),
+    };
+    sql.push(" LIMIT ")
+        .push_bind(query.limit.clamp(1, 500))
+        .push(" OFFSET ")
+        .push_bind(query.offset.max(0));
+    Ok(sql.build_query_as().fetch_all(pool).await?)
+}
+
+/// `GET /api/v1/notes` - list or search notes
+pub async fn list_notes(
+    State(state): State<AppState>,
+    Query(query): Query<NoteQuery>,
+) -> ApiResult<Json<Vec<Note>>> {
+    Ok(Json(search(state.db.pool(), &query).await?))
+}
+
+/// `POST /api/v1/notes` - create a note
+pub async fn create_note(
+    State(state): State<AppState>,
+    Json(body): Json<NewNote>,
+) -> ApiResult<(StatusCode, Json<Note>)> {
+    let mut conn = state.db.pool().acquire().await?;
+    let note = create(&mut conn, &body).await?;
+    Ok((StatusCode::CREATED, Json(note)))
+}
+
+/// `GET /api/v1/notes/:id` - one note
+pub async fn get_note(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<Note>> {
+    Ok(Json(find(state.db.pool(), id).await?))
+}
+
+/// `PATCH /api/v1/notes/:id` - change a note, saving a new revision
+pub async fn update_note(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(body): Json<NoteUpdate>,
+) -> ApiResult<Json<Note>> {
+    Ok(Json(update(state.db.pool(), id, &body).await?))
+}
+
+/// `DELETE /api/v1/notes/:id` - remove a note and its history
+pub async fn delete_note(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<StatusCode> {
+    let result = sqlx::query("DELETE FROM notes WHERE id = $1")
+        .bind(id)
+        .execute(state.db.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(t!("note-not-found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /api/v1/notes/:id/html` - a note's body rendered to sanitized HTML
+pub async fn note_html(
+    State(state): State<AppState>,
+    Ext(renderer): Ext<Renderer>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Response> {
+    let note = find(state.db.pool(), id).await?;
+    Ok((
+        [
+            (CONTENT_TYPE, "text/html; charset=utf-8"),
+            // Defense in depth should something slip past the sanitizer
+            (
+                CONTENT_SECURITY_POLICY,
+                "default-src 'none'; img-src https: data:; style-src 'unsafe-inline'; sandbox",
+            ),
+        ],
+        renderer.render(&note.body, None, true),
+    )
+        .into_response())
+}
+
+/// A revision in a note's history
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RevisionSummary {
+    pub revision: i32,
+    pub title: String,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// `GET /api/v1/notes/:id/revisions` - a note's history, newest first
+pub async fn list_revisions(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> ApiResult<Json<Vec<RevisionSummary>>> {
+    let pool = state.db.pool();
+    find(pool, id).await?;
+    let revisions = sqlx::query_as::<_, RevisionSummary>(
+        "SELECT revision, title, created_at FROM note_revisions
+         WHERE note_id = $1 ORDER BY revision DESC",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+    Ok(Json(revisions))
+}
+
+/// `GET /api/v1/notes/:id/revisions/:revision` - one saved version
+pub async fn get_revision(
+    State(state): State<AppState>,
+    Path((id, revision)): Path<(Uuid, i32)>,
+) -> ApiResult<Json<Revision>> {
+    Ok(Json(find_revision(state.db.pool(), id, revision).await?))
+}
+
+/// `POST /api/v1/notes/:id/revisions/:revision/restore` - save an old version as the latest
+pub async fn restore_revision(
+    State(state): State<AppState>,
+    Path((id, revision)): Path<(Uuid, i32)>,
+) -> ApiResult<Json<Note>> {
+    let pool = state.db.pool();
+    let old = find_revision(pool, id, revision).await?;
+    let changes = NoteUpdate {
+        title: Some(old.title),
+        body: Some(old.body),
+        folder: Some(old.folder),
+        tags: Some(old.tags),
+    };
+    Ok(Json(update(pool, id, &changes).await?))
+}
+
+/// Notes in a folder, not counting subfolders
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct FolderCount {
+    pub folder: String,
+    pub count: i64,
+}
+
+/// `GET /api/v1/notes/folders` - folders holding notes
+pub async fn list_folders(State(state): State<AppState>) -> ApiResult<Json<Vec<FolderCount>>> {
+    let folders = sqlx::query_as::<_, FolderCount>(
+        "SELECT folder, count(*) AS count FROM notes GROUP BY folder ORDER BY folder",
+    )
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(folders))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_folder() {
+        assert_eq!(
+            normalize_folder(" work / projects/").unwrap(),
+            "work/projects"
+        );
+        assert_eq!(normalize_folder("/").unwrap(), "");
+        assert_eq!(normalize_folder("a//b").unwrap(), "a/b");
+        assert!(normalize_folder("bad\nname").is_none());
+        assert!(normalize_folder(&"x".repeat(MAX_FOLDER_LEN + 1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_revisions_and_search() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        let mut conn = pool.acquire().await.unwrap();
+
+        let note = create(
+            &mut conn,
+            &NewNote {
+                title: "Deploy checklist".into(),
+                body: "Run the migrations first".into(),
+                folder: "work/ops".into(),
+                tags: vec!["Todo".into()],
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            (note.revision, note.tags.as_slice()),
+            (1, &["todo".to_string()][..])
+        );
+        create(
+            &mut conn,
+            &NewNote {
+                title: "Groceries".into(),
+                folder: "home".into(),
+                ..NewNote::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let changes = NoteUpdate {
+            body: Some("Back up the database".into()),
+            ..NoteUpdate::default()
+        };
+        let updated = update(pool, note.id, &changes).await.unwrap();
+        assert_eq!(updated.revision, 2);
+        assert_eq!(updated.title, "Deploy checklist");
+        let first = find_revision(pool, note.id, 1).await.unwrap();
+        assert_eq!(first.body, "Run the migrations first");
+
+        let query = |q: Option<&str>, folder: Option<&str>| NoteQuery {
+            q: q.map(String::from),
+            folder: folder.map(String::from),
+            limit: 10,
+            ..NoteQuery::default()
+        };
+        let found = search(pool, &query(Some("database"), None)).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(search(pool, &query(Some("migrations"), None))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            search(pool, &query(None, Some("work")))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(search(pool, &query(None, Some("wor")))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let unchanged = NoteUpdate {
+            title: Some(first.title),
+            body: Some(first.body),
+            folder: Some(first.folder),
+            tags: Some(first.tags),
+        };
+        let restored = update(pool, note.id, &unchanged).await.unwrap();
+        assert_eq!(restored.revision, 3);
+        assert_eq!(restored.body, "Run the migrations first");
+        assert!(matches!(
+            update(pool, Uuid::nil(), &NoteUpdate::default()).await,
+            Err(ApiError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,730 @@
+//! OpenID Connect identity provider.
+//!
+//! With `OIDC_ISSUER` set, other self-hosted applications can use this
+//! server for single sign-on through the authorization code flow (with
+//! optional PKCE). Clients are registered by admins under
+//! `/admin/oidc/clients`; users sign in on the authorization page with the
+//! same tokens `/auth/forward` accepts, which also decide the `sub` claim.
+//!
+//! ID and access tokens are RS256 JWTs signed with the keys from
+//! [`crate::jwt`] and published at `/oidc/jwks` and `/.well-known/jwks.json`.
+//! Keys are rotated every `SIGNING_KEY_ROTATION` by the leader, and every
+//! replica reloads them each minute, so rotations and
+//! `POST /admin/oidc/keys/rotate` take effect without a restart.
+
+use axum::{
+    extract::{Path, State},
+    http::{
+        header::{AUTHORIZATION, CACHE_CONTROL},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Json, Redirect, Response},
+    routing::{delete, get, post},
+    Form, Router,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::auth::{random_token, AdminToken, ApiKeys};
+use crate::crypto::{constant_time_eq, sha256_hex};
+use crate::encryption::EncryptedColumn;
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::forward_auth::identify;
+use crate::jwt::{self, SigningKey};
+use crate::leader::Leadership;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::templates::{Html, Template};
+use crate::urls::PublicUrl;
+use crate::AppState;
+
+/// Lifetime of ID and access tokens, in seconds
+const TOKEN_TTL: i64 = 3600;
+/// Lifetime of authorization codes, in seconds
+const CODE_TTL: i64 = 60;
+/// How often every replica reloads the signing keys
+const KEY_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Issuer state shared by the OIDC endpoints
+pub struct Provider {
+    issuer: String,
+    key_grace: Duration,
+    keys: RwLock<Arc<[SigningKey]>>,
+}
+
+impl Provider {
+    /// Provider whose replaced signing keys verify for `key_grace`
+    pub fn new(issuer: String, key_grace: Duration) -> Self {
+        Provider {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            key_grace,
+            keys: RwLock::new(Arc::new([])),
+        }
+    }
+
+    /// Issuer URL, without a trailing slash
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.issuer, path)
+    }
+
+    /// Signing keys, newest first, loaded on first use
+    async fn keys(&self, state: &AppState) -> ApiResult<Arc<[SigningKey]>> {
+        let keys = self.keys.read().expect("signing keys poisoned").clone();
+        if !keys.is_empty() {
+            return Ok(keys);
+        }
+        let keys: Arc<[SigningKey]> = jwt::load_or_create_keys(state.db.pool()).await?.into();
+        *self.keys.write().expect("signing keys poisoned") = keys.clone();
+        Ok(keys)
+    }
+
+    /// Replace the cached keys with those in the database
+    pub async fn reload_keys(&self, pool: &sqlx::PgPool) -> anyhow::Result<()> {
+        let keys = jwt::load_keys(pool).await?;
+        if !keys.is_empty() {
+            *self.keys.write().expect("signing keys poisoned") = keys.into();
+        }
+        Ok(())
+    }
+
+    /// The OpenID Provider Metadata document
+    pub fn discovery(&self) -> Value {
+        json!({
+            "issuer": self.issuer,
+            "authorization_endpoint": self.url("/oidc/authorize"),
+            "token_endpoint": self.url("/oidc/token"),
+            "userinfo_endpoint": self.url("/oidc/userinfo"),
+            "jwks_uri": self.url("/oidc/jwks"),
+            "response_types_supported": ["code"],
+            "grant_types_supported": ["authorization_code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+            "scopes_supported": ["openid", "profile"],
+            "claims_supported": ["sub", "preferred_username", "role"],
+            "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post"],
+            "code_challenge_methods_supported": ["S256"],
+        })
+    }
+}
+
+/// Route module serving the discovery, authorization and token endpoints
+pub struct OidcModule;
+
+impl RouteModule for OidcModule {
+    fn name(&self) -> &'static str {
+        "oidc"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/oidc/jwks", get(jwks))
+            .route("/.well-known/jwks.json", get(jwks))
+            .route("/oidc/authorize", get(authorize_page).post(authorize))
+            .route("/oidc/token", post(token))
+            .route("/oidc/userinfo", get(userinfo).post(userinfo))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[
+            Migration {
+                name: "0001_create_signing_keys",
+                kind: MigrationKind::Expand,
+                sql: "CREATE TABLE IF NOT EXISTS signing_keys (
+                    kid TEXT PRIMARY KEY,
+                    private_key TEXT NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                )",
+            },
+            Migration {
+                name: "0002_create_oidc_clients",
+                kind: MigrationKind::Expand,
+                sql: "CREATE TABLE IF NOT EXISTS oidc_clients (
+                    client_id TEXT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    secret_hash TEXT NOT NULL,
+                    redirect_uris TEXT[] NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                );
+                CREATE TABLE IF NOT EXISTS oidc_codes (
+                    code_hash TEXT PRIMARY KEY,
+                    client_id TEXT NOT NULL REFERENCES oidc_clients ON DELETE CASCADE,
+                    redirect_uri TEXT NOT NULL,
+                    subject TEXT NOT NULL,
+                    role TEXT NOT NULL,
+                    scope TEXT NOT NULL,
+                    nonce TEXT,
+                    code_challenge TEXT,
+                    expires_at TIMESTAMPTZ NOT NULL
+                )",
+            },
+            Migration {
+                name: "0003_add_signing_key_expiry",
+                kind: MigrationKind::Expand,
+                sql: "ALTER TABLE signing_keys ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ",
+            },
+        ]
+    }
+
+    fn encrypted_columns(&self) -> &'static [EncryptedColumn] {
+        &[EncryptedColumn {
+            table: "signing_keys",
+            column: "private_key",
+            key: "kid",
+        }]
+    }
+}
+
+/// Route module serving the client registration admin API
+pub struct OidcClientsModule;
+
+impl RouteModule for OidcClientsModule {
+    fn name(&self) -> &'static str {
+        "oidc_clients"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route(
+                "/admin/oidc/clients",
+                get(list_clients).post(register_client),
+            )
+            .route("/admin/oidc/clients/:client_id", delete(delete_client))
+            .route("/admin/oidc/keys/rotate", post(rotate_keys))
+    }
+}
+
+/// Rotate signing keys every `rotation` on the leader and reload them everywhere
+///
+/// A zero `rotation` only reloads, picking up keys rotated through the admin
+/// API or by other deployments.
+pub fn spawn_key_rotation(
+    provider: Arc<Provider>,
+    pool: sqlx::PgPool,
+    rotation: Duration,
+    leadership: Leadership,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(KEY_RELOAD_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if !rotation.is_zero() && leadership.is_leader() {
+                match jwt::rotate_keys(&pool, rotation, provider.key_grace).await {
+                    Ok(Some(kid)) => tracing::info!("🔑 Rotated token signing key to {}", kid),
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!("Failed to rotate signing keys: {:#}", e),
+                }
+            }
+            if let Err(e) = provider.reload_keys(&pool).await {
+                tracing::warn!("Failed to reload signing keys: {:#}", e);
+            }
+        }
+    })
+}
+
+fn provider(state: &AppState) -> ApiResult<Arc<Provider>> {
+    state
+        .extension::<Provider>()
+        .ok_or_else(|| ApiError::NotFound("OIDC provider is not configured".into()))
+}
+
+/// A registered relying party
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Client {
+    pub client_id: String,
+    pub name: String,
+    #[serde(skip)]
+    pub secret_hash: String,
+    pub redirect_uris: Vec<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+async fn find_client(state: &AppState, client_id: &str) -> ApiResult<Option<Client>> {
+    let client = sqlx::query_as::<_, Client>(
+        "SELECT client_id, name, secret_hash, redirect_uris, created_at
+         FROM oidc_clients WHERE client_id = $1",
+    )
+    .bind(client_id)
+    .fetch_optional(state.db.pool())
+    .await?;
+    Ok(client)
+}
+
+/// `GET /.well-known/openid-configuration` - provider metadata
+pub async fn discovery(State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    Ok(Json(provider(&state)?.discovery()))
+}
+
+/// `GET /oidc/jwks` - public signing keys
+pub async fn jwks(State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let provider = provider(&state)?;
+    let keys = provider.keys(&state).await?;
+    Ok(Json(json!({
+        "keys": keys.iter().map(SigningKey::jwk).collect::<Vec<_>>(),
+    })))
+}
+
+/// Parameters of an authorization request
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthorizeParams {
+    #[serde(default)]
+    response_type: String,
+    #[serde(default)]
+    client_id: String,
+    #[serde(default)]
+    redirect_uri: String,
+    #[serde(default)]
+    scope: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    /// Sign-in token, only present when the form is submitted
+    #[serde(default, skip_serializing)]
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct AuthorizePage {
+    client: String,
+    /// Absolute URL the form posts to, as the page may be served through a
+    /// proxy mounting the server under a path
+    action: String,
+    params: AuthorizeParams,
+    error: Option<String>,
+}
+
+impl Template for AuthorizePage {
+    const NAME: &'static str = "authorize.html";
+}
+
+/// Redirect back to the client with `params` added to its redirect URI
+fn redirect_to_client(params: &AuthorizeParams, pairs: &[(&str, &str)]) -> Response {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    query.extend_pairs(pairs);
+    if let Some(state) = &params.state {
+        query.append_pair("state", state);
+    }
+    let separator = if params.redirect_uri.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    Redirect::to(&format!(
+        "{}{}{}",
+        params.redirect_uri,
+        separator,
+        query.finish()
+    ))
+    .into_response()
+}
+
+/// Check an authorization request, returning the client or the response to
+/// send instead
+///
+/// Unknown clients and redirect URIs are rejected directly so the endpoint
+/// can't be used as an open redirect; other errors go back to the client.
+async fn check_request(state: &AppState, params: &AuthorizeParams) -> Result<Client, Response> {
+    let client = find_client(state, &params.client_id)
+        .await
+        .map_err(IntoResponse::into_response)?
+        .filter(|client| client.redirect_uris.contains(&params.redirect_uri))
+        .ok_or_else(|| {
+            ApiError::BadRequest("unknown client_id or redirect_uri".into()).into_response()
+        })?;
+
+    let error = if params.response_type != "code" {
+        Some("unsupported_response_type")
+    } else if !params.scope.split(' ').any(|scope| scope == "openid") {
+        Some("invalid_scope")
+    } else if params.code_challenge.is_some()
+        && params.code_challenge_method.as_deref() != Some("S256")
+    {
+        Some("invalid_request")
+    } else {
+        None
+    };
+    match error {
+        Some(error) => Err(redirect_to_client(params, &[("error", error)])),
+        None => Ok(client),
+    }
+}
+
+/// `GET /oidc/authorize` - sign-in page for an authorization request
+pub async fn authorize_page(
+    State(state): State<AppState>,
+    url: PublicUrl,
+    axum::extract::Query(params): axum::extract::Query<AuthorizeParams>,
+) -> Response {
+    if let Err(e) = provider(&state) {
+        return e.into_response();
+    }
+    match check_request(&state, &params).await {
+        Ok(client) => Html(AuthorizePage {
+            client: client.name,
+            action: url.join("/oidc/authorize"),
+            params,
+            error: None,
+        })
+        .into_response(),
+        Err(response) => response,
+    }
+}
+
+/// `POST /oidc/authorize` - sign in and issue an authorization code
+pub async fn authorize(
+    State(state): State<AppState>,
+    Ext(admin): Ext<AdminToken>,
+    Ext(keys): Ext<ApiKeys>,
+    url: PublicUrl,
+    Form(params): Form<AuthorizeParams>,
+) -> Response {
+    if let Err(e) = provider(&state) {
+        return e.into_response();
+    }
+    let client = match check_request(&state, &params).await {
+        Ok(client) => client,
+        Err(response) => return response,
+    };
+    let Some(identity) = identify(&params.token, &admin, &keys) else {
+        let page = AuthorizePage {
+            client: client.name,
+            action: url.join("/oidc/authorize"),
+            params,
+            error: Some("Invalid token".into()),
+        };
+        return (StatusCode::UNAUTHORIZED, Html(page)).into_response();
+    };
+
+    let code = random_token(32);
+    let pool = state.db.pool();
+    let stored = async {
+        sqlx::query("DELETE FROM oidc_codes WHERE expires_at < now()")
+            .execute(pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO oidc_codes
+                 (code_hash, client_id, redirect_uri, subject, role, scope, nonce, code_challenge,
+                  expires_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, now() + make_interval(secs => $9))",
+        )
+        .bind(sha256_hex(&code))
+        .bind(&client.client_id)
+        .bind(&params.redirect_uri)
+        .bind(&identity.user)
+        .bind(identity.role)
+        .bind(&params.scope)
+        .bind(&params.nonce)
+        .bind(&params.code_challenge)
+        .bind(CODE_TTL as f64)
+        .execute(pool)
+        .await?;
+        Ok::<_, sqlx::Error>(())
+    };
+    if let Err(e) = stored.await {
+        return ApiError::from(e).into_response();
+    }
+    tracing::info!(
+        "🔐 Issued OIDC authorization code for '{}' to client '{}'",
+        identity.user,
+        client.client_id
+    );
+    redirect_to_client(&params, &[("code", &code)])
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    #[serde(default)]
+    grant_type: String,
+    #[serde(default)]
+    code: String,
+    #[serde(default)]
+    redirect_uri: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    code_verifier: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct CodeGrant {
+    client_id: String,
+    redirect_uri: String,
+    subject: String,
+    role: String,
+    scope: String,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+}
+
+/// Client credentials from HTTP Basic auth or the form body
+fn client_credentials(headers: &HeaderMap, form: &TokenRequest) -> Option<(String, String)> {
+    let basic = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v.trim()).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+    if let Some((id, secret)) = basic.as_deref().and_then(|v| v.split_once(':')) {
+        return Some((id.to_string(), secret.to_string()));
+    }
+    Some((form.client_id.clone()?, form.client_secret.clone()?))
+}
+
+/// Whether a PKCE verifier matches the challenge stored with the code
+fn pkce_matches(challenge: Option<&str>, verifier: Option<&str>) -> bool {
+    match (challenge, verifier) {
+        (None, _) => true,
+        (Some(challenge), Some(verifier)) => {
+            let computed = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+            constant_time_eq(computed.as_bytes(), challenge.as_bytes())
+        }
+        (Some(_), None) => false,
+    }
+}
+
+/// `POST /oidc/token` - exchange an authorization code for tokens
+pub async fn token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<TokenRequest>,
+) -> ApiResult<Response> {
+    let provider = provider(&state)?;
+    if form.grant_type != "authorization_code" {
+        return Err(ApiError::BadRequest("unsupported_grant_type".into()));
+    }
+    let (client_id, secret) = client_credentials(&headers, &form)
+        .ok_or_else(|| ApiError::Unauthorized("invalid_client".into()))?;
+    let client = find_client(&state, &client_id)
+        .await?
+        .filter(|client| {
+            constant_time_eq(
+                client.secret_hash.as_bytes(),
+                sha256_hex(&secret).as_bytes(),
+            )
+        })
+        .ok_or_else(|| ApiError::Unauthorized("invalid_client".into()))?;
+
+    // Codes are single use: deleting while reading makes a replay fail
+    let grant = sqlx::query_as::<_, CodeGrant>(
+        "DELETE FROM oidc_codes WHERE code_hash = $1 AND expires_at > now()
+         RETURNING client_id, redirect_uri, subject, role, scope, nonce, code_challenge",
+    )
+    .bind(sha256_hex(&form.code))
+    .fetch_optional(state.db.pool())
+    .await?
+    .filter(|grant| grant.client_id == client.client_id && grant.redirect_uri == form.redirect_uri)
+    .filter(|grant| {
+        pkce_matches(
+            grant.code_challenge.as_deref(),
+            form.code_verifier.as_deref(),
+        )
+    })
+    .ok_or_else(|| ApiError::BadRequest("invalid_grant".into()))?;
+
+    let keys = provider.keys(&state).await?;
+    let key = &keys[0];
+    let now = Utc::now().timestamp();
+    let mut id_claims = json!({
+        "iss": provider.issuer,
+        "sub": grant.subject,
+        "aud": client.client_id,
+        "iat": now,
+        "exp": now + TOKEN_TTL,
+        "auth_time": now,
+        "preferred_username": grant.subject,
+        "role": grant.role,
+    });
+    if let Some(nonce) = &grant.nonce {
+        id_claims["nonce"] = json!(nonce);
+    }
+    let access_claims = json!({
+        "iss": provider.issuer,
+        "sub": grant.subject,
+        "aud": provider.issuer,
+        "client_id": client.client_id,
+        "scope": grant.scope,
+        "role": grant.role,
+        "iat": now,
+        "exp": now + TOKEN_TTL,
+    });
+
+    let body = json!({
+        "access_token": key.sign(&access_claims)?,
+        "token_type": "Bearer",
+        "expires_in": TOKEN_TTL,
+        "id_token": key.sign(&id_claims)?,
+        "scope": grant.scope,
+    });
+    Ok((
+        [(CACHE_CONTROL, HeaderValue::from_static("no-store"))],
+        Json(body),
+    )
+        .into_response())
+}
+
+/// `GET /oidc/userinfo` - claims about the holder of an access token
+pub async fn userinfo(State(state): State<AppState>, headers: HeaderMap) -> ApiResult<Json<Value>> {
+    let provider = provider(&state)?;
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::Unauthorized("invalid_token".into()))?;
+    let claims = jwt::verify(token.trim(), &provider.keys(&state).await?)
+        .ok()
+        // Only access tokens carry our issuer as audience
+        .filter(|claims| claims["iss"] == provider.issuer && claims["aud"] == provider.issuer)
+        .ok_or_else(|| ApiError::Unauthorized("invalid_token".into()))?;
+    Ok(Json(json!({
+        "sub": claims["sub"],
+        "preferred_username": claims["sub"],
+        "role": claims["role"],
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewClient {
+    name: String,
+    redirect_uris: Vec<String>,
+}
+
+/// `GET /admin/oidc/clients` - list registered clients
+pub async fn list_clients(State(state): State<AppState>) -> ApiResult<Json<Vec<Client>>> {
+    let clients = sqlx::query_as::<_, Client>(
+        "SELECT client_id, name, secret_hash, redirect_uris, created_at
+         FROM oidc_clients ORDER BY created_at",
+    )
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(clients))
+}
+
+/// `POST /admin/oidc/clients` - register a client
+///
+/// The response is the only time the client secret is shown.
+pub async fn register_client(
+    State(state): State<AppState>,
+    Json(new): Json<NewClient>,
+) -> ApiResult<(StatusCode, Json<Value>)> {
+    if new.name.trim().is_empty() {
+        return Err(ApiError::Validation("name must not be empty".into()));
+    }
+    if new.redirect_uris.is_empty() {
+        return Err(ApiError::Validation(
+            "redirect_uris must not be empty".into(),
+        ));
+    }
+    if let Some(uri) = new.redirect_uris.iter().find(|uri| {
+        !(uri.starts_with("https://") || uri.starts_with("http://")) || uri.contains('#')
+    }) {
+        return Err(ApiError::Validation(format!(
+            "invalid redirect URI '{}'",
+            uri
+        )));
+    }
+
+    let client_id = random_token(16);
+    let secret = random_token(32);
+    let client = sqlx::query_as::<_, Client>(
+        "INSERT INTO oidc_clients (client_id, name, secret_hash, redirect_uris)
+         VALUES ($1, $2, $3, $4)
+         RETURNING client_id, name, secret_hash, redirect_uris, created_at",
+    )
+    .bind(&client_id)
+    .bind(new.name.trim())
+    .bind(sha256_hex(&secret))
+    .bind(&new.redirect_uris)
+    .fetch_one(state.db.pool())
+    .await?;
+
+    let mut body = serde_json::to_value(&client).map_err(anyhow::Error::from)?;
+    body["client_secret"] = json!(secret);
+    Ok((StatusCode::CREATED, Json(body)))
+}
+
+/// `POST /admin/oidc/keys/rotate` - replace the signing key now
+pub async fn rotate_keys(State(state): State<AppState>) -> ApiResult<Json<Value>> {
+    let provider = provider(&state)?;
+    let pool = state.db.pool();
+    let kid = jwt::rotate_keys(pool, Duration::ZERO, provider.key_grace).await?;
+    provider.reload_keys(pool).await?;
+    tracing::info!("🔑 Rotated token signing key to {:?} on request", kid);
+    Ok(Json(json!({ "kid": kid })))
+}
+
+/// `DELETE /admin/oidc/clients/:client_id` - remove a client
+pub async fn delete_client(
+    State(state): State<AppState>,
+    Path(client_id): Path<String>,
+) -> ApiResult<StatusCode> {
+    let result = sqlx::query("DELETE FROM oidc_clients WHERE client_id = $1")
+        .bind(&client_id)
+        .execute(state.db.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(format!(
+            "client '{}' not found",
+            client_id
+        )));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkce() {
+        // Example from RFC 7636, appendix B
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+        assert!(pkce_matches(Some(challenge), Some(verifier)));
+        assert!(!pkce_matches(Some(challenge), Some("wrong")));
+        assert!(!pkce_matches(Some(challenge), None));
+        assert!(pkce_matches(None, None));
+    }
+
+    #[test]
+    fn test_discovery_and_redirects() {
+        let provider = Provider::new(
+            "https://sso.example.com/".into(),
+            Duration::from_secs(86400),
+        );
+        let metadata = provider.discovery();
+        assert_eq!(metadata["issuer"], "https://sso.example.com");
+        assert_eq!(metadata["jwks_uri"], "https://sso.example.com/oidc/jwks");
+
+        let params: AuthorizeParams = serde_json::from_value(json!({
+            "redirect_uri": "https://app.example.com/cb?x=1",
+            "state": "a b",
+        }))
+        .unwrap();
+        let response = redirect_to_client(&params, &[("code", "abc")]);
+        assert_eq!(
+            response.headers()["location"],
+            "https://app.example.com/cb?x=1&code=abc&state=a+b"
+        );
+    }
+}
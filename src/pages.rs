@@ -0,0 +1,49 @@
+//! Built-in server-rendered pages.
+
+use axum::{extract::Query, routing::get, Router};
+use serde::{Deserialize, Serialize};
+
+use crate::module::{RouteGroup, RouteModule};
+use crate::templates::{Html, Template};
+use crate::AppState;
+
+/// Route module serving the HTML login page
+pub struct PagesModule;
+
+impl RouteModule for PagesModule {
+    fn name(&self) -> &'static str {
+        "pages"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/login", get(login_page))
+    }
+}
+
+#[derive(Serialize)]
+pub struct LoginPage {
+    next: String,
+}
+
+impl Template for LoginPage {
+    const NAME: &'static str = "login.html";
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+/// `GET /login` - admin token sign-in form
+pub async fn login_page(Query(query): Query<LoginQuery>) -> Html<LoginPage> {
+    // Only follow same-site paths so the page can't be used as an open redirect
+    let next = query
+        .next
+        .filter(|next| next.starts_with('/') && !next.starts_with("//") && !next.contains('\\'))
+        .unwrap_or_else(|| "/".to_string());
+    Html(LoginPage { next })
+}
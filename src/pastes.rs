@@ -0,0 +1,435 @@
+//! Pastebin for text snippets.
+//!
+//! Pastes are created through the API (`POST /api/v1/pastes`), so they sit
+//! behind whatever the `api` middleware group requires, and are shared
+//! through the public raw link `/p/:id`:
+//!
+//! ```text
+//! POST /api/v1/pastes {"content": "...", "syntax": "rust", "expires_in": "1d", "password": "..."}
+//! ```
+//!
+//! The response carries a one-time `delete_token`, sent back in the
+//! `X-Delete-Token` header to `DELETE /api/v1/pastes/:id`. Password
+//! protected pastes are read with the password in `X-Paste-Password`.
+//! `syntax` is only a hint for highlighting clients. Expired pastes are
+//! never served and are swept whenever a new paste is created.
+
+use axum::{
+    extract::{Path, State},
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER, X_CONTENT_TYPE_OPTIONS},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::time::{Duration, Instant};
+
+use crate::auth::random_token;
+use crate::backoff::Backoff;
+use crate::config::parse_duration;
+use crate::crypto::sha256_hex;
+use crate::crypto::{constant_time_eq, password};
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::usage::Meter;
+use crate::{t, AppState};
+
+/// Largest paste accepted, in bytes
+pub const MAX_PASTE_BYTES: usize = 1024 * 1024;
+
+/// Longest lifetime a paste may ask for
+pub const MAX_EXPIRY: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Header carrying a paste's password
+pub const PASSWORD_HEADER: &str = "x-paste-password";
+
+/// Header carrying a paste's delete token
+pub const DELETE_TOKEN_HEADER: &str = "x-delete-token";
+
+/// A stored paste
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Paste {
+    pub id: String,
+    pub content: String,
+    pub syntax: Option<String>,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    #[serde(skip)]
+    pub delete_token_hash: String,
+    #[serde(with = "crate::time::rfc3339_option")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Route module creating, reading and deleting pastes
+pub struct PastesModule;
+
+impl RouteModule for PastesModule {
+    fn name(&self) -> &'static str {
+        "pastes"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/api/v1/pastes", axum::routing::post(create_paste))
+            .route("/api/v1/pastes/:id", get(get_paste).delete(delete_paste))
+            .route("/api/v1/pastes/:id/raw", get(raw_paste))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_pastes",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS pastes (
+                id TEXT PRIMARY KEY,
+                content TEXT NOT NULL,
+                syntax TEXT,
+                password_hash TEXT,
+                delete_token_hash TEXT NOT NULL,
+                expires_at TIMESTAMPTZ,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE INDEX IF NOT EXISTS pastes_expires_at ON pastes (expires_at)",
+        }]
+    }
+}
+
+/// Route module serving the public `/p/:id` raw links
+pub struct PasteLinksModule;
+
+impl RouteModule for PasteLinksModule {
+    fn name(&self) -> &'static str {
+        "paste_links"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/p/:id", get(raw_paste))
+    }
+}
+
+/// Check a syntax hint such as `rust`, `c++` or `objective-c`
+fn valid_syntax(syntax: &str) -> bool {
+    (1..=32).contains(&syntax.len())
+        && syntax
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '+' | '#' | '-'))
+}
+
+/// A paste about to be stored
+#[derive(Debug, Default, Deserialize)]
+pub struct NewPaste {
+    pub content: String,
+    #[serde(default)]
+    pub syntax: Option<String>,
+    /// Lifetime such as `1h` or `7d`; pastes without one are kept until deleted
+    #[serde(default)]
+    pub expires_in: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// A created paste with the token needed to delete it
+#[derive(Debug, Serialize)]
+pub struct CreatedPaste {
+    #[serde(flatten)]
+    pub paste: PasteView,
+    pub delete_token: String,
+}
+
+/// What readers see of a paste
+#[derive(Debug, Serialize)]
+pub struct PasteView {
+    pub id: String,
+    pub content: String,
+    pub syntax: Option<String>,
+    pub password_protected: bool,
+    pub raw_url: String,
+    #[serde(with = "crate::time::rfc3339_option")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Paste> for PasteView {
+    fn from(paste: Paste) -> Self {
+        PasteView {
+            raw_url: format!("/p/{}", paste.id),
+            password_protected: paste.password_hash.is_some(),
+            id: paste.id,
+            content: paste.content,
+            syntax: paste.syntax,
+            expires_at: paste.expires_at,
+            created_at: paste.created_at,
+        }
+    }
+}
+
+/// Check and store a paste, returning it with its delete token
+pub async fn create(pool: &PgPool, new: NewPaste) -> ApiResult<(Paste, String)> {
+    if new.content.is_empty() {
+        return Err(ApiError::Validation(t!("paste-empty")));
+    }
+    if new.content.len() > MAX_PASTE_BYTES {
+        return Err(ApiError::Validation(t!(
+            "paste-too-large",
+            max = MAX_PASTE_BYTES
+        )));
+    }
+    if let Some(syntax) = new.syntax.as_deref().filter(|s| !valid_syntax(s)) {
+        return Err(ApiError::Validation(t!("paste-syntax", syntax = syntax)));
+    }
+    let expires_in = new
+        .expires_in
+        .as_deref()
+        .map(|input| {
+            parse_duration(input)
+                .ok()
+                .filter(|d| !d.is_zero() && *d <= MAX_EXPIRY)
+                .ok_or_else(|| ApiError::Validation(t!("paste-expiry", expires_in = input)))
+        })
+        .transpose()?;
+    let password_hash = match new.password.as_deref().filter(|p| !p.is_empty()) {
+        Some(given) => Some(password::hash_async(given).await?),
+        None => None,
+    };
+
+    sqlx::query("DELETE FROM pastes WHERE expires_at < now()")
+        .execute(pool)
+        .await?;
+    let delete_token = random_token(24);
+    let paste = sqlx::query_as::<_, Paste>(
+        "INSERT INTO pastes (id, content, syntax, password_hash, delete_token_hash, expires_at)
+         VALUES ($1, $2, $3, $4, $5, now() + make_interval(secs => $6))
+         RETURNING *",
+    )
+    .bind(random_token(9))
+    .bind(&new.content)
+    .bind(&new.syntax)
+    .bind(password_hash)
+    .bind(sha256_hex(&delete_token))
+    .bind(expires_in.map(|d| d.as_secs_f64()))
+    .fetch_one(pool)
+    .await?;
+    Ok((paste, delete_token))
+}
+
+/// Look up an unexpired paste
+pub async fn find(pool: &PgPool, id: &str) -> ApiResult<Paste> {
+    sqlx::query_as::<_, Paste>(
+        "SELECT * FROM pastes WHERE id = $1 AND (expires_at IS NULL OR expires_at > now())",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(t!("paste-not-found")))
+}
+
+/// Wrong passwords per paste, so guessing one waits out a [`Backoff`]
+#[derive(Debug, Default)]
+pub struct PasswordAttempts(Backoff<String>);
+
+/// Look up a paste, checking the password in the request headers
+///
+/// A paste given the wrong password too often is answered `429` for a
+/// while, before the password is hashed.
+async fn find_readable(
+    pool: &PgPool,
+    attempts: &PasswordAttempts,
+    id: &str,
+    headers: &HeaderMap,
+) -> Result<Paste, Response> {
+    let paste = find(pool, id).await.map_err(IntoResponse::into_response)?;
+    if let Some(hash) = &paste.password_hash {
+        let Some(given) = headers
+            .get(PASSWORD_HEADER)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Err(ApiError::Forbidden(t!("paste-password")).into_response());
+        };
+        let now = Instant::now();
+        if let Some(wait) = attempts.0.blocked(&paste.id, now) {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, HeaderValue::from(wait))],
+                Json(serde_json::json!({ "error": t!("paste-too-many-failures") })),
+            )
+                .into_response());
+        }
+        if !password::verify_async(given, hash)
+            .await
+            .map_err(|e| ApiError::Internal(e).into_response())?
+        {
+            attempts.0.failed(paste.id.clone(), now);
+            return Err(ApiError::Forbidden(t!("paste-password")).into_response());
+        }
+        attempts.0.succeeded(&paste.id);
+    }
+    Ok(paste)
+}
+
+/// `POST /api/v1/pastes` - store a paste
+pub async fn create_paste(
+    State(state): State<AppState>,
+    meter: Meter,
+    Json(body): Json<NewPaste>,
+) -> ApiResult<(StatusCode, Json<CreatedPaste>)> {
+    let (paste, delete_token) = create(state.db.pool(), body).await?;
+    meter.storage(paste.content.len() as i64);
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedPaste {
+            paste: paste.into(),
+            delete_token,
+        }),
+    ))
+}
+
+/// `GET /api/v1/pastes/:id` - a paste with its metadata
+pub async fn get_paste(
+    State(state): State<AppState>,
+    Ext(attempts): Ext<PasswordAttempts>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<PasteView>, Response> {
+    let paste = find_readable(state.db.pool(), &attempts, &id, &headers).await?;
+    Ok(Json(paste.into()))
+}
+
+/// `GET /api/v1/pastes/:id/raw` and `GET /p/:id` - a paste as plain text
+pub async fn raw_paste(
+    State(state): State<AppState>,
+    Ext(attempts): Ext<PasswordAttempts>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    let paste = find_readable(state.db.pool(), &attempts, &id, &headers).await?;
+    Ok((
+        [
+            (CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        paste.content,
+    )
+        .into_response())
+}
+
+/// `DELETE /api/v1/pastes/:id` - remove a paste given its delete token
+pub async fn delete_paste(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    meter: Meter,
+    headers: HeaderMap,
+) -> ApiResult<StatusCode> {
+    let pool = state.db.pool();
+    let paste = find(pool, &id).await?;
+    let token = headers
+        .get(DELETE_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if !constant_time_eq(
+        sha256_hex(token).as_bytes(),
+        paste.delete_token_hash.as_bytes(),
+    ) {
+        return Err(ApiError::Forbidden(t!("paste-delete-token")));
+    }
+    sqlx::query("DELETE FROM pastes WHERE id = $1")
+        .bind(&paste.id)
+        .execute(pool)
+        .await?;
+    meter.storage(-(paste.content.len() as i64));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_syntax() {
+        assert!(valid_syntax("rust") && valid_syntax("c++") && valid_syntax("objective-c"));
+        assert!(!valid_syntax("") && !valid_syntax("Rust") && !valid_syntax("<script>"));
+    }
+
+    #[tokio::test]
+    async fn test_create_read_expire_delete() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+
+        let new = |expires_in: Option<&str>| NewPaste {
+            content: "fn main() {}".into(),
+            syntax: Some("rust".into()),
+            expires_in: expires_in.map(String::from),
+            password: Some("hunter22".into()),
+        };
+        let (paste, token) = create(pool, new(Some("1h"))).await.unwrap();
+        assert!(paste.expires_at.is_some());
+
+        let attempts = PasswordAttempts::default();
+        let status = |result: Result<Paste, Response>| result.unwrap_err().status();
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            status(find_readable(pool, &attempts, &paste.id, &headers).await),
+            StatusCode::FORBIDDEN
+        );
+        headers.insert(PASSWORD_HEADER, "hunter22".parse().unwrap());
+        let read = find_readable(pool, &attempts, &paste.id, &headers)
+            .await
+            .unwrap();
+        assert_eq!(read.content, "fn main() {}");
+
+        // Guessing waits once the free failures are used up
+        let mut wrong = HeaderMap::new();
+        wrong.insert(PASSWORD_HEADER, "hunter2".parse().unwrap());
+        for _ in 0..crate::backoff::FREE_FAILURES {
+            assert_eq!(
+                status(find_readable(pool, &attempts, &paste.id, &wrong).await),
+                StatusCode::FORBIDDEN
+            );
+        }
+        assert_eq!(
+            status(find_readable(pool, &attempts, &paste.id, &headers).await),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        assert!(matches!(
+            create(pool, new(Some("2y"))).await,
+            Err(ApiError::Validation(_))
+        ));
+        assert!(matches!(
+            create(pool, NewPaste::default()).await,
+            Err(ApiError::Validation(_))
+        ));
+
+        sqlx::query("UPDATE pastes SET expires_at = now() - interval '1 second' WHERE id = $1")
+            .bind(&paste.id)
+            .execute(pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            find(pool, &paste.id).await,
+            Err(ApiError::NotFound(_))
+        ));
+
+        let (paste, _) = create(pool, new(None)).await.unwrap();
+        assert!(paste.expires_at.is_none());
+        let expired: i64 = sqlx::query_scalar("SELECT count(*) FROM pastes")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(expired, 1, "creating a paste sweeps expired ones");
+        assert!(!token.is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+//! Config-driven middleware pipeline.
+//!
+//! Instead of a hard-coded layer stack, the middleware applied to the
+//! application is declared in configuration as ordered lists of layer
+//! names. `MIDDLEWARE` applies to every request; `MIDDLEWARE_PUBLIC`,
+//! `MIDDLEWARE_API` and `MIDDLEWARE_ADMIN` apply to a single route group.
+//! The first layer listed is the outermost. Lists are validated at startup
+//! so typos and layers missing their settings fail fast.
+
+use anyhow::Result;
+use axum::{middleware, Router};
+use sqlx::PgPool;
+use std::str::FromStr;
+use tower_http::{compression::CompressionLayer, cors::CorsLayer, trace::TraceLayer};
+
+use crate::auth::{self, ApiAuth};
+use crate::concurrency::ConcurrencyLimiter;
+use crate::module::RouteGroup;
+use crate::rate_limit::RateLimiter;
+use crate::single_flight::SingleFlight;
+use crate::transaction;
+
+/// A middleware layer that can be enabled from configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiddlewareLayer {
+    /// Request/response tracing
+    Trace,
+    /// Permissive CORS headers
+    Cors,
+    /// gzip/brotli response compression
+    Compression,
+    /// Per-client token-bucket rate limiting
+    RateLimit,
+    /// Cap on requests in flight per API key or user
+    ConcurrencyLimit,
+    /// Require a valid API key or request signature
+    Auth,
+    /// Run each request in a database transaction
+    Transaction,
+    /// Coalesce identical concurrent GETs into one handler run
+    SingleFlight,
+}
+
+impl MiddlewareLayer {
+    /// The name used in configuration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MiddlewareLayer::Trace => "trace",
+            MiddlewareLayer::Cors => "cors",
+            MiddlewareLayer::Compression => "compression",
+            MiddlewareLayer::RateLimit => "rate_limit",
+            MiddlewareLayer::ConcurrencyLimit => "concurrency_limit",
+            MiddlewareLayer::Auth => "auth",
+            MiddlewareLayer::Transaction => "transaction",
+            MiddlewareLayer::SingleFlight => "single_flight",
+        }
+    }
+}
+
+impl FromStr for MiddlewareLayer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "trace" => Ok(MiddlewareLayer::Trace),
+            "cors" => Ok(MiddlewareLayer::Cors),
+            "compression" => Ok(MiddlewareLayer::Compression),
+            "rate_limit" => Ok(MiddlewareLayer::RateLimit),
+            "concurrency_limit" => Ok(MiddlewareLayer::ConcurrencyLimit),
+            "auth" => Ok(MiddlewareLayer::Auth),
+            "transaction" => Ok(MiddlewareLayer::Transaction),
+            "single_flight" => Ok(MiddlewareLayer::SingleFlight),
+            other => anyhow::bail!(
+                "Unknown middleware '{}' (expected trace, cors, compression, rate_limit, concurrency_limit, auth, transaction or single_flight)",
+                other
+            ),
+        }
+    }
+}
+
+/// Parse an ordered, comma-separated list of middleware names
+pub fn parse_layers(input: &str) -> Result<Vec<MiddlewareLayer>> {
+    let mut layers = Vec::new();
+    for name in input.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let layer = name.parse::<MiddlewareLayer>()?;
+        if layers.contains(&layer) {
+            anyhow::bail!("Middleware '{}' is listed more than once", name);
+        }
+        layers.push(layer);
+    }
+    Ok(layers)
+}
+
+/// Middleware declared for the whole app and for each route group
+#[derive(Debug, Clone, Default)]
+pub struct MiddlewareConfig {
+    pub global: Vec<MiddlewareLayer>,
+    pub public: Vec<MiddlewareLayer>,
+    pub api: Vec<MiddlewareLayer>,
+    pub admin: Vec<MiddlewareLayer>,
+}
+
+impl MiddlewareConfig {
+    /// Layers configured for a route group
+    pub fn for_group(&self, group: RouteGroup) -> &[MiddlewareLayer] {
+        match group {
+            RouteGroup::Public => &self.public,
+            RouteGroup::Api => &self.api,
+            RouteGroup::Admin => &self.admin,
+        }
+    }
+
+    /// Check that no group repeats a layer the whole app already runs,
+    /// which would apply it twice to the group's requests
+    pub fn validate(&self) -> Result<()> {
+        let groups = [
+            ("MIDDLEWARE_PUBLIC", &self.public),
+            ("MIDDLEWARE_API", &self.api),
+            ("MIDDLEWARE_ADMIN", &self.admin),
+        ];
+        for (key, layers) in groups {
+            if let Some(layer) = layers.iter().find(|layer| self.global.contains(layer)) {
+                anyhow::bail!(
+                    "{} lists '{}', which MIDDLEWARE already applies to every request",
+                    key,
+                    layer.as_str()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a layer is used anywhere in the pipeline
+    pub fn uses(&self, layer: MiddlewareLayer) -> bool {
+        [&self.global, &self.public, &self.api, &self.admin]
+            .iter()
+            .any(|layers| layers.contains(&layer))
+    }
+}
+
+/// Builds the configured layers onto routers
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    rate_limiter: RateLimiter,
+    concurrency_limiter: ConcurrencyLimiter,
+    api_auth: ApiAuth,
+    pool: PgPool,
+    single_flight: SingleFlight,
+}
+
+impl Pipeline {
+    pub fn new(
+        rate_limiter: RateLimiter,
+        concurrency_limiter: ConcurrencyLimiter,
+        api_auth: ApiAuth,
+        pool: PgPool,
+    ) -> Self {
+        Pipeline {
+            rate_limiter,
+            concurrency_limiter,
+            api_auth,
+            pool,
+            single_flight: SingleFlight::default(),
+        }
+    }
+
+    /// Wrap a router in the given layers, first layer outermost
+    pub fn apply<S>(&self, router: Router<S>, layers: &[MiddlewareLayer]) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        self.apply_layers(router, layers, None)
+    }
+
+    /// Wrap a route group's router in the layers configured for it
+    pub fn apply_group<S>(
+        &self,
+        router: Router<S>,
+        group: RouteGroup,
+        config: &MiddlewareConfig,
+    ) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        self.apply_layers(router, config.for_group(group), Some(group))
+    }
+
+    fn apply_layers<S>(
+        &self,
+        router: Router<S>,
+        layers: &[MiddlewareLayer],
+        group: Option<RouteGroup>,
+    ) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        layers
+            .iter()
+            .rev()
+            .fold(router, |router, layer| match layer {
+                MiddlewareLayer::Trace => router.layer(TraceLayer::new_for_http()),
+                MiddlewareLayer::Cors => router.layer(CorsLayer::permissive()),
+                MiddlewareLayer::Compression => router.layer(CompressionLayer::new()),
+                MiddlewareLayer::RateLimit => router.layer(middleware::from_fn_with_state(
+                    self.rate_limiter.clone(),
+                    RateLimiter::middleware,
+                )),
+                MiddlewareLayer::ConcurrencyLimit => router.layer(middleware::from_fn_with_state(
+                    self.concurrency_limiter.clone(),
+                    ConcurrencyLimiter::middleware,
+                )),
+                MiddlewareLayer::Auth => router.layer(middleware::from_fn_with_state(
+                    ApiAuth {
+                        group,
+                        ..self.api_auth.clone()
+                    },
+                    auth::require_api_key,
+                )),
+                MiddlewareLayer::Transaction => router.layer(middleware::from_fn_with_state(
+                    self.pool.clone(),
+                    transaction::middleware,
+                )),
+                MiddlewareLayer::SingleFlight => router.layer(middleware::from_fn_with_state(
+                    self.single_flight.clone(),
+                    SingleFlight::middleware,
+                )),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_layers_keeps_order() {
+        assert_eq!(
+            parse_layers("trace, rate_limit,auth,concurrency_limit,single_flight,transaction")
+                .unwrap(),
+            vec![
+                MiddlewareLayer::Trace,
+                MiddlewareLayer::RateLimit,
+                MiddlewareLayer::Auth,
+                MiddlewareLayer::ConcurrencyLimit,
+                MiddlewareLayer::SingleFlight,
+                MiddlewareLayer::Transaction
+            ]
+        );
+        assert!(parse_layers("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_layers_rejects_unknown_and_duplicates() {
+        assert!(parse_layers("cors,gzip").is_err());
+        assert!(parse_layers("cors,cors").is_err());
+    }
+
+    #[test]
+    fn test_rejects_layers_repeated_in_groups() {
+        let config = MiddlewareConfig {
+            global: parse_layers("cors,rate_limit").unwrap(),
+            api: parse_layers("rate_limit,auth").unwrap(),
+            ..MiddlewareConfig::default()
+        };
+        assert!(config.validate().is_err());
+        let config = MiddlewareConfig {
+            api: parse_layers("auth").unwrap(),
+            ..config
+        };
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,500 @@
+//! WASM plugin runtime for request hooks and custom routes.
+//!
+//! Plugins are `.wasm` (or `.wat`) modules loaded from `PLUGINS_DIR` at
+//! startup and executed with wasmtime. Each call gets a fresh instance
+//! limited in fuel and memory, so plugins cannot hold state between
+//! requests, stall the server or exhaust its memory.
+//!
+//! # Host ABI
+//!
+//! Data crosses the boundary as UTF-8 JSON in the plugin's linear memory.
+//! Functions returning data pack the location as `(ptr << 32) | len` in an
+//! `i64`; returning `0` means "nothing".
+//!
+//! Required exports:
+//! - `memory`: the plugin's linear memory
+//! - `alloc(len: i32) -> i32`: reserve `len` bytes for host-written input
+//! - `manifest() -> i64`: `{"name": "...", "routes": [{"method": "GET", "path": "/x"}]}`
+//!
+//! Optional exports, each taking `(ptr: i32, len: i32) -> i64`:
+//! - `on_request`: receives the request metadata; may return a response
+//!   (`{"status", "headers", "body"}`) to short-circuit the request
+//! - `on_response`: receives the request metadata plus `status`; may return
+//!   `{"headers": {...}}` to add response headers
+//! - `handle`: serves the routes declared in the manifest, mounted under
+//!   `/plugins/<name>`; receives the request metadata plus `body`
+//!
+//! Host imports (module `env`):
+//! - `host_log(level: i32, ptr: i32, len: i32)`: log a message
+//!   (0 = error, 1 = warn, 2 = info, 3+ = debug)
+
+use anyhow::{Context, Result};
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{on, MethodFilter},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use wasmtime::{
+    Caller, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+use crate::AppState;
+
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// Plugin runtime settings
+#[derive(Debug, Clone)]
+pub struct PluginConfig {
+    pub dir: String,
+    pub fuel: u64,
+    /// Largest linear memory a plugin call may grow to, in bytes
+    pub max_memory: usize,
+}
+
+impl PluginConfig {
+    /// Load plugin settings from a variable lookup, as [`Config`](crate::config::Config) does
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let dir = var("PLUGINS_DIR").unwrap_or_else(|| "plugins".to_string());
+        let fuel = var("PLUGIN_FUEL")
+            .unwrap_or_else(|| "10000000".to_string())
+            .parse::<u64>()
+            .map_err(|e| anyhow::anyhow!("Invalid PLUGIN_FUEL: {}", e))?;
+        let max_memory = crate::config::parse_size(
+            &var("PLUGIN_MAX_MEMORY").unwrap_or_else(|| "64MB".to_string()),
+        )
+        .map_err(|e| anyhow::anyhow!("Invalid PLUGIN_MAX_MEMORY: {}", e))?;
+        Ok(PluginConfig {
+            dir,
+            fuel,
+            max_memory: usize::try_from(max_memory)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PluginRoute {
+    pub method: String,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    #[serde(default)]
+    pub routes: Vec<PluginRoute>,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestInfo<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: Option<&'a str>,
+    headers: BTreeMap<&'a str, &'a str>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PluginResponse {
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: String,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl IntoResponse for PluginResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::BAD_GATEWAY);
+        let mut response = (status, self.body).into_response();
+        append_headers(&mut response, &self.headers);
+        response
+    }
+}
+
+fn append_headers(response: &mut Response, headers: &BTreeMap<String, String>) {
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) {
+            response.headers_mut().append(name, value);
+        }
+    }
+}
+
+/// A loaded plugin ready to be instantiated per call
+pub struct Plugin {
+    manifest: Manifest,
+    engine: Engine,
+    instance_pre: InstancePre<StoreLimits>,
+    fuel: u64,
+    max_memory: usize,
+    exports: Vec<String>,
+}
+
+impl Plugin {
+    /// Compile a plugin module and read its manifest
+    pub fn load(engine: &Engine, path: &Path, fuel: u64, max_memory: usize) -> Result<Self> {
+        let module = Module::from_file(engine, path)
+            .with_context(|| format!("Failed to compile plugin {}", path.display()))?;
+        let exports = module.exports().map(|e| e.name().to_string()).collect();
+
+        let mut linker = Linker::new(engine);
+        linker.func_wrap(
+            "env",
+            "host_log",
+            |mut caller: Caller<'_, StoreLimits>, level: i32, ptr: i32, len: i32| {
+                let Some(memory) = caller.get_export("memory").and_then(|e| e.into_memory()) else {
+                    return;
+                };
+                let len = len.max(0) as usize;
+                if len > memory.data_size(&caller) {
+                    return;
+                }
+                let mut buf = vec![0u8; len];
+                if memory.read(&caller, ptr as usize, &mut buf).is_ok() {
+                    let message = String::from_utf8_lossy(&buf);
+                    match level {
+                        0 => tracing::error!(target: "plugin", "{}", message),
+                        1 => tracing::warn!(target: "plugin", "{}", message),
+                        2 => tracing::info!(target: "plugin", "{}", message),
+                        _ => tracing::debug!(target: "plugin", "{}", message),
+                    }
+                }
+            },
+        )?;
+        let instance_pre = linker.instantiate_pre(&module)?;
+
+        let mut plugin = Plugin {
+            manifest: Manifest {
+                name: String::new(),
+                routes: Vec::new(),
+            },
+            engine: engine.clone(),
+            instance_pre,
+            fuel,
+            max_memory,
+            exports,
+        };
+        let manifest = plugin
+            .call("manifest", None)?
+            .ok_or_else(|| anyhow::anyhow!("Plugin {} returned no manifest", path.display()))?;
+        plugin.manifest = serde_json::from_slice(&manifest)
+            .with_context(|| format!("Invalid manifest in plugin {}", path.display()))?;
+
+        let name = &plugin.manifest.name;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            anyhow::bail!("Invalid plugin name '{}' in {}", name, path.display());
+        }
+        Ok(plugin)
+    }
+
+    /// Name declared in the plugin manifest
+    pub fn name(&self) -> &str {
+        &self.manifest.name
+    }
+
+    fn exports(&self, name: &str) -> bool {
+        self.exports.iter().any(|e| e == name)
+    }
+
+    /// Invoke an export with an optional JSON input, returning its output
+    fn call(&self, export: &str, input: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.max_memory)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(self.fuel)?;
+        let instance = self.instance_pre.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("Plugin does not export memory"))?;
+
+        let packed = match input {
+            Some(input) => {
+                let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+                let ptr = alloc.call(&mut store, input.len() as i32)?;
+                memory.write(&mut store, ptr as usize, input)?;
+                instance
+                    .get_typed_func::<(i32, i32), i64>(&mut store, export)?
+                    .call(&mut store, (ptr, input.len() as i32))?
+            }
+            None => instance
+                .get_typed_func::<(), i64>(&mut store, export)?
+                .call(&mut store, ())?,
+        };
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let ptr = (packed as u64 >> 32) as usize;
+        let len = (packed as u64 & 0xffff_ffff) as usize;
+        // Checked before allocating, as the plugin chooses `len`
+        if ptr.saturating_add(len) > memory.data_size(&store) {
+            anyhow::bail!("Plugin output lies outside its memory");
+        }
+        let mut output = vec![0u8; len];
+        memory.read(&store, ptr, &mut output)?;
+        Ok(Some(output))
+    }
+
+    /// Invoke an export off the async runtime and decode its JSON output
+    async fn call_json<T: for<'de> Deserialize<'de>>(
+        self: &Arc<Self>,
+        export: &'static str,
+        input: Value,
+    ) -> Result<Option<T>> {
+        let plugin = self.clone();
+        let input = serde_json::to_vec(&input)?;
+        let output =
+            tokio::task::spawn_blocking(move || plugin.call(export, Some(&input))).await??;
+        output
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(Into::into)
+    }
+}
+
+/// All loaded plugins
+#[derive(Clone, Default)]
+pub struct PluginHost {
+    plugins: Vec<Arc<Plugin>>,
+}
+
+impl PluginHost {
+    /// Load every `.wasm`/`.wat` module in the plugins directory
+    ///
+    /// A missing directory yields an empty host.
+    pub fn load(config: &PluginConfig) -> Result<Self> {
+        let dir = Path::new(&config.dir);
+        if !dir.is_dir() {
+            return Ok(PluginHost::default());
+        }
+
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config)?;
+
+        let mut paths: Vec<_> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                matches!(
+                    path.extension().and_then(|e| e.to_str()),
+                    Some("wasm" | "wat")
+                )
+            })
+            .collect();
+        paths.sort();
+
+        let mut plugins: Vec<Arc<Plugin>> = Vec::new();
+        for path in paths {
+            let plugin = Plugin::load(&engine, &path, config.fuel, config.max_memory)?;
+            if plugins.iter().any(|p| p.name() == plugin.name()) {
+                anyhow::bail!("Duplicate plugin name '{}'", plugin.name());
+            }
+            tracing::info!("Loaded plugin {} from {}", plugin.name(), path.display());
+            plugins.push(Arc::new(plugin));
+        }
+        Ok(PluginHost { plugins })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    /// Routes declared by plugins, mounted under `/plugins/<name>`
+    pub fn router(&self) -> Router<AppState> {
+        let mut router = Router::new();
+        for plugin in &self.plugins {
+            if !plugin.exports("handle") {
+                continue;
+            }
+            for route in &plugin.manifest.routes {
+                let Ok(method) = Method::from_bytes(route.method.as_bytes()) else {
+                    tracing::warn!(
+                        "Plugin {} declares invalid method {}",
+                        plugin.name(),
+                        route.method
+                    );
+                    continue;
+                };
+                let Ok(filter) = MethodFilter::try_from(method) else {
+                    continue;
+                };
+                let path = format!("/plugins/{}{}", plugin.name(), route.path);
+                let plugin = plugin.clone();
+                router = router.route(
+                    &path,
+                    on(filter, move |request: Request| handle(plugin, request)),
+                );
+            }
+        }
+        router
+    }
+
+    /// Middleware running every plugin's `on_request` and `on_response` hooks
+    pub async fn hooks(self, request: Request, next: Next) -> Response {
+        let (method, path) = (request.method().clone(), request.uri().path().to_string());
+        let info = serde_json::to_value(request_info(&request)).unwrap_or(Value::Null);
+
+        for plugin in self.plugins.iter().filter(|p| p.exports("on_request")) {
+            match plugin
+                .call_json::<PluginResponse>("on_request", info.clone())
+                .await
+            {
+                Ok(Some(response)) => return response.into_response(),
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!("Plugin {} on_request failed: {:#}", plugin.name(), e);
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+        }
+
+        let mut response = next.run(request).await;
+
+        for plugin in self.plugins.iter().filter(|p| p.exports("on_response")) {
+            let input = serde_json::json!({
+                "method": method.as_str(),
+                "path": path,
+                "status": response.status().as_u16(),
+            });
+            match plugin
+                .call_json::<PluginResponse>("on_response", input)
+                .await
+            {
+                Ok(Some(extra)) => append_headers(&mut response, &extra.headers),
+                Ok(None) => {}
+                Err(e) => tracing::error!("Plugin {} on_response failed: {:#}", plugin.name(), e),
+            }
+        }
+        response
+    }
+}
+
+fn request_info(request: &Request) -> RequestInfo<'_> {
+    RequestInfo {
+        method: request.method().as_str(),
+        path: request.uri().path(),
+        query: request.uri().query(),
+        headers: request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+            .collect(),
+    }
+}
+
+async fn handle(plugin: Arc<Plugin>, request: Request<Body>) -> Response {
+    let mut input = serde_json::to_value(request_info(&request)).unwrap_or(Value::Null);
+    let body = match to_bytes(request.into_body(), MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+    input["body"] = Value::String(String::from_utf8_lossy(&body).into_owned());
+
+    match plugin.call_json::<PluginResponse>("handle", input).await {
+        Ok(Some(response)) => response.into_response(),
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            tracing::error!("Plugin {} handler failed: {:#}", plugin.name(), e);
+            StatusCode::BAD_GATEWAY.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"{"name":"hello","routes":[{"method":"GET","path":"/hi"}]}"#;
+    const RESPONSE: &str = r#"{"status":200,"body":"hi"}"#;
+
+    fn hello_plugin() -> String {
+        let escape = |s: &str| s.replace('"', "\\\"");
+        format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 1024))
+                (data (i32.const 0) "{manifest}")
+                (data (i32.const 512) "{response}")
+                (func (export "alloc") (param $len i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $next))
+                    (global.set $next (i32.add (global.get $next) (local.get $len)))
+                    (local.get $ptr))
+                (func (export "manifest") (result i64)
+                    (i64.const {manifest_len}))
+                (func (export "handle") (param i32 i32) (result i64)
+                    (i64.const {response_packed}))
+                (func (export "huge") (param i32 i32) (result i64)
+                    (i64.const 4294967295))
+                (func (export "grow") (param i32 i32) (result i64)
+                    (if (result i64) (i32.eq (memory.grow (i32.const 1024)) (i32.const -1))
+                        (then (i64.const 0))
+                        (else (i64.const {response_packed}))))
+                (func (export "spin") (param i32 i32) (result i64)
+                    (loop $forever (br $forever))
+                    (i64.const 0)))"#,
+            manifest = escape(MANIFEST),
+            response = escape(RESPONSE),
+            manifest_len = MANIFEST.len(),
+            response_packed = (512u64 << 32) | RESPONSE.len() as u64,
+        )
+    }
+
+    fn load_hello(fuel: u64) -> Plugin {
+        let dir = std::env::temp_dir().join(format!("plugins-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("hello-{}.wat", fuel));
+        std::fs::write(&path, hello_plugin()).unwrap();
+
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).unwrap();
+        Plugin::load(&engine, &path, fuel, 1 << 20).unwrap()
+    }
+
+    #[test]
+    fn test_plugin_manifest_and_handle() {
+        let plugin = load_hello(1_000_000);
+        assert_eq!(plugin.name(), "hello");
+        assert_eq!(plugin.manifest.routes[0].path, "/hi");
+
+        let output = plugin.call("handle", Some(b"{}")).unwrap().unwrap();
+        assert_eq!(output, RESPONSE.as_bytes());
+    }
+
+    #[test]
+    fn test_plugin_fuel_limit() {
+        let plugin = load_hello(10_000);
+        assert!(plugin.call("spin", Some(b"{}")).is_err());
+    }
+
+    #[test]
+    fn test_plugin_memory_limit() {
+        let plugin = load_hello(1_000_000);
+        // Growing past the 1MB limit fails inside the plugin
+        assert!(plugin.call("grow", Some(b"{}")).unwrap().is_none());
+        // A 4GB output is refused before anything is allocated
+        assert!(plugin.call("huge", Some(b"{}")).is_err());
+    }
+}
@@ -0,0 +1,366 @@
+//! Reverse proxy (gateway mode).
+//!
+//! `PROXY_ROUTES` maps path prefixes to upstream services so one port can
+//! front several self-hosted applications, e.g. `/grafana` to
+//! `http://127.0.0.1:3001`. The prefix is stripped before forwarding, request
+//! and response bodies are streamed, hop-by-hop headers are dropped and the
+//! usual `X-Forwarded-*` headers are added, describing the request's public
+//! URL (see [`crate::urls`]). Each upstream gets a non-critical
+//! health check named `proxy:<prefix>`.
+//!
+//! Proxied routes are mounted in the public route group; upstreams are
+//! expected to do their own authentication.
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{
+        header::{self, HeaderMap, HeaderName, HeaderValue},
+        uri::{PathAndQuery, Uri},
+    },
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::parse_duration;
+use crate::error::ApiError;
+use crate::health::{Criticality, HealthRegistry};
+use crate::module::{RouteGroup, RouteModule};
+use crate::urls::PublicUrl;
+use crate::AppState;
+
+/// Headers that describe a single connection and must not be forwarded
+const HOP_BY_HOP: [HeaderName; 7] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+];
+
+/// A path prefix forwarded to an upstream service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyRoute {
+    /// Path prefix without a trailing slash, e.g. `/grafana`
+    pub prefix: String,
+    /// Upstream base URL without a trailing slash, e.g. `http://127.0.0.1:3001`
+    pub upstream: String,
+    /// Overrides `PROXY_TIMEOUT` for this upstream
+    pub timeout: Option<Duration>,
+}
+
+/// Parse a list of proxy routes
+///
+/// Routes are comma-separated entries of the form `prefix=upstream[@timeout]`,
+/// e.g. `/grafana=http://127.0.0.1:3001,/git=http://gitea:3000@2m`.
+pub fn parse_routes(input: &str) -> Result<Vec<ProxyRoute>> {
+    let routes = input
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_route)
+        .collect::<Result<Vec<_>>>()?;
+    for (index, route) in routes.iter().enumerate() {
+        if routes[..index].iter().any(|r| r.prefix == route.prefix) {
+            anyhow::bail!("Duplicate proxy prefix '{}'", route.prefix);
+        }
+    }
+    Ok(routes)
+}
+
+fn parse_route(entry: &str) -> Result<ProxyRoute> {
+    let (prefix, target) = entry.split_once('=').ok_or_else(|| {
+        anyhow::anyhow!("Invalid proxy route '{}': expected prefix=upstream", entry)
+    })?;
+    let prefix = prefix.trim().trim_end_matches('/');
+    if !prefix.starts_with('/') || prefix.contains(['*', ':', '?', '#']) {
+        anyhow::bail!(
+            "Invalid proxy prefix '{}': expected a path such as /app",
+            prefix
+        );
+    }
+
+    let (upstream, timeout) = match target.trim().rsplit_once('@') {
+        Some((upstream, timeout)) => (upstream, Some(parse_duration(timeout)?)),
+        None => (target.trim(), None),
+    };
+    let uri: Uri = upstream
+        .parse()
+        .with_context(|| format!("Invalid proxy upstream '{}'", upstream))?;
+    if uri.scheme_str() != Some("http") || uri.authority().is_none() || uri.query().is_some() {
+        anyhow::bail!(
+            "Invalid proxy upstream '{}': expected http://host[:port][/path]",
+            upstream
+        );
+    }
+
+    Ok(ProxyRoute {
+        prefix: prefix.to_string(),
+        upstream: upstream.trim_end_matches('/').to_string(),
+        timeout,
+    })
+}
+
+/// Forwards requests for the configured routes
+#[derive(Clone)]
+pub struct Proxy {
+    client: Client<HttpConnector, Body>,
+    routes: Arc<Vec<ProxyRoute>>,
+    timeout: Duration,
+}
+
+impl Proxy {
+    pub fn new(routes: Vec<ProxyRoute>, timeout: Duration) -> Self {
+        Proxy {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            routes: Arc::new(routes),
+            timeout,
+        }
+    }
+
+    /// Register a non-critical health check for every upstream
+    pub fn register_health_checks(&self, health: &HealthRegistry) {
+        for route in self.routes.iter() {
+            let proxy = self.clone();
+            let upstream = route.upstream.clone();
+            health.register(
+                format!("proxy:{}", route.prefix),
+                Criticality::NonCritical,
+                move || {
+                    let (proxy, upstream) = (proxy.clone(), upstream.clone());
+                    async move { proxy.probe(&upstream).await }
+                },
+            );
+        }
+    }
+
+    /// Check that an upstream answers without a server error
+    async fn probe(&self, upstream: &str) -> Result<()> {
+        let request = Request::get(format!("{}/", upstream)).body(Body::empty())?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .with_context(|| format!("{} is unreachable", upstream))?;
+        anyhow::ensure!(
+            !response.status().is_server_error(),
+            "{} returned {}",
+            upstream,
+            response.status()
+        );
+        Ok(())
+    }
+
+    /// Forward `request` to the upstream of `route`
+    pub(crate) async fn forward(
+        &self,
+        route: &ProxyRoute,
+        request: Request,
+    ) -> Result<Response, ApiError> {
+        let (mut parts, body) = request.into_parts();
+        let client = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let original_host = parts.headers.get(header::HOST).cloned();
+        let public = parts.extensions.get::<PublicUrl>().cloned();
+
+        let uri: Uri = upstream_uri(route, &parts.uri)
+            .parse()
+            .map_err(|e| ApiError::BadRequest(format!("Invalid request path: {}", e)))?;
+        let headers = &mut parts.headers;
+        strip_hop_by_hop(headers);
+        headers.remove(header::HOST);
+        if let Some(client) = client {
+            let forwarded_for = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+                Some(existing) => format!("{}, {}", existing, client),
+                None => client.to_string(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+                headers.insert("x-forwarded-for", value);
+            }
+        }
+        // The public URL already accounts for trusted proxies in front
+        let (host, proto, prefix) = match &public {
+            Some(url) => (
+                HeaderValue::from_str(url.host()).ok(),
+                HeaderValue::from_str(url.scheme()).ok(),
+                format!("{}{}", url.prefix(), route.prefix),
+            ),
+            None => (original_host, None, route.prefix.clone()),
+        };
+        if let Some(host) = host {
+            headers.insert("x-forwarded-host", host);
+        }
+        match proto {
+            Some(proto) => {
+                headers.insert("x-forwarded-proto", proto);
+            }
+            None if !headers.contains_key("x-forwarded-proto") => {
+                headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+            }
+            None => {}
+        }
+        if !prefix.is_empty() {
+            if let Ok(prefix) = HeaderValue::from_str(&prefix) {
+                headers.insert("x-forwarded-prefix", prefix);
+            }
+        }
+        parts.uri = uri;
+        // hyper sets Host from the URI on HTTP/1 requests
+        parts.version = axum::http::Version::HTTP_11;
+
+        let timeout = route.timeout.unwrap_or(self.timeout);
+        let request = Request::from_parts(parts, body);
+        let response = tokio::time::timeout(timeout, self.client.request(request))
+            .await
+            .map_err(|_| ApiError::GatewayTimeout(format!("{} timed out", route.upstream)))?
+            .map_err(|e| {
+                tracing::warn!("Proxy request to {} failed: {}", route.upstream, e);
+                ApiError::BadGateway(format!("{} is unavailable", route.upstream))
+            })?;
+
+        let (mut parts, body) = response.into_parts();
+        strip_hop_by_hop(&mut parts.headers);
+        if let Some(location) = parts
+            .headers
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|location| rewrite_location(route, location))
+            .and_then(|location| HeaderValue::from_str(&location).ok())
+        {
+            parts.headers.insert(header::LOCATION, location);
+        }
+        Ok(Response::from_parts(parts, Body::new(body)))
+    }
+}
+
+/// Upstream URL for a request, with the route prefix stripped
+fn upstream_uri(route: &ProxyRoute, uri: &Uri) -> String {
+    let rest = uri
+        .path()
+        .strip_prefix(route.prefix.as_str())
+        .unwrap_or(uri.path());
+    let rest = if rest.is_empty() { "/" } else { rest };
+    match uri.path_and_query().and_then(PathAndQuery::query) {
+        Some(query) => format!("{}{}?{}", route.upstream, rest, query),
+        None => format!("{}{}", route.upstream, rest),
+    }
+}
+
+/// Map an absolute redirect to the upstream back under the route prefix
+fn rewrite_location(route: &ProxyRoute, location: &str) -> Option<String> {
+    let rest = location.strip_prefix(route.upstream.as_str())?;
+    if !(rest.is_empty() || rest.starts_with(['/', '?'])) {
+        return None;
+    }
+    Some(format!("{}{}", route.prefix, rest))
+}
+
+pub(crate) fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // Headers named in Connection are hop-by-hop too
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in HOP_BY_HOP.iter().chain(&listed) {
+        headers.remove(name);
+    }
+    // WebSocket upgrades are not proxied
+    headers.remove(header::UPGRADE);
+}
+
+/// Route module mounting the configured proxy routes
+pub struct ProxyModule(pub Proxy);
+
+impl RouteModule for ProxyModule {
+    fn name(&self) -> &'static str {
+        "proxy"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        self.0.routes.iter().fold(Router::new(), |router, route| {
+            let (proxy, target) = (self.0.clone(), route.clone());
+            let handler = any(move |request: Request| async move {
+                proxy.forward(&target, request).await.into_response()
+            });
+            router
+                .route(&route.prefix, handler.clone())
+                .route(&format!("{}/*rest", route.prefix), handler)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_routes() {
+        let routes =
+            parse_routes("/grafana/=http://127.0.0.1:3001, /git=http://gitea:3000/base/@2m")
+                .unwrap();
+        assert_eq!(routes[0].prefix, "/grafana");
+        assert_eq!(routes[0].timeout, None);
+        assert_eq!(routes[1].upstream, "http://gitea:3000/base");
+        assert_eq!(routes[1].timeout, Some(Duration::from_secs(120)));
+
+        assert!(parse_routes("grafana=http://127.0.0.1:3001").is_err());
+        assert!(parse_routes("/=http://127.0.0.1:3001").is_err());
+        assert!(parse_routes("/app=https://example.com").is_err());
+        assert!(parse_routes("/app=http://a:1,/app=http://b:1").is_err());
+    }
+
+    #[test]
+    fn test_rewrites_paths_and_redirects() {
+        let route = &parse_routes("/git=http://gitea:3000/base").unwrap()[0];
+        let uri = |s: &str| s.parse::<Uri>().unwrap();
+        assert_eq!(upstream_uri(route, &uri("/git")), "http://gitea:3000/base/");
+        assert_eq!(
+            upstream_uri(route, &uri("/git/repo?tab=1")),
+            "http://gitea:3000/base/repo?tab=1"
+        );
+
+        assert_eq!(
+            rewrite_location(route, "http://gitea:3000/base/login").as_deref(),
+            Some("/git/login")
+        );
+        assert_eq!(rewrite_location(route, "http://gitea:3000/basement"), None);
+        assert_eq!(rewrite_location(route, "https://example.com/"), None);
+    }
+
+    #[test]
+    fn test_strips_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("close, x-private"),
+        );
+        headers.insert("x-private", HeaderValue::from_static("1"));
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key(header::ACCEPT));
+    }
+}
@@ -0,0 +1,215 @@
+//! PROXY protocol headers.
+//!
+//! TCP load balancers such as HAProxy or an AWS NLB hide the client
+//! address: every connection appears to come from the balancer. With
+//! `PROXY_PROTOCOL_PORT` set, the server also listens on that port and
+//! expects each connection to start with a PROXY protocol header (v1 text
+//! or v2 binary) naming the real client. That address becomes the
+//! connection's `ConnectInfo`, so rate limiting, logging, API key IP
+//! allowlists and the reverse proxy's `X-Forwarded-For` all see it.
+//!
+//! Connections without a valid header are closed, as the protocol
+//! requires. `PROXY_PROTOCOL_FROM` limits which peers may send one at all;
+//! otherwise anyone reaching the port could claim any address. `PORT` keeps
+//! serving plain connections for probes that bypass the balancer.
+
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::auth::IpRange;
+
+/// Longest v1 header, including the CRLF
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// PROXY protocol listener settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyProtocolConfig {
+    pub port: u16,
+    /// Peers allowed to send a header; empty allows any
+    pub trusted: Vec<IpRange>,
+}
+
+impl ProxyProtocolConfig {
+    pub fn is_trusted(&self, peer: IpAddr) -> bool {
+        self.trusted.is_empty() || self.trusted.iter().any(|range| range.contains(peer))
+    }
+}
+
+/// Read a PROXY protocol header
+///
+/// Returns the client's address, or None when the header carries none
+/// (v1 `UNKNOWN`, v2 `LOCAL` such as balancer health checks, or a non-TCP
+/// family), in which case the peer's own address stands.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    let mut start = [0; 5];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY" {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            anyhow::ensure!(line.len() < V1_MAX, "v1 header too long");
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line[..line.len() - 2])
+    } else if start == V2_SIGNATURE[..5] {
+        let mut rest = [0; 11];
+        stream.read_exact(&mut rest).await?;
+        anyhow::ensure!(rest[..7] == V2_SIGNATURE[5..], "invalid v2 signature");
+        let (version_command, family) = (rest[7], rest[8]);
+        let length = u16::from_be_bytes([rest[9], rest[10]]) as usize;
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).await?;
+        parse_v2(version_command, family, &payload)
+    } else {
+        anyhow::bail!("missing PROXY protocol header")
+    }
+}
+
+/// Parse `PROXY TCP4|TCP6|UNKNOWN [src dst sport dport]`
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).context("v1 header is not ASCII")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().context("invalid v1 source address")?;
+            anyhow::ensure!(
+                ip.is_ipv4() == (*family == "TCP4"),
+                "v1 source address does not match {}",
+                family
+            );
+            let port: u16 = port.parse().context("invalid v1 source port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => anyhow::bail!("invalid v1 header"),
+    }
+}
+
+/// Parse the v2 address block following the fixed 16 bytes
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> Result<Option<SocketAddr>> {
+    anyhow::ensure!(version_command >> 4 == 2, "unsupported version");
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        command => anyhow::bail!("unsupported command {}", command),
+    }
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+    match family {
+        // TCP over IPv4: source, destination, source port, destination port
+        0x11 => {
+            anyhow::ensure!(payload.len() >= 12, "truncated v2 IPv4 addresses");
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[..4])?);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        // TCP over IPv6
+        0x21 => {
+            anyhow::ensure!(payload.len() >= 36, "truncated v2 IPv6 addresses");
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[..16])?);
+            Ok(Some(SocketAddr::new(ip.into(), port(32))))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::{serve, ConnectionTuning};
+    use axum::extract::ConnectInfo;
+    use axum::routing::get;
+    use axum::Router;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::watch;
+
+    async fn header(mut input: &[u8]) -> Result<Option<SocketAddr>> {
+        let client = read_header(&mut input).await?;
+        assert_eq!(input, b"GET", "header consumed exactly");
+        Ok(client)
+    }
+
+    #[tokio::test]
+    async fn test_reads_v1_and_v2_headers() {
+        assert_eq!(
+            header(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\nGET")
+                .await
+                .unwrap(),
+            Some("203.0.113.7:51234".parse().unwrap())
+        );
+        assert_eq!(
+            header(b"PROXY TCP6 2001:db8::7 2001:db8::1 4000 443\r\nGET")
+                .await
+                .unwrap(),
+            Some("[2001:db8::7]:4000".parse().unwrap())
+        );
+        assert_eq!(header(b"PROXY UNKNOWN\r\nGET").await.unwrap(), None);
+        assert!(header(b"PROXY TCP4 2001:db8::7 10.0.0.1 1 2\r\nGET")
+            .await
+            .is_err());
+        assert!(header(b"GET / HTTP/1.1\r\n\r\nGET").await.is_err());
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 15]);
+        v2.extend_from_slice(&[198, 51, 100, 9, 10, 0, 0, 1, 0x1f, 0x90, 0, 80]);
+        v2.extend_from_slice(&[0x04, 0, 0]); // an empty TLV, skipped
+        v2.extend_from_slice(b"GET");
+        assert_eq!(
+            header(&v2).await.unwrap(),
+            Some("198.51.100.9:8080".parse().unwrap())
+        );
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        local.extend_from_slice(b"GET");
+        assert_eq!(header(&local).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_serves_requests_as_the_proxied_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = Router::new().route(
+            "/ip",
+            get(|ConnectInfo(client): ConnectInfo<SocketAddr>| async move { client.to_string() }),
+        );
+        let config = ProxyProtocolConfig {
+            port: addr.port(),
+            trusted: vec!["127.0.0.0/8".parse().unwrap()],
+        };
+        let (stop, shutdown) = watch::channel(false);
+        let server = tokio::spawn(serve(
+            listener,
+            router,
+            ConnectionTuning::default(),
+            Some(config),
+            shutdown,
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"PROXY TCP4 203.0.113.7 127.0.0.1 51234 80\r\n\
+                  GET /ip HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("203.0.113.7:51234"), "{}", response);
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /ip HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        // Closed with the request unread, which may surface as a reset
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert_eq!(response, "", "connections without a header are closed");
+
+        stop.send(true).unwrap();
+        server.await.unwrap();
+    }
+}
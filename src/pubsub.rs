@@ -0,0 +1,1048 @@
+//! WebSocket pub/sub channels.
+//!
+//! With `WS_CHANNELS` set, clients connect to `GET /api/v1/ws` and exchange
+//! JSON messages tagged by `type`: they send `subscribe`, `unsubscribe` and
+//! `publish` (with a `channel` and, to publish, `data`), and receive
+//! `message`, `join` and `leave` events for the channels they joined.
+//! Handlers publish through the [`PubSub`] extension, admins through
+//! `POST /admin/channels/:channel/messages`.
+//!
+//! `WS_CHANNELS` also authorizes channels, as `pattern=access` rules where
+//! a pattern is a name or a prefix ending in `*` and the first match wins:
+//! `subscribe` lets anyone listen, `publish` lets anyone also send, and
+//! `admin` limits both to clients sending the admin token. Admins may also
+//! publish to `subscribe` channels. Channels matching no rule are refused.
+//!
+//! Presence tracks who is subscribed where: clients may pass `?name=` when
+//! connecting, get the current members when subscribing and `join`/`leave`
+//! events afterwards; `GET /api/v1/channels/:channel/presence` lists them.
+//!
+//! Channels are per instance unless `WS_REDIS_URL` is set: messages are then
+//! also published to Redis and replayed to clients of every replica, and
+//! each replica shares its members every 15 seconds, dropping those of
+//! replicas it has not heard from for three rounds.
+
+pub mod redis;
+
+use anyhow::Result;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query,
+    },
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use self::redis::{Connection, RedisConfig};
+use crate::auth::{random_token, AdminToken};
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::health::{Criticality, HealthRegistry};
+use crate::module::{RouteGroup, RouteModule};
+use crate::{t, AppState};
+
+/// How often replicas share their members
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(15);
+/// Messages queued per client; a client further behind misses messages
+const CLIENT_QUEUE: usize = 256;
+/// Envelopes queued for Redis
+const BACKPLANE_QUEUE: usize = 1024;
+/// Longest delay between Redis reconnection attempts
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+/// Who may use the channels a rule matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelAccess {
+    /// Anyone subscribes; only admins publish
+    Subscribe,
+    /// Anyone subscribes and publishes
+    Publish,
+    /// Only admins subscribe and publish
+    Admin,
+}
+
+/// A `WS_CHANNELS` entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelRule {
+    /// Channel name, or a prefix followed by `*`
+    pub pattern: String,
+    pub access: ChannelAccess,
+}
+
+impl ChannelRule {
+    fn matches(&self, channel: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => channel.starts_with(prefix),
+            None => channel == self.pattern,
+        }
+    }
+}
+
+/// Parse `WS_CHANNELS`, e.g. `news=subscribe,chat.*=publish,ops.*=admin`
+pub fn parse_rules(input: &str) -> Result<Vec<ChannelRule>> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (pattern, access) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected pattern=access, got '{}'", entry))?;
+            let pattern = pattern.trim();
+            anyhow::ensure!(
+                valid_channel(pattern.strip_suffix('*').unwrap_or(pattern)) || pattern == "*",
+                "invalid channel pattern '{}'",
+                pattern
+            );
+            let access = match access.trim() {
+                "subscribe" => ChannelAccess::Subscribe,
+                "publish" => ChannelAccess::Publish,
+                "admin" => ChannelAccess::Admin,
+                other => anyhow::bail!(
+                    "unknown access '{}' (expected subscribe, publish or admin)",
+                    other
+                ),
+            };
+            Ok(ChannelRule {
+                pattern: pattern.to_string(),
+                access,
+            })
+        })
+        .collect()
+}
+
+/// Whether `name` is a usable channel name
+fn valid_channel(name: &str) -> bool {
+    (1..=128).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | ':' | '/'))
+}
+
+/// What a client wants to do on a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Subscribe,
+    Publish,
+}
+
+/// Pub/sub settings, from configuration
+#[derive(Debug, Clone)]
+pub struct PubSubConfig {
+    pub rules: Vec<ChannelRule>,
+    /// Redis server relaying messages between replicas
+    pub redis: Option<RedisConfig>,
+    /// Redis channel the replicas share
+    pub redis_channel: String,
+    /// Largest WebSocket message accepted, in bytes
+    pub max_message: usize,
+}
+
+impl PubSubConfig {
+    /// Whether a client may perform `action` on `channel`
+    pub fn allows(&self, channel: &str, action: Action, admin: bool) -> bool {
+        let Some(rule) = self.rules.iter().find(|rule| rule.matches(channel)) else {
+            return false;
+        };
+        match rule.access {
+            ChannelAccess::Admin => admin,
+            ChannelAccess::Publish => true,
+            ChannelAccess::Subscribe => action == Action::Subscribe || admin,
+        }
+    }
+}
+
+/// A client subscribed to a channel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    /// Unique across replicas
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ChannelMember {
+    channel: String,
+    #[serde(flatten)]
+    member: Member,
+}
+
+/// What a client sends
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe { channel: String },
+    Unsubscribe { channel: String },
+    Publish { channel: String, data: Value },
+}
+
+/// What a client receives
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    Welcome {
+        id: &'a str,
+    },
+    Subscribed {
+        channel: &'a str,
+        members: Vec<Member>,
+    },
+    Unsubscribed {
+        channel: &'a str,
+    },
+    Message {
+        channel: &'a str,
+        data: &'a Value,
+    },
+    Join {
+        channel: &'a str,
+        member: &'a Member,
+    },
+    Leave {
+        channel: &'a str,
+        member: &'a Member,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl ServerMessage<'_> {
+    fn encode(&self) -> Arc<str> {
+        serde_json::to_string(self)
+            .expect("server messages serialize")
+            .into()
+    }
+}
+
+/// What replicas exchange over Redis
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Envelope {
+    Message {
+        instance: String,
+        channel: String,
+        data: Value,
+    },
+    /// Every member of the sending replica
+    Presence {
+        instance: String,
+        members: Vec<ChannelMember>,
+    },
+}
+
+struct Subscriber {
+    sender: mpsc::Sender<Arc<str>>,
+    member: Member,
+}
+
+struct RemoteInstance {
+    seen: Instant,
+    members: Vec<ChannelMember>,
+}
+
+/// A connected client
+pub struct Client {
+    id: u64,
+    member: Member,
+    sender: mpsc::Sender<Arc<str>>,
+}
+
+/// A channel with members, as reported by `/admin/channels`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChannelInfo {
+    pub channel: String,
+    /// Clients of this instance
+    pub subscribers: usize,
+    /// Members across all replicas
+    pub members: usize,
+}
+
+struct Inner {
+    config: PubSubConfig,
+    /// Identifies this replica on the backplane
+    instance: String,
+    next_id: AtomicU64,
+    local: RwLock<HashMap<String, HashMap<u64, Subscriber>>>,
+    remote: Mutex<HashMap<String, RemoteInstance>>,
+    backplane: Option<mpsc::Sender<Vec<u8>>>,
+    connected: AtomicBool,
+    dropped: AtomicU64,
+}
+
+/// Channel registry shared by every connection
+#[derive(Clone)]
+pub struct PubSub {
+    inner: Arc<Inner>,
+}
+
+impl PubSub {
+    /// A registry relaying through Redis when configured
+    ///
+    /// Must be called within the Tokio runtime when Redis is configured,
+    /// as the backplane tasks start right away.
+    pub fn new(config: PubSubConfig) -> Self {
+        let (backplane, queue) = match config.redis {
+            Some(_) => {
+                let (sender, queue) = mpsc::channel(BACKPLANE_QUEUE);
+                (Some(sender), Some(queue))
+            }
+            None => (None, None),
+        };
+        let pubsub = PubSub {
+            inner: Arc::new(Inner {
+                config,
+                instance: random_token(9),
+                next_id: AtomicU64::new(1),
+                local: RwLock::default(),
+                remote: Mutex::default(),
+                backplane,
+                connected: AtomicBool::new(false),
+                dropped: AtomicU64::new(0),
+            }),
+        };
+        if let (Some(redis), Some(queue)) = (&pubsub.inner.config.redis, queue) {
+            tokio::spawn(publish_loop(
+                redis.clone(),
+                pubsub.inner.config.redis_channel.clone(),
+                queue,
+            ));
+            tokio::spawn(pubsub.clone().subscribe_loop(redis.clone()));
+            tokio::spawn(pubsub.clone().presence_loop());
+        }
+        pubsub
+    }
+
+    pub fn config(&self) -> &PubSubConfig {
+        &self.inner.config
+    }
+
+    /// Register a client, returning it and the queue of its outgoing messages
+    pub fn connect(&self, name: Option<String>) -> (Client, mpsc::Receiver<Arc<str>>) {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel(CLIENT_QUEUE);
+        let member = Member {
+            id: format!("{}-{}", self.inner.instance, id),
+            name,
+            joined_at: Utc::now(),
+        };
+        (Client { id, member, sender }, receiver)
+    }
+
+    /// Subscribe a client, returning the channel's members including it
+    pub fn subscribe(&self, client: &Client, channel: &str) -> Vec<Member> {
+        let joined = {
+            let mut local = self.inner.local.write().expect("pubsub lock poisoned");
+            let subscribers = local.entry(channel.to_string()).or_default();
+            subscribers
+                .insert(
+                    client.id,
+                    Subscriber {
+                        sender: client.sender.clone(),
+                        member: client.member.clone(),
+                    },
+                )
+                .is_none()
+        };
+        if joined {
+            let event = ServerMessage::Join {
+                channel,
+                member: &client.member,
+            };
+            self.deliver(channel, &event.encode(), Some(client.id));
+            self.share_presence();
+        }
+        self.members(channel)
+    }
+
+    /// Unsubscribe a client from one channel
+    pub fn unsubscribe(&self, client: &Client, channel: &str) {
+        let left = {
+            let mut local = self.inner.local.write().expect("pubsub lock poisoned");
+            let left = local
+                .get_mut(channel)
+                .and_then(|subscribers| subscribers.remove(&client.id))
+                .is_some();
+            if local.get(channel).is_some_and(HashMap::is_empty) {
+                local.remove(channel);
+            }
+            left
+        };
+        if left {
+            let event = ServerMessage::Leave {
+                channel,
+                member: &client.member,
+            };
+            self.deliver(channel, &event.encode(), None);
+            self.share_presence();
+        }
+    }
+
+    /// Unsubscribe a client from every channel
+    pub fn disconnect(&self, client: &Client) {
+        let channels: Vec<String> = {
+            let local = self.inner.local.read().expect("pubsub lock poisoned");
+            local
+                .iter()
+                .filter(|(_, subscribers)| subscribers.contains_key(&client.id))
+                .map(|(channel, _)| channel.clone())
+                .collect()
+        };
+        for channel in channels {
+            self.unsubscribe(client, &channel);
+        }
+    }
+
+    /// Send `data` to every subscriber of `channel`, on every replica
+    ///
+    /// Returns how many clients of this instance it was queued for.
+    pub fn publish(&self, channel: &str, data: Value) -> usize {
+        let delivered = self.deliver(
+            channel,
+            &ServerMessage::Message {
+                channel,
+                data: &data,
+            }
+            .encode(),
+            None,
+        );
+        self.relay(&Envelope::Message {
+            instance: self.inner.instance.clone(),
+            channel: channel.to_string(),
+            data,
+        });
+        delivered
+    }
+
+    /// Members of `channel` on every replica, oldest first
+    pub fn members(&self, channel: &str) -> Vec<Member> {
+        let mut members: Vec<Member> = {
+            let local = self.inner.local.read().expect("pubsub lock poisoned");
+            local
+                .get(channel)
+                .map(|subscribers| subscribers.values().map(|s| s.member.clone()).collect())
+                .unwrap_or_default()
+        };
+        let remote = self.inner.remote.lock().expect("pubsub lock poisoned");
+        members.extend(
+            remote
+                .values()
+                .flat_map(|instance| &instance.members)
+                .filter(|m| m.channel == channel)
+                .map(|m| m.member.clone()),
+        );
+        members.sort_by(|a, b| a.joined_at.cmp(&b.joined_at).then(a.id.cmp(&b.id)));
+        members
+    }
+
+    /// Every channel with members, by name
+    pub fn channels(&self) -> Vec<ChannelInfo> {
+        let local: Vec<(String, usize)> = {
+            let local = self.inner.local.read().expect("pubsub lock poisoned");
+            local
+                .iter()
+                .map(|(channel, subscribers)| (channel.clone(), subscribers.len()))
+                .collect()
+        };
+        let remote: Vec<String> = {
+            let remote = self.inner.remote.lock().expect("pubsub lock poisoned");
+            remote
+                .values()
+                .flat_map(|instance| &instance.members)
+                .map(|m| m.channel.clone())
+                .collect()
+        };
+        let mut channels: HashMap<String, ChannelInfo> = HashMap::new();
+        for (channel, count) in local {
+            let entry = channels.entry(channel.clone()).or_insert(ChannelInfo {
+                channel,
+                subscribers: 0,
+                members: 0,
+            });
+            entry.subscribers += count;
+            entry.members += count;
+        }
+        for channel in remote {
+            let entry = channels.entry(channel.clone()).or_insert(ChannelInfo {
+                channel,
+                subscribers: 0,
+                members: 0,
+            });
+            entry.members += 1;
+        }
+        let mut channels: Vec<ChannelInfo> = channels.into_values().collect();
+        channels.sort_by(|a, b| a.channel.cmp(&b.channel));
+        channels
+    }
+
+    /// Register a non-critical `pubsub` check failing while Redis is unreachable
+    pub fn register_health_check(&self, health: &HealthRegistry) {
+        if self.inner.backplane.is_none() {
+            return;
+        }
+        let pubsub = self.clone();
+        health.register("pubsub", Criticality::NonCritical, move || {
+            let connected = pubsub.inner.connected.load(Ordering::Relaxed);
+            async move {
+                anyhow::ensure!(connected, "not subscribed to Redis");
+                Ok(())
+            }
+        });
+    }
+
+    /// Queue an encoded message for local subscribers, except `skip`
+    fn deliver(&self, channel: &str, message: &Arc<str>, skip: Option<u64>) -> usize {
+        let local = self.inner.local.read().expect("pubsub lock poisoned");
+        let Some(subscribers) = local.get(channel) else {
+            return 0;
+        };
+        let mut delivered = 0;
+        for (id, subscriber) in subscribers {
+            if Some(*id) == skip {
+                continue;
+            }
+            match subscriber.sender.try_send(message.clone()) {
+                Ok(()) => delivered += 1,
+                Err(_) => {
+                    self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Send an envelope to the other replicas, if there are any
+    fn relay(&self, envelope: &Envelope) {
+        let Some(backplane) = &self.inner.backplane else {
+            return;
+        };
+        let payload = serde_json::to_vec(envelope).expect("envelopes serialize");
+        if backplane.try_send(payload).is_err() {
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Tell the other replicas who is subscribed here
+    fn share_presence(&self) {
+        if self.inner.backplane.is_none() {
+            return;
+        }
+        let members = {
+            let local = self.inner.local.read().expect("pubsub lock poisoned");
+            local
+                .iter()
+                .flat_map(|(channel, subscribers)| {
+                    subscribers.values().map(|s| ChannelMember {
+                        channel: channel.clone(),
+                        member: s.member.clone(),
+                    })
+                })
+                .collect()
+        };
+        self.relay(&Envelope::Presence {
+            instance: self.inner.instance.clone(),
+            members,
+        });
+    }
+
+    /// Handle an envelope from another replica
+    fn receive(&self, payload: &[u8]) {
+        let envelope: Envelope = match serde_json::from_slice(payload) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                tracing::debug!("Ignoring malformed pub/sub envelope: {}", e);
+                return;
+            }
+        };
+        match envelope {
+            Envelope::Message {
+                instance,
+                channel,
+                data,
+            } if instance != self.inner.instance => {
+                let message = ServerMessage::Message {
+                    channel: &channel,
+                    data: &data,
+                };
+                self.deliver(&channel, &message.encode(), None);
+            }
+            Envelope::Presence { instance, members } if instance != self.inner.instance => {
+                let previous = {
+                    let mut remote = self.inner.remote.lock().expect("pubsub lock poisoned");
+                    remote.insert(
+                        instance,
+                        RemoteInstance {
+                            seen: Instant::now(),
+                            members: members.clone(),
+                        },
+                    )
+                };
+                let previous = previous.map(|p| p.members).unwrap_or_default();
+                self.announce_changes(&previous, &members);
+            }
+            _ => {}
+        }
+    }
+
+    /// Send `join` and `leave` events for the difference between two member lists
+    fn announce_changes(&self, previous: &[ChannelMember], current: &[ChannelMember]) {
+        for left in previous.iter().filter(|m| !current.contains(m)) {
+            let event = ServerMessage::Leave {
+                channel: &left.channel,
+                member: &left.member,
+            };
+            self.deliver(&left.channel, &event.encode(), None);
+        }
+        for joined in current.iter().filter(|m| !previous.contains(m)) {
+            let event = ServerMessage::Join {
+                channel: &joined.channel,
+                member: &joined.member,
+            };
+            self.deliver(&joined.channel, &event.encode(), None);
+        }
+    }
+
+    /// Forget replicas not heard from within `timeout`
+    fn expire(&self, timeout: Duration) {
+        let expired: Vec<RemoteInstance> = {
+            let mut remote = self.inner.remote.lock().expect("pubsub lock poisoned");
+            let stale: Vec<String> = remote
+                .iter()
+                .filter(|(_, instance)| instance.seen.elapsed() > timeout)
+                .map(|(name, _)| name.clone())
+                .collect();
+            stale
+                .iter()
+                .filter_map(|name| remote.remove(name))
+                .collect()
+        };
+        for instance in expired {
+            self.announce_changes(&instance.members, &[]);
+        }
+    }
+
+    /// Share presence periodically and expire silent replicas
+    async fn presence_loop(self) {
+        let mut ticker = tokio::time::interval(PRESENCE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            self.share_presence();
+            self.expire(PRESENCE_INTERVAL * 3);
+        }
+    }
+
+    /// Receive envelopes from Redis, reconnecting with backoff
+    async fn subscribe_loop(self, redis: RedisConfig) {
+        let channel = self.inner.config.redis_channel.clone();
+        let mut delay = Duration::from_secs(1);
+        loop {
+            let result: Result<()> = async {
+                let mut connection = Connection::connect(&redis).await?;
+                connection
+                    .command(&[b"SUBSCRIBE", channel.as_bytes()])
+                    .await?;
+                self.inner.connected.store(true, Ordering::Relaxed);
+                tracing::info!("📡 Relaying pub/sub channels through Redis at {}", redis.address);
+                delay = Duration::from_secs(1);
+                self.share_presence();
+                loop {
+                    if let redis::Value::Array(Some(items)) = connection.next().await? {
+                        if let [redis::Value::Bulk(Some(kind)), _, redis::Value::Bulk(Some(payload))] =
+                            items.as_slice()
+                        {
+                            if kind == b"message" {
+                                self.receive(payload);
+                            }
+                        }
+                    }
+                }
+            }
+            .await;
+            self.inner.connected.store(false, Ordering::Relaxed);
+            if let Err(e) = result {
+                tracing::warn!(
+                    "Pub/sub Redis subscription failed, retrying in {:?}: {:#}",
+                    delay,
+                    e
+                );
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY);
+        }
+    }
+
+    /// Handle a message from a client, returning the reply to send it
+    fn handle(&self, client: &Client, admin: bool, text: &str) -> Option<Arc<str>> {
+        let error = |message: String| Some(ServerMessage::Error { message }.encode());
+        let message: ClientMessage = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => return error(t!("ws-message-invalid", error = e.to_string())),
+        };
+        let (channel, action) = match &message {
+            ClientMessage::Subscribe { channel } => (channel, Action::Subscribe),
+            ClientMessage::Unsubscribe { channel } => (channel, Action::Subscribe),
+            ClientMessage::Publish { channel, .. } => (channel, Action::Publish),
+        };
+        if !valid_channel(channel) {
+            return error(t!("channel-invalid"));
+        }
+        if !self.inner.config.allows(channel, action, admin) {
+            return error(t!("channel-forbidden", channel = channel.as_str()));
+        }
+        match message {
+            ClientMessage::Subscribe { channel } => {
+                let members = self.subscribe(client, &channel);
+                Some(
+                    ServerMessage::Subscribed {
+                        channel: &channel,
+                        members,
+                    }
+                    .encode(),
+                )
+            }
+            ClientMessage::Unsubscribe { channel } => {
+                self.unsubscribe(client, &channel);
+                Some(ServerMessage::Unsubscribed { channel: &channel }.encode())
+            }
+            ClientMessage::Publish { channel, data } => {
+                self.publish(&channel, data);
+                None
+            }
+        }
+    }
+
+    /// Serve one WebSocket connection until it closes
+    async fn serve(self, mut socket: WebSocket, admin: bool, name: Option<String>) {
+        let (client, mut outgoing) = self.connect(name);
+        let welcome = ServerMessage::Welcome {
+            id: &client.member.id,
+        }
+        .encode();
+        if socket
+            .send(Message::Text(welcome.to_string()))
+            .await
+            .is_ok()
+        {
+            loop {
+                let reply = tokio::select! {
+                    incoming = socket.recv() => match incoming {
+                        Some(Ok(Message::Text(text))) => self.handle(&client, admin, &text),
+                        Some(Ok(Message::Binary(_))) => Some(
+                            ServerMessage::Error { message: t!("ws-message-invalid", error = "binary") }
+                                .encode(),
+                        ),
+                        Some(Ok(Message::Ping(_) | Message::Pong(_))) => None,
+                        Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    },
+                    message = outgoing.recv() => message,
+                };
+                if let Some(reply) = reply {
+                    if socket.send(Message::Text(reply.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        self.disconnect(&client);
+    }
+}
+
+/// Publish queued envelopes to Redis on one connection, reconnecting as needed
+async fn publish_loop(redis: RedisConfig, channel: String, mut queue: mpsc::Receiver<Vec<u8>>) {
+    let mut connection: Option<Connection> = None;
+    while let Some(payload) = queue.recv().await {
+        if connection.is_none() {
+            match Connection::connect(&redis).await {
+                Ok(connected) => connection = Some(connected),
+                Err(e) => {
+                    tracing::warn!("Failed to publish to Redis: {:#}", e);
+                    continue;
+                }
+            }
+        }
+        if let Some(conn) = connection.as_mut() {
+            if let Err(e) = conn
+                .command(&[b"PUBLISH", channel.as_bytes(), &payload])
+                .await
+            {
+                tracing::warn!("Failed to publish to Redis: {:#}", e);
+                connection = None;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectQuery {
+    /// Shown to other members of the client's channels
+    name: Option<String>,
+}
+
+/// `GET /api/v1/ws` - open a pub/sub connection
+pub async fn connect_ws(
+    ws: WebSocketUpgrade,
+    Ext(pubsub): Ext<PubSub>,
+    Ext(admin_token): Ext<AdminToken>,
+    headers: HeaderMap,
+    Query(query): Query<ConnectQuery>,
+) -> Response {
+    let admin = is_admin(&headers, &admin_token);
+    let name = query
+        .name
+        .filter(|name| !name.is_empty() && name.len() <= 64);
+    let pubsub = PubSub::clone(&pubsub);
+    ws.max_message_size(pubsub.config().max_message)
+        .on_upgrade(move |socket| pubsub.serve(socket, admin, name))
+}
+
+/// Whether the request carries the admin token
+fn is_admin(headers: &HeaderMap, token: &AdminToken) -> bool {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| token.matches(provided.trim()))
+}
+
+/// `GET /api/v1/channels/:channel/presence` - members of a channel
+pub async fn presence(
+    Ext(pubsub): Ext<PubSub>,
+    Ext(admin_token): Ext<AdminToken>,
+    headers: HeaderMap,
+    Path(channel): Path<String>,
+) -> ApiResult<Json<Value>> {
+    let admin = is_admin(&headers, &admin_token);
+    if !pubsub.config().allows(&channel, Action::Subscribe, admin) {
+        return Err(ApiError::Forbidden(t!(
+            "channel-forbidden",
+            channel = channel.as_str()
+        )));
+    }
+    Ok(Json(
+        json!({ "channel": channel, "members": pubsub.members(&channel) }),
+    ))
+}
+
+/// `GET /admin/channels` - channels with members
+pub async fn list_channels(Ext(pubsub): Ext<PubSub>) -> Json<Value> {
+    Json(json!({
+        "channels": pubsub.channels(),
+        "redis": pubsub.inner.config.redis.as_ref().map(|redis| json!({
+            "address": redis.address,
+            "connected": pubsub.inner.connected.load(Ordering::Relaxed),
+        })),
+        "dropped": pubsub.inner.dropped.load(Ordering::Relaxed),
+    }))
+}
+
+/// `POST /admin/channels/:channel/messages` - publish the JSON body to a channel
+pub async fn publish(
+    Ext(pubsub): Ext<PubSub>,
+    Path(channel): Path<String>,
+    Json(data): Json<Value>,
+) -> ApiResult<impl IntoResponse> {
+    if !valid_channel(&channel) {
+        return Err(ApiError::BadRequest(t!("channel-invalid")));
+    }
+    let delivered = pubsub.publish(&channel, data);
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "delivered": delivered })),
+    ))
+}
+
+/// Route module serving pub/sub connections, mounted with `WS_CHANNELS`
+pub struct PubSubModule;
+
+impl RouteModule for PubSubModule {
+    fn name(&self) -> &'static str {
+        "pubsub"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/api/v1/ws", get(connect_ws))
+            .route("/api/v1/channels/:channel/presence", get(presence))
+    }
+}
+
+/// Route module for publishing as an admin, mounted with `WS_CHANNELS`
+pub struct PubSubAdminModule;
+
+impl RouteModule for PubSubAdminModule {
+    fn name(&self) -> &'static str {
+        "pubsub_admin"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/admin/channels", get(list_channels))
+            .route("/admin/channels/:channel/messages", post(publish))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(rules: &str) -> PubSubConfig {
+        PubSubConfig {
+            rules: parse_rules(rules).unwrap(),
+            redis: None,
+            redis_channel: "test".into(),
+            max_message: 1024,
+        }
+    }
+
+    fn received(queue: &mut mpsc::Receiver<Arc<str>>) -> Vec<Value> {
+        std::iter::from_fn(|| queue.try_recv().ok())
+            .map(|message| serde_json::from_str(&message).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parses_rules_and_authorizes_channels() {
+        let config = config("news=subscribe, chat.*=publish,ops.*=admin,*=subscribe");
+        assert_eq!(config.rules.len(), 4);
+        assert!(config.allows("news", Action::Subscribe, false));
+        assert!(!config.allows("news", Action::Publish, false));
+        assert!(config.allows("news", Action::Publish, true));
+        assert!(config.allows("chat.lobby", Action::Publish, false));
+        assert!(!config.allows("ops.deploys", Action::Subscribe, false));
+        assert!(config.allows("ops.deploys", Action::Publish, true));
+        assert!(config.allows("anything", Action::Subscribe, false));
+        assert!(!self::config("news=subscribe").allows("chat", Action::Subscribe, true));
+
+        assert!(parse_rules("news").is_err());
+        assert!(parse_rules("news=write").is_err());
+        assert!(parse_rules("bad name=publish").is_err());
+        assert!(parse_rules(" , ").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fans_out_messages_and_presence() {
+        let pubsub = PubSub::new(config("chat.*=publish,news=subscribe"));
+        let (alice, mut alice_queue) = pubsub.connect(Some("alice".into()));
+        let (bob, mut bob_queue) = pubsub.connect(None);
+
+        let reply = pubsub.handle(
+            &alice,
+            false,
+            r#"{"type":"subscribe","channel":"chat.lobby"}"#,
+        );
+        let reply: Value = serde_json::from_str(&reply.unwrap()).unwrap();
+        assert_eq!(reply["type"], "subscribed");
+        assert_eq!(reply["members"][0]["name"], "alice");
+
+        pubsub.handle(
+            &bob,
+            false,
+            r#"{"type":"subscribe","channel":"chat.lobby"}"#,
+        );
+        let events = received(&mut alice_queue);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["type"], "join");
+        assert_eq!(events[0]["member"]["id"], bob.member.id.as_str());
+        assert_eq!(pubsub.members("chat.lobby").len(), 2);
+
+        let reply = pubsub.handle(
+            &bob,
+            false,
+            r#"{"type":"publish","channel":"chat.lobby","data":{"text":"hi"}}"#,
+        );
+        assert!(reply.is_none());
+        for queue in [&mut alice_queue, &mut bob_queue] {
+            let messages = received(queue);
+            assert_eq!(messages.last().unwrap()["data"]["text"], "hi");
+        }
+
+        for (admin, request) in [
+            (false, r#"{"type":"publish","channel":"news","data":1}"#),
+            (true, r#"{"type":"subscribe","channel":"ops"}"#),
+            (false, r#"{"type":"subscribe","channel":"bad name"}"#),
+            (false, r#"{"type":"shout"}"#),
+        ] {
+            let reply = pubsub.handle(&alice, admin, request).unwrap();
+            let reply: Value = serde_json::from_str(&reply).unwrap();
+            assert_eq!(reply["type"], "error", "{}", request);
+        }
+        assert_eq!(pubsub.publish("news", json!("nobody listens")), 0);
+
+        pubsub.disconnect(&bob);
+        let events = received(&mut alice_queue);
+        assert_eq!(events.last().unwrap()["type"], "leave");
+        assert_eq!(pubsub.members("chat.lobby").len(), 1);
+        pubsub.disconnect(&alice);
+        assert!(pubsub.channels().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tracks_members_of_other_replicas() {
+        let pubsub = PubSub::new(config("*=publish"));
+        let (client, mut queue) = pubsub.connect(None);
+        pubsub.subscribe(&client, "room");
+
+        let member = |id: &str| ChannelMember {
+            channel: "room".into(),
+            member: Member {
+                id: id.into(),
+                name: None,
+                joined_at: Utc::now(),
+            },
+        };
+        let presence = |members: Vec<ChannelMember>| {
+            serde_json::to_vec(&Envelope::Presence {
+                instance: "other".into(),
+                members,
+            })
+            .unwrap()
+        };
+        pubsub.receive(&presence(vec![member("other-1"), member("other-2")]));
+        assert_eq!(pubsub.members("room").len(), 3);
+        assert_eq!(
+            pubsub.channels(),
+            [ChannelInfo {
+                channel: "room".into(),
+                subscribers: 1,
+                members: 3,
+            }]
+        );
+
+        let kept = pubsub.inner.remote.lock().unwrap()["other"].members[1].clone();
+        pubsub.receive(&presence(vec![kept]));
+        let message = serde_json::to_vec(&Envelope::Message {
+            instance: "other".into(),
+            channel: "room".into(),
+            data: json!("relayed"),
+        })
+        .unwrap();
+        pubsub.receive(&message);
+        let events: Vec<String> = received(&mut queue)
+            .iter()
+            .map(|event| event["type"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(events, ["join", "join", "leave", "message"]);
+
+        pubsub.expire(Duration::ZERO);
+        assert_eq!(pubsub.members("room").len(), 1);
+        assert_eq!(received(&mut queue)[0]["type"], "leave");
+    }
+}
@@ -0,0 +1,143 @@
+//! Per-client rate limiting.
+//!
+//! A token bucket is kept for every client IP address. Each request takes a
+//! token; buckets refill at the configured rate up to the burst size.
+//! Requests without a known peer address share a single bucket.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Buckets beyond this count trigger eviction of idle clients
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Request extension exempting a request from rate limiting
+#[derive(Debug, Clone, Copy)]
+pub struct Exempt;
+
+/// Token-bucket rate limiter keyed by client IP
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` requests per second with bursts of `burst`
+    pub fn new(rate: u32, burst: u32) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Take a token for `client` at time `now`
+    ///
+    /// Returns `Err` with the seconds until a token is available when the
+    /// client is over its limit.
+    pub fn check_at(&self, client: IpAddr, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if self.rate > 0.0 {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil() as u64)
+        } else {
+            Err(u64::MAX)
+        }
+    }
+
+    /// Middleware rejecting clients over their limit with 429
+    pub async fn middleware(
+        State(limiter): State<RateLimiter>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        if request.extensions().get::<Exempt>().is_some() {
+            return next.run(request).await;
+        }
+        let client = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+        match limiter.check_at(client, Instant::now()) {
+            Ok(()) => next.run(request).await,
+            Err(retry_after) => {
+                let mut response = (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    axum::Json(json!({ "error": "rate limit exceeded" })),
+                )
+                    .into_response();
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                response
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bucket_exhausts_and_refills() {
+        let limiter = RateLimiter::new(1, 2);
+        let client = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let start = Instant::now();
+
+        assert!(limiter.check_at(client, start).is_ok());
+        assert!(limiter.check_at(client, start).is_ok());
+        assert_eq!(limiter.check_at(client, start), Err(1));
+        assert!(limiter
+            .check_at(client, start + Duration::from_secs(1))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_clients_are_independent() {
+        let limiter = RateLimiter::new(1, 1);
+        let start = Instant::now();
+        assert!(limiter
+            .check_at(IpAddr::V4(Ipv4Addr::LOCALHOST), start)
+            .is_ok());
+        assert!(limiter
+            .check_at(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), start)
+            .is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+//! Global read-only mode.
+//!
+//! While read-only, every request with a method other than `GET`, `HEAD`,
+//! `OPTIONS` or `TRACE` is rejected with 503 before reaching its handler,
+//! and reads keep working. This covers maintenance windows and restores.
+//! It is not enough to serve from a read-only standby database: reads
+//! still write bookkeeping such as session activity and shortlink hits.
+//!
+//! `READ_ONLY=true` starts the server in read-only mode, with an optional
+//! `READ_ONLY_REASON` shown to admins. It is switched at runtime with
+//! `PUT /admin/read-only`, which is itself exempt so the mode can always be
+//! turned off again. The switch is per instance and not persisted.
+
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::{Arc, RwLock};
+
+use crate::extensions::Ext;
+use crate::module::{RouteGroup, RouteModule};
+use crate::{t, AppState};
+
+const ADMIN_PATH: &str = "/admin/read-only";
+
+/// Whether writes are currently rejected, and why
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReadOnlyStatus {
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(
+        with = "crate::time::rfc3339_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// Shared read-only switch and the middleware enforcing it
+#[derive(Debug, Clone, Default)]
+pub struct ReadOnly {
+    status: Arc<RwLock<ReadOnlyStatus>>,
+}
+
+impl ReadOnly {
+    /// A switch starting out enabled or not
+    pub fn new(enabled: bool, reason: Option<String>) -> Self {
+        let read_only = ReadOnly::default();
+        read_only.set(enabled, reason);
+        read_only
+    }
+
+    /// The current state
+    pub fn status(&self) -> ReadOnlyStatus {
+        self.status.read().expect("read-only lock poisoned").clone()
+    }
+
+    /// Whether mutating requests are rejected
+    pub fn enabled(&self) -> bool {
+        self.status.read().expect("read-only lock poisoned").enabled
+    }
+
+    /// Turn read-only mode on or off
+    pub fn set(&self, enabled: bool, reason: Option<String>) {
+        let mut status = self.status.write().expect("read-only lock poisoned");
+        if enabled {
+            if !status.enabled {
+                status.since = Some(Utc::now());
+            }
+            status.enabled = true;
+            status.reason = reason;
+        } else {
+            *status = ReadOnlyStatus::default();
+        }
+    }
+
+    /// Middleware rejecting mutating requests while read-only
+    pub async fn middleware(
+        State(read_only): State<ReadOnly>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let safe = matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+        ) || matches!(request.method().as_str(), "PROPFIND" | "REPORT");
+        if safe || !read_only.enabled() || request.uri().path() == ADMIN_PATH {
+            return next.run(request).await;
+        }
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": t!("read-only") })),
+        )
+            .into_response()
+    }
+}
+
+/// Body of `PUT /admin/read-only`
+#[derive(Debug, Deserialize)]
+pub struct SetReadOnly {
+    pub enabled: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// `GET /admin/read-only` - whether writes are rejected
+async fn get_status(Ext(read_only): Ext<ReadOnly>) -> Json<ReadOnlyStatus> {
+    Json(read_only.status())
+}
+
+/// `PUT /admin/read-only` - turn read-only mode on or off
+async fn set_status(
+    Ext(read_only): Ext<ReadOnly>,
+    Json(body): Json<SetReadOnly>,
+) -> Json<ReadOnlyStatus> {
+    read_only.set(body.enabled, body.reason);
+    let status = read_only.status();
+    if status.enabled {
+        tracing::warn!(
+            "🔒 Read-only mode enabled{}",
+            status
+                .reason
+                .as_deref()
+                .map_or(String::new(), |reason| format!(": {}", reason))
+        );
+    } else {
+        tracing::info!("🔓 Read-only mode disabled");
+    }
+    Json(status)
+}
+
+/// Route module switching read-only mode
+pub struct ReadOnlyModule;
+
+impl RouteModule for ReadOnlyModule {
+    fn name(&self) -> &'static str {
+        "read_only"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route(ADMIN_PATH, get(get_status).put(set_status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::post};
+    use tower::Service;
+
+    #[tokio::test]
+    async fn test_rejects_writes_while_read_only() {
+        let read_only = ReadOnly::new(true, Some("restoring".into()));
+        let mut app = Router::new()
+            .route(
+                "/items",
+                get(|| async { "items" }).post(|| async { "created" }),
+            )
+            .route(ADMIN_PATH, post(|| async { "switched" }))
+            .layer(middleware::from_fn_with_state(
+                read_only.clone(),
+                ReadOnly::middleware,
+            ));
+        let mut status = |method: Method, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.call(request);
+            async move { response.await.unwrap().status() }
+        };
+
+        assert_eq!(status(Method::GET, "/items").await, StatusCode::OK);
+        assert_eq!(
+            status(Method::POST, "/items").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status(Method::POST, ADMIN_PATH).await, StatusCode::OK);
+        assert_eq!(read_only.status().reason.as_deref(), Some("restoring"));
+
+        read_only.set(false, None);
+        assert_eq!(status(Method::POST, "/items").await, StatusCode::OK);
+        assert_eq!(read_only.status(), ReadOnlyStatus::default());
+    }
+}
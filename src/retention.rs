@@ -0,0 +1,262 @@
+//! Data retention and pruning policies.
+//!
+//! Policies are declared per table in configuration and enforced by a
+//! background task. Each policy may cap the age of rows (based on a
+//! timestamp column) and/or the total number of rows kept. In dry-run mode
+//! the task only reports what would be purged. Every run records an audit
+//! entry in the `retention_audit` table.
+
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::time::Duration;
+
+use crate::config::parse_duration;
+use crate::db::validate_identifier;
+use crate::leader::Leadership;
+
+/// A retention policy for a single table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Table the policy applies to
+    pub table: String,
+    /// Timestamp column used for age and ordering
+    pub column: String,
+    /// Rows older than this are purged
+    pub max_age: Option<Duration>,
+    /// Only the newest `max_rows` rows are kept
+    pub max_rows: Option<i64>,
+}
+
+/// Outcome of applying a single policy
+#[derive(Debug, Clone, Serialize)]
+pub struct RetentionReport {
+    pub table: String,
+    pub expired_rows: i64,
+    pub overflow_rows: i64,
+    pub dry_run: bool,
+}
+
+/// Parse a list of retention policies
+///
+/// Policies are comma-separated entries of the form
+/// `table.column:max_age:max_rows`, where either limit may be `-` to disable
+/// it, e.g. `audit_log.created_at:90d:-,events.created_at:30d:100000`.
+pub fn parse_policies(input: &str) -> Result<Vec<RetentionPolicy>> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_policy)
+        .collect()
+}
+
+fn parse_policy(entry: &str) -> Result<RetentionPolicy> {
+    let parts: Vec<&str> = entry.split(':').map(str::trim).collect();
+    let [target, max_age, max_rows] = parts.as_slice() else {
+        anyhow::bail!(
+            "Invalid retention policy '{}': expected table.column:max_age:max_rows",
+            entry
+        );
+    };
+
+    let (table, column) = target.split_once('.').ok_or_else(|| {
+        anyhow::anyhow!(
+            "Invalid retention target '{}': expected table.column",
+            target
+        )
+    })?;
+    validate_identifier(table)?;
+    validate_identifier(column)?;
+
+    let max_age = match *max_age {
+        "-" | "" => None,
+        value => Some(parse_duration(value)?),
+    };
+    let max_rows = match *max_rows {
+        "-" | "" => None,
+        value => Some(
+            value
+                .parse::<i64>()
+                .ok()
+                .filter(|rows| *rows >= 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid retention row limit '{}'", value))?,
+        ),
+    };
+
+    if max_age.is_none() && max_rows.is_none() {
+        anyhow::bail!(
+            "Retention policy '{}' sets neither max_age nor max_rows",
+            entry
+        );
+    }
+
+    Ok(RetentionPolicy {
+        table: table.to_string(),
+        column: column.to_string(),
+        max_age,
+        max_rows,
+    })
+}
+
+/// Create the audit table if it does not exist yet
+pub async fn ensure_schema(pool: &PgPool) -> Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS retention_audit (
+            id BIGSERIAL PRIMARY KEY,
+            table_name TEXT NOT NULL,
+            expired_rows BIGINT NOT NULL,
+            overflow_rows BIGINT NOT NULL,
+            dry_run BOOLEAN NOT NULL,
+            ran_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )",
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| anyhow::anyhow!("Failed to create retention_audit table: {}", e))?;
+    Ok(())
+}
+
+/// Apply a single policy, returning how many rows were (or would be) purged
+pub async fn apply_policy(
+    pool: &PgPool,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<RetentionReport> {
+    let table = format!("\"{}\"", policy.table);
+    let column = format!("\"{}\"", policy.column);
+    let mut tx = pool.begin().await?;
+
+    let expired_rows = match policy.max_age {
+        Some(max_age) => {
+            let sql = if dry_run {
+                format!(
+                    "SELECT COUNT(*) FROM {table} WHERE {column} < now() - make_interval(secs => $1)"
+                )
+            } else {
+                format!(
+                    "WITH purged AS (DELETE FROM {table} WHERE {column} < now() - make_interval(secs => $1) RETURNING 1) SELECT COUNT(*) FROM purged"
+                )
+            };
+            sqlx::query_scalar::<_, i64>(&sql)
+                .bind(max_age.as_secs_f64())
+                .fetch_one(&mut *tx)
+                .await?
+        }
+        None => 0,
+    };
+
+    let overflow_rows = match policy.max_rows {
+        Some(max_rows) => {
+            let sql = if dry_run {
+                format!("SELECT GREATEST(COUNT(*) - $1, 0) FROM {table}")
+            } else {
+                format!(
+                    "WITH purged AS (DELETE FROM {table} WHERE ctid IN (SELECT ctid FROM {table} ORDER BY {column} DESC OFFSET $1) RETURNING 1) SELECT COUNT(*) FROM purged"
+                )
+            };
+            sqlx::query_scalar::<_, i64>(&sql)
+                .bind(max_rows)
+                .fetch_one(&mut *tx)
+                .await?
+        }
+        None => 0,
+    };
+
+    sqlx::query(
+        "INSERT INTO retention_audit (table_name, expired_rows, overflow_rows, dry_run) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(&policy.table)
+    .bind(expired_rows)
+    .bind(overflow_rows)
+    .bind(dry_run)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(RetentionReport {
+        table: policy.table.clone(),
+        expired_rows,
+        overflow_rows,
+        dry_run,
+    })
+}
+
+/// Apply every policy once, logging failures without aborting the run
+pub async fn run_once(
+    pool: &PgPool,
+    policies: &[RetentionPolicy],
+    dry_run: bool,
+) -> Vec<RetentionReport> {
+    let mut reports = Vec::with_capacity(policies.len());
+    for policy in policies {
+        match apply_policy(pool, policy, dry_run).await {
+            Ok(report) => {
+                tracing::info!(
+                    table = %report.table,
+                    expired_rows = report.expired_rows,
+                    overflow_rows = report.overflow_rows,
+                    dry_run = report.dry_run,
+                    "Retention policy applied"
+                );
+                reports.push(report);
+            }
+            Err(e) => {
+                tracing::error!("Retention policy for {} failed: {}", policy.table, e);
+            }
+        }
+    }
+    reports
+}
+
+/// Spawn the background task enforcing retention policies on an interval
+///
+/// Only the leader applies policies, so replicas don't race each other.
+pub fn spawn(
+    pool: PgPool,
+    policies: Vec<RetentionPolicy>,
+    interval: Duration,
+    dry_run: bool,
+    leadership: Leadership,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = ensure_schema(&pool).await {
+            tracing::error!("Retention disabled: {}", e);
+            return;
+        }
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if leadership.is_leader() {
+                run_once(&pool, &policies, dry_run).await;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policies() {
+        let policies =
+            parse_policies("audit_log.created_at:90d:-, events.created_at:30d:100000").unwrap();
+        assert_eq!(policies.len(), 2);
+        assert_eq!(policies[0].table, "audit_log");
+        assert_eq!(policies[0].column, "created_at");
+        assert_eq!(policies[0].max_age, Some(Duration::from_secs(90 * 86400)));
+        assert_eq!(policies[0].max_rows, None);
+        assert_eq!(policies[1].max_rows, Some(100000));
+    }
+
+    #[test]
+    fn test_parse_policies_rejects_invalid_entries() {
+        assert!(parse_policies("audit_log:90d:-").is_err());
+        assert!(parse_policies("audit_log.created_at:-:-").is_err());
+        assert!(parse_policies("audit;drop.created_at:1d:-").is_err());
+        assert!(parse_policies("events.created_at:1d:-5").is_err());
+        assert!(parse_policies("").unwrap().is_empty());
+    }
+}
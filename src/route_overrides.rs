@@ -0,0 +1,241 @@
+//! Per-route limit overrides.
+//!
+//! `ROUTE_OVERRIDES` tunes individual endpoints without code changes, e.g.
+//! a long-running export that needs more time and a larger body than the
+//! rest of the API:
+//!
+//! ```text
+//! ROUTE_OVERRIDES=/api/v1/export;timeout=120s;body_limit=1GB;rate_limit=off
+//! ```
+//!
+//! Entries are comma-separated; each is a path prefix followed by
+//! `;`-separated settings. A prefix matches itself and everything below it
+//! (`/api/v1/export` covers `/api/v1/export/123` but not
+//! `/api/v1/exports`), and the longest matching prefix wins.
+//!
+//! - `timeout` answers 503 when the handler takes longer
+//! - `body_limit` replaces the 2MB limit on buffered request bodies, or
+//!   lifts it with `off`
+//! - `rate_limit=off` exempts the route from the `rate_limit` middleware
+
+use anyhow::Result;
+use axum::{
+    extract::{DefaultBodyLimit, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::{Layer, Service};
+
+use crate::config::{parse_duration, parse_size};
+use crate::rate_limit;
+
+/// Settings for one path prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteOverride {
+    pub prefix: String,
+    pub timeout: Option<Duration>,
+    /// Some(None) lifts the body limit
+    pub body_limit: Option<Option<usize>>,
+    pub rate_limit: bool,
+}
+
+/// Parse `ROUTE_OVERRIDES`
+pub fn parse(input: &str) -> Result<Vec<RouteOverride>> {
+    let overrides = input
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_entry)
+        .collect::<Result<Vec<_>>>()?;
+    for (index, entry) in overrides.iter().enumerate() {
+        if overrides[..index].iter().any(|o| o.prefix == entry.prefix) {
+            anyhow::bail!("Duplicate route override for '{}'", entry.prefix);
+        }
+    }
+    Ok(overrides)
+}
+
+fn parse_entry(entry: &str) -> Result<RouteOverride> {
+    let mut parts = entry.split(';').map(str::trim);
+    let prefix = parts.next().unwrap_or_default().trim_end_matches('/');
+    let prefix = if prefix.is_empty() { "/" } else { prefix };
+    if !prefix.starts_with('/') || prefix.contains(['*', '?', '#']) {
+        anyhow::bail!(
+            "Invalid route override '{}': expected a path such as /api/v1/export",
+            prefix
+        );
+    }
+    let mut route = RouteOverride {
+        prefix: prefix.to_string(),
+        rate_limit: true,
+        ..RouteOverride::default()
+    };
+    for setting in parts.filter(|p| !p.is_empty()) {
+        let (key, value) = setting
+            .split_once('=')
+            .map(|(k, v)| (k.trim(), v.trim()))
+            .ok_or_else(|| anyhow::anyhow!("Invalid setting '{}': expected key=value", setting))?;
+        match key {
+            "timeout" => route.timeout = Some(parse_duration(value)?),
+            "body_limit" => {
+                route.body_limit = Some(match value {
+                    "off" => None,
+                    size => Some(usize::try_from(parse_size(size)?)?),
+                })
+            }
+            "rate_limit" => {
+                route.rate_limit = match value {
+                    "on" => true,
+                    "off" => false,
+                    other => anyhow::bail!("Invalid rate_limit '{}': expected on or off", other),
+                }
+            }
+            other => anyhow::bail!(
+                "Unknown setting '{}' (expected timeout, body_limit or rate_limit)",
+                other
+            ),
+        }
+    }
+    Ok(route)
+}
+
+/// Whether `prefix` matches `path` itself or a path below it
+pub(crate) fn covers(prefix: &str, path: &str) -> bool {
+    prefix == "/"
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// The configured overrides, matched against each request's path
+#[derive(Debug, Clone)]
+pub struct RouteOverrides {
+    routes: Arc<[RouteOverride]>,
+}
+
+impl RouteOverrides {
+    pub fn new(mut routes: Vec<RouteOverride>) -> Self {
+        // Longest prefix first, so the first match is the most specific
+        routes.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+        RouteOverrides {
+            routes: routes.into(),
+        }
+    }
+
+    /// The override for `path`, if any
+    pub fn find(&self, path: &str) -> Option<&RouteOverride> {
+        self.routes.iter().find(|route| covers(&route.prefix, path))
+    }
+
+    /// Middleware applying the override for the request's path
+    pub async fn middleware(
+        State(overrides): State<RouteOverrides>,
+        mut request: Request,
+        next: Next,
+    ) -> Response {
+        let Some(route) = overrides.find(request.uri().path()) else {
+            return next.run(request).await;
+        };
+        if !route.rate_limit {
+            request.extensions_mut().insert(rate_limit::Exempt);
+        }
+        let response = async {
+            match route.body_limit {
+                Some(limit) => {
+                    let limit = match limit {
+                        Some(limit) => DefaultBodyLimit::max(limit),
+                        None => DefaultBodyLimit::disable(),
+                    };
+                    match limit.layer(next).call(request).await {
+                        Ok(response) => response,
+                        Err(infallible) => match infallible {},
+                    }
+                }
+                None => next.run(request).await,
+            }
+        };
+        match route.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, response).await {
+                Ok(response) => response,
+                Err(_) => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(json!({ "error": "request timed out" })),
+                )
+                    .into_response(),
+            },
+            None => response.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, middleware, routing::post, Router};
+
+    #[test]
+    fn test_parse_and_match_longest_prefix() {
+        let overrides =
+            parse("/api/v1/export;timeout=120s;body_limit=1GB;rate_limit=off, /api;body_limit=off")
+                .unwrap();
+        assert_eq!(
+            overrides[0],
+            RouteOverride {
+                prefix: "/api/v1/export".into(),
+                timeout: Some(Duration::from_secs(120)),
+                body_limit: Some(Some(1 << 30)),
+                rate_limit: false,
+            }
+        );
+        let overrides = RouteOverrides::new(overrides);
+        let prefix = |path| overrides.find(path).map(|o| o.prefix.as_str());
+        assert_eq!(prefix("/api/v1/export/42"), Some("/api/v1/export"));
+        assert_eq!(prefix("/api/v1/exports"), Some("/api"));
+        assert_eq!(prefix("/health"), None);
+
+        assert!(parse("/x;timeout").is_err());
+        assert!(parse("/x;rate_limit=maybe").is_err());
+        assert!(parse("/x;retries=3").is_err());
+        assert!(parse("x;timeout=1s").is_err());
+        assert!(parse("/x;timeout=1s,/x/;body_limit=1KB").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_applies_body_limit_and_timeout() {
+        let app = Router::new()
+            .route(
+                "/upload",
+                post(|body: Bytes| async move { body.len().to_string() }),
+            )
+            .route(
+                "/slow",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "done"
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                RouteOverrides::new(parse("/upload;body_limit=4MB,/slow;timeout=50ms").unwrap()),
+                RouteOverrides::middleware,
+            ));
+        let send = |path: &str, size: usize| {
+            let mut app = app.clone();
+            let request = Request::post(path)
+                .body(axum::body::Body::from(vec![0u8; size]))
+                .unwrap();
+            async move { app.call(request).await.unwrap().status() }
+        };
+
+        assert_eq!(send("/upload", 3 << 20).await, StatusCode::OK);
+        assert_eq!(
+            send("/upload", 5 << 20).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(send("/slow", 0).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,201 @@
+//! Database schema introspection.
+//!
+//! `GET /admin/schema` lists the tables of the server's schema with their
+//! columns, indexes, estimated row counts and sizes, read from
+//! `pg_catalog`, so operators can inspect an instance's database without
+//! shelling into Postgres. `?database=name` inspects a pool from
+//! `DATABASES` instead.
+//!
+//! Row counts are the planner's estimates, refreshed by `ANALYZE` and
+//! autovacuum, so they are cheap to read but may lag behind; tables that
+//! were never analyzed have none.
+
+use axum::{
+    extract::{Query, State},
+    response::Json,
+    routing::get,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::error::{ApiError, ApiResult};
+use crate::module::{RouteGroup, RouteModule};
+use crate::AppState;
+
+/// A table with its columns and indexes
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Table {
+    pub name: String,
+    pub row_estimate: Option<i64>,
+    /// Size of the table, its indexes and TOAST data
+    pub total_bytes: i64,
+    pub table_bytes: i64,
+    pub index_bytes: i64,
+    #[sqlx(skip)]
+    pub columns: Vec<Column>,
+    #[sqlx(skip)]
+    pub indexes: Vec<Index>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Column {
+    #[serde(skip)]
+    pub table_name: String,
+    pub name: String,
+    pub data_type: String,
+    pub nullable: bool,
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Index {
+    #[serde(skip)]
+    pub table_name: String,
+    pub name: String,
+    pub definition: String,
+    pub unique: bool,
+    pub primary: bool,
+    pub size_bytes: i64,
+}
+
+/// Every table in the current schema, by name
+pub async fn tables(pool: &PgPool) -> sqlx::Result<Vec<Table>> {
+    let (mut tables, columns, indexes) = tokio::try_join!(
+        sqlx::query_as::<_, Table>(
+            "SELECT c.relname AS name,
+                    NULLIF(c.reltuples, -1)::bigint AS row_estimate,
+                    pg_total_relation_size(c.oid) AS total_bytes,
+                    pg_relation_size(c.oid) AS table_bytes,
+                    pg_indexes_size(c.oid) AS index_bytes
+             FROM pg_class c
+             JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE n.nspname = current_schema() AND c.relkind IN ('r', 'p')
+             ORDER BY c.relname",
+        )
+        .fetch_all(pool),
+        sqlx::query_as::<_, Column>(
+            "SELECT c.relname AS table_name,
+                    a.attname AS name,
+                    format_type(a.atttypid, a.atttypmod) AS data_type,
+                    NOT a.attnotnull AS nullable,
+                    pg_get_expr(d.adbin, d.adrelid) AS default
+             FROM pg_attribute a
+             JOIN pg_class c ON c.oid = a.attrelid
+             JOIN pg_namespace n ON n.oid = c.relnamespace
+             LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+             WHERE n.nspname = current_schema() AND c.relkind IN ('r', 'p')
+                 AND a.attnum > 0 AND NOT a.attisdropped
+             ORDER BY c.relname, a.attnum",
+        )
+        .fetch_all(pool),
+        sqlx::query_as::<_, Index>(
+            "SELECT t.relname AS table_name,
+                    i.relname AS name,
+                    pg_get_indexdef(i.oid) AS definition,
+                    x.indisunique AS unique,
+                    x.indisprimary AS primary,
+                    pg_relation_size(i.oid) AS size_bytes
+             FROM pg_index x
+             JOIN pg_class i ON i.oid = x.indexrelid
+             JOIN pg_class t ON t.oid = x.indrelid
+             JOIN pg_namespace n ON n.oid = t.relnamespace
+             WHERE n.nspname = current_schema()
+             ORDER BY t.relname, i.relname",
+        )
+        .fetch_all(pool),
+    )?;
+    for column in columns {
+        if let Some(table) = tables.iter_mut().find(|t| t.name == column.table_name) {
+            table.columns.push(column);
+        }
+    }
+    for index in indexes {
+        if let Some(table) = tables.iter_mut().find(|t| t.name == index.table_name) {
+            table.indexes.push(index);
+        }
+    }
+    Ok(tables)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SchemaQuery {
+    /// A `DATABASES` entry to inspect instead of the server's database
+    database: Option<String>,
+}
+
+/// `GET /admin/schema` - tables, columns, indexes and sizes
+pub async fn get_schema(
+    State(state): State<AppState>,
+    Query(query): Query<SchemaQuery>,
+) -> ApiResult<Json<Vec<Table>>> {
+    let db = match &query.database {
+        Some(name) => state
+            .db(name)
+            .ok_or_else(|| ApiError::NotFound(format!("Unknown database '{}'", name)))?,
+        None => &state.db,
+    };
+    Ok(Json(tables(db.pool()).await?))
+}
+
+/// Route module serving `/admin/schema`
+pub struct SchemaModule;
+
+impl RouteModule for SchemaModule {
+    fn name(&self) -> &'static str {
+        "schema"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/admin/schema", get(get_schema))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lists_tables_with_columns_and_indexes() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        sqlx::query(
+            "CREATE TABLE schema_items (
+                id BIGSERIAL PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                note TEXT
+            )",
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+        let tables = tables(&db.pool).await.unwrap();
+        let table = tables.iter().find(|t| t.name == "schema_items").unwrap();
+        let columns: Vec<(&str, &str, bool)> = table
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.data_type.as_str(), c.nullable))
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                ("id", "bigint", false),
+                ("name", "text", false),
+                ("note", "text", true)
+            ]
+        );
+        assert!(table.columns[0]
+            .default
+            .as_deref()
+            .is_some_and(|d| d.starts_with("nextval")));
+        assert_eq!(table.indexes.len(), 2);
+        assert!(table.indexes.iter().any(|i| i.primary && i.unique));
+        assert!(table.total_bytes >= table.index_bytes);
+    }
+}
@@ -0,0 +1,533 @@
+//! Embedded Rhai scripting for transforms and validation rules.
+//!
+//! Admins store scripts in the settings table under `scripts.<name>`. Each
+//! script has a kind and applies to requests whose path starts with its
+//! `path_prefix`:
+//!
+//! - `request_transform`: receives `request` (`method`, `path`, `query`,
+//!   `headers`, `body`) and returns the modified map
+//! - `response_transform`: receives `response` (`status`, `headers`, `body`)
+//!   and returns the modified map
+//! - `validation`: receives `request` and returns `true` to accept, or
+//!   `false`/an error message string to reject with 422
+//!
+//! JSON bodies are exposed as maps; other bodies as strings. Headers are an
+//! array of `[name, value]` pairs, so repeated headers such as `Set-Cookie`
+//! keep every value. Responses larger than 1MB are passed on untransformed.
+//! Scripts run in a sandboxed engine with no I/O and hard limits on
+//! operations, call depth and value sizes, on blocking threads so a script
+//! using up its operations doesn't hold up other requests.
+
+use anyhow::Result;
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{Path, Request, State},
+    http::{header::CONTENT_LENGTH, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+use futures_util::StreamExt;
+use rhai::{Dynamic, Engine, Scope, AST};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::sync::{Arc, RwLock};
+
+use crate::error::{ApiError, ApiResult};
+use crate::settings;
+
+const KEY_PREFIX: &str = "scripts.";
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Scripting sandbox limits
+#[derive(Debug, Clone)]
+pub struct ScriptConfig {
+    pub max_operations: u64,
+}
+
+impl ScriptConfig {
+    /// Load scripting settings from a variable lookup, as [`Config`](crate::config::Config) does
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let max_operations = var("SCRIPT_MAX_OPERATIONS")
+            .unwrap_or_else(|| "100000".to_string())
+            .parse::<u64>()
+            .map_err(|e| anyhow::anyhow!("Invalid SCRIPT_MAX_OPERATIONS: {}", e))?;
+        Ok(ScriptConfig { max_operations })
+    }
+}
+
+/// What a script is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptKind {
+    RequestTransform,
+    ResponseTransform,
+    Validation,
+}
+
+impl ScriptKind {
+    /// Name of the variable the script's input is bound to
+    fn input_name(self) -> &'static str {
+        match self {
+            ScriptKind::RequestTransform | ScriptKind::Validation => "request",
+            ScriptKind::ResponseTransform => "response",
+        }
+    }
+}
+
+/// A script definition as stored in the settings table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Script {
+    pub kind: ScriptKind,
+    #[serde(default = "default_prefix")]
+    pub path_prefix: String,
+    pub source: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_prefix() -> String {
+    "/".to_string()
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+struct CompiledScript {
+    name: String,
+    script: Script,
+    ast: AST,
+}
+
+/// Compiled scripts plus the sandboxed engine that runs them
+#[derive(Clone)]
+pub struct ScriptHost {
+    engine: Arc<Engine>,
+    scripts: Arc<RwLock<Vec<Arc<CompiledScript>>>>,
+    pool: PgPool,
+}
+
+/// Build a Rhai engine with sandbox limits applied
+fn sandboxed_engine(config: &ScriptConfig) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(config.max_operations)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(MAX_BODY_BYTES)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000);
+    engine
+}
+
+impl ScriptHost {
+    /// Create the host and load scripts from the settings table
+    pub async fn new(pool: PgPool, config: &ScriptConfig) -> Result<Self> {
+        let host = ScriptHost {
+            engine: Arc::new(sandboxed_engine(config)),
+            scripts: Arc::default(),
+            pool,
+        };
+        host.reload().await?;
+        Ok(host)
+    }
+
+    /// Recompile all scripts from the settings table
+    ///
+    /// Scripts that fail to parse are logged and skipped.
+    pub async fn reload(&self) -> Result<()> {
+        let mut compiled = Vec::new();
+        for setting in settings::list(&self.pool, KEY_PREFIX).await? {
+            let name = setting.key[KEY_PREFIX.len()..].to_string();
+            let script: Script = match serde_json::from_value(setting.value) {
+                Ok(script) => script,
+                Err(e) => {
+                    tracing::warn!("Ignoring malformed script {}: {}", name, e);
+                    continue;
+                }
+            };
+            match self.engine.compile(&script.source) {
+                Ok(ast) => compiled.push(Arc::new(CompiledScript { name, script, ast })),
+                Err(e) => tracing::warn!("Ignoring script {} that fails to compile: {}", name, e),
+            }
+        }
+        tracing::info!("Loaded {} scripts", compiled.len());
+        *self.scripts.write().expect("scripts lock poisoned") = compiled;
+        Ok(())
+    }
+
+    fn matching(&self, kind: ScriptKind, path: &str) -> Vec<Arc<CompiledScript>> {
+        self.scripts
+            .read()
+            .expect("scripts lock poisoned")
+            .iter()
+            .filter(|s| s.script.enabled && s.script.kind == kind)
+            .filter(|s| path.starts_with(&s.script.path_prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Evaluate a script against a JSON input on a blocking thread
+    async fn eval(&self, script: &Arc<CompiledScript>, input: Value) -> Result<Dynamic> {
+        let engine = self.engine.clone();
+        let script = script.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut scope = Scope::new();
+            let input = rhai::serde::to_dynamic(input).map_err(|e| anyhow::anyhow!("{}", e))?;
+            scope.push_dynamic(script.script.kind.input_name(), input);
+            engine
+                .eval_ast_with_scope::<Dynamic>(&mut scope, &script.ast)
+                .map_err(|e| anyhow::anyhow!("Script {} failed: {}", script.name, e))
+        })
+        .await?
+    }
+
+    async fn eval_json(&self, script: &Arc<CompiledScript>, input: Value) -> Result<Value> {
+        let output = self.eval(script, input).await?;
+        rhai::serde::from_dynamic(&output).map_err(|e| anyhow::anyhow!("{}", e))
+    }
+
+    /// Middleware applying validation and transform scripts
+    pub async fn middleware(self, request: Request, next: Next) -> Response {
+        match self.apply(request, next).await {
+            Ok(response) => response,
+            Err(e) => e.into_response(),
+        }
+    }
+
+    async fn apply(&self, request: Request, next: Next) -> ApiResult<Response> {
+        let path = request.uri().path().to_string();
+        let validations = self.matching(ScriptKind::Validation, &path);
+        let request_transforms = self.matching(ScriptKind::RequestTransform, &path);
+        let response_transforms = self.matching(ScriptKind::ResponseTransform, &path);
+
+        let request = if validations.is_empty() && request_transforms.is_empty() {
+            request
+        } else {
+            let (mut parts, body) = request.into_parts();
+            let body = to_bytes(body, MAX_BODY_BYTES)
+                .await
+                .map_err(|_| ApiError::BadRequest("request body too large".into()))?;
+            let mut input = json!({
+                "method": parts.method.as_str(),
+                "path": parts.uri.path(),
+                "query": parts.uri.query(),
+                "headers": headers_to_json(&parts.headers),
+                "body": body_to_json(&body),
+            });
+
+            for script in &validations {
+                let result = self.eval(script, input.clone()).await?;
+                if result.as_bool() == Ok(true) {
+                    continue;
+                }
+                let message = result
+                    .into_string()
+                    .unwrap_or_else(|_| "request rejected by validation rule".to_string());
+                return Err(ApiError::Validation(message));
+            }
+
+            let mut transformed = false;
+            for script in &request_transforms {
+                input = self.eval_json(script, input).await?;
+                transformed = true;
+            }
+
+            let body = if transformed {
+                parts.headers = headers_from_json(&input["headers"]);
+                let body = json_to_body(&input["body"]);
+                parts.headers.remove(CONTENT_LENGTH);
+                body
+            } else {
+                body.to_vec()
+            };
+            Request::from_parts(parts, Body::from(body))
+        };
+
+        let response = next.run(request).await;
+        if response_transforms.is_empty() {
+            return Ok(response);
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let body = match buffer(&parts.headers, body).await? {
+            Buffered::Whole(body) => body,
+            Buffered::TooLarge(body) => return Ok(Response::from_parts(parts, body)),
+        };
+        let mut output = json!({
+            "status": parts.status.as_u16(),
+            "headers": headers_to_json(&parts.headers),
+            "body": body_to_json(&body),
+        });
+        for script in &response_transforms {
+            output = self.eval_json(script, output).await?;
+        }
+
+        parts.status = output["status"]
+            .as_u64()
+            .and_then(|s| StatusCode::from_u16(s as u16).ok())
+            .unwrap_or(parts.status);
+        parts.headers = headers_from_json(&output["headers"]);
+        parts.headers.remove(CONTENT_LENGTH);
+        Ok(Response::from_parts(
+            parts,
+            Body::from(json_to_body(&output["body"])),
+        ))
+    }
+
+    /// Admin routes for managing scripts
+    pub fn admin_router(&self) -> Router {
+        Router::new()
+            .route("/admin/scripts", get(list_scripts))
+            .route(
+                "/admin/scripts/:name",
+                get(get_script).put(put_script).delete(delete_script),
+            )
+            .route("/admin/scripts/:name/test", post(test_script))
+            .with_state(self.clone())
+    }
+}
+
+/// A response body read for transforms
+enum Buffered {
+    Whole(Bytes),
+    /// Over `MAX_BODY_BYTES`, with what was read put back
+    TooLarge(Body),
+}
+
+/// Read a response body, unless it is larger than `MAX_BODY_BYTES`
+async fn buffer(headers: &HeaderMap, body: Body) -> Result<Buffered> {
+    let declared = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > MAX_BODY_BYTES) {
+        return Ok(Buffered::TooLarge(body));
+    }
+    let mut stream = body.into_data_stream();
+    let mut chunks = Vec::new();
+    let mut len = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| anyhow::anyhow!("Failed to buffer response: {}", e))?;
+        len += chunk.len();
+        chunks.push(chunk);
+        if len > MAX_BODY_BYTES {
+            let read = futures_util::stream::iter(chunks.into_iter().map(Ok));
+            return Ok(Buffered::TooLarge(Body::from_stream(read.chain(stream))));
+        }
+    }
+    Ok(Buffered::Whole(chunks.concat().into()))
+}
+
+/// Headers as `[name, value]` pairs, in order
+fn headers_to_json(headers: &HeaderMap) -> Value {
+    let pairs: Vec<[&str; 2]> = headers
+        .iter()
+        .filter_map(|(name, value)| Some([name.as_str(), value.to_str().ok()?]))
+        .collect();
+    json!(pairs)
+}
+
+/// Headers from `[name, value]` pairs, or an object of single values
+fn headers_from_json(value: &Value) -> HeaderMap {
+    let pairs: Vec<(&str, &Value)> = match value {
+        Value::Array(pairs) => pairs
+            .iter()
+            .filter_map(|pair| match pair.as_array()?.as_slice() {
+                [name, value] => Some((name.as_str()?, value)),
+                _ => None,
+            })
+            .collect(),
+        Value::Object(map) => map
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+            .collect(),
+        _ => Vec::new(),
+    };
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        let Some(value) = value.as_str() else {
+            continue;
+        };
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            headers.append(name, value);
+        }
+    }
+    headers
+}
+
+fn body_to_json(body: &[u8]) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    serde_json::from_slice(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()))
+}
+
+fn json_to_body(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Null => Vec::new(),
+        Value::String(s) => s.clone().into_bytes(),
+        other => serde_json::to_vec(other).unwrap_or_default(),
+    }
+}
+
+async fn list_scripts(State(host): State<ScriptHost>) -> ApiResult<Json<Vec<settings::Setting>>> {
+    Ok(Json(settings::list(&host.pool, KEY_PREFIX).await?))
+}
+
+async fn get_script(
+    State(host): State<ScriptHost>,
+    Path(name): Path<String>,
+) -> ApiResult<Json<Value>> {
+    settings::get(&host.pool, &format!("{KEY_PREFIX}{name}"))
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("script '{}' not found", name)))
+}
+
+async fn put_script(
+    State(host): State<ScriptHost>,
+    Path(name): Path<String>,
+    Json(script): Json<Script>,
+) -> ApiResult<Json<settings::Setting>> {
+    host.engine
+        .compile(&script.source)
+        .map_err(|e| ApiError::Validation(format!("script does not compile: {}", e)))?;
+    let value = serde_json::to_value(&script).map_err(anyhow::Error::from)?;
+    let setting = settings::set(&host.pool, &format!("{KEY_PREFIX}{name}"), &value).await?;
+    host.reload().await?;
+    Ok(Json(setting))
+}
+
+async fn delete_script(
+    State(host): State<ScriptHost>,
+    Path(name): Path<String>,
+) -> ApiResult<StatusCode> {
+    if !settings::delete(&host.pool, &format!("{KEY_PREFIX}{name}")).await? {
+        return Err(ApiError::NotFound(format!("script '{}' not found", name)));
+    }
+    host.reload().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct TestInput {
+    #[serde(default)]
+    input: Value,
+}
+
+/// Run a stored script against a sample input without side effects
+async fn test_script(
+    State(host): State<ScriptHost>,
+    Path(name): Path<String>,
+    Json(body): Json<TestInput>,
+) -> ApiResult<Json<Value>> {
+    let script = host
+        .scripts
+        .read()
+        .expect("scripts lock poisoned")
+        .iter()
+        .find(|s| s.name == name)
+        .cloned()
+        .ok_or_else(|| ApiError::NotFound(format!("script '{}' not found", name)))?;
+    let result = host
+        .eval_json(&script, body.input)
+        .await
+        .map_err(|e| ApiError::Validation(e.to_string()))?;
+    Ok(Json(json!({ "output": result })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compiled(engine: &Engine, kind: ScriptKind, source: &str) -> Arc<CompiledScript> {
+        Arc::new(CompiledScript {
+            name: "test".into(),
+            script: Script {
+                kind,
+                path_prefix: "/".into(),
+                source: source.into(),
+                enabled: true,
+            },
+            ast: engine.compile(source).unwrap(),
+        })
+    }
+
+    fn host(max_operations: u64) -> ScriptHost {
+        ScriptHost {
+            engine: Arc::new(sandboxed_engine(&ScriptConfig { max_operations })),
+            scripts: Arc::default(),
+            pool: PgPool::connect_lazy("postgres://localhost/unused").unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_transform() {
+        let host = host(10_000);
+        let script = compiled(
+            &host.engine,
+            ScriptKind::RequestTransform,
+            r#"request.body.name = request.body.name.to_upper(); request"#,
+        );
+        let output = host
+            .eval_json(&script, json!({"body": {"name": "ada"}}))
+            .await
+            .unwrap();
+        assert_eq!(output["body"]["name"], "ADA");
+    }
+
+    #[test]
+    fn test_headers_keep_repeated_values() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2"));
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+
+        let json = headers_to_json(&headers);
+        assert_eq!(
+            json,
+            json!([
+                ["set-cookie", "a=1"],
+                ["set-cookie", "b=2"],
+                ["content-type", "text/plain"]
+            ])
+        );
+        assert_eq!(headers_from_json(&json), headers);
+        assert_eq!(
+            headers_from_json(&json!({"x-id": "1"}))["x-id"],
+            HeaderValue::from_static("1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_large_responses_pass_through() {
+        let large = vec![b'x'; MAX_BODY_BYTES + 1];
+        let chunks = large
+            .chunks(64 * 1024)
+            .map(|chunk| Ok::<_, std::io::Error>(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        let body = Body::from_stream(futures_util::stream::iter(chunks));
+        let Buffered::TooLarge(body) = buffer(&HeaderMap::new(), body).await.unwrap() else {
+            panic!("expected the body to be passed through");
+        };
+        assert_eq!(to_bytes(body, usize::MAX).await.unwrap(), large);
+
+        let Buffered::Whole(body) = buffer(&HeaderMap::new(), Body::from("ok")).await.unwrap()
+        else {
+            panic!("expected the body to be buffered");
+        };
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_operation_limit() {
+        let host = host(1_000);
+        let script = compiled(&host.engine, ScriptKind::Validation, "loop {}");
+        assert!(host.eval(&script, json!({})).await.is_err());
+    }
+}
@@ -0,0 +1,1299 @@
+//! Server assembly.
+//!
+//! [`ServerBuilder`] wires configuration, the database, background tasks and
+//! the router together. Embedders can add their own routers, tower layers,
+//! listeners and lifecycle hooks before building.
+
+use anyhow::{Context, Result};
+use axum::{extract::Request, middleware, response::IntoResponse, routing::Route, Router};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{net::TcpListener, signal, sync::watch, task::JoinSet};
+use tower::{Layer, Service};
+use tracing::{info, warn, Instrument};
+
+use crate::anomaly::{AnomalyDetector, AnomalyModule};
+use crate::auth::{self, AdminToken, ApiAuth, ApiKeys};
+use crate::billing::{self, Billing, BillingModule, BillingWebhookModule};
+use crate::bookmarks;
+use crate::canary::{Canary, CanaryModule};
+use crate::capture::Recorder;
+use crate::challenge::ChallengeGuard;
+use crate::concurrency::ConcurrencyLimiter;
+use crate::config::{Config, Instance};
+use crate::consul::Consul;
+use crate::crypto::password;
+use crate::dav::files::{DavFilesModule, Locks};
+use crate::dav::{DavAuth, DavModule};
+use crate::db::Database;
+use crate::deprecation::{Deprecations, DeprecationsModule};
+use crate::doctor::{SelfTest, SelfTestPolicy};
+use crate::encryption::KeyRing;
+use crate::events::{CalendarFeed, CalendarFeedModule};
+use crate::experiments::{self, Experiments};
+use crate::extensions::Extensions;
+use crate::faults::{FaultInjector, FaultsModule};
+use crate::fetch::Fetcher;
+use crate::grpc;
+use crate::health::{self, Criticality, HealthHistoryModule, ServingState};
+use crate::i18n::{self, Catalog};
+use crate::jobs::JobStats;
+use crate::leader::{self, ElectionBackend, Leadership};
+use crate::lifecycle::{Hooks, Phase};
+use crate::listener::{self, ConnectionTuning};
+use crate::live::LiveStats;
+use crate::mail::{self, Inbox, MailModule, MailWebhookModule};
+use crate::markdown::Renderer;
+use crate::module::{self, MigrationKind, RouteGroup, RouteModule};
+use crate::oidc::Provider;
+use crate::pastes::PasswordAttempts;
+use crate::pipeline::Pipeline;
+use crate::proxy::{Proxy, ProxyModule};
+use crate::proxy_protocol::ProxyProtocolConfig;
+use crate::pubsub::{PubSub, PubSubAdminModule, PubSubModule};
+use crate::rate_limit::RateLimiter;
+use crate::read_only::ReadOnly;
+use crate::route_overrides::RouteOverrides;
+use crate::sessions::{
+    self, redis::RedisSessionStore, PgSessionStore, SessionBackend, SessionStore, Sessions,
+    SessionsModule,
+};
+use crate::shadow::{Shadow, ShadowModule};
+use crate::shortlinks::{ShortlinkRedirectModule, ShortlinksModule};
+use crate::signing::RequestVerifier;
+use crate::sql_console::{ConsolePool, SqlConsoleModule};
+use crate::sql_log::{self, QueriesModule, QueryTracker};
+use crate::storage::{Storage, StorageModule};
+use crate::unfurl::Unfurler;
+use crate::urls::UrlBuilder;
+use crate::usage::{self, UsageModule, UsageRecorder};
+use crate::watchdog::{Usage, Watchdog, WatchdogModule};
+use crate::{
+    changes, crashes, dependencies, disk, doctor, encryption, failover, kubernetes, mdns, oidc,
+    redact, retention, startup, status, templates, AppState,
+};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type LayerFn = Box<dyn FnOnce(Router) -> Router + Send>;
+type LeaderHook = Box<dyn FnOnce(Leadership, AppState) + Send>;
+
+/// Builder for an embeddable server instance
+pub struct ServerBuilder {
+    config: Config,
+    state: Option<AppState>,
+    modules: Vec<Arc<dyn RouteModule>>,
+    extensions: Extensions,
+    routes: Vec<Router<AppState>>,
+    layers: Vec<LayerFn>,
+    listeners: Vec<TcpListener>,
+    hooks: Hooks,
+    leader_hooks: Vec<LeaderHook>,
+    shutdown_signal: Option<BoxFuture>,
+}
+
+impl ServerBuilder {
+    /// Start building a server from configuration
+    pub fn new(config: Config) -> Self {
+        ServerBuilder {
+            config,
+            state: None,
+            modules: module::builtin_modules(),
+            extensions: Extensions::default(),
+            routes: Vec::new(),
+            layers: Vec::new(),
+            listeners: Vec::new(),
+            hooks: Hooks::default(),
+            leader_hooks: Vec::new(),
+            shutdown_signal: None,
+        }
+    }
+
+    /// Use an existing state instead of connecting to the database on build
+    pub fn state(mut self, state: AppState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Make a shared subsystem available through the extension registry
+    ///
+    /// Handlers read it back with the [`Ext`](crate::extensions::Ext)
+    /// extractor or [`AppState::extension`].
+    pub fn extension<T: Send + Sync + 'static>(self, value: T) -> Self {
+        self.extensions.insert(value);
+        self
+    }
+
+    /// Register a route module
+    ///
+    /// The module's migrations run on build and its routes are mounted in
+    /// its route group, unless its name is listed in `DISABLED_MODULES`.
+    pub fn module(mut self, module: impl RouteModule + 'static) -> Self {
+        self.modules.push(Arc::new(module));
+        self
+    }
+
+    /// Merge an additional router into the API route group
+    ///
+    /// The router receives the shared [`AppState`] and the `MIDDLEWARE_API`
+    /// layers.
+    pub fn route(mut self, router: Router<AppState>) -> Self {
+        self.routes.push(router);
+        self
+    }
+
+    /// Wrap the whole application in a tower layer
+    ///
+    /// Layers are applied outside the configured middleware pipeline, in the
+    /// order they are added (the last one added is outermost).
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.layers
+            .push(Box::new(move |router| router.layer(layer)));
+        self
+    }
+
+    /// Serve on an already-bound listener
+    ///
+    /// May be called several times to serve on multiple addresses. Without
+    /// any listener the server binds `0.0.0.0:PORT`.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Run a hook after migrations, before the router is built
+    ///
+    /// A failing startup hook aborts [`build`](Self::build).
+    pub fn on_startup<F, Fut>(mut self, name: impl Into<String>, hook: F) -> Self
+    where
+        F: FnOnce(AppState) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.hooks.add(Phase::Startup, name, hook);
+        self
+    }
+
+    /// Run a hook once the listeners accept connections
+    pub fn on_ready<F, Fut>(mut self, name: impl Into<String>, hook: F) -> Self
+    where
+        F: FnOnce(AppState) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.hooks.add(Phase::Ready, name, hook);
+        self
+    }
+
+    /// Run a hook after the server has stopped accepting requests
+    ///
+    /// Shutdown hooks run before the database pool is closed.
+    pub fn on_shutdown<F, Fut>(mut self, name: impl Into<String>, hook: F) -> Self
+    where
+        F: FnOnce(AppState) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.hooks.add(Phase::Shutdown, name, hook);
+        self
+    }
+
+    /// Run a hook every time this instance becomes the leader
+    ///
+    /// Use it for singleton work such as schedulers; see [`crate::leader`].
+    /// Leader hooks start once the server is ready.
+    pub fn on_leader<F, Fut>(mut self, name: impl Into<String>, hook: F) -> Self
+    where
+        F: Fn(AppState) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        self.leader_hooks.push(Box::new(move |leadership, state| {
+            leader::spawn_leader_hook(name, leadership, state, hook)
+        }));
+        self
+    }
+
+    /// Replace the default Ctrl+C/SIGTERM shutdown trigger
+    pub fn shutdown_signal<F>(mut self, signal: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.shutdown_signal = Some(Box::pin(signal));
+        self
+    }
+
+    /// Connect to the database, start background tasks and build the router
+    pub async fn build(self) -> Result<Server> {
+        let config = self.config;
+        redact::configure(&config);
+        encryption::install(KeyRing::new(config.encryption_keys().to_vec()));
+        password::install(config.password_hash());
+        sql_log::configure(config.sql_log());
+        let mut modules = enabled_modules(self.modules, config.disabled_modules())?;
+        templates::init(config.templates_dir().map(PathBuf::from));
+        i18n::install(
+            Catalog::load(config.locales_dir(), config.default_locale().clone())
+                .context("Failed to load message catalogs")?,
+        );
+        let self_test = SelfTest::new(&config);
+        if config.self_test() != SelfTestPolicy::Ignore {
+            doctor::enforce(config.self_test(), "local", &self_test.local().await)?;
+        }
+        let mut state = match self.state {
+            Some(state) => state,
+            None => {
+                info!("🗄️ Initializing database connection...");
+                let db = startup::connect_database(&config, config.startup_waits())
+                    .await
+                    .context("Failed to connect to database")?;
+                info!("✅ Database connection established");
+                AppState::new(db)
+            }
+        };
+        startup::wait_for_dependencies(config.startup_waits(), config.dependencies()).await?;
+        if config.self_test() != SelfTestPolicy::Ignore {
+            let checks = self_test.connected(Some(&state.db)).await;
+            doctor::enforce(config.self_test(), "connected", &checks)?;
+        }
+        if !config.databases().is_empty() {
+            let mut named = HashMap::new();
+            for database in config.databases() {
+                let db = Database::connect_named(database, config.sql_log().is_some()).await?;
+                let criticality = if database.critical {
+                    Criticality::Critical
+                } else {
+                    Criticality::NonCritical
+                };
+                let checked = db.clone();
+                state
+                    .health
+                    .register(format!("db:{}", database.name), criticality, move || {
+                        let db = checked.clone();
+                        async move { db.health_check().await }
+                    });
+                named.insert(database.name.clone(), db);
+            }
+            state.db = state.db.clone().with_named(named);
+        }
+        state.urls = UrlBuilder::new(
+            config.public_base_url().cloned(),
+            config.trusted_proxies().to_vec(),
+        );
+        let pool = state.db.pool();
+        state.extensions.extend(self.extensions);
+        state.extensions.insert(config.instance().clone());
+        state
+            .extensions
+            .insert(AdminToken::new(config.admin_token().map(String::from)));
+        state
+            .extensions
+            .insert(ApiKeys::new(config.api_keys().to_vec()));
+        state.extensions.insert(config.well_known().clone());
+        state
+            .extensions
+            .insert(Renderer::new(config.markdown_allowed_tags()));
+        if let Some(issuer) = config.oidc_issuer() {
+            state.extensions.insert(Provider::new(
+                issuer.to_string(),
+                config.signing_key_grace(),
+            ));
+        }
+        let db = state.db.clone();
+        state.health.register("db", Criticality::Critical, move || {
+            let db = db.clone();
+            async move { db.health_check().await }
+        });
+
+        if !config.dependencies().is_empty() {
+            info!(
+                "🩺 Monitoring {} external dependencies every {:?}",
+                config.dependencies().len(),
+                config.dependency_interval()
+            );
+            state.extensions.insert(dependencies::spawn(
+                config.dependencies().to_vec(),
+                config.dependency_interval(),
+                config.dependency_alerts().clone(),
+                &state.health,
+            ));
+        }
+        if !config.proxy_routes().is_empty() {
+            let proxy = Proxy::new(config.proxy_routes().to_vec(), config.proxy_timeout());
+            proxy.register_health_checks(&state.health);
+            for route in config.proxy_routes() {
+                info!("🔀 Proxying {} to {}", route.prefix, route.upstream);
+            }
+            modules.push(Arc::new(ProxyModule(proxy)));
+        }
+        if !config.challenge_routes().is_empty() {
+            for route in config.challenge_routes() {
+                info!(
+                    "🧩 Requiring a {}-bit challenge on {}{}",
+                    route.difficulty,
+                    route
+                        .method
+                        .as_ref()
+                        .map_or(String::new(), |m| format!("{} ", m)),
+                    route.prefix
+                );
+            }
+            state.extensions.insert(ChallengeGuard::new(
+                config.challenge_routes().to_vec(),
+                config.challenge_secret(),
+                config.challenge_ttl(),
+            ));
+        }
+        if config.anomaly().enabled {
+            info!("🕵️ Suspicious activity detection is enabled");
+            state.extensions.insert(AnomalyDetector::new(
+                config.anomaly().clone(),
+                state.db.pool().clone(),
+            ));
+            modules.push(Arc::new(AnomalyModule));
+        }
+
+        if config.health_history_db() {
+            modules.push(Arc::new(HealthHistoryModule));
+        }
+
+        if config.shortlinks() {
+            modules.push(Arc::new(ShortlinksModule));
+            modules.push(Arc::new(ShortlinkRedirectModule));
+        }
+
+        if let Some(storage) = config.storage() {
+            info!(
+                "🗄️ Storing files in {}, up to {} bytes each",
+                storage.dir.display(),
+                storage.max_file_size
+            );
+            state.extensions.insert(Storage::new(storage.clone()));
+            modules.push(Arc::new(StorageModule));
+        }
+
+        let read_only = ReadOnly::new(
+            config.read_only(),
+            config.read_only_reason().map(String::from),
+        );
+        if read_only.enabled() {
+            warn!("🔒 Starting in read-only mode; writes are rejected until it is switched off");
+        }
+        state.extensions.insert(read_only);
+
+        if let Some(sql_log) = config.sql_log() {
+            warn!("🐢 Logging every SQL statement; avoid this in production");
+            state.extensions.insert(QueryTracker::new(sql_log.clone()));
+            modules.push(Arc::new(QueriesModule));
+        }
+
+        if let Some(sql_console) = config.sql_console() {
+            warn!("🔎 The admin SQL console is enabled at /admin/sql");
+            let pool = match &sql_console.database {
+                Some(database) => Database::connect_named(database, false)
+                    .await?
+                    .pool()
+                    .clone(),
+                None => state.db.pool().clone(),
+            };
+            state.extensions.insert(ConsolePool(pool));
+            state.extensions.insert(sql_console.clone());
+            modules.push(Arc::new(SqlConsoleModule));
+        }
+
+        if let Some(pubsub) = config.pubsub() {
+            let pubsub = PubSub::new(pubsub.clone());
+            pubsub.register_health_check(&state.health);
+            state.extensions.insert(pubsub);
+            modules.push(Arc::new(PubSubModule));
+            modules.push(Arc::new(PubSubAdminModule));
+        }
+
+        if let Some(token) = config.calendar_feed_token() {
+            if !modules.iter().any(|module| module.name() == "events") {
+                anyhow::bail!(
+                    "CALENDAR_FEED_TOKEN needs the events module, which DISABLED_MODULES removes"
+                );
+            }
+            state.extensions.insert(CalendarFeed {
+                token: token.to_string(),
+                name: config.instance().name.clone(),
+            });
+            modules.push(Arc::new(CalendarFeedModule));
+        }
+
+        if config.dav() {
+            if !modules.iter().any(|module| module.name() == "events") {
+                anyhow::bail!("DAV needs the events module, which DISABLED_MODULES removes");
+            }
+            info!("📇 Serving CalDAV and CardDAV at /dav/");
+            state.extensions.insert(DavAuth::default());
+            modules.push(Arc::new(DavModule));
+            if state.extension::<Storage>().is_some() {
+                info!("📂 Sharing stored files over WebDAV at /dav/files/");
+                state.extensions.insert(Locks::default());
+                modules.push(Arc::new(DavFilesModule));
+            }
+        }
+
+        if let Some(mail) = config.mail() {
+            if !modules.iter().any(|module| module.name() == "notes") {
+                anyhow::bail!(
+                    "Inbound mail needs the notes module, which DISABLED_MODULES removes"
+                );
+            }
+            info!("📬 Taking in mail as notes in '{}'", mail.folder);
+            state.extensions.insert(Inbox::new(
+                mail.clone(),
+                state.extension::<Storage>(),
+                state.extension::<PubSub>(),
+            ));
+            modules.push(Arc::new(MailModule));
+            if mail.webhook_secret.is_some() {
+                modules.push(Arc::new(MailWebhookModule));
+            }
+        }
+
+        if let Some(config) = config.sessions() {
+            let store: Arc<dyn SessionStore> = match &config.backend {
+                SessionBackend::Postgres => Arc::new(PgSessionStore::new(pool.clone())),
+                SessionBackend::Redis(redis) => Arc::new(RedisSessionStore::new(redis.clone())),
+            };
+            state
+                .extensions
+                .insert(Sessions::new(store, config.clone()).with_remember(pool.clone()));
+            modules.push(Arc::new(SessionsModule));
+        }
+
+        if config.fault_injection() {
+            warn!("💥 Fault injection is enabled; never use this in production");
+            state.extensions.insert(FaultInjector::default());
+            modules.push(Arc::new(FaultsModule));
+        }
+
+        if let Some(capture) = config.capture() {
+            warn!(
+                "🎥 Capturing {}% of requests to {}; avoid this in production",
+                capture.sample_rate * 100.0,
+                capture.dir.display()
+            );
+            state.extensions.insert(Recorder::spawn(capture.clone()));
+        }
+
+        if let Some(shadow) = config.shadow() {
+            info!(
+                "👥 Shadowing {}% of {} requests to {}",
+                shadow.percent,
+                if shadow.methods.is_empty() {
+                    "all".to_string()
+                } else {
+                    shadow
+                        .methods
+                        .iter()
+                        .map(|m| m.as_str())
+                        .collect::<Vec<_>>()
+                        .join("/")
+                },
+                shadow.upstream
+            );
+            state.extensions.insert(Shadow::new(shadow.clone()));
+            modules.push(Arc::new(ShadowModule));
+        }
+
+        if let Some(canary) = config.canary() {
+            info!(
+                "🐤 Routing {}% of requests under {} to the {}",
+                canary.percent,
+                if canary.paths.is_empty() {
+                    "/".to_string()
+                } else {
+                    canary.paths.join(", ")
+                },
+                match &canary.upstream {
+                    Some(upstream) => format!("canary at {}", upstream),
+                    None => "canary variant".to_string(),
+                }
+            );
+            state.extensions.insert(Canary::new(canary.clone()));
+            modules.push(Arc::new(CanaryModule));
+        }
+
+        if let Some(usage) = config.usage() {
+            info!(
+                "🧾 Accounting usage per API key and tenant, flushed every {:?}",
+                usage.flush_interval
+            );
+            state.extensions.insert(UsageRecorder::new(
+                usage.clone(),
+                ApiKeys::new(config.api_keys().to_vec()),
+            ));
+            modules.push(Arc::new(UsageModule));
+        }
+
+        if let Some(billing) = config.billing() {
+            info!(
+                "💳 Billing tenants through Stripe on {} plans",
+                billing.plans.len()
+            );
+            state.extensions.insert(Billing::new(
+                billing.clone(),
+                ApiKeys::new(config.api_keys().to_vec()),
+            ));
+            modules.push(Arc::new(BillingModule));
+            modules.push(Arc::new(BillingWebhookModule));
+        }
+
+        if config.watchdog().enabled() {
+            info!(
+                "🐕 Watching resource usage every {:?}",
+                config.watchdog().interval
+            );
+            if Usage::sample() == Usage::default() {
+                warn!("🐕 Resource usage cannot be measured on this platform; limits are not enforced");
+            }
+            let watchdog = Watchdog::new(config.watchdog().clone());
+            watchdog.register_health_check(&state.health);
+            state.extensions.insert(watchdog);
+            modules.push(Arc::new(WatchdogModule));
+        }
+
+        disk::register_health_checks(&state.health, config.disk(), state.db.pool().clone());
+
+        let deprecations = Deprecations::new(
+            config
+                .deprecated_routes()
+                .iter()
+                .cloned()
+                .chain(modules.iter().flat_map(|module| module.deprecations()))
+                .collect(),
+        );
+        if !deprecations.is_empty() {
+            state.extensions.insert(deprecations);
+            modules.push(Arc::new(DeprecationsModule));
+        }
+
+        module::check_checksums(pool, &modules, config.migration_drift()).await?;
+        if config.migrate_on_startup() {
+            state
+                .db
+                .run_migrations(&modules)
+                .await
+                .context("Applying migrations failed; startup aborted")?;
+        } else {
+            module::check_schema(pool, &modules).await?;
+            let expand: Vec<_> = module::pending_migrations(pool, &modules)
+                .await?
+                .into_iter()
+                .filter(|(_, kind)| *kind == MigrationKind::Expand)
+                .map(|(name, _)| name)
+                .collect();
+            if !expand.is_empty() {
+                anyhow::bail!(
+                    "Migrations pending: {}; run `migrate --gate` or set MIGRATE_ON_STARTUP=true",
+                    expand.join(", ")
+                );
+            }
+        }
+        let contract: Vec<_> = module::pending_migrations(pool, &modules)
+            .await?
+            .into_iter()
+            .filter(|(_, kind)| *kind == MigrationKind::Contract)
+            .map(|(name, _)| name)
+            .collect();
+        if !contract.is_empty() {
+            warn!(
+                "🗄️ Contract migrations pending: {}; run `migrate` once no older version is running",
+                contract.join(", ")
+            );
+        }
+        for table in config.change_feed_tables() {
+            changes::track_table(pool, table).await?;
+        }
+        let mut hooks = self.hooks;
+        let leadership = match (config.leader_election(), config.kube_lease()) {
+            (ElectionBackend::Kubernetes, Some(lease)) => {
+                info!(
+                    "👑 Competing for Kubernetes lease '{}' as '{}'",
+                    lease.name, lease.identity
+                );
+                let (leadership, elector) = kubernetes::spawn(lease.clone());
+                hooks.add(Phase::Shutdown, "kubernetes-lease", move |_| async move {
+                    elector.release().await
+                });
+                leadership
+            }
+            (ElectionBackend::Postgres, _) => {
+                info!(
+                    "👑 Competing for Postgres leadership '{}'",
+                    config.leader_election_key()
+                );
+                let (leadership, elector) = leader::spawn_postgres(
+                    config.database_url().to_string(),
+                    config.leader_election_key().to_string(),
+                );
+                hooks.add(
+                    Phase::Shutdown,
+                    "postgres-leadership",
+                    move |_| async move {
+                        elector.release();
+                        Ok(())
+                    },
+                );
+                leadership
+            }
+            _ => Leadership::always(),
+        };
+        if leadership.backend() != ElectionBackend::None {
+            leader::register_health_check(&leadership, &state.health);
+        }
+        state.extensions.insert(leadership.clone());
+
+        if !config.retention_policies().is_empty() {
+            info!(
+                "🧹 Enforcing {} retention policies every {:?}",
+                config.retention_policies().len(),
+                config.retention_interval()
+            );
+            retention::spawn(
+                pool.clone(),
+                config.retention_policies().to_vec(),
+                config.retention_interval(),
+                config.retention_dry_run(),
+                leadership.clone(),
+            );
+        }
+
+        if let Some(sessions) = state.extension::<Sessions>() {
+            sessions::spawn_cleanup(Sessions::clone(&sessions), leadership.clone());
+        }
+
+        if let Some(config) = config.experiments() {
+            let experiments = Experiments::new(config.clone());
+            experiments.reload(pool).await?;
+            experiments::spawn(experiments.clone(), pool.clone());
+            state.extensions.insert(experiments);
+        }
+
+        let usage = state
+            .extension::<UsageRecorder>()
+            .map(|recorder| UsageRecorder::clone(&recorder));
+        if let Some(recorder) = &usage {
+            usage::spawn(recorder.clone(), pool.clone());
+            let (recorder, pool) = (recorder.clone(), pool.clone());
+            hooks.add(Phase::Shutdown, "usage-flush", move |_| async move {
+                recorder.flush(&pool).await
+            });
+        }
+
+        disk::spawn_alerts(
+            config.disk().clone(),
+            pool.clone(),
+            config.dependency_alerts().webhook.clone(),
+            leadership.clone(),
+        );
+
+        if let Some(billing) = state.extension::<Billing>() {
+            billing.refresh(pool).await?;
+            billing::spawn(Billing::clone(&billing), pool.clone());
+        }
+
+        let fetcher = Fetcher::new(
+            config.fetch_timeout(),
+            config.fetch_allow_private_networks(),
+        );
+        let jobs = JobStats::default();
+        state.extensions.insert(jobs.clone());
+        if modules.iter().any(|module| module.name() == "bookmarks") {
+            bookmarks::spawn(pool.clone(), fetcher.clone(), usage.clone(), jobs.clone());
+        }
+        if let Some(inbox) = state.extension::<Inbox>() {
+            if let Some(imap) = config.mail().and_then(|mail| mail.imap.clone()) {
+                info!("📥 Polling {} at {}", imap.mailbox, imap.address);
+                mail::spawn(
+                    Inbox::clone(&inbox),
+                    imap,
+                    pool.clone(),
+                    usage,
+                    jobs,
+                    leadership.clone(),
+                );
+            }
+        }
+        if modules.iter().any(|module| module.name() == "live") {
+            state.extensions.insert(LiveStats::new(
+                config.admin_stream_interval(),
+                modules.iter().any(|module| module.name() == "bookmarks"),
+            ));
+        }
+        if modules
+            .iter()
+            .any(|module| matches!(module.name(), "pastes" | "paste_links"))
+        {
+            state.extensions.insert(PasswordAttempts::default());
+        }
+        if modules.iter().any(|module| module.name() == "unfurl") {
+            state.extensions.insert(Unfurler {
+                fetcher,
+                cache_ttl: config.unfurl_cache_ttl(),
+            });
+        }
+
+        if let Some(watchdog) = state.extension::<Watchdog>() {
+            watchdog.spawn(pool.clone());
+        }
+        if modules.iter().any(|module| module.name() == "crashes") {
+            let webhook = config
+                .crash_alerts()
+                .then(|| config.dependency_alerts().webhook.clone())
+                .flatten();
+            crashes::spawn(pool.clone(), config.crash_dir().to_path_buf(), webhook);
+        }
+
+        let oidc_enabled = modules.iter().any(|module| module.name() == "oidc");
+        if let Some(provider) = state.extension::<Provider>().filter(|_| oidc_enabled) {
+            oidc::spawn_key_rotation(
+                provider,
+                pool.clone(),
+                config.signing_key_rotation(),
+                leadership.clone(),
+            );
+        }
+
+        if !config.status_sample_interval().is_zero() {
+            status::spawn(
+                pool.clone(),
+                state.health.clone(),
+                config.status_sample_interval(),
+                config.status_history_retention(),
+                leadership.clone(),
+            );
+        }
+
+        if config.health_history_db() {
+            health::spawn_recorder(pool.clone(), &state.health, config.instance().name.clone());
+        }
+
+        hooks
+            .run(Phase::Startup, &state, config.hook_timeout())
+            .await?;
+
+        let router = build_router(&config, &state, &modules, self.routes).await?;
+        let router = self
+            .layers
+            .into_iter()
+            .fold(router, |router, layer| layer(router));
+
+        #[cfg(feature = "http3")]
+        let (router, http3) = match config.http3() {
+            Some(http3) => {
+                let alt_svc = http3.alt_svc();
+                let router = router.layer(middleware::from_fn(move |req, next| {
+                    crate::http3::advertise(alt_svc.clone(), req, next)
+                }));
+                (router, Some(crate::http3::bind(http3)?))
+            }
+            None => (router, None),
+        };
+
+        let mut listeners = self.listeners;
+        if listeners.is_empty() {
+            let addr = SocketAddr::from(([0, 0, 0, 0], config.port()));
+            listeners.push(
+                listener::bind(addr, config.connections())
+                    .with_context(|| format!("Failed to bind to {}", addr))?,
+            );
+        }
+
+        let grpc_listener = match config.grpc_port() {
+            Some(port) => {
+                let addr = SocketAddr::from(([0, 0, 0, 0], port));
+                Some(
+                    TcpListener::bind(addr)
+                        .await
+                        .with_context(|| format!("Failed to bind gRPC to {}", addr))?,
+                )
+            }
+            None => None,
+        };
+        let proxy_protocol = match config.proxy_protocol() {
+            Some(proxy_protocol) => {
+                let addr = SocketAddr::from(([0, 0, 0, 0], proxy_protocol.port));
+                let listener = listener::bind(addr, config.connections())
+                    .with_context(|| format!("Failed to bind PROXY protocol to {}", addr))?;
+                Some((listener, proxy_protocol.clone()))
+            }
+            None => None,
+        };
+
+        if let Some(advertised) = config.mdns() {
+            let advertised = advertised.clone();
+            let port = listeners[0].local_addr()?.port();
+            let responder = Arc::new(std::sync::OnceLock::new());
+            let started = responder.clone();
+            hooks.add(Phase::Ready, "mdns-advertise", move |_| async move {
+                let _ = started.set(mdns::spawn(&advertised, port)?);
+                Ok(())
+            });
+            hooks.add(Phase::Shutdown, "mdns-goodbye", move |_| async move {
+                if let Some(responder) = responder.get() {
+                    responder.stop().await;
+                }
+                Ok(())
+            });
+        }
+        if let Some(consul) = config.consul() {
+            let consul = Consul::new(consul.clone());
+            let port = listeners[0].local_addr()?.port();
+            let registration = consul.clone();
+            hooks.add(Phase::Ready, "consul-register", move |state| async move {
+                registration.register(port).await?;
+                registration.spawn_heartbeat(state.health);
+                Ok(())
+            });
+            hooks.add(Phase::Shutdown, "consul-deregister", move |_| async move {
+                consul.deregister().await
+            });
+        }
+
+        let shutdown_signal = self
+            .shutdown_signal
+            .unwrap_or_else(|| Box::pin(shutdown_signal()));
+        let shutdown_signal: BoxFuture = match state.extension::<Watchdog>() {
+            Some(watchdog) => Box::pin(async move {
+                tokio::select! {
+                    _ = shutdown_signal => {},
+                    _ = watchdog.wait_for_restart() => {},
+                }
+            }),
+            None => shutdown_signal,
+        };
+
+        Ok(Server {
+            router,
+            listeners,
+            grpc_listener,
+            proxy_protocol,
+            connections: config.connections().clone(),
+            #[cfg(feature = "http3")]
+            http3,
+            state,
+            hooks,
+            leader_hooks: self.leader_hooks,
+            leadership,
+            hook_timeout: config.hook_timeout(),
+            drain: config.shutdown_drain(),
+            shutdown_signal,
+        })
+    }
+
+    /// Build the server and run it until shutdown
+    pub async fn serve(self) -> Result<()> {
+        self.build().await?.serve().await
+    }
+}
+
+/// Drop modules listed in `DISABLED_MODULES`, rejecting unknown names
+pub(crate) fn enabled_modules(
+    modules: Vec<Arc<dyn RouteModule>>,
+    disabled: &[String],
+) -> Result<Vec<Arc<dyn RouteModule>>> {
+    let mut seen = std::collections::HashSet::new();
+    for module in &modules {
+        if !seen.insert(module.name()) {
+            anyhow::bail!("Route module '{}' is registered twice", module.name());
+        }
+    }
+    if let Some(unknown) = disabled.iter().find(|name| !seen.contains(name.as_str())) {
+        anyhow::bail!("Invalid DISABLED_MODULES: unknown module '{}'", unknown);
+    }
+    Ok(modules
+        .into_iter()
+        .filter(|module| !disabled.iter().any(|name| name == module.name()))
+        .collect())
+}
+
+/// Assemble the route groups and middleware pipeline
+async fn build_router(
+    config: &Config,
+    state: &AppState,
+    modules: &[Arc<dyn RouteModule>],
+    extra_routes: Vec<Router<AppState>>,
+) -> Result<Router> {
+    let pipeline = Pipeline::new(
+        RateLimiter::new(config.rate_limit(), config.rate_limit_burst()),
+        ConcurrencyLimiter::new(config.concurrency_limit()),
+        ApiAuth {
+            keys: ApiKeys::new(config.api_keys().to_vec()),
+            signatures: RequestVerifier::new(
+                config.hmac_clients().to_vec(),
+                config.hmac_max_skew(),
+            ),
+            group: None,
+        },
+        state.db.pool().clone(),
+    );
+    let layers = config.middleware();
+
+    let group = |group: RouteGroup| {
+        modules
+            .iter()
+            .filter(|module| module.group() == group)
+            .fold(Router::new(), |router, module| {
+                router.merge(module.routes())
+            })
+    };
+    let public = group(RouteGroup::Public);
+    let api = group(RouteGroup::Api);
+    let api = extra_routes.into_iter().fold(api, Router::merge);
+    let app = Router::new()
+        .merge(pipeline.apply_group(public, RouteGroup::Public, layers))
+        .merge(pipeline.apply_group(api, RouteGroup::Api, layers))
+        .with_state(state.clone());
+
+    #[cfg(feature = "plugins")]
+    let app = {
+        let host =
+            crate::plugins::PluginHost::load(config.plugins()).context("Failed to load plugins")?;
+        if host.is_empty() {
+            app
+        } else {
+            let hooks = host.clone();
+            app.merge(host.router().with_state(state.clone()))
+                .layer(middleware::from_fn(move |req, next| {
+                    hooks.clone().hooks(req, next)
+                }))
+        }
+    };
+
+    let admin = group(RouteGroup::Admin).with_state(state.clone());
+
+    #[cfg(feature = "scripting")]
+    let (app, admin) = {
+        let scripts =
+            crate::scripting::ScriptHost::new(state.db.pool().clone(), config.scripting())
+                .await
+                .context("Failed to load scripts")?;
+        let hooks = scripts.clone();
+        (
+            app.layer(middleware::from_fn(move |req, next| {
+                hooks.clone().middleware(req, next)
+            })),
+            admin.merge(scripts.admin_router()),
+        )
+    };
+
+    let admin = admin.layer(middleware::from_fn_with_state(
+        AdminToken::new(config.admin_token().map(String::from)),
+        auth::require_admin,
+    ));
+    // Inside the global pipeline, so a retried read is only counted once
+    let app = app
+        .merge(pipeline.apply_group(admin, RouteGroup::Admin, layers))
+        .layer(middleware::from_fn_with_state(
+            state.db.clone(),
+            failover::middleware,
+        ));
+    let app = pipeline.apply(app, &layers.global);
+    // Outside the pipeline, so rate limiting sees the exemption
+    let app = match config.route_overrides() {
+        [] => app,
+        overrides => app.layer(middleware::from_fn_with_state(
+            RouteOverrides::new(overrides.to_vec()),
+            RouteOverrides::middleware,
+        )),
+    };
+    let app = match state.extension::<Deprecations>() {
+        Some(deprecations) => app.layer(middleware::from_fn_with_state(
+            Deprecations::clone(&deprecations),
+            Deprecations::middleware,
+        )),
+        None => app,
+    };
+    // Inside sessions, so logged-in users are bucketed by id
+    let app = match state.extension::<Experiments>() {
+        Some(experiments) => app.layer(middleware::from_fn_with_state(
+            Experiments::clone(&experiments),
+            Experiments::middleware,
+        )),
+        None => app,
+    };
+    // Inside sessions too, so cookie logins are accounted to their user
+    let app = match state.extension::<UsageRecorder>() {
+        Some(recorder) => app.layer(middleware::from_fn_with_state(
+            UsageRecorder::clone(&recorder),
+            UsageRecorder::middleware,
+        )),
+        None => app,
+    };
+    // Outside usage accounting, so refused requests are not counted
+    let app = match state.extension::<Billing>() {
+        Some(billing) => app.layer(middleware::from_fn_with_state(
+            Billing::clone(&billing),
+            Billing::middleware,
+        )),
+        None => app,
+    };
+    let app = match state.extension::<Sessions>() {
+        Some(sessions) => app.layer(middleware::from_fn_with_state(
+            Sessions::clone(&sessions),
+            Sessions::middleware,
+        )),
+        None => app,
+    };
+    let app = match state.extension::<QueryTracker>() {
+        Some(tracker) => app.layer(middleware::from_fn_with_state(
+            QueryTracker::clone(&tracker),
+            QueryTracker::middleware,
+        )),
+        None => app,
+    };
+    let app = match state.extension::<ReadOnly>() {
+        Some(read_only) => app.layer(middleware::from_fn_with_state(
+            ReadOnly::clone(&read_only),
+            ReadOnly::middleware,
+        )),
+        None => app,
+    };
+    let app = match state.extension::<ChallengeGuard>() {
+        Some(guard) => app.layer(middleware::from_fn_with_state(
+            ChallengeGuard::clone(&guard),
+            ChallengeGuard::middleware,
+        )),
+        None => app,
+    };
+    // Inside the fault layer, so injected errors are observed too
+    let app = match state.extension::<AnomalyDetector>() {
+        Some(detector) => app.layer(middleware::from_fn_with_state(
+            AnomalyDetector::clone(&detector),
+            AnomalyDetector::middleware,
+        )),
+        None => app,
+    };
+    let app = match state.extension::<FaultInjector>() {
+        Some(faults) => app.layer(middleware::from_fn_with_state(
+            FaultInjector::clone(&faults),
+            FaultInjector::middleware,
+        )),
+        None => app,
+    };
+    let app = match state.extension::<Canary>() {
+        Some(canary) => app.layer(middleware::from_fn_with_state(
+            Canary::clone(&canary),
+            Canary::middleware,
+        )),
+        None => app,
+    };
+    let app = match state.extension::<Shadow>() {
+        Some(shadow) => app.layer(middleware::from_fn_with_state(
+            Shadow::clone(&shadow),
+            Shadow::middleware,
+        )),
+        None => app,
+    };
+    let app = match state.extension::<Recorder>() {
+        Some(recorder) => app.layer(middleware::from_fn_with_state(
+            Recorder::clone(&recorder),
+            Recorder::middleware,
+        )),
+        None => app,
+    };
+    let app = match state.extension::<Watchdog>() {
+        Some(watchdog) => app.layer(middleware::from_fn_with_state(
+            Watchdog::clone(&watchdog),
+            Watchdog::middleware,
+        )),
+        None => app,
+    };
+    // Outside the watchdog, so shed requests are counted too
+    let app = match state.extension::<LiveStats>() {
+        Some(stats) => app.layer(middleware::from_fn_with_state(
+            LiveStats::clone(&stats),
+            LiveStats::middleware,
+        )),
+        None => app,
+    };
+    Ok(app
+        .layer(middleware::from_fn_with_state(
+            state.urls.clone(),
+            UrlBuilder::middleware,
+        ))
+        .layer(middleware::from_fn(i18n::middleware))
+        .layer(middleware::from_fn(crashes::middleware)))
+}
+
+/// A built server ready to accept connections
+pub struct Server {
+    router: Router,
+    listeners: Vec<TcpListener>,
+    grpc_listener: Option<TcpListener>,
+    proxy_protocol: Option<(TcpListener, ProxyProtocolConfig)>,
+    connections: ConnectionTuning,
+    #[cfg(feature = "http3")]
+    http3: Option<quinn::Endpoint>,
+    state: AppState,
+    hooks: Hooks,
+    leader_hooks: Vec<LeaderHook>,
+    leadership: Leadership,
+    hook_timeout: Duration,
+    drain: Duration,
+    shutdown_signal: BoxFuture,
+}
+
+impl Server {
+    /// The fully assembled application router
+    pub fn router(&self) -> Router {
+        self.router.clone()
+    }
+
+    /// The shared application state
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    /// Addresses the server is listening on
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|l| l.local_addr().ok())
+            .collect()
+    }
+
+    /// Serve requests until the shutdown signal fires
+    ///
+    /// Ready hooks run once every listener is accepting connections and
+    /// shutdown hooks once they have all stopped. On the signal, readiness
+    /// fails for `SHUTDOWN_DRAIN_DELAY` before the listeners close.
+    pub async fn serve(mut self) -> Result<()> {
+        let span = instance_span(self.state.extension::<Instance>().as_deref());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut servers = JoinSet::new();
+        for listener in self.listeners {
+            let addr = listener.local_addr()?;
+            info!("🚀 Server listening on http://{}", addr);
+            let (router, tuning) = (self.router.clone(), self.connections.clone());
+            let shutdown_rx = shutdown_rx.clone();
+            servers.spawn(
+                listener::serve(listener, router, tuning, None, shutdown_rx)
+                    .instrument(span.clone()),
+            );
+        }
+        if let Some((listener, config)) = self.proxy_protocol {
+            info!(
+                "🚀 Server listening on http://{} (PROXY protocol)",
+                listener.local_addr()?
+            );
+            let (router, tuning) = (self.router.clone(), self.connections.clone());
+            let shutdown_rx = shutdown_rx.clone();
+            servers.spawn(
+                listener::serve(listener, router, tuning, Some(config), shutdown_rx)
+                    .instrument(span.clone()),
+            );
+        }
+        if let Some(listener) = self.grpc_listener {
+            info!("🩺 gRPC health checks on {}", listener.local_addr()?);
+            let (health, shutdown_rx) = (self.state.health.clone(), shutdown_rx.clone());
+            servers.spawn(grpc::serve(listener, health, shutdown_rx).instrument(span.clone()));
+        }
+        #[cfg(feature = "http3")]
+        if let Some(endpoint) = self.http3 {
+            info!("⚡ HTTP/3 listening on udp://{}", endpoint.local_addr()?);
+            let (router, shutdown_rx) = (self.router.clone(), shutdown_rx.clone());
+            servers
+                .spawn(crate::http3::serve(endpoint, router, shutdown_rx).instrument(span.clone()));
+        }
+        info!("✅ Server is ready to accept connections");
+
+        let signal = self.shutdown_signal;
+        let (health, drain) = (self.state.health.clone(), self.drain);
+        tokio::spawn(
+            async move {
+                signal.await;
+                health.set_serving_state(ServingState::Draining);
+                if !drain.is_zero() {
+                    info!("⏳ Draining for {:?} before closing listeners", drain);
+                    tokio::time::sleep(drain).await;
+                }
+                let _ = shutdown_tx.send(true);
+            }
+            .instrument(span.clone()),
+        );
+        self.hooks
+            .run(Phase::Ready, &self.state, self.hook_timeout)
+            .await?;
+        if self.state.health.serving_state() == ServingState::Starting {
+            self.state.health.set_serving_state(ServingState::Serving);
+        }
+        for hook in self.leader_hooks.drain(..) {
+            hook(self.leadership.clone(), self.state.clone());
+        }
+
+        let mut result = Ok(());
+        while let Some(joined) = servers.join_next().await {
+            if let Err(e) = joined.context("Server task panicked") {
+                result = Err(e);
+            }
+        }
+
+        self.hooks
+            .run(Phase::Shutdown, &self.state, self.hook_timeout)
+            .await?;
+        self.state.db.close().await;
+        if let Some(watchdog) = self.state.extension::<Watchdog>() {
+            if watchdog.restart_requested() && result.is_ok() {
+                anyhow::bail!("Stopped by the watchdog after exceeding a resource limit");
+            }
+        }
+        result
+    }
+}
+
+/// Span carrying the instance labels for the server's own log lines
+fn instance_span(instance: Option<&Instance>) -> tracing::Span {
+    let span = tracing::info_span!(
+        "instance",
+        name = tracing::field::Empty,
+        namespace = tracing::field::Empty,
+        node = tracing::field::Empty
+    );
+    if let Some(instance) = instance {
+        span.record("name", instance.name.as_str());
+        if let Some(namespace) = &instance.namespace {
+            span.record("namespace", namespace.as_str());
+        }
+        if let Some(node) = &instance.node {
+            span.record("node", node.as_str());
+        }
+    }
+    span
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("🛑 Shutdown signal received");
+}
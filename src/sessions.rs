@@ -0,0 +1,1086 @@
+//! Cookie sessions for logged-in users.
+//!
+//! With `SESSION_STORE` set, users log in with `POST /api/v1/session` and
+//! get an opaque token in an `HttpOnly` cookie. The session middleware
+//! loads the matching [`Session`] for every request; handlers read it with
+//! the [`SessionHandle`] extractor, which also stores small values in the
+//! session. Only the SHA-256 of a token is stored, so a leaked store does
+//! not hand out logins.
+//!
+//! Sessions live behind the [`SessionStore`] trait, in Postgres
+//! (`SESSION_STORE=postgres`) or Redis (`SESSION_STORE=redis://...`, see
+//! [`redis`]). Either way session data is encrypted with `ENCRYPTION_KEYS`
+//! when keys are configured.
+//!
+//! Expiry slides: a session stays valid for `SESSION_TTL` after its last
+//! request, and never longer than `SESSION_MAX_AGE` after login. Requests
+//! only write to the store when the session was last seen over a minute
+//! ago or its data changed. Expired Postgres rows are deleted by the leader
+//! every `SESSION_CLEANUP_INTERVAL`; Redis expires keys itself.
+//!
+//! Logging in with `"remember": true` also hands out a [`remember`] token
+//! valid for `SESSION_REMEMBER_ME`, which starts a new session once the old
+//! one is gone.
+//!
+//! Users see their sessions at `GET /api/v1/me/sessions` and end them with
+//! `DELETE`. Sessions are read from the store on every request and saving a
+//! touched session never recreates a deleted one, so a revoked session stops
+//! working with its next request, on every replica.
+
+pub mod redis;
+pub mod remember;
+
+use anyhow::{Context, Result};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Path, Request, State},
+    http::{
+        header::{COOKIE, SET_COOKIE, USER_AGENT},
+        request::Parts,
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post},
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand_core::RngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sqlx::PgPool;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::db::BoxFuture;
+use crate::encryption::{Encrypted, EncryptedColumn};
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::leader::Leadership;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::pubsub::redis::RedisConfig;
+use crate::{t, users, AppState};
+
+/// How stale `last_seen_at` may get before a request refreshes it
+const TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest user agent kept with a session
+const MAX_USER_AGENT: usize = 256;
+
+/// Where sessions are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionBackend {
+    Postgres,
+    Redis(RedisConfig),
+}
+
+/// Session settings, from configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionConfig {
+    pub backend: SessionBackend,
+    /// How long a session survives without requests
+    pub ttl: Duration,
+    /// How long a session survives at most after login
+    pub max_age: Duration,
+    pub cookie_name: String,
+    /// Send the cookie over HTTPS only
+    pub cookie_secure: bool,
+    /// How often expired Postgres sessions are deleted
+    pub cleanup_interval: Duration,
+    /// How long remember-me tokens last, if they are offered
+    pub remember: Option<Duration>,
+}
+
+/// A logged-in user's session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// SHA-256 of the session token, hex-encoded
+    pub id: String,
+    pub user_id: i64,
+    /// Values handlers keep in the session
+    pub data: Map<String, Value>,
+    pub user_agent: Option<String>,
+    /// Address of the latest request
+    pub ip: Option<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub last_seen_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Storage for sessions
+///
+/// Implementations never return sessions past their `expires_at`.
+pub trait SessionStore: Send + Sync {
+    /// Look up a live session by id
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Session>>>;
+
+    /// Store a new session
+    fn insert<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<()>>;
+
+    /// Replace a session, unless it was deleted in the meantime
+    fn update<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<()>>;
+
+    /// Remove a session; removing a missing one is not an error
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    /// Live sessions of a user, most recently seen first
+    fn list<'a>(&'a self, user_id: i64) -> BoxFuture<'a, Result<Vec<Session>>>;
+
+    /// Delete expired sessions, returning how many were removed
+    fn cleanup(&self) -> BoxFuture<'_, Result<u64>>;
+}
+
+/// Sessions stored in the `sessions` table
+pub struct PgSessionStore {
+    pool: PgPool,
+}
+
+impl PgSessionStore {
+    pub fn new(pool: PgPool) -> Self {
+        PgSessionStore { pool }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct SessionRow {
+    id: String,
+    user_id: i64,
+    data: Encrypted,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+impl TryFrom<SessionRow> for Session {
+    type Error = anyhow::Error;
+
+    fn try_from(row: SessionRow) -> Result<Self> {
+        Ok(Session {
+            data: serde_json::from_str(row.data.as_str()).context("Invalid session data")?,
+            id: row.id,
+            user_id: row.user_id,
+            user_agent: row.user_agent,
+            ip: row.ip,
+            created_at: row.created_at,
+            last_seen_at: row.last_seen_at,
+            expires_at: row.expires_at,
+        })
+    }
+}
+
+const COLUMNS: &str = "id, user_id, data, user_agent, ip, created_at, last_seen_at, expires_at";
+
+impl SessionStore for PgSessionStore {
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Session>>> {
+        Box::pin(async move {
+            sqlx::query_as::<_, SessionRow>(&format!(
+                "SELECT {COLUMNS} FROM sessions WHERE id = $1 AND expires_at > now()"
+            ))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(Session::try_from)
+            .transpose()
+        })
+    }
+
+    fn insert<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO sessions
+                    (id, user_id, data, user_agent, ip, created_at, last_seen_at, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            )
+            .bind(&session.id)
+            .bind(session.user_id)
+            .bind(Encrypted(serde_json::to_string(&session.data)?))
+            .bind(&session.user_agent)
+            .bind(&session.ip)
+            .bind(session.created_at)
+            .bind(session.last_seen_at)
+            .bind(session.expires_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn update<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            sqlx::query(
+                "UPDATE sessions SET data = $2, user_agent = $3, ip = $4,
+                    last_seen_at = $5, expires_at = $6
+                 WHERE id = $1",
+            )
+            .bind(&session.id)
+            .bind(Encrypted(serde_json::to_string(&session.data)?))
+            .bind(&session.user_agent)
+            .bind(&session.ip)
+            .bind(session.last_seen_at)
+            .bind(session.expires_at)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            sqlx::query("DELETE FROM sessions WHERE id = $1")
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn list<'a>(&'a self, user_id: i64) -> BoxFuture<'a, Result<Vec<Session>>> {
+        Box::pin(async move {
+            sqlx::query_as::<_, SessionRow>(&format!(
+                "SELECT {COLUMNS} FROM sessions WHERE user_id = $1 AND expires_at > now()
+                 ORDER BY last_seen_at DESC"
+            ))
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(Session::try_from)
+            .collect()
+        })
+    }
+
+    fn cleanup(&self) -> BoxFuture<'_, Result<u64>> {
+        Box::pin(async move {
+            let result = sqlx::query("DELETE FROM sessions WHERE expires_at <= now()")
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected())
+        })
+    }
+}
+
+/// A new random session token
+fn new_token() -> String {
+    let mut token = [0u8; 32];
+    rand_core::OsRng.fill_bytes(&mut token);
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+/// Session id of a token
+pub fn session_id(token: &str) -> String {
+    crate::crypto::sha256_hex(token)
+}
+
+/// Value of the cookie `name` in a request
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// User agent and address of a request, as recorded with its session
+fn client(
+    headers: &HeaderMap,
+    connect: Option<&ConnectInfo<SocketAddr>>,
+) -> (Option<String>, Option<String>) {
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.chars().take(MAX_USER_AGENT).collect());
+    let ip = connect.map(|ConnectInfo(addr)| addr.ip().to_string());
+    (user_agent, ip)
+}
+
+/// The session store with its settings
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    config: SessionConfig,
+    /// Database of remember-me tokens, when they are offered
+    remember: Option<PgPool>,
+}
+
+impl Sessions {
+    pub fn new(store: Arc<dyn SessionStore>, config: SessionConfig) -> Self {
+        Sessions {
+            store,
+            config,
+            remember: None,
+        }
+    }
+
+    /// Offer remember-me tokens, kept in `pool`, if configured
+    pub fn with_remember(mut self, pool: PgPool) -> Self {
+        self.remember = self.config.remember.map(|_| pool);
+        self
+    }
+
+    /// Database and lifetime of remember-me tokens, when they are offered
+    fn remember(&self) -> Option<(&PgPool, Duration)> {
+        Some((self.remember.as_ref()?, self.config.remember?))
+    }
+
+    fn remember_cookie_name(&self) -> String {
+        format!("{}_remember", self.config.cookie_name)
+    }
+
+    pub fn store(&self) -> &dyn SessionStore {
+        &*self.store
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    /// Start a session for a user, returning its token and the session
+    pub async fn create(
+        &self,
+        user_id: i64,
+        user_agent: Option<String>,
+        ip: Option<String>,
+    ) -> Result<(String, Session)> {
+        let token = new_token();
+        let now = Utc::now();
+        let session = Session {
+            id: session_id(&token),
+            user_id,
+            data: Map::new(),
+            user_agent,
+            ip,
+            created_at: now,
+            last_seen_at: now,
+            expires_at: self.expiry(now, now),
+        };
+        self.store.insert(&session).await?;
+        Ok((token, session))
+    }
+
+    /// When a session seen at `now` expires
+    fn expiry(&self, created_at: DateTime<Utc>, now: DateTime<Utc>) -> DateTime<Utc> {
+        let idle = now + self.config.ttl;
+        let absolute = created_at + self.config.max_age;
+        idle.min(absolute)
+    }
+
+    /// `Set-Cookie` value handing out a session token
+    ///
+    /// The cookie has no `Max-Age`, so browsers drop it when closed; the
+    /// session itself expires in the store.
+    pub fn set_cookie(&self, token: &str) -> HeaderValue {
+        self.cookie_header(&self.config.cookie_name, token, None)
+    }
+
+    /// `Set-Cookie` value removing the session cookie
+    pub fn clear_cookie(&self) -> HeaderValue {
+        self.cookie_header(&self.config.cookie_name, "", Some(0))
+    }
+
+    /// `Set-Cookie` value handing out a remember-me token
+    fn set_remember_cookie(&self, value: &str, lifetime: Duration) -> HeaderValue {
+        self.cookie_header(
+            &self.remember_cookie_name(),
+            value,
+            Some(lifetime.as_secs()),
+        )
+    }
+
+    /// `Set-Cookie` value removing the remember-me cookie
+    fn clear_remember_cookie(&self) -> HeaderValue {
+        self.cookie_header(&self.remember_cookie_name(), "", Some(0))
+    }
+
+    fn cookie_header(&self, name: &str, value: &str, max_age: Option<u64>) -> HeaderValue {
+        let mut cookie = format!("{}={}; Path=/; HttpOnly; SameSite=Lax", name, value);
+        if let Some(max_age) = max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if self.config.cookie_secure {
+            cookie.push_str("; Secure");
+        }
+        HeaderValue::from_str(&cookie).expect("cookie names and tokens are header-safe")
+    }
+
+    /// Start a session from a remember-me cookie
+    ///
+    /// Returns the cookies to set: the new session's and the rotated
+    /// remember-me token, or one clearing a token that no longer works.
+    async fn resume(
+        &self,
+        value: &str,
+        user_agent: Option<String>,
+        ip: Option<String>,
+    ) -> Result<(Option<Session>, Vec<HeaderValue>)> {
+        let Some((pool, lifetime)) = self.remember() else {
+            return Ok((None, Vec::new()));
+        };
+        let redeemed =
+            remember::redeem(pool, value, user_agent.as_deref(), ip.as_deref(), lifetime).await?;
+        let Some((user_id, rotated)) = redeemed else {
+            return Ok((None, vec![self.clear_remember_cookie()]));
+        };
+        let (token, session) = self.create(user_id, user_agent, ip).await?;
+        let cookies = std::iter::once(self.set_cookie(&token))
+            .chain(rotated.map(|rotated| self.set_remember_cookie(&rotated, lifetime)))
+            .collect();
+        Ok((Some(session), cookies))
+    }
+
+    /// Middleware loading the request's session
+    pub async fn middleware(
+        State(sessions): State<Sessions>,
+        mut request: Request,
+        next: Next,
+    ) -> Response {
+        let token = cookie(request.headers(), &sessions.config.cookie_name);
+        let session = match token {
+            Some(token) => match sessions.store.load(&session_id(token)).await {
+                Ok(session) => session,
+                Err(e) => {
+                    tracing::error!("Failed to load a session: {:#}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let (user_agent, ip) = client(request.headers(), request.extensions().get());
+
+        let mut cookies = Vec::new();
+        let session = match session {
+            Some(session) => Some(session),
+            None => match cookie(request.headers(), &sessions.remember_cookie_name()) {
+                Some(value) => match sessions.resume(value, user_agent.clone(), ip.clone()).await {
+                    Ok((session, set)) => {
+                        cookies = set;
+                        session
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to resume a remembered session: {:#}", e);
+                        None
+                    }
+                },
+                None => None,
+            },
+        };
+        let Some(mut session) = session else {
+            let mut response = next.run(request).await;
+            append_cookies(&mut response, cookies);
+            return response;
+        };
+
+        let now = Utc::now();
+        let stale = (now - session.last_seen_at)
+            .to_std()
+            .is_ok_and(|age| age >= TOUCH_INTERVAL);
+        let moved = ip.is_some() && ip != session.ip;
+        if stale || moved {
+            session.last_seen_at = now;
+            session.expires_at = sessions.expiry(session.created_at, now);
+            session.user_agent = user_agent.or(session.user_agent);
+            session.ip = ip.or(session.ip);
+        }
+        let handle = SessionHandle {
+            inner: Arc::new(Mutex::new(Tracked {
+                session,
+                changed: stale || moved,
+            })),
+        };
+        request.extensions_mut().insert(handle.clone());
+
+        let mut response = next.run(request).await;
+        append_cookies(&mut response, cookies);
+        let changed = {
+            let tracked = handle.lock();
+            tracked.changed.then(|| tracked.session.clone())
+        };
+        if let Some(session) = changed {
+            if let Err(e) = sessions.store.update(&session).await {
+                tracing::error!("Failed to save a session: {:#}", e);
+            }
+        }
+        response
+    }
+}
+
+fn append_cookies(response: &mut Response, cookies: Vec<HeaderValue>) {
+    for cookie in cookies {
+        response.headers_mut().append(SET_COOKIE, cookie);
+    }
+}
+
+/// A session and whether it needs saving
+struct Tracked {
+    session: Session,
+    changed: bool,
+}
+
+/// The current request's session
+///
+/// As an extractor, rejects requests without a live session with 401.
+#[derive(Clone)]
+pub struct SessionHandle {
+    inner: Arc<Mutex<Tracked>>,
+}
+
+impl SessionHandle {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tracked> {
+        self.inner.lock().expect("session lock poisoned")
+    }
+
+    /// A copy of the session as it is now
+    pub fn session(&self) -> Session {
+        self.lock().session.clone()
+    }
+
+    pub fn id(&self) -> String {
+        self.lock().session.id.clone()
+    }
+
+    pub fn user_id(&self) -> i64 {
+        self.lock().session.user_id
+    }
+
+    /// A value stored in the session, if present and of type `T`
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let tracked = self.lock();
+        let value = tracked.session.data.get(key)?.clone();
+        serde_json::from_value(value).ok()
+    }
+
+    /// Store a value in the session, saved once the response is ready
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) -> Result<()> {
+        let value = serde_json::to_value(value)?;
+        let mut tracked = self.lock();
+        tracked.session.data.insert(key.to_string(), value);
+        tracked.changed = true;
+        Ok(())
+    }
+
+    /// Remove a value from the session
+    pub fn remove(&self, key: &str) {
+        let mut tracked = self.lock();
+        if tracked.session.data.remove(key).is_some() {
+            tracked.changed = true;
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for SessionHandle {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<SessionHandle>()
+            .cloned()
+            .ok_or_else(|| ApiError::Unauthorized(t!("session-required")))
+    }
+}
+
+/// Spawn the task deleting expired sessions and remember-me tokens on the leader
+pub fn spawn_cleanup(sessions: Sessions, leadership: Leadership) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(sessions.config.cleanup_interval);
+        loop {
+            ticker.tick().await;
+            if !leadership.is_leader() {
+                continue;
+            }
+            match sessions.store.cleanup().await {
+                Ok(0) => {}
+                Ok(removed) => tracing::debug!("🧹 Removed {} expired sessions", removed),
+                Err(e) => tracing::warn!("Failed to remove expired sessions: {:#}", e),
+            }
+            if let Some((pool, _)) = sessions.remember() {
+                match remember::cleanup(pool).await {
+                    Ok(0) => {}
+                    Ok(removed) => {
+                        tracing::debug!("🧹 Removed {} expired remember-me tokens", removed)
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to remove expired remember-me tokens: {:#}", e)
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Route module for logging in and out, mounted with `SESSION_STORE`
+pub struct SessionsModule;
+
+impl RouteModule for SessionsModule {
+    fn name(&self) -> &'static str {
+        "sessions"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/api/v1/session", post(login).get(current).delete(logout))
+            .route("/api/v1/session/remembered", get(list_remembered))
+            .route(
+                "/api/v1/session/remembered/:selector",
+                delete(forget_remembered),
+            )
+            .route(
+                "/api/v1/me/sessions",
+                get(list_sessions).delete(revoke_other_sessions),
+            )
+            .route("/api/v1/me/sessions/:id", delete(revoke_session))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[
+            Migration {
+                name: "0001_create_sessions",
+                kind: MigrationKind::Expand,
+                sql: "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+                data TEXT NOT NULL,
+                user_agent TEXT,
+                ip TEXT,
+                created_at TIMESTAMPTZ NOT NULL,
+                last_seen_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS sessions_user ON sessions (user_id);
+            CREATE INDEX IF NOT EXISTS sessions_expires ON sessions (expires_at)",
+            },
+            Migration {
+                name: "0002_create_remember_tokens",
+                kind: MigrationKind::Expand,
+                sql: "CREATE TABLE IF NOT EXISTS remember_tokens (
+                selector TEXT PRIMARY KEY,
+                validator_hash TEXT NOT NULL,
+                user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+                user_agent TEXT,
+                ip TEXT,
+                created_at TIMESTAMPTZ NOT NULL,
+                last_used_at TIMESTAMPTZ NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS remember_tokens_user ON remember_tokens (user_id);
+            CREATE INDEX IF NOT EXISTS remember_tokens_expires ON remember_tokens (expires_at)",
+            },
+            Migration {
+                name: "0003_remember_previous_validator",
+                kind: MigrationKind::Expand,
+                sql: "ALTER TABLE remember_tokens
+                ADD COLUMN IF NOT EXISTS previous_validator_hash TEXT,
+                ADD COLUMN IF NOT EXISTS rotated_at TIMESTAMPTZ",
+            },
+        ]
+    }
+
+    fn encrypted_columns(&self) -> &'static [EncryptedColumn] {
+        &[EncryptedColumn {
+            table: "sessions",
+            column: "data",
+            key: "id",
+        }]
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Login {
+    username: String,
+    password: String,
+    /// Also hand out a remember-me token
+    #[serde(default)]
+    remember: bool,
+}
+
+/// The logged-in user and their session, as returned by the session API
+fn describe(user: &users::User, session: &Session) -> Value {
+    json!({
+        "user": user,
+        "expires_at": crate::time::format(&session.expires_at),
+    })
+}
+
+/// `POST /api/v1/session` - log in, setting the session cookie
+pub async fn login(
+    State(state): State<AppState>,
+    Ext(sessions): Ext<Sessions>,
+    connect: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Json(login): Json<Login>,
+) -> ApiResult<Response> {
+    let (user_agent, ip) = client(&headers, connect.as_ref());
+    let user = users::authenticate(state.db.pool(), &login.username, &login.password)
+        .await?
+        .ok_or_else(|| ApiError::Unauthorized(t!("login-invalid")))?;
+    let remembered = match sessions.remember().filter(|_| login.remember) {
+        Some((pool, lifetime)) => {
+            let value = remember::issue(
+                pool,
+                user.id,
+                user_agent.as_deref(),
+                ip.as_deref(),
+                lifetime,
+            )
+            .await?;
+            Some(sessions.set_remember_cookie(&value, lifetime))
+        }
+        None => None,
+    };
+    let (token, session) = sessions.create(user.id, user_agent, ip).await?;
+    let mut response = (StatusCode::CREATED, Json(describe(&user, &session))).into_response();
+    append_cookies(
+        &mut response,
+        std::iter::once(sessions.set_cookie(&token))
+            .chain(remembered)
+            .collect(),
+    );
+    Ok(response)
+}
+
+/// `GET /api/v1/session` - the logged-in user
+pub async fn current(
+    State(state): State<AppState>,
+    handle: SessionHandle,
+) -> ApiResult<Json<Value>> {
+    let session = handle.session();
+    let user = users::find_by_id(state.db.pool(), session.user_id)
+        .await?
+        .ok_or_else(|| ApiError::Unauthorized(t!("session-required")))?;
+    Ok(Json(describe(&user, &session)))
+}
+
+/// `DELETE /api/v1/session` - log out, clearing the session cookie
+pub async fn logout(
+    Ext(sessions): Ext<Sessions>,
+    handle: Option<SessionHandle>,
+    headers: HeaderMap,
+) -> ApiResult<Response> {
+    if let Some(handle) = handle {
+        sessions.store.delete(&handle.id()).await?;
+    }
+    let mut cookies = vec![sessions.clear_cookie()];
+    if let Some((pool, _)) = sessions.remember() {
+        if let Some(value) = cookie(&headers, &sessions.remember_cookie_name()) {
+            remember::forget(pool, value).await?;
+        }
+        cookies.push(sessions.clear_remember_cookie());
+    }
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    append_cookies(&mut response, cookies);
+    Ok(response)
+}
+
+/// `GET /api/v1/session/remembered` - devices with a remember-me token
+pub async fn list_remembered(
+    Ext(sessions): Ext<Sessions>,
+    handle: SessionHandle,
+) -> ApiResult<Json<Vec<remember::RememberedDevice>>> {
+    let devices = match sessions.remember() {
+        Some((pool, _)) => remember::list(pool, handle.user_id()).await?,
+        None => Vec::new(),
+    };
+    Ok(Json(devices))
+}
+
+/// `DELETE /api/v1/session/remembered/:selector` - log a device out
+pub async fn forget_remembered(
+    Ext(sessions): Ext<Sessions>,
+    handle: SessionHandle,
+    Path(selector): Path<String>,
+) -> ApiResult<StatusCode> {
+    let revoked = match sessions.remember() {
+        Some((pool, _)) => remember::revoke(pool, handle.user_id(), &selector).await?,
+        None => false,
+    };
+    if !revoked {
+        return Err(ApiError::NotFound(t!("remembered-device-not-found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /api/v1/me/sessions` - the user's live sessions, most recently seen first
+pub async fn list_sessions(
+    Ext(sessions): Ext<Sessions>,
+    handle: SessionHandle,
+) -> ApiResult<Json<Vec<Value>>> {
+    let current = handle.id();
+    let listed = sessions.store.list(handle.user_id()).await?;
+    Ok(Json(
+        listed
+            .iter()
+            .map(|session| {
+                json!({
+                    "id": session.id,
+                    "user_agent": session.user_agent,
+                    "ip": session.ip,
+                    "created_at": crate::time::format(&session.created_at),
+                    "last_seen_at": crate::time::format(&session.last_seen_at),
+                    "expires_at": crate::time::format(&session.expires_at),
+                    "current": session.id == current,
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// `DELETE /api/v1/me/sessions/:id` - end one of the user's sessions
+///
+/// Ending the current session logs out, like `DELETE /api/v1/session`.
+pub async fn revoke_session(
+    Ext(sessions): Ext<Sessions>,
+    handle: SessionHandle,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<Response> {
+    if id == handle.id() {
+        return logout(Ext(sessions), Some(handle), headers).await;
+    }
+    let owned = sessions
+        .store
+        .list(handle.user_id())
+        .await?
+        .iter()
+        .any(|session| session.id == id);
+    if !owned {
+        return Err(ApiError::NotFound(t!("session-not-found")));
+    }
+    sessions.store.delete(&id).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// `DELETE /api/v1/me/sessions` - log out everywhere but here
+///
+/// Also forgets every remembered device but this one, so the other devices
+/// cannot log straight back in.
+pub async fn revoke_other_sessions(
+    Ext(sessions): Ext<Sessions>,
+    handle: SessionHandle,
+    headers: HeaderMap,
+) -> ApiResult<Json<Value>> {
+    let current = handle.id();
+    let mut revoked = 0;
+    for session in sessions.store.list(handle.user_id()).await? {
+        if session.id != current {
+            sessions.store.delete(&session.id).await?;
+            revoked += 1;
+        }
+    }
+    let forgotten = match sessions.remember() {
+        Some((pool, _)) => {
+            let keep = cookie(&headers, &sessions.remember_cookie_name());
+            remember::revoke_others(pool, handle.user_id(), keep).await?
+        }
+        None => 0,
+    };
+    Ok(Json(json!({ "sessions": revoked, "devices": forgotten })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module;
+    use axum::{body::Body, middleware, routing::get};
+    use http_body_util::BodyExt;
+    use tower::Service;
+
+    pub(super) fn config(backend: SessionBackend) -> SessionConfig {
+        SessionConfig {
+            backend,
+            ttl: Duration::from_secs(3600),
+            max_age: Duration::from_secs(86400),
+            cookie_name: "session".into(),
+            cookie_secure: true,
+            cleanup_interval: Duration::from_secs(3600),
+            remember: None,
+        }
+    }
+
+    #[test]
+    fn test_reads_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("theme=dark; session=abc"));
+        headers.append(COOKIE, HeaderValue::from_static("other=1"));
+        assert_eq!(cookie(&headers, "session"), Some("abc"));
+        assert_eq!(cookie(&headers, "other"), Some("1"));
+        assert_eq!(cookie(&headers, "sess"), None);
+    }
+
+    #[tokio::test]
+    async fn test_postgres_sessions_slide_and_expire() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        module::run_migrations(pool, &[Arc::new(SessionsModule)])
+            .await
+            .unwrap();
+        let user = users::create(pool, "alice", "correct horse", false)
+            .await
+            .unwrap()
+            .unwrap();
+        let sessions = Sessions::new(
+            Arc::new(PgSessionStore::new(pool.clone())),
+            config(SessionBackend::Postgres),
+        );
+        let (token, session) = sessions
+            .create(user.id, Some("curl/8".into()), None)
+            .await
+            .unwrap();
+        assert_ne!(session.id, token);
+
+        let mut app = Router::new()
+            .route(
+                "/visits",
+                get(|handle: SessionHandle| async move {
+                    let visits = handle.get::<u32>("visits").unwrap_or(0) + 1;
+                    handle.insert("visits", visits).unwrap();
+                    visits.to_string()
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                sessions.clone(),
+                Sessions::middleware,
+            ));
+        let mut visit = |cookie: &str| {
+            let request = Request::get("/visits")
+                .header(COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap();
+            app.call(request)
+        };
+        let cookie = format!("session={}", token);
+        visit(&cookie).await.unwrap();
+        let response = visit(&cookie).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"2");
+        let response = visit("session=forged").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Stored as JSON (encrypted once ENCRYPTION_KEYS is set); the expiry
+        // slides up to the absolute limit
+        let (data,): (String,) = sqlx::query_as("SELECT data FROM sessions WHERE id = $1")
+            .bind(&session.id)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(data, r#"{"visits":2}"#);
+        sqlx::query(
+            "UPDATE sessions SET last_seen_at = now() - interval '2 hours',
+                created_at = now() - interval '23 hours 30 minutes'
+             WHERE id = $1",
+        )
+        .bind(&session.id)
+        .execute(pool)
+        .await
+        .unwrap();
+        visit(&cookie).await.unwrap();
+        let stored = sessions.store().load(&session.id).await.unwrap().unwrap();
+        let remaining = stored.expires_at - Utc::now();
+        assert!(remaining <= chrono::Duration::minutes(30), "{}", remaining);
+
+        assert_eq!(sessions.store().list(user.id).await.unwrap().len(), 1);
+        sqlx::query("UPDATE sessions SET expires_at = now() - interval '1 second'")
+            .execute(pool)
+            .await
+            .unwrap();
+        assert!(sessions.store().load(&session.id).await.unwrap().is_none());
+        assert_eq!(sessions.store().cleanup().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lists_and_revokes_other_sessions() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        module::run_migrations(pool, &[Arc::new(SessionsModule)])
+            .await
+            .unwrap();
+        users::create(pool, "alice", "correct horse", false)
+            .await
+            .unwrap()
+            .unwrap();
+        let sessions = Sessions::new(
+            Arc::new(PgSessionStore::new(pool.clone())),
+            config(SessionBackend::Postgres),
+        );
+        let state = AppState::new(crate::db::Database::from_pool(pool.clone()));
+        state.extensions.insert(sessions.clone());
+        let mut app =
+            SessionsModule
+                .routes()
+                .with_state(state)
+                .layer(middleware::from_fn_with_state(
+                    sessions,
+                    Sessions::middleware,
+                ));
+
+        let mut cookies = Vec::new();
+        for _ in 0..3 {
+            let request = Request::post("/api/v1/session")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    r#"{"username":"alice","password":"correct horse"}"#,
+                ))
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+            cookies.push(cookie.split(';').next().unwrap().to_string());
+        }
+        let mut send = |method: &str, uri: &str, cookie: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap();
+            app.call(request)
+        };
+
+        let response = send("GET", "/api/v1/me/sessions", &cookies[0])
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let listed: Vec<Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed.iter().filter(|s| s["current"] == true).count(), 1);
+
+        let other = listed.iter().find(|s| s["current"] == false).unwrap();
+        let uri = format!("/api/v1/me/sessions/{}", other["id"].as_str().unwrap());
+        let response = send("DELETE", &uri, &cookies[0]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = send("DELETE", &uri, &cookies[0]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = send("DELETE", "/api/v1/me/sessions", &cookies[0])
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let revoked: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(revoked["sessions"], 1);
+        for (i, cookie) in cookies.iter().enumerate() {
+            let response = send("GET", "/api/v1/session", cookie).await.unwrap();
+            let expected = if i == 0 {
+                StatusCode::OK
+            } else {
+                StatusCode::UNAUTHORIZED
+            };
+            assert_eq!(response.status(), expected);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Sessions kept in Redis, for `SESSION_STORE=redis://...`.
+//!
+//! Each session is one key, `session:{id}`, holding the encrypted JSON of
+//! the [`Session`] and expiring with it, so Redis does the cleanup. A set per
+//! user, `session-user:{user_id}`, indexes the ids for [`SessionStore::list`];
+//! ids whose key has expired are pruned from it when listed, and the set
+//! expires with the longest-lived of them. Needs Redis 7 or later.
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use tokio::sync::Mutex;
+
+use super::{Session, SessionStore};
+use crate::db::BoxFuture;
+use crate::encryption::key_ring;
+use crate::pubsub::redis::{Connection, RedisConfig, Value};
+
+/// Sessions stored in Redis over a single, lazily opened connection
+pub struct RedisSessionStore {
+    config: RedisConfig,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl RedisSessionStore {
+    pub fn new(config: RedisConfig) -> Self {
+        RedisSessionStore {
+            config,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Run a command, reconnecting first if the last one failed
+    async fn command(&self, args: &[&[u8]]) -> Result<Value> {
+        let mut connection = self.connection.lock().await;
+        if connection.is_none() {
+            *connection = Some(Connection::connect(&self.config).await?);
+        }
+        let result = connection
+            .as_mut()
+            .expect("connection was just opened")
+            .command(args)
+            .await;
+        if result.is_err() {
+            *connection = None;
+        }
+        result
+    }
+
+    async fn write(&self, session: &Session, only_existing: bool) -> Result<()> {
+        let Some(millis) = ttl_millis(session) else {
+            return self.delete_key(&session.id).await;
+        };
+        let value = encode(session)?;
+        let key = key(&session.id);
+        let millis = millis.to_string();
+        let mut args: Vec<&[u8]> = vec![
+            b"SET",
+            key.as_bytes(),
+            value.as_bytes(),
+            b"PX",
+            millis.as_bytes(),
+        ];
+        if only_existing {
+            args.push(b"XX");
+        }
+        if self.command(&args).await? != Value::Simple("OK".into()) {
+            // Deleted in the meantime
+            return Ok(());
+        }
+        // Keep the user's index at least as long as this session
+        let user_key = user_key(session.user_id);
+        for condition in [b"NX", b"GT"] {
+            self.command(&[
+                b"PEXPIRE",
+                user_key.as_bytes(),
+                millis.as_bytes(),
+                condition,
+            ])
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_key(&self, id: &str) -> Result<()> {
+        self.command(&[b"DEL", key(id).as_bytes()]).await?;
+        Ok(())
+    }
+}
+
+fn key(id: &str) -> String {
+    format!("session:{}", id)
+}
+
+fn user_key(user_id: i64) -> String {
+    format!("session-user:{}", user_id)
+}
+
+/// Milliseconds until a session expires, or `None` once it has
+fn ttl_millis(session: &Session) -> Option<i64> {
+    let millis = (session.expires_at - Utc::now()).num_milliseconds();
+    (millis > 0).then_some(millis)
+}
+
+fn encode(session: &Session) -> Result<String> {
+    key_ring().encrypt(&serde_json::to_string(session)?)
+}
+
+/// Decode a stored session, dropping it if already expired
+fn decode(stored: &[u8]) -> Result<Option<Session>> {
+    let stored = std::str::from_utf8(stored).context("Invalid session data")?;
+    let session: Session =
+        serde_json::from_str(&key_ring().decrypt(stored)?).context("Invalid session data")?;
+    Ok(ttl_millis(&session).map(|_| session))
+}
+
+impl SessionStore for RedisSessionStore {
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Session>>> {
+        Box::pin(async move {
+            match self.command(&[b"GET", key(id).as_bytes()]).await? {
+                Value::Bulk(Some(stored)) => decode(&stored),
+                Value::Bulk(None) => Ok(None),
+                other => bail!("Unexpected reply to GET: {:?}", other),
+            }
+        })
+    }
+
+    fn insert<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let user_key = user_key(session.user_id);
+            self.command(&[b"SADD", user_key.as_bytes(), session.id.as_bytes()])
+                .await?;
+            self.write(session, false).await
+        })
+    }
+
+    fn update<'a>(&'a self, session: &'a Session) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.write(session, true))
+    }
+
+    fn delete<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.delete_key(id))
+    }
+
+    fn list<'a>(&'a self, user_id: i64) -> BoxFuture<'a, Result<Vec<Session>>> {
+        Box::pin(async move {
+            let user_key = user_key(user_id);
+            let ids = match self.command(&[b"SMEMBERS", user_key.as_bytes()]).await? {
+                Value::Array(Some(ids)) => ids,
+                other => bail!("Unexpected reply to SMEMBERS: {:?}", other),
+            };
+            let mut sessions = Vec::new();
+            for id in ids {
+                let Value::Bulk(Some(id)) = id else {
+                    continue;
+                };
+                let id = String::from_utf8(id).context("Invalid session id")?;
+                match self.load(&id).await? {
+                    Some(session) => sessions.push(session),
+                    None => {
+                        self.command(&[b"SREM", user_key.as_bytes(), id.as_bytes()])
+                            .await?;
+                    }
+                }
+            }
+            sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen_at));
+            Ok(sessions)
+        })
+    }
+
+    fn cleanup(&self) -> BoxFuture<'_, Result<u64>> {
+        // Keys expire on their own
+        Box::pin(async { Ok(0) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Map;
+
+    #[test]
+    fn test_encodes_live_sessions_only() {
+        let now = Utc::now();
+        let mut session = Session {
+            id: "abc".into(),
+            user_id: 7,
+            data: Map::new(),
+            user_agent: Some("curl/8".into()),
+            ip: None,
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + chrono::Duration::hours(1),
+        };
+        session.data.insert("visits".into(), 3.into());
+        let stored = encode(&session).unwrap();
+        let decoded = decode(stored.as_bytes()).unwrap().unwrap();
+        assert_eq!(decoded.data, session.data);
+        assert_eq!(decoded.user_id, 7);
+        assert!(ttl_millis(&session).unwrap() > 3_500_000);
+
+        session.expires_at = now - chrono::Duration::seconds(1);
+        assert_eq!(ttl_millis(&session), None);
+        let stored = encode(&session).unwrap();
+        assert!(decode(stored.as_bytes()).unwrap().is_none());
+    }
+}
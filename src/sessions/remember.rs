@@ -0,0 +1,288 @@
+//! Remember-me tokens, for logins that outlive the session cookie.
+//!
+//! Logging in with `"remember": true` also sets a long-lived cookie holding
+//! `selector.validator`. The selector names the device's row in
+//! `remember_tokens`; the validator is only stored as its SHA-256. When a
+//! request comes without a live session, the session middleware redeems the
+//! cookie: it starts a new session and rotates the validator, so each token
+//! works once. A known selector with the wrong validator means an old token
+//! was replayed after rotation, so the device is logged out.
+//!
+//! A browser whose session expired often sends several requests at once
+//! with the same cookie. For [`ROTATION_GRACE`] after a rotation the
+//! previous validator is therefore still accepted, without rotating again;
+//! the response to the first request carries the new cookie.
+//!
+//! Tokens always live in Postgres, whatever the session backend.
+
+use anyhow::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use rand_core::RngCore;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::time::Duration;
+
+use crate::crypto::sha256_hex;
+
+/// How long the validator a token was rotated from stays valid
+pub const ROTATION_GRACE: Duration = Duration::from_secs(30);
+
+/// A device with a remember-me token
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct RememberedDevice {
+    /// Public half of the token, identifying the device
+    pub selector: String,
+    pub user_id: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub last_used_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub expires_at: DateTime<Utc>,
+}
+
+fn random(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    rand_core::OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Split a cookie value into selector and validator
+fn split(value: &str) -> Option<(&str, &str)> {
+    value
+        .split_once('.')
+        .filter(|(selector, validator)| !selector.is_empty() && !validator.is_empty())
+}
+
+fn expiry(lifetime: Duration) -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::from_std(lifetime).unwrap_or(chrono::Duration::MAX)
+}
+
+/// Remember a device, returning the cookie value
+pub async fn issue(
+    pool: &PgPool,
+    user_id: i64,
+    user_agent: Option<&str>,
+    ip: Option<&str>,
+    lifetime: Duration,
+) -> Result<String> {
+    let selector = random(12);
+    let validator = random(32);
+    sqlx::query(
+        "INSERT INTO remember_tokens
+            (selector, validator_hash, user_id, user_agent, ip, created_at, last_used_at, expires_at)
+         VALUES ($1, $2, $3, $4, $5, now(), now(), $6)",
+    )
+    .bind(&selector)
+    .bind(sha256_hex(&validator))
+    .bind(user_id)
+    .bind(user_agent)
+    .bind(ip)
+    .bind(expiry(lifetime))
+    .execute(pool)
+    .await?;
+    Ok(format!("{}.{}", selector, validator))
+}
+
+/// Redeem a cookie value, returning the user and the rotated cookie value
+///
+/// The rotated value is `None` when the previous validator was redeemed
+/// within [`ROTATION_GRACE`], as by requests sent in parallel; the cookie
+/// is left as the first of them set it. Returns `None` for unknown, expired
+/// or replayed tokens; a replayed token revokes its device.
+pub async fn redeem(
+    pool: &PgPool,
+    value: &str,
+    user_agent: Option<&str>,
+    ip: Option<&str>,
+    lifetime: Duration,
+) -> Result<Option<(i64, Option<String>)>> {
+    let Some((selector, validator)) = split(value) else {
+        return Ok(None);
+    };
+    let rotated = random(32);
+    let user_id: Option<i64> = sqlx::query_scalar(
+        "UPDATE remember_tokens SET validator_hash = $3, previous_validator_hash = validator_hash,
+            rotated_at = now(), last_used_at = now(), expires_at = $4,
+            user_agent = COALESCE($5, user_agent), ip = COALESCE($6, ip)
+         WHERE selector = $1 AND validator_hash = $2 AND expires_at > now()
+         RETURNING user_id",
+    )
+    .bind(selector)
+    .bind(sha256_hex(validator))
+    .bind(sha256_hex(&rotated))
+    .bind(expiry(lifetime))
+    .bind(user_agent)
+    .bind(ip)
+    .fetch_optional(pool)
+    .await?;
+    if let Some(user_id) = user_id {
+        return Ok(Some((user_id, Some(format!("{}.{}", selector, rotated)))));
+    }
+
+    let recent: Option<i64> = sqlx::query_scalar(
+        "SELECT user_id FROM remember_tokens
+         WHERE selector = $1 AND previous_validator_hash = $2 AND expires_at > now()
+           AND rotated_at > now() - make_interval(secs => $3)",
+    )
+    .bind(selector)
+    .bind(sha256_hex(validator))
+    .bind(ROTATION_GRACE.as_secs_f64())
+    .fetch_optional(pool)
+    .await?;
+    if let Some(user_id) = recent {
+        return Ok(Some((user_id, None)));
+    }
+
+    let replayed: Option<i64> = sqlx::query_scalar(
+        "DELETE FROM remember_tokens WHERE selector = $1 AND expires_at > now() RETURNING user_id",
+    )
+    .bind(selector)
+    .fetch_optional(pool)
+    .await?;
+    if let Some(user_id) = replayed {
+        tracing::warn!(
+            "🔐 A used remember-me token of user {} was replayed; logged the device out",
+            user_id
+        );
+    }
+    Ok(None)
+}
+
+/// Forget the device a cookie value belongs to, as on logout
+pub async fn forget(pool: &PgPool, value: &str) -> Result<()> {
+    let Some((selector, validator)) = split(value) else {
+        return Ok(());
+    };
+    sqlx::query("DELETE FROM remember_tokens WHERE selector = $1 AND validator_hash = $2")
+        .bind(selector)
+        .bind(sha256_hex(validator))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Remembered devices of a user, most recently used first
+pub async fn list(pool: &PgPool, user_id: i64) -> Result<Vec<RememberedDevice>> {
+    let devices = sqlx::query_as(
+        "SELECT selector, user_id, user_agent, ip, created_at, last_used_at, expires_at
+         FROM remember_tokens WHERE user_id = $1 AND expires_at > now()
+         ORDER BY last_used_at DESC",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(devices)
+}
+
+/// Revoke one of a user's devices, returning whether it existed
+pub async fn revoke(pool: &PgPool, user_id: i64, selector: &str) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM remember_tokens WHERE user_id = $1 AND selector = $2")
+        .bind(user_id)
+        .bind(selector)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revoke all of a user's devices except the one `keep` is the cookie of,
+/// returning how many were revoked
+pub async fn revoke_others(pool: &PgPool, user_id: i64, keep: Option<&str>) -> Result<u64> {
+    let keep = keep.and_then(split).map(|(selector, _)| selector);
+    let result = sqlx::query(
+        "DELETE FROM remember_tokens WHERE user_id = $1 AND selector IS DISTINCT FROM $2",
+    )
+    .bind(user_id)
+    .bind(keep)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Delete expired tokens, returning how many were removed
+pub async fn cleanup(pool: &PgPool) -> Result<u64> {
+    let result = sqlx::query("DELETE FROM remember_tokens WHERE expires_at <= now()")
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module;
+    use crate::sessions::SessionsModule;
+    use crate::users;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_rotates_and_revokes_replayed_tokens() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        module::run_migrations(pool, &[Arc::new(SessionsModule)])
+            .await
+            .unwrap();
+        let user = users::create(pool, "alice", "correct horse", false)
+            .await
+            .unwrap()
+            .unwrap();
+        let lifetime = Duration::from_secs(86400);
+        let first = issue(pool, user.id, Some("curl/8"), None, lifetime)
+            .await
+            .unwrap();
+        let (stored,): (String,) = sqlx::query_as("SELECT validator_hash FROM remember_tokens")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert!(!first.contains(&stored));
+
+        let (user_id, second) = redeem(pool, &first, None, Some("10.0.0.1"), lifetime)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user_id, user.id);
+        let second = second.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first.split('.').next(), second.split('.').next());
+        let devices = list(pool, user.id).await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].ip.as_deref(), Some("10.0.0.1"));
+
+        // Parallel requests with the previous token still get in, without
+        // rotating it again
+        let (user_id, rotated) = redeem(pool, &first, None, None, lifetime)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((user_id, rotated), (user.id, None));
+
+        // Replaying it after the grace period logs the device out
+        sqlx::query("UPDATE remember_tokens SET rotated_at = now() - interval '1 hour'")
+            .execute(pool)
+            .await
+            .unwrap();
+        assert!(redeem(pool, &first, None, None, lifetime)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(redeem(pool, &second, None, None, lifetime)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(list(pool, user.id).await.unwrap().is_empty());
+
+        let third = issue(pool, user.id, None, None, lifetime).await.unwrap();
+        let selector = third.split('.').next().unwrap();
+        assert!(!revoke(pool, user.id + 1, selector).await.unwrap());
+        assert!(revoke(pool, user.id, selector).await.unwrap());
+        assert!(redeem(pool, &third, None, None, lifetime)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
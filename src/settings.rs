@@ -0,0 +1,199 @@
+//! Runtime settings stored in the database.
+//!
+//! The `settings` table is a simple key/value store of JSON values for
+//! configuration that admins change at runtime without a restart. Keys are
+//! namespaced with dots (e.g. `scripts.strip-tracking`).
+//!
+//! Queries go through [`Db`] so the handlers can be tested against a mock.
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+    routing, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::db::{Db, Param};
+use crate::error::{ApiError, ApiResult};
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::AppState;
+
+/// A single stored setting
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Setting {
+    pub key: String,
+    pub value: Value,
+    #[serde(with = "crate::time::rfc3339")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Route module serving the settings admin API
+pub struct SettingsModule;
+
+impl RouteModule for SettingsModule {
+    fn name(&self) -> &'static str {
+        "settings"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/admin/settings", routing::get(list_settings))
+            .route(
+                "/admin/settings/:key",
+                routing::get(get_setting)
+                    .put(put_setting)
+                    .delete(delete_setting),
+            )
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_settings",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value JSONB NOT NULL,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        }]
+    }
+}
+
+/// Get a setting's value
+pub async fn get(db: &dyn Db, key: &str) -> Result<Option<Value>> {
+    let row: Option<Setting> = db
+        .fetch_optional_as(
+            "SELECT key, value, updated_at FROM settings WHERE key = $1",
+            vec![key.into()],
+        )
+        .await?;
+    Ok(row.map(|setting| setting.value))
+}
+
+/// List all settings whose key starts with `prefix`
+pub async fn list(db: &dyn Db, prefix: &str) -> Result<Vec<Setting>> {
+    db.fetch_all_as(
+        "SELECT key, value, updated_at FROM settings WHERE starts_with(key, $1) ORDER BY key",
+        vec![prefix.into()],
+    )
+    .await
+}
+
+/// Insert or replace a setting
+pub async fn set(db: &dyn Db, key: &str, value: &Value) -> Result<Setting> {
+    db.fetch_one_as(
+        "INSERT INTO settings (key, value) VALUES ($1, $2)
+         ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, updated_at = now()
+         RETURNING key, value, updated_at",
+        vec![key.into(), Param::Json(value.clone())],
+    )
+    .await
+}
+
+/// Delete a setting, returning whether it existed
+pub async fn delete(db: &dyn Db, key: &str) -> Result<bool> {
+    let deleted = db
+        .execute("DELETE FROM settings WHERE key = $1", vec![key.into()])
+        .await?;
+    Ok(deleted > 0)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    #[serde(default)]
+    prefix: String,
+}
+
+/// `GET /admin/settings` - list settings, optionally filtered by prefix
+pub async fn list_settings(
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<Vec<Setting>>> {
+    Ok(Json(list(state.db.queries(), &query.prefix).await?))
+}
+
+/// `GET /admin/settings/:key` - fetch a single setting
+pub async fn get_setting(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> ApiResult<Json<Value>> {
+    get(state.db.queries(), &key)
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(format!("setting '{}' not found", key)))
+}
+
+/// `PUT /admin/settings/:key` - store a setting
+pub async fn put_setting(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Json(value): Json<Value>,
+) -> ApiResult<Json<Setting>> {
+    Ok(Json(set(state.db.queries(), &key, &value).await?))
+}
+
+/// `DELETE /admin/settings/:key` - remove a setting
+pub async fn delete_setting(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> ApiResult<StatusCode> {
+    if delete(state.db.queries(), &key).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound(format!("setting '{}' not found", key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::mock_db::MockDb;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn state(mock: &MockDb) -> State<AppState> {
+        State(AppState::new(Database::from_db(Arc::new(mock.clone()))))
+    }
+
+    #[tokio::test]
+    async fn test_handlers_issue_expected_queries() {
+        let mock = MockDb::new();
+        mock.returns(
+            "INSERT INTO settings",
+            vec![json!({ "key": "theme", "value": "dark", "updated_at": "2026-01-01T00:00:00Z" })],
+        );
+        let Json(setting) = put_setting(state(&mock), Path("theme".into()), Json(json!("dark")))
+            .await
+            .unwrap();
+        assert_eq!(setting.value, json!("dark"));
+
+        let missing = get_setting(state(&mock), Path("theme".into())).await;
+        assert!(matches!(missing, Err(ApiError::NotFound(_))));
+
+        mock.affects("DELETE FROM settings", 1);
+        let status = delete_setting(state(&mock), Path("theme".into()))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let statements = mock.statements();
+        assert_eq!(statements.len(), 3);
+        assert!(statements[0]
+            .sql
+            .starts_with("INSERT INTO settings (key, value)"));
+        assert_eq!(
+            statements[0].params,
+            vec![Param::Text("theme".into()), Param::Json(json!("dark"))]
+        );
+        assert_eq!(statements[2].params, vec![Param::Text("theme".into())]);
+    }
+}
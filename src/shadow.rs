@@ -0,0 +1,299 @@
+//! Traffic shadowing.
+//!
+//! With `SHADOW_UPSTREAM` set, `SHADOW_PERCENT` of requests whose method is
+//! in `SHADOW_METHODS` are copied to a second instance, e.g. a new version
+//! under evaluation, after the real response has been produced. The
+//! shadow's responses are discarded; only whether its status matched is
+//! counted, and mismatches are logged. Counters are at `GET /admin/shadow`.
+//!
+//! Shadowing never delays or fails real requests: copies are sent in the
+//! background, at most `SHADOW_CONCURRENCY` at a time, and requests with
+//! bodies over `SHADOW_MAX_BODY` or of unknown length are not copied.
+//! Copies carry `X-Shadow-Request: 1` and are never copied again.
+//! Mirroring writes (`SHADOW_METHODS=*`) only makes sense when the shadow
+//! has its own database.
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Request, State},
+    http::{header, request::Parts, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use http_body_util::Full;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::extensions::Ext;
+use crate::http_client::HttpClient;
+use crate::module::{RouteGroup, RouteModule};
+use crate::{capture, faults, proxy, AppState};
+
+/// Header marking a shadowed copy
+pub const SHADOW_HEADER: HeaderName = HeaderName::from_static("x-shadow-request");
+
+/// Shadowing settings, from configuration
+#[derive(Debug, Clone)]
+pub struct ShadowConfig {
+    /// Base URL of the shadow instance, e.g. `http://10.0.0.5:3000`
+    pub upstream: String,
+    /// Percentage of matching requests copied
+    pub percent: f64,
+    /// Methods copied; empty for all
+    pub methods: Vec<Method>,
+    /// Largest request body copied, in bytes
+    pub max_body: u64,
+    pub timeout: Duration,
+    /// Copies in flight at once; more are skipped
+    pub concurrency: usize,
+}
+
+impl ShadowConfig {
+    /// Whether requests with `method` are copied
+    fn copies(&self, method: &Method) -> bool {
+        self.methods.is_empty() || self.methods.contains(method)
+    }
+}
+
+/// Parse `SHADOW_METHODS`: comma-separated methods, or `*` for all
+pub fn parse_methods(input: &str) -> Result<Vec<Method>> {
+    if input.trim() == "*" {
+        return Ok(Vec::new());
+    }
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|method| !method.is_empty())
+        .map(|method| {
+            Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| anyhow::anyhow!("invalid method '{}'", method))
+        })
+        .collect::<Result<Vec<_>>>()
+        .and_then(|methods| {
+            anyhow::ensure!(!methods.is_empty(), "expected methods or *");
+            Ok(methods)
+        })
+}
+
+/// What happened to shadowed copies
+#[derive(Debug, Default, Serialize)]
+pub struct ShadowStats {
+    /// Copies whose status matched the real response
+    pub matched: AtomicU64,
+    /// Copies answered with a different status
+    pub mismatched: AtomicU64,
+    /// Copies that failed or timed out
+    pub errors: AtomicU64,
+    /// Sampled requests not copied: too large, or too many in flight
+    pub skipped: AtomicU64,
+}
+
+/// Shared shadowing state and the middleware copying requests
+#[derive(Clone)]
+pub struct Shadow {
+    config: Arc<ShadowConfig>,
+    client: HttpClient,
+    slots: Arc<Semaphore>,
+    stats: Arc<ShadowStats>,
+}
+
+impl Shadow {
+    pub fn new(config: ShadowConfig) -> Self {
+        Shadow {
+            client: HttpClient::new(config.timeout),
+            slots: Arc::new(Semaphore::new(config.concurrency)),
+            config: Arc::new(config),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Counters since startup
+    pub fn stats(&self) -> &ShadowStats {
+        &self.stats
+    }
+
+    /// Middleware copying a sample of requests to the shadow upstream
+    pub async fn middleware(
+        State(shadow): State<Shadow>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        // Copies are never copied again, so two instances cannot loop
+        if !shadow.config.copies(request.method())
+            || request.headers().contains_key(&SHADOW_HEADER)
+            || faults::random_unit() * 100.0 >= shadow.config.percent
+        {
+            return next.run(request).await;
+        }
+        let (parts, body) = request.into_parts();
+        let (body, copy) = match capture::buffer(body, shadow.config.max_body).await {
+            Ok(buffered) => buffered,
+            Err(e) => {
+                tracing::debug!("Failed to read request body for shadowing: {}", e);
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        };
+        let copy = copy.and_then(|body| shadow.prepare(&parts, body).ok());
+        let response = next.run(Request::from_parts(parts, body)).await;
+
+        let (Some(copy), Ok(permit)) = (copy, shadow.slots.clone().try_acquire_owned()) else {
+            shadow.stats.skipped.fetch_add(1, Ordering::Relaxed);
+            return response;
+        };
+        let status = response.status();
+        tokio::spawn(async move {
+            let (method, uri) = (copy.method().clone(), copy.uri().path().to_string());
+            match shadow.client.send(copy).await {
+                Ok((shadow_status, _, _)) if shadow_status == status => {
+                    shadow.stats.matched.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok((shadow_status, _, _)) => {
+                    shadow.stats.mismatched.fetch_add(1, Ordering::Relaxed);
+                    tracing::info!(
+                        "👥 Shadow answered {} {} with {}, expected {}",
+                        method,
+                        uri,
+                        shadow_status.as_u16(),
+                        status.as_u16()
+                    );
+                }
+                Err(e) => {
+                    shadow.stats.errors.fetch_add(1, Ordering::Relaxed);
+                    tracing::debug!("Shadow request failed: {:#}", e);
+                }
+            }
+            drop(permit);
+        });
+        response
+    }
+
+    /// The copy of a request to send to the shadow
+    fn prepare(&self, parts: &Parts, body: Bytes) -> Result<hyper::Request<Full<Bytes>>> {
+        let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+        let mut headers = parts.headers.clone();
+        proxy::strip_hop_by_hop(&mut headers);
+        headers.remove(header::HOST);
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(SHADOW_HEADER, HeaderValue::from_static("1"));
+
+        let mut request = hyper::Request::builder()
+            .method(parts.method.clone())
+            .uri(format!("{}{}", self.config.upstream, path))
+            .body(Full::new(body))?;
+        *request.headers_mut() = headers;
+        Ok(request)
+    }
+}
+
+/// Route module reporting shadowing counters, mounted with `SHADOW_UPSTREAM`
+pub struct ShadowModule;
+
+impl RouteModule for ShadowModule {
+    fn name(&self) -> &'static str {
+        "shadow"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/admin/shadow", get(status))
+    }
+}
+
+/// `GET /admin/shadow` - where traffic is copied and how the copies fared
+pub async fn status(Ext(shadow): Ext<Shadow>) -> Json<serde_json::Value> {
+    let config = &shadow.config;
+    Json(serde_json::json!({
+        "upstream": config.upstream,
+        "percent": config.percent,
+        "methods": config.methods.iter().map(Method::as_str).collect::<Vec<_>>(),
+        "in_flight": config.concurrency - shadow.slots.available_permits(),
+        "stats": shadow.stats(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::post};
+    use tokio::net::TcpListener;
+    use tower::Service;
+
+    #[test]
+    fn test_parse_methods() {
+        assert_eq!(
+            parse_methods("get, HEAD").unwrap(),
+            [Method::GET, Method::HEAD]
+        );
+        assert!(parse_methods("*").unwrap().is_empty());
+        assert!(parse_methods("").is_err());
+        assert!(parse_methods("GET,BAD METHOD").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_copies_requests_to_the_shadow() {
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let upstream = Router::new().route(
+            "/items",
+            post(move |request: Request| {
+                let sender = sender.clone();
+                async move {
+                    let marked = request.headers().contains_key(&SHADOW_HEADER);
+                    let query = request.uri().query().map(String::from);
+                    let body = axum::body::to_bytes(request.into_body(), 1024)
+                        .await
+                        .unwrap();
+                    sender.send((marked, query, body)).unwrap();
+                    StatusCode::CONFLICT
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let shadow = Shadow::new(ShadowConfig {
+            upstream: format!("http://{}", addr),
+            percent: 100.0,
+            methods: Vec::new(),
+            max_body: 1024,
+            timeout: Duration::from_secs(5),
+            concurrency: 4,
+        });
+        let mut app = Router::new()
+            .route("/items", post(|| async { "created" }))
+            .layer(middleware::from_fn_with_state(
+                shadow.clone(),
+                Shadow::middleware,
+            ));
+        let response = app
+            .call(
+                Request::post("/items?x=1")
+                    .body(Body::from("payload"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (marked, query, body) = received.recv().await.unwrap();
+        assert!(marked);
+        assert_eq!(query.as_deref(), Some("x=1"));
+        assert_eq!(body, "payload");
+        for _ in 0..50 {
+            if shadow.stats().mismatched.load(Ordering::Relaxed) == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(shadow.stats().mismatched.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,387 @@
+//! URL shortener.
+//!
+//! Enabled with `SHORTLINKS=true`. Links are managed through the admin API
+//! and resolved by the public `GET /s/:slug`, which answers with a
+//! `302 Found` so every visit is counted:
+//!
+//! - `POST /admin/shortlinks` `{"target": "https://...", "slug": "docs", "expires_in": "30d"}`
+//!   creates a link, with a random slug unless one is given
+//! - `GET /admin/shortlinks` lists links with their hit counts
+//! - `GET /admin/shortlinks/:slug/stats` reports daily hits and top referrers
+//! - `DELETE /admin/shortlinks/:slug` removes a link and its history
+//!
+//! Every visit is also recorded in `shortlink_hits`; add a
+//! `RETENTION_POLICIES` entry for that table to bound its growth.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{LOCATION, REFERER},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::time::Duration;
+
+use crate::auth::random_token;
+use crate::config::parse_duration;
+use crate::error::{ApiError, ApiResult};
+use crate::fetch::is_web_url;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::{t, AppState};
+
+/// Longest referrer kept per hit
+const MAX_REFERRER_LEN: usize = 512;
+
+/// Days covered by the daily hit counts
+const STATS_DAYS: i32 = 30;
+
+/// A short link
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Shortlink {
+    pub slug: String,
+    pub target: String,
+    pub hits: i64,
+    #[serde(with = "crate::time::rfc3339_option")]
+    pub last_hit_at: Option<DateTime<Utc>>,
+    #[serde(with = "crate::time::rfc3339_option")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Route module managing links, mounted when `SHORTLINKS` is on
+pub struct ShortlinksModule;
+
+impl RouteModule for ShortlinksModule {
+    fn name(&self) -> &'static str {
+        "shortlinks"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/admin/shortlinks", get(list_links).post(create_link))
+            .route("/admin/shortlinks/:slug", get(get_link).delete(delete_link))
+            .route("/admin/shortlinks/:slug/stats", get(link_stats))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_shortlinks",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS shortlinks (
+                slug TEXT PRIMARY KEY,
+                target TEXT NOT NULL,
+                hits BIGINT NOT NULL DEFAULT 0,
+                last_hit_at TIMESTAMPTZ,
+                expires_at TIMESTAMPTZ,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE TABLE IF NOT EXISTS shortlink_hits (
+                id BIGSERIAL PRIMARY KEY,
+                slug TEXT NOT NULL REFERENCES shortlinks (slug) ON DELETE CASCADE,
+                referrer TEXT,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE INDEX IF NOT EXISTS shortlink_hits_slug ON shortlink_hits (slug, created_at)",
+        }]
+    }
+}
+
+/// Route module resolving `/s/:slug`, mounted when `SHORTLINKS` is on
+pub struct ShortlinkRedirectModule;
+
+impl RouteModule for ShortlinkRedirectModule {
+    fn name(&self) -> &'static str {
+        "shortlink_redirect"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/s/:slug", get(redirect))
+    }
+}
+
+/// Check a custom slug
+fn valid_slug(slug: &str) -> bool {
+    (1..=64).contains(&slug.len())
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+}
+
+/// A link about to be created
+#[derive(Debug, Default, Deserialize)]
+pub struct NewShortlink {
+    pub target: String,
+    #[serde(default)]
+    pub slug: Option<String>,
+    /// Lifetime such as `1h` or `30d`; links without one never expire
+    #[serde(default)]
+    pub expires_in: Option<String>,
+}
+
+/// Check and store a link, returning `None` if the slug is taken
+pub async fn create(pool: &PgPool, new: &NewShortlink) -> ApiResult<Option<Shortlink>> {
+    if !is_web_url(&new.target) {
+        return Err(ApiError::Validation(t!(
+            "shortlink-target",
+            target = new.target.as_str()
+        )));
+    }
+    if let Some(slug) = new.slug.as_deref().filter(|s| !valid_slug(s)) {
+        return Err(ApiError::Validation(t!("shortlink-slug", slug = slug)));
+    }
+    let expires_in: Option<Duration> = new
+        .expires_in
+        .as_deref()
+        .map(|input| {
+            parse_duration(input)
+                .ok()
+                .filter(|d| !d.is_zero())
+                .ok_or_else(|| ApiError::Validation(t!("shortlink-expiry", expires_in = input)))
+        })
+        .transpose()?;
+    let slug = new.slug.clone().unwrap_or_else(|| random_token(5));
+    let link = sqlx::query_as::<_, Shortlink>(
+        "INSERT INTO shortlinks (slug, target, expires_at)
+         VALUES ($1, $2, now() + make_interval(secs => $3))
+         ON CONFLICT (slug) DO NOTHING
+         RETURNING *",
+    )
+    .bind(&slug)
+    .bind(&new.target)
+    .bind(expires_in.map(|d| d.as_secs_f64()))
+    .fetch_optional(pool)
+    .await?;
+    Ok(link)
+}
+
+/// Count a visit to an unexpired link, returning its target
+pub async fn resolve(pool: &PgPool, slug: &str, referrer: Option<&str>) -> ApiResult<String> {
+    let referrer = referrer.map(|r| match r.char_indices().nth(MAX_REFERRER_LEN) {
+        Some((end, _)) => &r[..end],
+        None => r,
+    });
+    let target: Option<String> = sqlx::query_scalar(
+        "WITH link AS (
+            UPDATE shortlinks SET hits = hits + 1, last_hit_at = now()
+            WHERE slug = $1 AND (expires_at IS NULL OR expires_at > now())
+            RETURNING slug, target
+        ), hit AS (
+            INSERT INTO shortlink_hits (slug, referrer) SELECT slug, $2 FROM link
+        )
+        SELECT target FROM link",
+    )
+    .bind(slug)
+    .bind(referrer)
+    .fetch_optional(pool)
+    .await?;
+    target.ok_or_else(|| ApiError::NotFound(t!("shortlink-not-found")))
+}
+
+async fn find(pool: &PgPool, slug: &str) -> ApiResult<Shortlink> {
+    sqlx::query_as::<_, Shortlink>("SELECT * FROM shortlinks WHERE slug = $1")
+        .bind(slug)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(t!("shortlink-not-found")))
+}
+
+/// `GET /s/:slug` - redirect to a link's target
+pub async fn redirect(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+    headers: HeaderMap,
+) -> ApiResult<Response> {
+    let referrer = headers.get(REFERER).and_then(|value| value.to_str().ok());
+    let target = resolve(state.db.pool(), &slug, referrer).await?;
+    Ok((StatusCode::FOUND, [(LOCATION, target)]).into_response())
+}
+
+/// `POST /admin/shortlinks` - create a link
+pub async fn create_link(
+    State(state): State<AppState>,
+    Json(body): Json<NewShortlink>,
+) -> ApiResult<(StatusCode, Json<Shortlink>)> {
+    create(state.db.pool(), &body)
+        .await?
+        .map(|link| (StatusCode::CREATED, Json(link)))
+        .ok_or_else(|| {
+            ApiError::Validation(t!("shortlink-exists", slug = body.slug.unwrap_or_default()))
+        })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+/// `GET /admin/shortlinks` - links, newest first
+pub async fn list_links(
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> ApiResult<Json<Vec<Shortlink>>> {
+    let links = sqlx::query_as::<_, Shortlink>(
+        "SELECT * FROM shortlinks ORDER BY created_at DESC, slug LIMIT $1 OFFSET $2",
+    )
+    .bind(query.limit.clamp(1, 1000))
+    .bind(query.offset.max(0))
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(links))
+}
+
+/// `GET /admin/shortlinks/:slug` - one link
+pub async fn get_link(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> ApiResult<Json<Shortlink>> {
+    Ok(Json(find(state.db.pool(), &slug).await?))
+}
+
+/// `DELETE /admin/shortlinks/:slug` - remove a link and its hits
+pub async fn delete_link(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> ApiResult<StatusCode> {
+    let result = sqlx::query("DELETE FROM shortlinks WHERE slug = $1")
+        .bind(&slug)
+        .execute(state.db.pool())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(t!("shortlink-not-found")));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Hits of one day
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct DailyHits {
+    pub day: NaiveDate,
+    pub hits: i64,
+}
+
+/// Hits from one referrer
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ReferrerHits {
+    pub referrer: Option<String>,
+    pub hits: i64,
+}
+
+/// Analytics of a link
+#[derive(Debug, Serialize)]
+pub struct LinkStats {
+    #[serde(flatten)]
+    pub link: Shortlink,
+    /// Hits per UTC day over the last 30 days, days without hits left out
+    pub daily: Vec<DailyHits>,
+    pub top_referrers: Vec<ReferrerHits>,
+}
+
+/// `GET /admin/shortlinks/:slug/stats` - daily hits and top referrers
+pub async fn link_stats(
+    State(state): State<AppState>,
+    Path(slug): Path<String>,
+) -> ApiResult<Json<LinkStats>> {
+    let pool = state.db.pool();
+    let link = find(pool, &slug).await?;
+    let daily = sqlx::query_as::<_, DailyHits>(
+        "SELECT (created_at AT TIME ZONE 'UTC')::date AS day, count(*) AS hits
+         FROM shortlink_hits
+         WHERE slug = $1 AND created_at > now() - make_interval(days => $2)
+         GROUP BY day ORDER BY day",
+    )
+    .bind(&slug)
+    .bind(STATS_DAYS)
+    .fetch_all(pool)
+    .await?;
+    let top_referrers = sqlx::query_as::<_, ReferrerHits>(
+        "SELECT referrer, count(*) AS hits FROM shortlink_hits
+         WHERE slug = $1 GROUP BY referrer ORDER BY hits DESC, referrer LIMIT 10",
+    )
+    .bind(&slug)
+    .fetch_all(pool)
+    .await?;
+    Ok(Json(LinkStats {
+        link,
+        daily,
+        top_referrers,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_slug() {
+        assert!(valid_slug("docs") && valid_slug("Q3-report_v2"));
+        assert!(!valid_slug("") && !valid_slug("a/b") && !valid_slug(&"x".repeat(65)));
+    }
+
+    #[tokio::test]
+    async fn test_create_resolve_and_expire() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        crate::module::run_migrations(pool, &[std::sync::Arc::new(ShortlinksModule)])
+            .await
+            .unwrap();
+
+        let new = NewShortlink {
+            target: "https://example.com/docs".into(),
+            slug: Some("docs".into()),
+            expires_in: None,
+        };
+        let link = create(pool, &new).await.unwrap().unwrap();
+        assert_eq!(link.hits, 0);
+        assert!(create(pool, &new).await.unwrap().is_none());
+
+        let target = resolve(pool, "docs", Some("https://news.example")).await;
+        assert_eq!(target.unwrap(), "https://example.com/docs");
+        resolve(pool, "docs", None).await.unwrap();
+        assert_eq!(find(pool, "docs").await.unwrap().hits, 2);
+
+        let random = create(
+            pool,
+            &NewShortlink {
+                target: "https://example.com".into(),
+                expires_in: Some("1h".into()),
+                ..NewShortlink::default()
+            },
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(valid_slug(&random.slug));
+        sqlx::query("UPDATE shortlinks SET expires_at = now() - interval '1 second'")
+            .execute(pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            resolve(pool, &random.slug, None).await,
+            Err(ApiError::NotFound(_))
+        ));
+    }
+}
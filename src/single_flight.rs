@@ -0,0 +1,256 @@
+//! In-flight deduplication of identical GET requests.
+//!
+//! When many clients refresh the same page at once, every request would
+//! otherwise run the same queries. With the `single_flight` middleware, a
+//! GET arriving while an identical one is still being handled waits for it
+//! and receives a copy of its response instead of running the handler
+//! again. Nothing is cached: once the first request finishes, the next one
+//! runs afresh.
+//!
+//! Requests are identical when their path, query and headers match,
+//! ignoring headers that only describe the client or the connection such as
+//! `User-Agent` or `X-Request-Id`, so credentials, cookies and content
+//! negotiation all keep responses apart. List the layer after `auth` and
+//! `rate_limit` so those still run for every request. Streams
+//! (`text/event-stream`, WebSocket upgrades) and responses over 1MB are not
+//! shared; waiting requests then run on their own.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_TYPE, UPGRADE},
+        HeaderMap, Method, StatusCode,
+    },
+    middleware::Next,
+    response::Response,
+};
+use futures_util::{stream, StreamExt};
+use http_body_util::BodyExt;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Largest response body copied to waiting requests
+const MAX_BODY: usize = 1 << 20;
+/// Request headers that do not change the response
+const IGNORED_HEADERS: &[&str] = &[
+    "user-agent",
+    "referer",
+    "dnt",
+    "priority",
+    "connection",
+    "keep-alive",
+    "te",
+    "x-request-id",
+    "traceparent",
+    "tracestate",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+];
+
+type Key = [u8; 32];
+/// Requests being handled, each with a receiver for its response
+type Flights = Mutex<HashMap<Key, watch::Receiver<Option<Arc<Shared>>>>>;
+
+/// A finished response, as handed to the requests that waited for it
+#[derive(Debug)]
+struct Shared {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// Requests currently being handled, by key
+#[derive(Debug, Clone, Default)]
+pub struct SingleFlight {
+    flights: Arc<Flights>,
+}
+
+/// Removes a flight once its leader finishes or is cancelled
+struct Landing<'a> {
+    flights: &'a Flights,
+    key: Key,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        self.flights
+            .lock()
+            .expect("single-flight lock poisoned")
+            .remove(&self.key);
+    }
+}
+
+fn key(request: &Request) -> Key {
+    let mut hasher = Sha256::new();
+    let uri = request.uri();
+    hasher.update(uri.path_and_query().map_or(uri.path(), |pq| pq.as_str()));
+    let mut headers: Vec<_> = request
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            !IGNORED_HEADERS.contains(&name.as_str()) && !name.as_str().starts_with("sec-")
+        })
+        .collect();
+    headers.sort_by(|a, b| (a.0.as_str(), a.1.as_bytes()).cmp(&(b.0.as_str(), b.1.as_bytes())));
+    for (name, value) in headers {
+        // Length-prefixed, so no two header lists hash the same input
+        for part in [name.as_str().as_bytes(), value.as_bytes()] {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        }
+    }
+    hasher.finalize().into()
+}
+
+impl SingleFlight {
+    /// Middleware coalescing identical concurrent GETs
+    pub async fn middleware(
+        State(single_flight): State<SingleFlight>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        if request.method() != Method::GET || request.headers().contains_key(UPGRADE) {
+            return next.run(request).await;
+        }
+        let key = key(&request);
+        let leader = {
+            let mut flights = single_flight
+                .flights
+                .lock()
+                .expect("single-flight lock poisoned");
+            match flights.get(&key) {
+                Some(receiver) => Err(receiver.clone()),
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    flights.insert(key, receiver);
+                    Ok(sender)
+                }
+            }
+        };
+        let sender = match leader {
+            Ok(sender) => sender,
+            Err(mut receiver) => {
+                // The leader drops the sender without a response it can share
+                let shared = match receiver.wait_for(Option::is_some).await {
+                    Ok(shared) => shared.clone(),
+                    Err(_) => None,
+                };
+                return match shared {
+                    Some(shared) => {
+                        let mut response = Response::new(Body::from(shared.body.clone()));
+                        *response.status_mut() = shared.status;
+                        *response.headers_mut() = shared.headers.clone();
+                        response
+                    }
+                    None => next.run(request).await,
+                };
+            }
+        };
+        let _landing = Landing {
+            flights: &single_flight.flights,
+            key,
+        };
+
+        let response = next.run(request).await;
+        let is_stream = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream"));
+        if is_stream {
+            return response;
+        }
+        let (parts, mut body) = response.into_parts();
+        let mut buffered = Vec::new();
+        while let Some(frame) = body.frame().await {
+            let data = match frame {
+                Ok(frame) => frame.into_data().unwrap_or_default(),
+                Err(e) => {
+                    // Pass the failure on to the leader's client only
+                    let chunks = [Ok(Bytes::from(buffered)), Err(e)];
+                    return Response::from_parts(parts, Body::from_stream(stream::iter(chunks)));
+                }
+            };
+            buffered.extend_from_slice(&data);
+            if buffered.len() > MAX_BODY {
+                let head = stream::once(async move { Ok(Bytes::from(buffered)) });
+                let body = Body::from_stream(head.chain(body.into_data_stream()));
+                return Response::from_parts(parts, body);
+            }
+        }
+        let body = Bytes::from(buffered);
+        sender.send_replace(Some(Arc::new(Shared {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        })));
+        Response::from_parts(parts, Body::from(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::get, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tower::Service;
+
+    #[tokio::test]
+    async fn test_coalesces_identical_concurrent_gets() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let app = Router::new()
+            .route(
+                "/items",
+                get(move || {
+                    let runs = counter.clone();
+                    async move {
+                        let run = runs.fetch_add(1, Ordering::SeqCst) + 1;
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        format!("run {}", run)
+                    }
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                SingleFlight::default(),
+                SingleFlight::middleware,
+            ));
+        let get = |uri: &str, token: &str| {
+            let mut app = app.clone();
+            let request = Request::get(uri)
+                .header("authorization", token)
+                .header("x-request-id", uri.len().to_string() + token)
+                .body(Body::empty())
+                .unwrap();
+            async move {
+                let response = app.call(request).await.unwrap();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let bodies = futures_util::future::join_all([
+            get("/items?page=1", "Bearer a"),
+            get("/items?page=1", "Bearer a"),
+            get("/items?page=1", "Bearer a"),
+            get("/items?page=2", "Bearer a"),
+            get("/items?page=1", "Bearer b"),
+        ])
+        .await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(bodies[0], bodies[1]);
+        assert_eq!(bodies[1], bodies[2]);
+        assert_ne!(bodies[0], bodies[3]);
+        assert_ne!(bodies[0], bodies[4]);
+
+        // Nothing is kept once the first request has finished
+        get("/items?page=1", "Bearer a").await;
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,291 @@
+//! Post-deploy smoke checks against a running instance.
+//!
+//! [`SmokeTest`] exercises a deployed server over HTTP the way a client
+//! would: the health endpoints, an admin auth round trip, and a
+//! create/read/delete cycle on a scratch setting that is removed again.
+//! Given database access it also reports migrations the deployment has not
+//! applied. Checks needing the admin token or the database are skipped
+//! without them.
+
+use anyhow::Result;
+use hyper::{Method, StatusCode};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::http_client::HttpClient;
+use crate::module::{self, MigrationKind, RouteModule};
+
+/// Result of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    Skip,
+}
+
+/// A check's outcome with what was observed
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+    pub duration_ms: u64,
+}
+
+/// Why a check did not pass
+pub(crate) enum Unmet {
+    Failed(String),
+    Skipped(String),
+}
+
+impl From<anyhow::Error> for Unmet {
+    fn from(e: anyhow::Error) -> Self {
+        Unmet::Failed(format!("{:#}", e))
+    }
+}
+
+pub(crate) type CheckResult = std::result::Result<String, Unmet>;
+
+/// A battery of checks against one instance
+pub struct SmokeTest {
+    base_url: String,
+    client: HttpClient,
+    admin_token: Option<String>,
+    database: Option<(PgPool, Vec<Arc<dyn RouteModule>>)>,
+}
+
+impl SmokeTest {
+    /// Check the server at `base_url`, e.g. `http://127.0.0.1:3000`
+    pub fn new(base_url: &str, timeout: Duration) -> Self {
+        SmokeTest {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: HttpClient::new(timeout),
+            admin_token: None,
+            database: None,
+        }
+    }
+
+    /// Enable the admin auth and CRUD checks
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Enable the migration check for the deployment's modules
+    pub fn database(mut self, pool: PgPool, modules: Vec<Arc<dyn RouteModule>>) -> Self {
+        self.database = Some((pool, modules));
+        self
+    }
+
+    /// Run every check in order
+    pub async fn run(&self) -> Vec<Check> {
+        vec![
+            timed("health", self.expect_ok("/health")).await,
+            timed("ready", self.expect_ok("/health/ready")).await,
+            timed("database", self.expect_ok("/health/db")).await,
+            timed("auth", self.auth()).await,
+            timed("crud", self.crud()).await,
+            timed("migrations", self.migrations()).await,
+        ]
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    fn admin(&self) -> std::result::Result<HttpClient, Unmet> {
+        let token = self
+            .admin_token
+            .as_deref()
+            .ok_or_else(|| Unmet::Skipped("no admin token".to_string()))?;
+        Ok(self.client.bearer(token)?)
+    }
+
+    async fn expect_ok(&self, path: &str) -> CheckResult {
+        let (status, body) = self.client.json(Method::GET, &self.url(path), None).await?;
+        let detail = match body["status"].as_str() {
+            Some(reported) => format!("{} ({})", status, reported),
+            None => status.to_string(),
+        };
+        if status == StatusCode::OK {
+            Ok(detail)
+        } else {
+            Err(Unmet::Failed(detail))
+        }
+    }
+
+    async fn auth(&self) -> CheckResult {
+        let admin = self.admin()?;
+        let url = self.url("/admin/settings");
+        let (anonymous, _) = self.client.request(Method::GET, &url, None).await?;
+        if anonymous != StatusCode::UNAUTHORIZED {
+            return Err(Unmet::Failed(format!(
+                "unauthenticated request got {}, expected 401",
+                anonymous
+            )));
+        }
+        let (status, _) = admin.request(Method::GET, &url, None).await?;
+        if status != StatusCode::OK {
+            return Err(Unmet::Failed(format!(
+                "admin token rejected with {}",
+                status
+            )));
+        }
+        Ok("401 without token, 200 with".to_string())
+    }
+
+    async fn crud(&self) -> CheckResult {
+        let admin = self.admin()?;
+        let key = format!(
+            "smoke.{:016x}",
+            rand_core::RngCore::next_u64(&mut rand_core::OsRng)
+        );
+        let url = self.url(&format!("/admin/settings/{}", key));
+        let value = json!({ "checked_at": chrono::Utc::now().to_rfc3339() });
+
+        let result = async {
+            let (status, _) = admin.request(Method::PUT, &url, Some(&value)).await?;
+            expect(status, StatusCode::OK, "create")?;
+            let (status, body) = admin.json(Method::GET, &url, None).await?;
+            expect(status, StatusCode::OK, "read")?;
+            if body != value {
+                return Err(Unmet::Failed(format!("read back {}", body)));
+            }
+            let (status, _) = admin.request(Method::DELETE, &url, None).await?;
+            expect(status, StatusCode::NO_CONTENT, "delete")?;
+            let (status, _) = admin.request(Method::GET, &url, None).await?;
+            expect(status, StatusCode::NOT_FOUND, "read after delete")?;
+            Ok(format!("created, read and deleted {}", key))
+        }
+        .await;
+        if result.is_err() {
+            // Best effort: don't leave the scratch setting behind
+            let _ = admin.request(Method::DELETE, &url, None).await;
+        }
+        result
+    }
+
+    async fn migrations(&self) -> CheckResult {
+        let (pool, modules) = self
+            .database
+            .as_ref()
+            .ok_or_else(|| Unmet::Skipped("no database access".to_string()))?;
+        let pending = module::pending_migrations(pool, modules).await?;
+        let (contract, expand): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .partition(|(_, kind)| *kind == MigrationKind::Contract);
+        let names = |migrations: Vec<(String, MigrationKind)>| {
+            migrations
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        if !expand.is_empty() {
+            Err(Unmet::Failed(format!("pending: {}", names(expand))))
+        } else if !contract.is_empty() {
+            // Contract steps wait for `migrate` once older releases are gone
+            Ok(format!(
+                "contract migrations awaiting migrate: {}",
+                names(contract)
+            ))
+        } else {
+            Ok("all migrations applied".to_string())
+        }
+    }
+}
+
+fn expect(status: StatusCode, expected: StatusCode, step: &str) -> Result<(), Unmet> {
+    if status == expected {
+        Ok(())
+    } else {
+        Err(Unmet::Failed(format!(
+            "{} returned {}, expected {}",
+            step, status, expected
+        )))
+    }
+}
+
+pub(crate) async fn timed(name: &'static str, check: impl Future<Output = CheckResult>) -> Check {
+    let started = Instant::now();
+    let (outcome, detail) = match check.await {
+        Ok(detail) => (Outcome::Pass, detail),
+        Err(Unmet::Failed(detail)) => (Outcome::Fail, detail),
+        Err(Unmet::Skipped(detail)) => (Outcome::Skip, detail),
+    };
+    Check {
+        name,
+        outcome,
+        detail,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// Summary line such as `5 passed, 1 skipped`
+pub fn summarize(checks: &[Check]) -> String {
+    let count = |outcome| checks.iter().filter(|c| c.outcome == outcome).count();
+    let mut parts = vec![format!("{} passed", count(Outcome::Pass))];
+    for (outcome, label) in [(Outcome::Fail, "failed"), (Outcome::Skip, "skipped")] {
+        if count(outcome) > 0 {
+            parts.push(format!("{} {}", count(outcome), label));
+        }
+    }
+    parts.join(", ")
+}
+
+/// JSON report of a run
+pub fn report(checks: &[Check]) -> Value {
+    json!({
+        "passed": checks.iter().all(|c| c.outcome != Outcome::Fail),
+        "summary": summarize(checks),
+        "checks": checks,
+    })
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::test_db::TestDatabase;
+    use crate::testing::{self, TestServer};
+
+    #[tokio::test]
+    async fn test_smoke_checks_pass_against_test_server() {
+        let Some(db) = TestDatabase::start().await else {
+            return;
+        };
+        let config = testing::config(&[("DATABASE_URL", &db.url)]).unwrap();
+        let server = TestServer::spawn(config).await.unwrap();
+
+        let base = server.url("");
+        let checks = SmokeTest::new(&base, Duration::from_secs(5)).run().await;
+        let outcomes: Vec<_> = checks.iter().map(|c| (c.name, c.outcome)).collect();
+        assert_eq!(outcomes[0], ("health", Outcome::Pass));
+        assert_eq!(outcomes[3], ("auth", Outcome::Skip));
+
+        let checks = SmokeTest::new(&base, Duration::from_secs(5))
+            .admin_token(testing::ADMIN_TOKEN)
+            .database(server.pool().clone(), module::builtin_modules())
+            .run()
+            .await;
+        let failed: Vec<_> = checks
+            .iter()
+            .filter(|c| c.outcome != Outcome::Pass)
+            .collect();
+        assert!(failed.is_empty(), "{:?}", failed);
+        assert_eq!(summarize(&checks), "6 passed");
+
+        let checks = SmokeTest::new(&base, Duration::from_secs(5))
+            .admin_token("wrong")
+            .run()
+            .await;
+        assert_eq!(checks[3].outcome, Outcome::Fail);
+        assert_eq!(report(&checks)["passed"], false);
+
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+//! Ad-hoc SQL console for admins.
+//!
+//! With `ADMIN_SQL_ENABLED=true`, `POST /admin/sql` runs a single `SELECT`
+//! (or `WITH ... SELECT`) against the server's database and returns its
+//! columns and rows. The console is off by default and only ever reads:
+//!
+//! - the query runs as a subquery in a `READ ONLY` transaction, so writes,
+//!   DDL and multiple statements are rejected by Postgres itself
+//! - `statement_timeout` is set to `ADMIN_SQL_TIMEOUT` for the transaction
+//! - at most `ADMIN_SQL_MAX_ROWS` rows are read per call, however the query
+//!   is written; `offset` and `next_offset` page through the rest, which
+//!   only gives stable pages for queries with an `ORDER BY`
+//! - with `ADMIN_SQL_DATABASE_URL`, queries run on their own connections
+//!   logged in as another role, e.g. one granted `pg_read_all_data` only,
+//!   to also rule out functions with side effects such as
+//!   `pg_terminate_backend`. Switching roles within the server's own
+//!   connections would not do, as a query can switch back
+//!
+//! Every query, including rejected ones, is logged and stored in
+//! `admin_sql_audit` with the client address, outcome and duration.
+
+use axum::{
+    extract::{ConnectInfo, State},
+    response::Json,
+    routing::{get, post},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{PgPool, Row};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::db::NamedDatabase;
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::AppState;
+
+/// SQL console settings, from configuration
+#[derive(Debug, Clone)]
+pub struct SqlConsoleConfig {
+    pub timeout: Duration,
+    /// Most rows returned by one call
+    pub max_rows: u32,
+    /// Database queries run on, instead of the server's own connections
+    pub database: Option<NamedDatabase>,
+}
+
+/// The pool console queries run on
+#[derive(Clone)]
+pub struct ConsolePool(pub PgPool);
+
+/// Body of `POST /admin/sql`
+#[derive(Debug, Deserialize)]
+pub struct SqlRequest {
+    pub sql: String,
+    /// Rows to return, at most `ADMIN_SQL_MAX_ROWS`
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: u64,
+}
+
+/// One page of a query's result
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SqlResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+    /// Offset of the next page, if there are more rows
+    pub next_offset: Option<u64>,
+    pub duration_ms: u64,
+}
+
+/// Run `sql` read-only, returning the page at `offset`
+pub async fn run(
+    pool: &PgPool,
+    config: &SqlConsoleConfig,
+    sql: &str,
+    limit: u32,
+    offset: u64,
+) -> sqlx::Result<SqlResult> {
+    let started = Instant::now();
+    let mut tx = pool.begin().await?;
+    sqlx::query("SET TRANSACTION READ ONLY")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("SELECT set_config('statement_timeout', $1, true)")
+        .bind(format!("{}ms", config.timeout.as_millis()))
+        .execute(&mut *tx)
+        .await?;
+    // One more row than requested tells whether another page follows; the
+    // line break keeps a trailing `--` comment from swallowing the rest.
+    // A query closing the subquery escapes the LIMIT, so rows past it are
+    // never read either
+    let rows: Vec<_> = sqlx::query(&format!(
+        "SELECT ARRAY(SELECT key FROM json_each(r.j) WITH ORDINALITY ORDER BY ordinality) AS columns,
+                (SELECT json_agg(value ORDER BY ordinality)
+                 FROM json_each(r.j) WITH ORDINALITY) AS values
+         FROM (SELECT row_to_json(q) AS j FROM ({}
+) q LIMIT $1 OFFSET $2) r",
+        sql
+    ))
+    .bind(i64::from(limit) + 1)
+    .bind(offset as i64)
+    .fetch(&mut *tx)
+    .take(limit as usize + 1)
+    .try_collect()
+    .await?;
+    tx.rollback().await?;
+
+    let more = rows.len() > limit as usize;
+    let mut columns = Vec::new();
+    let mut values = Vec::new();
+    for row in rows.iter().take(limit as usize) {
+        if columns.is_empty() {
+            columns = row.try_get("columns")?;
+        }
+        let sqlx::types::Json(row): sqlx::types::Json<Option<Vec<Value>>> =
+            row.try_get("values")?;
+        values.push(row.unwrap_or_default());
+    }
+    Ok(SqlResult {
+        columns,
+        next_offset: more.then(|| offset + u64::from(limit)),
+        rows: values,
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+/// `POST /admin/sql` - run a read-only query
+pub async fn execute(
+    State(state): State<AppState>,
+    Ext(config): Ext<SqlConsoleConfig>,
+    Ext(console): Ext<ConsolePool>,
+    client: Option<ConnectInfo<SocketAddr>>,
+    Json(request): Json<SqlRequest>,
+) -> ApiResult<Json<SqlResult>> {
+    let sql = request.sql.trim().trim_end_matches(';').trim();
+    if sql.is_empty() {
+        return Err(ApiError::BadRequest("sql must not be empty".to_string()));
+    }
+    let limit = request
+        .limit
+        .unwrap_or(config.max_rows)
+        .min(config.max_rows);
+    let client = client.map(|ConnectInfo(addr)| addr.ip().to_string());
+
+    let started = Instant::now();
+    let result = run(&console.0, &config, sql, limit, request.offset).await;
+    let entry = AuditEntry {
+        sql,
+        client: client.as_deref(),
+        rows: result.as_ref().ok().map(|r| r.rows.len() as i64),
+        error: result.as_ref().err().map(ToString::to_string),
+        duration: started.elapsed(),
+    };
+    audit(state.db.pool(), &entry).await?;
+    result.map(Json).map_err(|e| match e {
+        sqlx::Error::Database(e) => ApiError::BadRequest(e.message().to_string()),
+        e => ApiError::from(e),
+    })
+}
+
+struct AuditEntry<'a> {
+    sql: &'a str,
+    client: Option<&'a str>,
+    rows: Option<i64>,
+    error: Option<String>,
+    duration: Duration,
+}
+
+/// Log a query and store it in `admin_sql_audit`
+///
+/// Fails the request when it cannot be stored, so no query goes unaudited.
+async fn audit(pool: &PgPool, entry: &AuditEntry<'_>) -> ApiResult<()> {
+    tracing::warn!(
+        client = entry.client.unwrap_or("unknown"),
+        rows = entry.rows,
+        error = entry.error.as_deref(),
+        "🔎 Admin SQL query in {:.0?}: {}",
+        entry.duration,
+        entry.sql
+    );
+    sqlx::query(
+        "INSERT INTO admin_sql_audit (sql, client, row_count, error, duration_ms, executed_at)
+         VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(entry.sql)
+    .bind(entry.client)
+    .bind(entry.rows)
+    .bind(&entry.error)
+    .bind(entry.duration.as_millis() as i64)
+    .bind(Utc::now())
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        ApiError::Internal(anyhow::Error::new(e).context("Failed to audit the SQL query"))
+    })?;
+    Ok(())
+}
+
+/// A stored console query
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AuditRecord {
+    pub sql: String,
+    pub client: Option<String>,
+    pub row_count: Option<i64>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    #[serde(with = "crate::time::rfc3339")]
+    pub executed_at: DateTime<Utc>,
+}
+
+/// `GET /admin/sql/audit` - the 100 most recent console queries
+pub async fn list_audit(State(state): State<AppState>) -> ApiResult<Json<Vec<AuditRecord>>> {
+    let records = sqlx::query_as(
+        "SELECT sql, client, row_count, error, duration_ms, executed_at
+         FROM admin_sql_audit ORDER BY executed_at DESC LIMIT 100",
+    )
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(records))
+}
+
+/// Route module serving the SQL console, mounted with `ADMIN_SQL_ENABLED`
+pub struct SqlConsoleModule;
+
+impl RouteModule for SqlConsoleModule {
+    fn name(&self) -> &'static str {
+        "sql_console"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/admin/sql", post(execute))
+            .route("/admin/sql/audit", get(list_audit))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_admin_sql_audit",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS admin_sql_audit (
+                id BIGSERIAL PRIMARY KEY,
+                sql TEXT NOT NULL,
+                client TEXT,
+                row_count BIGINT,
+                error TEXT,
+                duration_ms BIGINT NOT NULL,
+                executed_at TIMESTAMPTZ NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS admin_sql_audit_executed_at
+                ON admin_sql_audit (executed_at)",
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_runs_read_only_queries_with_limits() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = db.pool.clone();
+        sqlx::query("CREATE TABLE console_items (id INT NOT NULL, name TEXT)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO console_items SELECT n, 'item ' || n FROM generate_series(1, 5) n",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let config = SqlConsoleConfig {
+            timeout: Duration::from_millis(200),
+            max_rows: 10,
+            database: None,
+        };
+
+        let sql = "SELECT name, id FROM console_items ORDER BY id";
+        let page = run(&pool, &config, sql, 2, 2).await.unwrap();
+        assert_eq!(page.columns, ["name", "id"]);
+        assert_eq!(
+            page.rows,
+            [
+                vec![Value::from("item 3"), Value::from(3)],
+                vec![Value::from("item 4"), Value::from(4)],
+            ]
+        );
+        assert_eq!(page.next_offset, Some(4));
+        let last = run(&pool, &config, sql, 2, 4).await.unwrap();
+        assert_eq!((last.rows.len(), last.next_offset), (1, None));
+
+        // Closing the subqueries early multiplies the rows past the LIMIT
+        let escaped = "SELECT 1) q LIMIT 1) r0, generate_series(1, 50) g, \
+                       (SELECT row_to_json(q) AS j FROM (SELECT 1";
+        let page = run(&pool, &config, escaped, 2, 0).await.unwrap();
+        assert_eq!((page.rows.len(), page.next_offset), (2, Some(2)));
+
+        for sql in [
+            "DELETE FROM console_items RETURNING id",
+            "SELECT 1) q; DELETE FROM console_items; SELECT (SELECT 1",
+            "SELECT set_config('transaction_read_only', 'off', true)",
+            "SELECT pg_sleep(5)",
+        ] {
+            assert!(run(&pool, &config, sql, 10, 0).await.is_err(), "{}", sql);
+        }
+        let count: i64 = sqlx::query_scalar("SELECT count(*) FROM console_items")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 5);
+    }
+}
@@ -0,0 +1,224 @@
+//! SQL query logging for development.
+//!
+//! With `SQL_LOG=true` every statement is logged with its duration and
+//! the rows it returned or affected. In debug builds, queries run through
+//! [`Db`](crate::db::Db) also log their bind parameters; release builds
+//! never do, as parameters may hold personal data.
+//!
+//! Queries are also counted per request and aggregated by route at
+//! `GET /admin/queries`, and a request running more than `SQL_QUERY_WARN`
+//! queries is logged as a likely N+1 pattern. Counting relies on the
+//! statement events, so it only works under `serve`, whose log setup
+//! installs [`QueryCountLayer`].
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{Json, Response},
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::db::Param;
+use crate::extensions::Ext;
+use crate::module::{RouteGroup, RouteModule};
+use crate::AppState;
+
+/// Target sqlx logs statements under
+const STATEMENT_TARGET: &str = "sqlx::query";
+
+static LOG_PARAMS: AtomicBool = AtomicBool::new(false);
+
+tokio::task_local! {
+    static QUERIES: Cell<u32>;
+}
+
+/// Query logging settings, from configuration
+#[derive(Debug, Clone)]
+pub struct SqlLogConfig {
+    /// Queries in one request above which it is logged as an N+1 suspect
+    pub warn_queries: u32,
+}
+
+/// Log bind parameters of [`Db`](crate::db::Db) queries, in debug builds
+pub fn configure(config: Option<&SqlLogConfig>) {
+    LOG_PARAMS.store(
+        config.is_some() && cfg!(debug_assertions),
+        Ordering::Relaxed,
+    );
+}
+
+/// Log a query's bind parameters if enabled
+pub(crate) fn log_params(sql: &str, params: &[Param]) {
+    if LOG_PARAMS.load(Ordering::Relaxed) && !params.is_empty() {
+        let summary: Vec<&str> = sql.split_whitespace().take(4).collect();
+        tracing::info!(params = ?params, "{} …", summary.join(" "));
+    }
+}
+
+/// Counts statement events towards the current request's total
+pub struct QueryCountLayer;
+
+impl<S: Subscriber> Layer<S> for QueryCountLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target() == STATEMENT_TARGET {
+            let _ = QUERIES.try_with(|count| count.set(count.get() + 1));
+        }
+    }
+}
+
+/// Queries run by the requests to one route
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RouteQueries {
+    pub route: String,
+    pub requests: u64,
+    pub queries: u64,
+    /// Most queries run by a single request
+    pub max: u32,
+}
+
+/// Per-route query counts and the middleware collecting them
+#[derive(Clone)]
+pub struct QueryTracker {
+    config: SqlLogConfig,
+    routes: Arc<Mutex<HashMap<String, RouteQueries>>>,
+}
+
+impl QueryTracker {
+    pub fn new(config: SqlLogConfig) -> Self {
+        QueryTracker {
+            config,
+            routes: Arc::default(),
+        }
+    }
+
+    /// Counts for every route seen, most queries per request first
+    pub fn routes(&self) -> Vec<RouteQueries> {
+        let mut routes: Vec<RouteQueries> = self
+            .routes
+            .lock()
+            .expect("query counts poisoned")
+            .values()
+            .cloned()
+            .collect();
+        routes.sort_by(|a, b| b.max.cmp(&a.max).then_with(|| a.route.cmp(&b.route)));
+        routes
+    }
+
+    fn record(&self, route: String, queries: u32) {
+        let mut routes = self.routes.lock().expect("query counts poisoned");
+        let entry = routes.entry(route.clone()).or_insert_with(|| RouteQueries {
+            route,
+            ..RouteQueries::default()
+        });
+        entry.requests += 1;
+        entry.queries += u64::from(queries);
+        entry.max = entry.max.max(queries);
+    }
+
+    /// Middleware counting the queries each request runs
+    pub async fn middleware(
+        State(tracker): State<QueryTracker>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let route = format!(
+            "{} {}",
+            request.method(),
+            request
+                .extensions()
+                .get::<MatchedPath>()
+                .map_or("(unmatched)", MatchedPath::as_str)
+        );
+        let (response, queries) = QUERIES
+            .scope(Cell::new(0), async {
+                let response = next.run(request).await;
+                (response, QUERIES.with(Cell::get))
+            })
+            .await;
+        if queries > tracker.config.warn_queries {
+            tracing::warn!(
+                "🐌 {} ran {} queries, more than {}; likely an N+1 pattern",
+                route,
+                queries,
+                tracker.config.warn_queries
+            );
+        }
+        if queries > 0 {
+            tracker.record(route, queries);
+        }
+        response
+    }
+}
+
+/// Route module reporting query counts, mounted with `SQL_LOG`
+pub struct QueriesModule;
+
+impl RouteModule for QueriesModule {
+    fn name(&self) -> &'static str {
+        "queries"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/admin/queries", get(list_routes))
+    }
+}
+
+/// `GET /admin/queries` - queries per request, by route
+async fn list_routes(Ext(tracker): Ext<QueryTracker>) -> Json<Vec<RouteQueries>> {
+    Json(tracker.routes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware};
+    use tower::Service;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test]
+    async fn test_counts_queries_per_route() {
+        let _subscriber =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(QueryCountLayer));
+        let tracker = QueryTracker::new(SqlLogConfig { warn_queries: 2 });
+        let mut app = Router::new()
+            .route(
+                "/notes/:id",
+                get(|| async {
+                    for _ in 0..3 {
+                        tracing::info!(target: "sqlx::query", "SELECT * FROM notes");
+                    }
+                }),
+            )
+            .route("/health", get(|| async {}))
+            .layer(middleware::from_fn_with_state(
+                tracker.clone(),
+                QueryTracker::middleware,
+            ));
+        for uri in ["/notes/1", "/notes/2", "/health"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            app.call(request).await.unwrap();
+        }
+
+        assert_eq!(
+            tracker.routes(),
+            [RouteQueries {
+                route: "GET /notes/:id".into(),
+                requests: 2,
+                queries: 6,
+                max: 3,
+            }]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! Waiting for dependencies at startup.
+//!
+//! When services start together, e.g. with Docker Compose, Postgres or a
+//! cache may still be coming up when the server does. `STARTUP_WAIT` lists
+//! what to wait for and for how long, as `name:timeout` entries: `db` is the
+//! server's own database, any other name a `HEALTH_DEPENDENCIES` entry, so
+//! Redis, SMTP or object storage are waited for by probing them the same
+//! way their health checks do.
+//!
+//! The database is waited for first, then the rest concurrently. Each
+//! failed attempt is logged with what is blocking, and startup only fails
+//! once a dependency's timeout runs out. Nothing listens until then, so
+//! readiness never flips early.
+
+use anyhow::{Context, Result};
+use sqlx::{Connection, PgConnection};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+use crate::config::Config;
+use crate::db::Database;
+use crate::dependencies::{self, Dependency};
+use crate::health::CHECK_TIMEOUT;
+use crate::http_client::HttpClient;
+
+/// Name of the server's own database in `STARTUP_WAIT`
+pub const DATABASE: &str = "db";
+
+/// Delay before the first retry, doubled after every failure
+const FIRST_RETRY: Duration = Duration::from_millis(250);
+/// Longest delay between attempts
+const MAX_RETRY: Duration = Duration::from_secs(5);
+
+/// A dependency to wait for at startup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wait {
+    pub name: String,
+    /// How long to keep trying before startup fails
+    pub timeout: Duration,
+}
+
+/// Parse `STARTUP_WAIT`, e.g. `db:60s,cache:30s`
+///
+/// Names other than `db` must be among `dependencies`.
+pub fn parse_waits(input: &str, dependencies: &[Dependency]) -> Result<Vec<Wait>> {
+    let mut waits: Vec<Wait> = Vec::new();
+    for entry in input.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, timeout) = entry
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expected name:timeout, got '{}'", entry))?;
+        let name = name.trim();
+        if name != DATABASE && !dependencies.iter().any(|d| d.name == name) {
+            anyhow::bail!(
+                "'{}' is neither {} nor a HEALTH_DEPENDENCIES entry",
+                name,
+                DATABASE
+            );
+        }
+        if waits.iter().any(|w| w.name == name) {
+            anyhow::bail!("'{}' is listed twice", name);
+        }
+        let timeout = crate::config::parse_duration(timeout.trim())
+            .map_err(|e| anyhow::anyhow!("'{}': {}", name, e))?;
+        waits.push(Wait {
+            name: name.to_string(),
+            timeout,
+        });
+    }
+    Ok(waits)
+}
+
+/// Retry `attempt` until it succeeds or `timeout` has passed
+///
+/// Every failure is logged; the error of the last attempt is returned.
+pub async fn wait_for<T, F, Fut>(name: &str, timeout: Duration, mut attempt: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let started = Instant::now();
+    let mut delay = FIRST_RETRY;
+    loop {
+        let remaining = timeout.saturating_sub(started.elapsed());
+        let error = match tokio::time::timeout(remaining, attempt()).await {
+            Ok(Ok(value)) => {
+                if started.elapsed() > FIRST_RETRY {
+                    tracing::info!(
+                        "✅ '{}' became available after {:.1?}",
+                        name,
+                        started.elapsed()
+                    );
+                }
+                return Ok(value);
+            }
+            Ok(Err(e)) => e,
+            Err(_) => anyhow::anyhow!("attempt did not finish in time"),
+        };
+        let elapsed = started.elapsed();
+        if elapsed + delay >= timeout {
+            let context = format!("'{}' was not available within {:?}", name, timeout);
+            return Err(error.context(context));
+        }
+        tracing::warn!(
+            "⏳ Waiting for '{}' ({:.0?} of {:?}): {:#}",
+            name,
+            elapsed,
+            timeout,
+            error
+        );
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY);
+    }
+}
+
+/// Connect to the server's database, waiting for it if `waits` says so
+pub async fn connect_database(config: &Config, waits: &[Wait]) -> Result<Database> {
+    let Some(wait) = waits.iter().find(|w| w.name == DATABASE) else {
+        return Database::new(config).await;
+    };
+    // A single connection fails fast with the actual error, where the pool
+    // would keep retrying silently until its acquire timeout
+    wait_for(&wait.name, wait.timeout, || async {
+        let mut conn = PgConnection::connect(config.database_url()).await?;
+        conn.ping().await?;
+        let _ = conn.close().await;
+        Ok(())
+    })
+    .await?;
+    Database::new(config).await
+}
+
+/// Wait for every non-database entry of `waits` concurrently
+pub async fn wait_for_dependencies(waits: &[Wait], dependencies: &[Dependency]) -> Result<()> {
+    let client = HttpClient::new(CHECK_TIMEOUT);
+    let mut pending = JoinSet::new();
+    for wait in waits.iter().filter(|w| w.name != DATABASE) {
+        let dependency = dependencies
+            .iter()
+            .find(|d| d.name == wait.name)
+            .with_context(|| format!("Unknown dependency '{}'", wait.name))?
+            .clone();
+        let (wait, client) = (wait.clone(), client.clone());
+        pending.spawn(async move {
+            wait_for(&wait.name, wait.timeout, || {
+                dependencies::probe(&client, &dependency.target)
+            })
+            .await
+        });
+    }
+    while let Some(result) = pending.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_parse_waits() {
+        let dependencies = dependencies::parse_dependencies("cache=tcp://redis:6379").unwrap();
+        assert_eq!(
+            parse_waits("db:60s, cache:5s", &dependencies).unwrap(),
+            [
+                Wait {
+                    name: "db".into(),
+                    timeout: Duration::from_secs(60)
+                },
+                Wait {
+                    name: "cache".into(),
+                    timeout: Duration::from_secs(5)
+                },
+            ]
+        );
+        assert!(parse_waits("", &dependencies).unwrap().is_empty());
+        assert!(parse_waits("mail:5s", &dependencies).is_err());
+        assert!(parse_waits("db", &dependencies).is_err());
+        assert!(parse_waits("db:5s,db:1s", &dependencies).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_retries_until_timeout() {
+        let attempts = AtomicU32::new(0);
+        let value = wait_for("flaky", Duration::from_secs(10), || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0..=1 => anyhow::bail!("refused"),
+                n => Ok(n),
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(value, 2);
+
+        let error = wait_for("down", Duration::from_secs(1), || async {
+            anyhow::bail!("refused") as Result<()>
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "'down' was not available within 1s: refused"
+        );
+    }
+}
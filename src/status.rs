@@ -0,0 +1,301 @@
+//! Public status page with uptime history.
+//!
+//! A background task samples the [`HealthRegistry`] every
+//! `STATUS_SAMPLE_INTERVAL` and stores each check's status in
+//! `status_samples`. A check that stops being healthy opens an incident in
+//! `status_incidents`, closed again when it recovers. `/status` shows the
+//! current state, uptime over the last day, week and month and the latest
+//! incidents, as HTML or, when the client accepts it, JSON.
+//!
+//! Only the leader samples, and only while serving; time the server was not
+//! running is left out of the uptime figures rather than counted as down.
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::{header::ACCEPT, HeaderMap},
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::PgPool;
+use std::time::Duration;
+
+use crate::error::ApiResult;
+use crate::health::{HealthRegistry, HealthReport, ServingState, Status, DEEP_CHECK_TIMEOUT};
+use crate::leader::Leadership;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::templates::{Html, Template};
+use crate::AppState;
+
+/// How many recent incidents the page lists
+const RECENT_INCIDENTS: i64 = 20;
+
+/// Route module serving `/status`
+pub struct StatusModule;
+
+impl RouteModule for StatusModule {
+    fn name(&self) -> &'static str {
+        "status"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/status", get(status_page))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_status_history",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS status_samples (
+                component TEXT NOT NULL,
+                status TEXT NOT NULL,
+                checked_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE INDEX IF NOT EXISTS status_samples_checked_at
+                ON status_samples (checked_at);
+            CREATE TABLE IF NOT EXISTS status_incidents (
+                id BIGSERIAL PRIMARY KEY,
+                component TEXT NOT NULL,
+                error TEXT,
+                started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                resolved_at TIMESTAMPTZ
+            );
+            CREATE INDEX IF NOT EXISTS status_incidents_open
+                ON status_incidents (component) WHERE resolved_at IS NULL",
+        }]
+    }
+}
+
+/// Uptime percentages of one component
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Uptime {
+    pub component: String,
+    pub day: Option<f64>,
+    pub week: Option<f64>,
+    pub month: Option<f64>,
+}
+
+/// A period during which a component was not healthy
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Incident {
+    pub id: i64,
+    pub component: String,
+    pub error: Option<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub started_at: DateTime<Utc>,
+    #[serde(with = "crate::time::rfc3339_option")]
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+/// Record the status of every check in `report`
+pub async fn record(pool: &PgPool, report: &HealthReport) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    for check in &report.checks {
+        sqlx::query("INSERT INTO status_samples (component, status) VALUES ($1, $2)")
+            .bind(&check.name)
+            .bind(check.status.as_str())
+            .execute(&mut *tx)
+            .await?;
+        if check.status == Status::Ok {
+            sqlx::query(
+                "UPDATE status_incidents SET resolved_at = now()
+                 WHERE component = $1 AND resolved_at IS NULL",
+            )
+            .bind(&check.name)
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            sqlx::query(
+                "INSERT INTO status_incidents (component, error)
+                 SELECT $1, $2 WHERE NOT EXISTS (
+                     SELECT 1 FROM status_incidents WHERE component = $1 AND resolved_at IS NULL
+                 )",
+            )
+            .bind(&check.name)
+            .bind(&check.error)
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Uptime of every component sampled in the last 30 days
+pub async fn uptime(pool: &PgPool) -> Result<Vec<Uptime>> {
+    let uptime = sqlx::query_as::<_, Uptime>(
+        "SELECT component,
+            round(100.0 * count(*) FILTER (WHERE status = 'ok' AND checked_at > now() - interval '1 day')
+                / NULLIF(count(*) FILTER (WHERE checked_at > now() - interval '1 day'), 0), 2)::float8 AS day,
+            round(100.0 * count(*) FILTER (WHERE status = 'ok' AND checked_at > now() - interval '7 days')
+                / NULLIF(count(*) FILTER (WHERE checked_at > now() - interval '7 days'), 0), 2)::float8 AS week,
+            round(100.0 * count(*) FILTER (WHERE status = 'ok') / count(*), 2)::float8 AS month
+         FROM status_samples
+         WHERE checked_at > now() - interval '30 days'
+         GROUP BY component
+         ORDER BY component",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(uptime)
+}
+
+/// The most recent incidents, newest first
+pub async fn incidents(pool: &PgPool) -> Result<Vec<Incident>> {
+    let incidents = sqlx::query_as::<_, Incident>(
+        "SELECT id, component, error, started_at, resolved_at
+         FROM status_incidents ORDER BY started_at DESC LIMIT $1",
+    )
+    .bind(RECENT_INCIDENTS)
+    .fetch_all(pool)
+    .await?;
+    Ok(incidents)
+}
+
+/// Sample the health registry in the background
+pub fn spawn(
+    pool: PgPool,
+    health: HealthRegistry,
+    interval: Duration,
+    retention: Duration,
+    leadership: Leadership,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if !leadership.is_leader() || health.serving_state() != ServingState::Serving {
+                continue;
+            }
+            let report = health.run(DEEP_CHECK_TIMEOUT).await;
+            if let Err(e) = record(&pool, &report).await {
+                tracing::warn!("Failed to record status sample: {:#}", e);
+            }
+            let pruned = sqlx::query(
+                "DELETE FROM status_samples WHERE checked_at < now() - make_interval(secs => $1)",
+            )
+            .bind(retention.as_secs_f64())
+            .execute(&pool)
+            .await;
+            if let Err(e) = pruned {
+                tracing::warn!("Failed to prune status samples: {}", e);
+            }
+        }
+    })
+}
+
+#[derive(Serialize)]
+pub struct StatusPage {
+    #[serde(flatten)]
+    report: HealthReport,
+    uptime: Vec<UptimeRow>,
+    incidents: Vec<IncidentRow>,
+}
+
+impl Template for StatusPage {
+    const NAME: &'static str = "status.html";
+}
+
+/// Uptime formatted for display
+#[derive(Serialize)]
+struct UptimeRow {
+    component: String,
+    day: String,
+    week: String,
+    month: String,
+}
+
+/// Incident formatted for display
+#[derive(Serialize)]
+struct IncidentRow {
+    component: String,
+    error: Option<String>,
+    started_at: String,
+    resolved_at: Option<String>,
+}
+
+fn percent(value: Option<f64>) -> String {
+    value.map_or_else(|| "–".to_string(), |v| format!("{:.2}%", v))
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+/// Whether the client prefers JSON over HTML
+fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json") && !accept.contains("text/html"))
+}
+
+/// `GET /status` - current health, uptime history and recent incidents
+pub async fn status_page(State(state): State<AppState>, headers: HeaderMap) -> ApiResult<Response> {
+    let report = state.health.run(DEEP_CHECK_TIMEOUT).await;
+    let pool = state.db.pool();
+    let (uptime, incidents) = tokio::try_join!(uptime(pool), incidents(pool))?;
+
+    if wants_json(&headers) {
+        return Ok(Json(json!({
+            "status": report.status,
+            "state": report.state,
+            "checks": report.checks,
+            "uptime": uptime,
+            "incidents": incidents,
+        }))
+        .into_response());
+    }
+    let page = StatusPage {
+        report,
+        uptime: uptime
+            .into_iter()
+            .map(|u| UptimeRow {
+                component: u.component,
+                day: percent(u.day),
+                week: percent(u.week),
+                month: percent(u.month),
+            })
+            .collect(),
+        incidents: incidents
+            .into_iter()
+            .map(|i| IncidentRow {
+                component: i.component,
+                error: i.error,
+                started_at: timestamp(i.started_at),
+                resolved_at: i.resolved_at.map(timestamp),
+            })
+            .collect(),
+    };
+    Ok(Html(page).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_wants_json() {
+        let mut headers = HeaderMap::new();
+        assert!(!wants_json(&headers));
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        assert!(wants_json(&headers));
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("text/html,application/xhtml+xml,application/json;q=0.9"),
+        );
+        assert!(!wants_json(&headers));
+
+        assert_eq!(percent(Some(99.5)), "99.50%");
+        assert_eq!(percent(None), "–");
+    }
+}
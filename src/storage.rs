@@ -0,0 +1,583 @@
+//! File storage with streaming uploads.
+//!
+//! With `STORAGE_DIR` set, files are kept under it by key and served
+//! through the API:
+//!
+//! ```text
+//! PUT    /api/v1/files/*key    the request body is the file
+//! GET    /api/v1/files/*key
+//! DELETE /api/v1/files/*key
+//! ```
+//!
+//! Uploads are never buffered whole: body frames are gathered into at most
+//! `STORAGE_CHUNK_SIZE` bytes and written out, with the SHA-256 computed
+//! along the way, so memory stays flat however large or numerous the
+//! uploads. A file is written to a hidden temporary file next to its
+//! destination and renamed into place once complete; an upload over
+//! `STORAGE_MAX_FILE_SIZE`, cut short, or not matching the digest in
+//! `X-Content-SHA256` leaves nothing behind. Downloads are streamed in
+//! chunks of the same size.
+//!
+//! Keys are `/`-separated segments of letters, digits, `.`, `_` and `-`,
+//! e.g. `avatars/alice.png`; a segment may not start with `.`.
+
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Json, Response},
+    routing::put,
+    Router,
+};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path as FsPath, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::auth::random_token;
+use crate::crypto::hex;
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::module::RouteModule;
+use crate::usage::Meter;
+use crate::{t, AppState};
+
+/// Header carrying the SHA-256 an upload must match, in hex
+pub const DIGEST_HEADER: &str = "x-content-sha256";
+
+/// Longest key accepted
+const MAX_KEY_LEN: usize = 1024;
+
+/// Where and how files are stored
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    pub dir: PathBuf,
+    /// Most bytes of an upload or download held in memory at once
+    pub chunk_size: usize,
+    /// Largest file accepted, in bytes
+    pub max_file_size: u64,
+}
+
+/// A stored file as reported to the uploader
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StoredFile {
+    pub key: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// A file or directory under the storage directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Empty for the storage directory itself
+    pub key: String,
+    pub dir: bool,
+    /// In bytes, 0 for directories
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+/// Files on the local filesystem, by key
+pub struct Storage {
+    config: StorageConfig,
+}
+
+impl Storage {
+    pub fn new(config: StorageConfig) -> Self {
+        Storage { config }
+    }
+
+    /// Get the directory files are stored in
+    pub fn dir(&self) -> &FsPath {
+        &self.config.dir
+    }
+
+    /// Get the largest file accepted, in bytes
+    pub fn max_file_size(&self) -> u64 {
+        self.config.max_file_size
+    }
+
+    /// Resolve a key to its path under the storage directory
+    fn path(&self, key: &str) -> ApiResult<PathBuf> {
+        if !valid_key(key) {
+            return Err(ApiError::Validation(t!("file-key", key = key)));
+        }
+        Ok(self.config.dir.join(key))
+    }
+
+    /// Stream `body` into the file at `key`, replacing any previous one
+    ///
+    /// When `expected` is given, the body's SHA-256 must match it.
+    pub async fn put<S, E>(
+        &self,
+        key: &str,
+        body: S,
+        expected: Option<&str>,
+    ) -> ApiResult<StoredFile>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let path = self.path(key)?;
+        let parent = path.parent().unwrap_or(&self.config.dir);
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(anyhow::Error::from)?;
+        let temp = parent.join(format!(".upload-{}", random_token(12)));
+        let result = self
+            .write(&temp, body)
+            .await
+            .and_then(|(size, sha256)| match expected {
+                Some(expected) if !expected.eq_ignore_ascii_case(&sha256) => Err(
+                    ApiError::Validation(t!("file-digest", expected = expected, actual = sha256)),
+                ),
+                _ => Ok((size, sha256)),
+            });
+        let (size, sha256) = match result {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp).await;
+                return Err(e);
+            }
+        };
+        if let Err(e) = tokio::fs::rename(&temp, &path).await {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(anyhow::Error::from(e).into());
+        }
+        Ok(StoredFile {
+            key: key.to_string(),
+            size,
+            sha256,
+        })
+    }
+
+    /// Write `body` to `path` a chunk at a time, returning its size and SHA-256
+    async fn write<S, E>(&self, path: &FsPath, mut body: S) -> ApiResult<(u64, String)>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let chunk_size = self.config.chunk_size;
+        let max = self.config.max_file_size;
+        let mut file = tokio::fs::File::create(path)
+            .await
+            .map_err(anyhow::Error::from)?;
+        let mut hasher = Sha256::new();
+        let mut chunk = BytesMut::with_capacity(chunk_size);
+        let mut size = 0u64;
+        while let Some(frame) = body.next().await {
+            let mut frame = frame.map_err(|e| {
+                ApiError::BadRequest(t!("file-interrupted", reason = e.to_string()))
+            })?;
+            size += frame.len() as u64;
+            if size > max {
+                return Err(ApiError::Validation(t!("file-too-large", max = max)));
+            }
+            hasher.update(&frame);
+            while !frame.is_empty() {
+                let take = frame.len().min(chunk_size - chunk.len());
+                chunk.extend_from_slice(&frame.split_to(take));
+                if chunk.len() == chunk_size {
+                    file.write_all(&chunk).await.map_err(anyhow::Error::from)?;
+                    chunk.clear();
+                }
+            }
+        }
+        file.write_all(&chunk).await.map_err(anyhow::Error::from)?;
+        file.sync_all().await.map_err(anyhow::Error::from)?;
+        Ok((size, hex(&hasher.finalize())))
+    }
+
+    /// Size of the file at `key`, if there is one
+    pub async fn size(&self, key: &str) -> ApiResult<Option<u64>> {
+        match tokio::fs::metadata(self.path(key)?).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(metadata.len())),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow::Error::from(e).into()),
+        }
+    }
+
+    /// Open the file at `key` for reading, with its size
+    pub async fn open(&self, key: &str) -> ApiResult<(tokio::fs::File, u64)> {
+        let size = self
+            .size(key)
+            .await?
+            .ok_or_else(|| ApiError::NotFound(t!("file-not-found")))?;
+        let file = tokio::fs::File::open(self.path(key)?)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok((file, size))
+    }
+
+    /// Remove the file at `key`, returning the bytes freed
+    pub async fn delete(&self, key: &str) -> ApiResult<u64> {
+        let size = self
+            .size(key)
+            .await?
+            .ok_or_else(|| ApiError::NotFound(t!("file-not-found")))?;
+        tokio::fs::remove_file(self.path(key)?)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(size)
+    }
+
+    /// The file or directory at `key`, the storage directory for `""`
+    pub async fn entry(&self, key: &str) -> ApiResult<Option<Entry>> {
+        let path = if key.is_empty() {
+            self.config.dir.clone()
+        } else {
+            self.path(key)?
+        };
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) => Ok(Some(entry(key.to_string(), &metadata))),
+            // Nothing has been stored yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && key.is_empty() => {
+                Ok(Some(Entry {
+                    key: String::new(),
+                    dir: true,
+                    size: 0,
+                    modified: DateTime::UNIX_EPOCH,
+                }))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow::Error::from(e).into()),
+        }
+    }
+
+    /// The files and directories directly in the directory at `key`
+    ///
+    /// Names that are not valid keys, such as uploads in progress, are left
+    /// out.
+    pub async fn list(&self, key: &str) -> ApiResult<Vec<Entry>> {
+        let path = if key.is_empty() {
+            self.config.dir.clone()
+        } else {
+            self.path(key)?
+        };
+        let mut dir = match tokio::fs::read_dir(&path).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow::Error::from(e).into()),
+        };
+        let mut entries = Vec::new();
+        while let Some(child) = dir.next_entry().await.map_err(anyhow::Error::from)? {
+            let Some(name) = child.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            let key = match key {
+                "" => name,
+                _ => format!("{}/{}", key, name),
+            };
+            if !valid_key(&key) {
+                continue;
+            }
+            let metadata = child.metadata().await.map_err(anyhow::Error::from)?;
+            entries.push(entry(key, &metadata));
+        }
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(entries)
+    }
+
+    /// Create the directory at `key`, whose parent must exist
+    pub async fn create_dir(&self, key: &str) -> ApiResult<()> {
+        let path = self.path(key)?;
+        tokio::fs::create_dir_all(&self.config.dir)
+            .await
+            .map_err(anyhow::Error::from)?;
+        tokio::fs::create_dir(path)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Move the file or directory at `from` to `to`, whose parent must exist
+    pub async fn rename(&self, from: &str, to: &str) -> ApiResult<()> {
+        tokio::fs::rename(self.path(from)?, self.path(to)?)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Remove the file or directory at `key` with everything in it,
+    /// returning the bytes freed
+    pub async fn remove(&self, key: &str) -> ApiResult<u64> {
+        let path = self.path(key)?;
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(ApiError::NotFound(t!("file-not-found")))
+            }
+            Err(e) => return Err(anyhow::Error::from(e).into()),
+        };
+        if !metadata.is_dir() {
+            return self.delete(key).await;
+        }
+        let mut freed = 0;
+        let mut pending = vec![path.clone()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .map_err(anyhow::Error::from)?;
+            while let Some(child) = entries.next_entry().await.map_err(anyhow::Error::from)? {
+                let metadata = child.metadata().await.map_err(anyhow::Error::from)?;
+                if metadata.is_dir() {
+                    pending.push(child.path());
+                } else if !child.file_name().to_string_lossy().starts_with('.') {
+                    // Uploads in progress were never accounted
+                    freed += metadata.len();
+                }
+            }
+        }
+        tokio::fs::remove_dir_all(path)
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(freed)
+    }
+
+    /// A file's contents as a body read one chunk at a time
+    pub fn stream(&self, file: tokio::fs::File) -> Body {
+        let chunk_size = self.config.chunk_size;
+        Body::from_stream(futures_util::stream::try_unfold(
+            file,
+            move |mut file| async move {
+                let mut chunk = BytesMut::with_capacity(chunk_size);
+                let read = file.read_buf(&mut chunk).await?;
+                Ok::<_, std::io::Error>((read > 0).then(|| (chunk.freeze(), file)))
+            },
+        ))
+    }
+}
+
+fn entry(key: String, metadata: &std::fs::Metadata) -> Entry {
+    Entry {
+        key,
+        dir: metadata.is_dir(),
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        modified: metadata
+            .modified()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or(DateTime::UNIX_EPOCH),
+    }
+}
+
+/// Check a key such as `avatars/alice.png`
+pub(crate) fn valid_key(key: &str) -> bool {
+    (1..=MAX_KEY_LEN).contains(&key.len())
+        && key.split('/').all(|segment| {
+            !segment.is_empty()
+                && !segment.starts_with('.')
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        })
+}
+
+/// Route module uploading, downloading and deleting files
+pub struct StorageModule;
+
+impl RouteModule for StorageModule {
+    fn name(&self) -> &'static str {
+        "storage"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route(
+            "/api/v1/files/*key",
+            put(put_file).get(get_file).delete(delete_file),
+        )
+    }
+}
+
+/// `PUT /api/v1/files/*key` - store the request body as a file
+pub async fn put_file(
+    Ext(storage): Ext<Storage>,
+    Path(key): Path<String>,
+    meter: Meter,
+    headers: HeaderMap,
+    body: Body,
+) -> ApiResult<(StatusCode, Json<StoredFile>)> {
+    let declared = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > storage.config.max_file_size) {
+        return Err(ApiError::Validation(t!(
+            "file-too-large",
+            max = storage.config.max_file_size
+        )));
+    }
+    let expected = headers
+        .get(DIGEST_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim);
+    let previous = storage.size(&key).await?;
+    let stored = storage.put(&key, body.into_data_stream(), expected).await?;
+    meter.storage(stored.size as i64 - previous.unwrap_or(0) as i64);
+    let status = match previous {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::CREATED,
+    };
+    Ok((status, Json(stored)))
+}
+
+/// `GET /api/v1/files/*key` - a file's contents
+pub async fn get_file(Ext(storage): Ext<Storage>, Path(key): Path<String>) -> ApiResult<Response> {
+    let (file, size) = storage.open(&key).await?;
+    Ok((
+        [
+            (CONTENT_TYPE, "application/octet-stream".to_string()),
+            (CONTENT_LENGTH, size.to_string()),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        storage.stream(file),
+    )
+        .into_response())
+}
+
+/// `DELETE /api/v1/files/*key` - remove a file
+pub async fn delete_file(
+    Ext(storage): Ext<Storage>,
+    Path(key): Path<String>,
+    meter: Meter,
+) -> ApiResult<StatusCode> {
+    let size = storage.delete(&key).await?;
+    meter.storage(-(size as i64));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::sha256_hex;
+
+    fn storage(dir: &FsPath) -> Storage {
+        Storage::new(StorageConfig {
+            dir: dir.to_path_buf(),
+            chunk_size: 4,
+            max_file_size: 16,
+        })
+    }
+
+    fn frames(frames: &[&'static str]) -> impl Stream<Item = Result<Bytes, String>> + Unpin {
+        futures_util::stream::iter(
+            frames
+                .iter()
+                .map(|f| Ok(Bytes::from_static(f.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn leftovers(dir: &FsPath) -> usize {
+        std::fs::read_dir(dir)
+            .map(|entries| entries.count())
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn test_valid_key() {
+        assert!(valid_key("a.txt") && valid_key("avatars/alice-1_2.png"));
+        assert!(!valid_key("") && !valid_key("../etc/passwd") && !valid_key("a//b"));
+        assert!(!valid_key(".upload-x") && !valid_key("a/.hidden") && !valid_key("a b"));
+    }
+
+    #[tokio::test]
+    async fn test_streams_uploads_to_disk() {
+        let dir = std::env::temp_dir().join(format!("storage-{}", std::process::id()));
+        let storage = storage(&dir);
+
+        let stored = storage
+            .put("docs/a.txt", frames(&["hello", " ", "world"]), None)
+            .await
+            .unwrap();
+        assert_eq!(stored.size, 11);
+        assert_eq!(stored.sha256, sha256_hex("hello world"));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("docs/a.txt")).unwrap(),
+            "hello world"
+        );
+        assert_eq!(storage.size("docs/a.txt").await.unwrap(), Some(11));
+
+        let (file, size) = storage.open("docs/a.txt").await.unwrap();
+        assert_eq!(size, 11);
+        let body = axum::body::to_bytes(storage.stream(file), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"hello world");
+
+        assert!(matches!(
+            storage
+                .put("docs/b.txt", frames(&["0123456789", "0123456789"]), None)
+                .await,
+            Err(ApiError::Validation(_))
+        ));
+        assert!(matches!(
+            storage
+                .put("docs/b.txt", frames(&["abc"]), Some("00"))
+                .await,
+            Err(ApiError::Validation(_))
+        ));
+        let broken = futures_util::stream::iter(vec![
+            Ok(Bytes::from_static(b"abc")),
+            Err("reset".to_string()),
+        ]);
+        assert!(matches!(
+            storage.put("docs/b.txt", broken, None).await,
+            Err(ApiError::BadRequest(_))
+        ));
+        assert_eq!(
+            leftovers(&dir.join("docs")),
+            1,
+            "failed uploads leave nothing"
+        );
+
+        let digest = sha256_hex("abc").to_uppercase();
+        storage
+            .put("docs/a.txt", frames(&["abc"]), Some(&digest))
+            .await
+            .unwrap();
+        assert_eq!(storage.delete("docs/a.txt").await.unwrap(), 3);
+        assert!(matches!(
+            storage.delete("docs/a.txt").await,
+            Err(ApiError::NotFound(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_directories() {
+        let dir = std::env::temp_dir().join(format!("storage-dirs-{}", std::process::id()));
+        let storage = storage(&dir);
+        assert_eq!(storage.list("").await.unwrap(), Vec::new());
+        assert!(storage.entry("").await.unwrap().unwrap().dir);
+
+        storage.create_dir("docs").await.unwrap();
+        storage
+            .put("docs/a.txt", frames(&["hello"]), None)
+            .await
+            .unwrap();
+        std::fs::write(dir.join("docs/.upload-x"), "partial").unwrap();
+        let keys: Vec<_> = storage
+            .list("docs")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.key, entry.dir, entry.size))
+            .collect();
+        assert_eq!(keys, [("docs/a.txt".to_string(), false, 5)]);
+        assert!(storage.create_dir("missing/docs").await.is_err());
+
+        storage.rename("docs", "archive").await.unwrap();
+        assert_eq!(storage.entry("docs").await.unwrap(), None);
+        assert_eq!(storage.size("archive/a.txt").await.unwrap(), Some(5));
+        assert_eq!(storage.remove("archive").await.unwrap(), 5);
+        assert_eq!(storage.entry("archive").await.unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
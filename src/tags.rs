@@ -0,0 +1,60 @@
+//! Free-form tags shared by bookmarks and notes.
+//!
+//! Tags are compared after normalization: lowercase, with runs of
+//! whitespace turned into `-`, so `Read Later` and `read-later` are the
+//! same tag. Commas are refused because they separate tags in imports.
+
+use crate::error::{ApiError, ApiResult};
+use crate::t;
+
+/// Most tags on one item
+pub const MAX_TAGS: usize = 32;
+
+/// Longest tag, in characters
+pub const MAX_TAG_LEN: usize = 64;
+
+/// Normalize a tag, or `None` if it cannot be one
+pub fn normalize(tag: &str) -> Option<String> {
+    let tag = tag
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_lowercase();
+    (!tag.is_empty() && tag.chars().count() <= MAX_TAG_LEN && !tag.contains(',')).then_some(tag)
+}
+
+/// Normalize a list of tags, dropping duplicates
+pub fn normalize_all(tags: &[String]) -> ApiResult<Vec<String>> {
+    if tags.len() > MAX_TAGS {
+        return Err(ApiError::Validation(t!("tags-too-many", max = MAX_TAGS)));
+    }
+    let mut normalized = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = normalize(tag)
+            .ok_or_else(|| ApiError::Validation(t!("tag-invalid", tag = tag.as_str())))?;
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("  Read   Later ").as_deref(), Some("read-later"));
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("a,b"), None);
+        assert_eq!(normalize(&"x".repeat(MAX_TAG_LEN + 1)), None);
+        let tags = [
+            "Rust".to_string(),
+            "rust".to_string(),
+            "Web Dev".to_string(),
+        ];
+        assert_eq!(normalize_all(&tags).unwrap(), ["rust", "web-dev"]);
+        assert!(normalize_all(&[" ".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+//! Server-rendered HTML templates.
+//!
+//! Handlers return [`Html<T>`] where `T` implements [`Template`]; the
+//! template named by `T::NAME` is rendered with `T` serialized as its
+//! context. Built-in templates are embedded in the binary and can be
+//! overridden by files of the same name in `TEMPLATES_DIR`. Debug builds
+//! re-read overrides on every render so edits show up without a restart.
+//!
+//! The syntax is a small subset of Jinja:
+//!
+//! - `{{ user.name }}` inserts an HTML-escaped value
+//! - `{% if errors %}...{% else %}...{% endif %}` tests truthiness
+//! - `{% for item in items %}...{% endfor %}` loops over an array
+
+use anyhow::{Context, Result};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::error::ApiError;
+use crate::html::escape_into;
+
+/// Templates shipped with the binary
+const BUILTIN: &[(&str, &str)] = &[
+    (
+        "authorize.html",
+        include_str!("../templates/authorize.html"),
+    ),
+    ("login.html", include_str!("../templates/login.html")),
+    ("status.html", include_str!("../templates/status.html")),
+];
+
+static TEMPLATES_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+#[cfg(not(debug_assertions))]
+static CACHE: OnceLock<
+    std::sync::RwLock<std::collections::HashMap<String, std::sync::Arc<Vec<Node>>>>,
+> = OnceLock::new();
+
+/// Set the directory searched for template overrides
+///
+/// Only the first call has an effect.
+pub fn init(dir: Option<PathBuf>) {
+    let _ = TEMPLATES_DIR.set(dir);
+}
+
+/// A serializable page context bound to a template file
+pub trait Template: Serialize {
+    /// File name of the template, e.g. `status.html`
+    const NAME: &'static str;
+}
+
+/// Responder rendering a [`Template`] as `text/html`
+pub struct Html<T>(pub T);
+
+impl<T: Template> IntoResponse for Html<T> {
+    fn into_response(self) -> Response {
+        match render(T::NAME, &self.0) {
+            Ok(body) => axum::response::Html(body).into_response(),
+            Err(e) => ApiError::Internal(e).into_response(),
+        }
+    }
+}
+
+/// Render the named template with a serializable context
+pub fn render(name: &str, context: &impl Serialize) -> Result<String> {
+    let context = serde_json::to_value(context)?;
+    let nodes = load(name)?;
+    let mut out = String::new();
+    let mut scope = Vec::new();
+    render_nodes(&nodes, &context, &mut scope, &mut out);
+    Ok(out)
+}
+
+#[cfg(debug_assertions)]
+fn load(name: &str) -> Result<std::sync::Arc<Vec<Node>>> {
+    Ok(std::sync::Arc::new(parse(&source(name)?)?))
+}
+
+#[cfg(not(debug_assertions))]
+fn load(name: &str) -> Result<std::sync::Arc<Vec<Node>>> {
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some(nodes) = cache.read().expect("template cache poisoned").get(name) {
+        return Ok(nodes.clone());
+    }
+    let nodes = std::sync::Arc::new(parse(&source(name)?)?);
+    cache
+        .write()
+        .expect("template cache poisoned")
+        .insert(name.to_string(), nodes.clone());
+    Ok(nodes)
+}
+
+fn source(name: &str) -> Result<String> {
+    if let Some(dir) = TEMPLATES_DIR.get().and_then(Option::as_ref) {
+        let path = dir.join(name);
+        if path.is_file() {
+            return std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read template {}", path.display()));
+        }
+    }
+    BUILTIN
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(_, source)| source.to_string())
+        .with_context(|| format!("Unknown template '{}'", name))
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Var(String),
+    If {
+        path: String,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        var: String,
+        path: String,
+        body: Vec<Node>,
+    },
+}
+
+/// Parse template source into a node tree
+fn parse(source: &str) -> Result<Vec<Node>> {
+    let mut rest = source;
+    let (nodes, end) = parse_block(&mut rest)?;
+    match end {
+        None => Ok(nodes),
+        Some(tag) => anyhow::bail!("Unexpected {{% {} %}}", tag),
+    }
+}
+
+/// Parse nodes until the end of input or a closing tag, which is returned
+fn parse_block(rest: &mut &str) -> Result<(Vec<Node>, Option<String>)> {
+    let mut nodes = Vec::new();
+    loop {
+        let Some(start) = rest.find("{{").into_iter().chain(rest.find("{%")).min() else {
+            if !rest.is_empty() {
+                nodes.push(Node::Text(rest.to_string()));
+            }
+            *rest = "";
+            return Ok((nodes, None));
+        };
+        if start > 0 {
+            nodes.push(Node::Text(rest[..start].to_string()));
+        }
+        let is_var = rest[start..].starts_with("{{");
+        let close = if is_var { "}}" } else { "%}" };
+        let body_start = start + 2;
+        let end = rest[body_start..]
+            .find(close)
+            .map(|i| body_start + i)
+            .context("Unclosed template tag")?;
+        let tag = rest[body_start..end].trim().to_string();
+        *rest = &rest[end + 2..];
+
+        if is_var {
+            nodes.push(Node::Var(tag));
+            continue;
+        }
+        let words: Vec<&str> = tag.split_whitespace().collect();
+        match words.as_slice() {
+            ["if", path] => {
+                let (then, end) = parse_block(rest)?;
+                let otherwise = match end.as_deref() {
+                    Some("endif") => Vec::new(),
+                    Some("else") => match parse_block(rest)? {
+                        (otherwise, Some(end)) if end == "endif" => otherwise,
+                        _ => anyhow::bail!("Missing {{% endif %}}"),
+                    },
+                    _ => anyhow::bail!("Missing {{% endif %}}"),
+                };
+                nodes.push(Node::If {
+                    path: path.to_string(),
+                    then,
+                    otherwise,
+                });
+            }
+            ["for", var, "in", path] => match parse_block(rest)? {
+                (body, Some(end)) if end == "endfor" => nodes.push(Node::For {
+                    var: var.to_string(),
+                    path: path.to_string(),
+                    body,
+                }),
+                _ => anyhow::bail!("Missing {{% endfor %}}"),
+            },
+            ["else"] | ["endif"] | ["endfor"] => return Ok((nodes, Some(tag))),
+            _ => anyhow::bail!("Unknown template tag '{{% {} %}}'", tag),
+        }
+    }
+}
+
+fn render_nodes<'a>(
+    nodes: &'a [Node],
+    context: &'a Value,
+    scope: &mut Vec<(&'a str, &'a Value)>,
+    out: &mut String,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var(path) => {
+                if let Some(value) = lookup(path, context, scope) {
+                    escape_into(&display(value), out);
+                }
+            }
+            Node::If {
+                path,
+                then,
+                otherwise,
+            } => {
+                let branch = if lookup(path, context, scope).is_some_and(truthy) {
+                    then
+                } else {
+                    otherwise
+                };
+                render_nodes(branch, context, scope, out);
+            }
+            Node::For { var, path, body } => {
+                if let Some(Value::Array(items)) = lookup(path, context, scope) {
+                    for item in items {
+                        scope.push((var, item));
+                        render_nodes(body, context, scope, out);
+                        scope.pop();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Resolve a dotted path against loop variables, then the root context
+fn lookup<'a>(path: &str, context: &'a Value, scope: &[(&str, &'a Value)]) -> Option<&'a Value> {
+    let mut parts = path.split('.');
+    let first = parts.next()?;
+    let mut value = match scope.iter().rev().find(|(name, _)| *name == first) {
+        Some((_, value)) => *value,
+        None => context.get(first)?,
+    };
+    for part in parts {
+        value = value.get(part)?;
+    }
+    Some(value)
+}
+
+fn truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(a) => !a.is_empty(),
+        Value::Object(o) => !o.is_empty(),
+    }
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render_str(source: &str, context: Value) -> String {
+        let mut out = String::new();
+        render_nodes(&parse(source).unwrap(), &context, &mut Vec::new(), &mut out);
+        out
+    }
+
+    #[test]
+    fn test_render_escapes_and_loops() {
+        let out = render_str(
+            "<h1>{{ title }}</h1>{% for c in checks %}<li>{{ c.name }}:{{ c.ms }}</li>{% endfor %}\
+             {% if error %}!{% else %}ok{% endif %}",
+            json!({
+                "title": "<b>Status</b>",
+                "checks": [{"name": "db", "ms": 3}, {"name": "smtp", "ms": 12}],
+                "error": null
+            }),
+        );
+        assert_eq!(
+            out,
+            "<h1>&lt;b&gt;Status&lt;/b&gt;</h1><li>db:3</li><li>smtp:12</li>ok"
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_unbalanced_tags() {
+        assert!(parse("{% if x %}open").is_err());
+        assert!(parse("{% endfor %}").is_err());
+        assert!(parse("{{ unclosed").is_err());
+        assert!(parse("{% while x %}{% endwhile %}").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+//! Disposable Postgres for the crate's own tests.
+//!
+//! [`TestDatabase::start`] launches a `postgres:16` container with
+//! testcontainers and applies the built-in module migrations. The container
+//! is removed when the fixture is dropped. When `TEST_DATABASE_URL` is set
+//! that database is used instead, with a schema of its own per fixture that
+//! is dropped afterwards.
+//!
+//! Without Docker or `TEST_DATABASE_URL` the fixture returns `None` and
+//! the test passes vacuously with a note on stderr, except under `CI` where
+//! it fails so database tests can't silently stop running.
+
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::PgPool;
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::{runners::AsyncRunner, ContainerAsync, ImageExt};
+
+use crate::config::Config;
+use crate::module;
+
+/// A migrated database for one test
+pub struct TestDatabase {
+    pub url: String,
+    pub pool: PgPool,
+    schema: Option<String>,
+    _container: Option<ContainerAsync<Postgres>>,
+}
+
+impl TestDatabase {
+    /// Start a database, or `None` if none is available outside CI
+    pub async fn start() -> Option<Self> {
+        let (url, container) = match std::env::var("TEST_DATABASE_URL") {
+            Ok(url) => (url, None),
+            Err(_) => match Self::container().await {
+                Ok((url, container)) => (url, Some(container)),
+                Err(e) if std::env::var_os("CI").is_none() => {
+                    eprintln!("skipping database test: {:#}", e);
+                    return None;
+                }
+                Err(e) => panic!("Failed to start Postgres container: {:#}", e),
+            },
+        };
+        let schema = container.is_none().then(|| {
+            format!(
+                "test_{:016x}",
+                rand_core::RngCore::next_u64(&mut rand_core::OsRng)
+            )
+        });
+        let mut options = url
+            .parse::<PgConnectOptions>()
+            .expect("Invalid test database URL");
+        if let Some(schema) = &schema {
+            sqlx::query(&format!("CREATE SCHEMA {}", schema))
+                .execute(&PgPool::connect_with(options.clone()).await.unwrap())
+                .await
+                .expect("Failed to create test schema");
+            options = options.options([("search_path", schema.as_str())]);
+        }
+        let pool = PgPoolOptions::new()
+            .connect_with(options)
+            .await
+            .expect("Failed to connect to the test database");
+        module::run_migrations(&pool, &module::builtin_modules())
+            .await
+            .expect("Failed to migrate the test database");
+        Some(TestDatabase {
+            url,
+            pool,
+            schema,
+            _container: container,
+        })
+    }
+
+    async fn container() -> anyhow::Result<(String, ContainerAsync<Postgres>)> {
+        let container = Postgres::default().with_tag("16-alpine").start().await?;
+        let url = format!(
+            "postgres://postgres:postgres@{}:{}/postgres",
+            container.get_host().await?,
+            container.get_host_port_ipv4(5432).await?
+        );
+        Ok((url, container))
+    }
+
+    /// Configuration pointing at this database
+    pub fn config(&self) -> Config {
+        Config::from_vars(|key| (key == "DATABASE_URL").then(|| self.url.clone()))
+            .expect("default configuration is valid")
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        let Some(schema) = self.schema.take() else {
+            return;
+        };
+        let url = self.url.clone();
+        // The test's runtime may be shutting down; drop the schema from a
+        // runtime of our own.
+        let dropped = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            runtime.block_on(async {
+                let pool = PgPool::connect(&url).await?;
+                sqlx::query(&format!("DROP SCHEMA {} CASCADE", schema))
+                    .execute(&pool)
+                    .await?;
+                anyhow::Ok(())
+            })
+        })
+        .join();
+        if let Ok(Err(e)) = dropped {
+            eprintln!("Failed to drop test schema: {:#}", e);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Test harness for integration tests.
+//!
+//! Enabled with the `testing` feature. [`TestServer::spawn`] builds the full
+//! server on an ephemeral loopback port against a fresh Postgres schema, so
+//! tests can run in parallel against one database without seeing each
+//! other's rows. Requests are made with [`TestServer::get`] and friends and
+//! authenticated with the [`ADMIN_TOKEN`] and [`API_KEY`] fixtures.
+//!
+//! ```no_run
+//! use rust_selfhost_server::testing::{self, TestServer};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let server = TestServer::spawn(testing::config(&[])?).await?;
+//! let response = server.get("/admin/settings").admin().send().await?;
+//! assert_eq!(response.status, 200);
+//! server.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    header::{HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    HeaderMap, Method, Request, StatusCode,
+};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::PgPool;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::db::Database;
+use crate::{AppState, ServerBuilder};
+
+/// Admin token configured by [`config`]
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+/// API key configured by [`config`]
+pub const API_KEY: &str = "test-api-key";
+
+/// Configuration for a test server
+///
+/// The database comes from `TEST_DATABASE_URL`, falling back to
+/// `DATABASE_URL`. Other variables take their defaults except for the
+/// [`ADMIN_TOKEN`] and [`API_KEY`] fixtures and a disabled status sampler;
+/// `overrides` replace any of them.
+pub fn config(overrides: &[(&str, &str)]) -> Result<Config> {
+    let database_url = std::env::var("TEST_DATABASE_URL")
+        .or_else(|_| std::env::var("DATABASE_URL"))
+        .ok();
+    Config::from_vars(|key| {
+        if let Some((_, value)) = overrides.iter().find(|(name, _)| *name == key) {
+            return Some(value.to_string());
+        }
+        match key {
+            "DATABASE_URL" => database_url.clone(),
+            "ADMIN_TOKEN" => Some(ADMIN_TOKEN.to_string()),
+            "API_KEYS" => Some(API_KEY.to_string()),
+            "STATUS_SAMPLE_INTERVAL" => Some("0s".to_string()),
+            // Results would depend on the host's free space
+            "HEALTH_DISK_MIN_FREE" => Some("0".to_string()),
+            "HEALTH_DISK_WARN_FREE" => Some("0".to_string()),
+            // The self-test would create the crash directory in the working directory
+            "STARTUP_SELF_TEST" => Some("ignore".to_string()),
+            _ => None,
+        }
+    })
+}
+
+/// A running server isolated in its own database schema
+pub struct TestServer {
+    addr: SocketAddr,
+    state: AppState,
+    schema: String,
+    admin_pool: PgPool,
+    client: Client<HttpConnector, Full<Bytes>>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl TestServer {
+    /// Boot the server with default modules
+    pub async fn spawn(config: Config) -> Result<Self> {
+        Self::spawn_with(config, |builder| builder).await
+    }
+
+    /// Boot the server after customizing the builder, e.g. to add modules
+    pub async fn spawn_with(
+        config: Config,
+        customize: impl FnOnce(ServerBuilder) -> ServerBuilder,
+    ) -> Result<Self> {
+        let admin_pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(config.database_url())
+            .await
+            .context("Failed to connect to the test database")?;
+        let schema = format!(
+            "test_{:016x}",
+            rand_core::RngCore::next_u64(&mut rand_core::OsRng)
+        );
+        sqlx::query(&format!("CREATE SCHEMA {}", schema))
+            .execute(&admin_pool)
+            .await?;
+
+        let options = config
+            .database_url()
+            .parse::<PgConnectOptions>()?
+            .options([("search_path", schema.as_str())]);
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections())
+            .connect_with(options)
+            .await?;
+        let state = AppState::new(Database::from_pool(pool));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (shutdown, mut stop) = watch::channel(false);
+        let builder = ServerBuilder::new(config)
+            .state(state.clone())
+            .listener(listener)
+            .shutdown_signal(async move {
+                let _ = stop.wait_for(|stop| *stop).await;
+            });
+        let server = customize(builder).build().await?;
+        let addr = server.local_addrs()[0];
+        let task = tokio::spawn(server.serve());
+
+        Ok(TestServer {
+            addr,
+            state,
+            schema,
+            admin_pool,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            shutdown,
+            task,
+        })
+    }
+
+    /// Address the server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Absolute URL of `path` on this server
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// The server's shared state, e.g. to seed the database
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    /// Connection pool scoped to this server's schema
+    pub fn pool(&self) -> &PgPool {
+        self.state.db.pool()
+    }
+
+    /// Start building a request
+    pub fn request(&self, method: Method, path: &str) -> TestRequest<'_> {
+        TestRequest {
+            server: self,
+            method,
+            path: path.to_string(),
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    /// Start building a `GET` request
+    pub fn get(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::GET, path)
+    }
+
+    /// Start building a `POST` request
+    pub fn post(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::POST, path)
+    }
+
+    /// Start building a `PUT` request
+    pub fn put(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::PUT, path)
+    }
+
+    /// Start building a `DELETE` request
+    pub fn delete(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::DELETE, path)
+    }
+
+    /// Stop the server and drop its schema
+    ///
+    /// A server that is dropped without calling this keeps running until
+    /// the test's runtime exits and leaves its schema behind.
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown.send(true);
+        let served = self.task.await.context("Test server panicked")?;
+        sqlx::query(&format!("DROP SCHEMA {} CASCADE", self.schema))
+            .execute(&self.admin_pool)
+            .await?;
+        served
+    }
+}
+
+/// A request being built against a [`TestServer`]
+pub struct TestRequest<'a> {
+    server: &'a TestServer,
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl TestRequest<'_> {
+    /// Add a header
+    pub fn header(mut self, name: HeaderName, value: &str) -> Self {
+        self.headers.insert(
+            name,
+            HeaderValue::from_str(value).expect("invalid header value"),
+        );
+        self
+    }
+
+    /// Authenticate with a bearer token
+    pub fn bearer(self, token: &str) -> Self {
+        self.header(AUTHORIZATION, &format!("Bearer {}", token))
+    }
+
+    /// Authenticate with the [`ADMIN_TOKEN`] fixture
+    pub fn admin(self) -> Self {
+        self.bearer(ADMIN_TOKEN)
+    }
+
+    /// Authenticate with the [`API_KEY`] fixture
+    pub fn api_key(self) -> Self {
+        self.bearer(API_KEY)
+    }
+
+    /// Send a raw body
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Send a JSON body
+    pub fn json(mut self, body: &impl Serialize) -> Self {
+        self.body = Bytes::from(serde_json::to_vec(body).expect("unserializable body"));
+        self.header(CONTENT_TYPE, "application/json")
+    }
+
+    /// Send the request and read the whole response
+    pub async fn send(self) -> Result<TestResponse> {
+        let mut request = Request::builder()
+            .method(self.method)
+            .uri(self.server.url(&self.path))
+            .body(Full::new(self.body))?;
+        *request.headers_mut() = self.headers;
+        let response = self.server.client.request(request).await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.into_body().collect().await?.to_bytes();
+        Ok(TestResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// A fully read response
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    /// Parse the body as JSON
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body).context("Response body is not valid JSON")
+    }
+
+    /// The body as text
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db::TestDatabase;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_server_isolates_schema() {
+        let Some(db) = TestDatabase::start().await else {
+            return;
+        };
+        let config = || config(&[("DATABASE_URL", &db.url)]).unwrap();
+        let server = TestServer::spawn(config()).await.unwrap();
+
+        let response = server.get("/health").send().await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+
+        let response = server
+            .put("/admin/settings/greeting")
+            .json(&json!("hi"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        let response = server
+            .put("/admin/settings/greeting")
+            .admin()
+            .json(&json!("hi"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.json::<Value>().unwrap()["value"], "hi");
+
+        let other = TestServer::spawn(config()).await.unwrap();
+        let response = other
+            .get("/admin/settings/greeting")
+            .admin()
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+
+        server.shutdown().await.unwrap();
+        other.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+//! Timestamp conventions of the API.
+//!
+//! Every timestamp the API returns is RFC 3339 in UTC with millisecond
+//! precision, e.g. `2024-05-01T12:30:00.250Z`: response fields use
+//! `#[serde(with = "time::rfc3339")]` (or [`rfc3339_option`]) and inputs
+//! use [`Timestamp`], which rejects values without an offset rather than
+//! guessing one. Columns are `TIMESTAMPTZ`, so the database never sees a
+//! local time either.
+//!
+//! Users may store a display timezone (an IANA name such as
+//! `Europe/Berlin`) in their profile; it only affects rendering for
+//! people, through [`format_in`], never the API representation.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Format a timestamp the way the API does
+pub fn format(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Parse an RFC 3339 timestamp with any offset into UTC
+pub fn parse(input: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(input.trim())
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| {
+            format!(
+                "invalid timestamp '{}': {} (expected RFC 3339 with an offset, e.g. 2024-05-01T12:30:00Z)",
+                input, e
+            )
+        })
+}
+
+/// Check an IANA timezone name, e.g. `Europe/Berlin`
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    Tz::from_str(name).map_err(|_| format!("unknown timezone '{}'", name))
+}
+
+/// Format a timestamp in a display timezone, keeping its offset explicit
+pub fn format_in(time: &DateTime<Utc>, timezone: Option<&str>) -> String {
+    match timezone.and_then(|name| parse_timezone(name).ok()) {
+        Some(tz) => time
+            .with_timezone(&tz)
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+        None => time.to_rfc3339_opts(SecondsFormat::Secs, true),
+    }
+}
+
+/// Serde adapter for `DateTime<Utc>` fields
+pub mod rfc3339 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        time: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(time))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        parse(&input).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serde adapter for `Option<DateTime<Utc>>` fields
+pub mod rfc3339_option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_some(&format(time)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|input| parse(&input).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// A timestamp accepted from clients, in query strings or bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub DateTime<Utc>);
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        parse(input).map(Timestamp)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format(&self.0))
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        rfc3339::serialize(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        rfc3339::deserialize(deserializer).map(Timestamp)
+    }
+}
+
+impl From<Timestamp> for DateTime<Utc> {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_and_parse() {
+        let time = parse("2024-05-01T14:30:00.25+02:00").unwrap();
+        assert_eq!(format(&time), "2024-05-01T12:30:00.250Z");
+        assert!(parse("2024-05-01T12:30:00").is_err());
+        assert!(parse("2024-05-01").is_err());
+
+        let timestamp: Timestamp = serde_json::from_str("\"2024-05-01T12:30:00Z\"").unwrap();
+        assert_eq!(
+            serde_json::to_string(&timestamp).unwrap(),
+            "\"2024-05-01T12:30:00.000Z\""
+        );
+        assert!(serde_json::from_str::<Timestamp>("\"1714566600\"").is_err());
+    }
+
+    #[test]
+    fn test_display_timezone() {
+        let time = parse("2024-01-15T12:00:00Z").unwrap();
+        assert_eq!(
+            format_in(&time, Some("Europe/Berlin")),
+            "2024-01-15T13:00:00+01:00"
+        );
+        assert_eq!(format_in(&time, None), "2024-01-15T12:00:00Z");
+        assert!(parse_timezone("Europe/Berlin").is_ok());
+        assert!(parse_timezone("Mars/Olympus").is_err());
+    }
+
+    #[test]
+    fn test_migrations_use_timestamptz() {
+        let timestamp = regex::Regex::new(r"(?i)\bTIMESTAMP\b").unwrap();
+        for module in crate::module::builtin_modules() {
+            for migration in module.migrations() {
+                assert!(
+                    !timestamp.is_match(migration.sql),
+                    "{}/{} uses TIMESTAMP instead of TIMESTAMPTZ",
+                    module.name(),
+                    migration.name
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Transaction per request.
+//!
+//! The `transaction` middleware layer opens a database transaction before
+//! the handler runs and hands it over in the request extensions, where the
+//! [`Tx`] extractor picks it up. The transaction commits when the response
+//! is a success (2xx) and rolls back otherwise; if the handler panics, the
+//! transaction is dropped and rolled back with it. Handlers get atomic
+//! writes across several queries without plumbing a transaction through.
+//!
+//! Enable it per route group, e.g. `MIDDLEWARE_API=auth,transaction`. Each
+//! request then holds a pool connection for its whole duration, so it is
+//! best kept to groups of short, database-bound handlers.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::request::Parts,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+use crate::error::ApiError;
+
+/// The current request's transaction
+///
+/// Lock it to run queries: `query.execute(&mut *tx.lock().await?)`.
+#[derive(Clone)]
+pub struct Tx(Arc<Mutex<Option<Transaction<'static, Postgres>>>>);
+
+impl Tx {
+    /// Exclusive access to the transaction's connection
+    ///
+    /// Fails once the request has finished and the transaction was
+    /// committed or rolled back, e.g. in a task spawned by the handler.
+    pub async fn lock(&self) -> Result<MappedMutexGuard<'_, PgConnection>, ApiError> {
+        MutexGuard::try_map(self.0.lock().await, |tx| tx.as_deref_mut()).map_err(|_| {
+            ApiError::Internal(anyhow::anyhow!("the request transaction has already ended"))
+        })
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Tx {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Tx>().cloned().ok_or_else(|| {
+            ApiError::Internal(anyhow::anyhow!(
+                "no request transaction; enable the transaction middleware for this route group"
+            ))
+        })
+    }
+}
+
+/// Middleware running the request in a transaction
+pub async fn middleware(State(pool): State<PgPool>, mut request: Request, next: Next) -> Response {
+    let tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return ApiError::from(e).into_response(),
+    };
+    let handle = Tx(Arc::new(Mutex::new(Some(tx))));
+    request.extensions_mut().insert(handle.clone());
+    let response = next.run(request).await;
+
+    let Some(tx) = handle.0.lock().await.take() else {
+        return response;
+    };
+    if response.status().is_success() {
+        if let Err(e) = tx.commit().await {
+            return ApiError::Internal(
+                anyhow::Error::new(e).context("Failed to commit the request transaction"),
+            )
+            .into_response();
+        }
+    } else if let Err(e) = tx.rollback().await {
+        tracing::warn!("Failed to roll back the request transaction: {}", e);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, routing::post, Router};
+    use tower::Service;
+
+    #[tokio::test]
+    async fn test_commits_on_success_and_rolls_back_on_error() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = db.pool.clone();
+        sqlx::query("CREATE TABLE tx_items (name TEXT NOT NULL)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let insert = |status: StatusCode| {
+            move |tx: Tx, name: String| async move {
+                sqlx::query("INSERT INTO tx_items (name) VALUES ($1)")
+                    .bind(name)
+                    .execute(&mut *tx.lock().await?)
+                    .await?;
+                Ok::<_, ApiError>(status)
+            }
+        };
+        let mut app = Router::new()
+            .route("/ok", post(insert(StatusCode::CREATED)))
+            .route("/fail", post(insert(StatusCode::CONFLICT)))
+            .layer(axum::middleware::from_fn_with_state(
+                pool.clone(),
+                middleware,
+            ));
+        for (path, status) in [
+            ("/ok", StatusCode::CREATED),
+            ("/fail", StatusCode::CONFLICT),
+        ] {
+            let request = Request::post(path).body(Body::from(path)).unwrap();
+            assert_eq!(app.call(request).await.unwrap().status(), status);
+        }
+
+        let names: Vec<String> = sqlx::query_scalar("SELECT name FROM tx_items")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(names, ["/ok"]);
+    }
+}
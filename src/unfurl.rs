@@ -0,0 +1,305 @@
+//! Link previews.
+//!
+//! `GET /api/v1/unfurl?url=https://...` fetches a page server-side through
+//! the SSRF-guarded [`Fetcher`] (so `FETCH_TIMEOUT` and
+//! `FETCH_ALLOW_PRIVATE_NETWORKS` apply), reads at most 512 KiB of it and
+//! returns a [`Preview`] built from its OpenGraph and Twitter card
+//! metadata, falling back to `<title>` and `<meta name="description">`.
+//!
+//! Previews are cached in `unfurl_cache` for `UNFURL_CACHE_TTL`, so chat
+//! clients unfurling the same link do not all hit the site; failures are
+//! not cached.
+
+use axum::{
+    extract::{Query, State},
+    response::Json,
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::error::{ApiError, ApiResult};
+use crate::extensions::Ext;
+use crate::fetch::{is_web_url, resolve_url, FetchedPage, Fetcher};
+use crate::html;
+use crate::module::{Migration, MigrationKind, RouteModule};
+use crate::{t, AppState};
+
+/// Most of a page read for its metadata
+const MAX_PAGE_BYTES: usize = 512 * 1024;
+
+/// Longest title kept, in characters
+const MAX_TITLE_LEN: usize = 300;
+
+/// Longest description kept, in characters
+const MAX_DESCRIPTION_LEN: usize = 1000;
+
+/// A normalized link preview
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Preview {
+    /// The canonical URL, after redirects
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    pub image: Option<String>,
+    /// `og:type`, e.g. `article` or `video.other`, or `image` for images
+    pub kind: Option<String>,
+    pub icon: String,
+    #[serde(with = "crate::time::rfc3339")]
+    pub fetched_at: DateTime<Utc>,
+}
+
+/// Fetches and caches previews
+pub struct Unfurler {
+    pub fetcher: Fetcher,
+    pub cache_ttl: Duration,
+}
+
+/// Route module serving `/api/v1/unfurl`
+pub struct UnfurlModule;
+
+impl RouteModule for UnfurlModule {
+    fn name(&self) -> &'static str {
+        "unfurl"
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/api/v1/unfurl", get(unfurl_url))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_unfurl_cache",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS unfurl_cache (
+                url TEXT PRIMARY KEY,
+                preview JSONB NOT NULL,
+                fetched_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE INDEX IF NOT EXISTS unfurl_cache_fetched_at ON unfurl_cache (fetched_at)",
+        }]
+    }
+}
+
+fn clip(text: &str, max: usize) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!text.is_empty()).then(|| text.chars().take(max).collect())
+}
+
+/// Build a preview from a fetched page
+pub fn extract(page: &FetchedPage) -> Preview {
+    let mut preview = Preview {
+        url: page.url.to_string(),
+        icon: page.icon_url(),
+        // The precision the API serializes, so cached copies compare equal
+        fetched_at: Utc::now().trunc_subsecs(3),
+        ..Preview::default()
+    };
+    if page
+        .content_type
+        .as_deref()
+        .is_some_and(|c| c.starts_with("image/"))
+    {
+        preview.kind = Some("image".to_string());
+        preview.image = Some(preview.url.clone());
+        return preview;
+    }
+    if !page.is_html() {
+        return preview;
+    }
+
+    let document = page.text();
+    // OpenGraph uses `property`, Twitter cards and plain HTML use `name`;
+    // the first occurrence of a key wins
+    let mut meta: HashMap<String, String> = HashMap::new();
+    for mut tag in html::tags(&document, "meta") {
+        let key = tag.remove("property").or_else(|| tag.remove("name"));
+        if let (Some(key), Some(content)) = (key, tag.remove("content")) {
+            meta.entry(key.to_ascii_lowercase()).or_insert(content);
+        }
+    }
+    let first = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| meta.get(*key).filter(|value| !value.trim().is_empty()))
+            .cloned()
+    };
+    let absolute = |url: String| resolve_url(&page.url, &url).filter(|url| is_web_url(url));
+
+    preview.title = first(&["og:title", "twitter:title"])
+        .or_else(|| html::title(&document))
+        .and_then(|title| clip(&title, MAX_TITLE_LEN));
+    preview.description = first(&["og:description", "twitter:description", "description"])
+        .and_then(|description| clip(&description, MAX_DESCRIPTION_LEN));
+    preview.site_name = first(&["og:site_name", "application-name"])
+        .and_then(|name| clip(&name, MAX_TITLE_LEN))
+        .or_else(|| page.url.host().map(str::to_string));
+    preview.image = first(&[
+        "og:image:secure_url",
+        "og:image",
+        "og:image:url",
+        "twitter:image",
+        "twitter:image:src",
+    ])
+    .and_then(absolute);
+    preview.kind = first(&["og:type"]);
+    if let Some(canonical) = first(&["og:url"]).and_then(absolute) {
+        preview.url = canonical;
+    }
+    preview
+}
+
+/// Preview a URL, from the cache if it was fetched within `cache_ttl`
+pub async fn unfurl(pool: &PgPool, unfurler: &Unfurler, url: &str) -> ApiResult<Preview> {
+    if !is_web_url(url) {
+        return Err(ApiError::Validation(t!("unfurl-url", url = url)));
+    }
+    let ttl = unfurler.cache_ttl.as_secs_f64();
+    let cached: Option<sqlx::types::Json<Preview>> = sqlx::query_scalar(
+        "SELECT preview FROM unfurl_cache
+         WHERE url = $1 AND fetched_at > now() - make_interval(secs => $2)",
+    )
+    .bind(url)
+    .bind(ttl)
+    .fetch_optional(pool)
+    .await?;
+    if let Some(sqlx::types::Json(preview)) = cached {
+        return Ok(preview);
+    }
+
+    let page = unfurler
+        .fetcher
+        .get(url, MAX_PAGE_BYTES)
+        .await
+        .map_err(|e| {
+            ApiError::BadGateway(t!("unfurl-failed", url = url, reason = format!("{:#}", e)))
+        })?;
+    if !page.status.is_success() {
+        return Err(ApiError::BadGateway(t!(
+            "unfurl-failed",
+            url = url,
+            reason = page.status.to_string()
+        )));
+    }
+    let preview = extract(&page);
+
+    sqlx::query("DELETE FROM unfurl_cache WHERE fetched_at < now() - make_interval(secs => $1)")
+        .bind(ttl)
+        .execute(pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO unfurl_cache (url, preview, fetched_at) VALUES ($1, $2, $3)
+         ON CONFLICT (url) DO UPDATE SET preview = $2, fetched_at = $3",
+    )
+    .bind(url)
+    .bind(sqlx::types::Json(&preview))
+    .bind(preview.fetched_at)
+    .execute(pool)
+    .await?;
+    Ok(preview)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnfurlQuery {
+    url: String,
+}
+
+/// `GET /api/v1/unfurl?url=` - a preview of a link
+pub async fn unfurl_url(
+    State(state): State<AppState>,
+    Ext(unfurler): Ext<Unfurler>,
+    Query(query): Query<UnfurlQuery>,
+) -> ApiResult<Json<Preview>> {
+    Ok(Json(unfurl(state.db.pool(), &unfurler, &query.url).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    fn page(url: &str, content_type: &str, body: &str) -> FetchedPage {
+        FetchedPage {
+            url: url.parse().unwrap(),
+            status: StatusCode::OK,
+            content_type: Some(content_type.to_string()),
+            body: body.to_string().into(),
+        }
+    }
+
+    #[test]
+    fn test_extract() {
+        let preview = extract(&page(
+            "https://example.com/posts/1?utm_source=x",
+            "text/html; charset=utf-8",
+            r#"<html><head>
+                <title>Fallback title</title>
+                <meta property="og:title" content="Hello &amp; welcome">
+                <meta name="twitter:title" content="Twitter title">
+                <meta name="description" content="  A   post
+                    about things ">
+                <meta property="og:image" content="/images/cover.png">
+                <meta property="og:type" content="article">
+                <meta property="og:url" content="https://example.com/posts/1">
+                <link rel="icon" href="/icon.svg">
+            </head></html>"#,
+        ));
+        assert_eq!(preview.url, "https://example.com/posts/1");
+        assert_eq!(preview.title.as_deref(), Some("Hello & welcome"));
+        assert_eq!(preview.description.as_deref(), Some("A post about things"));
+        assert_eq!(preview.site_name.as_deref(), Some("example.com"));
+        assert_eq!(
+            preview.image.as_deref(),
+            Some("https://example.com/images/cover.png")
+        );
+        assert_eq!(preview.kind.as_deref(), Some("article"));
+        assert_eq!(preview.icon, "https://example.com/icon.svg");
+
+        let image = extract(&page("https://example.com/a.png", "image/png", ""));
+        assert_eq!(image.kind.as_deref(), Some("image"));
+        assert_eq!(image.icon, "https://example.com/favicon.ico");
+    }
+
+    #[tokio::test]
+    async fn test_unfurl_caches() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+
+        let site = Router::new().route(
+            "/",
+            get(|| async { axum::response::Html("<title>Cached page</title>") }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, site).await });
+
+        let guarded = Unfurler {
+            fetcher: Fetcher::new(Duration::from_secs(2), false),
+            cache_ttl: Duration::from_secs(60),
+        };
+        assert!(matches!(
+            unfurl(pool, &guarded, &url).await,
+            Err(ApiError::BadGateway(_))
+        ));
+        assert!(matches!(
+            unfurl(pool, &guarded, "file:///etc/passwd").await,
+            Err(ApiError::Validation(_))
+        ));
+
+        let unfurler = Unfurler {
+            fetcher: Fetcher::new(Duration::from_secs(2), true),
+            cache_ttl: Duration::from_secs(60),
+        };
+        let preview = unfurl(pool, &unfurler, &url).await.unwrap();
+        assert_eq!(preview.title.as_deref(), Some("Cached page"));
+        server.abort();
+        let _ = server.await;
+        assert_eq!(unfurl(pool, &unfurler, &url).await.unwrap(), preview);
+    }
+}
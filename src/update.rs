@@ -0,0 +1,221 @@
+//! Self-update from published releases.
+//!
+//! `self-update` reads the latest release from `UPDATE_URL`, a GitHub
+//! releases API URL (or anything serving the same JSON), and downloads the
+//! asset built for this platform, `rust-selfhost-server-<arch>-<os>`, with
+//! its detached signature `<asset>.sig`. The signature is a base64 Ed25519
+//! signature of the binary, checked against `UPDATE_PUBLIC_KEY` (a base64
+//! raw 32-byte key); nothing is installed without a valid one. The new
+//! binary is written next to the running one and renamed over it, so the
+//! path always holds a complete binary.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::Deserialize;
+use std::io::Write;
+use std::path::Path;
+
+use crate::fetch::Fetcher;
+
+/// Where releases are looked up by default
+pub const DEFAULT_RELEASES_URL: &str =
+    "https://api.github.com/repos/a-ariff/rust-selfhost-server/releases/latest";
+
+/// Largest binary downloaded
+const MAX_BINARY_BYTES: usize = 256 * 1024 * 1024;
+
+/// Largest release document or signature downloaded
+const MAX_METADATA_BYTES: usize = 1024 * 1024;
+
+/// A release, in the GitHub releases API format
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    /// The version, without a leading `v`
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    fn asset(&self, name: &str) -> Result<&Asset> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .with_context(|| format!("Release {} has no asset {}", self.tag_name, name))
+    }
+}
+
+/// Name of the release asset for this platform
+pub fn asset_name() -> String {
+    format!(
+        "{}-{}-{}",
+        env!("CARGO_PKG_NAME"),
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    )
+}
+
+/// Whether `version` is newer than `current`, comparing `major.minor.patch`
+///
+/// Pre-release and build suffixes are ignored; unparsable versions are
+/// never newer.
+pub fn is_newer(version: &str, current: &str) -> bool {
+    fn parse(version: &str) -> Option<(u64, u64, u64)> {
+        let core = version.trim_start_matches('v').split(['-', '+']).next()?;
+        let mut parts = core.split('.').map(|part| part.parse::<u64>().ok());
+        Some((
+            parts.next()??,
+            parts.next()??,
+            parts.next().unwrap_or(Some(0))?,
+        ))
+    }
+    matches!((parse(version), parse(current)), (Some(v), Some(c)) if v > c)
+}
+
+/// Check a base64 Ed25519 signature of `binary`
+pub fn verify(binary: &[u8], signature: &str, public_key: &str) -> Result<()> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let public_key = engine
+        .decode(public_key.trim())
+        .context("UPDATE_PUBLIC_KEY is not valid base64")?;
+    anyhow::ensure!(
+        public_key.len() == 32,
+        "UPDATE_PUBLIC_KEY must be a 32-byte Ed25519 key"
+    );
+    let signature = engine
+        .decode(signature.trim())
+        .context("Signature is not valid base64")?;
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(binary, &signature)
+        .map_err(|_| anyhow::anyhow!("Signature verification failed"))
+}
+
+/// Replace the file at `path` with `binary`, atomically
+pub fn replace_binary(path: &Path, binary: &[u8]) -> Result<()> {
+    let dir = path.parent().context("Binary has no parent directory")?;
+    let name = path.file_name().context("Binary has no file name")?;
+    let staged = dir.join(format!(".{}.update", name.to_string_lossy()));
+    let result = (|| -> Result<()> {
+        let mut file = std::fs::File::create(&staged)
+            .with_context(|| format!("Failed to create {}", staged.display()))?;
+        file.write_all(binary)?;
+        file.sync_all()?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))?;
+        }
+        std::fs::rename(&staged, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&staged);
+    }
+    result
+}
+
+async fn download(fetcher: &Fetcher, url: &str, max_bytes: usize) -> Result<Vec<u8>> {
+    let response = fetcher.get(url, max_bytes + 1).await?;
+    anyhow::ensure!(
+        response.status.is_success(),
+        "GET {} returned {}",
+        url,
+        response.status
+    );
+    anyhow::ensure!(
+        response.body.len() <= max_bytes,
+        "{} is larger than {} bytes",
+        url,
+        max_bytes
+    );
+    Ok(response.body.to_vec())
+}
+
+/// Look up the latest release
+pub async fn latest_release(fetcher: &Fetcher, url: &str) -> Result<Release> {
+    let body = download(fetcher, url, MAX_METADATA_BYTES).await?;
+    serde_json::from_slice(&body).with_context(|| format!("Invalid release from {}", url))
+}
+
+/// Download this platform's binary from a release and check its signature
+pub async fn download_verified(
+    fetcher: &Fetcher,
+    release: &Release,
+    public_key: &str,
+) -> Result<Vec<u8>> {
+    let name = asset_name();
+    let binary = download(
+        fetcher,
+        &release.asset(&name)?.browser_download_url,
+        MAX_BINARY_BYTES,
+    )
+    .await?;
+    let signature = download(
+        fetcher,
+        &release
+            .asset(&format!("{}.sig", name))?
+            .browser_download_url,
+        MAX_METADATA_BYTES,
+    )
+    .await?;
+    verify(&binary, &String::from_utf8_lossy(&signature), public_key)
+        .with_context(|| format!("Refusing to install {} from {}", name, release.tag_name))?;
+    Ok(binary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer("v1.2.0", "1.1.9"));
+        assert!(is_newer("0.2", "0.1.5"));
+        assert!(is_newer("1.0.1-rc.1", "1.0.0"));
+        assert!(!is_newer("v1.0.0", "1.0.0"));
+        assert!(!is_newer("0.9.9", "1.0.0"));
+        assert!(!is_newer("latest", "1.0.0"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = engine.encode(key.public_key().as_ref());
+        let signature = engine.encode(key.sign(b"binary").as_ref());
+
+        verify(b"binary", &format!("{}\n", signature), &public_key).unwrap();
+        assert!(verify(b"tampered", &signature, &public_key).is_err());
+        assert!(verify(b"binary", &signature, "c2hvcnQ=").is_err());
+    }
+
+    #[test]
+    fn test_replace_binary() {
+        let dir = std::env::temp_dir().join(format!("update-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server");
+        std::fs::write(&path, b"old").unwrap();
+        replace_binary(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o755);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
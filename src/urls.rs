@@ -0,0 +1,265 @@
+//! The server's public URL.
+//!
+//! Absolute links (the OIDC sign-in form, deprecation `Link` headers, the
+//! Stripe portal's return URL and the `X-Forwarded-*` headers sent to
+//! proxied services) must use the URL clients reach the server at, which
+//! behind a reverse proxy is not the address it listens on.
+//! `PUBLIC_BASE_URL`, e.g. `https://example.com/selfhost`, sets it outright.
+//! Without it, the URL is derived from each request's `Host` header, or from
+//! `X-Forwarded-Proto` and `X-Forwarded-Host` when the request comes from a
+//! `TRUSTED_PROXIES` address, so other clients can't choose their own.
+//!
+//! Handlers take the resolved URL with the [`PublicUrl`] extractor and build
+//! links with [`PublicUrl::join`].
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::{header::HOST, request::Parts, uri::Authority, Extensions, HeaderMap, Uri},
+    middleware::Next,
+    response::Response,
+};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use crate::auth::IpRange;
+
+/// Host used when a request names none, as HTTP/1.0 requests may not
+const FALLBACK_HOST: &str = "localhost";
+
+/// Scheme, host and path prefix clients reach the server at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicUrl {
+    scheme: String,
+    authority: String,
+    /// Path the server is mounted under, without a trailing slash
+    prefix: String,
+}
+
+impl PublicUrl {
+    /// Parse a base URL such as `https://example.com/selfhost`
+    pub fn parse(url: &str) -> Result<Self> {
+        let uri: Uri = url.trim().parse()?;
+        let scheme = match uri.scheme_str() {
+            Some(scheme @ ("http" | "https")) => scheme.to_string(),
+            _ => anyhow::bail!("expected an http:// or https:// URL, got '{}'", url),
+        };
+        let authority = uri
+            .authority()
+            .ok_or_else(|| anyhow::anyhow!("'{}' has no host", url))?;
+        anyhow::ensure!(
+            !authority.as_str().contains('@'),
+            "'{}' must not contain credentials",
+            url
+        );
+        anyhow::ensure!(
+            uri.query().is_none() && !url.contains('#'),
+            "'{}' must not have a query or fragment",
+            url
+        );
+        Ok(PublicUrl {
+            scheme,
+            authority: authority.as_str().to_ascii_lowercase(),
+            prefix: uri.path().trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// `http` or `https`
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// Host, with the port if it is not the scheme's default
+    pub fn host(&self) -> &str {
+        &self.authority
+    }
+
+    /// Path the server is mounted under, empty at the root
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The public URL resolved for a request, as the extractor returns it
+    pub fn of(request: &Request) -> Self {
+        resolved(request.extensions(), request.uri(), request.headers())
+    }
+
+    /// Absolute URL of a path on this server, e.g. `/oidc/authorize`
+    pub fn join(&self, path: &str) -> String {
+        let separator = if path.starts_with('/') { "" } else { "/" };
+        format!("{}{}{}", self, separator, path)
+    }
+}
+
+impl fmt::Display for PublicUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}{}", self.scheme, self.authority, self.prefix)
+    }
+}
+
+/// The request's public URL, resolved by [`UrlBuilder::middleware`]
+///
+/// Without the middleware, as in handler tests, it is derived from the
+/// `Host` header alone.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for PublicUrl {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(resolved(&parts.extensions, &parts.uri, &parts.headers))
+    }
+}
+
+fn resolved(extensions: &Extensions, uri: &Uri, headers: &HeaderMap) -> PublicUrl {
+    match extensions.get::<PublicUrl>() {
+        Some(url) => url.clone(),
+        None => UrlBuilder::default().resolve(uri, headers, None),
+    }
+}
+
+/// Resolves the public URL of requests
+#[derive(Debug, Clone, Default)]
+pub struct UrlBuilder {
+    base: Option<PublicUrl>,
+    trusted_proxies: Arc<Vec<IpRange>>,
+}
+
+impl UrlBuilder {
+    /// A builder using `base` when set, and trusting forwarded headers from
+    /// `trusted_proxies` otherwise
+    pub fn new(base: Option<PublicUrl>, trusted_proxies: Vec<IpRange>) -> Self {
+        UrlBuilder {
+            base,
+            trusted_proxies: Arc::new(trusted_proxies),
+        }
+    }
+
+    /// `PUBLIC_BASE_URL`, if set
+    pub fn base(&self) -> Option<&PublicUrl> {
+        self.base.as_ref()
+    }
+
+    /// The public URL of a request from `peer`
+    pub fn resolve(&self, uri: &Uri, headers: &HeaderMap, peer: Option<IpAddr>) -> PublicUrl {
+        if let Some(base) = &self.base {
+            return base.clone();
+        }
+        let trusted = peer.is_some_and(|peer| {
+            self.trusted_proxies
+                .iter()
+                .any(|range| range.contains(peer))
+        });
+        // Proxies chaining requests append their values, so the first is
+        // what the client sent to the outermost one
+        let forwarded = |name: &str| {
+            headers
+                .get(name)
+                .filter(|_| trusted)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .map(str::trim)
+        };
+        let scheme = match forwarded("x-forwarded-proto") {
+            Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+            Some(proto) if proto.eq_ignore_ascii_case("http") => "http",
+            _ => uri.scheme_str().unwrap_or("http"),
+        };
+        let authority = forwarded("x-forwarded-host")
+            .or_else(|| headers.get(HOST).and_then(|value| value.to_str().ok()))
+            .or_else(|| uri.authority().map(Authority::as_str))
+            .and_then(|host| host.parse::<Authority>().ok())
+            .filter(|host| !host.as_str().contains('@'))
+            .map_or_else(
+                || FALLBACK_HOST.to_string(),
+                |host| host.as_str().to_ascii_lowercase(),
+            );
+        PublicUrl {
+            scheme: scheme.to_string(),
+            authority,
+            prefix: String::new(),
+        }
+    }
+
+    /// Middleware resolving each request's public URL for [`PublicUrl`]
+    pub async fn middleware(
+        State(urls): State<UrlBuilder>,
+        mut request: Request,
+        next: Next,
+    ) -> Response {
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let url = urls.resolve(request.uri(), request.headers(), peer);
+        request.extensions_mut().insert(url);
+        next.run(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_parses_base_urls() {
+        let url = PublicUrl::parse("https://Example.com:8443/selfhost/").unwrap();
+        assert_eq!(url.scheme(), "https");
+        assert_eq!(url.host(), "example.com:8443");
+        assert_eq!(url.prefix(), "/selfhost");
+        assert_eq!(
+            url.join("/oidc/authorize"),
+            "https://example.com:8443/selfhost/oidc/authorize"
+        );
+        assert_eq!(
+            PublicUrl::parse("http://example.com").unwrap().join("/"),
+            "http://example.com/"
+        );
+        for invalid in [
+            "example.com",
+            "ftp://example.com",
+            "https://user:pw@example.com",
+            "https://example.com/?a=1",
+            "https://example.com/#top",
+        ] {
+            assert!(PublicUrl::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_honors_forwarded_headers_from_trusted_proxies_only() {
+        let urls = UrlBuilder::new(None, vec!["10.0.0.0/8".parse().unwrap()]);
+        let uri = Uri::from_static("/notes");
+        let headers = headers(&[
+            ("host", "10.0.0.5:3000"),
+            ("x-forwarded-proto", "https, http"),
+            ("x-forwarded-host", "notes.example.com"),
+        ]);
+
+        let proxied = urls.resolve(&uri, &headers, Some("10.1.2.3".parse().unwrap()));
+        assert_eq!(proxied.to_string(), "https://notes.example.com");
+        let direct = urls.resolve(&uri, &headers, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(direct.to_string(), "http://10.0.0.5:3000");
+        assert_eq!(
+            urls.resolve(&uri, &HeaderMap::new(), None).to_string(),
+            "http://localhost"
+        );
+
+        let configured = UrlBuilder::new(
+            Some(PublicUrl::parse("https://example.com/selfhost").unwrap()),
+            Vec::new(),
+        );
+        let url = configured.resolve(&uri, &headers, Some("10.1.2.3".parse().unwrap()));
+        assert_eq!(url.join("/p/1"), "https://example.com/selfhost/p/1");
+    }
+}
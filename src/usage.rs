@@ -0,0 +1,626 @@
+//! Usage accounting per API key and tenant.
+//!
+//! With `USAGE_ACCOUNTING=true`, every request is accounted to its caller:
+//! the fingerprint of its API key (`token:<fingerprint>`), its HMAC client
+//! (`hmac:<id>`), its logged-in user (`user:<id>`) or `anonymous`, and the
+//! tenant its API key names with the `tenant=` attribute. Counted are
+//! requests, body bytes read and written, storage (bytes handlers report
+//! storing, less those they report freeing, through the [`Meter`]
+//! extractor) and background job time.
+//!
+//! Counters are kept in memory and added to hourly rows of `usage_rollups`
+//! every `USAGE_FLUSH_INTERVAL` and on shutdown, so replicas account into
+//! the same rows. `GET /admin/usage` reports them by hour, day, week or
+//! month, per key or per tenant:
+//!
+//! ```text
+//! GET /admin/usage?granularity=day&by=tenant&since=2026-10-01
+//! ```
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, Query, Request, State},
+    http::request::Parts,
+    middleware::Next,
+    response::{Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use http_body_util::BodyExt;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::ops::AddAssign;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::auth::{self, ApiKeys};
+use crate::error::ApiResult;
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::sessions::SessionHandle;
+use crate::time::Timestamp;
+use crate::{anomaly, AppState};
+
+/// Usage accounting settings, from configuration
+#[derive(Debug, Clone)]
+pub struct UsageConfig {
+    /// How often counters are written to the rollup table
+    pub flush_interval: Duration,
+}
+
+/// Who usage is accounted to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Subject {
+    /// `token:<fingerprint>`, `hmac:<id>`, `user:<id>`, `anonymous` or, for
+    /// background work, `system:<task>`
+    pub key: String,
+    pub tenant: Option<String>,
+}
+
+impl Subject {
+    /// The subject of background work no request asked for
+    pub fn system(task: &str) -> Self {
+        Subject {
+            key: format!("system:{}", task),
+            tenant: None,
+        }
+    }
+}
+
+/// Usage counted for a subject
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, sqlx::FromRow)]
+pub struct Counters {
+    pub requests: i64,
+    /// Request body bytes read
+    pub bytes_in: i64,
+    /// Response body bytes written
+    pub bytes_out: i64,
+    /// Bytes stored less bytes freed
+    pub storage_bytes: i64,
+    pub job_seconds: f64,
+}
+
+impl AddAssign for Counters {
+    fn add_assign(&mut self, other: Counters) {
+        self.requests += other.requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.storage_bytes += other.storage_bytes;
+        self.job_seconds += other.job_seconds;
+    }
+}
+
+/// Counters not yet flushed, by hour and subject
+type Pending = HashMap<(DateTime<Utc>, Subject), Counters>;
+
+/// Shared usage counters and the middleware feeding them
+#[derive(Clone)]
+pub struct UsageRecorder {
+    config: Arc<UsageConfig>,
+    keys: ApiKeys,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl UsageRecorder {
+    pub fn new(config: UsageConfig, keys: ApiKeys) -> Self {
+        UsageRecorder {
+            config: Arc::new(config),
+            keys,
+            pending: Arc::default(),
+        }
+    }
+
+    /// Add to a subject's counters for the current hour
+    pub fn record(&self, subject: &Subject, counters: Counters) {
+        let hour = Utc::now()
+            .duration_trunc(TimeDelta::hours(1))
+            .expect("an hour divides any timestamp");
+        *self
+            .pending
+            .lock()
+            .expect("usage lock poisoned")
+            .entry((hour, subject.clone()))
+            .or_default() += counters;
+    }
+
+    /// Account time spent on background work
+    pub fn record_job(&self, subject: &Subject, elapsed: Duration) {
+        self.record(
+            subject,
+            Counters {
+                job_seconds: elapsed.as_secs_f64(),
+                ..Counters::default()
+            },
+        );
+    }
+
+    /// Who a request is accounted to
+    fn subject(&self, request: &Request) -> Subject {
+        let tenant = auth::bearer_token(request)
+            .and_then(|token| self.keys.tenant(token))
+            .map(String::from);
+        let key = anomaly::account(request)
+            .or_else(|| {
+                request
+                    .extensions()
+                    .get::<SessionHandle>()
+                    .map(|session| format!("user:{}", session.user_id()))
+            })
+            .unwrap_or_else(|| "anonymous".to_string());
+        Subject { key, tenant }
+    }
+
+    /// Add pending counters to the rollup table
+    ///
+    /// Counters that fail to be written are kept for the next flush.
+    pub async fn flush(&self, pool: &PgPool) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock().expect("usage lock poisoned"));
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut rows = RollupColumns::default();
+        for ((hour, subject), counters) in &pending {
+            rows.push(*hour, subject, counters);
+        }
+        let result = sqlx::query(
+            "INSERT INTO usage_rollups
+                (bucket, key, tenant, requests, bytes_in, bytes_out, storage_bytes, job_seconds)
+             SELECT * FROM UNNEST($1::timestamptz[], $2::text[], $3::text[], $4::bigint[],
+                $5::bigint[], $6::bigint[], $7::bigint[], $8::float8[])
+             ON CONFLICT (bucket, key, tenant) DO UPDATE SET
+                requests = usage_rollups.requests + EXCLUDED.requests,
+                bytes_in = usage_rollups.bytes_in + EXCLUDED.bytes_in,
+                bytes_out = usage_rollups.bytes_out + EXCLUDED.bytes_out,
+                storage_bytes = usage_rollups.storage_bytes + EXCLUDED.storage_bytes,
+                job_seconds = usage_rollups.job_seconds + EXCLUDED.job_seconds",
+        )
+        .bind(rows.buckets)
+        .bind(rows.keys)
+        .bind(rows.tenants)
+        .bind(rows.requests)
+        .bind(rows.bytes_in)
+        .bind(rows.bytes_out)
+        .bind(rows.storage_bytes)
+        .bind(rows.job_seconds)
+        .execute(pool)
+        .await;
+        if result.is_err() {
+            let mut current = self.pending.lock().expect("usage lock poisoned");
+            for (entry, counters) in pending {
+                *current.entry(entry).or_default() += counters;
+            }
+        }
+        result?;
+        Ok(())
+    }
+
+    /// Middleware accounting requests and the bytes of their bodies
+    pub async fn middleware(
+        State(recorder): State<UsageRecorder>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let subject = recorder.subject(&request);
+        recorder.record(
+            &subject,
+            Counters {
+                requests: 1,
+                ..Counters::default()
+            },
+        );
+        let mut request =
+            request.map(|body| Tally::new(&recorder, &subject, Direction::In).count(body));
+        request.extensions_mut().insert(Meter(Some((
+            UsageRecorder::clone(&recorder),
+            subject.clone(),
+        ))));
+        let response = next.run(request).await;
+        response.map(|body| Tally::new(&recorder, &subject, Direction::Out).count(body))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    In,
+    Out,
+}
+
+/// Bytes counted while a body streams, recorded once it is dropped
+struct Tally {
+    recorder: UsageRecorder,
+    subject: Subject,
+    direction: Direction,
+    counters: Counters,
+}
+
+impl Tally {
+    fn new(recorder: &UsageRecorder, subject: &Subject, direction: Direction) -> Self {
+        Tally {
+            recorder: recorder.clone(),
+            subject: subject.clone(),
+            direction,
+            counters: Counters::default(),
+        }
+    }
+
+    fn add(&mut self, bytes: usize) {
+        match self.direction {
+            Direction::In => self.counters.bytes_in += bytes as i64,
+            Direction::Out => self.counters.bytes_out += bytes as i64,
+        }
+    }
+
+    /// Wrap a body, counting its bytes as they stream
+    fn count(mut self, body: Body) -> Body {
+        Body::new(body.map_frame(move |frame| {
+            if let Some(data) = frame.data_ref() {
+                self.add(data.len());
+            }
+            frame
+        }))
+    }
+}
+
+impl Drop for Tally {
+    fn drop(&mut self) {
+        if self.counters != Counters::default() {
+            self.recorder.record(&self.subject, self.counters);
+        }
+    }
+}
+
+/// Columns of rollup rows, bound as arrays
+#[derive(Default)]
+struct RollupColumns {
+    buckets: Vec<DateTime<Utc>>,
+    keys: Vec<String>,
+    tenants: Vec<String>,
+    requests: Vec<i64>,
+    bytes_in: Vec<i64>,
+    bytes_out: Vec<i64>,
+    storage_bytes: Vec<i64>,
+    job_seconds: Vec<f64>,
+}
+
+impl RollupColumns {
+    fn push(&mut self, bucket: DateTime<Utc>, subject: &Subject, counters: &Counters) {
+        self.buckets.push(bucket);
+        self.keys.push(subject.key.clone());
+        // Part of the primary key, so no tenant is stored as ''
+        self.tenants
+            .push(subject.tenant.clone().unwrap_or_default());
+        self.requests.push(counters.requests);
+        self.bytes_in.push(counters.bytes_in);
+        self.bytes_out.push(counters.bytes_out);
+        self.storage_bytes.push(counters.storage_bytes);
+        self.job_seconds.push(counters.job_seconds);
+    }
+}
+
+/// Lets handlers account storage and work to the request's caller
+///
+/// Does nothing without `USAGE_ACCOUNTING=true`.
+#[derive(Clone, Default)]
+pub struct Meter(Option<(UsageRecorder, Subject)>);
+
+impl Meter {
+    /// A meter for background work, accounted to `system:<task>`
+    pub fn system(recorder: Option<UsageRecorder>, task: &str) -> Self {
+        Meter(recorder.map(|recorder| (recorder, Subject::system(task))))
+    }
+
+    /// Account bytes stored, or freed when negative
+    pub fn storage(&self, bytes: i64) {
+        if let Some((recorder, subject)) = &self.0 {
+            recorder.record(
+                subject,
+                Counters {
+                    storage_bytes: bytes,
+                    ..Counters::default()
+                },
+            );
+        }
+    }
+
+    /// Account time spent on work done for the request
+    pub fn job(&self, elapsed: Duration) {
+        if let Some((recorder, subject)) = &self.0 {
+            recorder.record_job(subject, elapsed);
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Meter {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Meter>().cloned().unwrap_or_default())
+    }
+}
+
+/// Spawn the task flushing counters every `USAGE_FLUSH_INTERVAL`
+pub fn spawn(recorder: UsageRecorder, pool: PgPool) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let interval = recorder.config.flush_interval;
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = recorder.flush(&pool).await {
+                tracing::warn!("Failed to flush usage counters: {:#}", e);
+            }
+        }
+    })
+}
+
+/// Route module reporting usage, mounted with `USAGE_ACCOUNTING=true`
+pub struct UsageModule;
+
+impl RouteModule for UsageModule {
+    fn name(&self) -> &'static str {
+        "usage"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/admin/usage", get(usage_report))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_usage_rollups",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS usage_rollups (
+                bucket TIMESTAMPTZ NOT NULL,
+                key TEXT NOT NULL,
+                tenant TEXT NOT NULL,
+                requests BIGINT NOT NULL,
+                bytes_in BIGINT NOT NULL,
+                bytes_out BIGINT NOT NULL,
+                storage_bytes BIGINT NOT NULL,
+                job_seconds DOUBLE PRECISION NOT NULL,
+                PRIMARY KEY (bucket, key, tenant)
+            );
+            CREATE INDEX IF NOT EXISTS usage_rollups_tenant ON usage_rollups (tenant, bucket)",
+        }]
+    }
+}
+
+/// Length of the buckets reported
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Hour,
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Granularity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+            Granularity::Week => "week",
+            Granularity::Month => "month",
+        }
+    }
+}
+
+/// What usage is grouped by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    #[default]
+    Key,
+    Tenant,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UsageQuery {
+    #[serde(default)]
+    pub granularity: Granularity,
+    #[serde(default)]
+    pub by: GroupBy,
+    /// Start of the report, 30 days ago by default
+    pub since: Option<Timestamp>,
+    /// End of the report, now by default
+    pub until: Option<Timestamp>,
+    pub key: Option<String>,
+    pub tenant: Option<String>,
+}
+
+/// Usage of a key or tenant in one bucket
+#[derive(Debug, Clone, PartialEq, Serialize, sqlx::FromRow)]
+pub struct UsageRow {
+    #[serde(with = "crate::time::rfc3339")]
+    pub bucket: DateTime<Utc>,
+    /// `None` when grouped by tenant
+    pub key: Option<String>,
+    pub tenant: Option<String>,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub usage: Counters,
+}
+
+/// Usage in the query's buckets, oldest first
+pub async fn report(pool: &PgPool, query: &UsageQuery) -> Result<Vec<UsageRow>> {
+    let until = query.until.map_or_else(Utc::now, |until| until.0);
+    let since = query
+        .since
+        .map_or_else(|| until - TimeDelta::days(30), |since| since.0);
+    let rows = sqlx::query_as(
+        "SELECT date_trunc($1, bucket, 'UTC') AS bucket,
+            CASE WHEN $4 THEN NULL ELSE key END AS key,
+            NULLIF(tenant, '') AS tenant,
+            sum(requests)::bigint AS requests, sum(bytes_in)::bigint AS bytes_in,
+            sum(bytes_out)::bigint AS bytes_out, sum(storage_bytes)::bigint AS storage_bytes,
+            sum(job_seconds) AS job_seconds
+         FROM usage_rollups
+         WHERE bucket >= $2 AND bucket < $3
+           AND ($5::text IS NULL OR key = $5) AND ($6::text IS NULL OR tenant = $6)
+         GROUP BY 1, 2, 3
+         ORDER BY 1, 3 NULLS FIRST, 2",
+    )
+    .bind(query.granularity.as_str())
+    .bind(since)
+    .bind(until)
+    .bind(query.by == GroupBy::Tenant)
+    .bind(query.key.as_deref())
+    .bind(query.tenant.as_deref())
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// `GET /admin/usage` - usage by time bucket and key or tenant
+pub async fn usage_report(
+    State(state): State<AppState>,
+    Query(query): Query<UsageQuery>,
+) -> ApiResult<Json<serde_json::Value>> {
+    let rows = report(state.db.pool(), &query).await?;
+    Ok(Json(serde_json::json!({
+        "granularity": query.granularity,
+        "rows": rows,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::parse_api_keys;
+    use crate::module;
+    use axum::{http::header::AUTHORIZATION, middleware, routing::post};
+    use tower::Service;
+
+    fn recorder() -> UsageRecorder {
+        UsageRecorder::new(
+            UsageConfig {
+                flush_interval: Duration::from_secs(60),
+            },
+            ApiKeys::new(parse_api_keys("alpha;tenant=acme,beta").unwrap()),
+        )
+    }
+
+    fn pending(recorder: &UsageRecorder) -> HashMap<Subject, Counters> {
+        recorder
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((_, subject), counters)| (subject.clone(), *counters))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_accounts_requests_bytes_and_storage() {
+        let recorder = recorder();
+        let mut app = Router::new()
+            .route(
+                "/echo",
+                post(|meter: Meter, body: String| async move {
+                    meter.storage(body.len() as i64);
+                    format!("{}!", body)
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                recorder.clone(),
+                UsageRecorder::middleware,
+            ));
+        for token in ["alpha", "beta", "alpha"] {
+            let request = Request::post("/echo")
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::from("hello"))
+                .unwrap();
+            let response = app.call(request).await.unwrap();
+            response.into_body().collect().await.unwrap();
+        }
+        let request = Request::post("/echo").body(Body::empty()).unwrap();
+        let response = app.call(request).await.unwrap();
+        response.into_body().collect().await.unwrap();
+
+        let pending = pending(&recorder);
+        assert_eq!(pending.len(), 3);
+        let (alpha, usage) = pending
+            .iter()
+            .find(|(subject, _)| subject.tenant.as_deref() == Some("acme"))
+            .unwrap();
+        assert!(alpha.key.starts_with("token:"));
+        assert_eq!(
+            *usage,
+            Counters {
+                requests: 2,
+                bytes_in: 10,
+                bytes_out: 12,
+                storage_bytes: 10,
+                job_seconds: 0.0,
+            }
+        );
+        let anonymous = Subject {
+            key: "anonymous".into(),
+            tenant: None,
+        };
+        assert_eq!(pending[&anonymous].requests, 1);
+        assert_eq!(pending[&anonymous].bytes_out, 1);
+    }
+
+    #[tokio::test]
+    async fn test_flushes_into_rollups_and_reports() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+        module::run_migrations(pool, &[Arc::new(UsageModule)])
+            .await
+            .unwrap();
+        let recorder = recorder();
+        let acme = Subject {
+            key: "token:abc".into(),
+            tenant: Some("acme".into()),
+        };
+        let requests = Counters {
+            requests: 3,
+            bytes_out: 100,
+            ..Counters::default()
+        };
+        // Flushes add up, as replicas flushing the same hour would
+        for _ in 0..2 {
+            recorder.record(&acme, requests);
+            recorder.record_job(&Subject::system("fetch"), Duration::from_millis(1500));
+            recorder.flush(pool).await.unwrap();
+        }
+        assert!(pending(&recorder).is_empty());
+
+        let query = UsageQuery {
+            granularity: Granularity::Month,
+            ..UsageQuery::default()
+        };
+        let rows = report(pool, &query).await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].key.as_deref(), Some("system:fetch"));
+        assert_eq!(rows[0].tenant, None);
+        assert_eq!(rows[0].usage.job_seconds, 3.0);
+        assert_eq!(rows[1].usage.requests, 6);
+        assert_eq!(rows[1].usage.bytes_out, 200);
+
+        let query = UsageQuery {
+            by: GroupBy::Tenant,
+            tenant: Some("acme".into()),
+            granularity: Granularity::Month,
+            ..UsageQuery::default()
+        };
+        let rows = report(pool, &query).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].key, None);
+        assert_eq!(rows[0].tenant.as_deref(), Some("acme"));
+        assert_eq!(rows[0].usage.requests, 6);
+    }
+}
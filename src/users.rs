@@ -0,0 +1,355 @@
+//! Local user accounts.
+//!
+//! Users live in the `users` table with an Argon2id password hash (see
+//! [`crypto::password`](crate::crypto::password)), upgraded on login when
+//! it is weaker than the configured cost. Accounts are managed from the
+//! command line (`user create`, `user list`, `user delete`) or through the
+//! admin API.
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    routing, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::crypto::password;
+use crate::error::{ApiError, ApiResult};
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::{t, time, AppState};
+
+/// Shortest password accepted for new accounts
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// A user account, without its password hash
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub is_admin: bool,
+    /// IANA timezone dates are shown in to this user, e.g. `Europe/Berlin`
+    pub timezone: Option<String>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+/// Route module serving the user admin API
+pub struct UsersModule;
+
+impl RouteModule for UsersModule {
+    fn name(&self) -> &'static str {
+        "users"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route("/admin/users", routing::get(list_users).post(create_user))
+            .route("/admin/users/:username", routing::delete(delete_user))
+            .route(
+                "/admin/users/:username/timezone",
+                routing::put(set_user_timezone),
+            )
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[
+            Migration {
+                name: "0001_create_users",
+                kind: MigrationKind::Expand,
+                sql: "CREATE TABLE IF NOT EXISTS users (
+                    id BIGSERIAL PRIMARY KEY,
+                    username TEXT NOT NULL UNIQUE,
+                    password_hash TEXT NOT NULL,
+                    is_admin BOOLEAN NOT NULL DEFAULT false,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                )",
+            },
+            Migration {
+                name: "0002_add_user_timezone",
+                kind: MigrationKind::Expand,
+                sql: "ALTER TABLE users ADD COLUMN IF NOT EXISTS timezone TEXT",
+            },
+        ]
+    }
+}
+
+/// Check a username and password against the account rules
+pub fn validate(username: &str, password: &str) -> std::result::Result<(), String> {
+    if username.is_empty() || username.len() > 64 {
+        return Err(t!("username-length"));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(t!("username-characters"));
+    }
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(t!("password-length", min = MIN_PASSWORD_LEN));
+    }
+    Ok(())
+}
+
+/// Look up a user by name
+pub async fn find(pool: &PgPool, username: &str) -> Result<Option<User>> {
+    let user = sqlx::query_as::<_, User>(
+        "SELECT id, username, is_admin, timezone, created_at FROM users WHERE username = $1",
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await?;
+    Ok(user)
+}
+
+/// Look up a user by id
+pub async fn find_by_id(pool: &PgPool, id: i64) -> Result<Option<User>> {
+    let user = sqlx::query_as::<_, User>(
+        "SELECT id, username, is_admin, timezone, created_at FROM users WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(user)
+}
+
+/// List all users by name
+pub async fn list(pool: &PgPool) -> Result<Vec<User>> {
+    let users = sqlx::query_as::<_, User>(
+        "SELECT id, username, is_admin, timezone, created_at FROM users ORDER BY username",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(users)
+}
+
+/// Create a user, returning `None` if the name is taken
+pub async fn create(
+    pool: &PgPool,
+    username: &str,
+    password: &str,
+    is_admin: bool,
+) -> Result<Option<User>> {
+    let hash = password::hash_async(password).await?;
+    let user = sqlx::query_as::<_, User>(
+        "INSERT INTO users (username, password_hash, is_admin) VALUES ($1, $2, $3)
+         ON CONFLICT (username) DO NOTHING
+         RETURNING id, username, is_admin, timezone, created_at",
+    )
+    .bind(username)
+    .bind(hash)
+    .bind(is_admin)
+    .fetch_optional(pool)
+    .await?;
+    Ok(user)
+}
+
+/// Delete a user, returning whether it existed
+pub async fn delete(pool: &PgPool, username: &str) -> Result<bool> {
+    let result = sqlx::query("DELETE FROM users WHERE username = $1")
+        .bind(username)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Set or clear a user's display timezone, returning `None` if there is no such user
+pub async fn set_timezone(
+    pool: &PgPool,
+    username: &str,
+    timezone: Option<&str>,
+) -> Result<Option<User>> {
+    let user = sqlx::query_as::<_, User>(
+        "UPDATE users SET timezone = $2 WHERE username = $1
+         RETURNING id, username, is_admin, timezone, created_at",
+    )
+    .bind(username)
+    .bind(timezone)
+    .fetch_optional(pool)
+    .await?;
+    Ok(user)
+}
+
+/// Check a user's credentials, upgrading a legacy or weaker hash on success
+pub async fn authenticate(pool: &PgPool, username: &str, secret: &str) -> Result<Option<User>> {
+    let row: Option<(i64, String)> =
+        sqlx::query_as("SELECT id, password_hash FROM users WHERE username = $1")
+            .bind(username)
+            .fetch_optional(pool)
+            .await?;
+    let Some((id, hash)) = row else {
+        password::verify_dummy_async(secret).await?;
+        return Ok(None);
+    };
+    if !password::verify_async(secret, &hash).await? {
+        return Ok(None);
+    }
+    if password::needs_rehash(&hash) {
+        // Only replace the hash that was checked, in case of a concurrent change
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2 AND password_hash = $3")
+            .bind(password::hash_async(secret).await?)
+            .bind(id)
+            .bind(&hash)
+            .execute(pool)
+            .await?;
+        tracing::info!("🔐 Upgraded the password hash of user '{}'", username);
+    }
+    find(pool, username).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewUser {
+    username: String,
+    password: String,
+    #[serde(default)]
+    is_admin: bool,
+}
+
+/// `GET /admin/users` - list user accounts
+pub async fn list_users(State(state): State<AppState>) -> ApiResult<Json<Vec<User>>> {
+    Ok(Json(list(state.db.pool()).await?))
+}
+
+/// `POST /admin/users` - create a user account
+pub async fn create_user(
+    State(state): State<AppState>,
+    Json(body): Json<NewUser>,
+) -> ApiResult<(StatusCode, Json<User>)> {
+    validate(&body.username, &body.password).map_err(ApiError::Validation)?;
+    create(
+        state.db.pool(),
+        &body.username,
+        &body.password,
+        body.is_admin,
+    )
+    .await?
+    .map(|user| (StatusCode::CREATED, Json(user)))
+    .ok_or_else(|| ApiError::Validation(t!("user-exists", username = body.username.as_str())))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimezoneUpdate {
+    timezone: Option<String>,
+}
+
+/// `PUT /admin/users/:username/timezone` - set or clear (`null`) the display timezone
+pub async fn set_user_timezone(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Json(body): Json<TimezoneUpdate>,
+) -> ApiResult<Json<User>> {
+    if let Some(timezone) = &body.timezone {
+        time::parse_timezone(timezone).map_err(|_| {
+            ApiError::Validation(t!("timezone-unknown", timezone = timezone.as_str()))
+        })?;
+    }
+    set_timezone(state.db.pool(), &username, body.timezone.as_deref())
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::NotFound(t!("user-not-found", username = username.as_str())))
+}
+
+/// `DELETE /admin/users/:username` - remove a user account
+pub async fn delete_user(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> ApiResult<StatusCode> {
+    if delete(state.db.pool(), &username).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound(t!(
+            "user-not-found",
+            username = username.as_str()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("alice", "correct horse").is_ok());
+        assert!(validate("", "correct horse").is_err());
+        assert!(validate("al ice", "correct horse").is_err());
+        assert!(validate("alice", "short").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_create_authenticate_delete() {
+        let Some(db) = crate::test_db::TestDatabase::start().await else {
+            return;
+        };
+        let pool = &db.pool;
+
+        let user = create(pool, "alice", "correct horse", true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.is_admin);
+        assert!(create(pool, "alice", "other password", false)
+            .await
+            .unwrap()
+            .is_none());
+
+        let found = authenticate(pool, "alice", "correct horse").await.unwrap();
+        assert_eq!(found.map(|u| u.id), Some(user.id));
+        assert!(authenticate(pool, "alice", "wrong horse")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(authenticate(pool, "bob", "correct horse")
+            .await
+            .unwrap()
+            .is_none());
+
+        // A legacy Argon2i hash is replaced on the next successful login
+        use argon2::{password_hash::SaltString, PasswordHasher};
+        let legacy = argon2::Argon2::new(
+            argon2::Algorithm::Argon2i,
+            argon2::Version::V0x13,
+            argon2::Params::new(8, 1, 1, None).unwrap(),
+        )
+        .hash_password(
+            b"correct horse",
+            &SaltString::from_b64("c2FsdHNhbHQ").unwrap(),
+        )
+        .unwrap()
+        .to_string();
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+            .bind(&legacy)
+            .bind(user.id)
+            .execute(pool)
+            .await
+            .unwrap();
+        assert!(authenticate(pool, "alice", "correct horse")
+            .await
+            .unwrap()
+            .is_some());
+        let (hash,): (String,) = sqlx::query_as("SELECT password_hash FROM users WHERE id = $1")
+            .bind(user.id)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert!(hash.starts_with("$argon2id$") && !password::needs_rehash(&hash));
+        assert!(password::verify("correct horse", &hash));
+
+        let user = set_timezone(pool, "alice", Some("Europe/Berlin"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.timezone.as_deref(), Some("Europe/Berlin"));
+        assert!(set_timezone(pool, "bob", None).await.unwrap().is_none());
+
+        assert!(delete(pool, "alice").await.unwrap());
+        assert!(!delete(pool, "alice").await.unwrap());
+    }
+}
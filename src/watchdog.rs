@@ -0,0 +1,528 @@
+//! Resource watchdog.
+//!
+//! With `WATCHDOG_MAX_RSS` or `WATCHDOG_MAX_FDS` set, the process's
+//! resident memory and open file descriptors are sampled every
+//! `WATCHDOG_INTERVAL`. Above `WATCHDOG_SHED_PERCENT` of a limit the server
+//! sheds load: requests other than health probes and `/admin/watchdog`
+//! get 503 with `Retry-After`, and readiness fails so load balancers route
+//! elsewhere.
+//! Shedding stops once usage falls back under the threshold.
+//!
+//! At a limit, or after shedding for `WATCHDOG_RESTART_AFTER` (memory
+//! freed by the allocator is not always returned to the OS), the server
+//! drains and shuts down like on `SIGTERM`, then exits with a failure
+//! status for its supervisor (systemd `Restart=on-failure`, a Docker
+//! restart policy) to start a fresh process. That happens well before the
+//! kernel's OOM killer would kill it mid-request.
+//!
+//! Every transition is logged and stored in `watchdog_events`; current
+//! usage and counters are at `GET /admin/watchdog`. Usage is read from
+//! `/proc`, so the watchdog only works on Linux.
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::error::ApiResult;
+use crate::extensions::Ext;
+use crate::health::{Criticality, HealthRegistry};
+use crate::module::{Migration, MigrationKind, RouteGroup, RouteModule};
+use crate::time::Timestamp;
+use crate::{t, AppState};
+
+/// Where usage and events are reported, kept reachable while shedding
+const ADMIN_PATH: &str = "/admin/watchdog";
+
+/// Resource limits, from configuration
+#[derive(Debug, Clone)]
+pub struct WatchdogConfig {
+    pub interval: Duration,
+    /// Resident memory in bytes at which the server restarts
+    pub max_rss: Option<u64>,
+    /// Open file descriptors at which the server restarts
+    pub max_fds: Option<u64>,
+    /// Percentage of a limit above which load is shed
+    pub shed_percent: u8,
+    /// How long shedding may last before restarting (zero never restarts)
+    pub restart_after: Duration,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            interval: Duration::from_secs(5),
+            max_rss: None,
+            max_fds: None,
+            shed_percent: 90,
+            restart_after: Duration::from_secs(300),
+        }
+    }
+}
+
+impl WatchdogConfig {
+    /// Whether any limit is set
+    pub fn enabled(&self) -> bool {
+        self.max_rss.is_some() || self.max_fds.is_some()
+    }
+}
+
+/// Resource usage of this process; `None` where it cannot be measured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub rss_bytes: Option<u64>,
+    pub open_fds: Option<u64>,
+}
+
+impl Usage {
+    /// Measure the current process
+    pub fn sample() -> Self {
+        Usage {
+            rss_bytes: rss_bytes(),
+            open_fds: open_fds(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rss_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn open_fds() -> Option<u64> {
+    // Minus the descriptor used to list the directory
+    let entries = std::fs::read_dir("/proc/self/fd").ok()?.count() as u64;
+    Some(entries.saturating_sub(1))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_fds() -> Option<u64> {
+    None
+}
+
+/// A watched resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    Rss,
+    Fds,
+}
+
+impl Resource {
+    fn as_str(self) -> &'static str {
+        match self {
+            Resource::Rss => "rss",
+            Resource::Fds => "fds",
+        }
+    }
+}
+
+/// A resource over one of its thresholds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breach {
+    pub resource: Resource,
+    pub value: u64,
+    pub limit: u64,
+}
+
+/// What the watchdog does after a sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Shed(Breach),
+    Recover,
+    Restart(Breach),
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Shed(_) => "shed",
+            Action::Recover => "recover",
+            Action::Restart(_) => "restart",
+        }
+    }
+}
+
+/// Decisions from samples, separate from I/O so they can be tested
+#[derive(Debug, Default)]
+struct Monitor {
+    shedding_since: Option<Instant>,
+}
+
+impl Monitor {
+    fn step(&mut self, config: &WatchdogConfig, usage: Usage, now: Instant) -> Option<Action> {
+        let limits = [
+            (Resource::Rss, usage.rss_bytes, config.max_rss),
+            (Resource::Fds, usage.open_fds, config.max_fds),
+        ];
+        let breaches = |percent: u64| {
+            limits.into_iter().find_map(|(resource, value, limit)| {
+                let (value, limit) = (value?, limit?);
+                let threshold = limit.saturating_mul(percent) / 100;
+                (value >= threshold).then_some(Breach {
+                    resource,
+                    value,
+                    limit: threshold,
+                })
+            })
+        };
+
+        if let Some(breach) = breaches(100) {
+            return Some(Action::Restart(breach));
+        }
+        match (breaches(config.shed_percent.into()), self.shedding_since) {
+            (Some(breach), None) => {
+                self.shedding_since = Some(now);
+                Some(Action::Shed(breach))
+            }
+            (Some(breach), Some(since)) => (!config.restart_after.is_zero()
+                && now.duration_since(since) >= config.restart_after)
+                .then_some(Action::Restart(breach)),
+            (None, Some(_)) => {
+                self.shedding_since = None;
+                Some(Action::Recover)
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+/// Shared watchdog state and the middleware shedding load
+#[derive(Clone)]
+pub struct Watchdog {
+    config: Arc<WatchdogConfig>,
+    usage: Arc<Mutex<Usage>>,
+    shedding: Arc<AtomicBool>,
+    shed_requests: Arc<AtomicU64>,
+    restart: watch::Sender<bool>,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig) -> Self {
+        Watchdog {
+            config: Arc::new(config),
+            usage: Arc::default(),
+            shedding: Arc::default(),
+            shed_requests: Arc::default(),
+            restart: watch::channel(false).0,
+        }
+    }
+
+    /// Whether requests are currently being shed
+    pub fn is_shedding(&self) -> bool {
+        self.shedding.load(Ordering::Acquire)
+    }
+
+    /// Whether a restart has been requested
+    pub fn restart_requested(&self) -> bool {
+        *self.restart.borrow()
+    }
+
+    /// Resolve once a restart is requested
+    pub async fn wait_for_restart(&self) {
+        let mut restart = self.restart.subscribe();
+        let _ = restart.wait_for(|restart| *restart).await;
+    }
+
+    /// Fail readiness while shedding
+    pub fn register_health_check(&self, health: &HealthRegistry) {
+        let watchdog = self.clone();
+        health.register("resources", Criticality::Critical, move || {
+            let shedding = watchdog.is_shedding();
+            async move {
+                anyhow::ensure!(!shedding, "shedding load: resource usage is near its limit");
+                Ok(())
+            }
+        });
+    }
+
+    /// Sample usage every interval, recording transitions in `pool`
+    pub fn spawn(&self, pool: PgPool) {
+        let watchdog = self.clone();
+        tokio::spawn(async move {
+            let mut monitor = Monitor::default();
+            let mut interval = tokio::time::interval(watchdog.config.interval);
+            loop {
+                interval.tick().await;
+                let usage = tokio::task::spawn_blocking(Usage::sample)
+                    .await
+                    .unwrap_or_default();
+                *watchdog.usage.lock().expect("watchdog usage poisoned") = usage;
+                let Some(action) = monitor.step(&watchdog.config, usage, Instant::now()) else {
+                    continue;
+                };
+                watchdog.apply(action);
+                if let Err(e) = record(&pool, action).await {
+                    tracing::warn!("Failed to record watchdog event: {:#}", e);
+                }
+                if matches!(action, Action::Restart(_)) {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn apply(&self, action: Action) {
+        match action {
+            Action::Shed(breach) => {
+                tracing::warn!(
+                    "🐕 Shedding load: {} at {} of {} threshold",
+                    breach.resource.as_str(),
+                    breach.value,
+                    breach.limit
+                );
+                self.shedding.store(true, Ordering::Release);
+            }
+            Action::Recover => {
+                tracing::info!("🐕 Resource usage recovered, no longer shedding load");
+                self.shedding.store(false, Ordering::Release);
+            }
+            Action::Restart(breach) => {
+                tracing::error!(
+                    "🐕 Restarting: {} at {} of {} threshold",
+                    breach.resource.as_str(),
+                    breach.value,
+                    breach.limit
+                );
+                self.shedding.store(true, Ordering::Release);
+                self.restart.send_replace(true);
+            }
+        }
+    }
+
+    /// Middleware answering 503 while shedding, except to health probes and
+    /// the watchdog's own endpoints
+    pub async fn middleware(
+        State(watchdog): State<Watchdog>,
+        request: Request,
+        next: Next,
+    ) -> Response {
+        let path = request.uri().path();
+        if !watchdog.is_shedding() || path.starts_with("/health") || path.starts_with(ADMIN_PATH) {
+            return next.run(request).await;
+        }
+        watchdog.shed_requests.fetch_add(1, Ordering::Relaxed);
+        let mut response = (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": t!("watchdog-shedding") })),
+        )
+            .into_response();
+        response.headers_mut().insert(
+            RETRY_AFTER,
+            HeaderValue::from(watchdog.config.interval.as_secs().max(1)),
+        );
+        response
+    }
+}
+
+async fn record(pool: &PgPool, action: Action) -> sqlx::Result<()> {
+    let breach = match action {
+        Action::Shed(breach) | Action::Restart(breach) => Some(breach),
+        Action::Recover => None,
+    };
+    sqlx::query(
+        "INSERT INTO watchdog_events (action, resource, value, threshold)
+         VALUES ($1, $2, $3, $4)",
+    )
+    .bind(action.as_str())
+    .bind(breach.map(|b| b.resource.as_str()))
+    .bind(breach.map(|b| b.value as i64))
+    .bind(breach.map(|b| b.limit as i64))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Route module reporting usage, mounted when a watchdog limit is set
+pub struct WatchdogModule;
+
+impl RouteModule for WatchdogModule {
+    fn name(&self) -> &'static str {
+        "watchdog"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Admin
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new()
+            .route(ADMIN_PATH, get(status))
+            .route(&format!("{}/events", ADMIN_PATH), get(list_events))
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        &[Migration {
+            name: "0001_create_watchdog_events",
+            kind: MigrationKind::Expand,
+            sql: "CREATE TABLE IF NOT EXISTS watchdog_events (
+                id BIGSERIAL PRIMARY KEY,
+                action TEXT NOT NULL,
+                resource TEXT,
+                value BIGINT,
+                threshold BIGINT,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );
+            CREATE INDEX IF NOT EXISTS watchdog_events_created_at
+                ON watchdog_events (created_at)",
+        }]
+    }
+}
+
+/// `GET /admin/watchdog` - current usage, limits and counters
+pub async fn status(Ext(watchdog): Ext<Watchdog>) -> Json<serde_json::Value> {
+    let usage = *watchdog.usage.lock().expect("watchdog usage poisoned");
+    let config = &watchdog.config;
+    Json(json!({
+        "usage": usage,
+        "limits": {
+            "max_rss": config.max_rss,
+            "max_fds": config.max_fds,
+            "shed_percent": config.shed_percent,
+        },
+        "shedding": watchdog.is_shedding(),
+        "shed_requests": watchdog.shed_requests.load(Ordering::Relaxed),
+        "restart_requested": watchdog.restart_requested(),
+    }))
+}
+
+/// A recorded watchdog transition
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct WatchdogEvent {
+    pub id: i64,
+    pub action: String,
+    pub resource: Option<String>,
+    pub value: Option<i64>,
+    pub threshold: Option<i64>,
+    #[serde(with = "crate::time::rfc3339")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    #[serde(default = "default_limit")]
+    limit: i64,
+    /// Only events at or after this RFC 3339 timestamp
+    since: Option<Timestamp>,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+/// `GET /admin/watchdog/events` - most recent events first
+pub async fn list_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventsQuery>,
+) -> ApiResult<Json<Vec<WatchdogEvent>>> {
+    let events = sqlx::query_as(
+        "SELECT id, action, resource, value, threshold, created_at FROM watchdog_events
+         WHERE ($2::timestamptz IS NULL OR created_at >= $2)
+         ORDER BY created_at DESC, id DESC LIMIT $1",
+    )
+    .bind(query.limit.clamp(1, 1000))
+    .bind(query.since.map(DateTime::from))
+    .fetch_all(state.db.pool())
+    .await?;
+    Ok(Json(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(rss_mib: u64, fds: u64) -> Usage {
+        Usage {
+            rss_bytes: Some(rss_mib * 1024 * 1024),
+            open_fds: Some(fds),
+        }
+    }
+
+    #[test]
+    fn test_monitor_sheds_recovers_and_restarts() {
+        let config = WatchdogConfig {
+            max_rss: Some(100 * 1024 * 1024),
+            max_fds: Some(1000),
+            restart_after: Duration::from_secs(60),
+            ..WatchdogConfig::default()
+        };
+        let mut monitor = Monitor::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(monitor.step(&config, usage(50, 10), at(0)), None);
+        assert_eq!(
+            monitor.step(&config, usage(50, 950), at(5)),
+            Some(Action::Shed(Breach {
+                resource: Resource::Fds,
+                value: 950,
+                limit: 900,
+            }))
+        );
+        assert_eq!(monitor.step(&config, usage(50, 950), at(10)), None);
+        assert_eq!(
+            monitor.step(&config, usage(50, 100), at(15)),
+            Some(Action::Recover)
+        );
+
+        // Shedding too long
+        assert!(matches!(
+            monitor.step(&config, usage(95, 10), at(20)),
+            Some(Action::Shed(Breach {
+                resource: Resource::Rss,
+                ..
+            }))
+        ));
+        assert_eq!(monitor.step(&config, usage(95, 10), at(79)), None);
+        assert!(matches!(
+            monitor.step(&config, usage(95, 10), at(80)),
+            Some(Action::Restart(_))
+        ));
+
+        // Over a limit
+        let mut monitor = Monitor::default();
+        assert_eq!(
+            monitor.step(&config, usage(100, 10), at(0)),
+            Some(Action::Restart(Breach {
+                resource: Resource::Rss,
+                value: 100 * 1024 * 1024,
+                limit: 100 * 1024 * 1024,
+            }))
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sample() {
+        let usage = Usage::sample();
+        assert!(usage.rss_bytes.unwrap() > 0);
+        assert!(usage.open_fds.unwrap() >= 3);
+    }
+}
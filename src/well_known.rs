@@ -0,0 +1,208 @@
+//! Documents served under `/.well-known/`.
+//!
+//! Any document can be published, or a built-in one overridden, by storing
+//! it in the `well_known.<name>` setting: a JSON string is served as plain
+//! text and any other value as JSON. Without a setting these are built in:
+//!
+//! - `security.txt` (RFC 9116) listing `SECURITY_CONTACT`
+//! - `change-password` redirecting to `CHANGE_PASSWORD_URL`
+//! - `webfinger` (RFC 7033) pointing accounts at the OIDC issuer
+//! - `oauth-authorization-server` (RFC 8414) metadata of the OIDC provider
+//!
+//! The OIDC module serves `openid-configuration` and `jwks.json` itself.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_TYPE, LOCATION},
+        StatusCode,
+    },
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::error::{ApiError, ApiResult};
+use crate::module::{RouteGroup, RouteModule};
+use crate::oidc::Provider;
+use crate::{settings, AppState};
+
+/// Prefix of settings holding documents
+pub const SETTING_PREFIX: &str = "well_known.";
+
+/// How long the generated security.txt stays valid
+const SECURITY_TXT_TTL_DAYS: i64 = 180;
+
+/// Configured values behind the built-in documents
+#[derive(Debug, Clone, Default)]
+pub struct WellKnownConfig {
+    /// `Contact:` URIs of security.txt
+    pub security_contacts: Vec<String>,
+    /// Where `change-password` redirects to
+    pub change_password_url: Option<String>,
+}
+
+/// Parse comma-separated security contacts, turning bare emails into `mailto:` URIs
+pub fn parse_contacts(input: &str) -> Vec<String> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(|contact| {
+            if contact.contains(':') {
+                contact.to_string()
+            } else {
+                format!("mailto:{}", contact)
+            }
+        })
+        .collect()
+}
+
+/// Route module serving `/.well-known/:name`
+pub struct WellKnownModule;
+
+impl RouteModule for WellKnownModule {
+    fn name(&self) -> &'static str {
+        "well_known"
+    }
+
+    fn group(&self) -> RouteGroup {
+        RouteGroup::Public
+    }
+
+    fn routes(&self) -> Router<AppState> {
+        Router::new().route("/.well-known/:name", get(document))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DocumentQuery {
+    resource: Option<String>,
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// `GET /.well-known/:name` - a stored or built-in document
+pub async fn document(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(query): Query<DocumentQuery>,
+) -> ApiResult<Response> {
+    let not_found = || ApiError::NotFound(format!("/.well-known/{} not found", name));
+    if !valid_name(&name) {
+        return Err(not_found());
+    }
+    let stored = settings::get(state.db.queries(), &format!("{}{}", SETTING_PREFIX, name))
+        .await
+        // The settings module may be disabled; fall back to the built-ins
+        .unwrap_or_else(|e| {
+            tracing::debug!("Failed to read well-known setting: {:#}", e);
+            None
+        });
+    let config = state.extension::<WellKnownConfig>().unwrap_or_default();
+    let provider = state.extension::<Provider>();
+
+    match (name.as_str(), stored) {
+        ("change-password", Some(Value::String(url))) => Ok(redirect(&url)),
+        (_, Some(Value::String(text))) => Ok(text_response(text)),
+        (_, Some(value)) => Ok(Json(value).into_response()),
+        ("security.txt", None) if !config.security_contacts.is_empty() => {
+            Ok(text_response(security_txt(&config.security_contacts)))
+        }
+        ("change-password", None) => config
+            .change_password_url
+            .as_deref()
+            .map(redirect)
+            .ok_or_else(not_found),
+        ("webfinger", None) => {
+            let provider = provider.ok_or_else(not_found)?;
+            let resource = query
+                .resource
+                .filter(|r| r.starts_with("acct:") || r.starts_with("https://"))
+                .ok_or_else(|| ApiError::BadRequest("missing or invalid resource".into()))?;
+            let jrd = webfinger(&resource, provider.issuer());
+            Ok((
+                [
+                    (CONTENT_TYPE, "application/jrd+json"),
+                    (ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+                ],
+                jrd.to_string(),
+            )
+                .into_response())
+        }
+        ("oauth-authorization-server", None) => provider
+            .map(|p| Json(p.discovery()).into_response())
+            .ok_or_else(not_found),
+        _ => Err(not_found()),
+    }
+}
+
+fn text_response(text: String) -> Response {
+    ([(CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response()
+}
+
+fn redirect(url: &str) -> Response {
+    (StatusCode::FOUND, [(LOCATION, url.to_string())]).into_response()
+}
+
+/// An RFC 9116 security.txt valid for the next [`SECURITY_TXT_TTL_DAYS`]
+pub fn security_txt(contacts: &[String]) -> String {
+    let expires = chrono::Utc::now() + chrono::Duration::days(SECURITY_TXT_TTL_DAYS);
+    let mut text = String::new();
+    for contact in contacts {
+        text.push_str(&format!("Contact: {}\n", contact));
+    }
+    text.push_str(&format!(
+        "Expires: {}\n",
+        expires.format("%Y-%m-%dT%H:%M:%SZ")
+    ));
+    text
+}
+
+/// A JRD pointing `resource` at the OIDC issuer, for OpenID discovery
+pub fn webfinger(resource: &str, issuer: &str) -> Value {
+    json!({
+        "subject": resource,
+        "links": [{
+            "rel": "http://openid.net/specs/connect/1.0/issuer",
+            "href": issuer,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contacts_and_security_txt() {
+        let contacts = parse_contacts("security@example.com, https://example.com/report,");
+        assert_eq!(
+            contacts,
+            ["mailto:security@example.com", "https://example.com/report"]
+        );
+        let text = security_txt(&contacts);
+        assert!(text.starts_with(
+            "Contact: mailto:security@example.com\nContact: https://example.com/report\nExpires: "
+        ));
+        assert!(text.ends_with("Z\n"));
+    }
+
+    #[test]
+    fn test_names_and_webfinger() {
+        assert!(valid_name("security.txt") && valid_name("change-password"));
+        assert!(!valid_name("..") && !valid_name("a/b") && !valid_name(""));
+
+        let jrd = webfinger("acct:alice@example.com", "https://sso.example.com");
+        assert_eq!(jrd["subject"], "acct:alice@example.com");
+        assert_eq!(jrd["links"][0]["href"], "https://sso.example.com");
+    }
+}